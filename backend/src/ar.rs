@@ -0,0 +1,185 @@
+//! Based on https://github.com/dolphin-emu/dolphin/blob/master/Source/Core/Core/ActionReplay.cpp
+
+use byteorder::{ByteOrder, BE};
+use failure::{Error, ResultExt};
+use gecko::Code;
+
+const BASE_ADDRESS: u32 = 0x8000_0000;
+
+const TYPE_WRITE: u32 = 0;
+
+const SUBTYPE_RAM_WRITE: u32 = 0;
+const SUBTYPE_WRITE_POINTER: u32 = 1;
+const SUBTYPE_ADD_CODE: u32 = 2;
+const SUBTYPE_MASTER_CODE: u32 = 3;
+
+const SIZE_8_BIT: u32 = 0;
+const SIZE_16_BIT: u32 = 1;
+const SIZE_32_BIT: u32 = 2;
+
+const CONDITIONS: [&str; 8] = [
+    "write",
+    "equal",
+    "not equal",
+    "signed less than",
+    "signed greater than",
+    "unsigned less than",
+    "unsigned greater than",
+    "AND",
+];
+
+/// Parses a list of decrypted Action Replay codes and lowers them to direct
+/// memory writes. Only the unconditional RAM writes and fills can be applied
+/// to the game ahead of time, so all the other code types, like the
+/// conditional codes, are rejected. Encrypted codes are rejected as well and
+/// need to be decrypted beforehand, for example with Dolphin, which shows
+/// them decrypted once they are added to a game.
+pub fn parse_codes(text: &str) -> Result<Vec<Code>, Error> {
+    let mut codes = Vec::new();
+
+    for (line_index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        ensure!(
+            !is_encrypted_code(line),
+            "The Action Replay code \"{}\" on line {} is encrypted. Encrypted codes aren't \
+             supported, so it needs to be decrypted first.",
+            line,
+            line_index + 1
+        );
+
+        let mut words = line.split_whitespace();
+        let (left, right) = match (words.next(), words.next()) {
+            (Some(left), Some(right)) if is_code_word(left) && is_code_word(right) => {
+                (left, right)
+            }
+            // Code names and comments
+            _ => continue,
+        };
+        let left = u32::from_str_radix(left, 16)
+            .with_context(|_| format!("Invalid code on line {}", line_index + 1))?;
+        let right = u32::from_str_radix(right, 16)
+            .with_context(|_| format!("Invalid code on line {}", line_index + 1))?;
+
+        decode(left, right, &mut codes).with_context(|_| {
+            format!(
+                "Couldn't apply the code {:08X} {:08X} on line {}",
+                left,
+                right,
+                line_index + 1
+            )
+        })?;
+    }
+
+    Ok(codes)
+}
+
+/// Encrypted codes are written like `XXXX-XXXX-XXXXX`.
+fn is_encrypted_code(line: &str) -> bool {
+    let parts = line.split('-').map(|p| p.len()).collect::<Vec<_>>();
+    parts == [4, 4, 5] && line.chars().all(|c| c == '-' || c.is_ascii_alphanumeric())
+}
+
+fn is_code_word(word: &str) -> bool {
+    word.len() == 8 && word.chars().all(|c| c.is_digit(16))
+}
+
+fn decode(left: u32, right: u32, codes: &mut Vec<Code>) -> Result<(), Error> {
+    if left == 0 {
+        // Zero codes only control how the codes are executed, except for the
+        // fill and slide and the memory copy codes.
+        match right >> 29 {
+            0 | 2 | 3 => return Ok(()),
+            4 => bail!("Fill and slide codes are not supported"),
+            5 => bail!("Memory copy codes are not supported"),
+            kind => bail!("The zero code type {} is not supported", kind),
+        }
+    }
+
+    let address = BASE_ADDRESS | (left & 0x01FF_FFFF);
+    let size = (left >> 25) & 3;
+    let kind = (left >> 27) & 7;
+    let subtype = (left >> 30) & 3;
+
+    ensure!(
+        kind == TYPE_WRITE,
+        "Conditional codes, like this {} code, are evaluated while the game is running and \
+         can't be applied ahead of time. They can be rewritten as Gecko codes, which are run \
+         by the code handler.",
+        CONDITIONS[kind as usize]
+    );
+
+    match subtype {
+        SUBTYPE_RAM_WRITE => {
+            let data = match size {
+                SIZE_8_BIT => vec![right as u8; (right >> 8) as usize + 1],
+                SIZE_16_BIT => {
+                    let mut data = vec![0; 2 * ((right >> 16) as usize + 1)];
+                    for chunk in data.chunks_mut(2) {
+                        BE::write_u16(chunk, right as u16);
+                    }
+                    data
+                }
+                SIZE_32_BIT => {
+                    let mut data = vec![0; 4];
+                    BE::write_u32(&mut data, right);
+                    data
+                }
+                _ => bail!("Floating point writes are not supported"),
+            };
+            codes.push(Code::Write { address, data });
+        }
+        SUBTYPE_WRITE_POINTER | SUBTYPE_ADD_CODE => bail!(
+            "Pointer writes and add codes depend on the game's state while it's running and \
+             can't be applied ahead of time"
+        ),
+        SUBTYPE_MASTER_CODE => {
+            // The master code is only needed by the Action Replay itself.
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writes(text: &str) -> Vec<(u32, Vec<u8>)> {
+        parse_codes(text)
+            .unwrap()
+            .into_iter()
+            .map(|c| match c {
+                Code::Write { address, data } => (address, data),
+                Code::InsertAsm { .. } => panic!("Expected a write"),
+            }).collect()
+    }
+
+    #[test]
+    fn writes_and_fills_ram() {
+        assert_eq!(
+            writes("00001234 000002AB\n02001234 0001BEEF\n04001234 DEADBEEF"),
+            [
+                (0x8000_1234, vec![0xAB; 3]),
+                (0x8000_1234, vec![0xBE, 0xEF, 0xBE, 0xEF]),
+                (0x8000_1234, vec![0xDE, 0xAD, 0xBE, 0xEF]),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_names_and_master_codes() {
+        assert_eq!(
+            writes("Infinite Health\nC4001234 00000000\n00000000 00000000\n05123456 00000064"),
+            [(0x8112_3456, vec![0, 0, 0, 0x64])]
+        );
+    }
+
+    #[test]
+    fn rejects_codes_that_depend_on_the_game_state() {
+        assert!(parse_codes("0A001234 00000001").is_err());
+        assert!(parse_codes("44001234 00000001").is_err());
+        assert!(parse_codes("00000000 80001234").is_err());
+        assert!(parse_codes("ABCD-EFGH-IJKLM").is_err());
+    }
+}
@@ -0,0 +1,55 @@
+//! Wraps the archive formats, so files inside of archives can be read and
+//! replaced regardless of whether they are U8 or RARC archives.
+
+use failure::Error;
+use iso::virtual_file_system::{Directory, FileData};
+use rarc;
+use u8arc;
+
+pub enum Archive<'a> {
+    U8(Directory<'a>),
+    Rarc(rarc::Archive<'a>),
+}
+
+impl<'a> Archive<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        Ok(if rarc::is_rarc(data) {
+            Archive::Rarc(rarc::Archive::parse(data)?)
+        } else {
+            Archive::U8(u8arc::parse(data)?)
+        })
+    }
+
+    pub fn root(&self) -> &Directory<'a> {
+        match *self {
+            Archive::U8(ref root) => root,
+            Archive::Rarc(ref archive) => &archive.root,
+        }
+    }
+
+    pub fn root_mut(&mut self) -> &mut Directory<'a> {
+        match *self {
+            Archive::U8(ref mut root) => root,
+            Archive::Rarc(ref mut archive) => &mut archive.root,
+        }
+    }
+
+    /// Returns the contents of a file inside of the archive.
+    pub fn file(&self, path: &str) -> Result<&[u8], Error> {
+        let file = self
+            .root()
+            .resolve_path(path)
+            .ok_or_else(|| format_err!("The file \"{}\" doesn't exist in the archive", path))?;
+        match file.data {
+            FileData::Memory(ref data) => Ok(data),
+            FileData::Disc { .. } => bail!("The file \"{}\" wasn't loaded into memory", path),
+        }
+    }
+
+    pub fn write(&self) -> Result<Vec<u8>, Error> {
+        match *self {
+            Archive::U8(ref root) => u8arc::write(root),
+            Archive::Rarc(ref archive) => archive.write(),
+        }
+    }
+}
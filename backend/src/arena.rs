@@ -0,0 +1,134 @@
+//! The game allocates its heaps from the arena, which OSInit sets up to span
+//! the memory between the end of the DOL and the top of main memory. The
+//! arena is shrunk by clamping the bounds that OSSetArenaLo and OSSetArenaHi
+//! set, so neither OSInit nor the game can move them past the memory that's
+//! reserved for the Rom Hack. The heaps that Wii games allocate from their
+//! second memory are shrunk the same way through OSSetMEM2ArenaHi. The stubs
+//! clobber r0, which is volatile.
+
+use assembler::{build_checked_branch, Instruction};
+use dol::{DolFile, Section};
+use entry;
+use failure::Error;
+use hook::{read_displaced_instruction, relocate_instruction};
+
+/// The amount of bytes each stub takes up.
+pub const STUB_LEN: u32 = 7 * 4;
+
+/// The apploader copies the FST to the top of main memory, right below this
+/// address, and the arena originally ends where the FST starts.
+pub const FST_END: u32 = 0x817F_FFFF;
+
+const CMPLW_R3_R0: u32 = 0x7C03_0040;
+const BGE_FORWARD_8: u32 = 0x4080_0008;
+const BLE_FORWARD_8: u32 = 0x4081_0008;
+const MR_R3_R0: u32 = 0x7C03_0378;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Bound {
+    Lo,
+    Hi,
+    Mem2Hi,
+}
+
+impl Bound {
+    /// The name of the function that sets the bound.
+    pub fn function(self) -> &'static str {
+        match self {
+            Bound::Lo => "OSSetArenaLo",
+            Bound::Hi => "OSSetArenaHi",
+            Bound::Mem2Hi => "OSSetMEM2ArenaHi",
+        }
+    }
+}
+
+pub struct Clamp {
+    pub bound: Bound,
+    /// The address of the function that sets the bound.
+    pub function: u32,
+    /// The lowest start or the highest end of the arena.
+    pub value: u32,
+}
+
+fn d_form(opcode: u32, reg: u32, base: u32, immediate: u16) -> u32 {
+    (opcode << 26) | (reg << 21) | (base << 16) | immediate as u32
+}
+
+/// The amount of bytes the stubs of the clamps take up.
+pub fn stubs_len(clamps: &[Clamp]) -> u32 {
+    clamps.len() as u32 * STUB_LEN
+}
+
+/// The address the apploader copies the FST to, which the game's arena
+/// originally ends at. The disc header limits the size of the FST.
+pub fn fst_address(max_fst_size: u32) -> u32 {
+    FST_END.saturating_sub(max_fst_size) & !0x1F
+}
+
+/// The value the bound of the arena is clamped to, if it is.
+pub fn bound(clamps: &[Clamp], bound: Bound) -> Option<u32> {
+    clamps.iter().find(|c| c.bound == bound).map(|c| c.value)
+}
+
+/// Lowers the clamps to the branches at the start of the functions and the
+/// section containing the stubs, which starts at the stub address.
+pub fn lower(
+    clamps: &[Clamp],
+    dol: &DolFile,
+    stub_address: u32,
+) -> Result<(Vec<Instruction>, Option<Section>), Error> {
+    if let (Some(lo), Some(hi)) = (bound(clamps, Bound::Lo), bound(clamps, Bound::Hi)) {
+        ensure!(
+            lo < hi,
+            "The arena would end at {:08X}, before it starts at {:08X}",
+            hi,
+            lo
+        );
+    }
+
+    let mut instructions = Vec::with_capacity(clamps.len());
+    let mut stubs = Vec::with_capacity(clamps.len() * STUB_LEN as usize / 4);
+
+    for clamp in clamps {
+        let original = read_displaced_instruction(dol, clamp.function, clamp.bound.function())?;
+
+        let clamp_address = stub_address + 4 * stubs.len() as u32;
+        instructions.push(Instruction {
+            address: clamp.function,
+            data: build_checked_branch(clamp.function, clamp_address, false)?,
+            mask: !0,
+        });
+
+        // lis r0, value@h
+        stubs.push(d_form(15, 0, 0, (clamp.value >> 16) as u16));
+        // ori r0, r0, value@l
+        stubs.push(d_form(24, 0, 0, clamp.value as u16));
+        stubs.push(CMPLW_R3_R0);
+        stubs.push(match clamp.bound {
+            Bound::Lo => BGE_FORWARD_8,
+            Bound::Hi | Bound::Mem2Hi => BLE_FORWARD_8,
+        });
+        stubs.push(MR_R3_R0);
+
+        let original_address = stub_address + 4 * stubs.len() as u32;
+        stubs.push(relocate_instruction(
+            original,
+            clamp.function,
+            original_address,
+        )?);
+        let return_address = stub_address + 4 * stubs.len() as u32;
+        stubs.push(build_checked_branch(
+            return_address,
+            clamp.function + 4,
+            false,
+        )?);
+    }
+
+    let section = if stubs.is_empty() {
+        None
+    } else {
+        Some(entry::to_section(stub_address, &stubs))
+    };
+
+    Ok((instructions, section))
+}
@@ -0,0 +1,225 @@
+//! Encodes the data directives that embed tables and strings in patch files:
+//!
+//! - `.byte`, `.half` and `.word` (or `.long`) store comma separated values
+//! - `.float` and `.double` store floating point numbers
+//! - `.ascii` stores strings, `.string` and `.asciz` terminate them with a
+//!   null byte
+//! - `.space n` stores `n` zero bytes
+//! - `.fill count, size, value` repeats a value of 1, 2 or 4 bytes, which
+//!   fills ranges with `nop`s or clears tables. The size defaults to 1 and
+//!   the value to 0.
+//! - `.align n` pads with zero bytes up to the next multiple of `2^n`
+//!
+//! The data can start at any address and only replaces its own bytes, so
+//! single entries of tables can be edited without touching their neighbors.
+
+use super::expression::evaluate;
+use byteorder::{ByteOrder, BE};
+use failure::Error;
+
+/// Encodes the data directive on the line. Returns `None` if the line is not
+/// a data directive.
+pub fn encode<F>(
+    line: &str,
+    program_counter: u32,
+    resolve_symbol: &F,
+) -> Result<Option<Vec<u8>>, Error>
+where
+    F: Fn(&str) -> Result<u32, Error>,
+{
+    let (directive, operands) = match line.find(char::is_whitespace) {
+        Some(index) => (&line[..index], line[index..].trim()),
+        None => (line, ""),
+    };
+
+    let mut data = Vec::new();
+    match directive {
+        ".byte" => {
+            for value in split_values(operands) {
+                data.push(
+                    evaluate_in_range(value, -0x80, 0xFF, program_counter, resolve_symbol)? as u8,
+                );
+            }
+        }
+        ".half" | ".short" => {
+            for value in split_values(operands) {
+                let value =
+                    evaluate_in_range(value, -0x8000, 0xFFFF, program_counter, resolve_symbol)?;
+                let mut buf = [0; 2];
+                BE::write_u16(&mut buf, value as u16);
+                data.extend_from_slice(&buf);
+            }
+        }
+        ".word" | ".long" => {
+            for value in split_values(operands) {
+                let value = evaluate_in_range(
+                    value,
+                    -0x8000_0000,
+                    0xFFFF_FFFF,
+                    program_counter,
+                    resolve_symbol,
+                )?;
+                let mut buf = [0; 4];
+                BE::write_u32(&mut buf, value as u32);
+                data.extend_from_slice(&buf);
+            }
+        }
+        ".float" => {
+            for value in split_values(operands) {
+                let value = value
+                    .parse::<f32>()
+                    .map_err(|_| format_err!("Invalid float \"{}\"", value))?;
+                let mut buf = [0; 4];
+                BE::write_f32(&mut buf, value);
+                data.extend_from_slice(&buf);
+            }
+        }
+        ".double" => {
+            for value in split_values(operands) {
+                let value = value
+                    .parse::<f64>()
+                    .map_err(|_| format_err!("Invalid double \"{}\"", value))?;
+                let mut buf = [0; 8];
+                BE::write_f64(&mut buf, value);
+                data.extend_from_slice(&buf);
+            }
+        }
+        ".ascii" | ".string" | ".asciz" => {
+            let mut rest = operands;
+            loop {
+                rest = parse_string(rest, &mut data)?;
+                if directive != ".ascii" {
+                    data.push(0);
+                }
+                rest = rest.trim_left();
+                if rest.is_empty() {
+                    break;
+                }
+                ensure!(
+                    rest.starts_with(','),
+                    "Expected a comma between the strings"
+                );
+                rest = rest[1..].trim_left();
+            }
+        }
+        ".space" | ".zero" => {
+            let len = evaluate_in_range(operands, 0, 0x100_0000, program_counter, resolve_symbol)?;
+            data.resize(len as usize, 0);
+        }
+        ".fill" => {
+            let values = split_values(operands);
+            ensure!(
+                !values.is_empty() && values.len() <= 3,
+                "Expected a count, an optional size and an optional value"
+            );
+            let count =
+                evaluate_in_range(values[0], 0, 0x100_0000, program_counter, resolve_symbol)?;
+            let size = match values.get(1) {
+                Some(size) => evaluate_in_range(size, 1, 4, program_counter, resolve_symbol)?,
+                None => 1,
+            };
+            ensure!(size != 3, "The size needs to be 1, 2 or 4 bytes");
+            ensure!(
+                count * size <= 0x100_0000,
+                "Filling {} bytes is too much",
+                count * size
+            );
+            let value = match values.get(2) {
+                Some(value) => evaluate_in_range(
+                    value,
+                    -0x8000_0000,
+                    0xFFFF_FFFF,
+                    program_counter,
+                    resolve_symbol,
+                )?,
+                None => 0,
+            };
+            let mut buf = [0; 4];
+            BE::write_u32(&mut buf, value as u32);
+            let pattern = &buf[4 - size as usize..];
+            for _ in 0..count {
+                data.extend_from_slice(pattern);
+            }
+        }
+        ".align" => {
+            let shift = evaluate_in_range(operands, 0, 16, program_counter, resolve_symbol)?;
+            let alignment = 1 << shift;
+            let padding = (alignment - program_counter % alignment) % alignment;
+            data.resize(padding as usize, 0);
+        }
+        _ => return Ok(None),
+    }
+
+    Ok(Some(data))
+}
+
+fn split_values(operands: &str) -> Vec<&str> {
+    operands
+        .split(',')
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+fn evaluate_in_range<F>(
+    text: &str,
+    min: i64,
+    max: i64,
+    program_counter: u32,
+    resolve_symbol: &F,
+) -> Result<i64, Error>
+where
+    F: Fn(&str) -> Result<u32, Error>,
+{
+    let value = evaluate(text, program_counter, resolve_symbol)?;
+    ensure!(
+        min <= value && value <= max,
+        "{} is out of range, it needs to be between {} and {}",
+        value,
+        min,
+        max
+    );
+    Ok(value)
+}
+
+/// Parses a quoted string with C style escape sequences into the buffer and
+/// returns the text following it.
+fn parse_string<'a>(text: &'a str, data: &mut Vec<u8>) -> Result<&'a str, Error> {
+    ensure!(text.starts_with('"'), "Expected a quoted string");
+    let mut chars = text.char_indices().skip(1);
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '"' => return Ok(&text[index + 1..]),
+            '\\' => {
+                let (_, escaped) = chars
+                    .next()
+                    .ok_or_else(|| format_err!("Unterminated string"))?;
+                match escaped {
+                    'n' => data.push(b'\n'),
+                    'r' => data.push(b'\r'),
+                    't' => data.push(b'\t'),
+                    '0' => data.push(0),
+                    '\\' => data.push(b'\\'),
+                    '"' => data.push(b'"'),
+                    'x' => {
+                        let mut value = 0;
+                        for _ in 0..2 {
+                            let digit = chars
+                                .next()
+                                .and_then(|(_, c)| c.to_digit(16))
+                                .ok_or_else(|| format_err!("Expected two hex digits after \\x"))?;
+                            value = 16 * value + digit as u8;
+                        }
+                        data.push(value);
+                    }
+                    _ => bail!("Unknown escape sequence \\{}", escaped),
+                }
+            }
+            _ => {
+                let mut buf = [0; 4];
+                data.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            }
+        }
+    }
+    bail!("Unterminated string")
+}
@@ -0,0 +1,246 @@
+//! Points the errors of the patch files at the line they occur in, and at the
+//! part of the line they are about if it's known, the way compilers do:
+//!
+//! ```text
+//! error[E0004]: The branch target 81234568 is out of range
+//!   --> src/patch.asm:12:7
+//!    |
+//! 12 |     b far_away
+//!    |       ^^^^^^^^
+//!    = note: Invalid operand "far_away" for "b"
+//! ```
+//!
+//! The errors that know what went wrong carry a `Cause`, either as the error
+//! itself or as its context, which provides the code and the bytes of the line
+//! it's about. These bytes are only known for the line that was assembled,
+//! so the lines that a macro substituted into are pointed at as a whole.
+
+use super::reduce_line_to_code;
+use failure::{Context, Error, Fail};
+use std::error;
+use std::fmt::{self, Display};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+/// The line of a file that a line of assembly originates from, before the
+/// included files are inlined.
+#[derive(Debug, Clone)]
+pub struct Location {
+    pub path: PathBuf,
+    /// Starts at 1.
+    pub line: usize,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Code {
+    UnknownInstruction = 1,
+    OperandCount = 2,
+    InvalidOperand = 3,
+    BranchOutOfRange = 4,
+    ValueOutOfRange = 5,
+    UnknownSymbol = 6,
+    Misaligned = 7,
+    InvalidLabel = 8,
+}
+
+impl Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "E{:04}", *self as u32)
+    }
+}
+
+/// An error that knows what went wrong and which part of the line it's
+/// about, like the operand of a branch whose target is out of range.
+#[derive(Debug)]
+pub struct Cause {
+    pub code: Code,
+    /// The bytes of the assembled line the error is about, if it's only about
+    /// a part of the line.
+    pub span: Option<Range<usize>>,
+    pub message: String,
+}
+
+impl Cause {
+    pub fn new<S: Into<String>>(code: Code, message: S) -> Self {
+        Cause {
+            code,
+            span: None,
+            message: message.into(),
+        }
+    }
+
+    pub fn at<S: Into<String>>(code: Code, span: Range<usize>, message: S) -> Self {
+        Cause {
+            code,
+            span: Some(span),
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for Cause {}
+
+#[derive(Debug)]
+pub struct Diagnostic {
+    code: Option<Code>,
+    message: String,
+    /// The messages of the errors that led to the error, outermost first.
+    notes: Vec<String>,
+    path: Option<PathBuf>,
+    line: usize,
+    source: String,
+    /// The byte range of the source that the error is about.
+    span: Range<usize>,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.code {
+            Some(code) => writeln!(f, "error[{}]: {}", code, self.message)?,
+            None => writeln!(f, "error: {}", self.message)?,
+        }
+
+        let line_number = self.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        let column = self.source[..self.span.start].chars().count() + 1;
+        match self.path {
+            Some(ref path) => writeln!(
+                f,
+                "{}--> {}:{}:{}",
+                gutter,
+                path.display(),
+                self.line,
+                column
+            )?,
+            None => writeln!(f, "{}--> {}:{}", gutter, self.line, column)?,
+        }
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", line_number, self.source)?;
+
+        // Tabs are kept, so the carets line up with the source
+        let indentation = self.source[..self.span.start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        let carets = "^".repeat(self.source[self.span.clone()].chars().count().max(1));
+        write!(f, "{} | {}{}", gutter, indentation, carets)?;
+
+        for note in &self.notes {
+            write!(f, "\n{} = note: {}", gutter, note)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl error::Error for Diagnostic {}
+
+fn cause(fail: &Fail) -> Option<&Cause> {
+    fail.downcast_ref::<Cause>().or_else(|| {
+        fail.downcast_ref::<Context<Cause>>()
+            .map(|c| c.get_context())
+    })
+}
+
+/// Turns the error of the source line into a diagnostic that points at it.
+/// The code is the line that was assembled, which the spans of the causes
+/// refer to. The line is the line number in the file at the path, if there is
+/// one.
+pub fn locate(error: Error, source: &str, code: &str, path: Option<&Path>, line: usize) -> Error {
+    let mut messages = error
+        .iter_chain()
+        .map(|f| f.to_string())
+        .collect::<Vec<_>>();
+    let message = messages.pop().unwrap_or_default();
+
+    // The innermost cause is the most specific one
+    let causes = error.iter_chain().filter_map(cause).collect::<Vec<_>>();
+    let error_code = causes.last().map(|c| c.code);
+    let span = causes.iter().rev().filter_map(|c| c.span.clone()).next();
+
+    // The spans only match the source if no macro substituted into the line,
+    // otherwise the error is about all of the line's code
+    let code_start = source.len() - source.trim_left().len();
+    let source_code = reduce_line_to_code(source);
+    let span = match span {
+        Some(ref span) if source_code == code && span.end <= code.len() => {
+            code_start + span.start..code_start + span.end
+        }
+        _ => code_start..code_start + source_code.len(),
+    };
+
+    Diagnostic {
+        code: error_code,
+        message,
+        notes: messages,
+        path: path.map(|p| p.to_owned()),
+        line,
+        source: source.to_string(),
+        span,
+    }.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::encoder::encode;
+    use super::*;
+
+    fn resolve(symbol: &str) -> Result<u32, Error> {
+        bail!("Unknown symbol \"{}\"", symbol)
+    }
+
+    fn render(source: &str, code: &str) -> String {
+        let error = encode(0x8000_4000, code, resolve).unwrap_err();
+        locate(error, source, code, Some(Path::new("src/patch.asm")), 12).to_string()
+    }
+
+    #[test]
+    fn points_at_the_operand() {
+        let rendered = render("    li r3, 0x8000 ; too large", "li r3, 0x8000");
+        let lines = rendered.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("error[E0005]: 32768 is out of range"));
+        assert_eq!(
+            &lines[1..],
+            [
+                "  --> src/patch.asm:12:12",
+                "   |",
+                "12 |     li r3, 0x8000 ; too large",
+                "   |            ^^^^^^",
+                "   = note: Invalid operand \"0x8000\" for \"li\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn names_the_error_codes() {
+        let rendered = render("b 0x84000000", "b 0x84000000");
+        assert_eq!(
+            rendered.lines().collect::<Vec<_>>(),
+            [
+                "error[E0004]: The branch target 84000000 is out of range",
+                "  --> src/patch.asm:12:3",
+                "   |",
+                "12 | b 0x84000000",
+                "   |   ^^^^^^^^^^",
+                "   = note: Invalid operand \"0x84000000\" for \"b\"",
+            ]
+        );
+
+        let rendered = render("frobnicate r3", "frobnicate r3");
+        assert!(rendered.starts_with("error[E0001]: Unknown instruction"));
+        assert!(rendered.ends_with("   | ^^^^^^^^^^"));
+    }
+
+    #[test]
+    fn points_at_all_of_a_substituted_line() {
+        let rendered = render("    load_value r3", "li r3, 0x8000");
+        assert!(rendered.contains("--> src/patch.asm:12:5"));
+        assert!(rendered.contains("|     ^^^^^^^^^^^^^"));
+    }
+}
@@ -0,0 +1,106 @@
+//! Turns machine code back into assembly, so the instructions that a patch
+//! overwrites can be shown next to the ones that replace them.
+
+use super::isa::Operand::*;
+use super::isa::{swap_spr, Form, Operand, AA, FORMS, LK, OE, RC, SPRS};
+use std::fmt::Write;
+
+/// Disassembles a single instruction at the given address. Branch targets
+/// are resolved to absolute addresses. `None` is returned if the word is not
+/// a valid instruction.
+pub fn disassemble(address: u32, word: u32) -> Option<String> {
+    let form = FORMS.iter().find(|f| f.matches(word))?;
+
+    let mut text = String::from(form.mnemonic);
+    if form.flags & OE != 0 && word & 0x400 != 0 {
+        text.push('o');
+    }
+    if form.flags & LK != 0 && word & 1 != 0 {
+        text.push('l');
+    }
+    if form.flags & AA != 0 && word & 2 != 0 {
+        text.push('a');
+    }
+    if form.flags & RC != 0 && word & 1 != 0 {
+        text.push('.');
+    }
+
+    let operands = form
+        .operands
+        .iter()
+        .filter_map(|&operand| format_operand(form, operand, address, word))
+        .collect::<Vec<_>>();
+    if !operands.is_empty() {
+        let _ = write!(text, " {}", operands.join(", "));
+    }
+
+    Some(text)
+}
+
+/// Checks whether the word is a valid instruction.
+pub fn is_instruction(word: u32) -> bool {
+    FORMS.iter().any(|f| f.matches(word))
+}
+
+fn field(word: u32, shift: u8, bits: u8) -> u32 {
+    (word >> shift) & ((1 << bits) - 1)
+}
+
+fn format_operand(form: &Form, operand: Operand, address: u32, word: u32) -> Option<String> {
+    Some(match operand {
+        Gpr(shift) => format!("r{}", field(word, shift, 5)),
+        GprPair => format!("r{}", field(word, 21, 5)),
+        Fpr(shift) => format!("f{}", field(word, shift, 5)),
+        Crf(shift) => format!("cr{}", field(word, shift, 3)),
+        OptCrf(shift) => match field(word, shift, 3) {
+            0 => return None,
+            crf => format!("cr{}", crf),
+        },
+        Crb(shift) => field(word, shift, 5).to_string(),
+        Simm => format_signed(word as u16 as i16 as i32),
+        Uimm => format!("0x{:X}", word & 0xFFFF),
+        Offset => format!(
+            "{}(r{})",
+            format_signed(word as u16 as i16 as i32),
+            field(word, 16, 5)
+        ),
+        PsOffset => format!(
+            "{}(r{})",
+            format_signed(((word << 20) as i32) >> 20),
+            field(word, 16, 5)
+        ),
+        Imm(shift, bits) => field(word, shift, bits).to_string(),
+        Spr => {
+            let spr = swap_spr(field(word, 11, 10));
+            SPRS.iter()
+                .find(|&&(number, _)| number == spr)
+                .map(|&(_, name)| name.to_string())
+                .unwrap_or_else(|| spr.to_string())
+        }
+        Tbr => swap_spr(field(word, 11, 10)).to_string(),
+        BranchTarget => {
+            let offset = ((word << 6) as i32 >> 6) as u32 & !3;
+            format_target(form, address, word, offset)
+        }
+        CondTarget => {
+            let offset = (word as u16 as i16 as i32) as u32 & !3;
+            format_target(form, address, word, offset)
+        }
+    })
+}
+
+fn format_target(form: &Form, address: u32, word: u32, offset: u32) -> String {
+    if form.flags & AA != 0 && word & 2 != 0 {
+        format!("0x{:08X}", offset)
+    } else {
+        format!("0x{:08X}", address.wrapping_add(offset))
+    }
+}
+
+fn format_signed(value: i32) -> String {
+    if value < 0 {
+        format!("-0x{:X}", -value)
+    } else {
+        format!("0x{:X}", value)
+    }
+}
@@ -0,0 +1,535 @@
+//! Encodes the instructions of the Gekko and Broadway processors, including
+//! the paired single extensions, from their textual representation.
+
+use super::diagnostic::{Cause, Code};
+use super::expression::evaluate;
+use super::isa::Operand::*;
+use super::isa::{swap_spr, Form, Operand, AA, FORMS, LK, OE, RC, SPRS};
+use failure::{Error, ResultExt};
+use std::borrow::Cow;
+use std::ops::Range;
+
+/// An operand along with the bytes of the line it was written at, which the
+/// errors about it point at.
+#[derive(Clone)]
+struct Token<'a> {
+    text: Cow<'a, str>,
+    span: Range<usize>,
+}
+
+/// Encodes a single line of assembly at the given address. Symbols in the
+/// operands are resolved with the given function.
+pub fn encode<F>(address: u32, line: &str, resolve_symbol: F) -> Result<u32, Error>
+where
+    F: Fn(&str) -> Result<u32, Error>,
+{
+    encode_at(address, line, 0, resolve_symbol)
+}
+
+/// Encodes the instruction that starts at the byte offset of the line, so
+/// the errors point at the bytes of the whole line.
+pub fn encode_at<F>(address: u32, line: &str, start: usize, resolve_symbol: F) -> Result<u32, Error>
+where
+    F: Fn(&str) -> Result<u32, Error>,
+{
+    let text = &line[start..];
+    let name_len = text.find(char::is_whitespace).unwrap_or(text.len());
+    let name = Token {
+        text: Cow::Owned(text[..name_len].to_lowercase()),
+        span: start..start + name_len,
+    };
+    let operands = split_operands(&text[name_len..], start + name_len);
+
+    match expand_simplified(&name.text, &operands, address, &resolve_symbol)? {
+        Some((mnemonic, operands)) => {
+            let name = Token {
+                text: Cow::Owned(mnemonic),
+                span: name.span.clone(),
+            };
+            encode_operands(address, text, &name, &operands, &resolve_symbol)
+        }
+        None => encode_operands(address, text, &name, &operands, &resolve_symbol),
+    }
+}
+
+/// Splits the operands at their commas. The offset is where the operands
+/// start in the line.
+fn split_operands(operands: &str, offset: usize) -> Vec<Token> {
+    if operands.trim().is_empty() {
+        return Vec::new();
+    }
+    let mut tokens = Vec::new();
+    let mut start = offset;
+    for operand in operands.split(',') {
+        let leading = operand.len() - operand.trim_left().len();
+        let text = operand.trim();
+        tokens.push(Token {
+            text: Cow::Borrowed(text),
+            span: start + leading..start + leading + text.len(),
+        });
+        start += operand.len() + 1;
+    }
+    tokens
+}
+
+fn encode_operands<F>(
+    address: u32,
+    line: &str,
+    mnemonic: &Token,
+    operands: &[Token],
+    resolve_symbol: &F,
+) -> Result<u32, Error>
+where
+    F: Fn(&str) -> Result<u32, Error>,
+{
+    // Some mnemonics have multiple forms that only differ in their operands,
+    // so the first form that accepts them is used
+    let mut error = None;
+    for form in FORMS {
+        if let Some(flag_bits) = parse_suffixes(form, &mnemonic.text) {
+            match encode_form(form, flag_bits, address, operands, resolve_symbol) {
+                Ok(word) => return Ok(word),
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
+    }
+
+    Err(error.unwrap_or_else(|| {
+        let message = format!("Unknown instruction: \"{}\"", line);
+        Cause::at(Code::UnknownInstruction, mnemonic.span.clone(), message).into()
+    }))
+}
+
+/// Rewrites the simplified mnemonics whose operands are derived from each
+/// other, like the mask of `slwi` from its shift, into the instructions they
+/// stand for.
+fn expand_simplified<'a, F>(
+    mnemonic: &str,
+    operands: &[Token<'a>],
+    address: u32,
+    resolve_symbol: &F,
+) -> Result<Option<(String, Vec<Token<'a>>)>, Error>
+where
+    F: Fn(&str) -> Result<u32, Error>,
+{
+    let (base, record) = if mnemonic.ends_with('.') {
+        (&mnemonic[..mnemonic.len() - 1], ".")
+    } else {
+        (mnemonic, "")
+    };
+    // Only the rotations can record their result
+    if !record.is_empty() && (base == "subi" || base == "crclr" || base == "crset") {
+        return Ok(None);
+    }
+    let shift = |n: &Token| parse_immediate(&n.text, &n.span, 0, 31, address, resolve_symbol);
+    // The derived operands point at the operand they are derived from
+    let derived = |from: &Token, value: String| Token {
+        text: Cow::Owned(value),
+        span: from.span.clone(),
+    };
+    let rlwinm = |a: &Token<'a>, s: &Token<'a>, n: &Token, sh: i64, mb: i64, me: i64| {
+        let mut operands = vec![a.clone(), s.clone()];
+        operands.extend([sh, mb, me].iter().map(|f| derived(n, f.to_string())));
+        (format!("rlwinm{}", record), operands)
+    };
+
+    Ok(Some(match (base, operands) {
+        ("subi", &[ref d, ref a, ref value]) => {
+            let value = derived(value, format!("-({})", value.text));
+            ("addi".to_string(), vec![d.clone(), a.clone(), value])
+        }
+        ("slwi", &[ref a, ref s, ref n]) => {
+            let sh = shift(n)?;
+            rlwinm(a, s, n, sh, 0, 31 - sh)
+        }
+        ("srwi", &[ref a, ref s, ref n]) => {
+            let sh = shift(n)?;
+            rlwinm(a, s, n, (32 - sh) % 32, sh, 31)
+        }
+        ("clrrwi", &[ref a, ref s, ref n]) => {
+            let sh = shift(n)?;
+            rlwinm(a, s, n, 0, 0, 31 - sh)
+        }
+        ("crclr", &[ref bit]) => ("crxor".to_string(), vec![bit.clone(); 3]),
+        ("crset", &[ref bit]) => ("creqv".to_string(), vec![bit.clone(); 3]),
+        ("subi", _) | ("slwi", _) | ("srwi", _) | ("clrrwi", _) | ("crclr", _) | ("crset", _) => {
+            let expected = if base.starts_with("cr") { 1 } else { 3 };
+            let message = format!(
+                "\"{}\" expects {} operands, but {} were given",
+                base,
+                expected,
+                operands.len()
+            );
+            return Err(Cause::new(Code::OperandCount, message).into());
+        }
+        _ => return Ok(None),
+    }))
+}
+
+/// Checks whether the mnemonic is the form's mnemonic followed by suffixes
+/// that the form supports and returns the bits they set.
+fn parse_suffixes(form: &Form, mnemonic: &str) -> Option<u32> {
+    if !mnemonic.starts_with(form.mnemonic) {
+        return None;
+    }
+
+    let mut rest = &mnemonic[form.mnemonic.len()..];
+    let mut bits = 0;
+    for &(flag, suffix, bit) in &[(OE, 'o', 0x400), (LK, 'l', 1), (AA, 'a', 2), (RC, '.', 1)] {
+        if form.flags & flag != 0 && rest.starts_with(suffix) {
+            rest = &rest[1..];
+            bits |= bit;
+        }
+    }
+
+    if rest.is_empty() {
+        Some(bits)
+    } else {
+        None
+    }
+}
+
+fn encode_form<F>(
+    form: &Form,
+    flag_bits: u32,
+    address: u32,
+    operands: &[Token],
+    resolve_symbol: &F,
+) -> Result<u32, Error>
+where
+    F: Fn(&str) -> Result<u32, Error>,
+{
+    let skip_crf = match form.operands.first() {
+        // Labels may start with "cr" too, like in beq crash_handler
+        Some(&OptCrf(_)) => operands
+            .first()
+            .map_or(true, |o| parse_register(&o.text, "cr", 8).is_err()),
+        _ => false,
+    };
+    let expected = form.operands.len() - skip_crf as usize;
+
+    // Symbols may contain commas, so everything after the last separator
+    // belongs to the branch target
+    let mut operands = operands.to_vec();
+    if operands.len() > expected && expected != 0 {
+        match form.operands.last() {
+            Some(&BranchTarget) | Some(&CondTarget) => {
+                let target = operands.split_off(expected - 1);
+                let texts = target.iter().map(|o| &*o.text).collect::<Vec<_>>();
+                operands.push(Token {
+                    text: Cow::Owned(texts.join(",")),
+                    span: target[0].span.start..target[target.len() - 1].span.end,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    if operands.len() != expected {
+        let message = format!(
+            "\"{}\" expects {} operands, but {} were given",
+            form.mnemonic,
+            expected,
+            operands.len()
+        );
+        return Err(Cause::new(Code::OperandCount, message).into());
+    }
+
+    let mut word = form.bits | flag_bits;
+    let mut operands = operands.iter();
+    for &operand in form.operands {
+        if skip_crf {
+            if let OptCrf(_) = operand {
+                continue;
+            }
+        }
+        let token = operands.next().unwrap();
+        let text = &token.text;
+        let message = || format!("Invalid operand \"{}\" for \"{}\"", text, form.mnemonic);
+        word |= encode_operand(operand, token, address, word, resolve_symbol)
+            .with_context(|_| Cause::at(Code::InvalidOperand, token.span.clone(), message()))?;
+    }
+
+    Ok(word)
+}
+
+fn encode_operand<F>(
+    operand: Operand,
+    token: &Token,
+    address: u32,
+    word: u32,
+    resolve_symbol: &F,
+) -> Result<u32, Error>
+where
+    F: Fn(&str) -> Result<u32, Error>,
+{
+    let (text, span) = (&*token.text, &token.span);
+    let immediate = |text: &str, span: &Range<usize>, min, max| {
+        parse_immediate(text, span, min, max, address, resolve_symbol)
+    };
+
+    Ok(match operand {
+        Gpr(shift) => parse_gpr(text)? << shift,
+        GprPair => {
+            let register = parse_gpr(text)?;
+            register << 21 | register << 11
+        }
+        Fpr(shift) => parse_register(text, "f", 32)? << shift,
+        Crf(shift) | OptCrf(shift) => parse_register(text, "cr", 8)? << shift,
+        Crb(shift) if text.starts_with("cr") || text.starts_with("4*") => parse_crb(text)? << shift,
+        Crb(shift) => (immediate(text, span, 0, 31)? as u32) << shift,
+        Simm => immediate(text, span, -0x8000, 0x7FFF)? as u32 & 0xFFFF,
+        // Allows both lis r3, 0x8000 and lis r3, -0x8000
+        Uimm => immediate(text, span, -0x8000, 0xFFFF)? as u32 & 0xFFFF,
+        Offset => {
+            let (displacement, register) = parse_offset(token)?;
+            let displacement = immediate(&displacement.text, &displacement.span, -0x8000, 0x7FFF)?;
+            displacement as u32 & 0xFFFF | register << 16
+        }
+        PsOffset => {
+            let (displacement, register) = parse_offset(token)?;
+            let displacement = immediate(&displacement.text, &displacement.span, -0x800, 0x7FF)?;
+            displacement as u32 & 0xFFF | register << 16
+        }
+        Imm(shift, bits) => (immediate(text, span, 0, (1 << bits) - 1)? as u32) << shift,
+        Spr => {
+            let spr = match SPRS
+                .iter()
+                .find(|&&(_, name)| text.eq_ignore_ascii_case(name))
+            {
+                Some(&(spr, _)) => spr,
+                None => immediate(text, span, 0, 1023)? as u32,
+            };
+            swap_spr(spr) << 11
+        }
+        Tbr => {
+            let tbr = match &*text.to_lowercase() {
+                "tbl" => 268,
+                "tbu" => 269,
+                _ => immediate(text, span, 0, 1023)? as u32,
+            };
+            swap_spr(tbr) << 11
+        }
+        BranchTarget => encode_target(token, address, word, 26, resolve_symbol)?,
+        CondTarget => encode_target(token, address, word, 16, resolve_symbol)?,
+    })
+}
+
+fn encode_target<F>(
+    token: &Token,
+    address: u32,
+    word: u32,
+    bits: u32,
+    resolve_symbol: &F,
+) -> Result<u32, Error>
+where
+    F: Fn(&str) -> Result<u32, Error>,
+{
+    // Plain symbols are resolved directly, as demangled names can't be
+    // parsed as expressions
+    let target = match resolve_symbol(&token.text) {
+        Ok(target) => target,
+        Err(_) => evaluate(&token.text, address, resolve_symbol)? as u32,
+    };
+    let is_absolute = word & 2 != 0;
+    let offset = if is_absolute {
+        target
+    } else {
+        target.wrapping_sub(address)
+    };
+
+    if offset & 3 != 0 {
+        let message = format!("The branch target {:08X} is not aligned", target);
+        return Err(Cause::at(Code::Misaligned, token.span.clone(), message).into());
+    }
+    // The offset needs to survive being sign extended from the field
+    let shift = 32 - bits;
+    if ((offset << shift) as i32 >> shift) as u32 != offset {
+        let message = format!("The branch target {:08X} is out of range", target);
+        return Err(Cause::at(Code::BranchOutOfRange, token.span.clone(), message).into());
+    }
+
+    Ok(offset & ((1 << bits) - 1))
+}
+
+fn parse_gpr(text: &str) -> Result<u32, Error> {
+    match text {
+        "sp" => Ok(1),
+        "rtoc" => Ok(2),
+        _ => parse_register(text, "r", 32),
+    }
+}
+
+fn parse_register(text: &str, prefix: &str, count: u32) -> Result<u32, Error> {
+    ensure!(
+        text.starts_with(prefix),
+        "Expected a register starting with \"{}\"",
+        prefix
+    );
+    let index = text[prefix.len()..]
+        .parse::<u32>()
+        .map_err(|_| format_err!("Invalid register index"))?;
+    ensure!(index < count, "There is no register {}", text);
+    Ok(index)
+}
+
+/// Parses a condition register bit like `cr1eq` or `4*cr1+eq`.
+fn parse_crb(text: &str) -> Result<u32, Error> {
+    let text = text.trim_left_matches("4*");
+    let (field, bit) = text.split_at(text.len().min(3));
+    let field = parse_register(field, "cr", 8)?;
+    let bit = match bit.trim_left_matches('+') {
+        "lt" => 0,
+        "gt" => 1,
+        "eq" => 2,
+        "so" | "un" => 3,
+        _ => bail!("Expected lt, gt, eq or so"),
+    };
+    Ok(4 * field + bit)
+}
+
+/// Parses an operand like `0x8(r1)` into its displacement and register.
+fn parse_offset<'a>(token: &'a Token) -> Result<(Token<'a>, u32), Error> {
+    let text = &*token.text;
+    ensure!(text.ends_with(')'), "Expected an operand like 0x8(r1)");
+    let open = text
+        .rfind('(')
+        .ok_or_else(|| format_err!("Expected an operand like 0x8(r1)"))?;
+    let register = parse_gpr(text[open + 1..text.len() - 1].trim())?;
+    let leading = open - text[..open].trim_left().len();
+    let displacement = text[..open].trim();
+    let displacement = if displacement.is_empty() {
+        Token {
+            text: Cow::Borrowed("0"),
+            span: token.span.clone(),
+        }
+    } else {
+        let start = token.span.start + leading;
+        Token {
+            text: Cow::Borrowed(displacement),
+            span: start..start + displacement.len(),
+        }
+    };
+    Ok((displacement, register))
+}
+
+fn parse_immediate<F>(
+    text: &str,
+    span: &Range<usize>,
+    min: i64,
+    max: i64,
+    address: u32,
+    resolve_symbol: &F,
+) -> Result<i64, Error>
+where
+    F: Fn(&str) -> Result<u32, Error>,
+{
+    let value = evaluate(text, address, resolve_symbol)?;
+    if value < min || value > max {
+        let message = format!(
+            "{} is out of range, it needs to be between {} and {}",
+            value, min, max
+        );
+        return Err(Cause::at(Code::ValueOutOfRange, span.clone(), message).into());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::disassembler::disassemble;
+    use super::*;
+
+    const ADDRESS: u32 = 0x8000_4000;
+
+    fn resolve(symbol: &str) -> Result<u32, Error> {
+        match symbol {
+            "crash_handler" => Ok(0x8000_4100),
+            "far_away" => Ok(0x8400_0000),
+            _ => bail!("Unknown symbol \"{}\"", symbol),
+        }
+    }
+
+    fn assemble(line: &str) -> u32 {
+        encode(ADDRESS, line, resolve).unwrap_or_else(|e| panic!("{}: {}", line, e))
+    }
+
+    #[test]
+    fn encodes_known_opcodes() {
+        for &(line, word) in &[
+            ("nop", 0x6000_0000),
+            ("li r3, 1", 0x3860_0001),
+            ("lis r3, 0x8000", 0x3C60_8000),
+            ("addi r3, r4, -8", 0x3864_FFF8),
+            ("mr r31, r3", 0x7C7F_1B78),
+            ("lwz r0, 0x14(r1)", 0x8001_0014),
+            ("stwu r1, -0x10(r1)", 0x9421_FFF0),
+            ("mflr r0", 0x7C08_02A6),
+            ("mtctr r12", 0x7D89_03A6),
+            ("cmpwi r3, 0", 0x2C03_0000),
+            ("cmplw cr1, r3, r4", 0x7C83_2040),
+            ("rlwinm. r3, r4, 2, 0, 29", 0x5483_103B),
+            ("blr", 0x4E80_0020),
+            ("bctrl", 0x4E80_0421),
+            ("b 0x80004010", 0x4800_0010),
+            ("bl 0x80003FF0", 0x4BFF_FFF1),
+            ("beq 0x80004008", 0x4182_0008),
+            ("bne cr7, 0x80003FFC", 0x409E_FFFC),
+            ("ps_add f1, f2, f3", 0x1022_182A),
+        ] {
+            assert_eq!(assemble(line), word, "{}", line);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_the_disassembler() {
+        for &word in &[
+            0x3860_0001,
+            0x7C7F_1B78,
+            0x9421_FFF0,
+            0x7C08_02A6,
+            0x7C83_2040,
+            0x5483_103B,
+            0x4BFF_FFF1,
+            0x409E_FFFC,
+            0x1022_182A,
+        ] {
+            let text = disassemble(ADDRESS, word).unwrap();
+            assert_eq!(assemble(&text), word, "{}", text);
+        }
+    }
+
+    #[test]
+    fn encodes_simplified_mnemonics() {
+        for &(simplified, line) in &[
+            ("subi r3, r3, 4", "addi r3, r3, -4"),
+            ("slwi r3, r4, 2", "rlwinm r3, r4, 2, 0, 29"),
+            ("slwi. r3, r4, 2", "rlwinm. r3, r4, 2, 0, 29"),
+            ("srwi r3, r4, 8", "rlwinm r3, r4, 24, 8, 31"),
+            ("srwi r3, r4, 0", "rlwinm r3, r4, 0, 0, 31"),
+            ("clrrwi r3, r4, 5", "rlwinm r3, r4, 0, 0, 26"),
+        ] {
+            assert_eq!(assemble(simplified), assemble(line), "{}", simplified);
+        }
+        assert_eq!(assemble("crclr 6"), 0x4CC6_3182);
+        assert_eq!(assemble("crset 6"), 0x4CC6_3242);
+        assert!(encode(ADDRESS, "slwi r3, r4", resolve).is_err());
+        assert!(encode(ADDRESS, "slwi r3, r4, 32", resolve).is_err());
+    }
+
+    #[test]
+    fn branches_to_labels_starting_with_cr() {
+        assert_eq!(assemble("beq crash_handler"), 0x4182_0100);
+        assert_eq!(assemble("beq cr1, crash_handler"), 0x4186_0100);
+    }
+
+    #[test]
+    fn rejects_invalid_operands() {
+        assert!(encode(ADDRESS, "li r3, 0x8000", resolve).is_err());
+        assert!(encode(ADDRESS, "li r32, 0", resolve).is_err());
+        assert!(encode(ADDRESS, "li r3", resolve).is_err());
+        assert!(encode(ADDRESS, "b far_away", resolve).is_err());
+        assert!(encode(ADDRESS, "b 0x80004002", resolve).is_err());
+        assert!(encode(ADDRESS, "frobnicate r3", resolve).is_err());
+    }
+}
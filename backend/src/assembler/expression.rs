@@ -0,0 +1,224 @@
+//! Evaluates the arithmetic expressions used as operands, like
+//! `my_table@ha`, `(end - start) / 4` or `[symbol with spaces] + 0x10`.
+//!
+//! The operators and their precedence follow C, with comparisons and logical
+//! operators evaluating to 1 or 0. `.` is the address of the
+//! current instruction. `@h`, `@ha` and `@l` select the high half, the high
+//! half adjusted for a signed low half and the low half of a value. All
+//! three are sign extended, so they fit into signed and unsigned immediates.
+
+use failure::{Error, ResultExt};
+
+use super::parse_i64_literal;
+
+pub fn evaluate<F>(text: &str, program_counter: u32, resolve_symbol: &F) -> Result<i64, Error>
+where
+    F: Fn(&str) -> Result<u32, Error>,
+{
+    let mut parser = Parser {
+        text,
+        position: 0,
+        program_counter,
+        resolve_symbol,
+    };
+    let value = parser.binary(0)?;
+    parser.skip_whitespace();
+    ensure!(
+        parser.position == text.len(),
+        "Unexpected \"{}\" in the expression",
+        &text[parser.position..]
+    );
+    Ok(value)
+}
+
+/// The binary operators from the lowest to the highest precedence.
+const LEVELS: &[&[&str]] = &[
+    &["||"],
+    &["&&"],
+    &["|"],
+    &["^"],
+    &["&"],
+    &["==", "!="],
+    &["<", "<=", ">", ">="],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct Parser<'a, F: 'a> {
+    text: &'a str,
+    position: usize,
+    program_counter: u32,
+    resolve_symbol: &'a F,
+}
+
+impl<'a, F> Parser<'a, F>
+where
+    F: Fn(&str) -> Result<u32, Error>,
+{
+    fn rest(&self) -> &'a str {
+        &self.text[self.position..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.position += rest.len() - rest.trim_left().len();
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.rest().starts_with(token) {
+            self.position += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Finds the longest binary operator at the current position, so `||`
+    /// isn't mistaken for `|`.
+    fn peek_operator(&mut self) -> Option<&'static str> {
+        self.skip_whitespace();
+        let rest = self.rest();
+        LEVELS
+            .iter()
+            .flat_map(|operators| operators.iter())
+            .filter(|o| rest.starts_with(*o))
+            .max_by_key(|o| o.len())
+            .cloned()
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, Error> {
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+
+        let mut value = self.binary(level + 1)?;
+        loop {
+            let operator = match self.peek_operator() {
+                Some(operator) if LEVELS[level].contains(&operator) => operator,
+                _ => return Ok(value),
+            };
+            self.position += operator.len();
+            let rhs = self.binary(level + 1)?;
+            value = match operator {
+                "||" => (value != 0 || rhs != 0) as i64,
+                "&&" => (value != 0 && rhs != 0) as i64,
+                "|" => value | rhs,
+                "^" => value ^ rhs,
+                "&" => value & rhs,
+                "==" => (value == rhs) as i64,
+                "!=" => (value != rhs) as i64,
+                "<" => (value < rhs) as i64,
+                "<=" => (value <= rhs) as i64,
+                ">" => (value > rhs) as i64,
+                ">=" => (value >= rhs) as i64,
+                "<<" => value.wrapping_shl(rhs as u32),
+                ">>" => value.wrapping_shr(rhs as u32),
+                "+" => value.wrapping_add(rhs),
+                "-" => value.wrapping_sub(rhs),
+                "*" => value.wrapping_mul(rhs),
+                _ => {
+                    ensure!(rhs != 0, "Division by zero");
+                    if operator == "/" {
+                        value.wrapping_div(rhs)
+                    } else {
+                        value.wrapping_rem(rhs)
+                    }
+                }
+            };
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, Error> {
+        if self.eat("-") {
+            Ok(self.unary()?.wrapping_neg())
+        } else if self.eat("~") {
+            Ok(!self.unary()?)
+        } else if self.eat("!") {
+            Ok((self.unary()? == 0) as i64)
+        } else if self.eat("+") {
+            self.unary()
+        } else {
+            let value = self.primary()?;
+            self.relocation(value)
+        }
+    }
+
+    fn relocation(&mut self, value: i64) -> Result<i64, Error> {
+        // The longer operator needs to be checked first
+        Ok(if self.eat("@ha") {
+            (((value + 0x8000) >> 16) as u16 as i16).into()
+        } else if self.eat("@h") {
+            ((value >> 16) as u16 as i16).into()
+        } else if self.eat("@l") {
+            (value as u16 as i16).into()
+        } else {
+            value
+        })
+    }
+
+    fn primary(&mut self) -> Result<i64, Error> {
+        self.skip_whitespace();
+        let rest = self.rest();
+        let first = rest
+            .chars()
+            .next()
+            .ok_or_else(|| format_err!("Expected an integer literal or a symbol"))?;
+
+        if first == '(' {
+            self.position += 1;
+            let value = self.binary(0)?;
+            ensure!(self.eat(")"), "Expected a closing parenthesis");
+            Ok(value)
+        } else if first == '[' {
+            // Brackets allow for symbols that contain any character
+            let mut open_count = 0;
+            let len = rest
+                .char_indices()
+                .find(|&(_, c)| {
+                    match c {
+                        '[' => open_count += 1,
+                        ']' => open_count -= 1,
+                        _ => {}
+                    }
+                    open_count == 0
+                })
+                .map(|(i, _)| i)
+                .ok_or_else(|| format_err!("Expected a closing bracket"))?;
+            self.position += len + 1;
+            self.symbol(&rest[1..len])
+        } else if first.is_digit(10) {
+            let len = token_len(rest);
+            self.position += len;
+            let literal = &rest[..len];
+            Ok(parse_i64_literal(literal)
+                .with_context(|_| format!("Invalid integer literal \"{}\"", literal))?)
+        } else if is_symbol_char(first) {
+            let len = token_len(rest);
+            self.position += len;
+            match &rest[..len] {
+                "." => Ok(self.program_counter.into()),
+                symbol => self.symbol(symbol),
+            }
+        } else {
+            bail!(
+                "Expected an integer literal or a symbol, but found \"{}\"",
+                rest
+            )
+        }
+    }
+
+    fn symbol(&self, symbol: &str) -> Result<i64, Error> {
+        Ok((self.resolve_symbol)(symbol)?.into())
+    }
+}
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.' || c == '$'
+}
+
+fn token_len(text: &str) -> usize {
+    text.find(|c| !is_symbol_char(c))
+        .unwrap_or_else(|| text.len())
+}
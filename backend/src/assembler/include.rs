@@ -0,0 +1,133 @@
+//! Resolves `.include "common.s"` directives by inlining the included files,
+//! so headers with register aliases, constants and macros can be shared
+//! between patch files. Paths are relative to the project root.
+
+use super::Location;
+use failure::{Error, ResultExt};
+use file_source::FileSource;
+use std::path::{Component, Path, PathBuf};
+
+/// Reads the assembly file and recursively inlines all the files it
+/// includes.
+pub fn read_with_includes<F: FileSource>(files: &mut F, path: &Path) -> Result<String, Error> {
+    Ok(read_with_locations(files, path)?.0)
+}
+
+/// Reads the assembly file like `read_with_includes`, but also returns the
+/// file and line that each of the lines originates from.
+pub fn read_with_locations<F: FileSource>(
+    files: &mut F,
+    path: &Path,
+) -> Result<(String, Vec<Location>), Error> {
+    let mut source = String::new();
+    let mut locations = Vec::new();
+    inline(
+        files,
+        path,
+        &mut Vec::new(),
+        &mut Vec::new(),
+        &mut source,
+        &mut locations,
+    )?;
+    Ok((source, locations))
+}
+
+/// Lists the assembly file and all the files it recursively includes.
+pub fn source_files<F: FileSource>(files: &mut F, path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut visited = Vec::new();
+    inline(
+        files,
+        path,
+        &mut Vec::new(),
+        &mut visited,
+        &mut String::new(),
+        &mut Vec::new(),
+    )?;
+    Ok(visited)
+}
+
+fn inline<F: FileSource>(
+    files: &mut F,
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    visited: &mut Vec<PathBuf>,
+    source: &mut String,
+    locations: &mut Vec<Location>,
+) -> Result<(), Error> {
+    // The same file may be included through different paths
+    let path = &normalize(path);
+    if stack.iter().any(|p| p == path) {
+        bail!(
+            "\"{}\" is included by itself through {}",
+            path.display(),
+            stack
+                .iter()
+                .map(|p| format!("\"{}\"", p.display()))
+                .collect::<Vec<_>>()
+                .join(" -> ")
+        );
+    }
+
+    let text = files
+        .read_to_string(path)
+        .with_context(|_| format!("Couldn't read the assembly file \"{}\"", path.display()))?;
+    stack.push(path.to_owned());
+    visited.push(path.to_owned());
+
+    for (index, line) in text.lines().enumerate() {
+        let code = line.split(';').next().unwrap_or_default().trim();
+        if code.starts_with(".include") && code[".include".len()..].starts_with(char::is_whitespace)
+        {
+            let included = code[".include".len()..].trim();
+            ensure!(
+                included.len() >= 2 && included.starts_with('"') && included.ends_with('"'),
+                "Expected a quoted path after .include in \"{}\"",
+                path.display()
+            );
+            inline(
+                files,
+                Path::new(&included[1..included.len() - 1]),
+                stack,
+                visited,
+                source,
+                locations,
+            )
+            .with_context(|_| format!("Couldn't include a file in \"{}\"", path.display()))?;
+        } else {
+            source.push_str(line);
+            source.push('\n');
+            locations.push(Location {
+                path: path.to_owned(),
+                line: index + 1,
+            });
+        }
+    }
+
+    stack.pop();
+
+    Ok(())
+}
+
+/// Resolves the `.` and `..` components of the path without accessing the
+/// file system, as the files may also come from a patch.
+fn normalize(path: &Path) -> PathBuf {
+    let mut components = Vec::new();
+    for component in path.components() {
+        if component != Component::ParentDir {
+            if component != Component::CurDir {
+                components.push(component);
+            }
+            continue;
+        }
+        let last = components.last().cloned();
+        match last {
+            Some(Component::Normal(_)) => {
+                components.pop();
+            }
+            // There's nothing above the root
+            Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+            _ => components.push(component),
+        }
+    }
+    components.iter().map(|c| c.as_os_str()).collect()
+}
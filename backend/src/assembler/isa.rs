@@ -0,0 +1,457 @@
+//! The instruction set of the Gekko and Broadway processors, shared by the
+//! assembler and the disassembler. Every form describes the fixed bits of an
+//! instruction and where its operands are stored.
+
+pub const RC: u8 = 1 << 0;
+pub const OE: u8 = 1 << 1;
+pub const LK: u8 = 1 << 2;
+pub const AA: u8 = 1 << 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Operand {
+    /// A general purpose register stored at the given shift.
+    Gpr(u8),
+    /// The source register of `mr` and `not`, stored both as rS and rB.
+    GprPair,
+    /// A floating point register stored at the given shift.
+    Fpr(u8),
+    /// A condition register field stored at the given shift.
+    Crf(u8),
+    /// A condition register field that is left out when it's `cr0`.
+    OptCrf(u8),
+    /// A condition register bit stored at the given shift.
+    Crb(u8),
+    /// A signed 16-bit immediate.
+    Simm,
+    /// An unsigned 16-bit immediate.
+    Uimm,
+    /// A signed 16-bit displacement and its base register, `d(rA)`.
+    Offset,
+    /// A signed 12-bit displacement and its base register, as used by the
+    /// paired single loads and stores.
+    PsOffset,
+    /// An unsigned immediate with the given shift and number of bits.
+    Imm(u8, u8),
+    /// A special purpose register.
+    Spr,
+    /// A time base register.
+    Tbr,
+    /// The 24-bit target of `b`.
+    BranchTarget,
+    /// The 14-bit target of `bc`.
+    CondTarget,
+}
+
+#[derive(Debug)]
+pub struct Form {
+    pub mnemonic: &'static str,
+    pub bits: u32,
+    pub mask: u32,
+    pub operands: &'static [Operand],
+    pub flags: u8,
+}
+
+impl Form {
+    pub fn matches(&self, word: u32) -> bool {
+        word & self.mask == self.bits
+            && (!self.operands.contains(&Operand::GprPair)
+                || (word >> 21) & 0x1F == (word >> 11) & 0x1F)
+    }
+}
+
+pub const SPRS: &[(u32, &str)] = &[
+    (1, "xer"),
+    (8, "lr"),
+    (9, "ctr"),
+    (18, "dsisr"),
+    (19, "dar"),
+    (22, "dec"),
+    (25, "sdr1"),
+    (26, "srr0"),
+    (27, "srr1"),
+    (272, "sprg0"),
+    (273, "sprg1"),
+    (274, "sprg2"),
+    (275, "sprg3"),
+    (282, "ear"),
+    (287, "pvr"),
+    (528, "ibat0u"),
+    (529, "ibat0l"),
+    (530, "ibat1u"),
+    (531, "ibat1l"),
+    (532, "ibat2u"),
+    (533, "ibat2l"),
+    (534, "ibat3u"),
+    (535, "ibat3l"),
+    (536, "dbat0u"),
+    (537, "dbat0l"),
+    (538, "dbat1u"),
+    (539, "dbat1l"),
+    (540, "dbat2u"),
+    (541, "dbat2l"),
+    (542, "dbat3u"),
+    (543, "dbat3l"),
+    (912, "gqr0"),
+    (913, "gqr1"),
+    (914, "gqr2"),
+    (915, "gqr3"),
+    (916, "gqr4"),
+    (917, "gqr5"),
+    (918, "gqr6"),
+    (919, "gqr7"),
+    (920, "hid2"),
+    (921, "wpar"),
+    (922, "dma_u"),
+    (923, "dma_l"),
+    (936, "ummcr0"),
+    (937, "upmc1"),
+    (938, "upmc2"),
+    (939, "usia"),
+    (940, "ummcr1"),
+    (941, "upmc3"),
+    (942, "upmc4"),
+    (952, "mmcr0"),
+    (953, "pmc1"),
+    (954, "pmc2"),
+    (955, "sia"),
+    (956, "mmcr1"),
+    (957, "pmc3"),
+    (958, "pmc4"),
+    (1008, "hid0"),
+    (1009, "hid1"),
+    (1010, "iabr"),
+    (1013, "dabr"),
+    (1017, "l2cr"),
+    (1019, "ictc"),
+    (1020, "thrm1"),
+    (1021, "thrm2"),
+    (1022, "thrm3"),
+];
+
+/// The special purpose register field stores both halves of the number
+/// swapped, so swapping them again decodes the field.
+pub fn swap_spr(spr: u32) -> u32 {
+    ((spr & 0x1F) << 5) | ((spr >> 5) & 0x1F)
+}
+
+macro_rules! op {
+    ($primary:expr) => {
+        ($primary as u32) << 26
+    };
+}
+
+/// An instruction with an extended opcode in the low bits.
+macro_rules! xo {
+    ($primary:expr, $xo:expr) => {
+        op!($primary) | (($xo as u32) << 1)
+    };
+}
+
+macro_rules! form {
+    ($mnemonic:expr, $bits:expr, $mask:expr, [$($operand:expr),*], $flags:expr) => {
+        Form {
+            mnemonic: $mnemonic,
+            bits: $bits,
+            // The bits controlled by the flags are never part of the mask
+            mask: $mask
+                & !(((($flags & (RC | LK)) != 0) as u32)
+                    | (((($flags & AA) != 0) as u32) << 1)
+                    | (((($flags & OE) != 0) as u32) << 10)),
+            operands: &[$($operand),*],
+            flags: $flags,
+        }
+    };
+}
+
+use self::Operand::*;
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+pub static FORMS: &[Form] = &[
+    // Simplified mnemonics come first, so they are preferred when disassembling
+    form!("nop", 0x6000_0000, 0xFFFF_FFFF, [], 0),
+    form!("li", op!(14), 0xFC1F_0000, [Gpr(21), Simm], 0),
+    form!("lis", op!(15), 0xFC1F_0000, [Gpr(21), Uimm], 0),
+    form!("mr", xo!(31, 444), 0xFC00_07FF, [Gpr(16), GprPair], RC),
+    form!("not", xo!(31, 124), 0xFC00_07FF, [Gpr(16), GprPair], RC),
+    form!("mtcr", xo!(31, 144) | 0x000F_F000, 0xFC0F_F7FF, [Gpr(21)], 0),
+    form!("mfxer", xo!(31, 339) | 0x10000, 0xFC1F_FFFF, [Gpr(21)], 0),
+    form!("mtxer", xo!(31, 467) | 0x10000, 0xFC1F_FFFF, [Gpr(21)], 0),
+    form!("mflr", xo!(31, 339) | 0x80000, 0xFC1F_FFFF, [Gpr(21)], 0),
+    form!("mtlr", xo!(31, 467) | 0x80000, 0xFC1F_FFFF, [Gpr(21)], 0),
+    form!("mfctr", xo!(31, 339) | 0x90000, 0xFC1F_FFFF, [Gpr(21)], 0),
+    form!("mtctr", xo!(31, 467) | 0x90000, 0xFC1F_FFFF, [Gpr(21)], 0),
+    form!("cmpw", xo!(31, 0), 0xFC60_07FF, [OptCrf(23), Gpr(16), Gpr(11)], 0),
+    form!("cmplw", xo!(31, 32), 0xFC60_07FF, [OptCrf(23), Gpr(16), Gpr(11)], 0),
+    form!("cmpwi", op!(11), 0xFC60_0000, [OptCrf(23), Gpr(16), Simm], 0),
+    form!("cmplwi", op!(10), 0xFC60_0000, [OptCrf(23), Gpr(16), Uimm], 0),
+    form!("clrlwi", op!(21) | 0x3E, 0xFC00_F83F, [Gpr(16), Gpr(21), Imm(6, 5)], RC),
+    form!("rotlwi", op!(21) | 0x3E, 0xFC00_07FF, [Gpr(16), Gpr(21), Imm(11, 5)], RC),
+    form!("blr", 0x4E80_0020, 0xFFFF_FFFF, [], LK),
+    form!("bctr", 0x4E80_0420, 0xFFFF_FFFF, [], LK),
+    form!("blt", op!(16) | (12 << 21) | (0 << 16), 0xFFE3_0000, [OptCrf(18), CondTarget], LK | AA),
+    form!("bge", op!(16) | (4 << 21) | (0 << 16), 0xFFE3_0000, [OptCrf(18), CondTarget], LK | AA),
+    form!("bgt", op!(16) | (12 << 21) | (1 << 16), 0xFFE3_0000, [OptCrf(18), CondTarget], LK | AA),
+    form!("ble", op!(16) | (4 << 21) | (1 << 16), 0xFFE3_0000, [OptCrf(18), CondTarget], LK | AA),
+    form!("beq", op!(16) | (12 << 21) | (2 << 16), 0xFFE3_0000, [OptCrf(18), CondTarget], LK | AA),
+    form!("bne", op!(16) | (4 << 21) | (2 << 16), 0xFFE3_0000, [OptCrf(18), CondTarget], LK | AA),
+    form!("bso", op!(16) | (12 << 21) | (3 << 16), 0xFFE3_0000, [OptCrf(18), CondTarget], LK | AA),
+    form!("bns", op!(16) | (4 << 21) | (3 << 16), 0xFFE3_0000, [OptCrf(18), CondTarget], LK | AA),
+    form!("bltlr", xo!(19, 16) | (12 << 21) | (0 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("bgelr", xo!(19, 16) | (4 << 21) | (0 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("bgtlr", xo!(19, 16) | (12 << 21) | (1 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("blelr", xo!(19, 16) | (4 << 21) | (1 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("beqlr", xo!(19, 16) | (12 << 21) | (2 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("bnelr", xo!(19, 16) | (4 << 21) | (2 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("bsolr", xo!(19, 16) | (12 << 21) | (3 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("bnslr", xo!(19, 16) | (4 << 21) | (3 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("bltctr", xo!(19, 528) | (12 << 21) | (0 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("bgectr", xo!(19, 528) | (4 << 21) | (0 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("bgtctr", xo!(19, 528) | (12 << 21) | (1 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("blectr", xo!(19, 528) | (4 << 21) | (1 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("beqctr", xo!(19, 528) | (12 << 21) | (2 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("bnectr", xo!(19, 528) | (4 << 21) | (2 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("bsoctr", xo!(19, 528) | (12 << 21) | (3 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("bnsctr", xo!(19, 528) | (4 << 21) | (3 << 16), 0xFFE3_07FF, [OptCrf(18)], LK),
+    form!("bdnz", op!(16) | (16 << 21), 0xFFE0_0000, [CondTarget], LK | AA),
+    form!("bdz", op!(16) | (18 << 21), 0xFFE0_0000, [CondTarget], LK | AA),
+    form!("bdnzlr", xo!(19, 16) | (16 << 21), 0xFFE0_07FF, [], LK),
+    form!("bdzlr", xo!(19, 16) | (18 << 21), 0xFFE0_07FF, [], LK),
+
+    // Integer arithmetic and logic
+    form!("twi", op!(3), 0xFC00_0000, [Imm(21, 5), Gpr(16), Simm], 0),
+    form!("mulli", op!(7), 0xFC00_0000, [Gpr(21), Gpr(16), Simm], 0),
+    form!("subfic", op!(8), 0xFC00_0000, [Gpr(21), Gpr(16), Simm], 0),
+    form!("cmpli", op!(10), 0xFC40_0000, [Crf(23), Imm(21, 1), Gpr(16), Uimm], 0),
+    form!("cmpi", op!(11), 0xFC40_0000, [Crf(23), Imm(21, 1), Gpr(16), Simm], 0),
+    form!("addic", op!(12), 0xFC00_0000, [Gpr(21), Gpr(16), Simm], 0),
+    form!("addic.", op!(13), 0xFC00_0000, [Gpr(21), Gpr(16), Simm], 0),
+    form!("addi", op!(14), 0xFC00_0000, [Gpr(21), Gpr(16), Simm], 0),
+    form!("addis", op!(15), 0xFC00_0000, [Gpr(21), Gpr(16), Simm], 0),
+    form!("rlwimi", op!(20), 0xFC00_0000, [Gpr(16), Gpr(21), Imm(11, 5), Imm(6, 5), Imm(1, 5)], RC),
+    form!("rlwinm", op!(21), 0xFC00_0000, [Gpr(16), Gpr(21), Imm(11, 5), Imm(6, 5), Imm(1, 5)], RC),
+    form!("rlwnm", op!(23), 0xFC00_0000, [Gpr(16), Gpr(21), Gpr(11), Imm(6, 5), Imm(1, 5)], RC),
+    form!("ori", op!(24), 0xFC00_0000, [Gpr(16), Gpr(21), Uimm], 0),
+    form!("oris", op!(25), 0xFC00_0000, [Gpr(16), Gpr(21), Uimm], 0),
+    form!("xori", op!(26), 0xFC00_0000, [Gpr(16), Gpr(21), Uimm], 0),
+    form!("xoris", op!(27), 0xFC00_0000, [Gpr(16), Gpr(21), Uimm], 0),
+    form!("andi.", op!(28), 0xFC00_0000, [Gpr(16), Gpr(21), Uimm], 0),
+    form!("andis.", op!(29), 0xFC00_0000, [Gpr(16), Gpr(21), Uimm], 0),
+    form!("subfc", xo!(31, 8), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], OE | RC),
+    form!("addc", xo!(31, 10), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], OE | RC),
+    form!("subf", xo!(31, 40), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], OE | RC),
+    form!("subfe", xo!(31, 136), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], OE | RC),
+    form!("adde", xo!(31, 138), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], OE | RC),
+    form!("mullw", xo!(31, 235), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], OE | RC),
+    form!("add", xo!(31, 266), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], OE | RC),
+    form!("divwu", xo!(31, 459), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], OE | RC),
+    form!("divw", xo!(31, 491), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], OE | RC),
+    form!("mulhwu", xo!(31, 11), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], RC),
+    form!("mulhw", xo!(31, 75), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], RC),
+    form!("neg", xo!(31, 104), 0xFC00_07FF, [Gpr(21), Gpr(16)], OE | RC),
+    form!("subfze", xo!(31, 200), 0xFC00_07FF, [Gpr(21), Gpr(16)], OE | RC),
+    form!("addze", xo!(31, 202), 0xFC00_07FF, [Gpr(21), Gpr(16)], OE | RC),
+    form!("subfme", xo!(31, 232), 0xFC00_07FF, [Gpr(21), Gpr(16)], OE | RC),
+    form!("addme", xo!(31, 234), 0xFC00_07FF, [Gpr(21), Gpr(16)], OE | RC),
+    form!("cmp", xo!(31, 0), 0xFC40_07FF, [Crf(23), Imm(21, 1), Gpr(16), Gpr(11)], 0),
+    form!("cmpl", xo!(31, 32), 0xFC40_07FF, [Crf(23), Imm(21, 1), Gpr(16), Gpr(11)], 0),
+    form!("tw", xo!(31, 4), 0xFC00_07FF, [Imm(21, 5), Gpr(16), Gpr(11)], 0),
+    form!("slw", xo!(31, 24), 0xFC00_07FF, [Gpr(16), Gpr(21), Gpr(11)], RC),
+    form!("and", xo!(31, 28), 0xFC00_07FF, [Gpr(16), Gpr(21), Gpr(11)], RC),
+    form!("andc", xo!(31, 60), 0xFC00_07FF, [Gpr(16), Gpr(21), Gpr(11)], RC),
+    form!("nor", xo!(31, 124), 0xFC00_07FF, [Gpr(16), Gpr(21), Gpr(11)], RC),
+    form!("eqv", xo!(31, 284), 0xFC00_07FF, [Gpr(16), Gpr(21), Gpr(11)], RC),
+    form!("xor", xo!(31, 316), 0xFC00_07FF, [Gpr(16), Gpr(21), Gpr(11)], RC),
+    form!("orc", xo!(31, 412), 0xFC00_07FF, [Gpr(16), Gpr(21), Gpr(11)], RC),
+    form!("or", xo!(31, 444), 0xFC00_07FF, [Gpr(16), Gpr(21), Gpr(11)], RC),
+    form!("nand", xo!(31, 476), 0xFC00_07FF, [Gpr(16), Gpr(21), Gpr(11)], RC),
+    form!("srw", xo!(31, 536), 0xFC00_07FF, [Gpr(16), Gpr(21), Gpr(11)], RC),
+    form!("sraw", xo!(31, 792), 0xFC00_07FF, [Gpr(16), Gpr(21), Gpr(11)], RC),
+    form!("cntlzw", xo!(31, 26), 0xFC00_07FF, [Gpr(16), Gpr(21)], RC),
+    form!("extsh", xo!(31, 922), 0xFC00_07FF, [Gpr(16), Gpr(21)], RC),
+    form!("extsb", xo!(31, 954), 0xFC00_07FF, [Gpr(16), Gpr(21)], RC),
+    form!("srawi", xo!(31, 824), 0xFC00_07FF, [Gpr(16), Gpr(21), Imm(11, 5)], RC),
+
+    // Loads and stores
+    form!("lwz", op!(32), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("lwzu", op!(33), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("lbz", op!(34), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("lbzu", op!(35), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("stw", op!(36), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("stwu", op!(37), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("stb", op!(38), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("stbu", op!(39), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("lhz", op!(40), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("lhzu", op!(41), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("lha", op!(42), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("lhau", op!(43), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("sth", op!(44), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("sthu", op!(45), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("lmw", op!(46), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("stmw", op!(47), 0xFC00_0000, [Gpr(21), Offset], 0),
+    form!("lfs", op!(48), 0xFC00_0000, [Fpr(21), Offset], 0),
+    form!("lfsu", op!(49), 0xFC00_0000, [Fpr(21), Offset], 0),
+    form!("lfd", op!(50), 0xFC00_0000, [Fpr(21), Offset], 0),
+    form!("lfdu", op!(51), 0xFC00_0000, [Fpr(21), Offset], 0),
+    form!("stfs", op!(52), 0xFC00_0000, [Fpr(21), Offset], 0),
+    form!("stfsu", op!(53), 0xFC00_0000, [Fpr(21), Offset], 0),
+    form!("stfd", op!(54), 0xFC00_0000, [Fpr(21), Offset], 0),
+    form!("stfdu", op!(55), 0xFC00_0000, [Fpr(21), Offset], 0),
+    form!("lwarx", xo!(31, 20), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lwzx", xo!(31, 23), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lwzux", xo!(31, 55), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lbzx", xo!(31, 87), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lbzux", xo!(31, 119), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("stwx", xo!(31, 151), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("stwux", xo!(31, 183), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("stbx", xo!(31, 215), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("stbux", xo!(31, 247), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lhzx", xo!(31, 279), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lhzux", xo!(31, 311), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lhax", xo!(31, 343), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lhaux", xo!(31, 375), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("sthx", xo!(31, 407), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("sthux", xo!(31, 439), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lswx", xo!(31, 533), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lwbrx", xo!(31, 534), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("stswx", xo!(31, 661), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("stwbrx", xo!(31, 662), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lhbrx", xo!(31, 790), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("sthbrx", xo!(31, 918), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("eciwx", xo!(31, 310), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("ecowx", xo!(31, 438), 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("stwcx.", xo!(31, 150) | 1, 0xFC00_07FF, [Gpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lfsx", xo!(31, 535), 0xFC00_07FF, [Fpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lfsux", xo!(31, 567), 0xFC00_07FF, [Fpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lfdx", xo!(31, 599), 0xFC00_07FF, [Fpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lfdux", xo!(31, 631), 0xFC00_07FF, [Fpr(21), Gpr(16), Gpr(11)], 0),
+    form!("stfsx", xo!(31, 663), 0xFC00_07FF, [Fpr(21), Gpr(16), Gpr(11)], 0),
+    form!("stfsux", xo!(31, 695), 0xFC00_07FF, [Fpr(21), Gpr(16), Gpr(11)], 0),
+    form!("stfdx", xo!(31, 727), 0xFC00_07FF, [Fpr(21), Gpr(16), Gpr(11)], 0),
+    form!("stfdux", xo!(31, 759), 0xFC00_07FF, [Fpr(21), Gpr(16), Gpr(11)], 0),
+    form!("stfiwx", xo!(31, 983), 0xFC00_07FF, [Fpr(21), Gpr(16), Gpr(11)], 0),
+    form!("lswi", xo!(31, 597), 0xFC00_07FF, [Gpr(21), Gpr(16), Imm(11, 5)], 0),
+    form!("stswi", xo!(31, 725), 0xFC00_07FF, [Gpr(21), Gpr(16), Imm(11, 5)], 0),
+
+    // Branches and the condition register
+    form!("bc", op!(16), 0xFC00_0000, [Imm(21, 5), Imm(16, 5), CondTarget], LK | AA),
+    form!("sc", 0x4400_0002, 0xFFFF_FFFF, [], 0),
+    form!("b", op!(18), 0xFC00_0000, [BranchTarget], LK | AA),
+    form!("mcrf", xo!(19, 0), 0xFC00_07FF, [Crf(23), Crf(18)], 0),
+    form!("bclr", xo!(19, 16), 0xFC00_07FF, [Imm(21, 5), Imm(16, 5)], LK),
+    form!("bcctr", xo!(19, 528), 0xFC00_07FF, [Imm(21, 5), Imm(16, 5)], LK),
+    form!("crnor", xo!(19, 33), 0xFC00_07FF, [Crb(21), Crb(16), Crb(11)], 0),
+    form!("crandc", xo!(19, 129), 0xFC00_07FF, [Crb(21), Crb(16), Crb(11)], 0),
+    form!("crxor", xo!(19, 193), 0xFC00_07FF, [Crb(21), Crb(16), Crb(11)], 0),
+    form!("crnand", xo!(19, 225), 0xFC00_07FF, [Crb(21), Crb(16), Crb(11)], 0),
+    form!("crand", xo!(19, 257), 0xFC00_07FF, [Crb(21), Crb(16), Crb(11)], 0),
+    form!("creqv", xo!(19, 289), 0xFC00_07FF, [Crb(21), Crb(16), Crb(11)], 0),
+    form!("crorc", xo!(19, 417), 0xFC00_07FF, [Crb(21), Crb(16), Crb(11)], 0),
+    form!("cror", xo!(19, 449), 0xFC00_07FF, [Crb(21), Crb(16), Crb(11)], 0),
+    form!("rfi", xo!(19, 50), 0xFFFF_FFFF, [], 0),
+    form!("isync", xo!(19, 150), 0xFFFF_FFFF, [], 0),
+    form!("mcrxr", xo!(31, 512), 0xFC00_07FF, [Crf(23)], 0),
+    form!("mfcr", xo!(31, 19), 0xFC00_07FF, [Gpr(21)], 0),
+    form!("mtcrf", xo!(31, 144), 0xFC00_07FF, [Imm(12, 8), Gpr(21)], 0),
+
+    // System registers, caches and synchronization
+    form!("mfmsr", xo!(31, 83), 0xFC00_07FF, [Gpr(21)], 0),
+    form!("mtmsr", xo!(31, 146), 0xFC00_07FF, [Gpr(21)], 0),
+    form!("mfspr", xo!(31, 339), 0xFC00_07FF, [Gpr(21), Spr], 0),
+    form!("mtspr", xo!(31, 467), 0xFC00_07FF, [Spr, Gpr(21)], 0),
+    form!("mftb", xo!(31, 371), 0xFC00_07FF, [Gpr(21), Tbr], 0),
+    form!("mfsr", xo!(31, 595), 0xFC00_07FF, [Gpr(21), Imm(16, 4)], 0),
+    form!("mtsr", xo!(31, 210), 0xFC00_07FF, [Imm(16, 4), Gpr(21)], 0),
+    form!("mfsrin", xo!(31, 659), 0xFC00_07FF, [Gpr(21), Gpr(11)], 0),
+    form!("mtsrin", xo!(31, 242), 0xFC00_07FF, [Gpr(21), Gpr(11)], 0),
+    form!("dcbst", xo!(31, 54), 0xFC00_07FF, [Gpr(16), Gpr(11)], 0),
+    form!("dcbf", xo!(31, 86), 0xFC00_07FF, [Gpr(16), Gpr(11)], 0),
+    form!("dcbtst", xo!(31, 246), 0xFC00_07FF, [Gpr(16), Gpr(11)], 0),
+    form!("dcbt", xo!(31, 278), 0xFC00_07FF, [Gpr(16), Gpr(11)], 0),
+    form!("dcbi", xo!(31, 470), 0xFC00_07FF, [Gpr(16), Gpr(11)], 0),
+    form!("icbi", xo!(31, 982), 0xFC00_07FF, [Gpr(16), Gpr(11)], 0),
+    form!("dcbz", xo!(31, 1014), 0xFC00_07FF, [Gpr(16), Gpr(11)], 0),
+    form!("tlbie", xo!(31, 306), 0xFC00_07FF, [Gpr(11)], 0),
+    form!("tlbsync", xo!(31, 566), 0xFFFF_FFFF, [], 0),
+    form!("sync", xo!(31, 598), 0xFFFF_FFFF, [], 0),
+    form!("eieio", xo!(31, 854), 0xFFFF_FFFF, [], 0),
+
+    // Floating point
+    form!("fdivs", xo!(59, 18), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(11)], RC),
+    form!("fsubs", xo!(59, 20), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(11)], RC),
+    form!("fadds", xo!(59, 21), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(11)], RC),
+    form!("fres", xo!(59, 24), 0xFC00_003F, [Fpr(21), Fpr(11)], RC),
+    form!("fmuls", xo!(59, 25), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6)], RC),
+    form!("fmsubs", xo!(59, 28), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("fmadds", xo!(59, 29), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("fnmsubs", xo!(59, 30), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("fnmadds", xo!(59, 31), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("fdiv", xo!(63, 18), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(11)], RC),
+    form!("fsub", xo!(63, 20), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(11)], RC),
+    form!("fadd", xo!(63, 21), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(11)], RC),
+    form!("fsel", xo!(63, 23), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("fmul", xo!(63, 25), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6)], RC),
+    form!("frsqrte", xo!(63, 26), 0xFC00_003F, [Fpr(21), Fpr(11)], RC),
+    form!("fmsub", xo!(63, 28), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("fmadd", xo!(63, 29), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("fnmsub", xo!(63, 30), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("fnmadd", xo!(63, 31), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("fcmpu", xo!(63, 0), 0xFC00_07FF, [Crf(23), Fpr(16), Fpr(11)], 0),
+    form!("fcmpo", xo!(63, 32), 0xFC00_07FF, [Crf(23), Fpr(16), Fpr(11)], 0),
+    form!("frsp", xo!(63, 12), 0xFC00_07FF, [Fpr(21), Fpr(11)], RC),
+    form!("fctiw", xo!(63, 14), 0xFC00_07FF, [Fpr(21), Fpr(11)], RC),
+    form!("fctiwz", xo!(63, 15), 0xFC00_07FF, [Fpr(21), Fpr(11)], RC),
+    form!("fneg", xo!(63, 40), 0xFC00_07FF, [Fpr(21), Fpr(11)], RC),
+    form!("fmr", xo!(63, 72), 0xFC00_07FF, [Fpr(21), Fpr(11)], RC),
+    form!("fnabs", xo!(63, 136), 0xFC00_07FF, [Fpr(21), Fpr(11)], RC),
+    form!("fabs", xo!(63, 264), 0xFC00_07FF, [Fpr(21), Fpr(11)], RC),
+    form!("mtfsb1", xo!(63, 38), 0xFC00_07FF, [Crb(21)], RC),
+    form!("mtfsb0", xo!(63, 70), 0xFC00_07FF, [Crb(21)], RC),
+    form!("mcrfs", xo!(63, 64), 0xFC00_07FF, [Crf(23), Crf(18)], 0),
+    form!("mtfsfi", xo!(63, 134), 0xFC00_07FF, [Crf(23), Imm(12, 4)], RC),
+    form!("mffs", xo!(63, 583), 0xFC00_07FF, [Fpr(21)], RC),
+    form!("mtfsf", xo!(63, 711), 0xFC00_07FF, [Imm(17, 8), Fpr(11)], RC),
+
+    // Paired singles
+    form!("psq_l", op!(56), 0xFC00_0000, [Fpr(21), PsOffset, Imm(15, 1), Imm(12, 3)], 0),
+    form!("psq_lu", op!(57), 0xFC00_0000, [Fpr(21), PsOffset, Imm(15, 1), Imm(12, 3)], 0),
+    form!("psq_st", op!(60), 0xFC00_0000, [Fpr(21), PsOffset, Imm(15, 1), Imm(12, 3)], 0),
+    form!("psq_stu", op!(61), 0xFC00_0000, [Fpr(21), PsOffset, Imm(15, 1), Imm(12, 3)], 0),
+    form!(
+        "psq_lx", xo!(4, 6), 0xFC00_007F, [Fpr(21), Gpr(16), Gpr(11), Imm(10, 1), Imm(7, 3)], 0),
+    form!(
+        "psq_stx", xo!(4, 7), 0xFC00_007F, [Fpr(21), Gpr(16), Gpr(11), Imm(10, 1), Imm(7, 3)], 0),
+    form!(
+        "psq_lux", xo!(4, 38), 0xFC00_007F, [Fpr(21), Gpr(16), Gpr(11), Imm(10, 1), Imm(7, 3)], 0),
+    form!(
+        "psq_stux", xo!(4, 39), 0xFC00_007F, [Fpr(21), Gpr(16), Gpr(11), Imm(10, 1), Imm(7, 3)], 0),
+    form!("ps_cmpu0", xo!(4, 0), 0xFC00_07FF, [Crf(23), Fpr(16), Fpr(11)], 0),
+    form!("ps_cmpo0", xo!(4, 32), 0xFC00_07FF, [Crf(23), Fpr(16), Fpr(11)], 0),
+    form!("ps_cmpu1", xo!(4, 64), 0xFC00_07FF, [Crf(23), Fpr(16), Fpr(11)], 0),
+    form!("ps_cmpo1", xo!(4, 96), 0xFC00_07FF, [Crf(23), Fpr(16), Fpr(11)], 0),
+    form!("ps_div", xo!(4, 18), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(11)], RC),
+    form!("ps_sub", xo!(4, 20), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(11)], RC),
+    form!("ps_add", xo!(4, 21), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(11)], RC),
+    form!("ps_sum0", xo!(4, 10), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("ps_sum1", xo!(4, 11), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("ps_madds0", xo!(4, 14), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("ps_madds1", xo!(4, 15), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("ps_sel", xo!(4, 23), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("ps_msub", xo!(4, 28), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("ps_madd", xo!(4, 29), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("ps_nmsub", xo!(4, 30), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("ps_nmadd", xo!(4, 31), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6), Fpr(11)], RC),
+    form!("ps_muls0", xo!(4, 12), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6)], RC),
+    form!("ps_muls1", xo!(4, 13), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6)], RC),
+    form!("ps_mul", xo!(4, 25), 0xFC00_003F, [Fpr(21), Fpr(16), Fpr(6)], RC),
+    form!("ps_res", xo!(4, 24), 0xFC00_003F, [Fpr(21), Fpr(11)], RC),
+    form!("ps_rsqrte", xo!(4, 26), 0xFC00_003F, [Fpr(21), Fpr(11)], RC),
+    form!("ps_neg", xo!(4, 40), 0xFC00_07FF, [Fpr(21), Fpr(11)], RC),
+    form!("ps_mr", xo!(4, 72), 0xFC00_07FF, [Fpr(21), Fpr(11)], RC),
+    form!("ps_nabs", xo!(4, 136), 0xFC00_07FF, [Fpr(21), Fpr(11)], RC),
+    form!("ps_abs", xo!(4, 264), 0xFC00_07FF, [Fpr(21), Fpr(11)], RC),
+    form!("ps_merge00", xo!(4, 528), 0xFC00_07FF, [Fpr(21), Fpr(16), Fpr(11)], RC),
+    form!("ps_merge01", xo!(4, 560), 0xFC00_07FF, [Fpr(21), Fpr(16), Fpr(11)], RC),
+    form!("ps_merge10", xo!(4, 592), 0xFC00_07FF, [Fpr(21), Fpr(16), Fpr(11)], RC),
+    form!("ps_merge11", xo!(4, 624), 0xFC00_07FF, [Fpr(21), Fpr(16), Fpr(11)], RC),
+    form!("dcbz_l", xo!(4, 1014), 0xFC00_07FF, [Gpr(16), Gpr(11)], 0),
+];
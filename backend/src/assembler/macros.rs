@@ -0,0 +1,356 @@
+//! Expands GAS style `.macro` and `.rept` blocks and evaluates the
+//! conditional directives `.if`, `.ifdef`, `.ifndef`, `.elseif`, `.else` and
+//! `.endif` before the lines are assembled. The conditions can only refer to
+//! the symbols defined for the build.
+//!
+//! ```text
+//! .macro hook address, function
+//! \address:
+//!     bl \function
+//! .endm
+//!
+//! hook 0x80012345, on_frame
+//! ```
+//!
+//! Inside of a macro, `\@` is replaced by a number that is unique to each
+//! expansion, which is useful for labels.
+//!
+//! Code that is written at runtime, like a function the Rom Hack patches
+//! while the game runs, only becomes visible to the console's instruction
+//! cache once it's synchronized, while most emulators run it either way. The
+//! built-in `sync_icache` macro synchronizes the cache line at the address in
+//! a register, while `sync_icache_range` synchronizes the lines from the start
+//! in one register up to the end in another, clobbering the start and cr0.
+//! The start is aligned down to its cache line first. The Rom Hack's own
+//! code is synced automatically when the game starts.
+//!
+//! ```text
+//! stw r4, 0(r3)
+//! sync_icache r3
+//! ```
+
+use super::expression::evaluate;
+use super::parse_u32_literal;
+use failure::{Error, ResultExt};
+use std::collections::HashMap;
+
+/// Macros that expand to themselves would otherwise never stop expanding.
+const MAX_DEPTH: usize = 64;
+
+const BUILTIN_MACROS: &[&str] = &[
+    ".macro sync_icache address",
+    "dcbst r0, \\address",
+    "sync",
+    "icbi r0, \\address",
+    "isync",
+    ".endm",
+    ".macro sync_icache_range start, end",
+    "rlwinm \\start, \\start, 0, 0, 26",
+    ".sync_icache_range\\@:",
+    "dcbst r0, \\start",
+    "sync",
+    "icbi r0, \\start",
+    "addi \\start, \\start, 32",
+    "cmplw \\start, \\end",
+    "blt .sync_icache_range\\@",
+    "isync",
+    ".endm",
+];
+
+struct Macro {
+    parameters: Vec<(String, Option<String>)>,
+    body: Vec<String>,
+}
+
+struct Conditional {
+    is_active: bool,
+    was_taken: bool,
+    in_else: bool,
+    /// The index of the line that opened the conditional.
+    origin: usize,
+}
+
+struct Expander<'a> {
+    defines: &'a HashMap<String, i64>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    /// The index of the line being expanded, so errors can point at it.
+    line: usize,
+}
+
+/// An error of the expansion along with the index of the line it occurred
+/// in, which is the invocation for the lines of macros and repetitions.
+pub struct ExpandError {
+    pub index: usize,
+    pub error: Error,
+}
+
+/// Expands all the macro definitions, invocations, repetitions and
+/// conditionals. The lines are expected to already be stripped of comments.
+/// Each expanded line comes with the index of the line it originates from,
+/// which is the invocation for the lines of macros and repetitions.
+pub fn expand<S: AsRef<str>>(
+    lines: &[S],
+    defines: &HashMap<String, i64>,
+) -> Result<Vec<(usize, String)>, ExpandError> {
+    let mut expander = Expander {
+        defines,
+        macros: HashMap::new(),
+        expansions: 0,
+        line: 0,
+    };
+    let mut output = Vec::new();
+    expander
+        .expand(BUILTIN_MACROS, &mut output, 0, None)
+        .expect("The built-in macros are invalid");
+    expander
+        .expand(lines, &mut output, 0, None)
+        .map_err(|error| ExpandError {
+            index: expander.line,
+            error,
+        })?;
+    Ok(output)
+}
+
+impl<'a> Expander<'a> {
+    fn expand<S: AsRef<str>>(
+        &mut self,
+        lines: &[S],
+        output: &mut Vec<(usize, String)>,
+        depth: usize,
+        origin: Option<usize>,
+    ) -> Result<(), Error> {
+        ensure!(
+            depth < MAX_DEPTH,
+            "Macros are nested more than {} levels deep",
+            MAX_DEPTH
+        );
+
+        let mut conditionals = Vec::<Conditional>::new();
+        let mut index = 0;
+        while index < lines.len() {
+            let line = lines[index].as_ref().trim();
+            let (directive, rest) = split_first_word(line);
+            let line_origin = origin.unwrap_or(index);
+            self.line = line_origin;
+            index += 1;
+
+            let is_active = conditionals.iter().all(|c| c.is_active);
+            match directive {
+                ".if" | ".ifdef" | ".ifndef" => {
+                    let is_true = is_active && self.condition(directive, rest)?;
+                    conditionals.push(Conditional {
+                        is_active: is_true,
+                        // Branches of inactive conditionals are never taken
+                        was_taken: is_true || !is_active,
+                        in_else: false,
+                        origin: line_origin,
+                    });
+                    continue;
+                }
+                ".elseif" | ".else" | ".endif" => {
+                    let is_parent_active = conditionals.iter().rev().skip(1).all(|c| c.is_active);
+                    let conditional = conditionals
+                        .last_mut()
+                        .ok_or_else(|| format_err!("Unexpected \"{}\"", directive))?;
+                    ensure!(
+                        !conditional.in_else || directive == ".endif",
+                        "Unexpected \"{}\" after \".else\"",
+                        directive
+                    );
+                    if directive == ".elseif" {
+                        conditional.is_active = !conditional.was_taken
+                            && is_parent_active
+                            && self.condition(".if", rest)?;
+                        conditional.was_taken |= conditional.is_active;
+                    } else if directive == ".else" {
+                        conditional.is_active = !conditional.was_taken;
+                        conditional.was_taken = true;
+                        conditional.in_else = true;
+                    }
+                    if directive == ".endif" {
+                        conditionals.pop();
+                    }
+                    continue;
+                }
+                _ if !is_active => continue,
+                _ => {}
+            }
+
+            match directive {
+                ".macro" => {
+                    let body = block(lines, &mut index, ".macro", ".endm")?;
+                    let (name, parameters) = split_first_word(rest);
+                    ensure!(!name.is_empty(), "Expected a name for the macro");
+                    let parameters = split_arguments(parameters)
+                        .into_iter()
+                        .map(|p| match p.find('=') {
+                            Some(i) => (p[..i].trim().into(), Some(p[i + 1..].trim().into())),
+                            None => (p.into(), None),
+                        })
+                        .collect();
+                    self.macros
+                        .insert(name.to_string(), Macro { parameters, body });
+                }
+                ".rept" => {
+                    let body = block(lines, &mut index, ".rept", ".endr")?;
+                    let count = parse_u32_literal(rest)
+                        .with_context(|_| format!("Invalid repetition count \"{}\"", rest))?;
+                    for _ in 0..count {
+                        self.expand(&body, output, depth + 1, Some(line_origin))?;
+                    }
+                }
+                ".endm" | ".endr" => bail!("Unexpected \"{}\"", directive),
+                _ => {
+                    if let Some(lines) = self.invoke(directive, rest)? {
+                        self.expand(&lines, output, depth + 1, Some(line_origin))
+                            .with_context(|_| format!("In the macro \"{}\"", directive))?;
+                    } else {
+                        output.push((line_origin, line.to_string()));
+                    }
+                }
+            }
+        }
+
+        if let Some(conditional) = conditionals.last() {
+            self.line = conditional.origin;
+            bail!("Expected \".endif\" to close \".if\"");
+        }
+
+        Ok(())
+    }
+
+    fn condition(&self, directive: &str, condition: &str) -> Result<bool, Error> {
+        Ok(match directive {
+            ".ifdef" => self.defines.contains_key(condition),
+            ".ifndef" => !self.defines.contains_key(condition),
+            _ => {
+                let value = evaluate(condition, 0, &|symbol: &str| {
+                    self.defines
+                        .get(symbol)
+                        .map(|&v| v as u32)
+                        .ok_or_else(|| format_err!("\"{}\" is not defined", symbol))
+                })
+                .with_context(|_| format!("Couldn't evaluate the condition \"{}\"", condition))?;
+                value != 0
+            }
+        })
+    }
+
+    /// Substitutes the arguments into the body of the macro with the given
+    /// name. Returns `None` if there is no such macro.
+    fn invoke(&mut self, name: &str, arguments: &str) -> Result<Option<Vec<String>>, Error> {
+        let definition = match self.macros.get(name) {
+            Some(definition) => definition,
+            None => return Ok(None),
+        };
+
+        let arguments = split_arguments(arguments);
+        ensure!(
+            arguments.len() <= definition.parameters.len(),
+            "The macro \"{}\" takes at most {} arguments, but {} were given",
+            name,
+            definition.parameters.len(),
+            arguments.len()
+        );
+
+        self.expansions += 1;
+        let counter = self.expansions.to_string();
+
+        let mut values = HashMap::new();
+        values.insert("@", &counter[..]);
+        for (index, &(ref parameter, ref default)) in definition.parameters.iter().enumerate() {
+            let value = match arguments.get(index) {
+                Some(&argument) if !argument.is_empty() => argument,
+                _ => default.as_ref().map(|d| &d[..]).ok_or_else(|| {
+                    format_err!(
+                        "The macro \"{}\" is missing the argument \"{}\"",
+                        name,
+                        parameter
+                    )
+                })?,
+            };
+            values.insert(&parameter[..], value);
+        }
+
+        Ok(Some(
+            definition
+                .body
+                .iter()
+                .map(|line| substitute(line, &values))
+                .collect(),
+        ))
+    }
+}
+
+/// Collects the lines up to the end of the block, respecting nested blocks
+/// of the same kind.
+fn block<S: AsRef<str>>(
+    lines: &[S],
+    index: &mut usize,
+    start: &str,
+    end: &str,
+) -> Result<Vec<String>, Error> {
+    let mut nesting = 0;
+    let mut body = Vec::new();
+    while let Some(line) = lines.get(*index) {
+        let line = line.as_ref().trim();
+        *index += 1;
+        let (directive, _) = split_first_word(line);
+        if directive == start {
+            nesting += 1;
+        } else if directive == end {
+            if nesting == 0 {
+                return Ok(body);
+            }
+            nesting -= 1;
+        }
+        body.push(line.to_string());
+    }
+    bail!("Expected \"{}\" to close \"{}\"", end, start)
+}
+
+/// Replaces every `\name` with the value of the argument called `name`.
+fn substitute(line: &str, values: &HashMap<&str, &str>) -> String {
+    let mut output = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(index) = rest.find('\\') {
+        output.push_str(&rest[..index]);
+        rest = &rest[index + 1..];
+        let len = if rest.starts_with('@') {
+            1
+        } else {
+            rest.find(|c: char| !c.is_alphanumeric() && c != '_')
+                .unwrap_or_else(|| rest.len())
+        };
+        match values.get(&rest[..len]) {
+            Some(value) => output.push_str(value),
+            None => {
+                output.push('\\');
+                output.push_str(&rest[..len]);
+            }
+        }
+        rest = &rest[len..];
+        // `\()` separates an argument from text directly following it
+        if rest.starts_with("()") {
+            rest = &rest[2..];
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+fn split_first_word(line: &str) -> (&str, &str) {
+    match line.find(char::is_whitespace) {
+        Some(index) => (&line[..index], line[index..].trim()),
+        None => (line, ""),
+    }
+}
+
+fn split_arguments(arguments: &str) -> Vec<&str> {
+    if arguments.is_empty() {
+        Vec::new()
+    } else {
+        arguments.split(',').map(|a| a.trim()).collect()
+    }
+}
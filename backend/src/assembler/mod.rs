@@ -0,0 +1,1328 @@
+use byteorder::{ByteOrder, BE};
+use failure::{err_msg, Error, ResultExt};
+use port_map::PortMap;
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use syn::{self, synom::ParseError};
+use text_encoding::TextEncoding;
+
+mod disassembler;
+mod data;
+mod diagnostic;
+mod encoder;
+mod expression;
+mod include;
+mod isa;
+mod macros;
+
+pub use self::diagnostic::Location;
+use self::diagnostic::{Cause, Code};
+pub use self::disassembler::{disassemble, is_instruction};
+pub use self::include::{read_with_includes, read_with_locations, source_files};
+
+const NOP: u32 = 0x6000_0000;
+const BLR: u32 = 0x4E80_0020;
+/// Longer ranges of `nop` are most likely a mistake.
+const MAX_NOP_LEN: u32 = 0x10_0000;
+/// The labels usually settle after the second pass, unless the sizes of the
+/// data depend on each other.
+const MAX_LAYOUT_PASSES: usize = 8;
+
+pub struct Assembler<'a> {
+    symbol_table: BTreeMap<&'a str, u32>,
+    prelinked_symbols: &'a HashMap<String, u32>,
+    labels: HashMap<String, u32>,
+    defines: HashMap<String, i64>,
+    sources: BTreeMap<u32, String>,
+    source_locations: BTreeMap<u32, String>,
+    file_instructions: BTreeMap<String, Vec<Instruction>>,
+    far_branches: Vec<FarBranch>,
+    expectations: Vec<Expectation>,
+    injections: Vec<Injection>,
+    replacements: Vec<Replacement>,
+    strings: Vec<StringReplacement>,
+    text_encoding: Option<TextEncoding>,
+    port_map: Option<&'a PortMap>,
+    target: Option<String>,
+    program_counter: u32,
+    /// The lines of the files that the lines being assembled originate from.
+    locations: Vec<Location>,
+    /// Where the line that is being assembled is, like `patch.asm:12`.
+    location: String,
+}
+
+/// A word that a patch writes. Only the bits set in the mask are written, so
+/// data like single bytes and halfwords doesn't overwrite its neighbors.
+pub struct Instruction {
+    pub address: u32,
+    pub data: u32,
+    pub mask: u32,
+}
+
+/// A `b` or `bl` of a patch file whose target is out of the range of a
+/// relative branch. It branches to a veneer that jumps to the target instead.
+pub struct FarBranch {
+    pub address: u32,
+    pub target: u32,
+    pub link: bool,
+}
+
+/// A word that the game needs to contain before it's patched, as stated by
+/// an `.expect` line. This catches patches for other versions of the game.
+/// The file is the one targeted by the address label, if it's not the DOL.
+pub struct Expectation {
+    pub file: Option<String>,
+    pub address: u32,
+    pub value: u32,
+}
+
+/// A block of a patch file like `inject 0x80001234 { ... }`. The game
+/// executes the block right before the instruction at the address. The block
+/// is placed wherever there's space left, so it's only assembled once its
+/// address is known.
+pub struct Injection {
+    pub address: u32,
+    pub len: u32,
+    /// Where the `inject` line that opens the block is, like `patch.asm:12`.
+    pub location: String,
+    lines: Vec<(String, Origin)>,
+    /// The local labels of the block by their offsets into it.
+    labels: HashMap<String, u32>,
+}
+
+/// An inject block before it's laid out, along with the `inject` line that
+/// opens it.
+struct Block {
+    address: String,
+    start: (String, Origin),
+    lines: Vec<(String, Origin)>,
+}
+
+/// The line of a file that a line of assembly originates from, so the errors
+/// of lines that are only assembled later can still point at it.
+#[derive(Clone)]
+struct Origin {
+    source: String,
+    path: Option<PathBuf>,
+    line: usize,
+}
+
+impl Origin {
+    /// Describes where the line is, like `patch.asm:12`.
+    fn describe(&self) -> String {
+        match self.path {
+            Some(ref path) => format!("{}:{}", path.display(), self.line),
+            None => format!("line {}", self.line),
+        }
+    }
+
+    /// Points the error of the assembled code at the line.
+    fn locate(&self, error: Error, code: &str) -> Error {
+        let path = self.path.as_ref().map(|p| &**p);
+        diagnostic::locate(error, &self.source, code, path, self.line)
+    }
+}
+
+/// A function of the game that is replaced by another one, as stated by a
+/// line like `replace OSPanic with my_panic`. Either the function branches to
+/// its replacement right at its start, or only the `b` and `bl` to it are
+/// redirected by `replace calls to OSPanic with my_panic`, which leaves the
+/// function intact, so the replacement can still call it.
+pub struct Replacement {
+    pub function: u32,
+    pub replacement: u32,
+    pub calls_only: bool,
+}
+
+/// A string of the game that is replaced, as stated by a line like
+/// `string 0x803A_1234 "Press Start" pointers 0x8040_1000, 0x8040_1230`. The
+/// data is in the game's text encoding and includes the terminator. A string
+/// that doesn't fit where the original one is gets relocated, which requires
+/// the pointers to it to be listed.
+pub struct StringReplacement {
+    pub address: u32,
+    pub data: Vec<u8>,
+    pub terminator: Vec<u8>,
+    /// The size of the units of the encoding, like 2 bytes for UTF-16.
+    pub unit_len: u32,
+    /// The addresses of the words that point to the string, which get
+    /// rewritten when it's relocated.
+    pub pointers: Vec<u32>,
+}
+
+impl Instruction {
+    /// Applies the instruction to the original word at its address.
+    pub fn apply(&self, original: u32) -> u32 {
+        (original & !self.mask) | (self.data & self.mask)
+    }
+}
+
+impl<'a> Assembler<'a> {
+    pub fn new(
+        symbol_table: BTreeMap<&'a str, u32>,
+        prelinked_symbols: &'a HashMap<String, u32>,
+    ) -> Assembler<'a> {
+        Assembler {
+            symbol_table,
+            prelinked_symbols,
+            labels: HashMap::new(),
+            defines: HashMap::new(),
+            sources: BTreeMap::new(),
+            source_locations: BTreeMap::new(),
+            file_instructions: BTreeMap::new(),
+            far_branches: Vec::new(),
+            expectations: Vec::new(),
+            injections: Vec::new(),
+            replacements: Vec::new(),
+            strings: Vec::new(),
+            text_encoding: None,
+            port_map: None,
+            target: None,
+            program_counter: 0,
+            locations: Vec::new(),
+            location: String::new(),
+        }
+    }
+
+    /// Defines a symbol for the conditional directives, either as `NAME`,
+    /// which defines it as 1, or as `NAME=value`.
+    pub fn define(&mut self, definition: &str) -> Result<(), Error> {
+        let (name, value) = match definition.find('=') {
+            Some(index) => (
+                definition[..index].trim(),
+                expression::evaluate(&definition[index + 1..], 0, &|symbol: &str| {
+                    bail!("The definition can't refer to the symbol \"{}\"", symbol)
+                })?,
+            ),
+            None => (definition.trim(), 1),
+        };
+        ensure!(
+            !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_'),
+            "Invalid name \"{}\" to define",
+            name
+        );
+        self.defines.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    /// The local labels of the lines that were assembled last.
+    pub fn labels(&self) -> &HashMap<String, u32> {
+        &self.labels
+    }
+
+    /// The line that each word of the lines that were assembled last
+    /// originates from, after the macros are expanded.
+    pub fn sources(&self) -> &BTreeMap<u32, String> {
+        &self.sources
+    }
+
+    /// Where the line that each word of the lines that were assembled last
+    /// originates from is, like `patch.asm:12`.
+    pub fn source_locations(&self) -> &BTreeMap<u32, String> {
+        &self.source_locations
+    }
+
+    /// The instructions of the lines that were assembled last that patch
+    /// files on the disc instead of the DOL, by the paths of the files. Their
+    /// addresses are offsets into the files.
+    pub fn file_instructions(&self) -> &BTreeMap<String, Vec<Instruction>> {
+        &self.file_instructions
+    }
+
+    /// The branches of the lines that were assembled last that can't reach
+    /// their targets. No instructions are assembled for them.
+    pub fn far_branches(&self) -> &[FarBranch] {
+        &self.far_branches
+    }
+
+    /// The words that the lines that were assembled last expect the game to
+    /// contain.
+    pub fn expectations(&self) -> &[Expectation] {
+        &self.expectations
+    }
+
+    /// Sets the encoding the text of the string replacements is written in.
+    /// Without one, the text is written as it is, with a zero terminator.
+    pub fn set_text_encoding(&mut self, encoding: TextEncoding) {
+        self.text_encoding = Some(encoding);
+    }
+
+    /// Sets the port map that translates the addresses the lines patch,
+    /// branch to or hook, which are the addresses of the version of the game
+    /// that the lines were written for.
+    pub fn set_port_map(&mut self, port_map: &'a PortMap) {
+        self.port_map = Some(port_map);
+    }
+
+    /// Replaces the string at the address along with the strings of the lines
+    /// that were assembled last.
+    pub fn add_string(&mut self, address: u32, text: &str) -> Result<(), Error> {
+        let string = self.encode_string(address, text.as_bytes().to_owned())?;
+        self.strings.push(string);
+        Ok(())
+    }
+
+    /// The inject blocks of the lines that were assembled last.
+    pub fn injections(&self) -> &[Injection] {
+        &self.injections
+    }
+
+    /// The functions that the lines that were assembled last replace.
+    pub fn replacements(&self) -> &[Replacement] {
+        &self.replacements
+    }
+
+    /// The strings that the lines that were assembled last replace.
+    pub fn strings(&self) -> &[StringReplacement] {
+        &self.strings
+    }
+
+    pub fn assemble_all_lines(&mut self, lines: &[&str]) -> Result<Vec<Instruction>, Error> {
+        let mut dol_instructions = Vec::new();
+        let mut instructions = Vec::new();
+
+        let filtered_lines = lines
+            .iter()
+            .enumerate()
+            .map(|(index, l)| (index, reduce_line_to_code(l)))
+            .filter(|&(_, l)| !l.is_empty())
+            .collect::<Vec<_>>();
+        let code = filtered_lines.iter().map(|&(_, l)| l).collect::<Vec<_>>();
+        let expanded_lines = macros::expand(&code, &self.defines)
+            .map_err(|e| {
+                let error = e.error.context("Couldn't expand the macros").into();
+                self.locate(error, lines, filtered_lines[e.index].0, code[e.index])
+            })?
+            .into_iter()
+            .map(|(index, line)| (filtered_lines[index].0, line))
+            .collect();
+        let (expanded_lines, blocks) = self.split_injections(lines, expanded_lines)?;
+
+        // The local labels are laid out first, so branches can refer to labels
+        // that are only defined after them
+        self.labels.clear();
+        self.sources.clear();
+        self.source_locations.clear();
+        self.file_instructions.clear();
+        self.far_branches.clear();
+        self.expectations.clear();
+        self.injections.clear();
+        self.replacements.clear();
+        self.strings.clear();
+        self.target = None;
+        // The sizes of the data may depend on labels that follow it, so the
+        // lines are laid out again with the labels of the previous pass until
+        // they don't move anymore.
+        let start = self.program_counter;
+        let mut previous = None;
+        for pass in 1.. {
+            self.labels.clear();
+            let mut guessed = false;
+            for &(index, ref line) in &expanded_lines {
+                guessed |= self
+                    .layout_line(line, previous.as_ref())
+                    .map_err(|e| self.locate(e, lines, index, line))?;
+            }
+            self.program_counter = start;
+            if !guessed && previous.as_ref().map_or(true, |p| *p == self.labels) {
+                break;
+            }
+            ensure!(
+                pass < MAX_LAYOUT_PASSES,
+                "The labels don't settle, as the sizes of the data depend on each other"
+            );
+            previous = Some(self.labels.clone());
+        }
+
+        for block in blocks {
+            let injection = self.layout_injection(block)?;
+            self.injections.push(injection);
+        }
+
+        let mut data = Vec::new();
+        let mut data_address = 0;
+        for &(index, ref line) in &expanded_lines {
+            self.location = self.origin(lines, index).describe();
+            self.assemble_line(
+                line,
+                &mut data,
+                &mut data_address,
+                &mut instructions,
+                &mut dol_instructions,
+            ).map_err(|e| self.locate(e, lines, index, line))?;
+        }
+        flush_data(&mut data, data_address, &mut instructions);
+        self.move_instructions(&mut instructions, &mut dol_instructions);
+        self.target = None;
+
+        Ok(dol_instructions)
+    }
+
+    /// Assembles the lines like `assemble_all_lines`, but points the errors
+    /// at the lines of the files that the lines originate from.
+    pub fn assemble_lines_from(
+        &mut self,
+        lines: &[&str],
+        locations: Vec<Location>,
+    ) -> Result<Vec<Instruction>, Error> {
+        self.locations = locations;
+        let instructions = self.assemble_all_lines(lines);
+        self.locations.clear();
+        instructions
+    }
+
+    /// The origin of the line with the index.
+    fn origin(&self, lines: &[&str], index: usize) -> Origin {
+        let (path, line) = match self.locations.get(index) {
+            Some(location) => (Some(location.path.clone()), location.line),
+            None => (None, index + 1),
+        };
+        Origin {
+            source: lines[index].to_string(),
+            path,
+            line,
+        }
+    }
+
+    /// Points the error at the line it occurred in, which is the line with
+    /// the index. The code is what the line was expanded to.
+    fn locate(&self, error: Error, lines: &[&str], index: usize, code: &str) -> Error {
+        self.origin(lines, index).locate(error, code)
+    }
+
+    /// Splits the blocks like `inject 0x80001234 {` ... `}` off of the lines,
+    /// along with the addresses they inject at.
+    fn split_injections(
+        &self,
+        lines: &[&str],
+        expanded_lines: Vec<(usize, String)>,
+    ) -> Result<(Vec<(usize, String)>, Vec<Block>), Error> {
+        let mut remaining = Vec::with_capacity(expanded_lines.len());
+        let mut blocks = Vec::new();
+        let mut block: Option<Block> = None;
+        for (index, line) in expanded_lines {
+            if line == "}" {
+                match block.take() {
+                    Some(block) => blocks.push(block),
+                    None => {
+                        let error = err_msg("The \"}\" doesn't close an inject block");
+                        return Err(self.locate(error, lines, index, &line));
+                    }
+                }
+            } else if line.starts_with("inject ") && line.ends_with('{') {
+                if block.is_some() {
+                    let error = err_msg("Inject blocks can't be nested");
+                    return Err(self.locate(error, lines, index, &line));
+                }
+                block = Some(Block {
+                    address: line["inject ".len()..line.len() - 1].trim().to_string(),
+                    start: (line.clone(), self.origin(lines, index)),
+                    lines: Vec::new(),
+                });
+            } else if let Some(ref mut block) = block {
+                let origin = self.origin(lines, index);
+                block.lines.push((line, origin));
+            } else {
+                remaining.push((index, line));
+            }
+        }
+        if let Some(block) = block {
+            let error = format_err!("The inject block at \"{}\" is never closed", block.address);
+            return Err(block.start.1.locate(error, &block.start.0));
+        }
+        Ok((remaining, blocks))
+    }
+
+    /// Determines the address of the line and defines the local label it
+    /// may be. The labels that aren't defined yet are taken from the
+    /// previous layout pass. In the first pass they aren't known at all, so
+    /// the size of the data is guessed, which is reported back.
+    fn layout_line(
+        &mut self,
+        line: &str,
+        previous: Option<&HashMap<String, u32>>,
+    ) -> Result<bool, Error> {
+        if let Some(label) = local_label(line) {
+            let valid = label[1..]
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '.');
+            if !valid {
+                let message = format!("Invalid label \"{}\"", label);
+                return Err(Cause::at(Code::InvalidLabel, 0..label.len(), message).into());
+            }
+            let address = self.program_counter;
+            if self.labels.insert(label.to_string(), address).is_some() {
+                let message = format!("The label \"{}\" is defined multiple times", label);
+                return Err(Cause::at(Code::InvalidLabel, 0..label.len(), message).into());
+            }
+        } else if line.ends_with(':') {
+            self.program_counter = self
+                .parse_program_counter_label(line)
+                .context("Couldn't parse address label")?
+                .1;
+        } else if line.starts_with(".expect ")
+            || line.starts_with("replace ")
+            || line.starts_with("string ")
+            || is_operation(line)
+        {
+            // These don't take up any space at the current address
+        } else {
+            // The sizes of the data may depend on the symbols, like the
+            // end of a range to fill. The labels that follow are only known
+            // from the previous pass.
+            let guessed = Cell::new(false);
+            let resolve_symbol = |s: &str| match (self.resolve_symbol(s), previous) {
+                (Ok(value), _) => Ok(value),
+                (Err(e), Some(previous)) => previous.get(s).cloned().ok_or(e),
+                (Err(_), None) => {
+                    guessed.set(true);
+                    Ok(0)
+                }
+            };
+            let data = data::encode(line, self.program_counter, &resolve_symbol);
+            let data = match data {
+                Ok(data) => data,
+                // The guessed labels may be out of range, so the size is
+                // only known in the next pass
+                Err(_) if guessed.get() => return Ok(true),
+                Err(e) => return Err(e),
+            };
+            self.program_counter += data.map_or(4, |d| d.len() as u32);
+            return Ok(guessed.get());
+        }
+        Ok(false)
+    }
+
+    /// Assembles the line at the current address. Consecutive data is
+    /// collected, so it's only split into words once it ends.
+    fn assemble_line(
+        &mut self,
+        line: &str,
+        data: &mut Vec<u8>,
+        data_address: &mut u32,
+        instructions: &mut Vec<Instruction>,
+        dol_instructions: &mut Vec<Instruction>,
+    ) -> Result<(), Error> {
+        if local_label(line).is_some() {
+            return Ok(());
+        } else if line.ends_with(':') {
+            flush_data(data, *data_address, instructions);
+            let (target, address) = self
+                .parse_program_counter_label(line)
+                .context("Couldn't parse address label")?;
+            self.move_instructions(instructions, dol_instructions);
+            self.target = target;
+            self.program_counter = address;
+        } else if line.starts_with(".expect ") {
+            let expectation = self.parse_expectation(line)?;
+            self.expectations.push(expectation);
+        } else if line.starts_with("replace ") {
+            let replacement = self.parse_replacement(&line["replace ".len()..])?;
+            self.replacements.push(replacement);
+        } else if line.starts_with("string ") {
+            let string = self.parse_string_replacement(&line["string ".len()..])?;
+            self.strings.push(string);
+        } else if is_operation(line) {
+            for instruction in self.parse_operation(line)? {
+                self.add_source(instruction.address, line);
+                dol_instructions.push(instruction);
+            }
+        } else if let Some(bytes) = data::encode(line, self.program_counter, &|s: &str| {
+            self.resolve_symbol(s)
+        })? {
+            if data.is_empty() {
+                *data_address = self.program_counter;
+            }
+            data.extend_from_slice(&bytes);
+            let end = self.program_counter + bytes.len() as u32;
+            if self.target.is_none() {
+                for address in (self.program_counter & !3..end).step_by(4) {
+                    if !self.sources.contains_key(&address) {
+                        self.add_source(address, line);
+                    }
+                }
+            }
+            self.program_counter = end;
+        } else {
+            flush_data(data, *data_address, instructions);
+            if self.program_counter & 3 != 0 {
+                let message = format!(
+                    "The instruction \"{}\" at {:08X} is not aligned to 4 bytes",
+                    line, self.program_counter
+                );
+                return Err(Cause::new(Code::Misaligned, message).into());
+            }
+            if let Some(branch) = self.parse_far_branch(line) {
+                self.far_branches.push(branch);
+            } else {
+                instructions.push(self.parse_instruction(line)?);
+            }
+            if self.target.is_none() {
+                let address = self.program_counter;
+                self.add_source(address, line);
+            }
+            self.program_counter += 4;
+        }
+        Ok(())
+    }
+
+    /// Remembers the line that the word at the address originates from.
+    fn add_source(&mut self, address: u32, line: &str) {
+        self.sources.insert(address, line.to_string());
+        self.source_locations.insert(address, self.location.clone());
+    }
+
+    /// Moves the instructions assembled since the last address label to the
+    /// DOL's instructions or to the instructions of the file they patch.
+    fn move_instructions(
+        &mut self,
+        instructions: &mut Vec<Instruction>,
+        dol_instructions: &mut Vec<Instruction>,
+    ) {
+        match self.target {
+            Some(ref path) => self
+                .file_instructions
+                .entry(path.clone())
+                .or_insert_with(Vec::new)
+                .append(instructions),
+            None => dol_instructions.append(instructions),
+        }
+    }
+
+    /// Parses the word an `.expect` line expects at the current address,
+    /// which is either a value or an instruction, like `0x4E800020` or `blr`.
+    fn parse_expectation(&self, line: &str) -> Result<Expectation, Error> {
+        let operand = line[".expect ".len()..].trim();
+        let value = match self.resolve_address(operand) {
+            Ok(value) => value,
+            Err(_) => encoder::encode_at(
+                self.program_counter,
+                line,
+                line.len() - operand.len(),
+                |symbol| self.resolve_target(symbol),
+            )?,
+        };
+        Ok(Expectation {
+            file: self.target.clone(),
+            address: self.program_counter,
+            value,
+        })
+    }
+
+    /// Parses the functions of a line like `replace OSPanic with my_panic` or
+    /// `replace calls to OSPanic with my_panic`.
+    fn parse_replacement(&self, operands: &str) -> Result<Replacement, Error> {
+        let (calls_only, operands) = if operands.starts_with("calls to ") {
+            (true, &operands["calls to ".len()..])
+        } else {
+            (false, operands)
+        };
+        let index = operands
+            .find(" with ")
+            .ok_or_else(|| err_msg("Expected the function and its replacement"))?;
+        Ok(Replacement {
+            function: self.resolve_operand(&operands[..index])?,
+            replacement: self.resolve_operand(&operands[index + " with ".len()..])?,
+            calls_only,
+        })
+    }
+
+    /// Parses the address, the text and the optional pointers of a line like
+    /// `string 0x803A_1234 "Press Start" pointers 0x8040_1000, 0x8040_1230`.
+    fn parse_string_replacement(&self, operands: &str) -> Result<StringReplacement, Error> {
+        let index = operands
+            .find('"')
+            .ok_or_else(|| err_msg("Expected the address of the string and its text"))?;
+        let address = self.resolve_operand(&operands[..index])?;
+        let len = string_literal_len(&operands[index..])
+            .ok_or_else(|| err_msg("The string is missing its closing quote"))?;
+        let end = index + len;
+        let text = format!(".ascii {}", &operands[index..end]);
+        let data = data::encode(&text, address, &|s: &str| self.resolve_symbol(s))?
+            .ok_or_else(|| err_msg("Expected a quoted string"))?;
+        let mut string = self.encode_string(address, data)?;
+
+        let rest = operands[end..].trim();
+        if !rest.is_empty() {
+            ensure!(
+                rest.starts_with("pointers "),
+                "Expected the pointers to the string after its text, but found \"{}\"",
+                rest
+            );
+            for pointer in rest["pointers ".len()..].split(',') {
+                string.pointers.push(self.resolve_operand(pointer)?);
+            }
+        }
+
+        Ok(string)
+    }
+
+    /// Encodes the text of a string replacement in the text encoding.
+    fn encode_string(&self, address: u32, mut data: Vec<u8>) -> Result<StringReplacement, Error> {
+        let (terminator, unit_len) = match self.text_encoding {
+            Some(ref encoding) => {
+                let text = String::from_utf8(data).context("The string is not valid UTF-8")?;
+                data = encoding.encode(&text)?;
+                (encoding.terminator().to_vec(), encoding.unit_len())
+            }
+            None => (vec![0], 1),
+        };
+        ensure!(
+            address % unit_len == 0,
+            "The string at {:08X} is not aligned to its {} byte characters",
+            address,
+            unit_len
+        );
+        data.extend_from_slice(&terminator);
+
+        Ok(StringReplacement {
+            address,
+            data,
+            terminator,
+            unit_len,
+            pointers: Vec::new(),
+        })
+    }
+
+    /// Parses the operations that patch the DOL without writing assembly,
+    /// like `nop 0x80001234..0x80001240`, which replaces the instructions of
+    /// the range with `nop`, or `stub OSReport -> return 0`, which makes the
+    /// function return right away. Both keep the current address as it is.
+    fn parse_operation(&self, line: &str) -> Result<Vec<Instruction>, Error> {
+        let instruction = |address, data| Instruction {
+            address,
+            data,
+            mask: !0,
+        };
+
+        if line.starts_with("nop ") {
+            let operand = &line["nop ".len()..];
+            let (start, end) = match operand.find("..") {
+                Some(index) => (
+                    self.resolve_ported_address(&operand[..index])?,
+                    self.resolve_ported_address(&operand[index + 2..])?,
+                ),
+                None => {
+                    let address = self.resolve_ported_address(operand)?;
+                    (address, address.wrapping_add(4))
+                }
+            };
+            ensure!(
+                start & 3 == 0 && end & 3 == 0,
+                "The range {:08X}..{:08X} is not aligned to 4 bytes",
+                start,
+                end
+            );
+            ensure!(
+                start < end && end - start <= MAX_NOP_LEN,
+                "The range {:08X}..{:08X} is invalid",
+                start,
+                end
+            );
+            return Ok((start..end)
+                .step_by(4)
+                .map(|address| instruction(address, NOP))
+                .collect());
+        }
+
+        let operands = &line["stub ".len()..];
+        let index = operands
+            .find("->")
+            .ok_or_else(|| err_msg("Expected the function and what it returns"))?;
+        let function = self.resolve_operand(&operands[..index])?;
+        let result = operands[index + 2..].trim();
+        ensure!(
+            result.starts_with("return"),
+            "Expected what the function returns, like \"return 0\""
+        );
+        let value = result["return".len()..].trim();
+
+        let mut instructions = Vec::new();
+        if !value.is_empty() {
+            let value = self.resolve_address(value)?;
+            let signed = value as i32;
+            if -0x8000 <= signed && signed < 0x8000 {
+                // li r3, value
+                instructions.push((14 << 26) | (3 << 21) | (value & 0xFFFF));
+            } else {
+                // lis r3, value@h
+                instructions.push((15 << 26) | (3 << 21) | (value >> 16));
+                // ori r3, r3, value@l
+                instructions.push((24 << 26) | (3 << 21) | (3 << 16) | (value & 0xFFFF));
+            }
+        }
+        instructions.push(BLR);
+
+        Ok(instructions
+            .into_iter()
+            .enumerate()
+            .map(|(index, data)| instruction(function + 4 * index as u32, data))
+            .collect())
+    }
+
+    /// Resolves an operand that is either a symbol or an address, like a function.
+    fn resolve_operand(&self, operand: &str) -> Result<u32, Error> {
+        let operand = operand.trim();
+        self.resolve_target(operand)
+            .or_else(|_| self.resolve_ported_address(operand))
+    }
+
+    /// Lays out the lines of an inject block, so its length and the offsets
+    /// of its local labels are known before it's placed. Like the other
+    /// lines, they are laid out again until the labels settle, and a size
+    /// that depends on a symbol that isn't defined is an error.
+    fn layout_injection(&self, block: Block) -> Result<Injection, Error> {
+        let (ref start, ref origin) = block.start;
+        let address = self.resolve_ported_address(&block.address).map_err(|e| {
+            let error = e.context("Couldn't parse the address of the inject block");
+            origin.locate(error.into(), start)
+        })?;
+        let mut previous: Option<HashMap<String, u32>> = None;
+        for pass in 1.. {
+            let mut labels = HashMap::new();
+            let mut len = 0;
+            let mut guessed = false;
+            for &(ref line, ref origin) in &block.lines {
+                let (end, was_guessed) = self
+                    .layout_injected_line(line, &mut labels, len, previous.as_ref())
+                    .map_err(|e| origin.locate(e, line))?;
+                len = end;
+                guessed |= was_guessed;
+            }
+
+            let settled = previous.as_ref().map_or(true, |p| *p == labels);
+            if !guessed && settled {
+                return Ok(Injection {
+                    address,
+                    len,
+                    location: origin.describe(),
+                    lines: block.lines,
+                    labels,
+                });
+            }
+            if pass >= MAX_LAYOUT_PASSES {
+                let error = err_msg(
+                    "The labels don't settle, as the sizes of the data depend on each other",
+                );
+                return Err(origin.locate(error, start));
+            }
+            previous = Some(labels);
+        }
+        unreachable!()
+    }
+
+    /// Lays out the line of an inject block at the offset into the block.
+    /// Returns the offset after the line and whether its size was guessed,
+    /// as it depends on labels that are only known from the previous pass.
+    fn layout_injected_line(
+        &self,
+        line: &str,
+        labels: &mut HashMap<String, u32>,
+        offset: u32,
+        previous: Option<&HashMap<String, u32>>,
+    ) -> Result<(u32, bool), Error> {
+        if let Some(label) = local_label(line) {
+            ensure!(
+                !self.labels.contains_key(label) && !labels.contains_key(label),
+                "The label \"{}\" is defined multiple times",
+                label
+            );
+            labels.insert(label.to_string(), offset);
+            return Ok((offset, false));
+        }
+        ensure!(
+            !line.ends_with(':') && !line.starts_with(".expect "),
+            "\"{}\" can't be used in an inject block",
+            line
+        );
+        let guessed = Cell::new(false);
+        let data = {
+            let resolve_symbol = |s: &str| {
+                if let Some(&offset) = labels.get(s) {
+                    return Ok(offset);
+                }
+                match (self.resolve_symbol(s), previous) {
+                    (Ok(value), _) => Ok(value),
+                    (Err(e), Some(previous)) => previous.get(s).cloned().ok_or(e),
+                    (Err(_), None) => {
+                        guessed.set(true);
+                        Ok(0)
+                    }
+                }
+            };
+            data::encode(line, offset, &resolve_symbol)
+        };
+        match data {
+            Ok(data) => Ok((offset + data.map_or(4, |d| d.len() as u32), guessed.get())),
+            Err(_) if guessed.get() => Ok((offset, true)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Assembles the lines of the inject block at the address it's placed at.
+    pub fn assemble_injection(
+        &self,
+        injection: &Injection,
+        address: u32,
+    ) -> Result<Vec<u8>, Error> {
+        let resolve_symbol = |symbol: &str| match injection.labels.get(symbol) {
+            Some(&offset) => Ok(address + offset),
+            None => self.resolve_target(symbol),
+        };
+
+        let mut bytes = Vec::with_capacity(injection.len as usize);
+        for &(ref line, ref origin) in &injection.lines {
+            if local_label(line).is_some() {
+                continue;
+            }
+            let program_counter = address + bytes.len() as u32;
+            let data = assemble_injected_line(line, program_counter, &resolve_symbol)
+                .map_err(|e| origin.locate(e, line))?;
+            bytes.extend_from_slice(&data);
+        }
+        Ok(bytes)
+    }
+
+    /// Parses a `b` or `bl` whose target is out of range. Only the DOL can be
+    /// patched with them, as there's no space for veneers in the other files.
+    fn parse_far_branch(&self, line: &str) -> Option<FarBranch> {
+        if self.target.is_some() {
+            return None;
+        }
+        let index = line.find(char::is_whitespace)?;
+        let link = match &line[..index] {
+            "b" => false,
+            "bl" => true,
+            _ => return None,
+        };
+        let operand = line[index..].trim();
+        let target = match self.resolve_target(operand) {
+            Ok(target) => target,
+            Err(_) => self.resolve_ported_address(operand).ok()?,
+        };
+        if is_branch_in_range(self.program_counter, target) {
+            None
+        } else {
+            Some(FarBranch {
+                address: self.program_counter,
+                target,
+                link,
+            })
+        }
+    }
+
+    fn parse_instruction(&self, line: &str) -> Result<Instruction, Error> {
+        let data = if line.starts_with("u32 ") {
+            parse_u32_literal(&line[4..]).context("Couldn't parse the u32 literal")?
+        } else {
+            encoder::encode(self.program_counter, line, |symbol| {
+                self.resolve_target(symbol)
+            })?
+        };
+
+        Ok(Instruction {
+            address: self.program_counter,
+            data: data,
+            mask: !0,
+        })
+    }
+
+    pub fn resolve_symbol(&self, symbol: &str) -> Result<u32, Error> {
+        if let Ok(address) = parse_u32_literal(symbol) {
+            return Ok(address);
+        }
+
+        if let Some(&address) = self.labels.get(symbol) {
+            return Ok(address);
+        }
+
+        if let Some(&value) = self.defines.get(symbol) {
+            return Ok(value as u32);
+        }
+
+        if let Some(&symbol) = self.symbol_table.get(symbol) {
+            return Ok(symbol);
+        }
+
+        if let Some(&symbol) = self.prelinked_symbols.get(symbol) {
+            return Ok(symbol);
+        }
+
+        let message = format!("The symbol \"{}\" wasn't found", symbol);
+        Err(Cause::new(Code::UnknownSymbol, message).into())
+    }
+
+    /// Parses an address label. Labels like `file:maps/stage.dat+0x40:`
+    /// target an offset inside a file on the disc instead of an address. The
+    /// paths may also be the ones of extracted discs, like `sys/main.dol`.
+    fn parse_program_counter_label(&self, line: &str) -> Result<(Option<String>, u32), Error> {
+        let label = &line[..line.len() - 1];
+        if label.starts_with("file:") {
+            let target = &label["file:".len()..];
+            let (path, offset) = match target.rfind('+') {
+                Some(index) => (
+                    &target[..index],
+                    self.resolve_address(&target[index + 1..])?,
+                ),
+                None => (target, 0),
+            };
+            let path = path.trim().trim_left_matches('/');
+            ensure!(!path.is_empty(), "The label doesn't name a file to patch");
+            let (path, base) = disc_file(path);
+            return Ok((Some(path.to_string()), base + offset));
+        }
+        Ok((None, self.resolve_ported_address(label)?))
+    }
+
+    /// Resolves an address like `0x80001234`, `[symbol] + 0x10` or any other
+    /// expression.
+    pub fn resolve_address(&self, line: &str) -> Result<u32, Error> {
+        let address = expression::evaluate(line, self.program_counter, &|symbol| {
+            self.resolve_symbol(symbol)
+        })?;
+        Ok(address as u32)
+    }
+
+    /// Resolves an address that the lines patch, branch to or hook. Unless
+    /// it refers to symbols, which are already the ones of the game that is
+    /// built, the port map translates it.
+    pub fn resolve_ported_address(&self, line: &str) -> Result<u32, Error> {
+        let uses_symbols = Cell::new(false);
+        let address = expression::evaluate(line, self.program_counter, &|symbol| {
+            uses_symbols.set(true);
+            self.resolve_symbol(symbol)
+        })? as u32;
+        Ok(if uses_symbols.get() {
+            address
+        } else {
+            self.translate(address)
+        })
+    }
+
+    /// Resolves the symbol an instruction branches to, where an address is
+    /// translated by the port map.
+    fn resolve_target(&self, symbol: &str) -> Result<u32, Error> {
+        match parse_u32_literal(symbol) {
+            Ok(address) => Ok(self.translate(address)),
+            Err(_) => self.resolve_symbol(symbol),
+        }
+    }
+
+    fn translate(&self, address: u32) -> u32 {
+        self.port_map.map_or(address, |p| p.translate(address))
+    }
+}
+
+/// Assembles the line of an inject block at the address, which is either data
+/// or an instruction.
+fn assemble_injected_line<F>(
+    line: &str,
+    program_counter: u32,
+    resolve_symbol: &F,
+) -> Result<Vec<u8>, Error>
+where
+    F: Fn(&str) -> Result<u32, Error>,
+{
+    if let Some(data) = data::encode(line, program_counter, resolve_symbol)? {
+        return Ok(data);
+    }
+    ensure!(
+        program_counter & 3 == 0,
+        "The instruction \"{}\" at {:08X} is not aligned to 4 bytes",
+        line,
+        program_counter
+    );
+    let data = if line.starts_with("u32 ") {
+        parse_u32_literal(&line[4..]).context("Couldn't parse the u32 literal")?
+    } else {
+        encoder::encode(program_counter, line, resolve_symbol)?
+    };
+    let mut word = [0; 4];
+    BE::write_u32(&mut word, data);
+    Ok(word.to_vec())
+}
+
+/// Lines like `nop 0x80001234` and `stub OSReport -> return 0` that patch the
+/// DOL at their own addresses.
+/// Translates the paths of extracted discs to the ones on the disc, along
+/// with the offset of the file in it. The system data is stored in
+/// `&&systemdata` instead of `sys`, where the bi2.bin follows the boot.bin in
+/// the disc header, while the other files are in `files`.
+fn disc_file(path: &str) -> (&str, u32) {
+    match &*path.to_lowercase() {
+        "sys/main.dol" => ("&&systemdata/Start.dol", 0),
+        "sys/boot.bin" => ("&&systemdata/iso.hdr", 0),
+        "sys/bi2.bin" => ("&&systemdata/iso.hdr", 0x440),
+        "sys/apploader.img" => ("&&systemdata/AppLoader.ldr", 0),
+        _ if path.starts_with("files/") => (&path["files/".len()..], 0),
+        _ => (path, 0),
+    }
+}
+
+fn is_operation(line: &str) -> bool {
+    line.starts_with("nop ") || line.starts_with("stub ")
+}
+
+/// Labels starting with a dot, like `.skip:`, mark the current address
+/// instead of moving the program counter to the address they name.
+fn local_label(line: &str) -> Option<&str> {
+    if line.starts_with('.') && line.ends_with(':') && line.len() > 2 {
+        Some(&line[..line.len() - 1])
+    } else {
+        None
+    }
+}
+
+/// Splits the bytes at the address into the words that write them, like the
+/// data of a patch file.
+pub fn bytes_to_instructions(address: u32, data: &[u8]) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    flush_data(&mut data.to_vec(), address, &mut instructions);
+    instructions
+}
+
+/// Turns the data into instructions. The data doesn't need to be aligned, so
+/// the words at its start and end are masked to only write the data's bytes.
+fn flush_data(data: &mut Vec<u8>, address: u32, instructions: &mut Vec<Instruction>) {
+    if data.is_empty() {
+        return;
+    }
+    let (mut word, mut mask) = ([0; 4], [0; 4]);
+    let mut word_address = address & !3;
+    for (index, &byte) in data.iter().enumerate() {
+        let offset = ((address & 3) as usize + index) % 4;
+        word[offset] = byte;
+        mask[offset] = 0xFF;
+        if offset == 3 || index == data.len() - 1 {
+            instructions.push(Instruction {
+                address: word_address,
+                data: BE::read_u32(&word),
+                mask: BE::read_u32(&mask),
+            });
+            word = [0; 4];
+            mask = [0; 4];
+            word_address += 4;
+        }
+    }
+    data.clear();
+}
+
+/// The length of the quoted string at the start of the text, including its
+/// quotes.
+fn string_literal_len(text: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (index, c) in text.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' => return Some(index + 1),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn reduce_line_to_code(line: &str) -> &str {
+    let mut line = line;
+    // Semicolons in strings don't start comments
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => {
+                line = &line[..index];
+                break;
+            }
+            _ => {}
+        }
+    }
+    line.trim()
+}
+
+fn parse_i64_literal(literal: &str) -> Result<i64, ParseError> {
+    let val: syn::LitInt = syn::parse_str(literal)?;
+    Ok(val.value() as i64)
+}
+
+fn parse_u32_literal(literal: &str) -> Result<u32, ParseError> {
+    parse_i64_literal(literal).map(|i| i as u32)
+}
+
+/// Whether a relative branch at the address can reach the destination.
+pub fn is_branch_in_range(address: u32, destination: u32) -> bool {
+    let displacement = destination.wrapping_sub(address) as i32;
+    displacement >= -0x0200_0000 && displacement < 0x0200_0000
+}
+
+/// Builds a relative branch from generated code or to it. The code may have
+/// been placed in MEM2, which a branch from MEM1 can't reach, so the distance
+/// is checked instead of silently truncated.
+pub fn build_checked_branch(address: u32, destination: u32, lk: bool) -> Result<u32, Error> {
+    ensure!(
+        is_branch_in_range(address, destination),
+        "The branch at {:08X} can't reach {:08X}, as it's out of range",
+        address,
+        destination
+    );
+    Ok(build_branch_instruction(address, destination, false, lk))
+}
+
+pub fn build_branch_instruction(address: u32, destination: u32, aa: bool, lk: bool) -> u32 {
+    let bits_dest = if aa {
+        destination
+    } else {
+        destination.wrapping_sub(address)
+    };
+    let bits_aa = if aa { 1 } else { 0 };
+    let bits_lk = if lk { 1 } else { 0 };
+
+    (18 << 26) | (0x3FFFFFC & bits_dest) | (bits_aa << 1) | bits_lk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lays_out_data_that_depends_on_later_labels() {
+        let symbols = HashMap::new();
+        let mut assembler = Assembler::new(Default::default(), &symbols);
+        let lines = [
+            "0x80001800:",
+            ".start:",
+            ".space .end - .data",
+            ".data:",
+            ".word 1, 2",
+            ".end:",
+        ];
+        assembler.assemble_all_lines(&lines).unwrap();
+        assert_eq!(assembler.labels()[".start"], 0x8000_1800);
+        assert_eq!(assembler.labels()[".data"], 0x8000_1808);
+        assert_eq!(assembler.labels()[".end"], 0x8000_1810);
+    }
+
+    #[test]
+    fn patches_the_files_of_extracted_discs() {
+        let symbols = HashMap::new();
+        let mut assembler = Assembler::new(Default::default(), &symbols);
+        let lines = [
+            "file:sys/main.dol+0x100:",
+            ".word 1",
+            "file:sys/bi2.bin+0x4:",
+            ".word 2",
+            "file:files/maps/stage.dat:",
+            ".word 3",
+        ];
+        assembler.assemble_all_lines(&lines).unwrap();
+        let files = assembler
+            .file_instructions()
+            .iter()
+            .map(|(path, instructions)| (&**path, instructions[0].address))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            [
+                ("&&systemdata/Start.dol", 0x100),
+                ("&&systemdata/iso.hdr", 0x444),
+                ("maps/stage.dat", 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn translates_only_the_addresses_with_the_port_map() {
+        let symbols = HashMap::new();
+        let port_map = PortMap::parse("0x80003000..0x80004000 0x80005000").unwrap();
+        let mut assembler = Assembler::new(Default::default(), &symbols);
+        assembler.set_port_map(&port_map);
+        let lines = [
+            "0x80003100:",
+            "bl 0x80003200",
+            "lis r3, 0x8000",
+            "li r4, 0x3100",
+        ];
+        let instructions = assembler.assemble_all_lines(&lines).unwrap();
+        let words = instructions
+            .iter()
+            .map(|i| (i.address, i.data))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            words,
+            [
+                (0x8000_5100, 0x4800_0101),
+                (0x8000_5104, 0x3C60_8000),
+                (0x8000_5108, 0x3880_3100),
+            ]
+        );
+    }
+
+    #[test]
+    fn lays_out_inject_blocks() {
+        let symbols = HashMap::new();
+        let mut assembler = Assembler::new(Default::default(), &symbols);
+        let lines = [
+            "inject 0x80001234 {",
+            ".space .end - .data",
+            ".data:",
+            ".word 1, 2",
+            ".end:",
+            "blr",
+            "}",
+        ];
+        assembler.assemble_all_lines(&lines).unwrap();
+        assert_eq!(assembler.injections()[0].len, 0x14);
+
+        let lines = ["inject 0x80001234 {", ".space unknown", "}"];
+        assert!(assembler.assemble_all_lines(&lines).is_err());
+    }
+
+    #[test]
+    fn parses_the_pointers_of_strings() {
+        let symbols = HashMap::new();
+        let mut assembler = Assembler::new(Default::default(), &symbols);
+        let lines = [
+            r#"string 0x803A1234 "Press \"A\"" pointers 0x80401000, 0x80401230"#,
+            r#"string 0x803A2000 "Start""#,
+        ];
+        assembler.assemble_all_lines(&lines).unwrap();
+        let strings = assembler.strings();
+        assert_eq!(strings[0].data, b"Press \"A\"\0");
+        assert_eq!(strings[0].pointers, [0x8040_1000, 0x8040_1230]);
+        assert!(strings[1].pointers.is_empty());
+    }
+
+    #[test]
+    fn points_the_errors_at_their_lines() {
+        let symbols = HashMap::new();
+        let mut assembler = Assembler::new(Default::default(), &symbols);
+
+        let lines = ["nop", ".if 1", "nop"];
+        let error = assembler.assemble_all_lines(&lines).unwrap_err();
+        assert!(error.to_string().contains("--> 2:1"));
+
+        let lines = ["inject 0x80001234 {", "nop", "  li r3, 0x8000", "}"];
+        assembler.assemble_all_lines(&lines).unwrap();
+        let error = assembler
+            .assemble_injection(&assembler.injections()[0], 0x8000_3000)
+            .unwrap_err();
+        assert!(error.to_string().starts_with("error[E0005]"));
+        assert!(error.to_string().contains("--> 3:10"));
+    }
+
+    #[test]
+    fn rejects_labels_that_dont_settle() {
+        let symbols = HashMap::new();
+        let mut assembler = Assembler::new(Default::default(), &symbols);
+        let lines = [
+            "0x80001800:",
+            ".start:",
+            ".space .end - .start + 4",
+            ".end:",
+        ];
+        assert!(assembler.assemble_all_lines(&lines).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//! Encodes PCM samples as the ADPCM of the GameCube's and the Wii's audio DSP.
+//! Every 14 samples are stored as 4 bit differences to a prediction from the
+//! previous two samples, together with a header that selects one of eight
+//! pairs of coefficients for the prediction and a scale for the differences.
+//! The coefficients are fitted to each channel, and each frame is encoded
+//! with whichever of them and whichever scale end up closest to the original
+//! samples.
+
+use super::coefficients;
+use byteorder::{ByteOrder, BE};
+
+/// The number of samples in each frame.
+pub const SAMPLES_PER_FRAME: usize = 14;
+pub const FRAME_LEN: usize = 8;
+const MAX_SCALE: u8 = 12;
+
+/// The eight pairs of weights of the previous two samples, with 11
+/// fractional bits.
+pub type Coefficients = [[i16; 2]; 8];
+pub const COEFFICIENTS_LEN: usize = 0x20;
+
+/// The coefficients and the contexts for starting and looping a channel, as
+/// they are stored in DSP and BRSTM files.
+pub const INFO_LEN: usize = 0x2E;
+const OFFSET_HEADER: usize = 0x22;
+const OFFSET_LOOP_HEADER: usize = 0x28;
+const OFFSET_LOOP_HISTORY: usize = 0x2A;
+
+/// Keeps track of the coefficients and the last two decoded samples of a
+/// channel.
+pub struct Encoder {
+    pub coefficients: Coefficients,
+    pub hist1: i16,
+    pub hist2: i16,
+}
+
+/// A whole channel encoded at once, which keeps the decoded samples around,
+/// so the DSP can be told how to start decoding in the middle of it.
+pub struct Channel {
+    pub data: Vec<u8>,
+    coefficients: Coefficients,
+    decoded: Vec<i16>,
+}
+
+struct Frame {
+    header: u8,
+    nibbles: [u8; SAMPLES_PER_FRAME],
+    decoded: [i16; SAMPLES_PER_FRAME],
+    error: u64,
+}
+
+/// The length of the given number of samples once they are encoded.
+pub fn encoded_len(samples: usize) -> usize {
+    (samples + SAMPLES_PER_FRAME - 1) / SAMPLES_PER_FRAME * FRAME_LEN
+}
+
+pub fn write_coefficients(coefficients: &Coefficients, out: &mut [u8]) {
+    for (dst, pair) in out.chunks_mut(4).zip(coefficients) {
+        BE::write_i16(dst, pair[0]);
+        BE::write_i16(&mut dst[2..], pair[1]);
+    }
+}
+
+impl Encoder {
+    /// Fits the coefficients to all the samples of the channel, which are
+    /// then encoded from the start.
+    pub fn new(samples: &[i16]) -> Self {
+        Encoder {
+            coefficients: coefficients::fit(samples),
+            hist1: 0,
+            hist2: 0,
+        }
+    }
+
+    /// Encodes the samples, filling up the last frame with silence.
+    pub fn encode(&mut self, samples: &[i16], out: &mut Vec<u8>) {
+        for chunk in samples.chunks(SAMPLES_PER_FRAME) {
+            let (frame, _) = self.encode_frame(chunk);
+            out.extend_from_slice(&frame);
+        }
+    }
+
+    /// Encodes a single frame and returns it together with the samples the
+    /// DSP decodes from it.
+    pub fn encode_frame(&mut self, samples: &[i16]) -> ([u8; FRAME_LEN], [i16; SAMPLES_PER_FRAME]) {
+        let mut padded = [0; SAMPLES_PER_FRAME];
+        padded[..samples.len()].copy_from_slice(samples);
+
+        let mut best: Option<Frame> = None;
+        for predictor in 0..self.coefficients.len() {
+            for scale in 0..MAX_SCALE + 1 {
+                let frame = self.try_frame(&padded, predictor, scale);
+                if best.as_ref().map_or(true, |best| frame.error < best.error) {
+                    best = Some(frame);
+                }
+            }
+        }
+
+        let frame = best.unwrap();
+        let mut out = [0; FRAME_LEN];
+        out[0] = frame.header;
+        for (dst, pair) in out[1..].iter_mut().zip(frame.nibbles.chunks(2)) {
+            *dst = pair[0] << 4 | pair[1];
+        }
+        self.hist1 = frame.decoded[SAMPLES_PER_FRAME - 1];
+        self.hist2 = frame.decoded[SAMPLES_PER_FRAME - 2];
+        (out, frame.decoded)
+    }
+
+    fn try_frame(&self, samples: &[i16], predictor: usize, scale: u8) -> Frame {
+        let (c1, c2) = (
+            self.coefficients[predictor][0] as i32,
+            self.coefficients[predictor][1] as i32,
+        );
+        let (mut hist1, mut hist2) = (self.hist1 as i32, self.hist2 as i32);
+        let step = (2048 << scale) as f64;
+        let mut nibbles = [0; SAMPLES_PER_FRAME];
+        let mut decoded_samples = [0; SAMPLES_PER_FRAME];
+        let mut error = 0;
+
+        for ((nibble, decoded_sample), &sample) in
+            nibbles.iter_mut().zip(&mut decoded_samples).zip(samples)
+        {
+            let prediction = c1 * hist1 + c2 * hist2;
+            let difference = ((sample as i32 * 2048 - prediction) as f64 / step).round() as i32;
+            let difference = difference.max(-8).min(7);
+
+            // This is how the DSP decodes the samples
+            let decoded = ((difference << scale << 11) + 1024 + prediction) >> 11;
+            let decoded = decoded
+                .max(i16::min_value() as i32)
+                .min(i16::max_value() as i32);
+
+            error += (sample as i64 - decoded as i64).pow(2) as u64;
+            *nibble = difference as u8 & 0xF;
+            *decoded_sample = decoded as i16;
+            hist2 = hist1;
+            hist1 = decoded;
+        }
+
+        Frame {
+            header: (predictor as u8) << 4 | scale,
+            nibbles,
+            decoded: decoded_samples,
+            error,
+        }
+    }
+}
+
+impl Channel {
+    pub fn encode(samples: &[i16]) -> Self {
+        let mut encoder = Encoder::new(samples);
+        let mut data = Vec::with_capacity(encoded_len(samples.len()));
+        let mut decoded = Vec::with_capacity(samples.len() + SAMPLES_PER_FRAME);
+        for chunk in samples.chunks(SAMPLES_PER_FRAME) {
+            let (frame, samples) = encoder.encode_frame(chunk);
+            data.extend_from_slice(&frame);
+            decoded.extend_from_slice(&samples);
+        }
+        Self {
+            data,
+            coefficients: encoder.coefficients,
+            decoded,
+        }
+    }
+
+    /// The previous two samples the DSP needs to start decoding at the sample.
+    pub fn history(&self, sample: usize) -> (i16, i16) {
+        let decoded = |offset: usize| {
+            if sample >= offset {
+                self.decoded.get(sample - offset).cloned().unwrap_or(0)
+            } else {
+                0
+            }
+        };
+        (decoded(1), decoded(2))
+    }
+
+    /// The header of the frame that contains the sample.
+    fn header(&self, sample: usize) -> u8 {
+        self.data
+            .get(sample / SAMPLES_PER_FRAME * FRAME_LEN)
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    /// Writes the coefficients and the contexts for starting and looping the
+    /// channel. Channels start without any previous samples.
+    pub fn write_info(&self, loop_start: Option<usize>, out: &mut [u8]) {
+        write_coefficients(&self.coefficients, out);
+        BE::write_u16(&mut out[OFFSET_HEADER..], self.header(0) as u16);
+        if let Some(loop_start) = loop_start {
+            let (hist1, hist2) = self.history(loop_start);
+            BE::write_u16(
+                &mut out[OFFSET_LOOP_HEADER..],
+                self.header(loop_start) as u16,
+            );
+            BE::write_i16(&mut out[OFFSET_LOOP_HISTORY..], hist1);
+            BE::write_i16(&mut out[OFFSET_LOOP_HISTORY + 2..], hist2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resonance(len: usize) -> Vec<i16> {
+        let mut seed = 1u32;
+        let (mut hist1, mut hist2) = (0.0, 0.0);
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let noise = f64::from((seed >> 16) & 0x7FFF) - 16384.0;
+                let sample = 1.6 * hist1 - 0.8 * hist2 + 0.1 * noise;
+                hist2 = hist1;
+                hist1 = sample;
+                sample.round() as i16
+            })
+            .collect()
+    }
+
+    fn mean_square(samples: &[i16], other: &[i16]) -> i64 {
+        let sum = samples
+            .iter()
+            .zip(other)
+            .map(|(&a, &b)| (i64::from(a) - i64::from(b)).pow(2))
+            .sum::<i64>();
+        sum / samples.len() as i64
+    }
+
+    #[test]
+    fn decodes_close_to_the_original_samples() {
+        let samples = resonance(14000);
+        let channel = Channel::encode(&samples);
+        assert_eq!(channel.data.len(), encoded_len(samples.len()));
+        let power = mean_square(&samples, &vec![0; samples.len()]);
+        let error = mean_square(&samples, &channel.decoded);
+        assert!(
+            error * 1000 < power,
+            "The error of {} is too large for a power of {}",
+            error,
+            power
+        );
+    }
+
+    #[test]
+    fn writes_the_fitted_coefficients() {
+        let samples = resonance(14000);
+        let channel = Channel::encode(&samples);
+        let mut info = [0; INFO_LEN];
+        channel.write_info(None, &mut info);
+        let coefficients = coefficients::fit(&samples);
+        for (bytes, pair) in info[..COEFFICIENTS_LEN].chunks(4).zip(&coefficients) {
+            assert_eq!(BE::read_i16(bytes), pair[0]);
+            assert_eq!(BE::read_i16(&bytes[2..]), pair[1]);
+        }
+        assert_eq!(
+            BE::read_u16(&info[OFFSET_HEADER..]),
+            u16::from(channel.data[0])
+        );
+    }
+}
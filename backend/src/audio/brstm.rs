@@ -0,0 +1,242 @@
+//! Based on http://wiki.tockdom.com/wiki/BRSTM_(File_Format)
+//!
+//! BRSTM files stream the channels of a sound in blocks, which alternate
+//! between the channels. The `HEAD` chunk describes the stream, its tracks
+//! and the DSP ADPCM contexts of the channels, while the `ADPC` chunk holds
+//! the previous samples at the start of each block, so the games can seek.
+//! Offsets inside of the `HEAD` chunk are relative to its contents.
+
+use super::adpcm::{self, Channel, FRAME_LEN, SAMPLES_PER_FRAME};
+use super::Sound;
+use byteorder::{ByteOrder, BE};
+use failure::Error;
+
+const MAGIC: &[u8] = b"RSTM";
+const BYTE_ORDER_MARK: u16 = 0xFEFF;
+const VERSION: u16 = 0x0100;
+const HEADER_LEN: usize = 0x40;
+const NUM_SECTIONS: u16 = 2;
+const CHUNK_HEADER_LEN: usize = 8;
+const ALIGNMENT: usize = 0x20;
+
+const OFFSET_BYTE_ORDER_MARK: usize = 0x04;
+const OFFSET_VERSION: usize = 0x06;
+const OFFSET_FILE_SIZE: usize = 0x08;
+const OFFSET_HEADER_SIZE: usize = 0x0C;
+const OFFSET_NUM_SECTIONS: usize = 0x0E;
+const OFFSET_HEAD: usize = 0x10;
+const OFFSET_ADPC: usize = 0x18;
+const OFFSET_DATA: usize = 0x20;
+
+/// References point to structures inside of the `HEAD` chunk.
+const REFERENCE: u32 = 0x0100_0000;
+const REFERENCE_LEN: usize = 8;
+
+// Relative to the stream info
+const OFFSET_LOOP_FLAG: usize = 0x01;
+const OFFSET_NUM_CHANNELS: usize = 0x02;
+const OFFSET_DATA_OFFSET: usize = 0x10;
+const STREAM_INFO_LEN: usize = 0x34;
+
+const CODEC_ADPCM: u8 = 2;
+const TRACK_LEN: usize = 4;
+const ADPCM_INFO_LEN: usize = 0x30;
+const BLOCK_LEN: usize = 0x2000;
+const SAMPLES_PER_BLOCK: usize = BLOCK_LEN / FRAME_LEN * SAMPLES_PER_FRAME;
+/// The two previous samples of a channel.
+const HISTORY_LEN: usize = 4;
+
+fn align(offset: usize) -> usize {
+    (offset + ALIGNMENT - 1) & !(ALIGNMENT - 1)
+}
+
+fn push_u16(out: &mut Vec<u8>, value: u16) {
+    let mut buf = [0; 2];
+    BE::write_u16(&mut buf, value);
+    out.extend_from_slice(&buf);
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    let mut buf = [0; 4];
+    BE::write_u32(&mut buf, value);
+    out.extend_from_slice(&buf);
+}
+
+fn push_reference(out: &mut Vec<u8>, offset: usize) {
+    push_u32(out, REFERENCE);
+    push_u32(out, offset as u32);
+}
+
+/// Wraps the contents into a chunk that is padded to the alignment.
+fn chunk(magic: &[u8], mut contents: Vec<u8>) -> Vec<u8> {
+    let mut out = magic.to_owned();
+    let len = align(CHUNK_HEADER_LEN + contents.len());
+    push_u32(&mut out, len as u32);
+    out.append(&mut contents);
+    out.resize(len, 0);
+    out
+}
+
+pub fn is_brstm(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN && data.starts_with(MAGIC)
+}
+
+/// Returns the number of channels of the stream and whether it loops.
+pub fn info(data: &[u8]) -> Result<(usize, bool), Error> {
+    ensure!(is_brstm(data), "The file is not a BRSTM file");
+    let base = BE::read_u32(&data[OFFSET_HEAD..]) as usize + CHUNK_HEADER_LEN;
+    ensure!(
+        base + REFERENCE_LEN <= data.len(),
+        "The HEAD chunk of the BRSTM file is out of bounds"
+    );
+    let stream = base + BE::read_u32(&data[base + 4..]) as usize;
+    ensure!(
+        stream + STREAM_INFO_LEN <= data.len(),
+        "The stream info of the BRSTM file is out of bounds"
+    );
+    Ok((
+        data[stream + OFFSET_NUM_CHANNELS] as usize,
+        data[stream + OFFSET_LOOP_FLAG] != 0,
+    ))
+}
+
+pub fn write(sound: &Sound) -> Result<Vec<u8>, Error> {
+    ensure!(
+        sound.sample_rate <= 0xFFFF,
+        "BRSTM files can't have a sample rate of {} Hz",
+        sound.sample_rate
+    );
+    let channels = sound
+        .channels
+        .iter()
+        .map(|samples| Channel::encode(samples))
+        .collect::<Vec<_>>();
+    let num_channels = channels.len();
+    let num_tracks = (num_channels + 1) / 2;
+    let num_samples = sound.len();
+    let num_blocks = (num_samples + SAMPLES_PER_BLOCK - 1) / SAMPLES_PER_BLOCK;
+    let last_block_samples = num_samples - (num_blocks - 1) * SAMPLES_PER_BLOCK;
+    let last_block_len = adpcm::encoded_len(last_block_samples);
+
+    let stream_offset = 3 * REFERENCE_LEN;
+    let track_offset = stream_offset + STREAM_INFO_LEN;
+    let channel_offset = track_offset + 4 + num_tracks * (REFERENCE_LEN + TRACK_LEN);
+
+    let mut head = Vec::new();
+    for &offset in &[stream_offset, track_offset, channel_offset] {
+        push_reference(&mut head, offset);
+    }
+
+    head.push(CODEC_ADPCM);
+    head.push(sound.loop_start.is_some() as u8);
+    head.push(num_channels as u8);
+    head.push(0);
+    push_u16(&mut head, sound.sample_rate as u16);
+    push_u16(&mut head, 0);
+    push_u32(&mut head, sound.loop_start.unwrap_or(0) as u32);
+    push_u32(&mut head, num_samples as u32);
+    // The offset of the blocks is filled in once the chunks are laid out
+    push_u32(&mut head, 0);
+    push_u32(&mut head, num_blocks as u32);
+    push_u32(&mut head, BLOCK_LEN as u32);
+    push_u32(&mut head, SAMPLES_PER_BLOCK as u32);
+    push_u32(&mut head, last_block_len as u32);
+    push_u32(&mut head, last_block_samples as u32);
+    push_u32(&mut head, align(last_block_len) as u32);
+    push_u32(&mut head, SAMPLES_PER_BLOCK as u32);
+    push_u32(&mut head, HISTORY_LEN as u32);
+
+    // Stereo tracks use pairs of channels
+    head.push(num_tracks as u8);
+    head.push(0);
+    push_u16(&mut head, 0);
+    let tracks = track_offset + 4 + num_tracks * REFERENCE_LEN;
+    for track in 0..num_tracks {
+        push_reference(&mut head, tracks + track * TRACK_LEN);
+    }
+    for track in 0..num_tracks {
+        let left = 2 * track;
+        let is_stereo = left + 1 < num_channels;
+        head.push(if is_stereo { 2 } else { 1 });
+        head.push(left as u8);
+        head.push(if is_stereo { left as u8 + 1 } else { 0 });
+        head.push(0);
+    }
+
+    head.push(num_channels as u8);
+    head.extend_from_slice(&[0; 3]);
+    let entry_len = REFERENCE_LEN + ADPCM_INFO_LEN;
+    let entries = channel_offset + 4 + num_channels * REFERENCE_LEN;
+    for index in 0..num_channels {
+        push_reference(&mut head, entries + index * entry_len);
+    }
+    for (index, channel) in channels.iter().enumerate() {
+        push_reference(&mut head, entries + index * entry_len + REFERENCE_LEN);
+        let start = head.len();
+        head.resize(start + ADPCM_INFO_LEN, 0);
+        channel.write_info(sound.loop_start, &mut head[start..]);
+    }
+
+    let mut adpc = Vec::with_capacity(num_blocks * num_channels * HISTORY_LEN);
+    for block in 0..num_blocks {
+        for channel in &channels {
+            let (hist1, hist2) = channel.history(block * SAMPLES_PER_BLOCK);
+            push_u16(&mut adpc, hist1 as u16);
+            push_u16(&mut adpc, hist2 as u16);
+        }
+    }
+
+    // The blocks start at the next alignment after the DATA chunk's header
+    let mut data = Vec::new();
+    push_u32(&mut data, (ALIGNMENT - CHUNK_HEADER_LEN) as u32);
+    data.resize(ALIGNMENT - CHUNK_HEADER_LEN, 0);
+    for block in 0..num_blocks {
+        for channel in &channels {
+            let block_data = &channel.data[block * BLOCK_LEN..];
+            if block + 1 == num_blocks {
+                data.extend_from_slice(&block_data[..last_block_len]);
+                let len = data.len() + align(last_block_len) - last_block_len;
+                data.resize(len, 0);
+            } else {
+                data.extend_from_slice(&block_data[..BLOCK_LEN]);
+            }
+        }
+    }
+
+    let head = chunk(b"HEAD", head);
+    let adpc = chunk(b"ADPC", adpc);
+    let data = chunk(b"DATA", data);
+    let head_offset = HEADER_LEN;
+    let adpc_offset = head_offset + head.len();
+    let data_offset = adpc_offset + adpc.len();
+
+    let mut out = vec![0; HEADER_LEN];
+    out[..MAGIC.len()].copy_from_slice(MAGIC);
+    BE::write_u16(&mut out[OFFSET_BYTE_ORDER_MARK..], BYTE_ORDER_MARK);
+    BE::write_u16(&mut out[OFFSET_VERSION..], VERSION);
+    BE::write_u32(
+        &mut out[OFFSET_FILE_SIZE..],
+        (data_offset + data.len()) as u32,
+    );
+    BE::write_u16(&mut out[OFFSET_HEADER_SIZE..], HEADER_LEN as u16);
+    BE::write_u16(&mut out[OFFSET_NUM_SECTIONS..], NUM_SECTIONS);
+    for &(offset, contents, contents_offset) in &[
+        (OFFSET_HEAD, &head, head_offset),
+        (OFFSET_ADPC, &adpc, adpc_offset),
+        (OFFSET_DATA, &data, data_offset),
+    ] {
+        BE::write_u32(&mut out[offset..], contents_offset as u32);
+        BE::write_u32(&mut out[offset + 4..], contents.len() as u32);
+    }
+
+    out.extend_from_slice(&head);
+    let blocks_offset = (data_offset + ALIGNMENT) as u32;
+    BE::write_u32(
+        &mut out[head_offset + CHUNK_HEADER_LEN + stream_offset + OFFSET_DATA_OFFSET..],
+        blocks_offset,
+    );
+    out.extend_from_slice(&adpc);
+    out.extend_from_slice(&data);
+
+    Ok(out)
+}
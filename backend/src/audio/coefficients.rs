@@ -0,0 +1,347 @@
+//! Fits the eight pairs of coefficients a channel of DSP ADPCM is predicted
+//! with, the way Nintendo's DSPCorrelateCoefs does. Every frame is solved for
+//! the pair that predicts it best from the samples before it. These pairs are
+//! then averaged into a single one, which is split in two and refined against
+//! all of the frames, three times over, until there are eight of them.
+
+use super::adpcm::{Coefficients, SAMPLES_PER_FRAME};
+
+/// A second order predictor, with the weights of the previous samples at the
+/// indices 1 and 2 and the weight of the sample itself at index 0.
+type Vector = [f64; 3];
+type Matrix = [[f64; 3]; 3];
+
+pub fn fit(samples: &[i16]) -> Coefficients {
+    let mut records = Vec::new();
+
+    // The previous frame followed by the current one
+    let mut history = [0.0; 2 * SAMPLES_PER_FRAME];
+    for chunk in samples.chunks(SAMPLES_PER_FRAME) {
+        for i in 0..SAMPLES_PER_FRAME {
+            history[i] = history[SAMPLES_PER_FRAME + i];
+            history[SAMPLES_PER_FRAME + i] = chunk.get(i).map_or(0.0, |&s| f64::from(s));
+        }
+
+        let mut vector = inner_product(&history);
+        if vector[0].abs() > 10.0 {
+            let mut matrix = outer_product(&history);
+            if let Some(pivots) = decompose(&mut matrix) {
+                solve(&matrix, &pivots, &mut vector);
+                if to_reflection(&mut vector) {
+                    records.push(finish_record(vector));
+                }
+            }
+        }
+    }
+
+    let mut average = [1.0, 0.0, 0.0];
+    for record in &records {
+        let filtered = matrix_filter(record);
+        average[1] += filtered[1];
+        average[2] += filtered[2];
+    }
+    if !records.is_empty() {
+        average[1] /= records.len() as f64;
+        average[2] /= records.len() as f64;
+    }
+
+    let mut best = [[0.0; 3]; 8];
+    best[0] = merge_finish_record(&average);
+    let mut count = 1;
+    while count < best.len() {
+        for i in 0..count {
+            best[count + i] = [best[i][0], best[i][1] - 0.01, best[i][2]];
+        }
+        count *= 2;
+        refine(&mut best[..count], &records);
+    }
+
+    let mut coefficients = [[0; 2]; 8];
+    for (pair, vector) in coefficients.iter_mut().zip(&best) {
+        for (coefficient, &weight) in pair.iter_mut().zip(&vector[1..]) {
+            *coefficient = (-weight * 2048.0).round().max(-32768.0).min(32767.0) as i16;
+        }
+    }
+    coefficients
+}
+
+/// The negated correlation of the current frame with itself, shifted by up to
+/// two samples.
+fn inner_product(history: &[f64; 2 * SAMPLES_PER_FRAME]) -> Vector {
+    let mut vector = [0.0; 3];
+    for (i, value) in vector.iter_mut().enumerate() {
+        for x in SAMPLES_PER_FRAME..2 * SAMPLES_PER_FRAME {
+            *value -= history[x - i] * history[x];
+        }
+    }
+    vector
+}
+
+/// The correlation of the previous samples of the frame with each other.
+fn outer_product(history: &[f64; 2 * SAMPLES_PER_FRAME]) -> Matrix {
+    let mut matrix = [[0.0; 3]; 3];
+    for x in 1..3 {
+        for y in 1..3 {
+            for z in SAMPLES_PER_FRAME..2 * SAMPLES_PER_FRAME {
+                matrix[x][y] += history[z - x] * history[z - y];
+            }
+        }
+    }
+    matrix
+}
+
+/// Decomposes the matrix into its LU form with partial pivoting. Nothing is
+/// returned if the matrix is too close to being singular.
+fn decompose(matrix: &mut Matrix) -> Option<[usize; 3]> {
+    let mut scales = [0.0; 3];
+    for x in 1..3 {
+        let value = matrix[x][1].abs().max(matrix[x][2].abs());
+        if value < ::std::f64::EPSILON {
+            return None;
+        }
+        scales[x] = 1.0 / value;
+    }
+
+    let mut pivots = [0; 3];
+    let mut max_index = 0;
+    for i in 1..3 {
+        for x in 1..i {
+            let mut value = matrix[x][i];
+            for y in 1..x {
+                value -= matrix[x][y] * matrix[y][i];
+            }
+            matrix[x][i] = value;
+        }
+
+        let mut max = 0.0;
+        for x in i..3 {
+            let mut value = matrix[x][i];
+            for y in 1..i {
+                value -= matrix[x][y] * matrix[y][i];
+            }
+            matrix[x][i] = value;
+            if value.abs() * scales[x] >= max {
+                max = value.abs() * scales[x];
+                max_index = x;
+            }
+        }
+
+        if max_index != i {
+            matrix.swap(max_index, i);
+            scales[max_index] = scales[i];
+        }
+        pivots[i] = max_index;
+
+        if matrix[i][i] == 0.0 {
+            return None;
+        }
+        if i != 2 {
+            let reciprocal = 1.0 / matrix[i][i];
+            for row in &mut matrix[i + 1..] {
+                row[i] *= reciprocal;
+            }
+        }
+    }
+
+    let diagonal = [matrix[1][1].abs(), matrix[2][2].abs()];
+    let min = diagonal[0].min(diagonal[1]);
+    let max = diagonal[0].max(diagonal[1]);
+    if min / max < 1.0e-10 {
+        None
+    } else {
+        Some(pivots)
+    }
+}
+
+/// Solves the decomposed matrix for the vector by substituting forwards and
+/// backwards.
+fn solve(matrix: &Matrix, pivots: &[usize; 3], vector: &mut Vector) {
+    let mut first_nonzero = 0;
+    for i in 1..3 {
+        let mut value = vector[pivots[i]];
+        vector[pivots[i]] = vector[i];
+        if first_nonzero != 0 {
+            for y in first_nonzero..i {
+                value -= vector[y] * matrix[i][y];
+            }
+        } else if value != 0.0 {
+            first_nonzero = i;
+        }
+        vector[i] = value;
+    }
+
+    for i in (1..3).rev() {
+        let mut value = vector[i];
+        for y in i + 1..3 {
+            value -= vector[y] * matrix[i][y];
+        }
+        vector[i] = value / matrix[i][i];
+    }
+    vector[0] = 1.0;
+}
+
+/// Turns the predictor into reflection coefficients and tells whether it's
+/// stable.
+fn to_reflection(vector: &mut Vector) -> bool {
+    let v2 = vector[2];
+    let denominator = 1.0 - v2 * v2;
+    if denominator == 0.0 {
+        return false;
+    }
+    vector[0] = (vector[0] - v2 * v2) / denominator;
+    vector[1] = (vector[1] - vector[1] * v2) / denominator;
+    vector[1].abs() <= 1.0
+}
+
+/// Turns the reflection coefficients back into a predictor, keeping it stable.
+fn finish_record(mut reflection: Vector) -> Vector {
+    for value in &mut reflection[1..] {
+        *value = value.max(-0.999_999_999_9).min(0.999_999_999_9);
+    }
+    [
+        1.0,
+        reflection[2] * reflection[1] + reflection[1],
+        reflection[2],
+    ]
+}
+
+/// The autocorrelation of the predictor's impulse response.
+fn matrix_filter(source: &Vector) -> Vector {
+    let mut matrix = [[0.0; 3]; 3];
+    matrix[2][0] = 1.0;
+    for i in 1..3 {
+        matrix[2][i] = -source[i];
+    }
+    for i in (1..3).rev() {
+        let denominator = 1.0 - matrix[i][i] * matrix[i][i];
+        for y in 1..i + 1 {
+            matrix[i - 1][y] = (matrix[i][i] * matrix[i][y] + matrix[i][y]) / denominator;
+        }
+    }
+
+    let mut out = [1.0, 0.0, 0.0];
+    for i in 1..3 {
+        for y in 1..i + 1 {
+            out[i] += matrix[i][y] * out[i - y];
+        }
+    }
+    out
+}
+
+/// Turns an autocorrelation back into a predictor, like the Levinson-Durbin
+/// recursion does.
+fn merge_finish_record(source: &Vector) -> Vector {
+    let mut out = [1.0, 0.0, 0.0];
+    let mut reflection = [0.0; 3];
+    let mut error = source[0];
+    for i in 1..3 {
+        let mut sum = 0.0;
+        for y in 1..i {
+            sum += out[y] * source[i - y];
+        }
+        out[i] = if error > 0.0 {
+            -(sum + source[i]) / error
+        } else {
+            0.0
+        };
+        reflection[i] = out[i];
+        for y in 1..i {
+            out[y] += out[i] * out[i - y];
+        }
+        error *= 1.0 - out[i] * out[i];
+    }
+    finish_record(reflection)
+}
+
+/// How badly the predictor fits the frame the record was made for.
+fn distance(predictor: &Vector, record: &Vector) -> f64 {
+    let value = (record[2] * record[1] - record[1]) / (1.0 - record[2] * record[2]);
+    let energy =
+        predictor[0] * predictor[0] + predictor[1] * predictor[1] + predictor[2] * predictor[2];
+    let lag1 = predictor[0] * predictor[1] + predictor[1] * predictor[2];
+    let lag2 = predictor[0] * predictor[2];
+    energy + 2.0 * value * lag1 + 2.0 * (-record[1] * value - record[2]) * lag2
+}
+
+/// Assigns every record to the predictor that fits it best and replaces each
+/// predictor with the average of its records, twice.
+fn refine(best: &mut [Vector], records: &[Vector]) {
+    for _ in 0..2 {
+        let mut sums = [[0.0; 3]; 8];
+        let mut counts = [0; 8];
+        for record in records {
+            let mut index = 0;
+            let mut min = 1.0e30;
+            for (i, predictor) in best.iter().enumerate() {
+                let distance = distance(predictor, record);
+                if distance < min {
+                    min = distance;
+                    index = i;
+                }
+            }
+            counts[index] += 1;
+            let filtered = matrix_filter(record);
+            for (sum, value) in sums[index].iter_mut().zip(&filtered) {
+                *sum += value;
+            }
+        }
+
+        for ((predictor, sum), &count) in best.iter_mut().zip(&mut sums).zip(&counts) {
+            if count > 0 {
+                for value in sum.iter_mut() {
+                    *value /= f64::from(count);
+                }
+            }
+            *predictor = merge_finish_record(sum);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Noise shaped by s[n] = 1.6 s[n - 1] - 0.8 s[n - 2], like a resonance
+    /// of an instrument.
+    fn resonance(len: usize) -> Vec<i16> {
+        let mut seed = 1u32;
+        let (mut hist1, mut hist2) = (0.0, 0.0);
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let noise = f64::from((seed >> 16) & 0x7FFF) - 16384.0;
+                let sample = 1.6 * hist1 - 0.8 * hist2 + 0.1 * noise;
+                hist2 = hist1;
+                hist1 = sample;
+                sample.round() as i16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn silence_is_predicted_as_silence() {
+        assert_eq!(fit(&[0; 1000]), [[0; 2]; 8]);
+    }
+
+    #[test]
+    fn fits_the_resonance_of_a_sound() {
+        let coefficients = fit(&resonance(14000));
+        let expected = [(1.6 * 2048.0) as i32, (-0.8 * 2048.0) as i32];
+        assert!(
+            coefficients.iter().any(|pair| {
+                (i32::from(pair[0]) - expected[0]).abs() <= 128
+                    && (i32::from(pair[1]) - expected[1]).abs() <= 128
+            }),
+            "{:?} doesn't contain {:?}",
+            coefficients,
+            expected
+        );
+    }
+
+    #[test]
+    fn fits_different_coefficients_for_different_sounds() {
+        let samples = resonance(14000);
+        let every_other = samples.iter().step_by(2).cloned().collect::<Vec<_>>();
+        assert_ne!(fit(&samples), fit(&every_other));
+    }
+}
@@ -0,0 +1,59 @@
+//! DSP files contain a single channel of DSP ADPCM after a header, which holds
+//! what the DSP needs to be told to play the channel. The positions in the
+//! header are counted in nibbles, including the headers of the frames.
+
+use super::adpcm::{Channel, FRAME_LEN, SAMPLES_PER_FRAME};
+use super::Sound;
+use byteorder::{ByteOrder, BE};
+use failure::Error;
+
+const HEADER_LEN: usize = 0x60;
+
+const OFFSET_NUM_SAMPLES: usize = 0x00;
+const OFFSET_NUM_NIBBLES: usize = 0x04;
+const OFFSET_SAMPLE_RATE: usize = 0x08;
+const OFFSET_LOOP_FLAG: usize = 0x0C;
+const OFFSET_LOOP_START: usize = 0x10;
+const OFFSET_LOOP_END: usize = 0x14;
+const OFFSET_CURRENT_ADDRESS: usize = 0x18;
+const OFFSET_INFO: usize = 0x1C;
+
+/// The position of the sample in nibbles.
+fn nibble_address(sample: usize) -> usize {
+    // The header of each frame takes up the first two nibbles
+    sample / SAMPLES_PER_FRAME * 2 * FRAME_LEN + sample % SAMPLES_PER_FRAME + 2
+}
+
+pub fn is_looping(data: &[u8]) -> Result<bool, Error> {
+    ensure!(data.len() >= HEADER_LEN, "The DSP file is truncated");
+    Ok(BE::read_u16(&data[OFFSET_LOOP_FLAG..]) != 0)
+}
+
+pub fn write(sound: &Sound) -> Result<Vec<u8>, Error> {
+    ensure!(
+        sound.channels.len() == 1,
+        "DSP files can only contain a single channel"
+    );
+    let samples = &sound.channels[0];
+    let channel = Channel::encode(samples);
+    let last = nibble_address(samples.len() - 1);
+
+    let mut out = vec![0; HEADER_LEN];
+    BE::write_u32(&mut out[OFFSET_NUM_SAMPLES..], samples.len() as u32);
+    BE::write_u32(&mut out[OFFSET_NUM_NIBBLES..], last as u32 + 1);
+    BE::write_u32(&mut out[OFFSET_SAMPLE_RATE..], sound.sample_rate);
+    BE::write_u16(
+        &mut out[OFFSET_LOOP_FLAG..],
+        sound.loop_start.is_some() as u16,
+    );
+    BE::write_u32(
+        &mut out[OFFSET_LOOP_START..],
+        nibble_address(sound.loop_start.unwrap_or(0)) as u32,
+    );
+    BE::write_u32(&mut out[OFFSET_LOOP_END..], last as u32);
+    BE::write_u32(&mut out[OFFSET_CURRENT_ADDRESS..], nibble_address(0) as u32);
+    channel.write_info(sound.loop_start, &mut out[OFFSET_INFO..]);
+    out.extend_from_slice(&channel.data);
+
+    Ok(out)
+}
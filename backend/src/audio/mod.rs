@@ -0,0 +1,75 @@
+//! Encodes WAV files as the DSP ADPCM of the GameCube's and the Wii's audio
+//! DSP, so they can replace the sounds of the games. The replaced sounds keep
+//! their format, which is either a DSP file with a single channel or a BRSTM
+//! stream. If the WAV file has no loop, the whole sound loops if the
+//! original sound did. These files may also be inside of U8 and RARC
+//! archives. Sound archives, like the BRSAR files of Wii games or the wave
+//! banks of JAudio, are out of scope: they aren't repacked, so the sounds
+//! inside of them can't be replaced, and they are refused as such.
+
+use failure::Error;
+
+pub mod adpcm;
+mod brstm;
+mod coefficients;
+mod dsp;
+mod wav;
+
+/// The sound archives that are recognized to tell why they can't be replaced.
+const SOUND_ARCHIVES: &[(&[u8], &str)] = &[
+    (b"RSAR", "BRSAR sound archives"),
+    (b"RWAR", "BRWAR wave archives"),
+    (b"RWSD", "BRWSD sound sets"),
+    (b"RBNK", "BRBNK sound banks"),
+    (b"WSYS", "JAudio wave banks"),
+];
+
+pub struct Sound {
+    pub sample_rate: u32,
+    /// The samples of each channel.
+    pub channels: Vec<Vec<i16>>,
+    /// The sample the sound jumps back to once it ends.
+    pub loop_start: Option<usize>,
+}
+
+impl Sound {
+    fn len(&self) -> usize {
+        self.channels.first().map_or(0, Vec::len)
+    }
+}
+
+/// Encodes the WAV file in the format of the original sound at the path.
+pub fn replace_sound(path: &str, original: &[u8], wav: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut sound = wav::parse(wav)?;
+    ensure!(sound.len() > 0, "The WAV file contains no samples");
+
+    if brstm::is_brstm(original) {
+        let (num_channels, is_looping) = brstm::info(original)?;
+        ensure!(
+            sound.channels.len() == num_channels,
+            "The sound has {} channels, but the original one has {}",
+            sound.channels.len(),
+            num_channels
+        );
+        if is_looping && sound.loop_start.is_none() {
+            sound.loop_start = Some(0);
+        }
+        brstm::write(&sound)
+    } else if path.to_lowercase().ends_with(".dsp") {
+        if dsp::is_looping(original)? && sound.loop_start.is_none() {
+            sound.loop_start = Some(0);
+        }
+        dsp::write(&sound)
+    } else if let Some(&(_, name)) = SOUND_ARCHIVES
+        .iter()
+        .find(|&&(magic, _)| original.starts_with(magic))
+    {
+        bail!(
+            "{} aren't repacked, so the sounds inside of them can't be replaced. Only DSP \
+             files and BRSTM streams can be replaced with sounds.",
+            name
+        )
+    } else {
+        bail!("Only DSP files and BRSTM streams can be replaced with sounds")
+    }
+}
@@ -0,0 +1,89 @@
+//! Based on http://soundfile.sapp.org/doc/WaveFormat/
+//!
+//! WAV files are RIFF files with a `fmt ` chunk that describes the samples in
+//! the `data` chunk. The loop is taken from the `smpl` chunk, which most
+//! audio editors write when a loop is set.
+
+use super::Sound;
+use byteorder::{ByteOrder, LE};
+use failure::{err_msg, Error};
+use riff;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+
+// Relative to the `fmt ` chunk
+const OFFSET_FORMAT_TAG: usize = 0x00;
+const OFFSET_CHANNELS: usize = 0x02;
+const OFFSET_SAMPLE_RATE: usize = 0x04;
+const OFFSET_BITS_PER_SAMPLE: usize = 0x0E;
+const FORMAT_LEN: usize = 0x10;
+
+// Relative to the `smpl` chunk
+const OFFSET_NUM_LOOPS: usize = 0x1C;
+const SAMPLER_LEN: usize = 0x24;
+const OFFSET_LOOP_START: usize = 0x08;
+const OFFSET_LOOP_END: usize = 0x0C;
+const LOOP_LEN: usize = 0x18;
+
+pub fn is_wav(data: &[u8]) -> bool {
+    riff::is_riff(data, b"WAVE")
+}
+
+/// Parses the WAV file. Samples after the end of the loop are never played,
+/// so they are cut off.
+pub fn parse(data: &[u8]) -> Result<Sound, Error> {
+    ensure!(is_wav(data), "The file is not a WAV file");
+    let chunks = riff::form_chunks(data)?;
+    let find = |kind: &[u8]| {
+        chunks
+            .iter()
+            .find(|&&(id, _)| id == kind)
+            .map(|&(_, contents)| contents)
+    };
+
+    let format = find(b"fmt ").ok_or_else(|| err_msg("The WAV file has no format"))?;
+    ensure!(
+        format.len() >= FORMAT_LEN
+            && LE::read_u16(&format[OFFSET_FORMAT_TAG..]) == WAVE_FORMAT_PCM
+            && LE::read_u16(&format[OFFSET_BITS_PER_SAMPLE..]) == 16,
+        "The WAV file needs to contain 16 bit PCM samples"
+    );
+    let num_channels = LE::read_u16(&format[OFFSET_CHANNELS..]) as usize;
+    ensure!(num_channels > 0, "The WAV file has no channels");
+
+    let samples = find(b"data").ok_or_else(|| err_msg("The WAV file has no samples"))?;
+    let mut channels = (0..num_channels)
+        .map(|_| Vec::with_capacity(samples.len() / 2 / num_channels))
+        .collect::<Vec<_>>();
+    for (index, sample) in samples.chunks(2).filter(|s| s.len() == 2).enumerate() {
+        channels[index % num_channels].push(LE::read_i16(sample));
+    }
+    let mut len = channels.iter().map(Vec::len).min().unwrap_or(0);
+
+    let loop_start = match find(b"smpl") {
+        Some(sampler)
+            if sampler.len() >= SAMPLER_LEN + LOOP_LEN
+                && LE::read_u32(&sampler[OFFSET_NUM_LOOPS..]) > 0 =>
+        {
+            let start = LE::read_u32(&sampler[SAMPLER_LEN + OFFSET_LOOP_START..]) as usize;
+            // The end of the loop is inclusive
+            let end = LE::read_u32(&sampler[SAMPLER_LEN + OFFSET_LOOP_END..]) as usize + 1;
+            ensure!(
+                start < end && end <= len,
+                "The loop of the WAV file is out of bounds"
+            );
+            len = end;
+            Some(start)
+        }
+        _ => None,
+    };
+    for channel in &mut channels {
+        channel.truncate(len);
+    }
+
+    Ok(Sound {
+        sample_rate: LE::read_u32(&format[OFFSET_SAMPLE_RATE..]),
+        channels,
+        loop_start,
+    })
+}
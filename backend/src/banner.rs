@@ -1,14 +1,19 @@
 //! Based on http://www.gc-forever.com/yagcd/chap14.html#sec14.1
+//!
+//! `BNR1` banners contain a single set of texts, while the `BNR2` banners of
+//! PAL games contain one for each of the languages in `LANGUAGES`. The texts
+//! of Japanese games are encoded as Shift JIS, all others as Windows-1252.
 
-use encoding_rs::{UTF_8, SHIFT_JIS};
+use encoding_rs::{SHIFT_JIS, WINDOWS_1252};
 use failure::{err_msg, Error};
+use texture::{rgb5a3_to_rgba, rgba_to_rgb5a3};
 
 const COLUMNS: usize = 24;
 const ROWS: usize = 8;
 const PIXELS_PER_COLUMN: usize = 4;
 const PIXELS_PER_ROW: usize = 4;
-const WIDTH: usize = 96;
-const HEIGHT: usize = 32;
+pub const WIDTH: usize = 96;
+pub const HEIGHT: usize = 32;
 const UNCOMPRESSED_BYTES_PER_PIXEL: usize = 4;
 const COMPRESSED_BYTES_PER_PIXEL: usize = 2;
 const UNCOMPRESSED_IMAGE_SIZE: usize = WIDTH * HEIGHT * UNCOMPRESSED_BYTES_PER_PIXEL;
@@ -18,16 +23,27 @@ const LONG_TEXT_LEN: usize = 0x40;
 const DESCRIPTION_LEN: usize = 0x80;
 const MAGIC_LEN: usize = 4;
 const OFFSET_IMAGE: usize = 0x20;
-const OFFSET_GAME_NAME: usize = OFFSET_IMAGE + COMPRESSED_IMAGE_SIZE;
+const OFFSET_TEXTS: usize = OFFSET_IMAGE + COMPRESSED_IMAGE_SIZE;
+// The offsets of the texts relative to the start of each language's texts
+const OFFSET_GAME_NAME: usize = 0;
 const OFFSET_DEVELOPER_NAME: usize = OFFSET_GAME_NAME + SHORT_TEXT_LEN;
 const OFFSET_FULL_GAME_NAME: usize = OFFSET_DEVELOPER_NAME + SHORT_TEXT_LEN;
 const OFFSET_FULL_DEVELOPER_NAME: usize = OFFSET_FULL_GAME_NAME + LONG_TEXT_LEN;
 const OFFSET_GAME_DESCRIPTION: usize = OFFSET_FULL_DEVELOPER_NAME + LONG_TEXT_LEN;
-const BANNER_LEN: usize = OFFSET_GAME_DESCRIPTION + DESCRIPTION_LEN;
+const TEXTS_LEN: usize = OFFSET_GAME_DESCRIPTION + DESCRIPTION_LEN;
+
+/// The languages of `BNR2` banners in the order they are stored in.
+pub const LANGUAGES: [&str; 6] = ["english", "german", "french", "spanish", "italian", "dutch"];
 
 pub struct Banner {
-    pub magic: [u8; MAGIC_LEN],
     pub image: [u8; UNCOMPRESSED_IMAGE_SIZE],
+    /// A single set of texts for `BNR1` banners or one for each language for
+    /// `BNR2` banners.
+    pub texts: Vec<BannerTexts>,
+}
+
+#[derive(Default, Clone)]
+pub struct BannerTexts {
     pub game_name: String,
     pub developer_name: String,
     pub full_game_name: String,
@@ -35,57 +51,57 @@ pub struct Banner {
     pub game_description: String,
 }
 
-fn a1rgb5_to_rgba(v: &[u8]) -> [u8; 4] {
-    let (x, y) = (v[0], v[1]);
-    // ARRRRRGG GGGBBBBB
-    let a = x >> 7;
-    let r = (x >> 2) & 0b11111;
-    let g = ((x & 0b11) << 3) | (y >> 5);
-    let b = y & 0b11111;
-
-    let r = (r as f32 * (255.0 / 31.0)) as u8;
-    let g = (g as f32 * (255.0 / 31.0)) as u8;
-    let b = (b as f32 * (255.0 / 31.0)) as u8;
-    let a = a * 255;
-
-    [r, g, b, a]
-}
-
-fn rgba_to_a1rgb5(v: &[u8]) -> [u8; 2] {
-    let (r, g, b, a) = (v[0], v[1], v[2], v[3]);
-    let r = (r as f32 * (31.0 / 255.0)).round() as u8;
-    let g = (g as f32 * (31.0 / 255.0)).round() as u8;
-    let b = (b as f32 * (31.0 / 255.0)).round() as u8;
-    let a = (a >= 128) as u8;
-
-    let x = (a << 7) | (r << 2) | (g >> 3);
-    let y = (g << 5) | b;
-
-    [x, y]
-}
-
 fn read_string(is_japanese: bool, bytes: &[u8]) -> Result<String, Error> {
     let end = bytes.iter().position(|&x| x == 0).unwrap_or(bytes.len());
     let bytes = &bytes[..end];
-    let encoding = if is_japanese { SHIFT_JIS } else { UTF_8 };
+    let encoding = if is_japanese { SHIFT_JIS } else { WINDOWS_1252 };
     Ok(encoding
         .decode_without_bom_handling_and_without_replacement(bytes)
         .ok_or_else(|| err_msg("Couldn't parse string"))?
         .into_owned())
 }
 
-fn write_string(is_japanese: bool, text: &str, bytes: &mut [u8]) {
-    let encoding = if is_japanese { SHIFT_JIS } else { UTF_8 };
-    encoding.new_encoder().encode_from_utf8(text, bytes, true);
+fn write_string(is_japanese: bool, text: &str, bytes: &mut [u8]) -> Result<(), Error> {
+    let encoding = if is_japanese { SHIFT_JIS } else { WINDOWS_1252 };
+    let (encoded, _, had_errors) = encoding.encode(text);
+    ensure!(
+        !had_errors,
+        "\"{}\" contains characters that can't be encoded as {}",
+        text,
+        encoding.name()
+    );
+    ensure!(
+        encoded.len() <= bytes.len(),
+        "\"{}\" is {} bytes long, but only {} bytes fit",
+        text,
+        encoded.len(),
+        bytes.len()
+    );
+    bytes[..encoded.len()].copy_from_slice(&encoded);
+    Ok(())
 }
 
 impl Banner {
-    pub fn parse(is_japanese: bool, data: &[u8]) -> Result<Self, Error> {
-        let mut magic = [0; MAGIC_LEN];
-        magic.copy_from_slice(&data[..MAGIC_LEN]);
-        if &magic != b"BNR1" && &magic != b"BNR2" {
-            panic!("Invalid Banner File");
+    /// Creates an empty banner with the given number of languages, which is
+    /// either 1 for a `BNR1` banner or 6 for a `BNR2` banner.
+    pub fn new(language_count: usize) -> Self {
+        Self {
+            image: [0; UNCOMPRESSED_IMAGE_SIZE],
+            texts: vec![Default::default(); language_count],
         }
+    }
+
+    pub fn parse(is_japanese: bool, data: &[u8]) -> Result<Self, Error> {
+        ensure!(data.len() >= MAGIC_LEN, "The banner is too small");
+        let language_count = match &data[..MAGIC_LEN] {
+            b"BNR1" => 1,
+            b"BNR2" => LANGUAGES.len(),
+            _ => bail!("Invalid banner file"),
+        };
+        ensure!(
+            data.len() >= OFFSET_TEXTS + language_count * TEXTS_LEN,
+            "The banner is too small"
+        );
 
         let image_data = &data[OFFSET_IMAGE..][..COMPRESSED_IMAGE_SIZE];
         let mut rgba_image = [0; UNCOMPRESSED_IMAGE_SIZE];
@@ -100,43 +116,49 @@ impl Banner {
                         let x = column_x + x;
                         let pixel_index = UNCOMPRESSED_BYTES_PER_PIXEL * (y * WIDTH + x);
                         let dst = &mut rgba_image[pixel_index..][..UNCOMPRESSED_BYTES_PER_PIXEL];
-                        dst.copy_from_slice(&a1rgb5_to_rgba(image_data.next().unwrap()));
+                        dst.copy_from_slice(&rgb5a3_to_rgba(image_data.next().unwrap()));
                     }
                 }
             }
         }
 
-        let game_name = read_string(is_japanese, &data[OFFSET_GAME_NAME..][..SHORT_TEXT_LEN])?;
-        let developer_name = read_string(
-            is_japanese,
-            &data[OFFSET_DEVELOPER_NAME..][..SHORT_TEXT_LEN],
-        )?;
-        let full_game_name =
-            read_string(is_japanese, &data[OFFSET_FULL_GAME_NAME..][..LONG_TEXT_LEN])?;
-        let full_developer_name = read_string(
-            is_japanese,
-            &data[OFFSET_FULL_DEVELOPER_NAME..][..LONG_TEXT_LEN],
-        )?;
-        let game_description = read_string(
-            is_japanese,
-            &data[OFFSET_GAME_DESCRIPTION..][..DESCRIPTION_LEN],
-        )?;
+        let mut texts = Vec::with_capacity(language_count);
+        for data in data[OFFSET_TEXTS..].chunks(TEXTS_LEN).take(language_count) {
+            texts.push(BannerTexts {
+                game_name: read_string(is_japanese, &data[OFFSET_GAME_NAME..][..SHORT_TEXT_LEN])?,
+                developer_name: read_string(
+                    is_japanese,
+                    &data[OFFSET_DEVELOPER_NAME..][..SHORT_TEXT_LEN],
+                )?,
+                full_game_name: read_string(
+                    is_japanese,
+                    &data[OFFSET_FULL_GAME_NAME..][..LONG_TEXT_LEN],
+                )?,
+                full_developer_name: read_string(
+                    is_japanese,
+                    &data[OFFSET_FULL_DEVELOPER_NAME..][..LONG_TEXT_LEN],
+                )?,
+                game_description: read_string(
+                    is_japanese,
+                    &data[OFFSET_GAME_DESCRIPTION..][..DESCRIPTION_LEN],
+                )?,
+            });
+        }
 
         Ok(Self {
-            magic,
             image: rgba_image,
-            game_name,
-            developer_name,
-            full_game_name,
-            full_developer_name,
-            game_description,
+            texts,
         })
     }
 
-    pub fn to_bytes(&self, is_japanese: bool) -> [u8; BANNER_LEN] {
-        let mut data = [0; BANNER_LEN];
+    pub fn to_bytes(&self, is_japanese: bool) -> Result<Vec<u8>, Error> {
+        let mut data = vec![0; OFFSET_TEXTS + self.texts.len() * TEXTS_LEN];
 
-        data[..MAGIC_LEN].copy_from_slice(&self.magic);
+        data[..MAGIC_LEN].copy_from_slice(if self.texts.len() == 1 {
+            b"BNR1"
+        } else {
+            b"BNR2"
+        });
 
         {
             let image_data = &mut data[OFFSET_IMAGE..][..COMPRESSED_IMAGE_SIZE];
@@ -154,39 +176,45 @@ impl Banner {
                             image_data
                                 .next()
                                 .unwrap()
-                                .copy_from_slice(&rgba_to_a1rgb5(src));
+                                .copy_from_slice(&rgba_to_rgb5a3(src));
                         }
                     }
                 }
             }
         }
 
-        write_string(
-            is_japanese,
-            &self.game_name,
-            &mut data[OFFSET_GAME_NAME..][..SHORT_TEXT_LEN],
-        );
-        write_string(
-            is_japanese,
-            &self.developer_name,
-            &mut data[OFFSET_DEVELOPER_NAME..][..SHORT_TEXT_LEN],
-        );
-        write_string(
-            is_japanese,
-            &self.full_game_name,
-            &mut data[OFFSET_FULL_GAME_NAME..][..LONG_TEXT_LEN],
-        );
-        write_string(
-            is_japanese,
-            &self.full_developer_name,
-            &mut data[OFFSET_FULL_DEVELOPER_NAME..][..LONG_TEXT_LEN],
-        );
-        write_string(
-            is_japanese,
-            &self.game_description,
-            &mut data[OFFSET_GAME_DESCRIPTION..][..DESCRIPTION_LEN],
-        );
+        for (texts, data) in self
+            .texts
+            .iter()
+            .zip(data[OFFSET_TEXTS..].chunks_mut(TEXTS_LEN))
+        {
+            write_string(
+                is_japanese,
+                &texts.game_name,
+                &mut data[OFFSET_GAME_NAME..][..SHORT_TEXT_LEN],
+            )?;
+            write_string(
+                is_japanese,
+                &texts.developer_name,
+                &mut data[OFFSET_DEVELOPER_NAME..][..SHORT_TEXT_LEN],
+            )?;
+            write_string(
+                is_japanese,
+                &texts.full_game_name,
+                &mut data[OFFSET_FULL_GAME_NAME..][..LONG_TEXT_LEN],
+            )?;
+            write_string(
+                is_japanese,
+                &texts.full_developer_name,
+                &mut data[OFFSET_FULL_DEVELOPER_NAME..][..LONG_TEXT_LEN],
+            )?;
+            write_string(
+                is_japanese,
+                &texts.game_description,
+                &mut data[OFFSET_GAME_DESCRIPTION..][..DESCRIPTION_LEN],
+            )?;
+        }
 
-        data
+        Ok(data)
     }
 }
@@ -0,0 +1,509 @@
+//! Based on http://wiki.tockdom.com/wiki/BMG_(File_Format)
+//!
+//! BMG files store the messages of a game. The messages are looked up by the
+//! ID in the `MID1` section or by their index, if there is no such section.
+//! Besides text, messages contain commands for things like colors, icons or
+//! the player's name. These are written as their bytes in hex inside of
+//! braces, like `{FF 00 00 01}`, while literal braces are written as `{{`.
+//! All sections other than `INF1` and `DAT1` are kept as they are. Games
+//! with their own character tables have their text encoded and decoded
+//! through a TBL file instead of the encoding of the BMG file.
+
+use byteorder::{ByteOrder, BE};
+use encoding_rs::{Encoding as TextEncoding, SHIFT_JIS, WINDOWS_1252};
+use failure::{err_msg, Error, ResultExt};
+use std::char;
+use text_encoding::Table;
+
+const MAGIC: &[u8] = b"MESGbmg1";
+const HEADER_LEN: usize = 0x20;
+const SECTION_HEADER_LEN: usize = 8;
+const ALIGNMENT: usize = 0x20;
+
+const OFFSET_FILE_SIZE: usize = 0x08;
+const OFFSET_NUM_SECTIONS: usize = 0x0C;
+const OFFSET_ENCODING: usize = 0x10;
+
+// Relative to the start of each section
+const OFFSET_SECTION_SIZE: usize = 0x04;
+const OFFSET_NUM_ENTRIES: usize = 0x08;
+const OFFSET_ENTRY_LEN: usize = 0x0A;
+const INF1_HEADER_LEN: usize = 0x10;
+const MID1_HEADER_LEN: usize = 0x10;
+
+/// The character that starts a command.
+const COMMAND: u16 = 0x1A;
+
+#[derive(Copy, Clone, PartialEq)]
+enum Encoding {
+    Windows1252,
+    Utf16,
+    ShiftJis,
+    Utf8,
+}
+
+impl Encoding {
+    /// The size of each code unit.
+    fn unit_len(self) -> usize {
+        if self == Encoding::Utf16 {
+            2
+        } else {
+            1
+        }
+    }
+
+    fn legacy(self) -> Option<&'static TextEncoding> {
+        match self {
+            Encoding::Windows1252 => Some(WINDOWS_1252),
+            Encoding::ShiftJis => Some(SHIFT_JIS),
+            Encoding::Utf16 | Encoding::Utf8 => None,
+        }
+    }
+
+    fn encode(self, text: &str, out: &mut Vec<u8>) -> Result<(), Error> {
+        if let Some(encoding) = self.legacy() {
+            let (encoded, _, had_errors) = encoding.encode(text);
+            ensure!(
+                !had_errors,
+                "\"{}\" contains characters that can't be encoded as {}",
+                text,
+                encoding.name()
+            );
+            out.extend_from_slice(&encoded);
+            return Ok(());
+        }
+        match self {
+            Encoding::Utf16 => {
+                for unit in text.encode_utf16() {
+                    let mut buf = [0; 2];
+                    BE::write_u16(&mut buf, unit);
+                    out.extend_from_slice(&buf);
+                }
+            }
+            _ => out.extend_from_slice(text.as_bytes()),
+        }
+        Ok(())
+    }
+
+    fn decode(self, data: &[u8]) -> Result<String, Error> {
+        if let Some(encoding) = self.legacy() {
+            return Ok(encoding
+                .decode_without_bom_handling_and_without_replacement(data)
+                .ok_or_else(|| format_err!("A message is not valid {}", encoding.name()))?
+                .into_owned());
+        }
+        match self {
+            Encoding::Utf16 => {
+                let units = data.chunks(2).filter(|u| u.len() == 2).map(BE::read_u16);
+                char::decode_utf16(units)
+                    .collect::<Result<String, _>>()
+                    .map_err(|_| err_msg("A message is not valid UTF-16"))
+            }
+            _ => String::from_utf8(data.to_owned())
+                .map_err(|_| err_msg("A message is not valid UTF-8")),
+        }
+    }
+
+    fn read_unit(self, data: &[u8]) -> u16 {
+        if self == Encoding::Utf16 {
+            BE::read_u16(data)
+        } else {
+            data[0] as u16
+        }
+    }
+
+    fn write_unit(self, unit: u16, out: &mut Vec<u8>) {
+        if self == Encoding::Utf16 {
+            out.push((unit >> 8) as u8);
+        }
+        out.push(unit as u8);
+    }
+}
+
+struct Entry {
+    /// The encoded text without the terminator.
+    text: Vec<u8>,
+    attributes: Vec<u8>,
+}
+
+pub struct Bmg {
+    header: Vec<u8>,
+    encoding: Encoding,
+    /// The magic and the contents of each section after its header.
+    sections: Vec<([u8; 4], Vec<u8>)>,
+    entries: Vec<Entry>,
+    ids: Option<Vec<u32>>,
+}
+
+fn align(offset: usize) -> usize {
+    (offset + ALIGNMENT - 1) & !(ALIGNMENT - 1)
+}
+
+impl Bmg {
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        ensure!(
+            data.len() >= HEADER_LEN && data.starts_with(MAGIC),
+            "The file is not a BMG file"
+        );
+        let encoding = match data[OFFSET_ENCODING] {
+            0 | 1 => Encoding::Windows1252,
+            2 => Encoding::Utf16,
+            3 => Encoding::ShiftJis,
+            4 => Encoding::Utf8,
+            encoding => bail!("The BMG file uses the unknown encoding {}", encoding),
+        };
+
+        let mut sections = Vec::new();
+        let mut offset = HEADER_LEN;
+        for _ in 0..BE::read_u32(&data[OFFSET_NUM_SECTIONS..]) {
+            ensure!(
+                offset + SECTION_HEADER_LEN <= data.len(),
+                "The sections of the BMG file are out of bounds"
+            );
+            let len = BE::read_u32(&data[offset + OFFSET_SECTION_SIZE..]) as usize;
+            ensure!(
+                len >= SECTION_HEADER_LEN && offset + len <= data.len(),
+                "The sections of the BMG file are out of bounds"
+            );
+            let mut magic = [0; 4];
+            magic.copy_from_slice(&data[offset..][..4]);
+            sections.push((
+                magic,
+                data[offset + SECTION_HEADER_LEN..][..len - SECTION_HEADER_LEN].to_owned(),
+            ));
+            offset += len;
+        }
+
+        let (entries, ids) = {
+            let section = |magic: &[u8]| sections.iter().find(|s| s.0 == magic).map(|s| &s.1[..]);
+            let info =
+                section(b"INF1").ok_or_else(|| err_msg("The BMG file has no INF1 section"))?;
+            let text =
+                section(b"DAT1").ok_or_else(|| err_msg("The BMG file has no DAT1 section"))?;
+            let info_header = INF1_HEADER_LEN - SECTION_HEADER_LEN;
+            ensure!(info.len() >= info_header, "The INF1 section is too small");
+
+            let num_entries =
+                BE::read_u16(&info[OFFSET_NUM_ENTRIES - SECTION_HEADER_LEN..]) as usize;
+            let entry_len = BE::read_u16(&info[OFFSET_ENTRY_LEN - SECTION_HEADER_LEN..]) as usize;
+            ensure!(
+                entry_len >= 4 && info_header + num_entries * entry_len <= info.len(),
+                "The INF1 section is too small"
+            );
+
+            let mut entries = Vec::with_capacity(num_entries);
+            for entry in info[info_header..].chunks(entry_len).take(num_entries) {
+                let start = BE::read_u32(entry) as usize;
+                let len = message_len(encoding, text, start)?;
+                entries.push(Entry {
+                    text: text[start..][..len].to_owned(),
+                    attributes: entry[4..].to_owned(),
+                });
+            }
+
+            let ids = match section(b"MID1") {
+                Some(ids) => {
+                    let ids_header = MID1_HEADER_LEN - SECTION_HEADER_LEN;
+                    ensure!(ids.len() >= ids_header, "The MID1 section is too small");
+                    let num_ids = BE::read_u16(ids) as usize;
+                    ensure!(
+                        ids_header + num_ids * 4 <= ids.len(),
+                        "The MID1 section is too small"
+                    );
+                    Some(
+                        ids[ids_header..]
+                            .chunks(4)
+                            .take(num_ids)
+                            .map(BE::read_u32)
+                            .collect(),
+                    )
+                }
+                None => None,
+            };
+
+            (entries, ids)
+        };
+
+        Ok(Self {
+            header: data[..HEADER_LEN].to_owned(),
+            encoding,
+            sections,
+            entries,
+            ids,
+        })
+    }
+
+    /// Replaces the text of the message with the given ID. The text is encoded
+    /// through the table, if there is one.
+    pub fn set_message(&mut self, id: u32, text: &str, table: Option<&Table>) -> Result<(), Error> {
+        let index = match self.ids {
+            Some(ref ids) => ids.iter().position(|&i| i == id),
+            None if (id as usize) < self.entries.len() => Some(id as usize),
+            None => None,
+        };
+        let index = index.ok_or_else(|| format_err!("There is no message with the ID {}", id))?;
+        self.entries[index].text = self.encode(text, table)?;
+        Ok(())
+    }
+
+    /// The IDs and the texts of all the messages, in the same form the
+    /// messages are edited in. The texts are decoded through the table, if
+    /// there is one.
+    pub fn messages(&self, table: Option<&Table>) -> Result<Vec<(u32, String)>, Error> {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                let id = match self.ids {
+                    Some(ref ids) => ids.get(index).cloned().unwrap_or(index as u32),
+                    None => index as u32,
+                };
+                let text = self
+                    .decode(&entry.text, table)
+                    .with_context(|_| format!("Couldn't decode the message {}", id))?;
+                Ok((id, text))
+            }).collect()
+    }
+
+    fn encode_text(
+        &self,
+        text: &str,
+        table: Option<&Table>,
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        match table {
+            Some(table) => {
+                out.extend(table.encode(text)?);
+                Ok(())
+            }
+            None => self.encoding.encode(text, out),
+        }
+    }
+
+    fn decode_text(
+        &self,
+        data: &[u8],
+        table: Option<&Table>,
+        out: &mut String,
+    ) -> Result<(), Error> {
+        let text = match table {
+            Some(table) => table.decode(data)?,
+            None => self.encoding.decode(data)?,
+        };
+        out.push_str(&text.replace('{', "{{"));
+        Ok(())
+    }
+
+    fn decode(&self, data: &[u8], table: Option<&Table>) -> Result<String, Error> {
+        let unit_len = self.encoding.unit_len();
+        let mut out = String::new();
+        let (mut start, mut pos) = (0, 0);
+        while pos + unit_len <= data.len() {
+            if self.encoding.read_unit(&data[pos..]) != COMMAND {
+                pos += unit_len;
+                continue;
+            }
+            self.decode_text(&data[start..pos], table, &mut out)?;
+            let len = *data
+                .get(pos + unit_len)
+                .ok_or_else(|| err_msg("A command of the message is out of bounds"))?
+                as usize;
+            let command = data
+                .get(pos + unit_len + 1..pos + len)
+                .ok_or_else(|| err_msg("A command of the message is out of bounds"))?;
+            let bytes = command
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>();
+            out.push('{');
+            out.push_str(&bytes.join(" "));
+            out.push('}');
+            pos += len;
+            start = pos;
+        }
+        self.decode_text(&data[start..], table, &mut out)?;
+        Ok(out)
+    }
+
+    fn encode(&self, text: &str, table: Option<&Table>) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::new();
+        let mut rest = text;
+        while let Some(index) = rest.find('{') {
+            self.encode_text(&rest[..index], table, &mut out)?;
+            rest = &rest[index + 1..];
+            if rest.starts_with('{') {
+                self.encode_text("{", table, &mut out)?;
+                rest = &rest[1..];
+                continue;
+            }
+
+            let end = rest
+                .find('}')
+                .ok_or_else(|| format_err!("The command in \"{}\" is missing its \"}}\"", text))?;
+            let mut command = Vec::new();
+            for byte in rest[..end].split_whitespace() {
+                command.push(u8::from_str_radix(byte, 16).map_err(|_| {
+                    format_err!(
+                        "\"{}\" in the command of \"{}\" is not a hex byte",
+                        byte,
+                        text
+                    )
+                })?);
+            }
+            rest = &rest[end + 1..];
+
+            // The length includes the command character and the length itself
+            let len = self.encoding.unit_len() + 1 + command.len();
+            ensure!(len <= 0xFF, "A command in \"{}\" is too long", text);
+            self.encoding.write_unit(COMMAND, &mut out);
+            out.push(len as u8);
+            out.extend_from_slice(&command);
+        }
+        self.encode_text(rest, table, &mut out)?;
+        Ok(out)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        // Empty messages all point to the terminator at the start of the texts
+        let unit_len = self.encoding.unit_len();
+        let mut text = vec![0; unit_len];
+        let mut offsets = Vec::with_capacity(self.entries.len());
+        for entry in &self.entries {
+            offsets.push(if entry.text.is_empty() { 0 } else { text.len() });
+            if !entry.text.is_empty() {
+                text.extend_from_slice(&entry.text);
+                let len = text.len() + unit_len;
+                text.resize(len, 0);
+            }
+        }
+
+        let mut out = self.header.clone();
+        for &(ref magic, ref contents) in &self.sections {
+            let start = out.len();
+            out.extend_from_slice(magic);
+            out.extend_from_slice(&[0; 4]);
+            match magic {
+                b"INF1" => {
+                    let info_header = INF1_HEADER_LEN - SECTION_HEADER_LEN;
+                    out.extend_from_slice(&contents[..info_header]);
+                    for (entry, &offset) in self.entries.iter().zip(&offsets) {
+                        let mut buf = [0; 4];
+                        BE::write_u32(&mut buf, offset as u32);
+                        out.extend_from_slice(&buf);
+                        out.extend_from_slice(&entry.attributes);
+                    }
+                }
+                b"DAT1" => out.extend_from_slice(&text),
+                _ => out.extend_from_slice(contents),
+            }
+            let end = align(out.len());
+            out.resize(end, 0);
+            let len = (end - start) as u32;
+            BE::write_u32(&mut out[start + OFFSET_SECTION_SIZE..], len);
+        }
+
+        let len = out.len() as u32;
+        BE::write_u32(&mut out[OFFSET_FILE_SIZE..], len);
+        Ok(out)
+    }
+}
+
+/// Returns the length of the message at the offset up to its terminator,
+/// skipping over the commands, as they may contain zeros.
+fn message_len(encoding: Encoding, text: &[u8], start: usize) -> Result<usize, Error> {
+    let unit_len = encoding.unit_len();
+    let mut pos = start;
+    loop {
+        ensure!(
+            pos + unit_len <= text.len(),
+            "A message of the BMG file is missing its terminator"
+        );
+        match encoding.read_unit(&text[pos..]) {
+            0 => return Ok(pos - start),
+            COMMAND => {
+                ensure!(
+                    pos + unit_len < text.len(),
+                    "A command of the BMG file is out of bounds"
+                );
+                let len = text[pos + unit_len] as usize;
+                ensure!(
+                    len > unit_len,
+                    "A command of the BMG file has an invalid length"
+                );
+                pos += len;
+            }
+            _ => pos += unit_len,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(magic: &[u8], contents: &[u8]) -> Vec<u8> {
+        let mut data = magic.to_owned();
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(contents);
+        let len = align(data.len());
+        data.resize(len, 0);
+        BE::write_u32(&mut data[OFFSET_SECTION_SIZE..], len as u32);
+        data
+    }
+
+    /// A BMG file with the messages "Hello" and "A", followed by a command
+    /// and "B", with the IDs 100 and 200.
+    fn bmg() -> Vec<u8> {
+        let mut info = vec![0, 2, 0, 8, 0, 0, 0, 0];
+        info.extend_from_slice(&[0, 0, 0, 1, 0xAA, 0xAA, 0xAA, 0xAA]);
+        info.extend_from_slice(&[0, 0, 0, 7, 0xBB, 0xBB, 0xBB, 0xBB]);
+        let text = b"\0Hello\0A\x1A\x05\xFF\0\x01B\0";
+        let ids = [0, 2, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 100, 0, 0, 0, 200];
+
+        let mut data = MAGIC.to_owned();
+        data.resize(HEADER_LEN, 0);
+        BE::write_u32(&mut data[OFFSET_NUM_SECTIONS..], 3);
+        data[OFFSET_ENCODING] = 1;
+        data.extend(section(b"INF1", &info));
+        data.extend(section(b"DAT1", text));
+        data.extend(section(b"MID1", &ids));
+        let len = data.len() as u32;
+        BE::write_u32(&mut data[OFFSET_FILE_SIZE..], len);
+        data
+    }
+
+    #[test]
+    fn decodes_messages_with_commands() {
+        let bmg = Bmg::parse(&bmg()).unwrap();
+        assert_eq!(
+            bmg.messages(None).unwrap(),
+            [
+                (100, "Hello".to_string()),
+                (200, "A{FF 00 01}B".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn rebuilds_the_file_unchanged() {
+        let data = bmg();
+        assert_eq!(Bmg::parse(&data).unwrap().to_bytes().unwrap(), data);
+    }
+
+    #[test]
+    fn replaces_messages_by_their_ids() {
+        let mut bmg = Bmg::parse(&bmg()).unwrap();
+        bmg.set_message(200, "{{x} {01 02}", None).unwrap();
+        bmg.set_message(100, "", None).unwrap();
+        assert!(bmg.set_message(1, "Hi", None).is_err());
+        assert!(bmg.set_message(200, "{01", None).is_err());
+
+        let data = bmg.to_bytes().unwrap();
+        assert_eq!(data.len() % ALIGNMENT, 0);
+        assert_eq!(BE::read_u32(&data[OFFSET_FILE_SIZE..]) as usize, data.len());
+        assert_eq!(
+            Bmg::parse(&data).unwrap().messages(None).unwrap(),
+            [(100, String::new()), (200, "{{x} {01 02}".to_string())]
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use assembler::build_branch_instruction;
+use dol::Section;
+use entry;
+use std::ops::Range;
+
+/// The amount of bytes the stub takes up.
+pub const STUB_LEN: u32 = 14 * 4;
+
+const LI_R0_0: u32 = 0x3800_0000;
+const CMPLW_R3_R4: u32 = 0x7C03_2040;
+const BGE_FORWARD_16: u32 = 0x4080_0010;
+
+fn d_form(opcode: u32, reg: u32, base: u32, immediate: u16) -> u32 {
+    (opcode << 26) | (reg << 21) | (base << 16) | immediate as u32
+}
+
+fn load_address(reg: u32, address: u32) -> [u32; 2] {
+    [
+        // lis reg, address@h
+        d_form(15, reg, 0, (address >> 16) as u16),
+        // ori reg, reg, address@l
+        d_form(24, reg, reg, address as u16),
+    ]
+}
+
+/// Builds the stub that zero-fills the bss of the injected code before
+/// jumping to the game's original entry point. The game's startup code only
+/// clears the game's own bss, so uninitialized globals of the injected code
+/// would otherwise start out with whatever is left in memory. The stub needs
+/// to become the new entry point of the DOL.
+pub fn lower(bss: Range<u32>, entry_point: u32, stub_address: u32) -> Section {
+    let mut stub = Vec::with_capacity(STUB_LEN as usize / 4);
+    stub.extend_from_slice(&load_address(3, bss.start));
+    stub.extend_from_slice(&load_address(4, bss.end));
+    stub.push(LI_R0_0);
+
+    let loop_address = stub_address + 4 * stub.len() as u32;
+    stub.push(CMPLW_R3_R4);
+    stub.push(BGE_FORWARD_16);
+    // stb r0, 0(r3)
+    stub.push(d_form(38, 0, 3, 0));
+    // addi r3, r3, 1
+    stub.push(d_form(14, 3, 3, 1));
+    let branch_address = stub_address + 4 * stub.len() as u32;
+    stub.push(build_branch_instruction(
+        branch_address,
+        loop_address,
+        false,
+        false,
+    ));
+
+    stub.extend_from_slice(&entry::jump(entry_point));
+    entry::to_section(stub_address, &stub)
+}
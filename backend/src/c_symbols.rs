@@ -0,0 +1,71 @@
+//! Exports the addresses of the injected code, like the labels of the patch
+//! files and the generated stubs, for C code that is linked into the Rom Hack
+//! by the next build. Paths ending in `.ld` become linker scripts that define
+//! the symbols, so they can be declared as `extern`, and everything else
+//! becomes a C header with a `#define` for the address and size of each one.
+
+use failure::{Error, ResultExt};
+use framework_map::InjectedSymbol;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+const HEADER_PRELUDE: &str = "\
+/* The addresses of the code injected by the Rom Hack. This file is created by
+   every build, so any changes are overwritten. */
+
+#ifndef ROMHACK_SYMBOLS_H
+#define ROMHACK_SYMBOLS_H
+
+";
+
+const LINKER_SCRIPT_PRELUDE: &str = "\
+/* The addresses of the code injected by the Rom Hack. This file is created by
+   every build, so any changes are overwritten. */
+
+";
+
+pub fn create(path: &Path, injected: &[InjectedSymbol]) -> Result<(), Error> {
+    // C identifiers can't contain the characters that labels may contain, and
+    // the first symbol wins if they end up with the same name
+    let mut symbols = BTreeMap::new();
+    for symbol in injected {
+        symbols.entry(identifier(&symbol.name)).or_insert(symbol);
+    }
+
+    let mut file = BufWriter::new(File::create(path).context("Couldn't create the C symbols")?);
+
+    if path.extension() == Some("ld".as_ref()) {
+        file.write_all(LINKER_SCRIPT_PRELUDE.as_bytes())?;
+        for (name, symbol) in &symbols {
+            writeln!(file, "{} = 0x{:08X};", name, symbol.address)?;
+        }
+    } else {
+        file.write_all(HEADER_PRELUDE.as_bytes())?;
+        for (name, symbol) in &symbols {
+            writeln!(
+                file,
+                "#define ROMHACK_{}_ADDRESS 0x{:08X}u",
+                name, symbol.address
+            )?;
+            writeln!(file, "#define ROMHACK_{}_SIZE 0x{:X}u", name, symbol.len)?;
+        }
+        writeln!(file, "\n#endif")?;
+    }
+
+    file.flush()?;
+
+    Ok(())
+}
+
+fn identifier(name: &str) -> String {
+    let mut identifier = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    if identifier.is_empty() || identifier.starts_with(|c: char| c.is_ascii_digit()) {
+        identifier.insert(0, '_');
+    }
+    identifier
+}
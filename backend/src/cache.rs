@@ -0,0 +1,224 @@
+//! Skips building the output again if none of its inputs changed since the
+//! last build. All the inputs are hashed into a key that is stored next to
+//! the output. Hashing the whole original game would take about as long as
+//! building it, so only its system data, size and modification time are
+//! hashed instead. The same goes for verifying the original game against a
+//! DAT file, which is remembered for the game's size and modification time.
+
+use assembler;
+use byteorder::{ByteOrder, LE};
+use config::{split_inject_bin, Config};
+use failure::{Error, ResultExt};
+use file_source::FileSystem;
+use sha1::Sha1;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::iter;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The boot.bin and bi2.bin at the start of the disc.
+const SYSTEM_DATA_LEN: u64 = 0x2440;
+/// Every line stores the key of a verified game, followed by the name of its
+/// dump, which is left out if it's not a known good dump.
+const VERIFIED_GAMES: &str = "target/verified-games";
+
+pub struct Cache {
+    output: PathBuf,
+    path: PathBuf,
+    key: String,
+}
+
+impl Cache {
+    /// Hashes the settings of the build, the compiled library and every file
+    /// the config refers to.
+    pub fn new(
+        config: &Config,
+        output: PathBuf,
+        settings: &str,
+        compiled_library: &[u8],
+    ) -> Result<Self, Error> {
+        let mut hasher = Sha1::new();
+        let mut update = |name: &str, data: &[u8]| {
+            // The lengths separate the inputs from each other
+            let mut lengths = [0; 16];
+            LE::write_u64(&mut lengths[..8], name.len() as u64);
+            LE::write_u64(&mut lengths[8..], data.len() as u64);
+            hasher.update(&lengths);
+            hasher.update(name.as_bytes());
+            hasher.update(data);
+        };
+
+        update("settings", settings.as_bytes());
+        update("library", compiled_library);
+
+        for path in assembly_files(config) {
+            // The included files are part of the source
+            let source = assembler::read_with_includes(&mut FileSystem, path).unwrap_or_default();
+            update(&path.to_string_lossy(), source.as_bytes());
+        }
+
+        for path in input_files(config) {
+            let data = fs::read(path).unwrap_or_default();
+            update(&path.to_string_lossy(), &data);
+        }
+
+        let games = iter::once(&config.src.iso).chain(config.discs.values().map(|d| &d.iso));
+        for game in games {
+            let mut system_data = vec![0; 16];
+            if let Ok(file) = File::open(game) {
+                let (len, modified) = len_and_modified(&file)?;
+                LE::write_u64(&mut system_data[..8], len);
+                LE::write_u64(&mut system_data[8..], modified);
+                file.take(SYSTEM_DATA_LEN)
+                    .read_to_end(&mut system_data)
+                    .context("Couldn't read the original game")?;
+            }
+            update(&game.to_string_lossy(), &system_data);
+        }
+
+        let mut key = String::with_capacity(40);
+        for byte in &hasher.digest().bytes() {
+            write!(key, "{:02x}", byte)?;
+        }
+
+        let mut path = output.clone().into_os_string();
+        path.push(".romhack-cache");
+
+        Ok(Cache {
+            output,
+            path: path.into(),
+            key,
+        })
+    }
+
+    /// Checks whether the output exists and was built from the same inputs.
+    pub fn is_fresh(&self) -> bool {
+        self.output.exists()
+            && fs::read_to_string(&self.path)
+                .map(|key| key.trim() == self.key)
+                .unwrap_or(false)
+    }
+
+    /// Remembers the inputs the output was built from.
+    pub fn store(&self) -> Result<(), Error> {
+        fs::write(&self.path, &self.key).context("Couldn't write the build cache")?;
+        Ok(())
+    }
+}
+
+fn len_and_modified(file: &File) -> Result<(u64, u64), Error> {
+    let metadata = file.metadata().context("Couldn't read the original game")?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs());
+    Ok((metadata.len(), modified))
+}
+
+/// Identifies the game by its path, size and modification time, and the DAT
+/// file by its contents, so the game is only hashed again once either of them
+/// changes.
+pub fn verification_key(game: &Path, dat: &Path) -> Result<String, Error> {
+    let file = File::open(game)
+        .with_context(|_| format!("Couldn't open the original game \"{}\"", game.display()))?;
+    let (len, modified) = len_and_modified(&file)?;
+    let dat = fs::read(dat)
+        .with_context(|_| format!("Couldn't read the DAT file \"{}\".", dat.display()))?;
+
+    let mut hasher = Sha1::new();
+    hasher.update(&dat);
+    Ok(format!(
+        "{}:{}:{}:{}",
+        hasher.digest(),
+        len,
+        modified,
+        game.display()
+    ))
+}
+
+/// Looks up whether the game was verified before. It's either the name of
+/// its dump or `None`, if it wasn't a known good dump.
+pub fn verified_game(key: &str) -> Option<Option<String>> {
+    let verified = fs::read_to_string(VERIFIED_GAMES).ok()?;
+    verified
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, '\t');
+            if parts.next() != Some(key) {
+                return None;
+            }
+            Some(parts.next().map(str::to_owned))
+        })
+        .next()
+}
+
+/// Remembers the result of verifying the game.
+pub fn store_verified_game(key: &str, name: Option<&str>) -> Result<(), Error> {
+    let mut verified = fs::read_to_string(VERIFIED_GAMES).unwrap_or_default();
+    verified.push_str(key);
+    if let Some(name) = name {
+        verified.push('\t');
+        verified.push_str(name);
+    }
+    verified.push('\n');
+    if let Some(dir) = Path::new(VERIFIED_GAMES).parent() {
+        fs::create_dir_all(dir).context("Couldn't create the target directory")?;
+    }
+    fs::write(VERIFIED_GAMES, verified).context("Couldn't remember the verified game")?;
+    Ok(())
+}
+
+/// The patch files of the DOL and the RELs, sorted so the key doesn't depend
+/// on the order of the maps.
+pub fn assembly_files(config: &Config) -> Vec<&Path> {
+    let mut files = config
+        .src
+        .patch
+        .iter()
+        .chain(&config.src.feature_patches)
+        .chain(config.rels.values())
+        .map(|p| &**p)
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+/// All the other files that are read while building, except for the
+/// original game.
+pub fn input_files(config: &Config) -> Vec<&Path> {
+    let mut files = config
+        .src
+        .gecko
+        .iter()
+        .map(|p| &**p)
+        .chain(config.src.code_handler.iter().map(|p| &**p))
+        .chain(config.src.handler_codes.iter().map(|p| &**p))
+        .chain(config.src.action_replay.iter().map(|p| &**p))
+        .chain(config.src.map.iter().map(Path::new))
+        .chain(config.src.symbols.iter().map(|p| &**p))
+        .chain(config.src.port_map.iter().map(|p| &**p))
+        .chain(config.src.text_table.iter().map(|p| &**p))
+        .chain(config.src.apploader.iter().map(|p| &**p))
+        .chain(config.patches.iter().map(|p| &**p))
+        .chain(config.files.values().map(|p| &**p))
+        .chain(config.inject_bin.values().map(|e| split_inject_bin(e).0))
+        .chain(config.textures.values().map(|p| &**p))
+        .chain(config.fonts.values().flat_map(|g| g.values()).map(|p| &**p))
+        .chain(config.videos.values().map(|p| &**p))
+        .chain(config.sounds.values().map(|p| &**p))
+        .chain(config.info.image.iter().map(|p| &**p))
+        .chain(config.link.libs.iter().flat_map(|l| l).map(|p| &**p))
+        .chain(config.sources.iter().map(|s| &*s.path))
+        .collect::<Vec<_>>();
+    // The settings of a selected disc were already merged into the config.
+    // Otherwise every disc is read, like for a Riivolution patch of them all.
+    for disc in config.discs.values() {
+        files.extend(disc.symbols.iter().map(|p| &**p));
+        files.extend(disc.files.values().map(|p| &**p));
+    }
+    files.sort();
+    files
+}
@@ -0,0 +1,195 @@
+//! Based on http://problemkaputt.de/gbatek.htm#lzdecompressfunctions
+//!
+//! The LZ10 and LZ11 formats of Nintendo's handhelds, which some Wii games
+//! use as well. The games mark these files with the `LZ77` magic in front of
+//! the actual header. Unlike Yaz0, a set flag bit means the chunk is a back
+//! reference. LZ11 encodes longer matches than LZ10.
+
+use super::{copy_match, tokenize, Codec, Token};
+use byteorder::{ByteOrder, LE};
+use config::Compression;
+use failure::Error;
+
+const MAGIC: &[u8] = b"LZ77";
+/// The magic and the type and length of the uncompressed data.
+const HEADER_LEN: usize = 8;
+
+const TYPE_LZ10: u8 = 0x10;
+const TYPE_LZ11: u8 = 0x11;
+
+const LZ10_MAX_MATCH_LEN: usize = 0x12;
+const LZ11_MAX_MATCH_LEN: usize = 0x1_0110;
+/// Matches at least this long need three or four bytes in LZ11.
+const LZ11_MEDIUM_MATCH_LEN: usize = 0x11;
+const LZ11_LONG_MATCH_LEN: usize = 0x111;
+
+pub struct Lz10;
+pub struct Lz11;
+
+fn is_compressed(data: &[u8], kind: u8) -> bool {
+    data.len() >= HEADER_LEN && data.starts_with(MAGIC) && data[MAGIC.len()] == kind
+}
+
+fn decompress(data: &[u8], kind: u8) -> Result<Vec<u8>, Error> {
+    ensure!(
+        is_compressed(data, kind),
+        "The file is not LZ{:X} compressed",
+        kind
+    );
+    let mut len = LE::read_u32(&data[MAGIC.len()..]) as usize >> 8;
+    let mut offset = HEADER_LEN;
+    if len == 0 {
+        // Larger files store their length in an additional word
+        ensure!(
+            data.len() >= HEADER_LEN + 4,
+            "The compressed data is truncated"
+        );
+        len = LE::read_u32(&data[HEADER_LEN..]) as usize;
+        offset += 4;
+    }
+
+    let mut out = Vec::with_capacity(len);
+    let mut input = data[offset..].iter().cloned();
+    let mut next = || {
+        input
+            .next()
+            .map(|b| b as usize)
+            .ok_or_else(|| format_err!("The compressed data is truncated"))
+    };
+
+    while out.len() < len {
+        let group = next()?;
+        for bit in 0..8 {
+            if out.len() >= len {
+                break;
+            }
+            if group & (0x80 >> bit) == 0 {
+                out.push(next()? as u8);
+                continue;
+            }
+
+            let b0 = next()?;
+            let (match_len, distance_high) = match (kind, b0 >> 4) {
+                (TYPE_LZ11, 0) => {
+                    let b1 = next()?;
+                    (
+                        ((b0 & 0xF) << 4 | b1 >> 4) + LZ11_MEDIUM_MATCH_LEN,
+                        b1 & 0xF,
+                    )
+                }
+                (TYPE_LZ11, 1) => {
+                    let (b1, b2) = (next()?, next()?);
+                    (
+                        ((b0 & 0xF) << 12 | b1 << 4 | b2 >> 4) + LZ11_LONG_MATCH_LEN,
+                        b2 & 0xF,
+                    )
+                }
+                (TYPE_LZ11, n) => (n + 1, b0 & 0xF),
+                (_, n) => (n + 3, b0 & 0xF),
+            };
+            let distance = (distance_high << 8 | next()?) + 1;
+            copy_match(&mut out, distance, match_len)?;
+        }
+    }
+    out.truncate(len);
+
+    Ok(out)
+}
+
+fn compress(data: &[u8], kind: u8, compression: Compression) -> Vec<u8> {
+    let max_len = if kind == TYPE_LZ11 {
+        LZ11_MAX_MATCH_LEN
+    } else {
+        LZ10_MAX_MATCH_LEN
+    };
+
+    let mut out = Vec::with_capacity(HEADER_LEN + 4 + data.len() + data.len() / 8 + 4);
+    out.extend_from_slice(MAGIC);
+    if data.len() < 1 << 24 {
+        let mut header = [0; 4];
+        LE::write_u32(&mut header, (data.len() as u32) << 8 | kind as u32);
+        out.extend_from_slice(&header);
+    } else {
+        let mut header = [0; 8];
+        header[0] = kind;
+        LE::write_u32(&mut header[4..], data.len() as u32);
+        out.extend_from_slice(&header);
+    }
+
+    let mut group = 0;
+    for (index, token) in tokenize(data, max_len, compression).into_iter().enumerate() {
+        let chunk = index % 8;
+        if chunk == 0 {
+            group = out.len();
+            out.push(0);
+        }
+
+        let (len, distance) = match token {
+            Token::Literal(byte) => {
+                out.push(byte);
+                continue;
+            }
+            Token::Match { len, distance } => (len, distance - 1),
+        };
+        out[group] |= 0x80 >> chunk;
+
+        if kind == TYPE_LZ10 {
+            out.push(((len - 3) << 4 | distance >> 8) as u8);
+        } else if len >= LZ11_LONG_MATCH_LEN {
+            let len = len - LZ11_LONG_MATCH_LEN;
+            out.push((0x10 | len >> 12) as u8);
+            out.push((len >> 4) as u8);
+            out.push(((len & 0xF) << 4 | distance >> 8) as u8);
+        } else if len >= LZ11_MEDIUM_MATCH_LEN {
+            let len = len - LZ11_MEDIUM_MATCH_LEN;
+            out.push((len >> 4) as u8);
+            out.push(((len & 0xF) << 4 | distance >> 8) as u8);
+        } else {
+            out.push(((len - 1) << 4 | distance >> 8) as u8);
+        }
+        out.push(distance as u8);
+    }
+
+    // The games read the compressed data in words
+    while out.len() % 4 != 0 {
+        out.push(0);
+    }
+
+    out
+}
+
+impl Codec for Lz10 {
+    fn name(&self) -> &'static str {
+        "LZ10"
+    }
+
+    fn is_compressed(&self, data: &[u8]) -> bool {
+        is_compressed(data, TYPE_LZ10)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        decompress(data, TYPE_LZ10)
+    }
+
+    fn compress(&self, data: &[u8], compression: Compression) -> Vec<u8> {
+        compress(data, TYPE_LZ10, compression)
+    }
+}
+
+impl Codec for Lz11 {
+    fn name(&self) -> &'static str {
+        "LZ11"
+    }
+
+    fn is_compressed(&self, data: &[u8]) -> bool {
+        is_compressed(data, TYPE_LZ11)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        decompress(data, TYPE_LZ11)
+    }
+
+    fn compress(&self, data: &[u8], compression: Compression) -> Vec<u8> {
+        compress(data, TYPE_LZ11, compression)
+    }
+}
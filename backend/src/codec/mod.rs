@@ -0,0 +1,166 @@
+//! The compression formats games store their files and archives in. They are
+//! all LZ77 variants with a 4 KiB window, so they share the search for
+//! matches and only differ in how they encode them.
+
+use config::Compression;
+use failure::Error;
+
+mod lz77;
+mod yay0;
+mod yaz0;
+
+pub use self::lz77::{Lz10, Lz11};
+pub use self::yay0::Yay0;
+pub use self::yaz0::Yaz0;
+
+pub trait Codec {
+    fn name(&self) -> &'static str;
+    /// Whether the data starts with this format's magic bytes.
+    fn is_compressed(&self, data: &[u8]) -> bool;
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error>;
+    fn compress(&self, data: &[u8], compression: Compression) -> Vec<u8>;
+}
+
+/// The formats that can be told apart by their magic bytes. Files that are
+/// compressed as LZ10 or LZ11 without the `LZ77` magic in front can't be
+/// detected reliably, as their header is a single byte.
+const CODECS: [&dyn Codec; 4] = [&Yaz0, &Yay0, &Lz10, &Lz11];
+
+/// Finds the format the data is compressed with.
+pub fn detect(data: &[u8]) -> Option<&'static dyn Codec> {
+    CODECS.iter().cloned().find(|c| c.is_compressed(data))
+}
+
+const WINDOW_LEN: usize = 0x1000;
+const MIN_MATCH_LEN: usize = 3;
+
+const HASH_LEN: usize = 1 << 15;
+const NONE: usize = !0;
+/// How many earlier occurrences of the next three bytes are checked for the
+/// longest match when compressing fast.
+const FAST_CHAIN_LEN: usize = 16;
+
+enum Token {
+    Literal(u8),
+    Match { len: usize, distance: usize },
+}
+
+/// Finds earlier occurrences of the upcoming bytes through chains of the
+/// positions sharing the hash of their first three bytes.
+struct Matcher<'a> {
+    data: &'a [u8],
+    head: Vec<usize>,
+    prev: Vec<usize>,
+    inserted: usize,
+    max_len: usize,
+    max_chain_len: usize,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(data: &'a [u8], max_len: usize, max_chain_len: usize) -> Self {
+        Self {
+            data,
+            head: vec![NONE; HASH_LEN],
+            prev: vec![NONE; data.len()],
+            inserted: 0,
+            max_len,
+            max_chain_len,
+        }
+    }
+
+    fn hash(&self, pos: usize) -> usize {
+        let d = &self.data[pos..];
+        (((d[0] as usize) << 10) ^ ((d[1] as usize) << 5) ^ d[2] as usize) % HASH_LEN
+    }
+
+    /// Returns the length and the distance of the longest match for the
+    /// bytes at `pos`. Positions need to be passed in ascending order.
+    fn find(&mut self, pos: usize) -> (usize, usize) {
+        while self.inserted < pos {
+            let inserted = self.inserted;
+            if inserted + MIN_MATCH_LEN <= self.data.len() {
+                let hash = self.hash(inserted);
+                self.prev[inserted] = self.head[hash];
+                self.head[hash] = inserted;
+            }
+            self.inserted += 1;
+        }
+
+        if pos + MIN_MATCH_LEN > self.data.len() {
+            return (0, 0);
+        }
+
+        let max_len = self.max_len.min(self.data.len() - pos);
+        let (mut best_len, mut best_distance) = (0, 0);
+        let mut candidate = self.head[self.hash(pos)];
+        let mut chain_len = 0;
+        while candidate != NONE && pos - candidate <= WINDOW_LEN && chain_len < self.max_chain_len {
+            let len = self.data[candidate..]
+                .iter()
+                .zip(&self.data[pos..pos + max_len])
+                .take_while(|&(a, b)| a == b)
+                .count();
+            if len > best_len {
+                best_len = len;
+                best_distance = pos - candidate;
+                if len == max_len {
+                    break;
+                }
+            }
+            candidate = self.prev[candidate];
+            chain_len += 1;
+        }
+
+        (best_len, best_distance)
+    }
+}
+
+/// Splits the data into literals and matches of up to `max_len` bytes. Fast
+/// compression only checks a few earlier occurrences for each match, while
+/// optimal compression checks the whole window and defers a match if the
+/// next byte starts a longer one, which matches the compression ratio of the
+/// games' own files more closely.
+fn tokenize(data: &[u8], max_len: usize, compression: Compression) -> Vec<Token> {
+    let (max_chain_len, lazy) = match compression {
+        Compression::Fast => (FAST_CHAIN_LEN, false),
+        Compression::Optimal => (NONE, true),
+    };
+    let mut matcher = Matcher::new(data, max_len, max_chain_len);
+
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let (mut len, distance) = matcher.find(pos);
+        if lazy && len >= MIN_MATCH_LEN && len < max_len {
+            // A literal followed by a longer match compresses better
+            if matcher.find(pos + 1).0 > len {
+                len = 0;
+            }
+        }
+
+        if len >= MIN_MATCH_LEN {
+            tokens.push(Token::Match { len, distance });
+            pos += len;
+        } else {
+            tokens.push(Token::Literal(data[pos]));
+            pos += 1;
+        }
+    }
+
+    tokens
+}
+
+/// Appends a match to the decompressed data. The match may overlap with the
+/// bytes it produces.
+fn copy_match(out: &mut Vec<u8>, distance: usize, len: usize) -> Result<(), Error> {
+    ensure!(
+        distance <= out.len(),
+        "The compressed data refers to data in front of the file"
+    );
+    let start = out.len() - distance;
+    for index in start..start + len {
+        let byte = out[index];
+        out.push(byte);
+    }
+    Ok(())
+}
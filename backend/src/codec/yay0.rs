@@ -0,0 +1,131 @@
+//! Based on http://www.amnoid.de/gc/yay0.txt
+//!
+//! Yay0 is the predecessor of Yaz0, used by games like Super Mario Sunshine.
+//! Instead of interleaving them, it stores the flag bits, the back references
+//! and the literal bytes in three separate streams.
+
+use super::{copy_match, tokenize, Codec, Token};
+use byteorder::{ByteOrder, BE};
+use config::Compression;
+use failure::Error;
+
+const MAGIC: &[u8] = b"Yay0";
+const HEADER_LEN: usize = 0x10;
+const OFFSET_UNCOMPRESSED_LEN: usize = 0x4;
+const OFFSET_LINKS_OFFSET: usize = 0x8;
+const OFFSET_CHUNKS_OFFSET: usize = 0xC;
+
+const MAX_MATCH_LEN: usize = 0x111;
+/// Matches at least this long store their length in the chunk stream.
+const LONG_MATCH_LEN: usize = 0x12;
+
+pub struct Yay0;
+
+impl Codec for Yay0 {
+    fn name(&self) -> &'static str {
+        "Yay0"
+    }
+
+    fn is_compressed(&self, data: &[u8]) -> bool {
+        data.len() >= HEADER_LEN && data.starts_with(MAGIC)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        ensure!(self.is_compressed(data), "The file is not Yay0 compressed");
+        let len = BE::read_u32(&data[OFFSET_UNCOMPRESSED_LEN..]) as usize;
+        let links_offset = BE::read_u32(&data[OFFSET_LINKS_OFFSET..]) as usize;
+        let chunks_offset = BE::read_u32(&data[OFFSET_CHUNKS_OFFSET..]) as usize;
+        ensure!(
+            links_offset <= data.len() && chunks_offset <= data.len(),
+            "The compressed data is truncated"
+        );
+
+        let mut masks = data[HEADER_LEN..].chunks(4);
+        let mut links = data[links_offset..].chunks(2);
+        let mut chunks = data[chunks_offset..].iter().cloned();
+        let truncated = || format_err!("The compressed data is truncated");
+
+        let mut out = Vec::with_capacity(len);
+        let mut mask = 0;
+        let mut bits_left = 0;
+        while out.len() < len {
+            if bits_left == 0 {
+                match masks.next() {
+                    Some(word) if word.len() == 4 => mask = BE::read_u32(word),
+                    _ => return Err(truncated()),
+                }
+                bits_left = 32;
+            }
+
+            if mask & 0x8000_0000 != 0 {
+                out.push(chunks.next().ok_or_else(truncated)?);
+            } else {
+                let link = match links.next() {
+                    Some(link) if link.len() == 2 => BE::read_u16(link) as usize,
+                    _ => return Err(truncated()),
+                };
+                let distance = (link & 0xFFF) + 1;
+                let match_len = match link >> 12 {
+                    0 => chunks.next().ok_or_else(truncated)? as usize + LONG_MATCH_LEN,
+                    n => n + 2,
+                };
+                copy_match(&mut out, distance, match_len)?;
+            }
+
+            mask <<= 1;
+            bits_left -= 1;
+        }
+        out.truncate(len);
+
+        Ok(out)
+    }
+
+    fn compress(&self, data: &[u8], compression: Compression) -> Vec<u8> {
+        let mut masks = Vec::new();
+        let mut links = Vec::new();
+        let mut chunks = Vec::new();
+
+        for (index, token) in tokenize(data, MAX_MATCH_LEN, compression)
+            .into_iter()
+            .enumerate()
+        {
+            let bit = index % 32;
+            if bit == 0 {
+                masks.push(0u32);
+            }
+
+            match token {
+                Token::Literal(byte) => {
+                    *masks.last_mut().unwrap() |= 0x8000_0000 >> bit;
+                    chunks.push(byte);
+                }
+                Token::Match { len, distance } => {
+                    let distance = (distance - 1) as u16;
+                    if len >= LONG_MATCH_LEN {
+                        links.push(distance);
+                        chunks.push((len - LONG_MATCH_LEN) as u8);
+                    } else {
+                        links.push(((len - 2) as u16) << 12 | distance);
+                    }
+                }
+            }
+        }
+
+        let links_offset = HEADER_LEN + masks.len() * 4;
+        let chunks_offset = links_offset + links.len() * 2;
+        let mut out = vec![0; chunks_offset];
+        out[..MAGIC.len()].copy_from_slice(MAGIC);
+        BE::write_u32(&mut out[OFFSET_UNCOMPRESSED_LEN..], data.len() as u32);
+        BE::write_u32(&mut out[OFFSET_LINKS_OFFSET..], links_offset as u32);
+        BE::write_u32(&mut out[OFFSET_CHUNKS_OFFSET..], chunks_offset as u32);
+        for (mask, dst) in masks.iter().zip(out[HEADER_LEN..].chunks_mut(4)) {
+            BE::write_u32(dst, *mask);
+        }
+        for (link, dst) in links.iter().zip(out[links_offset..].chunks_mut(2)) {
+            BE::write_u16(dst, *link);
+        }
+        out.extend_from_slice(&chunks);
+
+        out
+    }
+}
@@ -0,0 +1,150 @@
+//! Based on http://wiki.tockdom.com/wiki/YAZ0_(File_Format)
+//!
+//! Yaz0 is the format most Nintendo games compress their files and archives
+//! (`.szs` files) with. Each group of up to eight chunks is preceded by a byte
+//! whose bits tell whether the chunk is a literal byte or a back reference.
+
+use super::{copy_match, tokenize, Codec, Token};
+use byteorder::{ByteOrder, BE};
+use config::Compression;
+use failure::Error;
+
+const MAGIC: &[u8] = b"Yaz0";
+const HEADER_LEN: usize = 0x10;
+const OFFSET_UNCOMPRESSED_LEN: usize = 4;
+
+const MAX_MATCH_LEN: usize = 0x111;
+/// Matches at least this long store their length in a third byte.
+const LONG_MATCH_LEN: usize = 0x12;
+
+pub struct Yaz0;
+
+impl Codec for Yaz0 {
+    fn name(&self) -> &'static str {
+        "Yaz0"
+    }
+
+    fn is_compressed(&self, data: &[u8]) -> bool {
+        data.len() >= HEADER_LEN && data.starts_with(MAGIC)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        ensure!(self.is_compressed(data), "The file is not Yaz0 compressed");
+        let len = BE::read_u32(&data[OFFSET_UNCOMPRESSED_LEN..]) as usize;
+        let mut out = Vec::with_capacity(len);
+        let mut input = data[HEADER_LEN..].iter().cloned();
+        let mut next = || {
+            input
+                .next()
+                .ok_or_else(|| format_err!("The compressed data is truncated"))
+        };
+
+        while out.len() < len {
+            let group = next()?;
+            for bit in 0..8 {
+                if out.len() >= len {
+                    break;
+                }
+                if group & (0x80 >> bit) != 0 {
+                    out.push(next()?);
+                } else {
+                    let (b0, b1) = (next()? as usize, next()? as usize);
+                    let distance = ((b0 & 0xF) << 8 | b1) + 1;
+                    let match_len = match b0 >> 4 {
+                        0 => next()? as usize + LONG_MATCH_LEN,
+                        n => n + 2,
+                    };
+                    copy_match(&mut out, distance, match_len)?;
+                }
+            }
+        }
+        out.truncate(len);
+
+        Ok(out)
+    }
+
+    fn compress(&self, data: &[u8], compression: Compression) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + data.len() + data.len() / 8 + 1);
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&[0; HEADER_LEN - 4]);
+        BE::write_u32(&mut out[OFFSET_UNCOMPRESSED_LEN..], data.len() as u32);
+
+        let mut group = 0;
+        for (index, token) in tokenize(data, MAX_MATCH_LEN, compression)
+            .into_iter()
+            .enumerate()
+        {
+            let chunk = index % 8;
+            if chunk == 0 {
+                group = out.len();
+                out.push(0);
+            }
+
+            match token {
+                Token::Literal(byte) => {
+                    out[group] |= 0x80 >> chunk;
+                    out.push(byte);
+                }
+                Token::Match { len, distance } => {
+                    let distance = distance - 1;
+                    if len >= LONG_MATCH_LEN {
+                        out.push((distance >> 8) as u8);
+                        out.push(distance as u8);
+                        out.push((len - LONG_MATCH_LEN) as u8);
+                    } else {
+                        out.push(((len - 2) << 4 | distance >> 8) as u8);
+                        out.push(distance as u8);
+                    }
+                }
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compressed(len: u32, chunks: &[u8]) -> Vec<u8> {
+        let mut data = vec![0; HEADER_LEN];
+        data[..4].copy_from_slice(MAGIC);
+        BE::write_u32(&mut data[OFFSET_UNCOMPRESSED_LEN..], len);
+        data.extend_from_slice(chunks);
+        data
+    }
+
+    #[test]
+    fn decompresses_short_and_long_matches() {
+        let data = compressed(6, &[0xE0, b'a', b'b', b'c', 0x10, 0x02]);
+        assert_eq!(Yaz0.decompress(&data).unwrap(), b"abcabc");
+
+        let data = compressed(0x20, &[0x80, b'a', 0x00, 0x00, 0x0D]);
+        assert_eq!(Yaz0.decompress(&data).unwrap(), vec![b'a'; 0x20]);
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let truncated = compressed(6, &[0xE0, b'a', b'b']);
+        assert!(Yaz0.decompress(&truncated).is_err());
+        assert!(Yaz0.decompress(b"Yaz0").is_err());
+    }
+
+    #[test]
+    fn compresses_losslessly() {
+        let mut data = Vec::new();
+        for _ in 0..40 {
+            data.extend_from_slice(b"The quick brown fox jumps over the lazy dog. ");
+        }
+        data.extend((0..0x2000u32).map(|i| (i * i >> 5) as u8));
+        for &compression in &[Compression::Fast, Compression::Optimal] {
+            let compressed = Yaz0.compress(&data, compression);
+            assert!(Yaz0.is_compressed(&compressed));
+            assert!(compressed.len() < data.len());
+            assert_eq!(Yaz0.decompress(&compressed).unwrap(), data);
+        }
+        let empty = Yaz0.compress(&[], Compression::Fast);
+        assert!(Yaz0.decompress(&empty).unwrap().is_empty());
+    }
+}
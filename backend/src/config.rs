@@ -1,27 +1,258 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Config {
+    #[serde(default, rename = "remove-files")]
+    pub remove_files: Vec<String>,
+    #[serde(default, rename = "patch")]
+    pub patches: Vec<PathBuf>,
     #[serde(default)]
     pub info: Info,
     pub src: Src,
     #[serde(default)]
-    pub files: HashMap<String, PathBuf>,
+    pub files: BTreeMap<String, PathBuf>,
+    #[serde(default)]
+    pub rels: BTreeMap<String, PathBuf>,
+    /// The binaries that are placed into new data sections by the symbols
+    /// their addresses are exposed as, like `data/table.bin @ 0x817F_0000`.
+    #[serde(default, rename = "inject-bin")]
+    pub inject_bin: BTreeMap<String, String>,
+    /// The new texts of the messages in BMG files by their IDs.
+    #[serde(default)]
+    pub messages: BTreeMap<String, BTreeMap<String, String>>,
+    /// The images that replace the textures in TPL and BTI files.
+    #[serde(default)]
+    pub textures: BTreeMap<String, PathBuf>,
+    /// The images of the glyphs that are added to BRFNT and BFN fonts by
+    /// their characters.
+    #[serde(default)]
+    pub fonts: BTreeMap<String, BTreeMap<String, PathBuf>>,
+    /// The THP or AVI files that replace the THP videos.
+    #[serde(default)]
+    pub videos: BTreeMap<String, PathBuf>,
+    /// The WAV files that replace the sounds in DSP and BRSTM files. Sound
+    /// archives like BRSAR files aren't repacked, so their sounds can't be
+    /// replaced.
+    #[serde(default)]
+    pub sounds: BTreeMap<String, PathBuf>,
+    #[serde(default)]
+    pub hooks: BTreeMap<String, Hook>,
     pub build: Build,
     pub link: Link,
+    #[serde(default)]
+    pub regions: BTreeMap<String, Region>,
+    #[serde(default)]
+    pub discs: BTreeMap<String, Disc>,
+    #[serde(default)]
+    pub features: BTreeMap<String, Feature>,
+    #[serde(default)]
+    pub header: Header,
+    /// The alignments and offsets of files on the disc by their paths.
+    #[serde(default)]
+    pub layout: BTreeMap<String, FileLayout>,
+    #[serde(default)]
+    pub save: Save,
+    /// Additional files the patches are read from, by their kind.
+    #[serde(default)]
+    pub sources: Vec<Source>,
+}
+
+/// The structures of the game that end up in its saves, and how the Rom
+/// Hack keeps its saves apart from the original game's ones if the patches
+/// change them.
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct Save {
+    #[serde(default)]
+    pub structures: Vec<String>,
+    pub game_id: Option<String>,
+    pub comment: Option<SaveComment>,
+}
+
+/// Splits an entry of `inject-bin` into the path of the binary and its
+/// address, which is `auto` for placing it after the Rom Hack.
+pub fn split_inject_bin(entry: &str) -> (&Path, &str) {
+    match entry.rfind('@') {
+        Some(index) => (Path::new(entry[..index].trim()), entry[index + 1..].trim()),
+        None => (Path::new(entry.trim()), "auto"),
+    }
+}
+
+/// A file that patches are read from, like a binary that is written to the
+/// address.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Source {
+    pub kind: String,
+    pub path: PathBuf,
+    pub address: Option<String>,
+}
+
+impl Source {
+    pub fn new(kind: &str, path: PathBuf) -> Self {
+        Source {
+            kind: kind.to_string(),
+            path,
+            address: None,
+        }
+    }
+}
+
+/// The string the game uses as the comment of its saves.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SaveComment {
+    pub address: String,
+    pub text: String,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct Src {
     pub src: Option<PathBuf>,
     pub iso: PathBuf,
+    pub game_id: Option<String>,
+    /// The DAT file with the known good dumps of the game.
+    pub dat: Option<PathBuf>,
     pub patch: Option<PathBuf>,
+    /// The patch files of the selected features, which are assembled along
+    /// with the main patch file.
+    #[serde(default)]
+    pub feature_patches: Vec<PathBuf>,
+    pub gecko: Option<PathBuf>,
+    /// The Gecko code handler that is embedded into the DOL. It runs the
+    /// codes of `handler-codes` whenever the game executes `handler-hook`.
+    pub code_handler: Option<PathBuf>,
+    pub handler_codes: Option<PathBuf>,
+    pub handler_hook: Option<String>,
+    /// A custom apploader that replaces the game's one.
+    pub apploader: Option<PathBuf>,
+    pub action_replay: Option<PathBuf>,
     pub map: Option<String>,
+    #[serde(default)]
+    pub symbols: Vec<PathBuf>,
+    #[serde(default)]
+    pub defines: Vec<String>,
+    #[serde(default)]
+    pub addresses: BTreeMap<String, String>,
+    /// The byte patterns that locate functions of the game by their names.
+    #[serde(default)]
+    pub signatures: BTreeMap<String, String>,
+    /// Translates the addresses of the patch files and the hooks, which were
+    /// written for another version of the game.
+    pub port_map: Option<PathBuf>,
+    /// The platform the game runs on. It's detected from the original game,
+    /// so giving it only makes sure the Rom Hack is built for the right one.
+    /// The build itself only differs by the checks of the platform, like
+    /// Triforce games keeping their boot.id. Only decrypted Triforce images
+    /// are supported. The ones still encrypted for the game's security chip,
+    /// like GD-ROM dumps, are refused, and the built image is never encrypted
+    /// again, so it needs to be net booted.
+    pub platform: Option<Platform>,
+    /// The encoding the strings that the patch files replace are written in.
+    pub text_encoding: Option<TextEncoding>,
+    /// A TBL file with the game's own text encoding.
+    pub text_table: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone)]
+pub enum TextEncoding {
+    #[serde(rename = "shift-jis")]
+    ShiftJis,
+    #[serde(rename = "utf-16")]
+    Utf16,
+}
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Platform {
+    GameCube,
+    Wii,
+    Triforce,
+}
+
+impl Platform {
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::GameCube => "GameCube",
+            Platform::Wii => "Wii",
+            Platform::Triforce => "Triforce",
+        }
+    }
+}
+
+/// The function a hook calls. The instruction the hook replaces may be given
+/// as well, so hooks for another version of the game are caught.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Hook {
+    Function(String),
+    Checked { function: String, original: String },
+}
+
+impl Hook {
+    pub fn function(&self) -> &str {
+        match *self {
+            Hook::Function(ref function) | Hook::Checked { ref function, .. } => function,
+        }
+    }
+
+    pub fn original(&self) -> Option<&str> {
+        match *self {
+            Hook::Function(_) => None,
+            Hook::Checked { ref original, .. } => Some(original),
+        }
+    }
+}
+
+/// The settings that differ between the regions of a game. Each region is
+/// built separately with these settings applied on top of the rest.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct Region {
+    pub iso: PathBuf,
+    pub game_id: Option<String>,
+    pub output: Option<PathBuf>,
+    #[serde(default)]
+    pub symbols: Vec<PathBuf>,
+    pub port_map: Option<PathBuf>,
+    #[serde(default)]
+    pub addresses: BTreeMap<String, String>,
+    #[serde(default)]
+    pub defines: Vec<String>,
+    #[serde(default)]
+    pub files: BTreeMap<String, PathBuf>,
 }
 
-#[derive(Deserialize, Serialize, Default, Debug)]
+/// A disc of a game that spans multiple discs. The discs share the patches
+/// and the symbols of the Rom Hack, with these settings applied on top.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct Disc {
+    pub iso: PathBuf,
+    pub output: Option<PathBuf>,
+    #[serde(default)]
+    pub symbols: Vec<PathBuf>,
+    #[serde(default)]
+    pub addresses: BTreeMap<String, String>,
+    #[serde(default)]
+    pub defines: Vec<String>,
+    #[serde(default)]
+    pub files: BTreeMap<String, PathBuf>,
+}
+
+/// An optional part of the Rom Hack that is only built when it's selected.
+/// `FEATURE_<NAME>` is defined for the patch files of builds that include it.
+#[derive(Deserialize, Serialize, Debug, Default, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct Feature {
+    pub patch: Option<PathBuf>,
+    #[serde(default)]
+    pub defines: Vec<String>,
+    #[serde(default)]
+    pub files: BTreeMap<String, PathBuf>,
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct Info {
     pub game_name: Option<String>,
@@ -30,17 +261,166 @@ pub struct Info {
     pub full_developer_name: Option<String>,
     pub description: Option<String>,
     pub image: Option<PathBuf>,
+    #[serde(default)]
+    pub languages: BTreeMap<String, BannerTexts>,
+}
+
+/// Overrides the texts of the banner for a single language. Only the banners
+/// of PAL games contain texts for multiple languages.
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct BannerTexts {
+    pub game_name: Option<String>,
+    pub developer_name: Option<String>,
+    pub full_game_name: Option<String>,
+    pub full_developer_name: Option<String>,
+    pub description: Option<String>,
+}
+
+/// Streamed files like audio and videos may need to be aligned further than
+/// other files or even stay at a fixed offset of the disc.
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct FileLayout {
+    pub alignment: Option<String>,
+    pub offset: Option<String>,
+}
+
+/// Overrides the fields of the disc header, which consists of boot.bin and
+/// bi2.bin. The offsets of the DOL and the FST are byte offsets on the disc
+/// and the other parts of the disc are placed around them. The files that
+/// follow the FST move along with it, unless they are audio streams that are
+/// kept in place.
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+pub struct Header {
+    pub country_code: Option<CountryCode>,
+    pub debug_flag: Option<String>,
+    pub simulated_memory_size: Option<String>,
+    pub argument_offset: Option<String>,
+    pub dol_offset: Option<String>,
+    pub fst_offset: Option<String>,
+    pub keep_audio_streams_in_place: Option<bool>,
 }
 
-#[derive(Deserialize, Serialize, Default, Debug)]
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CountryCode {
+    Japan,
+    Usa,
+    Pal,
+    Korea,
+}
+
+impl CountryCode {
+    pub fn value(self) -> u32 {
+        match self {
+            CountryCode::Japan => 0,
+            CountryCode::Usa => 1,
+            CountryCode::Pal => 2,
+            CountryCode::Korea => 4,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, Clone)]
 pub struct Build {
     pub map: Option<PathBuf>,
     pub iso: PathBuf,
+    pub report: Option<PathBuf>,
+    pub elf: Option<PathBuf>,
+    #[serde(rename = "symbol-export")]
+    pub symbol_export: Option<PathBuf>,
+    #[serde(rename = "c-symbols")]
+    pub c_symbols: Option<PathBuf>,
+    #[serde(rename = "linker-script")]
+    pub linker_script: Option<PathBuf>,
+    pub gdbinit: Option<PathBuf>,
+    pub manifest: Option<PathBuf>,
+    pub stats: Option<PathBuf>,
+    #[serde(rename = "dolphin-ini")]
+    pub dolphin_ini: Option<PathBuf>,
+    #[serde(rename = "game-id")]
+    pub game_id: Option<String>,
+    #[serde(default)]
+    pub format: OutputFormat,
+    #[serde(default)]
+    pub compression: Compression,
+    /// Stores the injected binaries Yaz0 compressed in the DOL, which are then
+    /// decompressed when the game starts.
+    #[serde(default, rename = "compress-binaries")]
+    pub compress_binaries: bool,
+    /// Trims the zeros at the end of the Rom Hack's data and leaves out the
+    /// sections that are never loaded, like debug info, to shrink the DOL.
+    #[serde(default, rename = "trim-sections")]
+    pub trim_sections: bool,
+    #[serde(default)]
+    pub padding: Padding,
+}
+
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Iso,
+    Wbfs,
+    Ciso,
+    Wad,
+    Patch,
+    Riivolution,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Iso
+    }
+}
+
+/// How thoroughly repacked archives and files are compressed again.
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Fast,
+    Optimal,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression::Fast
+    }
+}
+
+/// What fills the gaps between the files of the disc. Nintendo's junk
+/// rebuilds the original disc exactly, while zeros compress better.
+#[derive(Deserialize, Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Padding {
+    Junk,
+    Zeros,
+}
+
+impl Default for Padding {
+    fn default() -> Self {
+        Padding::Junk
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Link {
     pub entries: Vec<String>,
     pub base: String,
     pub libs: Option<Vec<PathBuf>>,
+    #[serde(default)]
+    pub free: Vec<String>,
+    #[serde(default)]
+    pub init: Vec<String>,
+    /// The bounds the arena that the game allocates its heaps from is shrunk
+    /// to, which reserves the memory outside of it for the Rom Hack.
+    #[serde(rename = "arena-lo")]
+    pub arena_lo: Option<String>,
+    #[serde(rename = "arena-hi")]
+    pub arena_hi: Option<String>,
+    /// The end the heaps of Wii games in MEM2 are shrunk to, which frees up
+    /// the memory above them for the generated code and data.
+    #[serde(rename = "mem2-arena-hi")]
+    pub mem2_arena_hi: Option<String>,
 }
@@ -0,0 +1,108 @@
+use assembler::Instruction;
+use dol::{DolFile, Section};
+use failure::Error;
+use std::collections::BTreeMap;
+
+/// Instructions that were all produced from the same source, like the patch
+/// file or the Gecko codes.
+pub struct Patch<'a> {
+    pub name: &'a str,
+    pub instructions: &'a [Instruction],
+    /// Where the lines of the instructions are, like `patch.asm:12`, by the
+    /// addresses of the instructions, if they come from lines of a file.
+    pub locations: Option<&'a BTreeMap<u32, String>>,
+}
+
+/// Makes sure that none of the DOL's sections overlap each other and that no
+/// two instructions write to the same bytes. Every conflict is reported at
+/// once, so they can all be fixed in one go.
+pub fn check(dol: &DolFile, patches: &[Patch]) -> Result<(), Error> {
+    let mut conflicts = Vec::new();
+
+    let mut sections = dol
+        .text_sections
+        .iter()
+        .map(|s| ("text", s))
+        .chain(dol.data_sections.iter().map(|s| ("data", s)))
+        .filter(|&(_, s)| !s.data.is_empty())
+        .collect::<Vec<_>>();
+    sections.sort_by_key(|&(_, s)| s.address);
+
+    for (index, &(kind, section)) in sections.iter().enumerate() {
+        for &(other_kind, other) in &sections[index + 1..] {
+            if other.address >= section.end_address() {
+                break;
+            }
+            conflicts.push(format!(
+                "The {} section {} overlaps the {} section {}",
+                kind,
+                display_range(section),
+                other_kind,
+                display_range(other)
+            ));
+        }
+    }
+
+    let mut writes = patches
+        .iter()
+        .flat_map(|p| {
+            p.instructions.iter().map(move |i| {
+                let location = p.locations.and_then(|l| l.get(&i.address));
+                (i.address, i.mask, p.name, location)
+            })
+        }).collect::<Vec<_>>();
+    writes.sort_by_key(|&(address, _, _, _)| address);
+
+    // Writes to different bytes of the same word don't conflict
+    for (index, &(address, mask, name, location)) in writes.iter().enumerate() {
+        for &(other_address, other_mask, other_name, other_location) in &writes[index + 1..] {
+            if other_address >= address + 4 {
+                break;
+            }
+            if other_address == address && mask & other_mask == 0 {
+                continue;
+            }
+            if name != other_name {
+                conflicts.push(format!(
+                    "Both {} and {} write to {:08X}",
+                    describe(name, location),
+                    describe(other_name, other_location),
+                    other_address
+                ));
+            } else if let (Some(location), Some(other_location)) = (location, other_location) {
+                conflicts.push(format!(
+                    "Both {} and {} of {} write to {:08X}",
+                    location, other_location, name, other_address
+                ));
+            } else {
+                conflicts.push(format!(
+                    "Multiple instructions of {} write to {:08X}",
+                    name, other_address
+                ));
+            }
+        }
+    }
+
+    if !conflicts.is_empty() {
+        bail!(
+            "Found {} conflict{}:\n{}",
+            conflicts.len(),
+            if conflicts.len() == 1 { "" } else { "s" },
+            conflicts.join("\n")
+        );
+    }
+
+    Ok(())
+}
+
+/// Names the patch along with the line of the instruction, if it's known.
+fn describe(name: &str, location: Option<&String>) -> String {
+    match location {
+        Some(location) => format!("{} at {}", name, location),
+        None => name.to_string(),
+    }
+}
+
+fn display_range(section: &Section) -> String {
+    format!("{:08X}..{:08X}", section.address, section.end_address())
+}
@@ -0,0 +1,185 @@
+//! Stores big binaries Yaz0 compressed in the DOL, which makes the DOL and
+//! the disc smaller at the cost of decompressing them when the game starts.
+//! A stub decompresses each of them to its address and syncs the caches for
+//! it before jumping to the game's original entry point.
+
+use assembler::build_branch_instruction;
+use dol::Section;
+use entry;
+
+/// The amount of bytes each stub takes up.
+pub const STUB_LEN: u32 = 47 * 4 + entry::SYNC_CACHES_LEN;
+
+/// The compressed data follows the Yaz0 header.
+const HEADER_LEN: u32 = 0x10;
+
+const CMPLW_R4_R5: u32 = 0x7C04_2840;
+const OR_R10_R10_R9: u32 = 0x7D4A_4B78;
+const SUBF_R10_R10_R4: u32 = 0x7D4A_2050;
+const MTCTR_R8: u32 = 0x7D09_03A6;
+
+// The conditions of the conditional branches
+const BO_FALSE: u32 = 4;
+const BO_TRUE: u32 = 12;
+const BO_DECREMENT_NONZERO: u32 = 16;
+const BI_LT: u32 = 0;
+const BI_EQ: u32 = 2;
+
+fn d_form(opcode: u32, reg: u32, base: u32, immediate: u16) -> u32 {
+    (opcode << 26) | (reg << 21) | (base << 16) | immediate as u32
+}
+
+fn load_address(reg: u32, address: u32) -> [u32; 2] {
+    [
+        // lis reg, address@h
+        d_form(15, reg, 0, (address >> 16) as u16),
+        // ori reg, reg, address@l
+        d_form(24, reg, reg, address as u16),
+    ]
+}
+
+fn rlwinm(dest: u32, source: u32, shift: u32, mask_begin: u32, mask_end: u32, record: bool) -> u32 {
+    (21 << 26)
+        | (source << 21)
+        | (dest << 16)
+        | (shift << 11)
+        | (mask_begin << 6)
+        | (mask_end << 1)
+        | record as u32
+}
+
+/// Patches the conditional branch at the index to branch to the other index.
+fn branch_conditional(stub: &mut [u32], from: usize, to: usize, bo: u32, bi: u32) {
+    let displacement = (to as i32 - from as i32) * 4;
+    stub[from] = (16 << 26) | (bo << 21) | (bi << 16) | (displacement as u32 & 0xFFFC);
+}
+
+/// Builds the stub that decompresses the Yaz0 compressed data to the address
+/// before jumping to the game's original entry point. The stub needs to
+/// become the new entry point of the DOL.
+pub fn lower(
+    compressed_address: u32,
+    address: u32,
+    len: u32,
+    entry_point: u32,
+    stub_address: u32,
+) -> Section {
+    let mut stub = Vec::with_capacity(STUB_LEN as usize / 4);
+    stub.extend_from_slice(&load_address(3, compressed_address + HEADER_LEN));
+    stub.extend_from_slice(&load_address(4, address));
+    stub.extend_from_slice(&load_address(5, address + len));
+
+    // Every group of 8 chunks starts with a byte whose bits tell whether
+    // each chunk is a literal byte or a copy of earlier bytes
+    let group = stub.len();
+    stub.push(CMPLW_R4_R5);
+    let group_done = stub.len();
+    stub.push(0);
+    // lbz r6, 0(r3)
+    stub.push(d_form(34, 6, 3, 0));
+    // addi r3, r3, 1
+    stub.push(d_form(14, 3, 3, 1));
+    // li r7, 8
+    stub.push(d_form(14, 7, 0, 8));
+
+    let chunk = stub.len();
+    stub.push(CMPLW_R4_R5);
+    let chunk_done = stub.len();
+    stub.push(0);
+    // andi. r0, r6, 0x80
+    stub.push(d_form(28, 6, 0, 0x80));
+    // slwi r6, r6, 1
+    stub.push(rlwinm(6, 6, 1, 0, 30, false));
+    let is_copy = stub.len();
+    stub.push(0);
+    // lbz r0, 0(r3)
+    stub.push(d_form(34, 0, 3, 0));
+    // addi r3, r3, 1
+    stub.push(d_form(14, 3, 3, 1));
+    // stb r0, 0(r4)
+    stub.push(d_form(38, 0, 4, 0));
+    // addi r4, r4, 1
+    stub.push(d_form(14, 4, 4, 1));
+    let literal_done = stub.len();
+    stub.push(0);
+
+    // The copies consist of the distance in the lower 12 bits and the
+    // length in the upper 4 bits, with a third byte for longer copies
+    let copy = stub.len();
+    // lbz r8, 0(r3)
+    stub.push(d_form(34, 8, 3, 0));
+    // lbz r9, 1(r3)
+    stub.push(d_form(34, 9, 3, 1));
+    // addi r3, r3, 2
+    stub.push(d_form(14, 3, 3, 2));
+    stub.push(rlwinm(10, 8, 8, 20, 23, false));
+    stub.push(OR_R10_R10_R9);
+    // addi r10, r10, 1
+    stub.push(d_form(14, 10, 10, 1));
+    stub.push(SUBF_R10_R10_R4);
+    // srwi. r8, r8, 4
+    stub.push(rlwinm(8, 8, 28, 4, 31, true));
+    let is_short = stub.len();
+    stub.push(0);
+    // lbz r8, 0(r3)
+    stub.push(d_form(34, 8, 3, 0));
+    // addi r3, r3, 1
+    stub.push(d_form(14, 3, 3, 1));
+    // addi r8, r8, 0x10
+    stub.push(d_form(14, 8, 8, 0x10));
+    let short = stub.len();
+    // addi r8, r8, 2
+    stub.push(d_form(14, 8, 8, 2));
+    stub.push(MTCTR_R8);
+    let copy_loop = stub.len();
+    // lbz r0, 0(r10)
+    stub.push(d_form(34, 0, 10, 0));
+    // addi r10, r10, 1
+    stub.push(d_form(14, 10, 10, 1));
+    // stb r0, 0(r4)
+    stub.push(d_form(38, 0, 4, 0));
+    // addi r4, r4, 1
+    stub.push(d_form(14, 4, 4, 1));
+    let copy_loop_end = stub.len();
+    stub.push(0);
+
+    let next = stub.len();
+    // addic. r7, r7, -1
+    stub.push(d_form(13, 7, 7, -1i16 as u16));
+    let has_chunks = stub.len();
+    stub.push(0);
+    let group_end = stub.len();
+    stub.push(build_branch_instruction(
+        stub_address + 4 * group_end as u32,
+        stub_address + 4 * group as u32,
+        false,
+        false,
+    ));
+
+    // The decompressed data may be code, so it needs to reach the
+    // instruction cache
+    let done = stub.len();
+    entry::sync_caches(&mut stub, stub_address, address..address + len);
+    stub.extend_from_slice(&entry::jump(entry_point));
+
+    branch_conditional(&mut stub, group_done, done, BO_FALSE, BI_LT);
+    branch_conditional(&mut stub, chunk_done, done, BO_FALSE, BI_LT);
+    branch_conditional(&mut stub, is_copy, copy, BO_TRUE, BI_EQ);
+    stub[literal_done] = build_branch_instruction(
+        stub_address + 4 * literal_done as u32,
+        stub_address + 4 * next as u32,
+        false,
+        false,
+    );
+    branch_conditional(&mut stub, is_short, short, BO_FALSE, BI_EQ);
+    branch_conditional(
+        &mut stub,
+        copy_loop_end,
+        copy_loop,
+        BO_DECREMENT_NONZERO,
+        BI_LT,
+    );
+    branch_conditional(&mut stub, has_chunks, chunk, BO_FALSE, BI_EQ);
+
+    entry::to_section(stub_address, &stub)
+}
@@ -0,0 +1,217 @@
+//! Compares two games, like a build of the Rom Hack and the original game or
+//! two builds of the Rom Hack. The sections of the main DOLs are compared,
+//! with the changed code disassembled instruction by instruction, followed by
+//! the files of the file systems that were added, removed or changed.
+
+use byteorder::{ByteOrder, BE};
+use dol::{DolFile, Section};
+use failure::{Error, ResultExt};
+use iso::virtual_file_system::{Directory, File, Node};
+use report::format_word;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::{Read, Seek};
+
+pub fn diff_dols(report: &mut String, original: &DolFile, patched: &DolFile) -> Result<(), Error> {
+    writeln!(report, "DOL")?;
+    if original.entry_point != patched.entry_point {
+        writeln!(
+            report,
+            "  Changed the entry point from {:08X} to {:08X}",
+            original.entry_point, patched.entry_point
+        )?;
+    }
+    if (original.bss_address, original.bss_size) != (patched.bss_address, patched.bss_size) {
+        writeln!(
+            report,
+            "  Changed the bss from {:08X}..{:08X} to {:08X}..{:08X}",
+            original.bss_address,
+            original.bss_address.wrapping_add(original.bss_size),
+            patched.bss_address,
+            patched.bss_address.wrapping_add(patched.bss_size)
+        )?;
+    }
+    diff_sections(
+        report,
+        "text",
+        &original.text_sections,
+        &patched.text_sections,
+    )?;
+    diff_sections(
+        report,
+        "data",
+        &original.data_sections,
+        &patched.data_sections,
+    )?;
+
+    for region in regions(&changed_words(original, &patched.text_sections)) {
+        writeln!(
+            report,
+            "  Changed the code at {:08X}..{:08X}",
+            region[0].0,
+            region[region.len() - 1].0 + 4
+        )?;
+        for &(address, old, new) in region {
+            writeln!(
+                report,
+                "    {:08X}: {} -> {}",
+                address,
+                format_word(address, old),
+                format_word(address, new)
+            )?;
+        }
+    }
+    for region in regions(&changed_words(original, &patched.data_sections)) {
+        writeln!(
+            report,
+            "  Changed the data at {:08X}..{:08X}",
+            region[0].0,
+            region[region.len() - 1].0 + 4
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Sections are matched up by their addresses.
+fn diff_sections(
+    report: &mut String,
+    kind: &str,
+    original: &[Section],
+    patched: &[Section],
+) -> Result<(), Error> {
+    for section in original {
+        match patched.iter().find(|s| s.address == section.address) {
+            None => writeln!(
+                report,
+                "  Removed the {} section {:08X}..{:08X}",
+                kind,
+                section.address,
+                section.end_address()
+            )?,
+            Some(patched) if patched.data.len() != section.data.len() => writeln!(
+                report,
+                "  Resized the {} section {:08X}..{:08X} to {:08X}..{:08X}",
+                kind,
+                section.address,
+                section.end_address(),
+                patched.address,
+                patched.end_address()
+            )?,
+            Some(_) => {}
+        }
+    }
+    for section in patched {
+        if !original.iter().any(|s| s.address == section.address) {
+            writeln!(
+                report,
+                "  Added the {} section {:08X}..{:08X}",
+                kind,
+                section.address,
+                section.end_address()
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The words of the sections that differ from the original DOL, along with
+/// their address and the original word.
+fn changed_words(original: &DolFile, sections: &[Section]) -> Vec<(u32, u32, u32)> {
+    let mut changes = Vec::new();
+    for section in sections {
+        for (index, word) in section.data.chunks(4).enumerate() {
+            if word.len() < 4 {
+                break;
+            }
+            let address = section.address + 4 * index as u32;
+            let new = BE::read_u32(word);
+            match original.read_u32(address) {
+                Some(old) if old != new => changes.push((address, old, new)),
+                _ => {}
+            }
+        }
+    }
+    changes
+}
+
+/// Groups the changed words into runs of consecutive words.
+fn regions(changes: &[(u32, u32, u32)]) -> Vec<&[(u32, u32, u32)]> {
+    let mut regions = Vec::new();
+    let mut start = 0;
+    for index in 1..=changes.len() {
+        if index == changes.len() || changes[index].0 != changes[index - 1].0 + 4 {
+            regions.push(&changes[start..index]);
+            start = index;
+        }
+    }
+    regions
+}
+
+pub fn diff_files<A: Read + Seek, B: Read + Seek>(
+    report: &mut String,
+    original: &Directory,
+    original_reader: &mut A,
+    patched: &Directory,
+    patched_reader: &mut B,
+) -> Result<(), Error> {
+    let (mut original_files, mut patched_files) = (BTreeMap::new(), BTreeMap::new());
+    collect_files(original, "", &mut original_files);
+    collect_files(patched, "", &mut patched_files);
+
+    writeln!(report, "Files")?;
+    let mut unchanged = 0;
+    for (path, file) in &original_files {
+        let patched_file = match patched_files.get(path) {
+            Some(patched_file) => patched_file,
+            None => {
+                writeln!(report, "  Removed {}", path)?;
+                continue;
+            }
+        };
+        if file.len() != patched_file.len() {
+            writeln!(
+                report,
+                "  Changed {} from {} to {} bytes",
+                path,
+                file.len(),
+                patched_file.len()
+            )?;
+        } else if file
+            .read(original_reader)
+            .with_context(|_| format!("Couldn't read \"{}\" of the original game", path))?
+            != patched_file
+                .read(patched_reader)
+                .with_context(|_| format!("Couldn't read \"{}\" of the patched game", path))?
+        {
+            writeln!(report, "  Changed {}", path)?;
+        } else {
+            unchanged += 1;
+        }
+    }
+    for (path, file) in &patched_files {
+        if !original_files.contains_key(path) {
+            writeln!(report, "  Added {} with {} bytes", path, file.len())?;
+        }
+    }
+    writeln!(report, "  {} files are unchanged", unchanged)?;
+
+    Ok(())
+}
+
+fn collect_files<'a, 'b>(
+    dir: &'b Directory<'a>,
+    prefix: &str,
+    files: &mut BTreeMap<String, &'b File<'a>>,
+) {
+    for node in &dir.children {
+        match *node {
+            Node::Directory(ref child) => {
+                collect_files(child, &format!("{}{}/", prefix, child.name), files)
+            }
+            Node::File(ref file) => {
+                files.insert(format!("{}{}", prefix, file.name), file);
+            }
+        }
+    }
+}
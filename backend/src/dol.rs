@@ -1,7 +1,19 @@
 use assembler::Instruction;
 use byteorder::{ByteOrder, BE};
 use failure::Error;
-use std::fmt::{self, Debug};
+use std::error;
+use std::fmt::{self, Debug, Display};
+use std::mem;
+use std::ops::Range;
+
+pub const TEXT_SECTION_COUNT: usize = 7;
+pub const DATA_SECTION_COUNT: usize = 11;
+pub const HEADER_LEN: usize = 0x100;
+/// The end of the console's main memory, as seen through the cached mirror.
+pub const MEM1_END: u32 = 0x8180_0000;
+/// The largest gap between two sections that is filled with zeros to merge
+/// them. Sections further apart are kept separate.
+const MAX_MERGE_GAP: u32 = 0x20;
 
 pub struct Section {
     pub address: u32,
@@ -15,15 +27,25 @@ pub struct DolFile {
     pub bss_address: u32,
     pub bss_size: u32,
     pub entry_point: u32,
+    /// The parts of memory that injected code may still be placed into.
+    pub free_regions: Vec<Range<u32>>,
+    /// The parts of memory that were handed out by `allocate`.
+    pub reservations: Vec<Reservation>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Reservation {
+    pub address: u32,
+    pub size: u32,
 }
 
 pub struct DolHeader {
-    pub text_section_offsets: [u32; 7],
-    pub data_section_offsets: [u32; 11],
-    pub text_section_addresses: [u32; 7],
-    pub data_section_addresses: [u32; 11],
-    pub text_section_sizes: [u32; 7],
-    pub data_section_sizes: [u32; 11],
+    pub text_section_offsets: [u32; TEXT_SECTION_COUNT],
+    pub data_section_offsets: [u32; DATA_SECTION_COUNT],
+    pub text_section_addresses: [u32; TEXT_SECTION_COUNT],
+    pub data_section_addresses: [u32; DATA_SECTION_COUNT],
+    pub text_section_sizes: [u32; TEXT_SECTION_COUNT],
+    pub data_section_sizes: [u32; DATA_SECTION_COUNT],
     pub bss_address: u32,
     pub bss_size: u32,
     pub entry_point: u32,
@@ -53,6 +75,63 @@ entry_point: {:x}",
     }
 }
 
+#[derive(Debug)]
+pub enum ParseError {
+    HeaderTooSmall {
+        len: usize,
+    },
+    SectionOutOfBounds {
+        kind: &'static str,
+        index: usize,
+        offset: u32,
+        len: u32,
+        file_len: usize,
+    },
+    SectionOverlapsHeader {
+        kind: &'static str,
+        index: usize,
+        offset: u32,
+    },
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::HeaderTooSmall { len } => write!(
+                f,
+                "The DOL is only {} bytes long, which is too small to contain the header",
+                len
+            ),
+            ParseError::SectionOutOfBounds {
+                kind,
+                index,
+                offset,
+                len,
+                file_len,
+            } => write!(
+                f,
+                "The {} section {} spans from {:#x} to {:#x}, but the DOL is only {:#x} bytes long",
+                kind,
+                index,
+                offset,
+                offset as u64 + len as u64,
+                file_len
+            ),
+            ParseError::SectionOverlapsHeader {
+                kind,
+                index,
+                offset,
+            } => write!(
+                f,
+                "The {} section {} starts at {:#x}, which is inside the header",
+                kind, index, offset
+            ),
+        }
+    }
+}
+
+impl error::Error for ParseError {}
+
 fn read_u32(data: &[u8]) -> u32 {
     BE::read_u32(data)
 }
@@ -67,7 +146,8 @@ fn read_sections(
     addresses_offset: usize,
     lengths_offset: usize,
     max: usize,
-) -> Vec<Section> {
+    kind: &'static str,
+) -> Result<Vec<Section>, ParseError> {
     let mut sections = Vec::new();
     for i in 0..max {
         let offset = read_u32(&data[4 * i + offsets_offset..]);
@@ -76,7 +156,23 @@ fn read_sections(
         if length == 0 {
             break;
         }
-        let section_data = data[offset as usize..(offset + length) as usize]
+        if (offset as usize) < HEADER_LEN {
+            return Err(ParseError::SectionOverlapsHeader {
+                kind,
+                index: i,
+                offset,
+            });
+        }
+        if offset as u64 + length as u64 > data.len() as u64 {
+            return Err(ParseError::SectionOutOfBounds {
+                kind,
+                index: i,
+                offset,
+                len: length,
+                file_len: data.len(),
+            });
+        }
+        let section_data = data[offset as usize..][..length as usize]
             .to_vec()
             .into_boxed_slice();
         let section = Section {
@@ -85,32 +181,165 @@ fn read_sections(
         };
         sections.push(section);
     }
+    Ok(sections)
+}
+
+/// Determines the length of the DOL by looking at where its sections end.
+/// Only the header needs to be provided. Returns `None` if the header is
+/// truncated.
+pub fn file_len(header: &[u8]) -> Option<usize> {
+    if header.len() < HEADER_LEN {
+        return None;
+    }
+
+    let section_count = TEXT_SECTION_COUNT + DATA_SECTION_COUNT;
+    let mut len = HEADER_LEN;
+    for i in 0..section_count {
+        let offset = read_u32(&header[4 * i..]) as usize;
+        let size = read_u32(&header[4 * (i + 2 * section_count)..]) as usize;
+        if size != 0 {
+            len = len.max(offset + size);
+        }
+    }
+
+    Some(len)
+}
+
+impl Section {
+    pub fn end_address(&self) -> u32 {
+        self.address + self.data.len() as u32
+    }
+}
+
+fn overlaps_any(start: u32, end: u32, sections: &[Section]) -> bool {
     sections
+        .iter()
+        .any(|s| s.address < end && start < s.end_address())
+}
+
+/// Coalesces adjacent sections until at most `max` sections are left. The gap
+/// between two merged sections is filled with zeros, so sections are only
+/// merged if that gap is at most `MAX_MERGE_GAP` bytes and doesn't overlap any
+/// other section of the DOL.
+fn merge_sections(
+    sections: &mut Vec<Section>,
+    other_sections: &[Section],
+    max: usize,
+    kind: &str,
+) -> Result<(), Error> {
+    if sections.len() <= max {
+        return Ok(());
+    }
+
+    sections.sort_by_key(|s| s.address);
+
+    while sections.len() > max {
+        let index = sections
+            .windows(2)
+            .enumerate()
+            .filter_map(|(index, pair)| {
+                let gap_start = pair[0].end_address();
+                let gap_end = pair[1].address;
+                if gap_end < gap_start
+                    || gap_end - gap_start > MAX_MERGE_GAP
+                    || overlaps_any(gap_start, gap_end, other_sections)
+                {
+                    None
+                } else {
+                    Some((index, gap_end - gap_start))
+                }
+            }).min_by_key(|&(_, gap)| gap)
+            .map(|(index, _)| index)
+            .ok_or_else(|| {
+                format_err!(
+                    "The DOL has {} {} sections, but only {} fit into the header and none of \
+                     them are close enough to be merged without overlapping other sections. \
+                     Sections are only merged if they are at most {:#x} bytes apart.",
+                    sections.len(),
+                    kind,
+                    max,
+                    MAX_MERGE_GAP
+                )
+            })?;
+
+        let second = sections.remove(index + 1);
+        let first = &mut sections[index];
+        let mut data = mem::replace(&mut first.data, Box::new([])).into_vec();
+        data.resize((second.address - first.address) as usize, 0);
+        data.extend_from_slice(&second.data);
+        first.data = data.into_boxed_slice();
+    }
+
+    Ok(())
 }
 
 impl DolFile {
-    pub fn parse(data: &[u8]) -> Self {
-        let text_sections = read_sections(data, 0x0, 0x48, 0x90, 7);
-        let data_sections = read_sections(data, 0x1c, 0x64, 0xac, 11);
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        if data.len() < HEADER_LEN {
+            return Err(ParseError::HeaderTooSmall { len: data.len() }.into());
+        }
+
+        let text_sections = read_sections(data, 0x0, 0x48, 0x90, TEXT_SECTION_COUNT, "text")?;
+        let data_sections = read_sections(data, 0x1c, 0x64, 0xac, DATA_SECTION_COUNT, "data")?;
         let bss_address = read_u32(&data[0xd8..]);
         let bss_size = read_u32(&data[0xdc..]);
         let entry_point = read_u32(&data[0xe0..]);
 
-        DolFile {
+        Ok(DolFile {
             text_sections: text_sections,
             data_sections: data_sections,
             bss_address: bss_address,
             bss_size: bss_size,
             entry_point: entry_point,
-        }
+            free_regions: Vec::new(),
+            reservations: Vec::new(),
+        })
     }
 
-    pub fn append(&mut self, other: DolFile) {
+    /// Adds the sections of the other DOL. Its bss isn't merged into this
+    /// DOL's bss, as the game's startup code only clears the bss it knows
+    /// about. Instead it's returned, so it can be cleared separately.
+    pub fn append(&mut self, other: DolFile) -> Option<Range<u32>> {
         self.text_sections.extend(other.text_sections);
         self.data_sections.extend(other.data_sections);
+        if other.bss_size != 0 {
+            Some(other.bss_address..other.bss_address + other.bss_size)
+        } else {
+            None
+        }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
+    /// Merges sections so that the DOL fits into the fixed amount of text and
+    /// data sections the DOL header supports.
+    pub fn merge_sections(&mut self) -> Result<(), Error> {
+        merge_sections(
+            &mut self.text_sections,
+            &self.data_sections,
+            TEXT_SECTION_COUNT,
+            "text",
+        )?;
+        merge_sections(
+            &mut self.data_sections,
+            &self.text_sections,
+            DATA_SECTION_COUNT,
+            "data",
+        )
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        ensure!(
+            self.text_sections.len() <= TEXT_SECTION_COUNT,
+            "The DOL has {} text sections, but only {} are supported",
+            self.text_sections.len(),
+            TEXT_SECTION_COUNT
+        );
+        ensure!(
+            self.data_sections.len() <= DATA_SECTION_COUNT,
+            "The DOL has {} data sections, but only {} are supported",
+            self.data_sections.len(),
+            DATA_SECTION_COUNT
+        );
+
         let mut header = DolHeader::new();
         header.bss_address = self.bss_address;
         header.bss_size = self.bss_size;
@@ -118,7 +347,7 @@ impl DolFile {
 
         let mut data = Vec::<u8>::new();
         let mut i = 0;
-        let mut offset = 256;
+        let mut offset = HEADER_LEN;
 
         for section in &self.text_sections {
             header.text_section_offsets[i] = offset as u32;
@@ -145,7 +374,171 @@ impl DolFile {
         let mut bytes = header.to_bytes();
         bytes.extend(data);
 
-        bytes
+        Ok(bytes)
+    }
+
+    /// The first word aligned address after all the sections.
+    pub fn end_address(&self) -> Option<u32> {
+        self.text_sections
+            .iter()
+            .chain(&self.data_sections)
+            .map(|s| (s.end_address() + 3) & !3)
+            .max()
+    }
+
+    /// Trims the zeros at the end of the data section that the bss follows
+    /// and clears them along with the bss instead, which shrinks the DOL
+    /// without changing what ends up in memory. Only the bss of appended DOLs
+    /// is cleared, so the game's own sections can't be trimmed. Returns the
+    /// amount of bytes that were trimmed.
+    pub fn trim_into_bss(&mut self) -> u32 {
+        let bss_address = if self.bss_size != 0 {
+            self.bss_address
+        } else {
+            !0
+        };
+        let last = self
+            .text_sections
+            .iter()
+            .chain(&self.data_sections)
+            .filter(|s| s.end_address() <= bss_address)
+            .map(|s| s.end_address())
+            .max();
+        let index = match self
+            .data_sections
+            .iter()
+            .position(|s| Some(s.end_address()) == last && !s.data.is_empty())
+        {
+            Some(index) => index,
+            None => return 0,
+        };
+
+        let address = self.data_sections[index].address;
+        let data_len = self.data_sections[index].data.len();
+        // The section stays word aligned
+        let len = self.data_sections[index]
+            .data
+            .iter()
+            .rposition(|&b| b != 0)
+            .map_or(0, |i| (i + 4) & !3);
+        if len >= data_len {
+            return 0;
+        }
+        if len == 0 {
+            self.data_sections.remove(index);
+        } else {
+            let data = self.data_sections[index].data[..len].to_vec();
+            self.data_sections[index].data = data.into_boxed_slice();
+        }
+
+        let bss_end = if self.bss_size != 0 {
+            self.bss_address + self.bss_size
+        } else {
+            address + data_len as u32
+        };
+        self.bss_address = address + len as u32;
+        self.bss_size = bss_end - self.bss_address;
+        (data_len - len) as u32
+    }
+
+    /// Marks a part of memory as free, so injected code can be placed into
+    /// it. The region may not overlap any of the sections.
+    pub fn add_free_region(&mut self, start: u32, end: u32) -> Result<(), Error> {
+        ensure!(
+            start <= end,
+            "The free region {:08X}..{:08X} ends before it starts",
+            start,
+            end
+        );
+        if let Some(section) = self
+            .text_sections
+            .iter()
+            .chain(&self.data_sections)
+            .find(|s| s.address < end && start < s.end_address())
+        {
+            bail!(
+                "The free region {:08X}..{:08X} overlaps the section at {:08X}..{:08X}",
+                start,
+                end,
+                section.address,
+                section.end_address()
+            );
+        }
+        self.free_regions.push(start..end);
+        Ok(())
+    }
+
+    /// Marks the parts of memory between `start` and `end` as free that none
+    /// of the sections cover, like the sections of binaries placed into the
+    /// memory the arena is shrunk by.
+    pub fn add_free_regions_around_sections(&mut self, start: u32, end: u32) {
+        let mut sections = self
+            .text_sections
+            .iter()
+            .chain(&self.data_sections)
+            .filter(|s| s.address < end && start < s.end_address())
+            .map(|s| (s.address, s.end_address()))
+            .collect::<Vec<_>>();
+        sections.sort();
+
+        let mut free_start = start;
+        for (section_start, section_end) in sections {
+            if free_start < section_start {
+                self.free_regions.push(free_start..section_start);
+            }
+            free_start = free_start.max(section_end);
+        }
+        if free_start < end {
+            self.free_regions.push(free_start..end);
+        }
+    }
+
+    /// Hands out the address of `size` bytes of free memory, aligned to
+    /// `align` bytes, which needs to be a power of two. The free regions are
+    /// tried in the order they were added, and the memory is recorded as a
+    /// reservation, so it's never handed out twice.
+    pub fn allocate(&mut self, size: u32, align: u32) -> Result<u32, Error> {
+        ensure!(
+            align.is_power_of_two(),
+            "The alignment {} is not a power of two",
+            align
+        );
+
+        for index in 0..self.free_regions.len() {
+            let region = self.free_regions[index].clone();
+            let address = match region.start.checked_add(align - 1) {
+                Some(address) => address & !(align - 1),
+                None => continue,
+            };
+            let end = match address.checked_add(size) {
+                Some(end) if end <= region.end => end,
+                _ => continue,
+            };
+
+            // The alignment padding stays available for smaller allocations
+            self.free_regions[index] = end..region.end;
+            if address > region.start {
+                self.free_regions.insert(index, region.start..address);
+            }
+            self.reservations.push(Reservation { address, size });
+
+            return Ok(address);
+        }
+
+        bail!(
+            "There's no free region left that fits {:#x} bytes aligned to {} bytes",
+            size,
+            align
+        )
+    }
+
+    pub fn read_u32(&self, address: u32) -> Option<u32> {
+        let section = self
+            .text_sections
+            .iter()
+            .chain(&self.data_sections)
+            .find(|s| s.address <= address && s.end_address() >= address + 4)?;
+        Some(read_u32(&section.data[(address - section.address) as usize..]))
     }
 
     pub fn patch(&mut self, instructions: &[Instruction]) -> Result<(), Error> {
@@ -156,14 +549,18 @@ impl DolFile {
                 .chain(self.data_sections.iter_mut())
                 .find(|d| {
                     d.address <= instruction.address
-                        && d.address + d.data.len() as u32 > instruction.address
+                        && d.address + d.data.len() as u32 >= instruction.address + 4
                 });
 
             if let Some(section) = section {
                 let index = (instruction.address - section.address) as usize;
-                write_u32(&mut section.data[index..], instruction.data);
+                let word = instruction.apply(read_u32(&section.data[index..]));
+                write_u32(&mut section.data[index..], word);
             } else {
-                bail!("Patch couldn't be applied.");
+                bail!(
+                    "The patch at {:08X} is not within one of the DOL's sections",
+                    instruction.address
+                );
             }
         }
 
@@ -174,12 +571,12 @@ impl DolFile {
 impl DolHeader {
     pub fn new() -> Self {
         DolHeader {
-            text_section_offsets: [0; 7],
-            data_section_offsets: [0; 11],
-            text_section_addresses: [0; 7],
-            data_section_addresses: [0; 11],
-            text_section_sizes: [0; 7],
-            data_section_sizes: [0; 11],
+            text_section_offsets: [0; TEXT_SECTION_COUNT],
+            data_section_offsets: [0; DATA_SECTION_COUNT],
+            text_section_addresses: [0; TEXT_SECTION_COUNT],
+            data_section_addresses: [0; DATA_SECTION_COUNT],
+            text_section_sizes: [0; TEXT_SECTION_COUNT],
+            data_section_sizes: [0; DATA_SECTION_COUNT],
             bss_address: 0,
             bss_size: 0,
             entry_point: 0,
@@ -187,7 +584,7 @@ impl DolHeader {
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut data = vec![0; 256];
+        let mut data = vec![0; HEADER_LEN];
         let mut offset = 0;
 
         for &value in &self.text_section_offsets {
@@ -229,3 +626,98 @@ impl DolHeader {
         data
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(address: u32, data: &[u8]) -> Section {
+        Section {
+            address,
+            data: data.to_vec().into_boxed_slice(),
+        }
+    }
+
+    fn text_dol(sections: Vec<Section>) -> DolFile {
+        DolFile {
+            text_sections: sections,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn keeps_sections_that_already_fit() {
+        let mut dol = text_dol(vec![
+            section(0x8000_4000, &[1; 4]),
+            section(0x8000_3000, &[2; 4]),
+        ]);
+        dol.merge_sections().unwrap();
+        assert_eq!(dol.text_sections.len(), 2);
+        assert_eq!(dol.text_sections[0].address, 0x8000_4000);
+    }
+
+    #[test]
+    fn merges_adjacent_sections() {
+        let mut sections = (0..TEXT_SECTION_COUNT as u32)
+            .map(|i| section(0x8000_4000 + 0x100 * i, &[i as u8; 0x10]))
+            .collect::<Vec<_>>();
+        sections.push(section(0x8000_4010, &[0xFF; 8]));
+        let mut dol = text_dol(sections);
+        dol.merge_sections().unwrap();
+
+        assert_eq!(dol.text_sections.len(), TEXT_SECTION_COUNT);
+        let merged = &dol.text_sections[0];
+        assert_eq!(merged.address, 0x8000_4000);
+        assert_eq!(&merged.data[..0x10], &[0; 0x10]);
+        assert_eq!(&merged.data[0x10..], &[0xFF; 8]);
+    }
+
+    #[test]
+    fn fills_small_gaps_with_zeros() {
+        let mut sections = (0..TEXT_SECTION_COUNT as u32)
+            .map(|i| section(0x8000_4000 + 0x100 * i, &[1; 0x10]))
+            .collect::<Vec<_>>();
+        sections.push(section(0x8000_4010 + MAX_MERGE_GAP, &[2; 4]));
+        let mut dol = text_dol(sections);
+        dol.merge_sections().unwrap();
+
+        assert_eq!(dol.text_sections.len(), TEXT_SECTION_COUNT);
+        let merged = &dol.text_sections[0];
+        assert_eq!(merged.end_address(), 0x8000_4014 + MAX_MERGE_GAP);
+        assert_eq!(&merged.data[..0x10], &[1; 0x10]);
+        assert!(merged.data[0x10..][..MAX_MERGE_GAP as usize]
+            .iter()
+            .all(|&b| b == 0));
+        assert_eq!(&merged.data[0x10 + MAX_MERGE_GAP as usize..], &[2; 4]);
+    }
+
+    #[test]
+    fn keeps_sections_with_large_gaps_apart() {
+        let sections = (0..TEXT_SECTION_COUNT as u32 + 1)
+            .map(|i| section(0x8000_4000 + 0x100 * i, &[1; 0x10]))
+            .collect();
+        let mut dol = text_dol(sections);
+        assert!(dol.merge_sections().is_err());
+    }
+
+    #[test]
+    fn doesnt_merge_overlapping_sections() {
+        let mut sections = (0..TEXT_SECTION_COUNT as u32)
+            .map(|i| section(0x8000_4000 + 0x100 * i, &[1; 0x10]))
+            .collect::<Vec<_>>();
+        sections.push(section(0x8000_4008, &[2; 0x10]));
+        let mut dol = text_dol(sections);
+        assert!(dol.merge_sections().is_err());
+    }
+
+    #[test]
+    fn doesnt_merge_across_other_sections() {
+        let mut sections = (0..TEXT_SECTION_COUNT as u32)
+            .map(|i| section(0x8000_4000 + 0x100 * i, &[1; 0x10]))
+            .collect::<Vec<_>>();
+        sections.push(section(0x8000_4018, &[2; 4]));
+        let mut dol = text_dol(sections);
+        dol.data_sections.push(section(0x8000_4010, &[3; 4]));
+        assert!(dol.merge_sections().is_err());
+    }
+}
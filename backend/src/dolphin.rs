@@ -0,0 +1,151 @@
+//! Launches Dolphin and patches the memory of the game it is running. Dolphin
+//! keeps the emulated main memory in a shared memory mapping called
+//! `dolphin-emu.<pid>`, which is located through `/proc/<pid>/maps` and then
+//! written through `/proc/<pid>/mem`. This is only supported on Linux.
+//!
+//! Dolphin's JIT doesn't notice instructions changing underneath it, so
+//! blocks that were already compiled keep running the old code until they
+//! are recompiled. The interpreters pick up the changes immediately.
+
+use dol::{DolFile, Section};
+use failure::{Error, ResultExt};
+use std::path::Path;
+use std::process::{Child, Command};
+
+/// The emulated main memory is mapped with the size of the address space
+/// that is reserved for it, rather than the 24 MiB that are actually there.
+#[cfg(target_os = "linux")]
+const MEM1_MAPPING_SIZE: u64 = 0x200_0000;
+#[cfg(target_os = "linux")]
+const MEM1_SIZE: u32 = 0x180_0000;
+
+pub struct Dolphin {
+    process: Child,
+}
+
+impl Dolphin {
+    pub fn launch(executable: &Path, game: &Path) -> Result<Self, Error> {
+        let process = Command::new(executable)
+            .arg("-e")
+            .arg(game)
+            .spawn()
+            .context("Couldn't start Dolphin")?;
+        Ok(Dolphin { process })
+    }
+
+    /// Checks whether Dolphin is still running, as it may have been closed.
+    pub fn is_running(&mut self) -> bool {
+        self.process
+            .try_wait()
+            .map(|s| s.is_none())
+            .unwrap_or(false)
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn write_memory(&self, address: u32, data: &[u8]) -> Result<(), Error> {
+        use std::fs::{self, OpenOptions};
+        use std::io::{prelude::*, SeekFrom};
+
+        // Both the cached and the uncached mirror map to physical memory
+        let physical = address & 0x3FFF_FFFF;
+        ensure!(
+            address & 0xC000_0000 != 0 && physical + data.len() as u32 <= MEM1_SIZE,
+            "{:08X} is not in main memory",
+            address
+        );
+
+        let pid = self.process.id();
+        let name = format!("dolphin-emu.{}", pid);
+        let maps = fs::read_to_string(format!("/proc/{}/maps", pid))
+            .context("Couldn't read the memory mappings of Dolphin")?;
+        let start = maps
+            .lines()
+            .filter(|line| line.contains(&name))
+            .filter_map(|line| {
+                let range = line.split_whitespace().next()?;
+                let mut bounds = range.split('-');
+                let start = u64::from_str_radix(bounds.next()?, 16).ok()?;
+                let end = u64::from_str_radix(bounds.next()?, 16).ok()?;
+                if end - start == MEM1_MAPPING_SIZE {
+                    Some(start)
+                } else {
+                    None
+                }
+            })
+            .next()
+            .ok_or_else(|| format_err!("Couldn't find the emulated memory, is a game running?"))?;
+
+        let mut memory = OpenOptions::new()
+            .write(true)
+            .open(format!("/proc/{}/mem", pid))
+            .context("Couldn't open the memory of Dolphin")?;
+        memory.seek(SeekFrom::Start(start + u64::from(physical)))?;
+        memory
+            .write_all(data)
+            .context("Couldn't write to the memory of Dolphin")?;
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn write_memory(&self, _address: u32, _data: &[u8]) -> Result<(), Error> {
+        bail!("Writing to the memory of Dolphin is only supported on Linux")
+    }
+
+    /// Writes the words that differ between the DOL that is running and the
+    /// new DOL into memory. This is only possible if both DOLs load their
+    /// sections to the same places, as the game may have already put other
+    /// data right after them. Returns the number of words that were written
+    /// or `None` if the game needs to be restarted.
+    pub fn hot_patch(&self, running: &DolFile, new: &DolFile) -> Result<Option<usize>, Error> {
+        let same_layout = |a: &[Section], b: &[Section]| {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|(a, b)| a.address == b.address && a.data.len() == b.data.len())
+        };
+        if !same_layout(&running.text_sections, &new.text_sections)
+            || !same_layout(&running.data_sections, &new.data_sections)
+            || running.bss_address != new.bss_address
+            || running.bss_size != new.bss_size
+            || running.entry_point != new.entry_point
+        {
+            return Ok(None);
+        }
+
+        let mut count = 0;
+        let sections = running
+            .text_sections
+            .iter()
+            .zip(&new.text_sections)
+            .chain(running.data_sections.iter().zip(&new.data_sections));
+        for (old, new) in sections {
+            // Consecutive changed words are written at once
+            let len = old.data.len();
+            let differs = |i: usize| old.data[i..len.min(i + 4)] != new.data[i..len.min(i + 4)];
+            let mut index = 0;
+            while index < len {
+                if !differs(index) {
+                    index += 4;
+                    continue;
+                }
+                let start = index;
+                while index < len && differs(index) {
+                    index += 4;
+                    count += 1;
+                }
+                let end = len.min(index);
+                self.write_memory(old.address + start as u32, &new.data[start..end])?;
+            }
+        }
+
+        Ok(Some(count))
+    }
+}
+
+impl Drop for Dolphin {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
@@ -0,0 +1,210 @@
+//! Writes a Dolphin game INI that applies the Rom Hack's changes to the DOL
+//! on top of the original game. The replaced instructions become `[OnFrame]`
+//! patches and the newly added sections become `[Gecko]` codes, as those can
+//! write whole blocks of memory at once. This way the Rom Hack can be toggled
+//! in Dolphin without rebuilding the game, keeping the savestates and the
+//! cache of the original game.
+//!
+//! Dolphin applies both kinds of patches every frame, so the Gecko code is
+//! guarded by a word of the sections that is only there once they are
+//! written. This way the sections are written once, along with the bss being
+//! cleared, and the Rom Hack's data isn't reset every frame. The original
+//! game's arena isn't shrunk, so the sections need to be in the free regions.
+
+use assembler::Instruction;
+use byteorder::{ByteOrder, BE};
+use conflicts::Patch;
+use dol::Section;
+use failure::{err_msg, Error, ResultExt};
+use std::fs::File;
+use std::io::{prelude::*, BufWriter};
+use std::ops::Range;
+use std::path::Path;
+
+const SECTIONS_NAME: &str = "Rom Hack sections";
+
+/// The Gecko code types of the 8-bit fill, the string write, the check
+/// whether a word doesn't equal a value and the terminator that ends it.
+const GECKO_FILL_8: u32 = 0x0000_0000;
+const GECKO_WRITE_STRING: u32 = 0x0600_0000;
+const GECKO_IF_NOT_EQUAL: u32 = 0x2200_0000;
+const GECKO_FULL_TERMINATOR: (u32, u32) = (0xE000_0000, 0x8000_8000);
+/// The 8-bit fill writes up to this many bytes at once.
+const MAX_FILL_LEN: u32 = 0x1_0000;
+
+pub fn create(
+    path: &Path,
+    sections: &[&Section],
+    bss: Option<&Range<u32>>,
+    free_regions: &[(u32, u32)],
+    patches: &[Patch],
+) -> Result<(), Error> {
+    let ranges = sections
+        .iter()
+        .map(|s| s.address..s.end_address())
+        .chain(bss.cloned());
+    for range in ranges {
+        ensure!(
+            free_regions
+                .iter()
+                .any(|&(start, end)| start <= range.start && range.end <= end),
+            "The Rom Hack's memory at {:08X}..{:08X} isn't in any of the free regions. As the \
+             original game's arena isn't shrunk in Dolphin, the Rom Hack needs to be linked \
+             into the free regions.",
+            range.start,
+            range.end
+        );
+    }
+
+    let mut file = BufWriter::new(File::create(path).context("Couldn't create the Dolphin INI")?);
+    let patches = patches
+        .iter()
+        .filter(|p| !p.instructions.is_empty())
+        .collect::<Vec<_>>();
+
+    writeln!(file, "[OnFrame]")?;
+    for patch in &patches {
+        writeln!(file, "${}", patch_name(patch))?;
+        for instruction in patch.instructions {
+            write_instruction(&mut file, instruction)?;
+        }
+    }
+
+    writeln!(file, "[OnFrame_Enabled]")?;
+    for patch in &patches {
+        writeln!(file, "${}", patch_name(patch))?;
+    }
+
+    let has_code = !sections.is_empty() || bss.is_some();
+    writeln!(file, "[Gecko]")?;
+    if has_code {
+        writeln!(file, "${}", SECTIONS_NAME)?;
+        let (address, value) = guard(sections)?;
+        write_code(&mut file, GECKO_IF_NOT_EQUAL, address, value)?;
+        for section in sections {
+            write_section(&mut file, section)?;
+        }
+        if let Some(bss) = bss {
+            clear_bss(&mut file, bss)?;
+        }
+        writeln!(
+            file,
+            "{:08X} {:08X}",
+            GECKO_FULL_TERMINATOR.0, GECKO_FULL_TERMINATOR.1
+        )?;
+    }
+
+    writeln!(file, "[Gecko_Enabled]")?;
+    if has_code {
+        writeln!(file, "${}", SECTIONS_NAME)?;
+    }
+
+    file.flush()?;
+
+    Ok(())
+}
+
+fn patch_name(patch: &Patch) -> String {
+    format!("Rom Hack: {}", patch.name)
+}
+
+/// Writes the instruction as a patch of a full word. The bytes of masked
+/// instructions are written on their own, or as a halfword if they form one.
+fn write_instruction<W: Write>(file: &mut W, instruction: &Instruction) -> Result<(), Error> {
+    let address = instruction.address;
+    let data = instruction.data;
+    match instruction.mask {
+        0xFFFF_FFFF => writeln!(file, "0x{:08X}:dword:0x{:08X}", address, data)?,
+        0xFFFF_0000 => writeln!(file, "0x{:08X}:word:0x{:04X}", address, data >> 16)?,
+        0x0000_FFFF => writeln!(file, "0x{:08X}:word:0x{:04X}", address + 2, data & 0xFFFF)?,
+        mask => {
+            for index in 0..4 {
+                let shift = 8 * (3 - index);
+                if (mask >> shift) & 0xFF != 0 {
+                    writeln!(
+                        file,
+                        "0x{:08X}:byte:0x{:02X}",
+                        address + index,
+                        (data >> shift) & 0xFF
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Finds the word that tells whether the sections were written already. It's
+/// the first word that isn't zero, and as the text sections come first, it's
+/// usually code, which the game doesn't change.
+fn guard(sections: &[&Section]) -> Result<(u32, u32), Error> {
+    let words = sections.iter().flat_map(|s| {
+        s.data
+            .chunks(4)
+            .enumerate()
+            .filter(|&(_, w)| w.len() == 4)
+            .map(move |(i, w)| (s.address + 4 * i as u32, BE::read_u32(w)))
+    });
+    words
+        .find(|&(_, value)| value != 0)
+        .ok_or_else(|| err_msg("The Rom Hack has no data that tells whether it's written already"))
+}
+
+/// Writes a Gecko code line. The lowest bit of the code type is the 25th bit
+/// of the address.
+fn write_code<W: Write>(
+    file: &mut W,
+    code_type: u32,
+    address: u32,
+    value: u32,
+) -> Result<(), Error> {
+    ensure!(
+        address & 0xFE00_0000 == 0x8000_0000,
+        "The memory at {:08X} can't be written by a Gecko code",
+        address
+    );
+    writeln!(
+        file,
+        "{:08X} {:08X}",
+        code_type | (address & 0x01FF_FFFF),
+        value
+    )?;
+    Ok(())
+}
+
+/// Clears the bss with Gecko codes of type 00, which fill up to 0x10000
+/// bytes each.
+fn clear_bss<W: Write>(file: &mut W, bss: &Range<u32>) -> Result<(), Error> {
+    let mut address = bss.start;
+    while address < bss.end {
+        let len = (bss.end - address).min(MAX_FILL_LEN);
+        write_code(file, GECKO_FILL_8, address, (len - 1) << 16)?;
+        address += len;
+    }
+    Ok(())
+}
+
+/// Writes the section as a Gecko code of type 06, which writes a string of
+/// bytes.
+fn write_section<W: Write>(file: &mut W, section: &Section) -> Result<(), Error> {
+    write_code(
+        file,
+        GECKO_WRITE_STRING,
+        section.address,
+        section.data.len() as u32,
+    )?;
+
+    // The data is padded to full lines
+    for line in section.data.chunks(8) {
+        let mut buf = [0; 8];
+        buf[..line.len()].copy_from_slice(line);
+        writeln!(
+            file,
+            "{:08X} {:08X}",
+            BE::read_u32(&buf),
+            BE::read_u32(&buf[4..])
+        )?;
+    }
+
+    Ok(())
+}
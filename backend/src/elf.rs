@@ -0,0 +1,364 @@
+//! Writes the patched DOL as an ELF executable, so it can be loaded into
+//! disassemblers and debuggers along with all the known symbols. Every
+//! section of the DOL becomes its own section and segment of the ELF. The
+//! debug info of the linked objects is included as well, so the Rom Hack can
+//! be debugged on the source level.
+
+use byteorder::{WriteBytesExt, BE};
+use dol::DolFile;
+use failure::{Error, ResultExt};
+use framework_map::{self, InjectedSymbol};
+use linker::{DebugSection, LinkedSection};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+const ELF_HEADER_LEN: u32 = 0x34;
+const PROGRAM_HEADER_LEN: u32 = 0x20;
+const SECTION_HEADER_LEN: u32 = 0x28;
+const SYMBOL_LEN: u32 = 0x10;
+
+const ET_EXEC: u16 = 2;
+const EM_PPC: u16 = 20;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_NOBITS: u32 = 8;
+const SHF_WRITE: u32 = 1;
+const SHF_ALLOC: u32 = 2;
+const SHF_EXECINSTR: u32 = 4;
+
+const SHN_ABS: u16 = 0xFFF1;
+const STB_GLOBAL: u8 = 1;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u32,
+    address: u32,
+    offset: u32,
+    len: u32,
+    link: u32,
+    info: u32,
+    align: u32,
+    entry_len: u32,
+}
+
+struct Symbol {
+    name: u32,
+    address: u32,
+    len: u32,
+}
+
+struct StringTable {
+    data: Vec<u8>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        Self { data: vec![0] }
+    }
+
+    fn add(&mut self, string: &str) -> u32 {
+        let offset = self.data.len() as u32;
+        self.data.extend_from_slice(string.as_bytes());
+        self.data.push(0);
+        offset
+    }
+}
+
+fn align(offset: u32) -> u32 {
+    (offset + 0x1F) & !0x1F
+}
+
+/// Creates the ELF with the symbols of the original game's symbol map, the
+/// Rom Hack's linked sections and the injected code.
+pub fn create(
+    path: &Path,
+    dol: &DolFile,
+    original: &HashMap<String, u32>,
+    sections: &[LinkedSection],
+    debug_sections: &[DebugSection],
+    injected: &[InjectedSymbol],
+) -> Result<(), Error> {
+    let loaded = dol
+        .text_sections
+        .iter()
+        .map(|s| (s, true))
+        .chain(dol.data_sections.iter().map(|s| (s, false)))
+        .filter(|&(s, _)| !s.data.is_empty())
+        .collect::<Vec<_>>();
+    let has_bss = dol.bss_size != 0;
+    let program_header_count = loaded.len() as u32 + has_bss as u32;
+
+    let mut section_names = StringTable::new();
+    let mut headers = vec![SectionHeader {
+        name: 0,
+        kind: 0,
+        flags: 0,
+        address: 0,
+        offset: 0,
+        len: 0,
+        link: 0,
+        info: 0,
+        align: 0,
+        entry_len: 0,
+    }];
+
+    let mut offset = align(ELF_HEADER_LEN + program_header_count * PROGRAM_HEADER_LEN);
+    let (mut text_index, mut data_index) = (0, 0);
+    for &(section, is_text) in &loaded {
+        let name = if is_text {
+            text_index += 1;
+            format!(".text{}", text_index - 1)
+        } else {
+            data_index += 1;
+            format!(".data{}", data_index - 1)
+        };
+        headers.push(SectionHeader {
+            name: section_names.add(&name),
+            kind: SHT_PROGBITS,
+            flags: if is_text {
+                SHF_ALLOC | SHF_EXECINSTR
+            } else {
+                SHF_ALLOC | SHF_WRITE
+            },
+            address: section.address,
+            offset,
+            len: section.data.len() as u32,
+            link: 0,
+            info: 0,
+            align: 4,
+            entry_len: 0,
+        });
+        offset = align(offset + section.data.len() as u32);
+    }
+    if has_bss {
+        headers.push(SectionHeader {
+            name: section_names.add(".bss"),
+            kind: SHT_NOBITS,
+            flags: SHF_ALLOC | SHF_WRITE,
+            address: dol.bss_address,
+            offset,
+            len: dol.bss_size,
+            link: 0,
+            info: 0,
+            align: 4,
+            entry_len: 0,
+        });
+    }
+
+    for section in debug_sections {
+        headers.push(SectionHeader {
+            name: section_names.add(section.name),
+            kind: SHT_PROGBITS,
+            flags: 0,
+            address: 0,
+            offset,
+            len: section.data.len() as u32,
+            link: 0,
+            info: 0,
+            align: 1,
+            entry_len: 0,
+        });
+        offset += section.data.len() as u32;
+    }
+
+    // The symbol map is stored unordered, but the ELF needs to be the same for
+    // every build
+    let mut original = original.iter().collect::<Vec<_>>();
+    original.sort();
+
+    let mut names = StringTable::new();
+    let mut symbols = original
+        .into_iter()
+        .map(|(name, &address)| Symbol {
+            name: names.add(name),
+            address,
+            len: 0,
+        }).collect::<Vec<_>>();
+    for section in sections {
+        symbols.push(Symbol {
+            name: names.add(&framework_map::symbol_name(section)),
+            address: section.address + section.sym_offset,
+            len: section.len - section.sym_offset,
+        });
+    }
+    for symbol in injected {
+        symbols.push(Symbol {
+            name: names.add(&symbol.name),
+            address: symbol.address,
+            len: symbol.len,
+        });
+    }
+    symbols.sort_by_key(|s| s.address);
+
+    let mut symbol_table = Vec::with_capacity((symbols.len() + 1) * SYMBOL_LEN as usize);
+    symbol_table.extend_from_slice(&[0; SYMBOL_LEN as usize]);
+    for symbol in &symbols {
+        let index = headers
+            .iter()
+            .position(|h| {
+                h.flags & SHF_ALLOC != 0
+                    && h.address <= symbol.address
+                    && symbol.address < h.address + h.len
+            }).map_or(SHN_ABS, |index| index as u16);
+        let kind = if index != SHN_ABS && headers[index as usize].flags & SHF_EXECINSTR != 0 {
+            STT_FUNC
+        } else {
+            STT_OBJECT
+        };
+        symbol_table.write_u32::<BE>(symbol.name)?;
+        symbol_table.write_u32::<BE>(symbol.address)?;
+        symbol_table.write_u32::<BE>(symbol.len)?;
+        symbol_table.push((STB_GLOBAL << 4) | kind);
+        symbol_table.push(0);
+        symbol_table.write_u16::<BE>(index)?;
+    }
+
+    let symbol_table_index = headers.len() as u32;
+    headers.push(SectionHeader {
+        name: section_names.add(".symtab"),
+        kind: SHT_SYMTAB,
+        flags: 0,
+        address: 0,
+        offset,
+        len: symbol_table.len() as u32,
+        // The string table follows the symbol table
+        link: symbol_table_index + 1,
+        // The index of the first global symbol
+        info: 1,
+        align: 4,
+        entry_len: SYMBOL_LEN,
+    });
+    offset += symbol_table.len() as u32;
+    headers.push(SectionHeader {
+        name: section_names.add(".strtab"),
+        kind: SHT_STRTAB,
+        flags: 0,
+        address: 0,
+        offset,
+        len: names.data.len() as u32,
+        link: 0,
+        info: 0,
+        align: 1,
+        entry_len: 0,
+    });
+    offset += names.data.len() as u32;
+    let section_names_index = headers.len() as u16;
+    let name = section_names.add(".shstrtab");
+    headers.push(SectionHeader {
+        name,
+        kind: SHT_STRTAB,
+        flags: 0,
+        address: 0,
+        offset,
+        len: section_names.data.len() as u32,
+        link: 0,
+        info: 0,
+        align: 1,
+        entry_len: 0,
+    });
+    offset += section_names.data.len() as u32;
+    let section_headers_offset = (offset + 3) & !3;
+
+    let mut file = BufWriter::new(File::create(path).context("Couldn't create the ELF file")?);
+
+    file.write_all(b"\x7FELF")?;
+    // 32-bit, big endian, version 1
+    file.write_all(&[1, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0])?;
+    file.write_u16::<BE>(ET_EXEC)?;
+    file.write_u16::<BE>(EM_PPC)?;
+    file.write_u32::<BE>(1)?;
+    file.write_u32::<BE>(dol.entry_point)?;
+    file.write_u32::<BE>(ELF_HEADER_LEN)?;
+    file.write_u32::<BE>(section_headers_offset)?;
+    file.write_u32::<BE>(0)?;
+    file.write_u16::<BE>(ELF_HEADER_LEN as u16)?;
+    file.write_u16::<BE>(PROGRAM_HEADER_LEN as u16)?;
+    file.write_u16::<BE>(program_header_count as u16)?;
+    file.write_u16::<BE>(SECTION_HEADER_LEN as u16)?;
+    file.write_u16::<BE>(headers.len() as u16)?;
+    file.write_u16::<BE>(section_names_index)?;
+
+    for header in headers.iter().filter(|h| h.flags & SHF_ALLOC != 0) {
+        let file_len = if header.kind == SHT_NOBITS {
+            0
+        } else {
+            header.len
+        };
+        let flags = if header.flags & SHF_EXECINSTR != 0 {
+            PF_R | PF_X
+        } else {
+            PF_R | PF_W
+        };
+        for &value in &[
+            PT_LOAD,
+            header.offset,
+            header.address,
+            header.address,
+            file_len,
+            header.len,
+            flags,
+            4,
+        ] {
+            file.write_u32::<BE>(value)?;
+        }
+    }
+
+    let mut position = ELF_HEADER_LEN + program_header_count * PROGRAM_HEADER_LEN;
+    let contents = loaded
+        .iter()
+        .map(|&(section, _)| &section.data[..])
+        .chain(debug_sections.iter().map(|s| &s.data[..]))
+        .chain(vec![
+            &symbol_table[..],
+            &names.data[..],
+            &section_names.data[..],
+        ]);
+    for (header, data) in headers[1..]
+        .iter()
+        .filter(|h| h.kind != SHT_NOBITS)
+        .zip(contents)
+    {
+        write_padding(&mut file, header.offset - position)?;
+        file.write_all(data)?;
+        position = header.offset + data.len() as u32;
+    }
+    write_padding(&mut file, section_headers_offset - position)?;
+
+    for header in &headers {
+        for &value in &[
+            header.name,
+            header.kind,
+            header.flags,
+            header.address,
+            header.offset,
+            header.len,
+            header.link,
+            header.info,
+            header.align,
+            header.entry_len,
+        ] {
+            file.write_u32::<BE>(value)?;
+        }
+    }
+    file.flush()?;
+
+    Ok(())
+}
+
+fn write_padding<W: Write>(writer: &mut W, len: u32) -> Result<(), Error> {
+    for _ in 0..len {
+        writer.write_all(&[0])?;
+    }
+    Ok(())
+}
@@ -0,0 +1,272 @@
+use assembler::{build_branch_instruction, is_branch_in_range};
+use byteorder::{ByteOrder, BE};
+use dol::{DolFile, Section};
+use failure::Error;
+use std::ops::Range;
+
+const MTCTR_R3: u32 = 0x7C69_03A6;
+const BCTR: u32 = 0x4E80_0420;
+const BLR: u32 = 0x4E80_0020;
+const STWU_R1_MINUS_8: u32 = 0x9421_FFF8;
+
+const CACHE_LINE_LEN: u32 = 0x20;
+const CMPLW_R3_R4: u32 = 0x7C03_2040;
+const BGE_FORWARD_24: u32 = 0x4080_0018;
+const DCBST_R3: u32 = 0x7C00_186C;
+const SYNC: u32 = 0x7C00_04AC;
+const ICBI_R3: u32 = 0x7C00_1FAC;
+const ISYNC: u32 = 0x4C00_012C;
+
+/// The amount of bytes the instructions of `sync_caches` take up.
+pub const SYNC_CACHES_LEN: u32 = 12 * 4;
+
+/// The instructions that jump to the address through r3, so the destination
+/// may be anywhere in memory.
+pub fn jump(address: u32) -> [u32; 4] {
+    [
+        // lis r3, address@h
+        (15 << 26) | (3 << 21) | (address >> 16),
+        // ori r3, r3, address@l
+        (24 << 26) | (3 << 21) | (3 << 16) | (address & 0xFFFF),
+        MTCTR_R3,
+        BCTR,
+    ]
+}
+
+/// Appends the instructions that flush the data cache and invalidate the
+/// instruction cache for the range to the stub at the address, so the code
+/// that was written there is executed instead of stale instructions. They use
+/// r3 and r4.
+pub fn sync_caches(stub: &mut Vec<u32>, stub_address: u32, range: Range<u32>) {
+    let start = range.start & !(CACHE_LINE_LEN - 1);
+    // lis r3, start@h
+    stub.push((15 << 26) | (3 << 21) | (start >> 16));
+    // ori r3, r3, start@l
+    stub.push((24 << 26) | (3 << 21) | (3 << 16) | (start & 0xFFFF));
+    // lis r4, end@h
+    stub.push((15 << 26) | (4 << 21) | (range.end >> 16));
+    // ori r4, r4, end@l
+    stub.push((24 << 26) | (4 << 21) | (4 << 16) | (range.end & 0xFFFF));
+
+    let loop_address = stub_address + 4 * stub.len() as u32;
+    stub.push(CMPLW_R3_R4);
+    stub.push(BGE_FORWARD_24);
+    stub.push(DCBST_R3);
+    stub.push(SYNC);
+    stub.push(ICBI_R3);
+    // addi r3, r3, CACHE_LINE_LEN
+    stub.push((14 << 26) | (3 << 21) | (3 << 16) | CACHE_LINE_LEN);
+    let branch_address = stub_address + 4 * stub.len() as u32;
+    stub.push(build_branch_instruction(
+        branch_address,
+        loop_address,
+        false,
+        false,
+    ));
+    stub.push(ISYNC);
+}
+
+/// The amount of bytes the stub of `sync_stub` takes up.
+pub fn sync_stub_len(ranges: &[Range<u32>]) -> u32 {
+    ranges.len() as u32 * SYNC_CACHES_LEN + 4 * 4
+}
+
+/// Builds the stub that syncs the caches for the ranges of code before it
+/// jumps to the game's original entry point. The stub needs to become the new
+/// entry point of the DOL.
+pub fn sync_stub(ranges: &[Range<u32>], entry_point: u32, stub_address: u32) -> Section {
+    let mut stub = Vec::with_capacity(sync_stub_len(ranges) as usize / 4);
+    for range in ranges {
+        sync_caches(&mut stub, stub_address, range.clone());
+    }
+    stub.extend_from_slice(&jump(entry_point));
+    to_section(stub_address, &stub)
+}
+
+pub fn to_section(address: u32, instructions: &[u32]) -> Section {
+    let mut data = vec![0; 4 * instructions.len()];
+    for (chunk, &instruction) in data.chunks_mut(4).zip(instructions) {
+        BE::write_u32(chunk, instruction);
+    }
+    Section {
+        address,
+        data: data.into_boxed_slice(),
+    }
+}
+
+/// The registers the game's runtime relies on, which are the stack pointer
+/// and the anchors of the small data areas.
+const RUNTIME_REGISTERS: [u32; 3] = [1, 2, 13];
+/// How far the entry point and the functions it calls are searched for the
+/// setup of the runtime registers.
+const SEARCH_LEN: u32 = 64;
+
+/// The amount of bytes the stub for the init functions takes up.
+pub fn stub_len(functions: &[u32]) -> u32 {
+    4 * (functions.len() as u32 + 2 * RUNTIME_REGISTERS.len() as u32 + 5)
+}
+
+/// Finds the values the game's entry point loads into the runtime registers,
+/// either directly or in one of the functions it calls, like
+/// `__init_registers`.
+fn runtime_registers(dol: &DolFile) -> Result<[u32; 3], Error> {
+    let mut values = [None; 3];
+    let mut high = [None; 32];
+    let mut functions = vec![dol.entry_point];
+    let mut index = 0;
+    while index < functions.len() && values.iter().any(Option::is_none) {
+        let function = functions[index];
+        index += 1;
+        for address in (0..SEARCH_LEN).map(|i| function + 4 * i) {
+            let instruction = match dol.read_u32(address) {
+                Some(instruction) => instruction,
+                None => break,
+            };
+            let opcode = instruction >> 26;
+            let d = (instruction >> 21) & 0x1F;
+            let a = (instruction >> 16) & 0x1F;
+            let immediate = instruction & 0xFFFF;
+            let value = match opcode {
+                // lis rD, value@h
+                15 if a == 0 => {
+                    high[d as usize] = Some(immediate << 16);
+                    None
+                }
+                // addi rD, rD, value@l
+                14 if a == d => {
+                    high[d as usize].map(|h| h.wrapping_add(immediate as u16 as i16 as u32))
+                }
+                // ori rD, rD, value@l
+                24 if a == d => high[d as usize].map(|h| h | immediate),
+                // bl function
+                18 if instruction & 3 == 1 => {
+                    let offset = ((instruction & 0x03FF_FFFC) << 6) as i32 >> 6;
+                    if function == dol.entry_point {
+                        functions.push(address.wrapping_add(offset as u32));
+                    }
+                    None
+                }
+                _ if instruction == BLR => break,
+                _ => None,
+            };
+            if let Some(value) = value {
+                if let Some(i) = RUNTIME_REGISTERS.iter().position(|&r| r == d) {
+                    values[i].get_or_insert(value);
+                }
+            }
+        }
+    }
+
+    match values {
+        [Some(r1), Some(r2), Some(r13)] => Ok([r1, r2, r13]),
+        _ => bail!(
+            "Couldn't find where the game sets up its stack and its small data areas, \
+             which the init functions rely on"
+        ),
+    }
+}
+
+/// Builds the stub that calls the init functions in order before it jumps to
+/// the game's original entry point. The stub needs to become the new entry
+/// point of the DOL. The stub sets up the stack and the small data areas the
+/// same way the game does, so the functions can be called like any other.
+/// The rest of the game's runtime isn't set up yet, so they should only
+/// prepare the state of the Rom Hack, like the state that its hooks rely on.
+/// Anything they store in the game's bss is cleared once the game starts.
+pub fn lower(functions: &[u32], dol: &DolFile, stub_address: u32) -> Result<Section, Error> {
+    let mut stub = Vec::with_capacity(stub_len(functions) as usize / 4);
+    let values = runtime_registers(dol)?;
+    for (&register, &value) in RUNTIME_REGISTERS.iter().zip(&values) {
+        // lis rD, value@h
+        stub.push((15 << 26) | (register << 21) | (value >> 16));
+        // ori rD, rD, value@l
+        stub.push((24 << 26) | (register << 21) | (register << 16) | (value & 0xFFFF));
+    }
+    // The callers' frame needs to be on the stack, as the functions store
+    // their link register in it
+    stub.push(STWU_R1_MINUS_8);
+    for &function in functions {
+        let call_address = stub_address + 4 * stub.len() as u32;
+        ensure!(
+            is_branch_in_range(call_address, function),
+            "The init function {:08X} is out of range of the stub calling it",
+            function
+        );
+        stub.push(build_branch_instruction(
+            call_address,
+            function,
+            false,
+            true,
+        ));
+    }
+    stub.extend_from_slice(&jump(dol.entry_point));
+    Ok(to_section(stub_address, &stub))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dol(entry: &[u32], init_registers: &[u32]) -> DolFile {
+        let mut code = entry.to_vec();
+        code.resize(16, 0x6000_0000);
+        code.extend_from_slice(init_registers);
+        DolFile {
+            text_sections: vec![to_section(0x8000_3100, &code)],
+            data_sections: Vec::new(),
+            bss_address: 0,
+            bss_size: 0,
+            entry_point: 0x8000_3100,
+            free_regions: Vec::new(),
+            reservations: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn finds_the_runtime_registers() {
+        let dol = dol(
+            &[
+                // bl __init_registers
+                0x4800_0041,
+            ],
+            &[
+                // lis r1, 0x8043
+                0x3C20_8043,
+                // ori r1, r1, 0x1234
+                0x6021_1234,
+                // lis r2, 0x8042
+                0x3C40_8042,
+                // addi r2, r2, -0x8000
+                0x3842_8000,
+                // lis r13, 0x8042
+                0x3DA0_8042,
+                // ori r13, r13, 0x5678
+                0x61AD_5678,
+                BLR,
+            ],
+        );
+        assert_eq!(
+            runtime_registers(&dol).unwrap(),
+            [0x8043_1234, 0x8041_8000, 0x8042_5678]
+        );
+
+        let stub = lower(&[0x8000_3200], &dol, 0x8000_2000).unwrap();
+        assert_eq!(stub.data.len() as u32, stub_len(&[0x8000_3200]));
+    }
+
+    #[test]
+    fn syncs_the_caches_of_the_range() {
+        let mut stub = Vec::new();
+        sync_caches(&mut stub, 0x8000_2000, 0x8000_3104..0x8000_3200);
+        assert_eq!(4 * stub.len() as u32, SYNC_CACHES_LEN);
+        assert_eq!(stub[..2], [0x3C60_8000, 0x6063_3100]);
+        // The loop branches back to the comparison
+        assert_eq!(stub[10], 0x4BFF_FFE8);
+    }
+
+    #[test]
+    fn requires_the_runtime_registers() {
+        let dol = dol(&[0x4800_0041], &[0x3C20_8043, 0x6021_1234, BLR]);
+        assert!(lower(&[0x8000_3200], &dol, 0x8000_2000).is_err());
+    }
+}
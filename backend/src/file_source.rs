@@ -25,6 +25,18 @@ impl FileSource for FileSystem {
     }
 }
 
+impl<'a, F: FileSource> FileSource for &'a mut F {
+    fn read_to_vec<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<u8>, Error> {
+        (**self).read_to_vec(path)
+    }
+    fn read_to_string<P: AsRef<Path>>(&mut self, path: P) -> Result<String, Error> {
+        (**self).read_to_string(path)
+    }
+    fn open_image<P: AsRef<Path>>(&mut self, path: P) -> Result<DynamicImage, Error> {
+        (**self).open_image(path)
+    }
+}
+
 impl<R: Read + Seek> FileSource for ZipArchive<R> {
     fn read_to_vec<P: AsRef<Path>>(&mut self, path: P) -> Result<Vec<u8>, Error> {
         let mut file = self.by_name(
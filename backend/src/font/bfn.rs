@@ -0,0 +1,175 @@
+//! Based on http://wiki.tockdom.com/wiki/BFN_(File_Format)
+//!
+//! BFN files are the fonts of GameCube games. The glyphs are stored on the
+//! texture sheets of GLY1 blocks, their widths in WID1 blocks and the mapping
+//! from the character codes to the glyphs in MAP1 blocks. The blocks don't
+//! refer to each other, the game collects them in order instead and uses the
+//! first MAP1 block whose range covers a character. So the MAP1 blocks of
+//! the new glyphs are placed ahead of the original ones.
+
+use super::{Encoding, Glyph, Sheets};
+use byteorder::{ByteOrder, BE};
+use failure::Error;
+use texture::Format;
+
+const MAGIC: &[u8] = b"FONTbfn1";
+const HEADER_LEN: usize = 0x20;
+const BLOCK_HEADER_LEN: usize = 0x08;
+const ALIGNMENT: usize = 0x20;
+
+const OFFSET_FILE_SIZE: usize = 0x08;
+const OFFSET_NUM_BLOCKS: usize = 0x0C;
+
+// Relative to the start of each block
+const OFFSET_BLOCK_SIZE: usize = 0x04;
+
+const INF1_LEN: usize = 0x0A;
+const OFFSET_FONT_TYPE: usize = 0x08;
+
+const GLY1_HEADER_LEN: usize = 0x20;
+const OFFSET_FIRST_GLYPH: usize = 0x08;
+const OFFSET_LAST_GLYPH: usize = 0x0A;
+const OFFSET_CELL_WIDTH: usize = 0x0C;
+const OFFSET_CELL_HEIGHT: usize = 0x0E;
+const OFFSET_SHEET_SIZE: usize = 0x10;
+const OFFSET_SHEET_FORMAT: usize = 0x14;
+const OFFSET_CELLS_PER_ROW: usize = 0x16;
+const OFFSET_CELLS_PER_COLUMN: usize = 0x18;
+const OFFSET_SHEET_WIDTH: usize = 0x1A;
+const OFFSET_SHEET_HEIGHT: usize = 0x1C;
+
+const WID1_HEADER_LEN: usize = 0x0C;
+
+const MAP1_HEADER_LEN: usize = 0x10;
+const MAP_TABLE: u16 = 2;
+
+fn align(offset: usize) -> usize {
+    (offset + ALIGNMENT - 1) & !(ALIGNMENT - 1)
+}
+
+fn new_block(magic: &[u8], len: usize) -> Vec<u8> {
+    let mut block = vec![0; align(len)];
+    block[..4].copy_from_slice(magic);
+    let size = block.len() as u32;
+    BE::write_u32(&mut block[OFFSET_BLOCK_SIZE..], size);
+    block
+}
+
+pub fn is_bfn(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+fn parse_blocks(data: &[u8]) -> Result<Vec<Vec<u8>>, Error> {
+    ensure!(
+        data.len() >= HEADER_LEN && data.starts_with(MAGIC),
+        "The file is not a BFN file"
+    );
+    let mut blocks = Vec::new();
+    let mut offset = HEADER_LEN;
+    for _ in 0..BE::read_u32(&data[OFFSET_NUM_BLOCKS..]) {
+        ensure!(
+            offset + BLOCK_HEADER_LEN <= data.len(),
+            "The blocks of the BFN file are out of bounds"
+        );
+        let len = BE::read_u32(&data[offset + OFFSET_BLOCK_SIZE..]) as usize;
+        ensure!(
+            len >= BLOCK_HEADER_LEN && offset + len <= data.len(),
+            "The blocks of the BFN file are out of bounds"
+        );
+        blocks.push(data[offset..offset + len].to_owned());
+        offset += len;
+    }
+    Ok(blocks)
+}
+
+pub fn encoding(data: &[u8]) -> Result<Encoding, Error> {
+    let blocks = parse_blocks(data)?;
+    let info = blocks
+        .iter()
+        .find(|b| b.starts_with(b"INF1") && b.len() >= INF1_LEN)
+        .ok_or_else(|| format_err!("The BFN file has no INF1 block"))?;
+    Ok(match BE::read_u16(&info[OFFSET_FONT_TYPE..]) {
+        0 => Encoding::Windows1252,
+        1 | 2 => Encoding::ShiftJis,
+        font_type => bail!("The font is of the unknown type {}", font_type),
+    })
+}
+
+/// Adds the glyphs in a new GLY1 block, along with a WID1 block for their
+/// widths and a MAP1 block for each character.
+pub fn add_glyphs(data: &[u8], glyphs: &[Glyph]) -> Result<Vec<u8>, Error> {
+    let mut blocks = parse_blocks(data)?;
+
+    let mut sheets_header = None;
+    let mut first_index = 0;
+    for block in &blocks {
+        if block.starts_with(b"GLY1") {
+            ensure!(
+                block.len() >= GLY1_HEADER_LEN,
+                "The GLY1 block is too small"
+            );
+            first_index = first_index.max(BE::read_u16(&block[OFFSET_LAST_GLYPH..]) as usize + 1);
+            sheets_header.get_or_insert_with(|| block[..GLY1_HEADER_LEN].to_owned());
+        }
+    }
+    let mut gly1 = sheets_header.ok_or_else(|| format_err!("The BFN file has no GLY1 block"))?;
+
+    let sheets = Sheets {
+        format: Format::from_id(u32::from(BE::read_u16(&gly1[OFFSET_SHEET_FORMAT..])))?,
+        width: BE::read_u16(&gly1[OFFSET_SHEET_WIDTH..]) as usize,
+        height: BE::read_u16(&gly1[OFFSET_SHEET_HEIGHT..]) as usize,
+        cell_width: BE::read_u16(&gly1[OFFSET_CELL_WIDTH..]) as usize,
+        cell_height: BE::read_u16(&gly1[OFFSET_CELL_HEIGHT..]) as usize,
+        cells_per_row: BE::read_u16(&gly1[OFFSET_CELLS_PER_ROW..]) as usize,
+        cells_per_column: BE::read_u16(&gly1[OFFSET_CELLS_PER_COLUMN..]) as usize,
+        spacing: 0,
+    };
+    ensure!(
+        BE::read_u32(&gly1[OFFSET_SHEET_SIZE..]) as usize == sheets.len(),
+        "The size of the sheets doesn't match their dimensions"
+    );
+    let last_index = first_index + glyphs.len() - 1;
+    ensure!(last_index <= 0xFFFF, "The font can't hold any more glyphs");
+
+    gly1.extend(sheets.encode(glyphs)?);
+    let len = align(gly1.len());
+    gly1.resize(len, 0);
+    BE::write_u32(&mut gly1[OFFSET_BLOCK_SIZE..], len as u32);
+    BE::write_u16(&mut gly1[OFFSET_FIRST_GLYPH..], first_index as u16);
+    BE::write_u16(&mut gly1[OFFSET_LAST_GLYPH..], last_index as u16);
+
+    let mut wid1 = new_block(b"WID1", WID1_HEADER_LEN + 2 * glyphs.len());
+    BE::write_u16(&mut wid1[0x08..], first_index as u16);
+    BE::write_u16(&mut wid1[0x0A..], last_index as u16);
+    for (widths, glyph) in wid1[WID1_HEADER_LEN..].chunks_mut(2).zip(glyphs) {
+        widths.copy_from_slice(&[0, glyph.image.width() as u8]);
+    }
+
+    let maps = glyphs.iter().enumerate().map(|(index, glyph)| {
+        let mut map1 = new_block(b"MAP1", MAP1_HEADER_LEN + 2);
+        BE::write_u16(&mut map1[0x08..], MAP_TABLE);
+        BE::write_u16(&mut map1[0x0A..], glyph.code);
+        BE::write_u16(&mut map1[0x0C..], glyph.code);
+        BE::write_u16(&mut map1[0x0E..], 1);
+        BE::write_u16(&mut map1[MAP1_HEADER_LEN..], (first_index + index) as u16);
+        map1
+    });
+    let first_map = blocks
+        .iter()
+        .position(|b| b.starts_with(b"MAP1"))
+        .unwrap_or_else(|| blocks.len());
+    let rest = blocks.split_off(first_map);
+    blocks.extend(maps);
+    blocks.extend(rest);
+    blocks.push(gly1);
+    blocks.push(wid1);
+
+    let mut out = data[..HEADER_LEN].to_owned();
+    BE::write_u32(&mut out[OFFSET_NUM_BLOCKS..], blocks.len() as u32);
+    for block in &blocks {
+        out.extend_from_slice(block);
+    }
+    let len = out.len() as u32;
+    BE::write_u32(&mut out[OFFSET_FILE_SIZE..], len);
+    Ok(out)
+}
@@ -0,0 +1,368 @@
+//! Based on http://wiki.tockdom.com/wiki/BRFNT_(File_Format)
+//!
+//! BRFNT files are the fonts of Wii games. The glyphs are stored on the
+//! texture sheets of the TGLP section, their widths in CWDH sections and the
+//! mapping from the character codes to the glyphs in CMAP sections. The CWDH
+//! and CMAP sections form linked lists that are searched in order, so the
+//! new sections are added at their heads. The sections point to each other
+//! by the absolute offsets of the data following their headers.
+
+use super::{Encoding, Glyph, Sheets};
+use byteorder::{ByteOrder, BE};
+use failure::Error;
+use std::collections::HashMap;
+use texture::Format;
+
+const MAGIC: &[u8] = b"RFNT";
+const HEADER_LEN: usize = 0x10;
+const SECTION_HEADER_LEN: usize = 0x08;
+
+const OFFSET_FILE_SIZE: usize = 0x08;
+const OFFSET_HEADER_LEN: usize = 0x0C;
+const OFFSET_NUM_SECTIONS: usize = 0x0E;
+
+// Relative to the start of each section
+const OFFSET_SECTION_SIZE: usize = 0x04;
+
+const FINF_LEN: usize = 0x1C;
+const OFFSET_ENCODING: usize = 0x0F;
+const OFFSET_TGLP: usize = 0x10;
+const OFFSET_CWDH: usize = 0x14;
+const OFFSET_CMAP: usize = 0x18;
+
+const TGLP_HEADER_LEN: usize = 0x20;
+const OFFSET_CELL_WIDTH: usize = 0x08;
+const OFFSET_CELL_HEIGHT: usize = 0x09;
+const OFFSET_SHEET_SIZE: usize = 0x0C;
+const OFFSET_NUM_SHEETS: usize = 0x10;
+const OFFSET_SHEET_FORMAT: usize = 0x12;
+const OFFSET_CELLS_PER_ROW: usize = 0x14;
+const OFFSET_CELLS_PER_COLUMN: usize = 0x16;
+const OFFSET_SHEET_WIDTH: usize = 0x18;
+const OFFSET_SHEET_HEIGHT: usize = 0x1A;
+const OFFSET_SHEET_DATA: usize = 0x1C;
+
+const CWDH_HEADER_LEN: usize = 0x10;
+const OFFSET_CWDH_NEXT: usize = 0x0C;
+
+const CMAP_DIRECT_LEN: usize = 0x18;
+const OFFSET_CMAP_NEXT: usize = 0x10;
+const CMAP_DIRECT: u16 = 0;
+
+/// The sheet format's flag for compressed sheets.
+const COMPRESSED: u16 = 0x8000;
+
+struct Section {
+    /// The offset of the section in the original file. New sections get the
+    /// offsets they would have if they were appended to the original file.
+    offset: usize,
+    data: Vec<u8>,
+}
+
+impl Section {
+    fn new(magic: &[u8], offset: usize, len: usize) -> Self {
+        let mut data = vec![0; (len + 3) & !3];
+        data[..4].copy_from_slice(magic);
+        let size = data.len() as u32;
+        BE::write_u32(&mut data[OFFSET_SECTION_SIZE..], size);
+        Section { offset, data }
+    }
+
+    fn magic(&self) -> &[u8] {
+        &self.data[..4]
+    }
+
+    /// The pointer to the section that the other sections use.
+    fn pointer(&self) -> u32 {
+        (self.offset + SECTION_HEADER_LEN) as u32
+    }
+}
+
+pub fn is_brfnt(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+fn parse_sections(data: &[u8]) -> Result<(usize, Vec<Section>), Error> {
+    ensure!(
+        data.len() >= HEADER_LEN && data.starts_with(MAGIC),
+        "The file is not a BRFNT file"
+    );
+    let header_len = BE::read_u16(&data[OFFSET_HEADER_LEN..]) as usize;
+    ensure!(
+        header_len >= HEADER_LEN && header_len <= data.len(),
+        "The header of the BRFNT file is invalid"
+    );
+
+    let mut sections = Vec::new();
+    let mut offset = header_len;
+    for _ in 0..BE::read_u16(&data[OFFSET_NUM_SECTIONS..]) {
+        ensure!(
+            offset + SECTION_HEADER_LEN <= data.len(),
+            "The sections of the BRFNT file are out of bounds"
+        );
+        let len = BE::read_u32(&data[offset + OFFSET_SECTION_SIZE..]) as usize;
+        ensure!(
+            len >= SECTION_HEADER_LEN && offset + len <= data.len(),
+            "The sections of the BRFNT file are out of bounds"
+        );
+        sections.push(Section {
+            offset,
+            data: data[offset..offset + len].to_owned(),
+        });
+        offset += len;
+    }
+
+    Ok((header_len, sections))
+}
+
+fn find_section(sections: &[Section], magic: &str, min_len: usize) -> Result<usize, Error> {
+    let index = sections
+        .iter()
+        .position(|s| s.magic() == magic.as_bytes())
+        .ok_or_else(|| format_err!("The BRFNT file has no {} section", magic))?;
+    ensure!(
+        sections[index].data.len() >= min_len,
+        "The {} section is too small",
+        magic
+    );
+    Ok(index)
+}
+
+pub fn encoding(data: &[u8]) -> Result<Encoding, Error> {
+    let (_, sections) = parse_sections(data)?;
+    let finf = &sections[find_section(&sections, "FINF", FINF_LEN)?].data;
+    Ok(match finf[OFFSET_ENCODING] {
+        0 | 1 => Encoding::Unicode,
+        2 => Encoding::ShiftJis,
+        3 => Encoding::Windows1252,
+        encoding => bail!("The font uses the unknown encoding {}", encoding),
+    })
+}
+
+/// Adds the glyphs on new sheets at the end of the TGLP section, along with
+/// a CWDH section for their widths and a CMAP section for each character.
+pub fn add_glyphs(data: &[u8], glyphs: &[Glyph]) -> Result<Vec<u8>, Error> {
+    let (header_len, mut sections) = parse_sections(data)?;
+    let finf = find_section(&sections, "FINF", FINF_LEN)?;
+    let tglp = find_section(&sections, "TGLP", TGLP_HEADER_LEN)?;
+
+    let (sheets, num_sheets, sheets_end) = {
+        let section = &sections[tglp];
+        let header = &section.data;
+        let format = BE::read_u16(&header[OFFSET_SHEET_FORMAT..]);
+        ensure!(
+            format & COMPRESSED == 0,
+            "Fonts with compressed sheets are not supported"
+        );
+        let sheets = Sheets {
+            format: Format::from_id(u32::from(format))?,
+            width: BE::read_u16(&header[OFFSET_SHEET_WIDTH..]) as usize,
+            height: BE::read_u16(&header[OFFSET_SHEET_HEIGHT..]) as usize,
+            cell_width: header[OFFSET_CELL_WIDTH] as usize,
+            cell_height: header[OFFSET_CELL_HEIGHT] as usize,
+            cells_per_row: BE::read_u16(&header[OFFSET_CELLS_PER_ROW..]) as usize,
+            cells_per_column: BE::read_u16(&header[OFFSET_CELLS_PER_COLUMN..]) as usize,
+            // The cells are separated by a line of pixels
+            spacing: 1,
+        };
+        ensure!(
+            BE::read_u32(&header[OFFSET_SHEET_SIZE..]) as usize == sheets.len(),
+            "The size of the sheets doesn't match their dimensions"
+        );
+        let num_sheets = BE::read_u16(&header[OFFSET_NUM_SHEETS..]) as usize;
+        let sheet_data = BE::read_u32(&header[OFFSET_SHEET_DATA..]) as usize;
+        let sheets_end = (sheet_data + num_sheets * sheets.len())
+            .checked_sub(section.offset)
+            .filter(|&end| sheet_data >= section.offset && end <= header.len())
+            .ok_or_else(|| format_err!("The sheets of the BRFNT file are out of bounds"))?;
+        (sheets, num_sheets, sheets_end)
+    };
+
+    // The new glyphs start on a new sheet
+    let first_index = num_sheets * sheets.cells();
+    ensure!(
+        first_index + glyphs.len() <= 0xFFFF,
+        "The font can't hold any more glyphs"
+    );
+    let new_sheets = sheets.encode(glyphs)?;
+    let num_sheets = num_sheets + new_sheets.len() / sheets.len();
+    ensure!(num_sheets <= 0xFFFF, "The font can't hold any more sheets");
+    {
+        let tglp = &mut sections[tglp].data;
+        let rest = tglp.split_off(sheets_end);
+        tglp.extend(new_sheets);
+        tglp.extend(rest);
+        BE::write_u16(&mut tglp[OFFSET_NUM_SHEETS..], num_sheets as u16);
+        let len = tglp.len() as u32;
+        BE::write_u32(&mut tglp[OFFSET_SECTION_SIZE..], len);
+    }
+
+    let mut offset = data.len();
+    let mut cwdh = Section::new(b"CWDH", offset, CWDH_HEADER_LEN + 3 * glyphs.len());
+    BE::write_u16(&mut cwdh.data[0x08..], first_index as u16);
+    BE::write_u16(
+        &mut cwdh.data[0x0A..],
+        (first_index + glyphs.len() - 1) as u16,
+    );
+    let head = BE::read_u32(&sections[finf].data[OFFSET_CWDH..]);
+    BE::write_u32(&mut cwdh.data[OFFSET_CWDH_NEXT..], head);
+    for (widths, glyph) in cwdh.data[CWDH_HEADER_LEN..].chunks_mut(3).zip(glyphs) {
+        let width = glyph.image.width() as u8;
+        widths.copy_from_slice(&[0, width, width]);
+    }
+    offset += cwdh.data.len();
+    BE::write_u32(&mut sections[finf].data[OFFSET_CWDH..], cwdh.pointer());
+    sections.push(cwdh);
+
+    let mut next = BE::read_u32(&sections[finf].data[OFFSET_CMAP..]);
+    let mut cmaps = Vec::with_capacity(glyphs.len());
+    for (index, glyph) in glyphs.iter().enumerate().rev() {
+        let cmap_offset = offset + index * CMAP_DIRECT_LEN;
+        let mut cmap = Section::new(b"CMAP", cmap_offset, CMAP_DIRECT_LEN);
+        BE::write_u16(&mut cmap.data[0x08..], glyph.code);
+        BE::write_u16(&mut cmap.data[0x0A..], glyph.code);
+        BE::write_u16(&mut cmap.data[0x0C..], CMAP_DIRECT);
+        BE::write_u32(&mut cmap.data[OFFSET_CMAP_NEXT..], next);
+        BE::write_u16(&mut cmap.data[0x14..], (first_index + index) as u16);
+        next = cmap.pointer();
+        cmaps.push(cmap);
+    }
+    BE::write_u32(&mut sections[finf].data[OFFSET_CMAP..], next);
+    sections.extend(cmaps.into_iter().rev());
+
+    relocate(&mut sections, header_len, tglp)?;
+
+    let mut out = data[..header_len].to_owned();
+    BE::write_u16(&mut out[OFFSET_NUM_SECTIONS..], sections.len() as u16);
+    for section in &sections {
+        out.extend_from_slice(&section.data);
+    }
+    let len = out.len() as u32;
+    BE::write_u32(&mut out[OFFSET_FILE_SIZE..], len);
+    Ok(out)
+}
+
+/// Moves the sections to where they end up in the new file and updates the
+/// pointers between them.
+fn relocate(sections: &mut [Section], header_len: usize, tglp: usize) -> Result<(), Error> {
+    let mut new_pointers = HashMap::new();
+    let mut offset = header_len;
+    for section in sections.iter() {
+        new_pointers.insert(section.pointer(), (offset + SECTION_HEADER_LEN) as u32);
+        offset += section.data.len();
+    }
+    let relocate_pointer = |data: &mut [u8], field: usize| -> Result<(), Error> {
+        let pointer = BE::read_u32(&data[field..]);
+        if pointer != 0 {
+            let new_pointer = *new_pointers.get(&pointer).ok_or_else(|| {
+                format_err!("The pointer {:#x} doesn't point to a section", pointer)
+            })?;
+            BE::write_u32(&mut data[field..], new_pointer);
+        }
+        Ok(())
+    };
+
+    let old_tglp = sections[tglp].offset;
+    let new_tglp = new_pointers[&sections[tglp].pointer()] as usize - SECTION_HEADER_LEN;
+    for section in sections.iter_mut() {
+        let mut magic = [0; 4];
+        magic.copy_from_slice(section.magic());
+        match &magic {
+            b"FINF" => {
+                for &field in &[OFFSET_TGLP, OFFSET_CWDH, OFFSET_CMAP] {
+                    relocate_pointer(&mut section.data, field)?;
+                }
+            }
+            b"CWDH" if section.data.len() >= CWDH_HEADER_LEN => {
+                relocate_pointer(&mut section.data, OFFSET_CWDH_NEXT)?
+            }
+            b"CMAP" if section.data.len() >= OFFSET_CMAP_NEXT + 4 => {
+                relocate_pointer(&mut section.data, OFFSET_CMAP_NEXT)?
+            }
+            b"TGLP" => {
+                let sheet_data = BE::read_u32(&section.data[OFFSET_SHEET_DATA..]) as usize;
+                let sheet_data = sheet_data - old_tglp + new_tglp;
+                BE::write_u32(&mut section.data[OFFSET_SHEET_DATA..], sheet_data as u32);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbaImage;
+
+    /// A font with a single I4 sheet of 8x8 pixels that has a single cell,
+    /// without any CWDH or CMAP sections.
+    fn font() -> Vec<u8> {
+        let mut data = vec![0; 0x70];
+        data[..4].copy_from_slice(MAGIC);
+        BE::write_u32(&mut data[OFFSET_FILE_SIZE..], 0x70);
+        BE::write_u16(&mut data[OFFSET_HEADER_LEN..], HEADER_LEN as u16);
+        BE::write_u16(&mut data[OFFSET_NUM_SECTIONS..], 2);
+
+        let finf = &mut data[0x10..0x30];
+        finf[..4].copy_from_slice(b"FINF");
+        BE::write_u32(&mut finf[OFFSET_SECTION_SIZE..], 0x20);
+        finf[OFFSET_ENCODING] = 1;
+        BE::write_u32(&mut finf[OFFSET_TGLP..], 0x38);
+
+        let tglp = &mut data[0x30..];
+        tglp[..4].copy_from_slice(b"TGLP");
+        BE::write_u32(&mut tglp[OFFSET_SECTION_SIZE..], 0x40);
+        tglp[OFFSET_CELL_WIDTH] = 7;
+        tglp[OFFSET_CELL_HEIGHT] = 7;
+        BE::write_u32(&mut tglp[OFFSET_SHEET_SIZE..], 0x20);
+        BE::write_u16(&mut tglp[OFFSET_NUM_SHEETS..], 1);
+        BE::write_u16(&mut tglp[OFFSET_CELLS_PER_ROW..], 1);
+        BE::write_u16(&mut tglp[OFFSET_CELLS_PER_COLUMN..], 1);
+        BE::write_u16(&mut tglp[OFFSET_SHEET_WIDTH..], 8);
+        BE::write_u16(&mut tglp[OFFSET_SHEET_HEIGHT..], 8);
+        BE::write_u32(&mut tglp[OFFSET_SHEET_DATA..], 0x50);
+        data
+    }
+
+    #[test]
+    fn reads_the_encoding() {
+        assert_eq!(encoding(&font()).unwrap(), Encoding::Unicode);
+        assert!(encoding(b"RFNT").is_err());
+    }
+
+    #[test]
+    fn adds_glyphs_on_a_new_sheet() {
+        let glyphs = [Glyph {
+            code: 0x41,
+            image: RgbaImage::new(4, 6),
+        }];
+        let data = add_glyphs(&font(), &glyphs).unwrap();
+        assert_eq!(data.len(), 0xBC);
+        assert_eq!(BE::read_u32(&data[OFFSET_FILE_SIZE..]), 0xBC);
+        assert_eq!(BE::read_u16(&data[OFFSET_NUM_SECTIONS..]), 4);
+
+        // The sections following the grown TGLP section are moved
+        let finf = &data[0x10..];
+        assert_eq!(BE::read_u32(&finf[OFFSET_TGLP..]), 0x38);
+        assert_eq!(BE::read_u32(&finf[OFFSET_CWDH..]), 0x98);
+        assert_eq!(BE::read_u32(&finf[OFFSET_CMAP..]), 0xAC);
+
+        let tglp = &data[0x30..];
+        assert_eq!(BE::read_u32(&tglp[OFFSET_SECTION_SIZE..]), 0x60);
+        assert_eq!(BE::read_u16(&tglp[OFFSET_NUM_SHEETS..]), 2);
+        assert_eq!(BE::read_u32(&tglp[OFFSET_SHEET_DATA..]), 0x50);
+
+        let cwdh = &data[0x90..0xA4];
+        assert_eq!(&cwdh[..4], b"CWDH");
+        assert_eq!(&cwdh[0x08..0x0C], &[0, 1, 0, 1]);
+        assert_eq!(BE::read_u32(&cwdh[OFFSET_CWDH_NEXT..]), 0);
+        assert_eq!(&cwdh[CWDH_HEADER_LEN..][..3], &[0, 4, 4]);
+
+        let cmap = &data[0xA4..];
+        assert_eq!(&cmap[..4], b"CMAP");
+        assert_eq!(&cmap[0x08..0x0E], &[0, 0x41, 0, 0x41, 0, 0]);
+        assert_eq!(BE::read_u32(&cmap[OFFSET_CMAP_NEXT..]), 0);
+        assert_eq!(BE::read_u16(&cmap[0x14..]), 1);
+    }
+}
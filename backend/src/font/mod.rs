@@ -0,0 +1,133 @@
+//! Adds glyphs to the fonts of the games, so translations can use characters
+//! like accented letters that the original fonts lack. The glyphs are placed
+//! on new texture sheets after the original ones, in the format of the
+//! original sheets, and their characters are mapped to them ahead of the
+//! original mappings. So characters that already have a glyph use the new
+//! one instead.
+
+use encoding_rs::{SHIFT_JIS, WINDOWS_1252};
+use failure::Error;
+use image::{imageops, RgbaImage};
+use texture::{self, Format, PaletteFormat};
+
+pub mod bfn;
+pub mod brfnt;
+
+/// A glyph to add and the code of its character in the font's encoding.
+pub struct Glyph {
+    pub code: u16,
+    pub image: RgbaImage,
+}
+
+/// The encoding of the character codes of a font.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Encoding {
+    Unicode,
+    ShiftJis,
+    Windows1252,
+}
+
+impl Encoding {
+    /// Encodes the text, which is usually a single character.
+    pub fn encode(self, text: &str) -> Result<Vec<u8>, Error> {
+        let encoding = match self {
+            Encoding::Unicode => {
+                let mut bytes = Vec::new();
+                for unit in text.encode_utf16() {
+                    bytes.push((unit >> 8) as u8);
+                    bytes.push(unit as u8);
+                }
+                return Ok(bytes);
+            }
+            Encoding::ShiftJis => SHIFT_JIS,
+            Encoding::Windows1252 => WINDOWS_1252,
+        };
+        let (bytes, _, had_errors) = encoding.encode(text);
+        ensure!(
+            !had_errors,
+            "\"{}\" can't be encoded as {}",
+            text,
+            encoding.name()
+        );
+        Ok(bytes.into_owned())
+    }
+}
+
+/// The layout of the sheets of a font, which all sheets share. The glyphs
+/// are placed in cells, row by row, which may be spaced apart.
+struct Sheets {
+    format: Format,
+    width: usize,
+    height: usize,
+    cell_width: usize,
+    cell_height: usize,
+    cells_per_row: usize,
+    cells_per_column: usize,
+    spacing: usize,
+}
+
+impl Sheets {
+    fn cells(&self) -> usize {
+        self.cells_per_row * self.cells_per_column
+    }
+
+    fn len(&self) -> usize {
+        self.format.data_len(self.width, self.height, 1)
+    }
+
+    /// Places the glyphs on as many sheets as they need and encodes them.
+    fn encode(&self, glyphs: &[Glyph]) -> Result<Vec<u8>, Error> {
+        ensure!(
+            !self.format.uses_palette(),
+            "Sheets with palettes are not supported"
+        );
+        ensure!(self.cells() > 0, "The sheets of the font have no cells");
+
+        let mut data = Vec::new();
+        for sheet_glyphs in glyphs.chunks(self.cells()) {
+            let mut sheet = RgbaImage::new(self.width as u32, self.height as u32);
+            for (index, glyph) in sheet_glyphs.iter().enumerate() {
+                ensure!(
+                    glyph.image.width() as usize <= self.cell_width
+                        && glyph.image.height() as usize <= self.cell_height,
+                    "The glyph of the character {:#X} is larger than the font's cells of {}x{} \
+                     pixels",
+                    glyph.code,
+                    self.cell_width,
+                    self.cell_height
+                );
+                let x = (index % self.cells_per_row) * (self.cell_width + self.spacing);
+                let y = (index / self.cells_per_row) * (self.cell_height + self.spacing);
+                imageops::replace(&mut sheet, &glyph.image, x as u32, y as u32);
+            }
+            let (sheet, _) = texture::encode(&sheet, self.format, PaletteFormat::Rgb5a3, 1)?;
+            data.extend_from_slice(&sheet);
+        }
+        Ok(data)
+    }
+}
+
+/// The encoding of the font's character codes.
+pub fn encoding(data: &[u8]) -> Result<Encoding, Error> {
+    if brfnt::is_brfnt(data) {
+        brfnt::encoding(data)
+    } else if bfn::is_bfn(data) {
+        bfn::encoding(data)
+    } else {
+        bail!("The file is neither a BRFNT nor a BFN font")
+    }
+}
+
+/// Adds the glyphs to the font, which is either a BRFNT or a BFN file.
+pub fn add_glyphs(data: &[u8], glyphs: &[Glyph]) -> Result<Vec<u8>, Error> {
+    if glyphs.is_empty() {
+        return Ok(data.to_owned());
+    }
+    if brfnt::is_brfnt(data) {
+        brfnt::add_glyphs(data, glyphs)
+    } else if bfn::is_bfn(data) {
+        bfn::add_glyphs(data, glyphs)
+    } else {
+        bail!("The file is neither a BRFNT nor a BFN font")
+    }
+}
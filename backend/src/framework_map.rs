@@ -4,15 +4,40 @@ use failure::{Error, ResultExt};
 use linker::{LinkedSection, SectionKind};
 use regex::{Captures, Regex};
 use rustc_demangle::demangle as demangle_rust;
-use std::collections::HashMap;
+use std::borrow::Cow;
 use std::fs::File;
 use std::io::{prelude::*, BufWriter};
 use std::str;
 
+/// Code that was added to the game without going through the linker, like
+/// the labels of the patch file or the trampolines of the hooks.
+pub struct InjectedSymbol {
+    pub address: u32,
+    pub len: u32,
+    pub name: String,
+}
+
+/// The name of the function or variable in a linked section. Rust functions
+/// are demangled without their hash.
+pub fn symbol_name<'a>(section: &LinkedSection<'a>) -> Cow<'a, str> {
+    let section_name = section.section_name;
+    if section_name.starts_with(".text.") && section.kind == SectionKind::TextSection {
+        let mut section_name = demangle_rust(&section_name[".text.".len()..]).to_string();
+        if section_name.len() >= 19 && &section_name[section_name.len() - 19..][..3] == "::h" {
+            let len = section_name.len() - 19;
+            section_name.truncate(len);
+        }
+        Cow::Owned(section_name)
+    } else {
+        Cow::Borrowed(section_name)
+    }
+}
+
 pub fn create(
     config: &Config,
     original: Option<&[u8]>,
     sections: &[LinkedSection],
+    injected: &[InjectedSymbol],
 ) -> Result<(), Error> {
     let path = match &config.build.map {
         Some(path) => path,
@@ -24,21 +49,7 @@ pub fn create(
     writeln!(file, ".text section layout")?;
 
     for section in sections {
-        let mut section_name_buf;
-        let section_name = section.section_name;
-        let section_name = if section_name.starts_with(".text.")
-            && section.kind == SectionKind::TextSection
-        {
-            section_name_buf = demangle_rust(&section_name[".text.".len()..]).to_string();
-            let mut section_name: &str = &section_name_buf;
-            if section_name.len() >= 19 && &section_name[section_name.len() - 19..][..3] == "::h" {
-                section_name = &section_name[..section_name.len() - 19];
-            }
-            section_name
-        } else {
-            section_name
-        };
-
+        let section_name = symbol_name(section);
         writeln!(
             file,
             "  00000000 {:06x} {:08x}  4 {} \t{}",
@@ -49,6 +60,14 @@ pub fn create(
         )?;
     }
 
+    for symbol in injected {
+        writeln!(
+            file,
+            "  00000000 {:06x} {:08x}  4 {} \tromhack",
+            symbol.len, symbol.address, symbol.name
+        )?;
+    }
+
     if let Some(original) = original {
         let regex = Regex::new(r"(\s{2}\d\s)(.*)(\s{2}.*)").unwrap();
 
@@ -67,26 +86,3 @@ pub fn create(
 
     Ok(())
 }
-
-pub fn parse(buf: &[u8]) -> Result<HashMap<String, u32>, Error> {
-    let mut symbols = HashMap::new();
-    let regex = Regex::new(r"\s{2}\w{8}\s\w{6}\s(\w{8}).{4}(.*)\s{2}").unwrap();
-    let text = str::from_utf8(buf).context("The symbol map has invalid UTF-8")?;
-    for line in text.lines() {
-        if let Some(captures) = regex.captures(line) {
-            let name = captures.get(2).unwrap().as_str();
-            if !name.starts_with('.') {
-                let address = u32::from_str_radix(captures.get(1).unwrap().as_str(), 16)
-                    .context("Couldn't parse the address")?;
-
-                symbols.insert(
-                    demangle_tww(name)
-                        .map(|n| n.into_owned())
-                        .unwrap_or_else(|_| name.to_owned()),
-                    address,
-                );
-            }
-        }
-    }
-    Ok(symbols)
-}
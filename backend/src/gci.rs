@@ -0,0 +1,217 @@
+//! Based on http://www.gc-forever.com/yagcd/chap12.html#sec12.4
+//!
+//! GCI files hold a single save of a memory card, the way Dolphin and the
+//! memory card managers import and export them. They consist of the save's
+//! directory entry, followed by its blocks of 8 KiB. The directory entry names
+//! the game the save belongs to, so a starter save only shows up for a Rom
+//! Hack with its own game ID once it's remapped to that ID.
+
+use byteorder::{ByteOrder, BE};
+use failure::{err_msg, Error, ResultExt};
+use std::ops::Range;
+use std::str;
+
+pub const BLOCK_LEN: usize = 0x2000;
+const ENTRY_LEN: usize = 0x40;
+const GAME_CODE_LEN: usize = 4;
+const MAKER_CODE_LEN: usize = 2;
+const FILE_NAME_LEN: usize = 0x20;
+const OFFSET_MAKER_CODE: usize = 0x04;
+const OFFSET_FILE_NAME: usize = 0x08;
+const OFFSET_IMAGE: usize = 0x2C;
+const OFFSET_PERMISSIONS: usize = 0x34;
+const OFFSET_BLOCK_COUNT: usize = 0x38;
+const OFFSET_COMMENT: usize = 0x3C;
+
+/// Saves are copied, moved and deleted freely unless these are set.
+const PERMISSION_PUBLIC: u8 = 0x04;
+
+pub struct Gci {
+    entry: [u8; ENTRY_LEN],
+    /// The save data without the directory entry, in whole blocks.
+    pub data: Vec<u8>,
+}
+
+/// A checksum in the save data that the game verifies when it loads the save.
+/// It's the sum of the big endian words in the region, stored as a big endian
+/// word at the offset. Both are relative to the start of the save data.
+pub struct Checksum {
+    pub region: Range<usize>,
+    pub offset: usize,
+}
+
+impl Gci {
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        ensure!(data.len() >= ENTRY_LEN, "The directory entry is truncated");
+        let mut entry = [0; ENTRY_LEN];
+        entry.copy_from_slice(&data[..ENTRY_LEN]);
+
+        let block_count = BE::read_u16(&entry[OFFSET_BLOCK_COUNT..]) as usize;
+        ensure!(
+            data.len() - ENTRY_LEN == block_count * BLOCK_LEN,
+            "The save is supposed to be {} blocks long, but is {:#x} bytes long",
+            block_count,
+            data.len() - ENTRY_LEN
+        );
+
+        Ok(Gci {
+            entry,
+            data: data[ENTRY_LEN..].to_vec(),
+        })
+    }
+
+    /// Wraps the save data into a GCI file. The data is padded to whole
+    /// blocks. The save doesn't have a banner, an icon or a comment.
+    pub fn new(game_id: &[u8], file_name: &str, mut data: Vec<u8>) -> Result<Self, Error> {
+        ensure!(
+            file_name.is_ascii() && file_name.len() < FILE_NAME_LEN,
+            "The file name \"{}\" needs to consist of less than {} ASCII characters",
+            file_name,
+            FILE_NAME_LEN
+        );
+        ensure!(!data.is_empty(), "The save is empty");
+
+        let block_count = (data.len() + BLOCK_LEN - 1) / BLOCK_LEN;
+        ensure!(
+            block_count <= u16::max_value() as usize,
+            "The save is too large"
+        );
+        data.resize(block_count * BLOCK_LEN, 0);
+
+        let mut entry = [0; ENTRY_LEN];
+        entry[OFFSET_MAKER_CODE + MAKER_CODE_LEN] = 0xFF;
+        entry[OFFSET_FILE_NAME..][..file_name.len()].copy_from_slice(file_name.as_bytes());
+        BE::write_u32(&mut entry[OFFSET_IMAGE..], 0xFFFF_FFFF);
+        entry[OFFSET_PERMISSIONS] = PERMISSION_PUBLIC;
+        BE::write_u16(&mut entry[OFFSET_BLOCK_COUNT..], block_count as u16);
+        BE::write_u16(&mut entry[OFFSET_BLOCK_COUNT + 2..], 0xFFFF);
+        BE::write_u32(&mut entry[OFFSET_COMMENT..], 0xFFFF_FFFF);
+
+        let mut gci = Gci { entry, data };
+        gci.set_game_id(game_id)?;
+        Ok(gci)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(ENTRY_LEN + self.data.len());
+        data.extend_from_slice(&self.entry);
+        data.extend_from_slice(&self.data);
+        data
+    }
+
+    /// The game code, followed by the maker code.
+    pub fn game_id(&self) -> &[u8] {
+        &self.entry[..GAME_CODE_LEN + MAKER_CODE_LEN]
+    }
+
+    /// Remaps the save to another game. A game ID of 4 characters keeps the
+    /// maker code.
+    pub fn set_game_id(&mut self, game_id: &[u8]) -> Result<(), Error> {
+        ensure!(
+            game_id.len() == GAME_CODE_LEN || game_id.len() == GAME_CODE_LEN + MAKER_CODE_LEN,
+            "The game ID \"{}\" needs to consist of 4 or 6 characters",
+            String::from_utf8_lossy(game_id)
+        );
+        self.entry[..game_id.len()].copy_from_slice(game_id);
+        Ok(())
+    }
+
+    pub fn file_name(&self) -> Result<&str, Error> {
+        let name = &self.entry[OFFSET_FILE_NAME..][..FILE_NAME_LEN];
+        let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        Ok(str::from_utf8(&name[..end]).context("The file name is not valid ASCII")?)
+    }
+
+    /// The comment of the save, which the memory card manager shows next to
+    /// it, as an offset into the save data.
+    pub fn comment_offset(&self) -> Option<usize> {
+        match BE::read_u32(&self.entry[OFFSET_COMMENT..]) {
+            0xFFFF_FFFF => None,
+            offset => Some(offset as usize),
+        }
+    }
+
+    /// Recalculates the checksums after editing the save data, in order, so
+    /// a checksum may cover the ones before it.
+    pub fn fix_checksums(&mut self, checksums: &[Checksum]) -> Result<(), Error> {
+        for checksum in checksums {
+            let region = self
+                .data
+                .get(checksum.region.clone())
+                .ok_or_else(|| err_msg("The checksum's region is outside of the save"))?;
+            ensure!(
+                region.len() % 4 == 0,
+                "The checksum's region doesn't consist of whole words"
+            );
+            let sum = region
+                .chunks(4)
+                .fold(0u32, |sum, word| sum.wrapping_add(BE::read_u32(word)));
+
+            ensure!(
+                checksum.offset + 4 <= self.data.len(),
+                "The checksum at {:#x} is outside of the save",
+                checksum.offset
+            );
+            BE::write_u32(&mut self.data[checksum.offset..], sum);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_save_data_into_whole_blocks() {
+        let gci = Gci::new(b"GZLE01", "gczelda", vec![1; 0x2001]).unwrap();
+        let bytes = gci.to_bytes();
+        assert_eq!(bytes.len(), ENTRY_LEN + 2 * BLOCK_LEN);
+
+        let gci = Gci::parse(&bytes).unwrap();
+        assert_eq!(gci.game_id(), b"GZLE01");
+        assert_eq!(gci.file_name().unwrap(), "gczelda");
+        assert_eq!(gci.comment_offset(), None);
+        assert_eq!(&gci.data[0x1FFF..0x2002], &[1, 1, 0]);
+
+        assert!(Gci::parse(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Gci::new(b"GZLE01", "gczelda", Vec::new()).is_err());
+    }
+
+    #[test]
+    fn remaps_the_game_code_and_keeps_the_maker_code() {
+        let mut gci = Gci::new(b"GZLE01", "gczelda", vec![0; 4]).unwrap();
+        gci.set_game_id(b"GZLP").unwrap();
+        assert_eq!(gci.game_id(), b"GZLP01");
+        gci.set_game_id(b"GZLJ99").unwrap();
+        assert_eq!(gci.game_id(), b"GZLJ99");
+        assert!(gci.set_game_id(b"GZL").is_err());
+    }
+
+    #[test]
+    fn fixes_checksums_in_order() {
+        let mut data = vec![0; 0x10];
+        BE::write_u32(&mut data[0x0..], 0xFFFF_FFFF);
+        BE::write_u32(&mut data[0x4..], 2);
+        let mut gci = Gci::new(b"GZLE01", "gczelda", data).unwrap();
+        let checksums = [
+            Checksum {
+                region: 0x0..0x8,
+                offset: 0x8,
+            },
+            Checksum {
+                region: 0x4..0xC,
+                offset: 0xC,
+            },
+        ];
+        gci.fix_checksums(&checksums).unwrap();
+        assert_eq!(BE::read_u32(&gci.data[0x8..]), 1);
+        assert_eq!(BE::read_u32(&gci.data[0xC..]), 3);
+
+        let outside = [Checksum {
+            region: 0x0..0x8,
+            offset: BLOCK_LEN,
+        }];
+        assert!(gci.fix_checksums(&outside).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+//! Writes a GDB script that loads the debug info of every linked object at
+//! the addresses its sections were linked to. This way the Rom Hack can be
+//! debugged on the source level through Dolphin's GDB stub. The objects are
+//! extracted from their archives into a directory next to the script, as GDB
+//! can't load them from the archives.
+
+use failure::{Error, ResultExt};
+use linker::{self, LinkedSection};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{prelude::*, BufWriter};
+use std::path::Path;
+
+pub fn create(
+    path: &Path,
+    archive_bufs: &[Vec<u8>],
+    sections: &[LinkedSection],
+) -> Result<(), Error> {
+    let mut objects = BTreeMap::new();
+    for section in sections.iter().filter(|s| s.len != 0) {
+        objects
+            .entry((section.archive_index, section.member_name))
+            .or_insert_with(Vec::new)
+            .push(section);
+    }
+
+    let objects_dir = path.with_file_name("objects");
+    let mut file = BufWriter::new(File::create(path).context("Couldn't create the GDB script")?);
+    writeln!(
+        file,
+        "# Connect to Dolphin's GDB stub with the port set in its config"
+    )?;
+    writeln!(file, "# target remote localhost:2345")?;
+
+    for (&(archive_index, member_name), sections) in &objects {
+        let object_dir = objects_dir.join(archive_index.to_string());
+        fs::create_dir_all(&object_dir).with_context(|_| {
+            format!("Couldn't create the directory \"{}\"", object_dir.display())
+        })?;
+        let object_path = object_dir.join(member_name);
+        let object = linker::archive_member(&archive_bufs[archive_index], member_name)
+            .with_context(|_| format!("Couldn't extract the object \"{}\"", member_name))?;
+        fs::write(&object_path, object)
+            .with_context(|_| format!("Couldn't write the object \"{}\"", object_path.display()))?;
+
+        write!(file, "add-symbol-file \"{}\"", object_path.display())?;
+        for section in sections {
+            write!(
+                file,
+                " -s {} 0x{:08X}",
+                section.section_name, section.address
+            )?;
+        }
+        writeln!(file)?;
+    }
+
+    file.flush()?;
+
+    Ok(())
+}
@@ -0,0 +1,375 @@
+//! Based on http://wiiright.wikidot.com/gecko-codetypes
+//! and https://github.com/dolphin-emu/dolphin/blob/master/Source/Core/Core/GeckoCodeConfig.cpp
+
+use assembler::{build_checked_branch, is_instruction, Instruction};
+use byteorder::{ByteOrder, BE};
+use dol::{DolFile, Section};
+use failure::{Error, ResultExt};
+use std::collections::BTreeMap;
+
+const GCT_MAGIC: [u8; 8] = [0x00, 0xD0, 0xC0, 0xDE, 0x00, 0xD0, 0xC0, 0xDE];
+const GCT_END: [u8; 8] = [0xF0, 0, 0, 0, 0, 0, 0, 0];
+const BASE_ADDRESS: u32 = 0x8000_0000;
+
+/// Code handlers are built to run from the part of the low memory that the
+/// OS leaves to debuggers, with their code list directly following them.
+pub const HANDLER_ADDRESS: u32 = 0x8000_1800;
+pub const HANDLER_END: u32 = 0x8000_3000;
+/// The code handler is called here, after the variables at its start.
+pub const HANDLER_ENTRY: u32 = 0x8000_18A8;
+
+pub enum Code {
+    /// Writes the bytes to the address. This covers the 8-bit, 16-bit and
+    /// 32-bit writes as well as the string writes.
+    Write { address: u32, data: Vec<u8> },
+    /// Executes the instructions in place of the instruction at the address
+    /// and then returns to the instruction after it.
+    InsertAsm { address: u32, instructions: Vec<u8> },
+}
+
+pub fn parse_text(text: &str) -> Result<Vec<Code>, Error> {
+    decode(&parse_text_lines(text)?)
+}
+
+fn parse_text_lines(text: &str) -> Result<Vec<(u32, u32)>, Error> {
+    let mut lines = Vec::new();
+
+    for (line_index, line) in text.lines().enumerate() {
+        let mut words = line.split_whitespace();
+        let (left, right) = match (words.next(), words.next()) {
+            (Some(left), Some(right)) if is_code_word(left) && is_code_word(right) => {
+                (left, right)
+            }
+            // Code names, comments and descriptions
+            _ => continue,
+        };
+        let left = u32::from_str_radix(left, 16)
+            .with_context(|_| format!("Invalid code on line {}", line_index + 1))?;
+        let right = u32::from_str_radix(right, 16)
+            .with_context(|_| format!("Invalid code on line {}", line_index + 1))?;
+        lines.push((left, right));
+    }
+
+    Ok(lines)
+}
+
+/// Converts the codes to a GCT file as is, for a code handler to run them.
+pub fn text_to_gct(text: &str) -> Result<Vec<u8>, Error> {
+    let lines = parse_text_lines(text)?;
+    let mut data = Vec::with_capacity(8 * lines.len() + GCT_MAGIC.len() + GCT_END.len());
+    data.extend_from_slice(&GCT_MAGIC);
+    for (left, right) in lines {
+        let mut buf = [0; 8];
+        BE::write_u32(&mut buf, left);
+        BE::write_u32(&mut buf[4..], right);
+        data.extend_from_slice(&buf);
+    }
+    data.extend_from_slice(&GCT_END);
+    Ok(data)
+}
+
+/// Builds the section containing the code handler and the code list after
+/// it. Without a GCT file, the code list is empty. The codes are left to the
+/// handler, so all of its code types are supported.
+pub fn embed_handler(handler: &[u8], gct: Option<&[u8]>) -> Result<Section, Error> {
+    ensure!(
+        HANDLER_ADDRESS + handler.len() as u32 > HANDLER_ENTRY,
+        "The code handler is too short to be called at {:08X}",
+        HANDLER_ENTRY
+    );
+
+    let mut data = handler.to_vec();
+    match gct {
+        Some(gct) => {
+            ensure!(
+                gct.len() >= GCT_MAGIC.len() && gct[..GCT_MAGIC.len()] == GCT_MAGIC,
+                "The code list is not a valid GCT file"
+            );
+            let has_end = gct[GCT_MAGIC.len()..].chunks(8).any(|line| line == GCT_END);
+            ensure!(has_end, "The GCT file is truncated");
+            data.extend_from_slice(gct);
+        }
+        None => {
+            data.extend_from_slice(&GCT_MAGIC);
+            data.extend_from_slice(&GCT_END);
+        }
+    }
+
+    let len = data.len() as u32;
+    ensure!(
+        len <= HANDLER_END - HANDLER_ADDRESS,
+        "The code handler and its codes take up {:#x} bytes, but only {:#x} bytes fit",
+        len,
+        HANDLER_END - HANDLER_ADDRESS
+    );
+
+    Ok(Section {
+        address: HANDLER_ADDRESS,
+        data: data.into_boxed_slice(),
+    })
+}
+
+pub fn parse_gct(data: &[u8]) -> Result<Vec<Code>, Error> {
+    ensure!(
+        data.len() >= GCT_MAGIC.len() && data[..GCT_MAGIC.len()] == GCT_MAGIC,
+        "The code list is not a valid GCT file"
+    );
+
+    let mut lines = Vec::new();
+    for line in data[GCT_MAGIC.len()..].chunks(8) {
+        ensure!(line.len() == 8, "The GCT file is truncated");
+        if line == GCT_END {
+            break;
+        }
+        lines.push((BE::read_u32(line), BE::read_u32(&line[4..])));
+    }
+
+    decode(&lines)
+}
+
+fn is_code_word(word: &str) -> bool {
+    word.len() == 8 && word.chars().all(|c| c.is_digit(16))
+}
+
+fn decode(lines: &[(u32, u32)]) -> Result<Vec<Code>, Error> {
+    let mut codes = Vec::new();
+    let mut lines = lines.iter();
+
+    while let Some(&(left, right)) = lines.next() {
+        // The lowest bit of the code type is the highest bit of the address
+        let code_type = (left >> 24) as u8 & !1;
+        let address = BASE_ADDRESS | (left & 0x01FF_FFFF);
+
+        ensure!(
+            code_type >= 0xC0 || code_type & 0x10 == 0,
+            "The Gecko code {:08X} {:08X} uses the pointer address, which is not supported",
+            left,
+            right
+        );
+
+        match code_type {
+            0x00 => {
+                let count = (right >> 16) as usize + 1;
+                codes.push(Code::Write {
+                    address,
+                    data: vec![right as u8; count],
+                });
+            }
+            0x02 => {
+                let count = (right >> 16) as usize + 1;
+                let mut data = vec![0; 2 * count];
+                for chunk in data.chunks_mut(2) {
+                    BE::write_u16(chunk, right as u16);
+                }
+                codes.push(Code::Write { address, data });
+            }
+            0x04 => {
+                let mut data = vec![0; 4];
+                BE::write_u32(&mut data, right);
+                codes.push(Code::Write { address, data });
+            }
+            0x06 => {
+                let len = right as usize;
+                let data = take_bytes(&mut lines, len)
+                    .with_context(|_| format!("String write to {:08X} is truncated", address))?;
+                codes.push(Code::Write { address, data });
+            }
+            0xC2 => {
+                let len = 8 * right as usize;
+                let instructions = take_bytes(&mut lines, len).with_context(|_| {
+                    format!("Instruction insertion at {:08X} is truncated", address)
+                })?;
+                ensure!(
+                    len != 0 && BE::read_u32(&instructions[len - 4..]) == 0,
+                    "Instruction insertion at {:08X} doesn't end with 00000000",
+                    address
+                );
+                codes.push(Code::InsertAsm {
+                    address,
+                    instructions,
+                });
+            }
+            _ => bail!(
+                "The Gecko code type {:02X} ({:08X} {:08X}) is not supported",
+                code_type,
+                left,
+                right
+            ),
+        }
+    }
+
+    Ok(codes)
+}
+
+fn take_bytes<'a, I>(lines: &mut I, len: usize) -> Result<Vec<u8>, Error>
+where
+    I: Iterator<Item = &'a (u32, u32)>,
+{
+    let mut data = Vec::with_capacity(len + 8);
+    while data.len() < len {
+        let &(left, right) = lines
+            .next()
+            .ok_or_else(|| format_err!("Expected {} more bytes", len - data.len()))?;
+        let mut buf = [0; 8];
+        BE::write_u32(&mut buf, left);
+        BE::write_u32(&mut buf[4..], right);
+        data.extend_from_slice(&buf);
+    }
+    data.truncate(len);
+    Ok(data)
+}
+
+/// The amount of bytes the stubs of the codes take up.
+pub fn stubs_len(codes: &[Code]) -> u32 {
+    codes
+        .iter()
+        .map(|code| match *code {
+            Code::InsertAsm {
+                ref instructions, ..
+            } => instructions.len() as u32,
+            Code::Write { .. } => 0,
+        }).sum()
+}
+
+/// Lowers the codes to instructions that patch the DOL. The instructions
+/// inserted by the codes are placed into a new section starting at the stub
+/// address. Writes that don't cover full words are masked, so they keep the
+/// rest of the original words.
+pub fn lower(
+    codes: &[Code],
+    dol: &DolFile,
+    stub_address: u32,
+) -> Result<(Vec<Instruction>, Option<Section>), Error> {
+    // Maps each word address to the bytes written and the mask of the bytes
+    let mut words = BTreeMap::new();
+    let mut stubs = Vec::new();
+
+    for code in codes {
+        match *code {
+            Code::Write { address, ref data } => {
+                for (index, &byte) in data.iter().enumerate() {
+                    let address = address + index as u32;
+                    let shift = 8 * (3 - (address & 3));
+                    let word = words.entry(address & !3).or_insert((0u32, 0u32));
+                    word.0 = (word.0 & !(0xFF << shift)) | ((byte as u32) << shift);
+                    word.1 |= 0xFF << shift;
+                }
+            }
+            Code::InsertAsm {
+                address,
+                ref instructions,
+            } => {
+                ensure!(
+                    address & 3 == 0,
+                    "Instruction insertion at {:08X} is not aligned",
+                    address
+                );
+                let is_code = dol
+                    .text_sections
+                    .iter()
+                    .any(|s| s.address <= address && address < s.end_address());
+                match dol.read_u32(address) {
+                    Some(word) if is_code && is_instruction(word) => {}
+                    _ => bail!(
+                        "Instruction insertion at {:08X} doesn't replace an instruction",
+                        address
+                    ),
+                }
+                let stub_start = stub_address + stubs.len() as u32;
+                stubs.extend_from_slice(instructions);
+                let return_offset = stubs.len() - 4;
+                let branch_back =
+                    build_checked_branch(stub_address + return_offset as u32, address + 4, false)?;
+                BE::write_u32(&mut stubs[return_offset..], branch_back);
+
+                words.insert(
+                    address,
+                    (build_checked_branch(address, stub_start, false)?, !0),
+                );
+            }
+        }
+    }
+
+    let mut instructions = Vec::with_capacity(words.len());
+    for (address, (data, mask)) in words {
+        ensure!(
+            mask == !0 || dol.read_u32(address).is_some(),
+            "The Gecko code writes to {:08X}, which is not in the DOL",
+            address
+        );
+        instructions.push(Instruction {
+            address,
+            data,
+            mask,
+        });
+    }
+
+    let section = if stubs.is_empty() {
+        None
+    } else {
+        Some(Section {
+            address: stub_address,
+            data: stubs.into_boxed_slice(),
+        })
+    };
+
+    Ok((instructions, section))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_writes(lines: &[(u32, u32)]) -> Vec<(u32, Vec<u8>)> {
+        decode(lines)
+            .unwrap()
+            .into_iter()
+            .map(|c| match c {
+                Code::Write { address, data } => (address, data),
+                Code::InsertAsm { .. } => panic!("Expected a write"),
+            }).collect()
+    }
+
+    #[test]
+    fn writes_to_the_upper_half_of_mem1() {
+        assert_eq!(
+            decode_writes(&[(0x0512_3456, 0xDEAD_BEEF)]),
+            [(0x8112_3456, vec![0xDE, 0xAD, 0xBE, 0xEF])]
+        );
+        assert_eq!(
+            decode_writes(&[(0x0112_3456, 0x0002_00AB)]),
+            [(0x8112_3456, vec![0xAB; 3])]
+        );
+    }
+
+    #[test]
+    fn writes_strings() {
+        assert_eq!(
+            decode_writes(&[(0x0600_1000, 5), (0x0102_0304, 0x05FF_FFFF)]),
+            [(0x8000_1000, vec![1, 2, 3, 4, 5])]
+        );
+        assert!(decode(&[(0x0600_1000, 9), (0, 0)]).is_err());
+    }
+
+    #[test]
+    fn rejects_pointer_codes() {
+        assert!(decode(&[(0x1400_0010, 0)]).is_err());
+        assert!(decode(&[(0x1500_0010, 0)]).is_err());
+    }
+
+    #[test]
+    fn inserts_instructions() {
+        let codes = decode(&[(0xC300_2000, 1), (0x3860_0001, 0)]).unwrap();
+        match codes[..] {
+            [Code::InsertAsm {
+                address,
+                ref instructions,
+            }] => {
+                assert_eq!(address, 0x8100_2000);
+                assert_eq!(*instructions, [0x38, 0x60, 0, 1, 0, 0, 0, 0]);
+            }
+            _ => panic!("Expected an instruction insertion"),
+        }
+        assert!(decode(&[(0xC200_2000, 1), (0x3860_0001, 0x3860_0002)]).is_err());
+    }
+}
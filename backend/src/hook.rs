@@ -0,0 +1,255 @@
+use assembler::{
+    build_branch_instruction, build_checked_branch, is_branch_in_range, is_instruction, Instruction,
+};
+use byteorder::{ByteOrder, BE};
+use dol::{DolFile, Section};
+use failure::Error;
+
+/// Calls the function whenever the game executes the instruction at the
+/// address. The function is called with the registers r3 to r10 as they are
+/// at that point, so it can take them as its arguments. All the volatile
+/// registers are restored afterwards, so the game continues as if nothing
+/// happened. Only the first halves of the paired singles are preserved.
+pub struct Hook {
+    pub address: u32,
+    pub function: u32,
+    /// The instruction the hook is expected to replace.
+    pub original: Option<u32>,
+}
+
+// The layout of the trampoline's stack frame
+const FRAME_SIZE: i16 = 0xC0;
+const OFFSET_R0: i16 = 0x08;
+const OFFSET_R3: i16 = 0x0C;
+const OFFSET_LR: i16 = 0x34;
+const OFFSET_CTR: i16 = 0x38;
+const OFFSET_CR: i16 = 0x3C;
+const OFFSET_XER: i16 = 0x40;
+const OFFSET_F0: i16 = 0x48;
+
+pub const TRAMPOLINE_LEN: u32 = 71 * 4;
+
+const MFLR_R0: u32 = 0x7C08_02A6;
+const MTLR_R0: u32 = 0x7C08_03A6;
+const MFCTR_R0: u32 = 0x7C09_02A6;
+const MTCTR_R0: u32 = 0x7C09_03A6;
+const MFXER_R0: u32 = 0x7C01_02A6;
+const MTXER_R0: u32 = 0x7C01_03A6;
+const MFCR_R0: u32 = 0x7C00_0026;
+const MTCRF_FF_R0: u32 = 0x7C0F_F120;
+
+fn d_form(opcode: u32, reg: u32, base: u32, offset: i16) -> u32 {
+    (opcode << 26) | (reg << 21) | (base << 16) | (offset as u16 as u32)
+}
+
+fn stwu_r1(offset: i16) -> u32 {
+    d_form(37, 1, 1, offset)
+}
+
+fn addi_r1(offset: i16) -> u32 {
+    d_form(14, 1, 1, offset)
+}
+
+fn stw(reg: u32, offset: i16) -> u32 {
+    d_form(36, reg, 1, offset)
+}
+
+fn lwz(reg: u32, offset: i16) -> u32 {
+    d_form(32, reg, 1, offset)
+}
+
+fn stfd(reg: u32, offset: i16) -> u32 {
+    d_form(54, reg, 1, offset)
+}
+
+fn lfd(reg: u32, offset: i16) -> u32 {
+    d_form(50, reg, 1, offset)
+}
+
+/// Moves the instruction from one address to another, adjusting relative
+/// branches so they keep their destination.
+pub fn relocate_instruction(instruction: u32, from: u32, to: u32) -> Result<u32, Error> {
+    let opcode = instruction >> 26;
+    let is_absolute = instruction & 2 != 0;
+
+    if opcode == 18 && !is_absolute {
+        let displacement = ((instruction & 0x03FF_FFFC) << 6) as i32 >> 6;
+        let destination = from.wrapping_add(displacement as u32);
+        let new_displacement = destination.wrapping_sub(to) as i32;
+        ensure!(
+            new_displacement >= -0x0200_0000 && new_displacement < 0x0200_0000,
+            "The branch at {:08X} can't reach its destination once it's moved",
+            from
+        );
+        Ok(build_branch_instruction(
+            to,
+            destination,
+            false,
+            instruction & 1 != 0,
+        ))
+    } else if opcode == 16 && !is_absolute {
+        let displacement = ((instruction & 0xFFFC) << 16) as i32 >> 16;
+        let destination = from.wrapping_add(displacement as u32);
+        let new_displacement = destination.wrapping_sub(to) as i32;
+        ensure!(
+            new_displacement >= -0x8000 && new_displacement < 0x8000,
+            "The conditional branch at {:08X} can't be moved that far",
+            from
+        );
+        Ok((instruction & !0xFFFC) | (new_displacement as u32 & 0xFFFC))
+    } else {
+        Ok(instruction)
+    }
+}
+
+/// Reads the instruction that a branch to generated code replaces, like the
+/// one a hook replaces, which is moved into the generated code.
+pub fn read_displaced_instruction(dol: &DolFile, address: u32, name: &str) -> Result<u32, Error> {
+    ensure!(
+        address & 3 == 0,
+        "The {} at {:08X} is not aligned",
+        name,
+        address
+    );
+    let is_code = dol
+        .text_sections
+        .iter()
+        .any(|s| s.address <= address && address < s.end_address());
+    ensure!(
+        is_code,
+        "The {} at {:08X} is not in one of the DOL's text sections",
+        name,
+        address
+    );
+    let original = dol.read_u32(address).ok_or_else(|| {
+        format_err!(
+            "The {} at {:08X} is not in one of the DOL's sections",
+            name,
+            address
+        )
+    })?;
+    ensure!(
+        is_instruction(original),
+        "The {} at {:08X} doesn't replace an instruction, but {:08X}",
+        name,
+        address,
+        original
+    );
+    Ok(original)
+}
+
+/// The amount of bytes the trampolines of the hooks take up.
+pub fn trampolines_len(hooks: &[Hook]) -> u32 {
+    hooks.len() as u32 * TRAMPOLINE_LEN
+}
+
+/// Lowers the hooks to the branches that replace the hooked instructions and
+/// the section containing the trampolines, which starts at the stub address.
+pub fn lower(
+    hooks: &[Hook],
+    dol: &DolFile,
+    stub_address: u32,
+) -> Result<(Vec<Instruction>, Option<Section>), Error> {
+    let mut instructions = Vec::with_capacity(hooks.len());
+    let mut trampolines = Vec::new();
+
+    for hook in hooks {
+        let original = read_displaced_instruction(dol, hook.address, "hook")?;
+        if let Some(expected) = hook.original {
+            ensure!(
+                original == expected,
+                "The hook at {:08X} replaces {:08X} instead of {:08X}, is it meant for \
+                 another version of the game?",
+                hook.address,
+                original,
+                expected
+            );
+        }
+
+        let trampoline_address = stub_address + 4 * trampolines.len() as u32;
+        instructions.push(Instruction {
+            address: hook.address,
+            data: build_checked_branch(hook.address, trampoline_address, false)?,
+            mask: !0,
+        });
+
+        trampolines.push(stwu_r1(-FRAME_SIZE));
+        trampolines.push(stw(0, OFFSET_R0));
+        for &(mf, offset) in &[
+            (MFLR_R0, OFFSET_LR),
+            (MFCTR_R0, OFFSET_CTR),
+            (MFCR_R0, OFFSET_CR),
+            (MFXER_R0, OFFSET_XER),
+        ] {
+            trampolines.push(mf);
+            trampolines.push(stw(0, offset));
+        }
+        for reg in 3..13 {
+            trampolines.push(stw(reg, OFFSET_R3 + 4 * (reg as i16 - 3)));
+        }
+        for reg in 0..14 {
+            trampolines.push(stfd(reg, OFFSET_F0 + 8 * reg as i16));
+        }
+
+        let call_address = stub_address + 4 * trampolines.len() as u32;
+        ensure!(
+            is_branch_in_range(call_address, hook.function),
+            "The function {:08X} of the hook at {:08X} is out of the trampoline's range",
+            hook.function,
+            hook.address
+        );
+        trampolines.push(build_branch_instruction(
+            call_address,
+            hook.function,
+            false,
+            true,
+        ));
+
+        for reg in 0..14 {
+            trampolines.push(lfd(reg, OFFSET_F0 + 8 * reg as i16));
+        }
+        for reg in 3..13 {
+            trampolines.push(lwz(reg, OFFSET_R3 + 4 * (reg as i16 - 3)));
+        }
+        for &(mt, offset) in &[
+            (MTXER_R0, OFFSET_XER),
+            (MTCRF_FF_R0, OFFSET_CR),
+            (MTCTR_R0, OFFSET_CTR),
+            (MTLR_R0, OFFSET_LR),
+        ] {
+            trampolines.push(lwz(0, offset));
+            trampolines.push(mt);
+        }
+        trampolines.push(lwz(0, OFFSET_R0));
+        trampolines.push(addi_r1(FRAME_SIZE));
+
+        let original_address = stub_address + 4 * trampolines.len() as u32;
+        trampolines.push(relocate_instruction(
+            original,
+            hook.address,
+            original_address,
+        )?);
+
+        let return_address = stub_address + 4 * trampolines.len() as u32;
+        trampolines.push(build_checked_branch(
+            return_address,
+            hook.address + 4,
+            false,
+        )?);
+    }
+
+    let section = if trampolines.is_empty() {
+        None
+    } else {
+        let mut data = vec![0; 4 * trampolines.len()];
+        for (chunk, &instruction) in data.chunks_mut(4).zip(&trampolines) {
+            BE::write_u32(chunk, instruction);
+        }
+        Some(Section {
+            address: stub_address,
+            data: data.into_boxed_slice(),
+        })
+    };
+
+    Ok((instructions, section))
+}
@@ -0,0 +1,82 @@
+//! Lowers the inject blocks of the patch files. The instruction at the
+//! address of a block is replaced by a branch to the block, which is followed
+//! by the displaced instruction and a branch back to the instruction after
+//! it. So the block runs right before the displaced instruction, with all the
+//! registers as they are at that point.
+
+use assembler::{build_checked_branch, Assembler, Injection, Instruction};
+use byteorder::{ByteOrder, BE};
+use dol::{DolFile, Section};
+use failure::{Error, ResultExt};
+use hook::{read_displaced_instruction, relocate_instruction};
+
+/// The amount of bytes the block takes up, including the displaced
+/// instruction and the branch back.
+pub fn stub_len(injection: &Injection) -> u32 {
+    ((injection.len + 3) & !3) + 2 * 4
+}
+
+/// The amount of bytes all the blocks take up.
+pub fn stubs_len(injections: &[Injection]) -> u32 {
+    injections.iter().map(stub_len).sum()
+}
+
+/// Lowers the blocks to the branches that replace the instructions at their
+/// addresses and the section containing the blocks, which starts at the stub
+/// address.
+pub fn lower(
+    injections: &[Injection],
+    assembler: &Assembler,
+    dol: &DolFile,
+    stub_address: u32,
+) -> Result<(Vec<Instruction>, Option<Section>), Error> {
+    let mut instructions = Vec::with_capacity(injections.len());
+    let mut stubs = Vec::new();
+
+    for injection in injections {
+        let original = read_displaced_instruction(dol, injection.address, "inject block")?;
+
+        let block_address = stub_address + stubs.len() as u32;
+        let block = assembler
+            .assemble_injection(injection, block_address)
+            .with_context(|_| {
+                format!(
+                    "Couldn't assemble the inject block at {:08X}",
+                    injection.address
+                )
+            })?;
+        stubs.extend_from_slice(&block);
+        while stubs.len() % 4 != 0 {
+            stubs.push(0);
+        }
+
+        let original_address = stub_address + stubs.len() as u32;
+        let mut words = [0; 8];
+        BE::write_u32(
+            &mut words[..4],
+            relocate_instruction(original, injection.address, original_address)?,
+        );
+        BE::write_u32(
+            &mut words[4..],
+            build_checked_branch(original_address + 4, injection.address + 4, false)?,
+        );
+        stubs.extend_from_slice(&words);
+
+        instructions.push(Instruction {
+            address: injection.address,
+            data: build_checked_branch(injection.address, block_address, false)?,
+            mask: !0,
+        });
+    }
+
+    let section = if stubs.is_empty() {
+        None
+    } else {
+        Some(Section {
+            address: stub_address,
+            data: stubs.into_boxed_slice(),
+        })
+    };
+
+    Ok((instructions, section))
+}
@@ -0,0 +1,83 @@
+//! The apploader follows the disc header and loads the DOL when the game
+//! boots. It consists of a header, its code and a trailer, whose sizes are
+//! stored in the header. Only those parts are carried over, so a rebuilt disc
+//! doesn't keep the padding between the apploader and the DOL around.
+
+use byteorder::{ByteOrder, BE};
+use failure::{Error, ResultExt};
+use std::str;
+
+const HEADER_LEN: usize = 0x20;
+const OFFSET_ENTRY_POINT: usize = 0x10;
+const OFFSET_CODE_LEN: usize = 0x14;
+const OFFSET_TRAILER_LEN: usize = 0x18;
+
+/// The apploader's code is loaded here, without its header.
+const LOAD_ADDRESS: u32 = 0x8120_0000;
+
+pub struct Apploader<'a> {
+    pub date: &'a str,
+    pub entry_point: u32,
+    pub code: &'a [u8],
+    pub trailer: &'a [u8],
+    header: &'a [u8],
+}
+
+impl<'a> Apploader<'a> {
+    /// Parses the apploader at the start of the data. The data may continue
+    /// after the apploader's trailer.
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        ensure!(
+            data.len() >= HEADER_LEN,
+            "The apploader's header is truncated"
+        );
+        let date =
+            str::from_utf8(&data[..10]).context("The apploader's date is not valid ASCII")?;
+        let entry_point = BE::read_u32(&data[OFFSET_ENTRY_POINT..]);
+        let code_len = BE::read_u32(&data[OFFSET_CODE_LEN..]) as usize;
+        let trailer_len = BE::read_u32(&data[OFFSET_TRAILER_LEN..]) as usize;
+
+        let code_end = HEADER_LEN
+            .checked_add(code_len)
+            .filter(|&end| end <= data.len())
+            .ok_or_else(|| {
+                format_err!("The apploader's code of {:#x} bytes is truncated", code_len)
+            })?;
+        let trailer_end = code_end
+            .checked_add(trailer_len)
+            .filter(|&end| end <= data.len())
+            .ok_or_else(|| {
+                format_err!(
+                    "The apploader's trailer of {:#x} bytes is truncated",
+                    trailer_len
+                )
+            })?;
+
+        ensure!(
+            LOAD_ADDRESS <= entry_point && entry_point < LOAD_ADDRESS + code_len as u32,
+            "The apploader's entry point {:08X} is outside of its code",
+            entry_point
+        );
+
+        Ok(Apploader {
+            date,
+            entry_point,
+            code: &data[HEADER_LEN..code_end],
+            trailer: &data[code_end..trailer_end],
+            header: &data[..HEADER_LEN],
+        })
+    }
+
+    /// The size of the apploader, without anything that follows the trailer.
+    pub fn len(&self) -> usize {
+        HEADER_LEN + self.code.len() + self.trailer.len()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(self.len());
+        data.extend_from_slice(self.header);
+        data.extend_from_slice(self.code);
+        data.extend_from_slice(self.trailer);
+        data
+    }
+}
@@ -0,0 +1,247 @@
+//! Based on https://github.com/dolphin-emu/dolphin/blob/master/Source/Core/DiscIO/CISOBlob.h
+//!
+//! CISO files leave out the blocks of a disc that are never used. A map in
+//! the header marks the blocks that are stored, which follow the header in
+//! the order of the disc. The blocks that are left out are read as zeros.
+
+use byteorder::{ByteOrder, LE};
+use failure::Error;
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+const MAGIC: &[u8] = b"CISO";
+const HEADER_LEN: usize = 0x8000;
+const OFFSET_BLOCK_SIZE: usize = 0x04;
+const OFFSET_MAP: usize = 0x08;
+const MAP_LEN: usize = HEADER_LEN - OFFSET_MAP;
+/// The block size that USB loaders expect.
+const BLOCK_SIZE: u64 = 0x20_0000;
+
+pub fn is_ciso(header: &[u8]) -> bool {
+    header.starts_with(MAGIC)
+}
+
+pub struct CisoReader<R> {
+    reader: R,
+    block_size: u64,
+    /// The offset of each block in the file, unless it's left out.
+    blocks: Vec<Option<u64>>,
+    position: u64,
+    reader_position: u64,
+}
+
+impl<R> CisoReader<R> {
+    fn len(&self) -> u64 {
+        self.blocks.len() as u64 * self.block_size
+    }
+}
+
+impl<R: Read + Seek> CisoReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let mut header = vec![0; HEADER_LEN];
+        reader.seek(SeekFrom::Start(0))?;
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| format_err!("The header of the CISO file is truncated"))?;
+        ensure!(is_ciso(&header), "The file is not a CISO file");
+
+        let block_size = LE::read_u32(&header[OFFSET_BLOCK_SIZE..]) as u64;
+        ensure!(block_size > 0, "The CISO file has no block size");
+
+        // Blocks after the last stored one are never part of the disc
+        let num_blocks = header[OFFSET_MAP..]
+            .iter()
+            .rposition(|&b| b != 0)
+            .map_or(0, |index| index + 1);
+        let mut offset = HEADER_LEN as u64;
+        let blocks = header[OFFSET_MAP..][..num_blocks]
+            .iter()
+            .map(|&is_stored| {
+                if is_stored != 0 {
+                    offset += block_size;
+                    Some(offset - block_size)
+                } else {
+                    None
+                }
+            }).collect();
+
+        Ok(Self {
+            reader,
+            block_size,
+            blocks,
+            position: 0,
+            reader_position: HEADER_LEN as u64,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for CisoReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len() || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let index = (self.position / self.block_size) as usize;
+        let offset = self.position % self.block_size;
+        let len = cmp::min(buf.len() as u64, self.block_size - offset) as usize;
+        let len = match self.blocks[index] {
+            Some(block_offset) => {
+                if self.reader_position != block_offset + offset {
+                    self.reader.seek(SeekFrom::Start(block_offset + offset))?;
+                }
+                let len = self.reader.read(&mut buf[..len])?;
+                self.reader_position = block_offset + offset + len as u64;
+                len
+            }
+            None => {
+                for b in &mut buf[..len] {
+                    *b = 0;
+                }
+                len
+            }
+        };
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<R> Seek for CisoReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.len() as i64 + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+        if position < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seeked before the start of the disc",
+            ));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+/// Writes a disc into a CISO file. Blocks that only contain zeros are left
+/// out. As the stored blocks need to be in the order of the disc, a block
+/// that was left out can't be written to once a later block is stored.
+pub struct CisoWriter<W> {
+    writer: W,
+    /// The position in the disc.
+    position: u64,
+    /// The offset of each stored block in the file.
+    blocks: Vec<Option<u64>>,
+    /// The end of the stored blocks in the file.
+    end: u64,
+    writer_position: u64,
+}
+
+impl<W: Write + Seek> CisoWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            position: 0,
+            blocks: vec![None; MAP_LEN],
+            end: HEADER_LEN as u64,
+            writer_position: 0,
+        }
+    }
+
+    /// Writes the header and returns the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        // The last block is padded to the block size
+        if self.end > HEADER_LEN as u64 {
+            self.writer.seek(SeekFrom::Start(self.end - 1))?;
+            self.writer.write_all(&[0])?;
+        }
+
+        let mut header = vec![0; HEADER_LEN];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        LE::write_u32(&mut header[OFFSET_BLOCK_SIZE..], BLOCK_SIZE as u32);
+        for (is_stored, block) in header[OFFSET_MAP..].iter_mut().zip(&self.blocks) {
+            *is_stored = block.is_some() as u8;
+        }
+
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.writer.write_all(&header)?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write + Seek> Write for CisoWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let index = (self.position / BLOCK_SIZE) as usize;
+        let offset = self.position % BLOCK_SIZE;
+        let len = cmp::min(buf.len() as u64, BLOCK_SIZE - offset) as usize;
+        let buf = &buf[..len];
+        if index >= self.blocks.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "The disc is too large for a CISO file",
+            ));
+        }
+
+        let block_offset = match self.blocks[index] {
+            Some(block_offset) => block_offset,
+            None if buf.iter().all(|&b| b == 0) => {
+                self.position += len as u64;
+                return Ok(len);
+            }
+            None => {
+                if self.blocks[index..].iter().any(Option::is_some) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Other,
+                        "The blocks of a CISO file need to be written in order",
+                    ));
+                }
+                let block_offset = self.end;
+                self.blocks[index] = Some(block_offset);
+                self.end += BLOCK_SIZE;
+                block_offset
+            }
+        };
+
+        if self.writer_position != block_offset + offset {
+            self.writer.seek(SeekFrom::Start(block_offset + offset))?;
+        }
+        self.writer.write_all(buf)?;
+        self.writer_position = block_offset + offset + len as u64;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W> Seek for CisoWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "CISO files can't be seeked from the end",
+                ))
+            }
+        };
+        if position < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seeked before the start of the disc",
+            ));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
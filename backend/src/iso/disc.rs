@@ -0,0 +1,204 @@
+//! Opens the original game, which is either a raw disc image, a split ISO, a
+//! CISO file, a GCZ file or a WIA or RVZ file. All of them are read as the raw
+//! disc, so the rest of the compiler doesn't need to know how the original
+//! game is stored. The output is written the same way, with its format chosen
+//! by its extension. Raw disc images are memory mapped, so reading a few files
+//! of them doesn't load the rest of the disc.
+//!
+//! NKit images are read as raw disc images. They leave out the junk and the
+//! update partition, but everything the compiler reads from them is still
+//! there, so the built game is complete. Nothing is ever written as WIA, RVZ
+//! or NKit.
+
+use super::ciso::{self, CisoReader, CisoWriter};
+use super::gcz::{self, GczReader};
+use super::split::{self, SplitFile, SplitReader};
+use super::wbfs::WbfsWriter;
+use super::wia::{self, WiaReader};
+use failure::{Error, ResultExt};
+#[cfg(not(target_arch = "wasm32"))]
+use memmap::Mmap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+// Memory mapping isn't available on the web, so those discs are always read
+// through a buffer
+#[cfg(target_arch = "wasm32")]
+type Mmap = Box<[u8]>;
+
+const HEADER_LEN: usize = 4;
+
+pub enum Disc {
+    Raw(BufReader<File>),
+    Mapped(Cursor<Mmap>),
+    Split(SplitReader),
+    Ciso(CisoReader<BufReader<File>>),
+    Gcz(GczReader<BufReader<File>>),
+    Wia(WiaReader<BufReader<File>>),
+}
+
+impl Disc {
+    /// Maps the disc image into memory. Images that can't be mapped, like
+    /// images larger than the address space of 32-bit systems, are read
+    /// through a buffer instead.
+    pub fn raw(file: File) -> Self {
+        match map(&file) {
+            Some(map) => Disc::Mapped(Cursor::new(map)),
+            None => Disc::Raw(BufReader::with_capacity(4 << 20, file)),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn map(file: &File) -> Option<Mmap> {
+    // The original game is not supposed to be modified while it's being read
+    unsafe { Mmap::map(file) }.ok()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn map(_: &File) -> Option<Mmap> {
+    None
+}
+
+pub fn open(path: &Path) -> Result<Disc, Error> {
+    if split::is_split(path) {
+        let reader = SplitReader::open(path)
+            .with_context(|_| format!("Couldn't find \"{}\".", path.display()))?;
+        return Ok(Disc::Split(reader));
+    }
+
+    let file =
+        File::open(path).with_context(|_| format!("Couldn't find \"{}\".", path.display()))?;
+    let mut reader = BufReader::with_capacity(4 << 20, file);
+
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    reader
+        .by_ref()
+        .take(HEADER_LEN as u64)
+        .read_to_end(&mut header)
+        .with_context(|_| format!("Couldn't read \"{}\".", path.display()))?;
+    reader.seek(SeekFrom::Start(0))?;
+
+    if ciso::is_ciso(&header) {
+        return Ok(Disc::Ciso(
+            CisoReader::new(reader).context("Couldn't parse the CISO file")?,
+        ));
+    }
+    if gcz::is_gcz(&header) {
+        return Ok(Disc::Gcz(
+            GczReader::new(reader).context("Couldn't parse the GCZ file")?,
+        ));
+    }
+    if wia::is_wia(&header) {
+        return Ok(Disc::Wia(
+            WiaReader::new(reader).context("Couldn't parse the WIA or RVZ file")?,
+        ));
+    }
+
+    Ok(Disc::raw(reader.into_inner()))
+}
+
+impl Read for Disc {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Disc::Raw(ref mut reader) => reader.read(buf),
+            Disc::Mapped(ref mut reader) => reader.read(buf),
+            Disc::Split(ref mut reader) => reader.read(buf),
+            Disc::Ciso(ref mut reader) => reader.read(buf),
+            Disc::Gcz(ref mut reader) => reader.read(buf),
+            Disc::Wia(ref mut reader) => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for Disc {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match *self {
+            Disc::Raw(ref mut reader) => reader.seek(pos),
+            Disc::Mapped(ref mut reader) => reader.seek(pos),
+            Disc::Split(ref mut reader) => reader.seek(pos),
+            Disc::Ciso(ref mut reader) => reader.seek(pos),
+            Disc::Gcz(ref mut reader) => reader.seek(pos),
+            Disc::Wia(ref mut reader) => reader.seek(pos),
+        }
+    }
+}
+
+pub enum Output {
+    Iso(BufWriter<File>),
+    Split(SplitFile),
+    Ciso(CisoWriter<BufWriter<File>>),
+    Wbfs(WbfsWriter),
+}
+
+/// Creates the output in the format of its extension. Paths ending with
+/// `.part0.iso` are written as split ISOs.
+pub fn create(path: &Path) -> Result<Output, Error> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase());
+    let output = match extension.as_ref().map(|e| &e[..]) {
+        Some("wbfs") => WbfsWriter::create(path).map(Output::Wbfs),
+        Some("ciso") => File::create(path)
+            .map(|file| Output::Ciso(CisoWriter::new(BufWriter::with_capacity(4 << 20, file)))),
+        _ if split::is_split(path) => SplitFile::create(path, split::part_path).map(Output::Split),
+        _ => File::create(path).map(|file| Output::Iso(BufWriter::with_capacity(4 << 20, file))),
+    };
+    Ok(output.with_context(|_| format!("Couldn't create \"{}\".", path.display()))?)
+}
+
+impl Output {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Output::Iso(_) => "ISO",
+            Output::Split(_) => "split ISO",
+            Output::Ciso(_) => "CISO file",
+            Output::Wbfs(_) => "WBFS file",
+        }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Output::Iso(mut writer) => writer.flush(),
+            Output::Split(file) => {
+                let len = file.end();
+                file.finish(len)
+            }
+            Output::Ciso(writer) => writer.finish()?.flush(),
+            Output::Wbfs(writer) => writer.finish(),
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Output::Iso(ref mut writer) => writer.write(buf),
+            Output::Split(ref mut writer) => writer.write(buf),
+            Output::Ciso(ref mut writer) => writer.write(buf),
+            Output::Wbfs(ref mut writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Output::Iso(ref mut writer) => writer.flush(),
+            Output::Split(ref mut writer) => writer.flush(),
+            Output::Ciso(ref mut writer) => writer.flush(),
+            Output::Wbfs(ref mut writer) => writer.flush(),
+        }
+    }
+}
+
+impl Seek for Output {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match *self {
+            Output::Iso(ref mut writer) => writer.seek(pos),
+            Output::Split(ref mut writer) => writer.seek(pos),
+            Output::Ciso(ref mut writer) => writer.seek(pos),
+            Output::Wbfs(ref mut writer) => writer.seek(pos),
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Based on https://github.com/dolphin-emu/dolphin/blob/master/Source/Core/DiscIO/CompressedBlob.h
+//!
+//! GCZ files are Dolphin's compressed disc images. The disc is split into
+//! blocks that are compressed with zlib on their own, unless compressing
+//! them doesn't make them any smaller. A table after the header points to
+//! each block, so the disc can be read at any position.
+
+use byteorder::{ByteOrder, LE};
+use failure::Error;
+use flate2::read::ZlibDecoder;
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom};
+
+const MAGIC: u32 = 0xB10B_C001;
+const HEADER_LEN: usize = 0x20;
+
+const OFFSET_COMPRESSED_DATA_SIZE: usize = 0x08;
+const OFFSET_DATA_SIZE: usize = 0x10;
+const OFFSET_BLOCK_SIZE: usize = 0x18;
+const OFFSET_NUM_BLOCKS: usize = 0x1C;
+
+/// Set in the pointer of a block that is stored uncompressed.
+const UNCOMPRESSED_FLAG: u64 = 1 << 63;
+/// The table of block pointers is followed by an Adler-32 hash per block.
+const BLOCK_ENTRY_LEN: usize = 8 + 4;
+
+pub fn is_gcz(header: &[u8]) -> bool {
+    header.len() >= 4 && LE::read_u32(header) == MAGIC
+}
+
+pub struct GczReader<R> {
+    reader: R,
+    data_size: u64,
+    compressed_data_size: u64,
+    block_size: usize,
+    /// Relative to the start of the compressed blocks.
+    block_pointers: Vec<u64>,
+    blocks_offset: u64,
+    position: u64,
+    block_index: Option<usize>,
+    block: Vec<u8>,
+}
+
+impl<R: Read + Seek> GczReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let mut header = [0; HEADER_LEN];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut header)?;
+        ensure!(is_gcz(&header), "The file is not a GCZ file");
+
+        let block_size = LE::read_u32(&header[OFFSET_BLOCK_SIZE..]) as usize;
+        let num_blocks = LE::read_u32(&header[OFFSET_NUM_BLOCKS..]) as usize;
+        ensure!(block_size > 0, "The GCZ file has no block size");
+
+        let mut table = vec![0; 8 * num_blocks];
+        reader
+            .read_exact(&mut table)
+            .map_err(|_| format_err!("The block table of the GCZ file is truncated"))?;
+        let block_pointers = table.chunks(8).map(LE::read_u64).collect();
+
+        Ok(Self {
+            reader,
+            data_size: LE::read_u64(&header[OFFSET_DATA_SIZE..]),
+            compressed_data_size: LE::read_u64(&header[OFFSET_COMPRESSED_DATA_SIZE..]),
+            block_size,
+            block_pointers,
+            blocks_offset: (HEADER_LEN + BLOCK_ENTRY_LEN * num_blocks) as u64,
+            position: 0,
+            block_index: None,
+            block: Vec::with_capacity(block_size),
+        })
+    }
+
+    fn read_block(&mut self, index: usize) -> io::Result<()> {
+        let pointer = self.block_pointers[index];
+        let start = pointer & !UNCOMPRESSED_FLAG;
+        let end = match self.block_pointers.get(index + 1) {
+            Some(&next) => next & !UNCOMPRESSED_FLAG,
+            None => self.compressed_data_size,
+        };
+        if end < start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "The block table of the GCZ file is invalid",
+            ));
+        }
+
+        let mut stored = vec![0; (end - start) as usize];
+        self.reader
+            .seek(SeekFrom::Start(self.blocks_offset + start))?;
+        self.reader.read_exact(&mut stored)?;
+
+        self.block.clear();
+        if pointer & UNCOMPRESSED_FLAG != 0 {
+            self.block.append(&mut stored);
+        } else {
+            ZlibDecoder::new(&stored[..]).read_to_end(&mut self.block)?;
+        }
+        // The last block is padded to the block size as well
+        self.block.resize(self.block_size, 0);
+
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for GczReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.data_size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let block_index = (self.position / self.block_size as u64) as usize;
+        if block_index >= self.block_pointers.len() {
+            return Ok(0);
+        }
+        if self.block_index != Some(block_index) {
+            self.block_index = None;
+            self.read_block(block_index)?;
+            self.block_index = Some(block_index);
+        }
+
+        let offset = (self.position % self.block_size as u64) as usize;
+        let remaining = self.data_size - self.position;
+        let data = &self.block[offset..];
+        let len = cmp::min(cmp::min(buf.len(), data.len()) as u64, remaining) as usize;
+        buf[..len].copy_from_slice(&data[..len]);
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<R> Seek for GczReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.data_size as i64 + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+        if position < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seeked before the start of the disc",
+            ));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
@@ -0,0 +1,103 @@
+use super::consts::*;
+use byteorder::{ByteOrder, BE};
+use failure::Error;
+use std::str;
+
+pub const GAMECUBE_MAGIC: u32 = 0xC233_9F3D;
+pub const WII_MAGIC: u32 = 0x5D1C_9EA3;
+
+const OFFSET_GAME_CODE: usize = 0x000;
+const OFFSET_MAKER_CODE: usize = 0x004;
+const OFFSET_DISC_NUMBER: usize = 0x006;
+const OFFSET_VERSION: usize = 0x007;
+const OFFSET_AUDIO_STREAMING: usize = 0x008;
+const OFFSET_STREAM_BUFFER_SIZE: usize = 0x009;
+const OFFSET_WII_MAGIC: usize = 0x018;
+const OFFSET_GAMECUBE_MAGIC: usize = 0x01C;
+const OFFSET_GAME_NAME: usize = 0x020;
+const GAME_NAME_LEN: usize = 0x3E0;
+const OFFSET_MAX_FST_SIZE: usize = 0x42C;
+
+// bi2.bin follows boot.bin at 0x440
+pub const OFFSET_SIMULATED_MEMORY_SIZE: usize = 0x444;
+pub const OFFSET_ARGUMENT_OFFSET: usize = 0x448;
+pub const OFFSET_DEBUG_FLAG: usize = 0x44C;
+pub const OFFSET_COUNTRY_CODE: usize = 0x458;
+
+/// The disc header, also known as boot.bin.
+pub struct Header {
+    pub game_code: [u8; 4],
+    pub maker_code: [u8; 2],
+    pub disc_number: u8,
+    pub version: u8,
+    pub audio_streaming: bool,
+    pub stream_buffer_size: u8,
+    pub game_name: String,
+    pub is_wii: bool,
+    pub dol_offset: u32,
+    pub fst_offset: u32,
+    pub fst_size: u32,
+    pub max_fst_size: u32,
+}
+
+/// Wii discs store offsets and sizes divided by 4.
+pub fn offset_shift(header: &[u8]) -> u32 {
+    if header.len() >= OFFSET_WII_MAGIC + 4
+        && BE::read_u32(&header[OFFSET_WII_MAGIC..]) == WII_MAGIC
+    {
+        2
+    } else {
+        0
+    }
+}
+
+impl Header {
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        ensure!(
+            data.len() >= HEADER_LENGTH,
+            "The image is too small to be a GameCube or Wii disc"
+        );
+        let is_wii = BE::read_u32(&data[OFFSET_WII_MAGIC..]) == WII_MAGIC;
+        ensure!(
+            is_wii || BE::read_u32(&data[OFFSET_GAMECUBE_MAGIC..]) == GAMECUBE_MAGIC,
+            "The image is neither a GameCube nor a Wii disc. Triforce images need to be \
+             decrypted first."
+        );
+        let shift = offset_shift(data);
+
+        let mut game_code = [0; 4];
+        game_code.copy_from_slice(&data[OFFSET_GAME_CODE..][..4]);
+        let mut maker_code = [0; 2];
+        maker_code.copy_from_slice(&data[OFFSET_MAKER_CODE..][..2]);
+
+        let game_name = &data[OFFSET_GAME_NAME..][..GAME_NAME_LEN];
+        let game_name_len = game_name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(GAME_NAME_LEN);
+        let game_name = String::from_utf8_lossy(&game_name[..game_name_len]).into_owned();
+
+        Ok(Header {
+            game_code,
+            maker_code,
+            disc_number: data[OFFSET_DISC_NUMBER],
+            version: data[OFFSET_VERSION],
+            audio_streaming: data[OFFSET_AUDIO_STREAMING] != 0,
+            stream_buffer_size: data[OFFSET_STREAM_BUFFER_SIZE],
+            game_name,
+            is_wii,
+            dol_offset: BE::read_u32(&data[OFFSET_DOL_OFFSET..]) << shift,
+            fst_offset: BE::read_u32(&data[OFFSET_FST_OFFSET..]) << shift,
+            fst_size: BE::read_u32(&data[OFFSET_FST_SIZE..]) << shift,
+            max_fst_size: BE::read_u32(&data[OFFSET_MAX_FST_SIZE..]) << shift,
+        })
+    }
+
+    /// The six character game ID, like GZLE01.
+    pub fn game_id(&self) -> String {
+        let mut id = String::with_capacity(6);
+        id.push_str(str::from_utf8(&self.game_code).unwrap_or("????"));
+        id.push_str(str::from_utf8(&self.maker_code).unwrap_or("??"));
+        id
+    }
+}
@@ -0,0 +1,238 @@
+//! Nintendo's discs fill the gaps between the files with pseudo-random junk
+//! instead of zeros. The junk comes from a lagged Fibonacci generator that is
+//! seeded with the game ID, the disc number and the block of 32 KiB it fills,
+//! so it can be generated again to rebuild the disc byte for byte. The bytes
+//! up to the next multiple of 4 after a file are zeros. RVZ files store the
+//! seed of the generator instead of the junk itself.
+
+use byteorder::{ByteOrder, BE};
+use std::cmp;
+use std::io::{self, Write};
+
+pub const BLOCK_LEN: usize = 0x8000;
+
+const LAG_K: usize = 521;
+const LAG_J: usize = 32;
+const SEED_LEN: usize = 17;
+/// The seed is stored as big-endian words.
+pub const SEED_SIZE: usize = 4 * SEED_LEN;
+
+pub struct Junk {
+    game_id: [u8; 4],
+    disc_number: u8,
+    block: Option<u64>,
+    data: Vec<u8>,
+}
+
+impl Junk {
+    /// The game ID and the disc number are taken from the disc header.
+    pub fn new(header: &[u8]) -> Self {
+        Junk {
+            game_id: [header[0], header[1], header[2], header[3]],
+            disc_number: header[6],
+            block: None,
+            data: vec![0; BLOCK_LEN],
+        }
+    }
+
+    /// Writes the gap from the end of a file to the offset of the next one.
+    pub fn write_gap<W: Write>(&mut self, writer: &mut W, start: u64, end: u64) -> io::Result<()> {
+        let junk_start = ((start + 3) & !3).min(end);
+        writer.write_all(&[0; 3][..(junk_start - start) as usize])?;
+
+        let mut position = junk_start;
+        while position < end {
+            let block = position / BLOCK_LEN as u64;
+            let block_end = ((block + 1) * BLOCK_LEN as u64).min(end);
+            self.generate(block);
+            let index = (position % BLOCK_LEN as u64) as usize;
+            writer.write_all(&self.data[index..index + (block_end - position) as usize])?;
+            position = block_end;
+        }
+
+        Ok(())
+    }
+
+    fn generate(&mut self, block: u64) {
+        if self.block == Some(block) {
+            return;
+        }
+        self.block = Some(block);
+        generate(self.seed(block), 0, &mut self.data);
+    }
+
+    fn seed(&self, block: u64) -> [u32; LAG_K] {
+        let id = self.game_id;
+        let seed = BE::read_u32(&[
+            id[2],
+            id[1],
+            id[3].wrapping_add(id[2]),
+            id[0].wrapping_add(id[1]),
+        ]) ^ u32::from(self.disc_number);
+
+        let mut buffer = [0u32; LAG_K];
+        let mut n = seed.wrapping_mul(0x0260_BCD5) ^ (block as u32).wrapping_mul(0x1EF2_9123);
+        for value in &mut buffer[..SEED_LEN] {
+            for _ in 0..LAG_J {
+                n = n.wrapping_mul(0x5D58_8B65).wrapping_add(1);
+                *value = (*value >> 1) | (n & 0x8000_0000);
+            }
+        }
+        buffer[16] ^= (buffer[0] >> 9) ^ (buffer[16] << 23);
+        buffer
+    }
+}
+
+/// Fills `out` with the junk of a seed, starting `skip` bytes into it.
+pub fn fill(seed: &[u8], skip: usize, out: &mut [u8]) {
+    let mut buffer = [0u32; LAG_K];
+    for (value, bytes) in buffer[..SEED_LEN].iter_mut().zip(seed.chunks(4)) {
+        *value = BE::read_u32(bytes);
+    }
+    generate(buffer, skip, out);
+}
+
+/// Generates the junk from the first 17 words of the buffer.
+fn generate(mut buffer: [u32; LAG_K], skip: usize, out: &mut [u8]) {
+    for i in SEED_LEN..LAG_K {
+        buffer[i] = (buffer[i - 17] << 23) ^ (buffer[i - 16] >> 9) ^ buffer[i - 1];
+    }
+    // The third byte of each word is taken two bits further up.
+    for value in buffer.iter_mut() {
+        *value = (*value & 0xFF00_FFFF) | ((*value >> 2) & 0x00FF_0000);
+    }
+    for _ in 0..4 + skip / (4 * LAG_K) {
+        forward(&mut buffer);
+    }
+
+    let mut bytes = [0; 4 * LAG_K];
+    let mut index = skip % bytes.len();
+    let mut written = 0;
+    while written < out.len() {
+        for (bytes, &value) in bytes.chunks_mut(4).zip(buffer.iter()) {
+            BE::write_u32(bytes, value);
+        }
+        let len = cmp::min(bytes.len() - index, out.len() - written);
+        out[written..][..len].copy_from_slice(&bytes[index..][..len]);
+        written += len;
+        index = 0;
+        forward(&mut buffer);
+    }
+}
+
+fn forward(buffer: &mut [u32; LAG_K]) {
+    for i in 0..LAG_J {
+        buffer[i] ^= buffer[i + LAG_K - LAG_J];
+    }
+    for i in LAG_J..LAG_K {
+        buffer[i] ^= buffer[i - LAG_J];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+    use std::io::{Read, Seek, SeekFrom};
+
+    fn gap(header: &[u8], start: u64, end: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        Junk::new(header).write_gap(&mut out, start, end).unwrap();
+        out
+    }
+
+    #[test]
+    fn pads_files_to_words_with_zeros() {
+        let header = b"GALE01\0\0";
+        let junk = gap(header, 0x1001, 0x1010);
+        assert_eq!(junk.len(), 0xF);
+        assert_eq!(&junk[..3], &[0; 3]);
+        assert_eq!(&junk[3..], &gap(header, 0x1004, 0x1010)[..]);
+        assert!(junk[3..].iter().any(|&b| b != 0));
+        assert_eq!(gap(header, 0x1001, 0x1002), [0]);
+    }
+
+    #[test]
+    fn continues_the_junk_across_blocks() {
+        let header = b"GALE01\0\0";
+        let start = BLOCK_LEN as u64 - 0x100;
+        let end = BLOCK_LEN as u64 + 0x100;
+        let junk = gap(header, start, end);
+        assert_eq!(&junk[..0x100], &gap(header, start, BLOCK_LEN as u64)[..]);
+        assert_eq!(&junk[0x100..], &gap(header, BLOCK_LEN as u64, end)[..]);
+        assert_ne!(&junk[..0x100], &junk[0x100..]);
+    }
+
+    #[test]
+    fn depends_on_the_game_and_the_disc() {
+        let junk = gap(b"GALE01\0\0", 0, 0x100);
+        assert_ne!(junk, gap(b"GALE01\x01\0", 0, 0x100));
+        assert_ne!(junk, gap(b"GALP01\0\0", 0, 0x100));
+        assert_eq!(junk, gap(b"GALE99\0\0", 0, 0x100));
+    }
+
+    #[test]
+    fn generates_the_junk_of_a_stored_seed() {
+        let header = b"GALE01\0\0";
+        let mut seed = [0; SEED_SIZE];
+        for (bytes, &value) in seed.chunks_mut(4).zip(Junk::new(header).seed(3).iter()) {
+            BE::write_u32(bytes, value);
+        }
+        let start = 3 * BLOCK_LEN as u64;
+        for &(skip, len) in &[(0, BLOCK_LEN), (0x124, 0x200), (0x7000, 0x1000)] {
+            let mut junk = vec![0; len];
+            fill(&seed, skip, &mut junk);
+            let skip = skip as u64;
+            assert_eq!(junk, gap(header, start + skip, start + skip + len as u64));
+        }
+    }
+
+    /// No disc image can be checked into the repository, so the junk of a real
+    /// GameCube disc is only compared when `JUNK_TEST_DISC` points to a plain
+    /// ISO dump of one.
+    #[test]
+    #[ignore]
+    fn matches_the_junk_of_a_real_disc() {
+        let path = env::var("JUNK_TEST_DISC").expect("JUNK_TEST_DISC isn't set");
+        let mut disc = File::open(path).unwrap();
+        let mut header = vec![0; 0x440];
+        disc.read_exact(&mut header).unwrap();
+        let fst_offset = BE::read_u32(&header[0x424..]);
+        let fst_size = BE::read_u32(&header[0x428..]);
+
+        let mut fst = vec![0; fst_size as usize];
+        disc.seek(SeekFrom::Start(u64::from(fst_offset))).unwrap();
+        disc.read_exact(&mut fst).unwrap();
+        let count = BE::read_u32(&fst[8..]) as usize;
+        let mut files = fst[..0xC * count]
+            .chunks(0xC)
+            .filter(|entry| entry[0] == 0)
+            .map(|entry| {
+                let offset = u64::from(BE::read_u32(&entry[4..]));
+                (offset, offset + u64::from(BE::read_u32(&entry[8..])))
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+
+        let mut compared = 0;
+        for pair in files.windows(2) {
+            let (start, end) = (pair[0].1, pair[1].0);
+            if end <= start {
+                continue;
+            }
+            let end = end.min(start + BLOCK_LEN as u64);
+            let mut expected = vec![0; (end - start) as usize];
+            disc.seek(SeekFrom::Start(start)).unwrap();
+            disc.read_exact(&mut expected).unwrap();
+            assert!(
+                gap(&header, start, end) == expected,
+                "The junk from {:#x} to {:#x} differs",
+                start,
+                end
+            );
+            compared += 1;
+        }
+        assert!(compared > 0, "The disc has no gaps between its files");
+    }
+}
@@ -1,8 +1,19 @@
 //! Based on http://www.gc-forever.com/yagcd/chap13.html#sec13
 //! and https://github.com/LordNed/WArchive-Tools
 
+pub mod apploader;
+pub mod ciso;
+pub mod disc;
+pub mod gcz;
+pub mod header;
+pub mod junk;
 pub mod reader;
+pub mod split;
+pub mod triforce;
 pub mod virtual_file_system;
+pub mod wbfs;
+pub mod wia;
+pub mod wii;
 pub mod writer;
 
 pub mod consts {
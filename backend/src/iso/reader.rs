@@ -1,50 +1,139 @@
+use super::apploader::Apploader;
+use super::header::{offset_shift, Header};
 use super::virtual_file_system::{Directory, File, Node};
 use super::{consts::*, FstEntry, FstNodeType};
 use byteorder::{ByteOrder, BE};
-use failure::{Error, ResultExt};
-use std::fs;
-use std::io::{Read, Result as IOResult};
-use std::path::Path;
+use dol;
+use failure::{err_msg, Error, ResultExt};
+use std::io::{Read, Seek, SeekFrom};
 use std::str;
 
-pub fn load_iso_buf<P: AsRef<Path>>(path: P) -> IOResult<Vec<u8>> {
-    let mut file = fs::File::open(path)?;
-    let len = file.metadata()?.len();
-    let mut buf = Vec::with_capacity(len as usize + 1);
-    file.read_to_end(&mut buf)?;
-    Ok(buf)
+/// The parts of the disc that are always kept in memory. All the other files
+/// are only referenced by their location on the disc.
+pub struct SystemData {
+    pub header: Vec<u8>,
+    pub apploader: Vec<u8>,
+    pub dol: Vec<u8>,
+    pub fst: Vec<u8>,
+    pub disc_len: u64,
+    shift: u32,
 }
 
-pub fn load_iso<'a>(buf: &'a [u8]) -> Result<Directory<'a>, Error> {
-    let fst_offset = BE::read_u32(&buf[OFFSET_FST_OFFSET..]) as usize;
-    let mut pos = fst_offset;
-    let num_entries = BE::read_u32(&buf[fst_offset + 8..]) as usize;
+impl SystemData {
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self, Error> {
+        let disc_len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut header = vec![0; HEADER_LENGTH];
+        reader
+            .read_exact(&mut header)
+            .context("The image is too small to be a GameCube or Wii disc")?;
+        let parsed_header = Header::parse(&header).context("Couldn't parse the disc header")?;
+        let dol_offset = parsed_header.dol_offset as u64;
+        let fst_offset = parsed_header.fst_offset as u64;
+        let fst_size = parsed_header.fst_size as u64;
+
+        ensure!(
+            dol_offset >= HEADER_LENGTH as u64 && dol_offset + dol::HEADER_LEN as u64 <= disc_len,
+            "The DOL offset {:#x} is invalid",
+            dol_offset
+        );
+        let mut apploader = vec![0; dol_offset as usize - HEADER_LENGTH];
+        reader
+            .read_exact(&mut apploader)
+            .context("Couldn't read the apploader")?;
+        let apploader_len = Apploader::parse(&apploader)
+            .context("Couldn't parse the apploader")?
+            .len();
+        apploader.truncate(apploader_len);
+
+        let mut dol = vec![0; dol::HEADER_LEN];
+        reader
+            .read_exact(&mut dol)
+            .context("Couldn't read the DOL")?;
+        let dol_size =
+            dol::file_len(&dol).ok_or_else(|| err_msg("The DOL's header is truncated"))?;
+        ensure!(
+            dol_offset + dol_size as u64 <= disc_len,
+            "The DOL's sections are out of bounds"
+        );
+        dol.resize(dol_size, 0);
+        reader
+            .read_exact(&mut dol[dol::HEADER_LEN..])
+            .context("Couldn't read the DOL")?;
+
+        ensure!(
+            fst_offset + fst_size <= disc_len && fst_size >= 0xC,
+            "The FST at {:#x} is out of bounds",
+            fst_offset
+        );
+        let mut fst = vec![0; fst_size as usize];
+        reader.seek(SeekFrom::Start(fst_offset))?;
+        reader
+            .read_exact(&mut fst)
+            .context("Couldn't read the FST")?;
+
+        Ok(Self {
+            shift: offset_shift(&header),
+            header,
+            apploader,
+            dol,
+            fst,
+            disc_len,
+        })
+    }
+}
+
+pub fn load_iso<'a>(system_data: &'a SystemData) -> Result<Directory<'a>, Error> {
+    let shift = system_data.shift;
+    let disc_len = system_data.disc_len;
+
+    let fst = &system_data.fst;
+    let num_entries = BE::read_u32(&fst[8..]) as usize;
     let string_table_offset = num_entries * 0xC;
+    ensure!(
+        string_table_offset <= fst.len(),
+        "The FST has more entries than fit into it"
+    );
+    let string_table = &fst[string_table_offset..];
 
     let mut fst_entries = Vec::with_capacity(num_entries);
-    for _ in 0..num_entries {
-        let kind = if buf[pos] == 0 {
+    for (index, entry) in fst[..string_table_offset].chunks(0xC).enumerate() {
+        let kind = if entry[0] == 0 {
             FstNodeType::File
         } else {
             FstNodeType::Directory
         };
-        pos += 2;
 
-        let cur_pos = pos;
-        let string_offset = BE::read_u16(&buf[pos..]) as usize;
+        let string_offset = BE::read_u16(&entry[2..]) as usize;
+        ensure!(
+            string_offset < string_table.len(),
+            "The name of FST entry {} is out of bounds",
+            index
+        );
+        let name = &string_table[string_offset..];
+        let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        let relative_file_name =
+            str::from_utf8(&name[..end]).context("Couldn't parse the relative file name")?;
 
-        pos = string_offset + string_table_offset + fst_offset;
-        let mut end = pos;
-        while buf[end] != 0 {
-            end += 1;
+        let mut file_offset_parent_dir = BE::read_u32(&entry[4..]) as usize;
+        let file_size_next_dir_index = BE::read_u32(&entry[8..]) as usize;
+        if kind == FstNodeType::File {
+            file_offset_parent_dir <<= shift;
         }
-        let relative_file_name =
-            str::from_utf8(&buf[pos..end]).context("Couldn't parse the relative file name")?;
 
-        pos = cur_pos + 2;
-        let file_offset_parent_dir = BE::read_u32(&buf[pos..]) as usize;
-        let file_size_next_dir_index = BE::read_u32(&buf[pos + 4..]) as usize;
-        pos += 8;
+        match kind {
+            FstNodeType::File => ensure!(
+                (file_offset_parent_dir + file_size_next_dir_index) as u64 <= disc_len,
+                "The file \"{}\" is out of bounds",
+                relative_file_name
+            ),
+            FstNodeType::Directory => ensure!(
+                file_size_next_dir_index > index && file_size_next_dir_index <= num_entries,
+                "The directory \"{}\" has an invalid FST entry",
+                relative_file_name
+            ),
+        }
 
         fst_entries.push(FstEntry {
             kind,
@@ -54,31 +143,30 @@ pub fn load_iso<'a>(buf: &'a [u8]) -> Result<Directory<'a>, Error> {
             file_name_offset: 0,
         });
     }
+    ensure!(
+        fst_entries.first().map(|e| e.kind) == Some(FstNodeType::Directory),
+        "The FST's root entry is not a directory"
+    );
 
     let mut root_dir = Directory::new("root");
     let mut sys_data = Directory::new("&&systemdata");
 
     sys_data
         .children
-        .push(Node::File(File::new("iso.hdr", &buf[..HEADER_LENGTH])));
-
-    let dol_offset = BE::read_u32(&buf[OFFSET_DOL_OFFSET..]) as usize;
+        .push(Node::File(File::new("iso.hdr", &*system_data.header)));
 
     sys_data.children.push(Node::File(File::new(
         "AppLoader.ldr",
-        &buf[HEADER_LENGTH..dol_offset],
+        &*system_data.apploader,
     )));
 
-    sys_data.children.push(Node::File(File::new(
-        "Start.dol",
-        &buf[dol_offset..fst_offset],
-    )));
+    sys_data
+        .children
+        .push(Node::File(File::new("Start.dol", &*system_data.dol)));
 
-    let fst_size = BE::read_u32(&buf[OFFSET_FST_SIZE..]) as usize;
-    sys_data.children.push(Node::File(File::new(
-        "Game.toc",
-        &buf[fst_offset..][..fst_size],
-    )));
+    sys_data
+        .children
+        .push(Node::File(File::new("Game.toc", &**fst)));
 
     root_dir.children.push(Node::Directory(Box::new(sys_data)));
 
@@ -90,12 +178,12 @@ pub fn load_iso<'a>(buf: &'a [u8]) -> Result<Directory<'a>, Error> {
             let mut dir = Directory::new(entry.relative_file_name);
 
             while count < entry.file_size_next_dir_index - 1 {
-                count = get_dir_structure_recursive(count + 1, &fst_entries, &mut dir, buf);
+                count = get_dir_structure_recursive(count + 1, &fst_entries, &mut dir);
             }
 
             root_dir.children.push(Node::Directory(Box::new(dir)));
         } else {
-            let file = get_file_data(&fst_entries[count], buf);
+            let file = get_file_data(&fst_entries[count]);
             root_dir.children.push(Node::File(file));
         }
         count += 1;
@@ -108,7 +196,6 @@ fn get_dir_structure_recursive<'a>(
     mut cur_index: usize,
     fst: &[FstEntry<'a>],
     parent_dir: &mut Directory<'a>,
-    buf: &'a [u8],
 ) -> usize {
     let entry = &fst[cur_index];
 
@@ -116,19 +203,22 @@ fn get_dir_structure_recursive<'a>(
         let mut dir = Directory::new(entry.relative_file_name);
 
         while cur_index < entry.file_size_next_dir_index - 1 {
-            cur_index = get_dir_structure_recursive(cur_index + 1, fst, &mut dir, buf);
+            cur_index = get_dir_structure_recursive(cur_index + 1, fst, &mut dir);
         }
 
         parent_dir.children.push(Node::Directory(Box::new(dir)));
     } else {
-        let file = get_file_data(entry, buf);
+        let file = get_file_data(entry);
         parent_dir.children.push(Node::File(file));
     }
 
     cur_index
 }
 
-fn get_file_data<'a>(fst_data: &FstEntry<'a>, buf: &'a [u8]) -> File<'a> {
-    let data = &buf[fst_data.file_offset_parent_dir..][..fst_data.file_size_next_dir_index];
-    File::new(fst_data.relative_file_name, data)
+fn get_file_data<'a>(fst_data: &FstEntry<'a>) -> File<'a> {
+    File::on_disc(
+        fst_data.relative_file_name,
+        fst_data.file_offset_parent_dir as u64,
+        fst_data.file_size_next_dir_index as u64,
+    )
 }
@@ -0,0 +1,212 @@
+//! Discs can be spread over several files, so none of them exceeds the file
+//! size limit of FAT32, which the drives of USB loaders are usually formatted
+//! with. Split ISOs are named `.part0.iso`, `.part1.iso` and so on.
+
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// FAT32 drives can't store files of 4 GiB or larger.
+pub const SPLIT_SIZE: u64 = (4 << 30) - 0x8000;
+
+const FIRST_PART: &str = ".part0";
+
+/// Whether the path is the first part of a split ISO.
+pub fn is_split(path: &Path) -> bool {
+    path.file_stem()
+        .and_then(|s| s.to_str())
+        .map_or(false, |s| s.ends_with(FIRST_PART))
+}
+
+/// The path of the part of a split ISO with the given index.
+pub fn part_path(path: &Path, index: usize) -> PathBuf {
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+    let stem = stem.trim_right_matches(FIRST_PART);
+    let mut file_name = format!("{}.part{}", stem, index);
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        file_name.push('.');
+        file_name.push_str(extension);
+    }
+    path.with_file_name(file_name)
+}
+
+fn seek_position(position: u64, pos: SeekFrom) -> io::Result<u64> {
+    let position = match pos {
+        SeekFrom::Start(offset) => offset as i64,
+        SeekFrom::Current(offset) => position as i64 + offset,
+        SeekFrom::End(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Split files can't be seeked from the end",
+            ))
+        }
+    };
+    if position < 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Seeked before the start of the split file",
+        ));
+    }
+    Ok(position as u64)
+}
+
+/// Writes a file in parts of `SPLIT_SIZE`. The paths of the parts are
+/// determined by `part_path`, with the first part at index 0.
+pub struct SplitFile {
+    path: PathBuf,
+    part_path: fn(&Path, usize) -> PathBuf,
+    files: Vec<(BufWriter<File>, u64)>,
+    position: u64,
+    end: u64,
+}
+
+impl SplitFile {
+    pub fn create(path: &Path, part_path: fn(&Path, usize) -> PathBuf) -> io::Result<Self> {
+        // Parts of previous builds would be mistaken for parts of this one
+        let mut index = 1;
+        while fs::remove_file(part_path(path, index)).is_ok() {
+            index += 1;
+        }
+        let mut file = Self {
+            path: path.to_owned(),
+            part_path,
+            files: Vec::new(),
+            position: 0,
+            end: 0,
+        };
+        file.open(0)?;
+        Ok(file)
+    }
+
+    fn open(&mut self, index: usize) -> io::Result<()> {
+        while self.files.len() <= index {
+            let path = (self.part_path)(&self.path, self.files.len());
+            self.files
+                .push((BufWriter::with_capacity(4 << 20, File::create(path)?), 0));
+        }
+        Ok(())
+    }
+
+    /// The end of what has been written so far.
+    pub fn end(&self) -> u64 {
+        self.end
+    }
+
+    /// Sets the length of the whole file, padding it with zeros.
+    pub fn finish(mut self, len: u64) -> io::Result<()> {
+        let last = ((cmp::max(len, 1) - 1) / SPLIT_SIZE) as usize;
+        self.open(last)?;
+        for (index, (file, _)) in self.files.into_iter().enumerate() {
+            let file = file.into_inner().map_err(|e| e.into_error())?;
+            let file_len = cmp::min(len.saturating_sub(index as u64 * SPLIT_SIZE), SPLIT_SIZE);
+            if file.metadata()?.len() != file_len {
+                file.set_len(file_len)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for SplitFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let index = (self.position / SPLIT_SIZE) as usize;
+        let offset = self.position % SPLIT_SIZE;
+        self.open(index)?;
+        let (ref mut file, ref mut file_position) = self.files[index];
+        if *file_position != offset {
+            file.seek(SeekFrom::Start(offset))?;
+        }
+        let len = cmp::min(buf.len() as u64, SPLIT_SIZE - offset) as usize;
+        let len = file.write(&buf[..len])?;
+        *file_position = offset + len as u64;
+        self.position += len as u64;
+        self.end = cmp::max(self.end, self.position);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for &mut (ref mut file, _) in &mut self.files {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Seek for SplitFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(self.position, pos)?;
+        Ok(self.position)
+    }
+}
+
+/// Reads the parts of a split ISO as a single disc.
+pub struct SplitReader {
+    /// The parts with their offset in the disc and the position in them.
+    files: Vec<(BufReader<File>, u64, u64)>,
+    len: u64,
+    position: u64,
+}
+
+impl SplitReader {
+    /// Opens the first part and all the parts that follow it.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut files = Vec::new();
+        let mut len = 0;
+        loop {
+            let file = match File::open(part_path(path, files.len())) {
+                Ok(file) => file,
+                Err(_) if !files.is_empty() => break,
+                Err(e) => return Err(e),
+            };
+            let file_len = file.metadata()?.len();
+            files.push((BufReader::with_capacity(4 << 20, file), len, 0));
+            len += file_len;
+        }
+        Ok(Self {
+            files,
+            len,
+            position: 0,
+        })
+    }
+}
+
+impl Read for SplitReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let position = self.position;
+        let index = self
+            .files
+            .iter()
+            .rposition(|&(_, start, _)| start <= position)
+            .unwrap_or(0);
+        let end = self
+            .files
+            .get(index + 1)
+            .map_or(self.len, |&(_, start, _)| start);
+        let (ref mut file, ref start, ref mut file_position) = self.files[index];
+        let offset = position - *start;
+        if *file_position != offset {
+            file.seek(SeekFrom::Start(offset))?;
+        }
+        let len = cmp::min(buf.len() as u64, end - position) as usize;
+        let len = file.read(&mut buf[..len])?;
+        *file_position = offset + len as u64;
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl Seek for SplitReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::End(offset) => seek_position(self.len, SeekFrom::Current(offset))?,
+            pos => seek_position(self.position, pos)?,
+        };
+        Ok(self.position)
+    }
+}
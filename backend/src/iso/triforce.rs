@@ -0,0 +1,29 @@
+//! The Triforce arcade board runs games from discs that look like GameCube
+//! discs. Its media board loads them instead of the console's apploader, and
+//! identifies them by the boot.id in the root of the disc.
+//!
+//! Only the decrypted images are supported, which are built like any other
+//! GameCube disc. The boot.id and the game ID it refers to are kept as they
+//! are, as the media board wouldn't recognize the game otherwise. The images
+//! that are still encrypted with the key of the game's security chip, like
+//! the ones dumped from GD-ROMs, aren't GameCube discs at all and need to be
+//! decrypted first. Rebuilding their boot structures and encrypting them
+//! again is not supported, so the media board needs to load the decrypted
+//! image, like from a net boot.
+
+use super::virtual_file_system::Directory;
+use failure::{Error, ResultExt};
+use std::io::{Read, Seek};
+
+pub const BOOT_ID_PATH: &str = "boot.id";
+const BOOT_ID_MAGIC: &[u8] = b"BTID";
+
+/// Whether the disc has a boot.id for the media board.
+pub fn is_triforce<R: Read + Seek>(iso: &Directory, reader: &mut R) -> Result<bool, Error> {
+    let boot_id = match iso.resolve_path(BOOT_ID_PATH) {
+        Some(boot_id) => boot_id,
+        None => return Ok(false),
+    };
+    let boot_id = boot_id.read(reader).context("Couldn't read the boot.id")?;
+    Ok(boot_id.starts_with(BOOT_ID_MAGIC))
+}
@@ -1,4 +1,12 @@
+use failure::Error;
 use std::borrow::Cow;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// The file system of a disc. The files don't store their offsets on the
+/// disc, so they may be replaced, added or removed freely. The writer lays
+/// them out again, aligning each file to 32 bytes and each audio stream to
+/// 32 KiB.
+pub type Fst<'a> = Directory<'a>;
 
 #[derive(Debug)]
 pub enum Node<'a> {
@@ -54,6 +62,20 @@ impl<'a> Directory<'a> {
         }
     }
 
+    pub fn main_dol(&self) -> Option<&File<'a>> {
+        let sys_dir = self
+            .children
+            .iter()
+            .filter_map(|c| c.as_directory())
+            .find(|d| d.name == "&&systemdata")?;
+        let dol = sys_dir
+            .children
+            .iter()
+            .filter_map(|c| c.as_file())
+            .find(|f| f.name.ends_with(".dol"))?;
+        Some(dol)
+    }
+
     pub fn main_dol_mut(&mut self) -> Option<&mut File<'a>> {
         let sys_dir = self
             .children
@@ -100,6 +122,88 @@ impl<'a> Directory<'a> {
         None
     }
 
+    pub fn resolve_path_mut(&mut self, path: &str) -> Option<&mut File<'a>> {
+        let mut dir = self;
+        let mut segments = path.split('/').peekable();
+
+        while let Some(segment) = segments.next() {
+            if segments.peek().is_some() {
+                // Must be a folder
+                dir = dir
+                    .children
+                    .iter_mut()
+                    .filter_map(|c| c.as_directory_mut())
+                    .find(|d| d.name == segment)?;
+            } else {
+                return dir
+                    .children
+                    .iter_mut()
+                    .filter_map(|c| c.as_file_mut())
+                    .find(|f| f.name == segment);
+            }
+        }
+        None
+    }
+
+    /// Replaces the contents of an existing file.
+    pub fn replace_file<A: Into<Cow<'a, [u8]>>>(
+        &mut self,
+        path: &str,
+        data: A,
+    ) -> Result<(), Error> {
+        let file = self
+            .resolve_path_mut(path)
+            .ok_or_else(|| format_err!("The file \"{}\" doesn't exist on the disc", path))?;
+        file.data = FileData::Memory(data.into());
+        Ok(())
+    }
+
+    /// Adds a new file, creating all the directories leading up to it.
+    pub fn add_file<A: Into<Cow<'a, [u8]>>>(
+        &mut self,
+        path: &'a str,
+        data: A,
+    ) -> Result<(), Error> {
+        ensure!(
+            self.resolve_path(path).is_none(),
+            "The file \"{}\" already exists on the disc",
+            path
+        );
+        self.resolve_and_create_path(path).data = FileData::Memory(data.into());
+        Ok(())
+    }
+
+    /// Removes a file. Directories that end up empty are kept.
+    pub fn remove_file(&mut self, path: &str) -> Result<(), Error> {
+        let (dir_path, file_name) = match path.rfind('/') {
+            Some(index) => (Some(&path[..index]), &path[index + 1..]),
+            None => (None, path),
+        };
+
+        let mut dir = self;
+        if let Some(dir_path) = dir_path {
+            for segment in dir_path.split('/') {
+                dir = dir
+                    .children
+                    .iter_mut()
+                    .filter_map(|c| c.as_directory_mut())
+                    .find(|d| d.name == segment)
+                    .ok_or_else(|| {
+                        format_err!("The file \"{}\" doesn't exist on the disc", path)
+                    })?;
+            }
+        }
+
+        let index = dir
+            .children
+            .iter()
+            .position(|c| c.as_file().map_or(false, |f| f.name == file_name))
+            .ok_or_else(|| format_err!("The file \"{}\" doesn't exist on the disc", path))?;
+        dir.children.remove(index);
+
+        Ok(())
+    }
+
     // TODO NLL This is really bad
     pub fn resolve_and_create_path(&mut self, path: &'a str) -> &mut File<'a> {
         let mut splits = path.splitn(2, '/');
@@ -137,16 +241,72 @@ impl<'a> Directory<'a> {
     }
 }
 
+pub enum FileData<'a> {
+    /// The file is still stored on the original disc. It only gets copied
+    /// over when the disc gets written.
+    Disc {
+        offset: u64,
+        len: u64,
+    },
+    Memory(Cow<'a, [u8]>),
+}
+
+impl<'a> From<Vec<u8>> for FileData<'a> {
+    fn from(data: Vec<u8>) -> Self {
+        FileData::Memory(data.into())
+    }
+}
+
+impl<'a> From<&'a [u8]> for FileData<'a> {
+    fn from(data: &'a [u8]) -> Self {
+        FileData::Memory(data.into())
+    }
+}
+
+impl<'a> From<Cow<'a, [u8]>> for FileData<'a> {
+    fn from(data: Cow<'a, [u8]>) -> Self {
+        FileData::Memory(data)
+    }
+}
+
 pub struct File<'a> {
     pub name: &'a str,
-    pub data: Cow<'a, [u8]>,
+    pub data: FileData<'a>,
 }
 
 impl<'a> File<'a> {
     pub fn new<A: Into<Cow<'a, [u8]>>>(name: &'a str, data: A) -> File<'a> {
         Self {
             name,
-            data: data.into(),
+            data: FileData::Memory(data.into()),
+        }
+    }
+
+    pub fn on_disc(name: &'a str, offset: u64, len: u64) -> File<'a> {
+        Self {
+            name,
+            data: FileData::Disc { offset, len },
+        }
+    }
+
+    pub fn len(&self) -> u64 {
+        match self.data {
+            FileData::Disc { len, .. } => len,
+            FileData::Memory(ref data) => data.len() as u64,
+        }
+    }
+
+    /// Reads the contents of the file. Files that are still on the original
+    /// disc are read from the reader.
+    pub fn read<R: Read + Seek>(&self, reader: &mut R) -> io::Result<Cow<[u8]>> {
+        match self.data {
+            FileData::Disc { offset, len } => {
+                let mut data = vec![0; len as usize];
+                reader.seek(SeekFrom::Start(offset))?;
+                reader.read_exact(&mut data)?;
+                Ok(Cow::Owned(data))
+            }
+            FileData::Memory(ref data) => Ok(Cow::Borrowed(data)),
         }
     }
 }
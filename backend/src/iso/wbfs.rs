@@ -0,0 +1,181 @@
+//! Based on https://github.com/kwiirk/wbfs/blob/master/libwbfs/libwbfs.h
+//!
+//! WBFS files store a Wii disc in blocks of 2 MiB, so USB loaders can play
+//! it directly. A table maps the blocks of the disc to the blocks of the
+//! file, which lets unused blocks of the disc be left out. The first block of
+//! the file holds the WBFS header, the table and a bitmap of the free blocks.
+
+use super::split::SplitFile;
+use byteorder::{ByteOrder, BE};
+use std::cmp;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8] = b"WBFS";
+const HD_SECTOR_SIZE_SHIFT: u8 = 9;
+const HD_SECTOR_SIZE: usize = 1 << HD_SECTOR_SIZE_SHIFT;
+const WBFS_SECTOR_SIZE_SHIFT: u8 = 21;
+const WBFS_SECTOR_SIZE: u64 = 1 << WBFS_SECTOR_SIZE_SHIFT;
+const WII_SECTOR_SIZE: u64 = 0x8000;
+/// The number of Wii sectors on a dual layer disc.
+const WII_SECTORS_PER_DISC: u64 = 143_432 * 2;
+const BLOCKS_PER_DISC: usize = (WII_SECTORS_PER_DISC * WII_SECTOR_SIZE / WBFS_SECTOR_SIZE) as usize;
+/// The first block holds the headers, the others the blocks of the disc.
+const NUM_BLOCKS: usize = BLOCKS_PER_DISC + 1;
+
+const OFFSET_NUM_HD_SECTORS: usize = 0x04;
+const OFFSET_HD_SECTOR_SIZE_SHIFT: usize = 0x08;
+const OFFSET_WBFS_SECTOR_SIZE_SHIFT: usize = 0x09;
+const OFFSET_DISC_TABLE: usize = 0x0C;
+/// The first sector after the header describes the disc.
+const OFFSET_DISC_INFO: usize = HD_SECTOR_SIZE;
+const DISC_HEADER_COPY_LEN: usize = 0x100;
+
+/// The parts after the first one are named `.wbf1`, `.wbf2` and so on.
+pub fn part_path(path: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        path.to_owned()
+    } else {
+        path.with_extension(format!("wbf{}", index))
+    }
+}
+
+/// Writes a Wii disc into a WBFS file. Blocks of the disc that only contain
+/// zeros, like the padding after the partitions, are left out, as well as the
+/// ones that are known to be unused, like the padding between the files.
+pub struct WbfsWriter {
+    file: SplitFile,
+    /// The position in the disc.
+    position: u64,
+    /// The block of the file that stores each block of the disc. The blocks
+    /// that are left out are stored as 0.
+    blocks: Vec<u16>,
+    /// The blocks of the disc that nothing is read from.
+    unused_blocks: Vec<bool>,
+    num_used_blocks: usize,
+    disc_header: [u8; DISC_HEADER_COPY_LEN],
+}
+
+impl WbfsWriter {
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            file: SplitFile::create(path.as_ref(), part_path)?,
+            position: 0,
+            blocks: vec![0; BLOCKS_PER_DISC],
+            unused_blocks: vec![false; BLOCKS_PER_DISC],
+            num_used_blocks: 0,
+            disc_header: [0; DISC_HEADER_COPY_LEN],
+        })
+    }
+
+    /// Leaves out the blocks of the disc that are entirely within the unused
+    /// parts of it.
+    pub fn leave_out(&mut self, unused: &[Range<u64>]) {
+        for (index, is_unused) in self.unused_blocks.iter_mut().enumerate() {
+            let start = index as u64 * WBFS_SECTOR_SIZE;
+            let end = start + WBFS_SECTOR_SIZE;
+            // The unused parts are merged, so a block is either within one of
+            // them or it's used
+            *is_unused = unused.iter().any(|r| r.start <= start && end <= r.end);
+        }
+    }
+
+    /// Writes the headers into the first block of the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        let mut header = vec![0; WBFS_SECTOR_SIZE as usize];
+        header[..MAGIC.len()].copy_from_slice(MAGIC);
+        let num_hd_sectors = (NUM_BLOCKS as u32) << (WBFS_SECTOR_SIZE_SHIFT - HD_SECTOR_SIZE_SHIFT);
+        BE::write_u32(&mut header[OFFSET_NUM_HD_SECTORS..], num_hd_sectors);
+        header[OFFSET_HD_SECTOR_SIZE_SHIFT] = HD_SECTOR_SIZE_SHIFT;
+        header[OFFSET_WBFS_SECTOR_SIZE_SHIFT] = WBFS_SECTOR_SIZE_SHIFT;
+        // The file contains a single disc
+        header[OFFSET_DISC_TABLE] = 1;
+
+        header[OFFSET_DISC_INFO..][..DISC_HEADER_COPY_LEN].copy_from_slice(&self.disc_header);
+        let table = &mut header[OFFSET_DISC_INFO + DISC_HEADER_COPY_LEN..];
+        for (dst, &block) in table.chunks_mut(2).zip(&self.blocks) {
+            BE::write_u16(dst, block);
+        }
+
+        // The free blocks are marked in a bitmap at the end of the first
+        // block, with the first bit standing for the block after it
+        let bitmap_offset = (header.len() - NUM_BLOCKS / 8) / HD_SECTOR_SIZE * HD_SECTOR_SIZE;
+        for block in self.num_used_blocks + 1..NUM_BLOCKS {
+            let bit = block - 1;
+            header[bitmap_offset + bit / 32 * 4 + 3 - bit % 32 / 8] |= 1 << (bit % 8);
+        }
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.flush()?;
+        self.file
+            .finish((self.num_used_blocks as u64 + 1) * WBFS_SECTOR_SIZE)
+    }
+}
+
+impl Write for WbfsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.position < DISC_HEADER_COPY_LEN as u64 {
+            let offset = self.position as usize;
+            let len = cmp::min(buf.len(), DISC_HEADER_COPY_LEN - offset);
+            self.disc_header[offset..][..len].copy_from_slice(&buf[..len]);
+        }
+
+        let index = (self.position / WBFS_SECTOR_SIZE) as usize;
+        let offset = self.position % WBFS_SECTOR_SIZE;
+        let len = cmp::min(buf.len() as u64, WBFS_SECTOR_SIZE - offset) as usize;
+        let buf = &buf[..len];
+        if index >= BLOCKS_PER_DISC {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "The disc is too large for a WBFS file",
+            ));
+        }
+
+        if self.blocks[index] == 0 {
+            if self.unused_blocks[index] || buf.iter().all(|&b| b == 0) {
+                self.position += len as u64;
+                return Ok(len);
+            }
+            self.num_used_blocks += 1;
+            self.blocks[index] = self.num_used_blocks as u16;
+        }
+
+        let file_position = self.blocks[index] as u64 * WBFS_SECTOR_SIZE + offset;
+        self.file.seek(SeekFrom::Start(file_position))?;
+        self.file.write_all(buf)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for WbfsWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+            SeekFrom::End(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "WBFS files can't be seeked from the end",
+                ))
+            }
+        };
+        if position < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seeked before the start of the disc",
+            ));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
@@ -0,0 +1,1020 @@
+//! Based on https://github.com/dolphin-emu/dolphin/blob/master/Source/Core/DiscIO/WIABlob.h
+//!
+//! WIA and RVZ files are compressed disc images. The disc is split into
+//! chunks that are compressed on their own, with the first 0x80 bytes of the
+//! disc kept in the header. The partitions of Wii discs are stored decrypted
+//! and without their hashes, so they are hashed and encrypted again when they
+//! are read, with the few hashes that don't match their data stored as
+//! exceptions. RVZ files additionally store the seed of the junk between the
+//! files instead of the junk itself.
+
+use super::junk;
+use super::wii::{
+    self, CLUSTERS_PER_GROUP, CLUSTER_DATA_SIZE, CLUSTER_HASH_SIZE, CLUSTER_SIZE, GROUP_DATA_SIZE,
+    GROUP_SIZE,
+};
+#[cfg(not(target_arch = "wasm32"))]
+use byteorder::LE;
+use byteorder::{ByteOrder, BE};
+#[cfg(not(target_arch = "wasm32"))]
+use bzip2::read::BzDecoder;
+use failure::Error;
+use std::cmp;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+#[cfg(not(target_arch = "wasm32"))]
+use xz2::read::XzDecoder;
+#[cfg(not(target_arch = "wasm32"))]
+use xz2::stream::{Filters, LzmaOptions, Stream};
+#[cfg(not(target_arch = "wasm32"))]
+use zstd;
+
+const WIA_MAGIC: &[u8] = b"WIA\x01";
+const RVZ_MAGIC: &[u8] = b"RVZ\x01";
+/// The newest version of the format that can be read.
+const VERSION: u32 = 0x0100_0000;
+
+const FILE_HEAD_LEN: usize = 0x48;
+const OFFSET_VERSION_COMPATIBLE: usize = 0x08;
+const OFFSET_DISC_SIZE: usize = 0x0C;
+const OFFSET_ISO_FILE_SIZE: usize = 0x24;
+
+// The offsets of the disc struct, which follows the file head
+const OFFSET_DISC_TYPE: usize = 0x00;
+const OFFSET_COMPRESSION: usize = 0x04;
+const OFFSET_CHUNK_SIZE: usize = 0x0C;
+const OFFSET_DISC_HEADER: usize = 0x10;
+const OFFSET_NUM_PARTITIONS: usize = 0x90;
+const OFFSET_PARTITION_ENTRY_SIZE: usize = 0x94;
+const OFFSET_PARTITION_ENTRIES: usize = 0x98;
+const OFFSET_NUM_RAW_DATA: usize = 0xB4;
+const OFFSET_RAW_DATA_ENTRIES: usize = 0xB8;
+const OFFSET_RAW_DATA_ENTRIES_SIZE: usize = 0xC0;
+const OFFSET_NUM_GROUPS: usize = 0xC4;
+const OFFSET_GROUP_ENTRIES: usize = 0xC8;
+const OFFSET_GROUP_ENTRIES_SIZE: usize = 0xD0;
+const OFFSET_COMPRESSOR_DATA_SIZE: usize = 0xD4;
+const OFFSET_COMPRESSOR_DATA: usize = 0xD5;
+const DISC_LEN: usize = 0xDC;
+const COMPRESSOR_DATA_LEN: usize = 7;
+
+const DISC_TYPE_GAMECUBE: u32 = 1;
+const DISC_TYPE_WII: u32 = 2;
+
+const DISC_HEADER_LEN: usize = 0x80;
+const PARTITION_ENTRY_LEN: usize = 0x30;
+const PARTITION_DATA_ENTRY_LEN: usize = 0x10;
+const RAW_DATA_ENTRY_LEN: usize = 0x18;
+const WIA_GROUP_ENTRY_LEN: usize = 0x08;
+const RVZ_GROUP_ENTRY_LEN: usize = 0x0C;
+const HASH_SIZE: usize = 20;
+const HASH_EXCEPTION_LEN: usize = 2 + HASH_SIZE;
+
+/// Set in the size of an RVZ group that is compressed.
+const COMPRESSED_FLAG: u32 = 1 << 31;
+/// Set in the size of a run of packed RVZ data that is junk.
+const JUNK_FLAG: u32 = 1 << 31;
+
+pub fn is_wia(header: &[u8]) -> bool {
+    header.starts_with(WIA_MAGIC) || header.starts_with(RVZ_MAGIC)
+}
+
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+enum Compression {
+    None,
+    /// Only stores the parts of the data that aren't zeros.
+    Purge,
+    Bzip2,
+    Lzma,
+    Lzma2,
+    Zstd,
+}
+
+#[derive(Copy, Clone)]
+struct PartitionData {
+    first_sector: u64,
+    num_sectors: u64,
+    group_index: u64,
+    num_groups: u64,
+}
+
+/// The data of a partition is split in two, so that the data before the
+/// file system can be compressed on its own.
+#[derive(Copy, Clone)]
+struct Partition {
+    key: [u8; 16],
+    data: [PartitionData; 2],
+}
+
+#[derive(Copy, Clone)]
+struct RawData {
+    offset: u64,
+    size: u64,
+    group_index: u64,
+    num_groups: u64,
+}
+
+#[derive(Copy, Clone)]
+struct Group {
+    offset: u64,
+    size: u64,
+    compressed: bool,
+    /// The size of the data before it is unpacked, if it's packed at all.
+    packed_size: u64,
+}
+
+struct Chunk {
+    /// The offsets of the hashes that are replaced, relative to the hashes of
+    /// a group of Wii clusters.
+    exceptions: Vec<Vec<(usize, [u8; HASH_SIZE])>>,
+    data: Vec<u8>,
+}
+
+pub struct WiaReader<R> {
+    reader: R,
+    compression: Compression,
+    compressor_data: Vec<u8>,
+    chunk_size: u64,
+    disc_header: Vec<u8>,
+    iso_size: u64,
+    partitions: Vec<Partition>,
+    raw_data: Vec<RawData>,
+    groups: Vec<Group>,
+    position: u64,
+    chunk_index: Option<u64>,
+    chunk: Chunk,
+    /// The disc offset and the data of the raw data or the Wii clusters that
+    /// were read last.
+    block: Option<(u64, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> WiaReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        let mut head = [0; FILE_HEAD_LEN];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut head)?;
+        ensure!(is_wia(&head), "The file is not a WIA or RVZ file");
+        let is_rvz = head.starts_with(RVZ_MAGIC);
+        ensure!(
+            BE::read_u32(&head[OFFSET_VERSION_COMPATIBLE..]) <= VERSION,
+            "The file was made by a newer version of Dolphin"
+        );
+
+        let disc_size = BE::read_u32(&head[OFFSET_DISC_SIZE..]) as usize;
+        ensure!(
+            disc_size >= DISC_LEN,
+            "The disc struct of the file is truncated"
+        );
+        let mut disc = vec![0; disc_size];
+        reader.read_exact(&mut disc)?;
+
+        let disc_type = BE::read_u32(&disc[OFFSET_DISC_TYPE..]);
+        ensure!(
+            disc_type == DISC_TYPE_GAMECUBE || disc_type == DISC_TYPE_WII,
+            "The file contains an unknown type of disc"
+        );
+        let compression = match BE::read_u32(&disc[OFFSET_COMPRESSION..]) {
+            0 => Compression::None,
+            1 => Compression::Purge,
+            2 => Compression::Bzip2,
+            3 => Compression::Lzma,
+            4 => Compression::Lzma2,
+            5 => Compression::Zstd,
+            method => bail!("The file is compressed with an unknown method ({})", method),
+        };
+        let chunk_size = u64::from(BE::read_u32(&disc[OFFSET_CHUNK_SIZE..]));
+        ensure!(
+            chunk_size > 0 && chunk_size % CLUSTER_SIZE as u64 == 0,
+            "The chunk size of the file is invalid"
+        );
+        let compressor_data_len = cmp::min(
+            disc[OFFSET_COMPRESSOR_DATA_SIZE] as usize,
+            COMPRESSOR_DATA_LEN,
+        );
+
+        let num_partitions = BE::read_u32(&disc[OFFSET_NUM_PARTITIONS..]) as usize;
+        let partition_entry_size = BE::read_u32(&disc[OFFSET_PARTITION_ENTRY_SIZE..]) as usize;
+        ensure!(
+            num_partitions == 0 || partition_entry_size >= PARTITION_ENTRY_LEN,
+            "The partition table of the file is invalid"
+        );
+        let mut table = vec![0; num_partitions * partition_entry_size];
+        reader.seek(SeekFrom::Start(BE::read_u64(
+            &disc[OFFSET_PARTITION_ENTRIES..],
+        )))?;
+        reader
+            .read_exact(&mut table)
+            .map_err(|_| format_err!("The partition table of the file is truncated"))?;
+        let partitions = table
+            .chunks(cmp::max(partition_entry_size, 1))
+            .map(Partition::parse)
+            .collect();
+
+        let mut wia = Self {
+            reader,
+            compression,
+            compressor_data: disc[OFFSET_COMPRESSOR_DATA..][..compressor_data_len].to_vec(),
+            chunk_size,
+            disc_header: disc[OFFSET_DISC_HEADER..][..DISC_HEADER_LEN].to_vec(),
+            iso_size: BE::read_u64(&head[OFFSET_ISO_FILE_SIZE..]),
+            partitions,
+            raw_data: Vec::new(),
+            groups: Vec::new(),
+            position: 0,
+            chunk_index: None,
+            chunk: Chunk {
+                exceptions: Vec::new(),
+                data: Vec::new(),
+            },
+            block: None,
+        };
+
+        let num_raw_data = BE::read_u32(&disc[OFFSET_NUM_RAW_DATA..]) as usize;
+        wia.raw_data = wia
+            .read_table(
+                BE::read_u64(&disc[OFFSET_RAW_DATA_ENTRIES..]),
+                BE::read_u32(&disc[OFFSET_RAW_DATA_ENTRIES_SIZE..]),
+                num_raw_data * RAW_DATA_ENTRY_LEN,
+            )
+            .map_err(|_| format_err!("The raw data table of the file is invalid"))?
+            .chunks(RAW_DATA_ENTRY_LEN)
+            .map(RawData::parse)
+            .collect();
+
+        let num_groups = BE::read_u32(&disc[OFFSET_NUM_GROUPS..]) as usize;
+        let group_entry_len = if is_rvz {
+            RVZ_GROUP_ENTRY_LEN
+        } else {
+            WIA_GROUP_ENTRY_LEN
+        };
+        wia.groups = wia
+            .read_table(
+                BE::read_u64(&disc[OFFSET_GROUP_ENTRIES..]),
+                BE::read_u32(&disc[OFFSET_GROUP_ENTRIES_SIZE..]),
+                num_groups * group_entry_len,
+            )
+            .map_err(|_| format_err!("The group table of the file is invalid"))?
+            .chunks(group_entry_len)
+            .map(|entry| Group::parse(entry, is_rvz))
+            .collect();
+
+        Ok(wia)
+    }
+
+    /// The tables are compressed the same way as the chunks.
+    fn read_table(&mut self, offset: u64, stored_size: u32, len: usize) -> io::Result<Vec<u8>> {
+        let mut stored = vec![0; stored_size as usize];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(&mut stored)?;
+
+        let mut table = vec![0; len];
+        self.decoder(self.compression, &stored, len)?
+            .read_exact(&mut table)?;
+        Ok(table)
+    }
+
+    fn decoder<'a>(
+        &self,
+        compression: Compression,
+        stored: &'a [u8],
+        len: usize,
+    ) -> io::Result<Box<dyn Read + 'a>> {
+        if compression == Compression::Purge {
+            return Ok(Box::new(Cursor::new(unpurge(stored, len)?)));
+        }
+        decompressor(compression, &self.compressor_data, stored)
+    }
+
+    /// Reads the chunk of a group, unless it's the one that was read last.
+    fn read_chunk(
+        &mut self,
+        group_index: u64,
+        data_len: u64,
+        exception_lists: usize,
+        data_offset: u64,
+    ) -> io::Result<&Chunk> {
+        if self.chunk_index != Some(group_index) {
+            self.chunk_index = None;
+            let group = *self
+                .groups
+                .get(group_index as usize)
+                .ok_or_else(|| invalid("The file refers to a group that doesn't exist"))?;
+            self.chunk =
+                self.decompress_chunk(group, data_len as usize, exception_lists, data_offset)?;
+            self.chunk_index = Some(group_index);
+        }
+        Ok(&self.chunk)
+    }
+
+    fn decompress_chunk(
+        &mut self,
+        group: Group,
+        data_len: usize,
+        exception_lists: usize,
+        data_offset: u64,
+    ) -> io::Result<Chunk> {
+        // Groups without any data are all zeros
+        if group.size == 0 {
+            return Ok(Chunk {
+                exceptions: vec![Vec::new(); exception_lists],
+                data: vec![0; data_len],
+            });
+        }
+
+        let mut stored = vec![0; group.size as usize];
+        self.reader.seek(SeekFrom::Start(group.offset))?;
+        self.reader.read_exact(&mut stored)?;
+
+        let compression = if group.compressed {
+            self.compression
+        } else {
+            Compression::None
+        };
+        let len = if group.packed_size != 0 {
+            group.packed_size as usize
+        } else {
+            data_len
+        };
+
+        // The exception lists are only compressed along with the data by the
+        // actual compression methods. Otherwise they are stored in front of
+        // it, padded to a multiple of 4 bytes.
+        let mut input = &stored[..];
+        let mut exceptions = Vec::new();
+        let stored_lists = compression <= Compression::Purge;
+        if stored_lists {
+            exceptions = read_exception_lists(&mut input, exception_lists)?;
+            let lists_len = (stored.len() - input.len() + 3) & !3;
+            input = stored.get(lists_len..).unwrap_or(&[]);
+        }
+        let mut decoder = self.decoder(compression, input, len)?;
+        if !stored_lists {
+            exceptions = read_exception_lists(&mut decoder, exception_lists)?;
+        }
+        let mut data = vec![0; len];
+        decoder.read_exact(&mut data)?;
+
+        if group.packed_size != 0 {
+            data = unpack(&data, data_len, data_offset)?;
+        }
+
+        Ok(Chunk { exceptions, data })
+    }
+
+    fn read_block(&mut self, position: u64) -> io::Result<(u64, Vec<u8>)> {
+        let sector = position / CLUSTER_SIZE as u64;
+        let partition = self.partitions.iter().cloned().find(|partition| {
+            partition
+                .data
+                .iter()
+                .any(|data| sector >= data.first_sector && sector < data.end_sector())
+        });
+        match partition {
+            Some(partition) => self.read_wii_group(partition, sector),
+            None => self.read_raw_data(position),
+        }
+    }
+
+    fn read_raw_data(&mut self, position: u64) -> io::Result<(u64, Vec<u8>)> {
+        let entry = self
+            .raw_data
+            .iter()
+            .cloned()
+            .find(|entry| position >= entry.offset && position < entry.offset + entry.size)
+            .ok_or_else(|| invalid("The file doesn't store this part of the disc"))?;
+
+        // The chunks start at the cluster the raw data starts in
+        let start = entry.offset - entry.offset % CLUSTER_SIZE as u64;
+        let index = (position - start) / self.chunk_size;
+        if index >= entry.num_groups {
+            return Err(invalid("The raw data of the file is truncated"));
+        }
+        let chunk_start = start + index * self.chunk_size;
+        let chunk_end = cmp::min(chunk_start + self.chunk_size, entry.offset + entry.size);
+
+        let skipped = entry.offset.saturating_sub(chunk_start);
+        let chunk = self.read_chunk(
+            entry.group_index + index,
+            chunk_end - chunk_start,
+            0,
+            chunk_start,
+        )?;
+        Ok((
+            chunk_start + skipped,
+            chunk.data[skipped as usize..].to_vec(),
+        ))
+    }
+
+    /// Hashes and encrypts the group of Wii clusters that contains the sector.
+    fn read_wii_group(&mut self, partition: Partition, sector: u64) -> io::Result<(u64, Vec<u8>)> {
+        let first_sector = partition.data[0].first_sector;
+        let end_sector = cmp::max(
+            partition.data[0].end_sector(),
+            partition.data[1].end_sector(),
+        );
+        let group = (sector - first_sector) / CLUSTERS_PER_GROUP as u64;
+        let group_sector = first_sector + group * CLUSTERS_PER_GROUP as u64;
+        let sectors = cmp::min(CLUSTERS_PER_GROUP as u64, end_sector - group_sector) as usize;
+
+        let mut data = vec![0; GROUP_DATA_SIZE];
+        let mut exceptions = Vec::new();
+        self.read_partition_data(
+            &partition,
+            group * GROUP_DATA_SIZE as u64,
+            &mut data[..sectors * CLUSTER_DATA_SIZE],
+            &mut exceptions,
+        )?;
+
+        let mut clusters = vec![0; GROUP_SIZE];
+        wii::encrypt_group(&partition.key, &data, &mut clusters, |hashes| {
+            for &(offset, ref hash) in &exceptions {
+                if let Some(replaced) = hashes.get_mut(offset..offset + HASH_SIZE) {
+                    replaced.copy_from_slice(hash);
+                }
+            }
+        });
+        clusters.truncate(sectors * CLUSTER_SIZE);
+
+        Ok((group_sector * CLUSTER_SIZE as u64, clusters))
+    }
+
+    /// Reads the decrypted data of the partition at the offset, along with the
+    /// exceptions to the hashes of that data.
+    fn read_partition_data(
+        &mut self,
+        partition: &Partition,
+        offset: u64,
+        out: &mut [u8],
+        exceptions: &mut Vec<(usize, [u8; HASH_SIZE])>,
+    ) -> io::Result<()> {
+        let chunk_size = self.chunk_size / CLUSTER_SIZE as u64 * CLUSTER_DATA_SIZE as u64;
+        let exception_lists = cmp::max(1, chunk_size / GROUP_DATA_SIZE as u64) as usize;
+        let end = offset + out.len() as u64;
+
+        for data in partition.data.iter().filter(|data| data.num_sectors > 0) {
+            let data_start =
+                (data.first_sector - partition.data[0].first_sector) * CLUSTER_DATA_SIZE as u64;
+            let data_end = data_start + data.num_sectors * CLUSTER_DATA_SIZE as u64;
+
+            let mut position = cmp::max(offset, data_start);
+            while position < cmp::min(end, data_end) {
+                let index = (position - data_start) / chunk_size;
+                if index >= data.num_groups {
+                    return Err(invalid("The partition data of the file is truncated"));
+                }
+                let chunk_start = data_start + index * chunk_size;
+                let chunk_end = cmp::min(chunk_start + chunk_size, data_end);
+                let read_end = cmp::min(chunk_end, end);
+
+                let chunk = self.read_chunk(
+                    data.group_index + index,
+                    chunk_end - chunk_start,
+                    exception_lists,
+                    chunk_start,
+                )?;
+                let chunk_offset = (position - chunk_start) as usize;
+                out[(position - offset) as usize..(read_end - offset) as usize]
+                    .copy_from_slice(&chunk.data[chunk_offset..][..(read_end - position) as usize]);
+
+                // Chunks that are smaller than a group of clusters only
+                // store the exceptions to the hashes of their own clusters.
+                let hashes_offset = (index * chunk_size % GROUP_DATA_SIZE as u64) as usize
+                    / CLUSTER_DATA_SIZE
+                    * CLUSTER_HASH_SIZE;
+                if let Some(list) = chunk.exceptions.get(chunk_offset / GROUP_DATA_SIZE) {
+                    exceptions.extend(
+                        list.iter()
+                            .map(|&(offset, hash)| (hashes_offset + offset, hash)),
+                    );
+                }
+
+                position = read_end;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Partition {
+    fn parse(entry: &[u8]) -> Self {
+        let mut key = [0; 16];
+        key.copy_from_slice(&entry[..16]);
+        Partition {
+            key,
+            data: [
+                PartitionData::parse(&entry[16..]),
+                PartitionData::parse(&entry[16 + PARTITION_DATA_ENTRY_LEN..]),
+            ],
+        }
+    }
+}
+
+impl PartitionData {
+    fn parse(entry: &[u8]) -> Self {
+        PartitionData {
+            first_sector: u64::from(BE::read_u32(entry)),
+            num_sectors: u64::from(BE::read_u32(&entry[0x4..])),
+            group_index: u64::from(BE::read_u32(&entry[0x8..])),
+            num_groups: u64::from(BE::read_u32(&entry[0xC..])),
+        }
+    }
+
+    fn end_sector(&self) -> u64 {
+        self.first_sector + self.num_sectors
+    }
+}
+
+impl RawData {
+    fn parse(entry: &[u8]) -> Self {
+        RawData {
+            offset: BE::read_u64(entry),
+            size: BE::read_u64(&entry[0x08..]),
+            group_index: u64::from(BE::read_u32(&entry[0x10..])),
+            num_groups: u64::from(BE::read_u32(&entry[0x14..])),
+        }
+    }
+}
+
+impl Group {
+    fn parse(entry: &[u8], is_rvz: bool) -> Self {
+        let size = BE::read_u32(&entry[0x4..]);
+        Group {
+            offset: u64::from(BE::read_u32(entry)) << 2,
+            size: u64::from(size & !COMPRESSED_FLAG),
+            compressed: !is_rvz || size & COMPRESSED_FLAG != 0,
+            packed_size: if is_rvz {
+                u64::from(BE::read_u32(&entry[0x8..]))
+            } else {
+                0
+            },
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_exception_lists<R: Read>(
+    reader: &mut R,
+    count: usize,
+) -> io::Result<Vec<Vec<(usize, [u8; HASH_SIZE])>>> {
+    let mut lists = Vec::with_capacity(count);
+    for _ in 0..count {
+        let mut len = [0; 2];
+        reader.read_exact(&mut len)?;
+        let mut list = Vec::new();
+        for _ in 0..BE::read_u16(&len) {
+            let mut exception = [0; HASH_EXCEPTION_LEN];
+            reader.read_exact(&mut exception)?;
+            let mut hash = [0; HASH_SIZE];
+            hash.copy_from_slice(&exception[2..]);
+            list.push((BE::read_u16(&exception) as usize, hash));
+        }
+        lists.push(list);
+    }
+    Ok(lists)
+}
+
+/// Purged data is a list of the segments that aren't zeros, each with its
+/// offset and size, followed by a SHA-1 hash.
+fn unpurge(stored: &[u8], len: usize) -> io::Result<Vec<u8>> {
+    let segments_len = stored
+        .len()
+        .checked_sub(HASH_SIZE)
+        .ok_or_else(|| invalid("The purged data of the file is truncated"))?;
+    let mut segments = &stored[..segments_len];
+
+    let mut data = vec![0; len];
+    while !segments.is_empty() {
+        if segments.len() < 8 {
+            return Err(invalid("The purged data of the file is truncated"));
+        }
+        let offset = BE::read_u32(segments) as usize;
+        let size = BE::read_u32(&segments[4..]) as usize;
+        segments = &segments[8..];
+        if size > segments.len() || offset > len || size > len - offset {
+            return Err(invalid("The purged data of the file is invalid"));
+        }
+        data[offset..][..size].copy_from_slice(&segments[..size]);
+        segments = &segments[size..];
+    }
+
+    Ok(data)
+}
+
+/// Packed RVZ data is a list of runs, each with its size. Runs of junk only
+/// store the seed of the junk.
+fn unpack(mut packed: &[u8], len: usize, mut data_offset: u64) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        if packed.len() < 4 {
+            return Err(invalid("The packed data of the file is truncated"));
+        }
+        let size = BE::read_u32(packed);
+        packed = &packed[4..];
+
+        let start = data.len();
+        let run_len = (size & !JUNK_FLAG) as usize;
+        if run_len > len - start {
+            return Err(invalid("The packed data of the file is invalid"));
+        }
+        if size & JUNK_FLAG != 0 {
+            if packed.len() < junk::SEED_SIZE {
+                return Err(invalid("The packed data of the file is truncated"));
+            }
+            data.resize(start + run_len, 0);
+            let skip = (data_offset % junk::BLOCK_LEN as u64) as usize;
+            junk::fill(&packed[..junk::SEED_SIZE], skip, &mut data[start..]);
+            packed = &packed[junk::SEED_SIZE..];
+        } else {
+            if packed.len() < run_len {
+                return Err(invalid("The packed data of the file is truncated"));
+            }
+            data.extend_from_slice(&packed[..run_len]);
+            packed = &packed[run_len..];
+        }
+        data_offset += run_len as u64;
+    }
+
+    Ok(data)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn decompressor<'a>(
+    compression: Compression,
+    compressor_data: &[u8],
+    stored: &'a [u8],
+) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        Compression::None | Compression::Purge => Box::new(stored),
+        Compression::Bzip2 => Box::new(BzDecoder::new(stored)),
+        Compression::Lzma | Compression::Lzma2 => Box::new(XzDecoder::new_stream(
+            stored,
+            lzma_stream(compression, compressor_data)?,
+        )),
+        Compression::Zstd => Box::new(zstd::Decoder::new(stored)?),
+    })
+}
+
+// None of the codecs are available on the web
+#[cfg(target_arch = "wasm32")]
+fn decompressor<'a>(
+    compression: Compression,
+    _: &[u8],
+    stored: &'a [u8],
+) -> io::Result<Box<dyn Read + 'a>> {
+    match compression {
+        Compression::None | Compression::Purge => Ok(Box::new(stored)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Other,
+            "Compressed WIA and RVZ files can't be read on the web",
+        )),
+    }
+}
+
+/// The compressor data holds the properties of the raw LZMA stream. LZMA
+/// stores them in 5 bytes and LZMA2 only stores the dictionary size.
+#[cfg(not(target_arch = "wasm32"))]
+fn lzma_stream(compression: Compression, compressor_data: &[u8]) -> io::Result<Stream> {
+    let to_io = |e| io::Error::new(io::ErrorKind::InvalidData, e);
+    let mut options = LzmaOptions::new_preset(6).map_err(to_io)?;
+    let mut filters = Filters::new();
+
+    if compression == Compression::Lzma {
+        if compressor_data.len() < 5 {
+            return Err(invalid("The LZMA properties of the file are missing"));
+        }
+        let properties = u32::from(compressor_data[0]);
+        options
+            .literal_context_bits(properties % 9)
+            .literal_position_bits(properties / 9 % 5)
+            .position_bits(properties / 45)
+            .dict_size(LE::read_u32(&compressor_data[1..]));
+        filters.lzma1(&options);
+    } else {
+        let properties = match compressor_data.first() {
+            Some(&properties) if properties <= 40 => u32::from(properties),
+            _ => return Err(invalid("The LZMA2 properties of the file are invalid")),
+        };
+        let dict_size = if properties == 40 {
+            0xFFFF_FFFF
+        } else {
+            (2 | (properties & 1)) << (properties / 2 + 11)
+        };
+        options.dict_size(dict_size);
+        filters.lzma2(&options);
+    }
+
+    Stream::new_raw_decoder(&filters).map_err(to_io)
+}
+
+impl<R: Read + Seek> Read for WiaReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.iso_size || buf.is_empty() {
+            return Ok(0);
+        }
+        let remaining = self.iso_size - self.position;
+
+        let len = if self.position < DISC_HEADER_LEN as u64 {
+            let data = &self.disc_header[self.position as usize..];
+            let len = cmp::min(cmp::min(buf.len(), data.len()) as u64, remaining) as usize;
+            buf[..len].copy_from_slice(&data[..len]);
+            len
+        } else {
+            let position = self.position;
+            let block = match self.block.take().filter(|&(start, ref data)| {
+                position >= start && position - start < data.len() as u64
+            }) {
+                Some(block) => block,
+                None => self.read_block(position)?,
+            };
+
+            let len = {
+                let data = &block.1[(position - block.0) as usize..];
+                let len = cmp::min(cmp::min(buf.len(), data.len()) as u64, remaining) as usize;
+                buf[..len].copy_from_slice(&data[..len]);
+                len
+            };
+            self.block = Some(block);
+            len
+        };
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<R> Seek for WiaReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.iso_size as i64 + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+        if position < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seeked before the start of the disc",
+            ));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 16] = *b"0123456789abcdef";
+
+    /// The data of the groups is stored uncompressed, with the packed size of
+    /// each group if it's an RVZ file.
+    struct File {
+        is_rvz: bool,
+        disc_type: u32,
+        chunk_size: u32,
+        iso: Vec<u8>,
+        partitions: Vec<u8>,
+        raw_data: Vec<u8>,
+        groups: Vec<(Vec<u8>, u32)>,
+    }
+
+    impl File {
+        fn new(disc_type: u32, iso: Vec<u8>) -> Self {
+            File {
+                is_rvz: false,
+                disc_type,
+                chunk_size: CLUSTER_SIZE as u32,
+                iso,
+                partitions: Vec::new(),
+                raw_data: Vec::new(),
+                groups: Vec::new(),
+            }
+        }
+
+        fn raw_data(&mut self, offset: u64, size: u64, groups: &[Vec<u8>]) {
+            let mut entry = [0; RAW_DATA_ENTRY_LEN];
+            BE::write_u64(&mut entry, offset);
+            BE::write_u64(&mut entry[0x08..], size);
+            BE::write_u32(&mut entry[0x10..], self.groups.len() as u32);
+            BE::write_u32(&mut entry[0x14..], groups.len() as u32);
+            self.raw_data.extend_from_slice(&entry);
+            self.groups
+                .extend(groups.iter().map(|group| (group.clone(), 0)));
+        }
+
+        fn partition(&mut self, first_sector: u32, num_sectors: u32, groups: &[Vec<u8>]) {
+            let mut entry = [0; PARTITION_ENTRY_LEN];
+            entry[..16].copy_from_slice(&KEY);
+            BE::write_u32(&mut entry[0x10..], first_sector);
+            BE::write_u32(&mut entry[0x14..], num_sectors);
+            BE::write_u32(&mut entry[0x18..], self.groups.len() as u32);
+            BE::write_u32(&mut entry[0x1C..], groups.len() as u32);
+            self.partitions.extend_from_slice(&entry);
+            self.groups
+                .extend(groups.iter().map(|group| (group.clone(), 0)));
+        }
+
+        fn build(&self) -> Vec<u8> {
+            let mut file = vec![0; FILE_HEAD_LEN + DISC_LEN];
+            file[..4].copy_from_slice(if self.is_rvz { RVZ_MAGIC } else { WIA_MAGIC });
+            BE::write_u32(&mut file[OFFSET_VERSION_COMPATIBLE..], VERSION);
+            BE::write_u32(&mut file[OFFSET_DISC_SIZE..], DISC_LEN as u32);
+            BE::write_u64(&mut file[OFFSET_ISO_FILE_SIZE..], self.iso.len() as u64);
+
+            let partitions_offset = file.len();
+            file.extend_from_slice(&self.partitions);
+            let raw_data_offset = file.len();
+            file.extend_from_slice(&self.raw_data);
+
+            let mut entries = Vec::new();
+            for &(ref data, packed_size) in &self.groups {
+                let mut entry = [0; RVZ_GROUP_ENTRY_LEN];
+                BE::write_u32(&mut entry, (file.len() >> 2) as u32);
+                BE::write_u32(&mut entry[0x4..], data.len() as u32);
+                BE::write_u32(&mut entry[0x8..], packed_size);
+                let len = if self.is_rvz {
+                    RVZ_GROUP_ENTRY_LEN
+                } else {
+                    WIA_GROUP_ENTRY_LEN
+                };
+                entries.extend_from_slice(&entry[..len]);
+                file.extend_from_slice(data);
+                let len = (file.len() + 3) & !3;
+                file.resize(len, 0);
+            }
+            let groups_offset = file.len();
+            file.extend_from_slice(&entries);
+
+            let disc = &mut file[FILE_HEAD_LEN..][..DISC_LEN];
+            BE::write_u32(&mut disc[OFFSET_DISC_TYPE..], self.disc_type);
+            BE::write_u32(&mut disc[OFFSET_CHUNK_SIZE..], self.chunk_size);
+            disc[OFFSET_DISC_HEADER..][..DISC_HEADER_LEN]
+                .copy_from_slice(&self.iso[..DISC_HEADER_LEN]);
+            BE::write_u32(
+                &mut disc[OFFSET_NUM_PARTITIONS..],
+                (self.partitions.len() / PARTITION_ENTRY_LEN) as u32,
+            );
+            BE::write_u32(
+                &mut disc[OFFSET_PARTITION_ENTRY_SIZE..],
+                PARTITION_ENTRY_LEN as u32,
+            );
+            BE::write_u64(
+                &mut disc[OFFSET_PARTITION_ENTRIES..],
+                partitions_offset as u64,
+            );
+            BE::write_u32(
+                &mut disc[OFFSET_NUM_RAW_DATA..],
+                (self.raw_data.len() / RAW_DATA_ENTRY_LEN) as u32,
+            );
+            BE::write_u64(&mut disc[OFFSET_RAW_DATA_ENTRIES..], raw_data_offset as u64);
+            BE::write_u32(
+                &mut disc[OFFSET_RAW_DATA_ENTRIES_SIZE..],
+                self.raw_data.len() as u32,
+            );
+            BE::write_u32(&mut disc[OFFSET_NUM_GROUPS..], self.groups.len() as u32);
+            BE::write_u64(&mut disc[OFFSET_GROUP_ENTRIES..], groups_offset as u64);
+            BE::write_u32(&mut disc[OFFSET_GROUP_ENTRIES_SIZE..], entries.len() as u32);
+
+            file
+        }
+
+        fn read(&self) -> Vec<u8> {
+            let mut data = Vec::new();
+            WiaReader::new(Cursor::new(self.build()))
+                .unwrap()
+                .read_to_end(&mut data)
+                .unwrap();
+            data
+        }
+    }
+
+    fn noise(len: usize, mut seed: u32) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    }
+
+    /// Stores the data of a Wii partition's chunk after its exception list.
+    fn with_exceptions(exceptions: &[(u16, [u8; HASH_SIZE])], data: &[u8]) -> Vec<u8> {
+        let mut group = vec![0; 2];
+        BE::write_u16(&mut group, exceptions.len() as u16);
+        for &(offset, ref hash) in exceptions {
+            let mut exception = [0; HASH_EXCEPTION_LEN];
+            BE::write_u16(&mut exception, offset);
+            exception[2..].copy_from_slice(hash);
+            group.extend_from_slice(&exception);
+        }
+        let len = (group.len() + 3) & !3;
+        group.resize(len, 0);
+        group.extend_from_slice(data);
+        group
+    }
+
+    #[test]
+    fn reads_a_gamecube_disc() {
+        let iso = noise(0x2_4000, 1);
+        let mut file = File::new(DISC_TYPE_GAMECUBE, iso.clone());
+        let groups = iso
+            .chunks(CLUSTER_SIZE)
+            .map(|c| c.to_vec())
+            .collect::<Vec<_>>();
+        file.raw_data(0x80, iso.len() as u64 - 0x80, &groups);
+        assert!(file.read() == iso);
+
+        let mut reader = WiaReader::new(Cursor::new(file.build())).unwrap();
+        let mut data = vec![0; 0x100];
+        reader.seek(SeekFrom::Start(0x1_7F80)).unwrap();
+        reader.read_exact(&mut data).unwrap();
+        assert_eq!(&data[..], &iso[0x1_7F80..0x1_8080]);
+    }
+
+    #[test]
+    fn unpacks_the_junk_of_rvz_files() {
+        let seed = noise(junk::SEED_SIZE, 2);
+        let mut iso = noise(CLUSTER_SIZE + 0x100, 3);
+        let mut junk = vec![0; 0x7F00];
+        junk::fill(&seed, 0x100, &mut junk);
+        iso.extend_from_slice(&junk);
+
+        let mut packed = vec![0; 4];
+        BE::write_u32(&mut packed, 0x100);
+        packed.extend_from_slice(&iso[CLUSTER_SIZE..][..0x100]);
+        let mut run = [0; 4];
+        BE::write_u32(&mut run, JUNK_FLAG | 0x7F00);
+        packed.extend_from_slice(&run);
+        packed.extend_from_slice(&seed);
+
+        let mut file = File::new(DISC_TYPE_GAMECUBE, iso.clone());
+        file.is_rvz = true;
+        file.raw_data(
+            0x80,
+            iso.len() as u64 - 0x80,
+            &[iso[..CLUSTER_SIZE].to_vec(), packed.clone()],
+        );
+        file.groups[1].1 = packed.len() as u32;
+        assert!(file.read() == iso);
+    }
+
+    #[test]
+    fn rebuilds_the_hashes_of_wii_partitions() {
+        let partition_offset = 2 * CLUSTER_SIZE;
+        let data = noise(3 * CLUSTER_DATA_SIZE, 4);
+        let hash = [0xAB; HASH_SIZE];
+
+        let mut padded = data.clone();
+        padded.resize(GROUP_DATA_SIZE, 0);
+        let mut clusters = vec![0; GROUP_SIZE];
+        wii::encrypt_group(&KEY, &padded, &mut clusters, |hashes| {
+            hashes[CLUSTER_HASH_SIZE + 0x10..][..HASH_SIZE].copy_from_slice(&hash);
+        });
+        let mut iso = noise(partition_offset, 5);
+        iso.extend_from_slice(&clusters[..3 * CLUSTER_SIZE]);
+
+        let mut file = File::new(DISC_TYPE_WII, iso.clone());
+        let groups = iso[..partition_offset]
+            .chunks(CLUSTER_SIZE)
+            .map(|c| c.to_vec())
+            .collect::<Vec<_>>();
+        file.raw_data(0x80, partition_offset as u64 - 0x80, &groups);
+        let groups = data
+            .chunks(CLUSTER_DATA_SIZE)
+            .enumerate()
+            .map(|(i, data)| {
+                let exceptions = if i == 1 { vec![(0x10, hash)] } else { vec![] };
+                with_exceptions(&exceptions, data)
+            })
+            .collect::<Vec<_>>();
+        file.partition(2, 3, &groups);
+        assert!(file.read() == iso);
+
+        let mut hashes = iso[partition_offset + CLUSTER_SIZE..][..CLUSTER_HASH_SIZE].to_vec();
+        wii::decrypt(&KEY, [0; 16], &mut hashes);
+        assert_eq!(hashes[0x10..][..HASH_SIZE], hash);
+    }
+
+    #[test]
+    fn unpurges_the_data() {
+        let mut stored = Vec::new();
+        for &(offset, ref segment) in &[(0x10, &b"abc"[..]), (0x20, &b"defg"[..])] {
+            let mut header = [0; 8];
+            BE::write_u32(&mut header, offset);
+            BE::write_u32(&mut header[4..], segment.len() as u32);
+            stored.extend_from_slice(&header);
+            stored.extend_from_slice(segment);
+        }
+        stored.extend_from_slice(&[0; HASH_SIZE]);
+
+        let data = unpurge(&stored, 0x30).unwrap();
+        let mut expected = vec![0; 0x30];
+        expected[0x10..0x13].copy_from_slice(b"abc");
+        expected[0x20..0x24].copy_from_slice(b"defg");
+        assert_eq!(data, expected);
+        assert!(unpurge(&stored, 0x22).is_err());
+    }
+}
@@ -0,0 +1,676 @@
+//! Based on http://wiibrew.org/wiki/Wii_Disc
+
+use super::header::offset_shift;
+use aes::block_cipher_trait::generic_array::GenericArray;
+use aes::block_cipher_trait::BlockCipher;
+use aes::Aes128;
+use byteorder::{ByteOrder, BE};
+use failure::{Error, ResultExt};
+use sha1::Sha1;
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+const COMMON_KEYS: [[u8; 16]; 2] = [
+    [
+        0xEB, 0xE4, 0x2A, 0x22, 0x5E, 0x85, 0x93, 0xE4, 0x48, 0xD9, 0xC5, 0x45, 0x73, 0x81, 0xAA,
+        0xF7,
+    ],
+    // Korean common key
+    [
+        0x63, 0xB8, 0x2B, 0xB4, 0xF4, 0x61, 0x4E, 0x2E, 0x13, 0xF2, 0xFE, 0xFB, 0xBA, 0x4C, 0x9B,
+        0x7E,
+    ],
+];
+
+const OFFSET_PARTITION_INFO: u64 = 0x4_0000;
+const PARTITION_TABLE_COUNT: usize = 4;
+const PARTITION_TYPE_DATA: u32 = 0;
+
+// Relative to the start of the partition
+const OFFSET_TITLE_KEY: usize = 0x1BF;
+const OFFSET_TITLE_ID: usize = 0x1DC;
+const OFFSET_COMMON_KEY_INDEX: usize = 0x1F1;
+const OFFSET_TMD_SIZE: usize = 0x2A4;
+const OFFSET_TMD_OFFSET: usize = 0x2A8;
+const OFFSET_CERT_CHAIN_SIZE: usize = 0x2AC;
+const OFFSET_CERT_CHAIN_OFFSET: usize = 0x2B0;
+const OFFSET_H3_OFFSET: usize = 0x2B4;
+const OFFSET_DATA_OFFSET: usize = 0x2B8;
+const OFFSET_DATA_SIZE: usize = 0x2BC;
+
+// Relative to the start of the TMD
+const OFFSET_TMD_TITLE_ID: usize = 0x18C;
+const OFFSET_TMD_CONTENT_HASH: usize = 0x1F4;
+
+// Relative to the start of the ticket and the TMD
+const OFFSET_SIGNATURE: usize = 0x004;
+const SIGNATURE_LEN: usize = 0x100;
+const OFFSET_SIGNED_DATA: usize = 0x140;
+const TICKET_LEN: usize = 0x2A4;
+/// Unused bytes that are changed until the signed data hashes as needed for
+/// fakesigning. The ticket's bytes are part of the access permissions of
+/// contents that a partition doesn't have and the TMD's are reserved.
+const OFFSET_TICKET_FAKESIGN_PAD: usize = 0x24C;
+const OFFSET_TMD_FAKESIGN_PAD: usize = 0x1D4;
+
+/// The game code is the lower half of the title ID.
+const GAME_CODE_LEN: usize = 4;
+const GAME_ID_LEN: usize = 6;
+
+const H3_SIZE: usize = 0x1_8000;
+const HASH_SIZE: usize = 20;
+
+pub const CLUSTER_SIZE: usize = 0x8000;
+pub const CLUSTER_HASH_SIZE: usize = 0x400;
+pub const CLUSTER_DATA_SIZE: usize = CLUSTER_SIZE - CLUSTER_HASH_SIZE;
+const BLOCK_SIZE: usize = 0x400;
+const CLUSTERS_PER_SUBGROUP: usize = 8;
+const SUBGROUPS_PER_GROUP: usize = 8;
+pub const CLUSTERS_PER_GROUP: usize = CLUSTERS_PER_SUBGROUP * SUBGROUPS_PER_GROUP;
+
+// Relative to the start of a cluster's hashes
+const OFFSET_H1: usize = 0x280;
+const OFFSET_H2: usize = 0x340;
+const OFFSET_DATA_IV: usize = 0x3D0;
+const H0_TABLE_SIZE: usize = HASH_SIZE * CLUSTER_DATA_SIZE / BLOCK_SIZE;
+const H1_TABLE_SIZE: usize = HASH_SIZE * CLUSTERS_PER_SUBGROUP;
+const H2_TABLE_SIZE: usize = HASH_SIZE * SUBGROUPS_PER_GROUP;
+
+pub const GROUP_DATA_SIZE: usize = CLUSTERS_PER_GROUP * CLUSTER_DATA_SIZE;
+pub const GROUP_SIZE: usize = CLUSTERS_PER_GROUP * CLUSTER_SIZE;
+
+pub fn is_wii<R: Read + Seek>(reader: &mut R) -> io::Result<bool> {
+    let mut header = [0; 0x20];
+    reader.seek(SeekFrom::Start(0))?;
+    reader.read_exact(&mut header)?;
+    Ok(offset_shift(&header) == 2)
+}
+
+/// The encrypted partition of a Wii disc that contains the game itself.
+pub struct DataPartition {
+    offset: u64,
+    next_partition_offset: Option<u64>,
+    cipher: Aes128,
+    title_key: [u8; 16],
+    /// Everything from the ticket up to the start of the encrypted data.
+    header: Vec<u8>,
+    tmd_offset: usize,
+    tmd_size: usize,
+    cert_chain_offset: usize,
+    cert_chain_size: usize,
+    h3_offset: usize,
+    data_size: u64,
+}
+
+impl DataPartition {
+    pub fn find<R: Read + Seek>(reader: &mut R) -> Result<Self, Error> {
+        let disc_len = reader.seek(SeekFrom::End(0))?;
+
+        let mut partition_info = [0; 8 * PARTITION_TABLE_COUNT];
+        reader.seek(SeekFrom::Start(OFFSET_PARTITION_INFO))?;
+        reader
+            .read_exact(&mut partition_info)
+            .context("The Wii disc is too small to contain a partition table")?;
+
+        let mut partitions = Vec::new();
+        for table in partition_info.chunks(8) {
+            let count = BE::read_u32(table) as usize;
+            let table_offset = (BE::read_u32(&table[4..]) as u64) << 2;
+            ensure!(
+                table_offset + 8 * count as u64 <= disc_len,
+                "The partition table at {:#x} is out of bounds",
+                table_offset
+            );
+            let mut entries = vec![0; 8 * count];
+            reader.seek(SeekFrom::Start(table_offset))?;
+            reader.read_exact(&mut entries)?;
+            for entry in entries.chunks(8) {
+                let offset = (BE::read_u32(entry) as u64) << 2;
+                let kind = BE::read_u32(&entry[4..]);
+                partitions.push((offset, kind));
+            }
+        }
+
+        let offset = partitions
+            .iter()
+            .find(|&&(_, kind)| kind == PARTITION_TYPE_DATA)
+            .map(|&(offset, _)| offset)
+            .ok_or_else(|| format_err!("The Wii disc has no data partition"))?;
+        let next_partition_offset = partitions
+            .iter()
+            .map(|&(offset, _)| offset)
+            .filter(|&o| o > offset)
+            .min();
+
+        let mut ticket = [0; OFFSET_DATA_SIZE + 4];
+        reader.seek(SeekFrom::Start(offset))?;
+        reader
+            .read_exact(&mut ticket)
+            .with_context(|_| format!("The data partition at {:#x} is out of bounds", offset))?;
+
+        let title_key =
+            decrypt_title_key(&ticket).context("Couldn't decrypt the data partition")?;
+
+        let tmd_size = BE::read_u32(&ticket[OFFSET_TMD_SIZE..]) as usize;
+        let tmd_offset = (BE::read_u32(&ticket[OFFSET_TMD_OFFSET..]) as usize) << 2;
+        let cert_chain_size = BE::read_u32(&ticket[OFFSET_CERT_CHAIN_SIZE..]) as usize;
+        let cert_chain_offset = (BE::read_u32(&ticket[OFFSET_CERT_CHAIN_OFFSET..]) as usize) << 2;
+        let h3_offset = (BE::read_u32(&ticket[OFFSET_H3_OFFSET..]) as usize) << 2;
+        let data_offset = (BE::read_u32(&ticket[OFFSET_DATA_OFFSET..]) as usize) << 2;
+        let data_size = (BE::read_u32(&ticket[OFFSET_DATA_SIZE..]) as u64) << 2;
+
+        ensure!(
+            tmd_offset >= TICKET_LEN
+                && tmd_size >= OFFSET_TMD_CONTENT_HASH + HASH_SIZE
+                && tmd_offset + tmd_size <= data_offset,
+            "The TMD of the data partition is invalid"
+        );
+        ensure!(
+            cert_chain_offset + cert_chain_size <= data_offset,
+            "The certificate chain of the data partition is invalid"
+        );
+        ensure!(
+            h3_offset + H3_SIZE <= data_offset,
+            "The H3 table of the data partition is invalid"
+        );
+        ensure!(
+            offset + data_offset as u64 + data_size <= disc_len,
+            "The data partition's contents are out of bounds"
+        );
+        ensure!(
+            data_size % CLUSTER_SIZE as u64 == 0,
+            "The data partition's size is not a multiple of the cluster size"
+        );
+
+        let mut header = vec![0; data_offset];
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(&mut header)?;
+
+        Ok(Self {
+            offset,
+            next_partition_offset,
+            cipher: Aes128::new(GenericArray::from_slice(&title_key)),
+            title_key,
+            header,
+            tmd_offset,
+            tmd_size,
+            cert_chain_offset,
+            cert_chain_size,
+            h3_offset,
+            data_size,
+        })
+    }
+
+    /// The game code of the title ID in the TMD. The Wii keeps the saves of
+    /// the games apart by their title IDs, not by the IDs of their headers.
+    pub fn game_code(&self) -> &[u8] {
+        let tmd_title_id = self.tmd_offset + OFFSET_TMD_TITLE_ID;
+        &self.header[tmd_title_id + 8 - GAME_CODE_LEN..][..GAME_CODE_LEN]
+    }
+
+    fn data_offset(&self) -> u64 {
+        self.offset + self.header.len() as u64
+    }
+
+    /// The ticket, which holds the title key.
+    pub fn ticket(&self) -> &[u8] {
+        &self.header[..TICKET_LEN]
+    }
+
+    pub fn tmd(&self) -> &[u8] {
+        &self.header[self.tmd_offset..][..self.tmd_size]
+    }
+
+    /// The certificates the ticket and the TMD are signed with.
+    pub fn cert_chain(&self) -> &[u8] {
+        &self.header[self.cert_chain_offset..][..self.cert_chain_size]
+    }
+
+    /// The parts of the disc with the clusters of the partition that none of
+    /// the used ranges of its contents are in. The clusters after the last
+    /// used one aren't included, as they aren't written at all.
+    pub fn unused_clusters(&self, used: &[Range<u64>]) -> Vec<Range<u64>> {
+        let cluster_data_size = CLUSTER_DATA_SIZE as u64;
+        let end = used.iter().map(|r| r.end).max().unwrap_or(0);
+        let mut is_used = vec![false; ((end + cluster_data_size - 1) / cluster_data_size) as usize];
+        for range in used.iter().filter(|r| r.start < r.end) {
+            let first = range.start / cluster_data_size;
+            let last = (range.end - 1) / cluster_data_size;
+            for cluster in first..last + 1 {
+                is_used[cluster as usize] = true;
+            }
+        }
+
+        let mut unused = Vec::<Range<u64>>::new();
+        for (cluster, _) in is_used.iter().enumerate().filter(|&(_, &u)| !u) {
+            let start = self.data_offset() + (cluster * CLUSTER_SIZE) as u64;
+            let end = start + CLUSTER_SIZE as u64;
+            let extends_last = unused.last().map_or(false, |last| last.end == start);
+            if extends_last {
+                unused.last_mut().unwrap().end = end;
+            } else {
+                unused.push(start..end);
+            }
+        }
+        unused
+    }
+
+    /// Decrypts the partition's contents on the fly. They are laid out just
+    /// like a GameCube disc.
+    pub fn reader<R: Read + Seek>(&self, disc: R) -> PartitionReader<R> {
+        PartitionReader {
+            partition: self,
+            disc,
+            position: 0,
+            cluster_index: None,
+            cluster: vec![0; CLUSTER_SIZE],
+        }
+    }
+
+    /// Copies everything in front of the partition's contents from the
+    /// original disc and returns a writer that hashes and encrypts the new
+    /// contents. If a game ID is given, the disc header, the ticket and the
+    /// TMD are changed to it. The ticket and the TMD are fakesigned, as
+    /// their signatures can't match anymore.
+    pub fn writer<R, W>(
+        &self,
+        disc: &mut R,
+        mut writer: W,
+        game_id: Option<&[u8]>,
+    ) -> Result<PartitionWriter<W>, Error>
+    where
+        R: Read + Seek,
+        W: Write + Seek,
+    {
+        disc.seek(SeekFrom::Start(0))?;
+        let copied = io::copy(&mut disc.by_ref().take(self.data_offset()), &mut writer)?;
+        ensure!(
+            copied == self.data_offset(),
+            "The original disc is truncated"
+        );
+
+        if let Some(game_id) = game_id {
+            ensure!(
+                game_id.len() == GAME_ID_LEN,
+                "The game ID needs to be 6 characters long"
+            );
+            writer.seek(SeekFrom::Start(0))?;
+            writer.write_all(game_id)?;
+            writer.seek(SeekFrom::Start(copied))?;
+        }
+
+        Ok(PartitionWriter {
+            partition: self,
+            game_id: game_id.map(|id| id[..GAME_CODE_LEN].to_owned()),
+            writer,
+            group_data: Vec::with_capacity(GROUP_DATA_SIZE),
+            group: vec![0; GROUP_SIZE],
+            h3: vec![0; H3_SIZE],
+            groups: 0,
+        })
+    }
+
+    /// Hashes and encrypts a group of 64 clusters and returns its H3 hash.
+    fn encrypt_group(&self, data: &[u8], clusters: &mut [u8]) -> [u8; HASH_SIZE] {
+        hash_and_encrypt_group(&self.cipher, data, clusters, |_| ())
+    }
+}
+
+/// Hashes and encrypts a group of 64 clusters with the title key, like the
+/// writer of a data partition does. The hashes of all the clusters are passed
+/// to `patch_hashes` before they are encrypted, so that the hashes of discs
+/// that don't match their data can be restored.
+pub fn encrypt_group<F>(title_key: &[u8; 16], data: &[u8], clusters: &mut [u8], patch_hashes: F)
+where
+    F: FnOnce(&mut [u8]),
+{
+    let cipher = Aes128::new(GenericArray::from_slice(title_key));
+    hash_and_encrypt_group(&cipher, data, clusters, patch_hashes);
+}
+
+fn hash_and_encrypt_group<F>(
+    cipher: &Aes128,
+    data: &[u8],
+    clusters: &mut [u8],
+    patch_hashes: F,
+) -> [u8; HASH_SIZE]
+where
+    F: FnOnce(&mut [u8]),
+{
+    let mut hashes = vec![0; CLUSTERS_PER_GROUP * CLUSTER_HASH_SIZE];
+
+    for (hashes, data) in hashes
+        .chunks_mut(CLUSTER_HASH_SIZE)
+        .zip(data.chunks(CLUSTER_DATA_SIZE))
+    {
+        for (h0, block) in hashes[..H0_TABLE_SIZE]
+            .chunks_mut(HASH_SIZE)
+            .zip(data.chunks(BLOCK_SIZE))
+        {
+            h0.copy_from_slice(&sha1(block));
+        }
+    }
+
+    let mut h2_table = [0; H2_TABLE_SIZE];
+    for (subgroup, h2) in hashes
+        .chunks_mut(CLUSTERS_PER_SUBGROUP * CLUSTER_HASH_SIZE)
+        .zip(h2_table.chunks_mut(HASH_SIZE))
+    {
+        let mut h1_table = [0; H1_TABLE_SIZE];
+        for (hashes, h1) in subgroup
+            .chunks(CLUSTER_HASH_SIZE)
+            .zip(h1_table.chunks_mut(HASH_SIZE))
+        {
+            h1.copy_from_slice(&sha1(&hashes[..H0_TABLE_SIZE]));
+        }
+        for hashes in subgroup.chunks_mut(CLUSTER_HASH_SIZE) {
+            hashes[OFFSET_H1..][..H1_TABLE_SIZE].copy_from_slice(&h1_table);
+        }
+        h2.copy_from_slice(&sha1(&h1_table));
+    }
+    for hashes in hashes.chunks_mut(CLUSTER_HASH_SIZE) {
+        hashes[OFFSET_H2..][..H2_TABLE_SIZE].copy_from_slice(&h2_table);
+    }
+    patch_hashes(&mut hashes);
+
+    for ((cluster, hashes), data) in clusters
+        .chunks_mut(CLUSTER_SIZE)
+        .zip(hashes.chunks(CLUSTER_HASH_SIZE))
+        .zip(data.chunks(CLUSTER_DATA_SIZE))
+    {
+        let (hash_block, data_block) = cluster.split_at_mut(CLUSTER_HASH_SIZE);
+        hash_block.copy_from_slice(hashes);
+        cbc_encrypt(cipher, [0; 16], hash_block);
+
+        let mut iv = [0; 16];
+        iv.copy_from_slice(&hash_block[OFFSET_DATA_IV..][..16]);
+        data_block.copy_from_slice(data);
+        cbc_encrypt(cipher, iv, data_block);
+    }
+
+    sha1(&h2_table)
+}
+
+pub struct PartitionReader<'a, R> {
+    partition: &'a DataPartition,
+    disc: R,
+    position: u64,
+    cluster_index: Option<u64>,
+    cluster: Vec<u8>,
+}
+
+impl<'a, R> PartitionReader<'a, R> {
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.disc
+    }
+
+    fn len(&self) -> u64 {
+        self.partition.data_size / CLUSTER_SIZE as u64 * CLUSTER_DATA_SIZE as u64
+    }
+}
+
+impl<'a, R: Read + Seek> Read for PartitionReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len() || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let cluster_index = self.position / CLUSTER_DATA_SIZE as u64;
+        if self.cluster_index != Some(cluster_index) {
+            self.cluster_index = None;
+            self.disc.seek(SeekFrom::Start(
+                self.partition.data_offset() + cluster_index * CLUSTER_SIZE as u64,
+            ))?;
+            self.disc.read_exact(&mut self.cluster)?;
+            let mut iv = [0; 16];
+            iv.copy_from_slice(&self.cluster[OFFSET_DATA_IV..][..16]);
+            cbc_decrypt(
+                &self.partition.cipher,
+                iv,
+                &mut self.cluster[CLUSTER_HASH_SIZE..],
+            );
+            self.cluster_index = Some(cluster_index);
+        }
+
+        let offset = (self.position % CLUSTER_DATA_SIZE as u64) as usize;
+        let data = &self.cluster[CLUSTER_HASH_SIZE + offset..];
+        let len = cmp::min(buf.len(), data.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        self.position += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl<'a, R> Seek for PartitionReader<'a, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.len() as i64 + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+        if position < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seeked before the start of the partition",
+            ));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+pub struct PartitionWriter<'a, W> {
+    partition: &'a DataPartition,
+    game_id: Option<Vec<u8>>,
+    writer: W,
+    group_data: Vec<u8>,
+    group: Vec<u8>,
+    h3: Vec<u8>,
+    groups: usize,
+}
+
+impl<'a, W: Write + Seek> PartitionWriter<'a, W> {
+    fn write_group(&mut self) -> io::Result<()> {
+        if (self.groups + 1) * HASH_SIZE > H3_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "The data partition is too large to be hashed",
+            ));
+        }
+        let end = self.partition.data_offset() + ((self.groups + 1) * GROUP_SIZE) as u64;
+        if self
+            .partition
+            .next_partition_offset
+            .map_or(false, |o| end > o)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "The data partition grew into the partition that follows it",
+            ));
+        }
+
+        // The last group is padded with zeros
+        self.group_data.resize(GROUP_DATA_SIZE, 0);
+        let hash = self
+            .partition
+            .encrypt_group(&self.group_data, &mut self.group);
+        self.h3[self.groups * HASH_SIZE..][..HASH_SIZE].copy_from_slice(&hash);
+        self.groups += 1;
+        self.group_data.clear();
+
+        self.writer.write_all(&self.group)
+    }
+
+    /// Writes the remaining data and updates the partition's H3 table and TMD.
+    /// The partitions following the data partition are copied over from the
+    /// original disc.
+    pub fn finish<R: Read + Seek>(mut self, disc: &mut R) -> Result<W, Error> {
+        if !self.group_data.is_empty() {
+            self.write_group()?;
+        }
+
+        let data_size = (self.groups * GROUP_SIZE) as u64;
+        let end = self.partition.data_offset() + data_size;
+        if let Some(next_partition_offset) = self.partition.next_partition_offset {
+            io::copy(
+                &mut io::repeat(0).take(next_partition_offset - end),
+                &mut self.writer,
+            )?;
+            disc.seek(SeekFrom::Start(next_partition_offset))?;
+            io::copy(disc, &mut self.writer)?;
+        }
+
+        let mut header = self.partition.header.clone();
+        BE::write_u32(&mut header[OFFSET_DATA_SIZE..], (data_size >> 2) as u32);
+        header[self.partition.h3_offset..][..H3_SIZE].copy_from_slice(&self.h3);
+        header[self.partition.tmd_offset + OFFSET_TMD_CONTENT_HASH..][..HASH_SIZE]
+            .copy_from_slice(&sha1(&self.h3));
+
+        if let Some(ref game_code) = self.game_id {
+            let tmd_title_id = self.partition.tmd_offset + OFFSET_TMD_TITLE_ID;
+            header[tmd_title_id + 8 - GAME_CODE_LEN..][..GAME_CODE_LEN].copy_from_slice(game_code);
+            header[OFFSET_TITLE_ID + 8 - GAME_CODE_LEN..][..GAME_CODE_LEN]
+                .copy_from_slice(game_code);
+
+            // The title ID is the IV of the title key's encryption, so the
+            // title key needs to be encrypted again
+            encrypt_title_key(&mut header[..TICKET_LEN], &self.partition.title_key)?;
+        }
+
+        fakesign_ticket(&mut header[..TICKET_LEN])?;
+        fakesign_tmd(&mut header[self.partition.tmd_offset..][..self.partition.tmd_size])?;
+
+        self.writer.seek(SeekFrom::Start(self.partition.offset))?;
+        self.writer.write_all(&header)?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+impl<'a, W: Write + Seek> Write for PartitionWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = cmp::min(buf.len(), GROUP_DATA_SIZE - self.group_data.len());
+        self.group_data.extend_from_slice(&buf[..len]);
+        if self.group_data.len() == GROUP_DATA_SIZE {
+            self.write_group()?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decrypts the title key of the ticket with the common key it refers to. The
+/// ticket's title ID is the IV.
+pub fn decrypt_title_key(ticket: &[u8]) -> Result<[u8; 16], Error> {
+    ensure!(ticket.len() >= TICKET_LEN, "The ticket is truncated");
+    let mut title_key = [0; 16];
+    title_key.copy_from_slice(&ticket[OFFSET_TITLE_KEY..][..16]);
+    cbc_decrypt(
+        &Aes128::new(GenericArray::from_slice(common_key(ticket)?)),
+        title_key_iv(ticket),
+        &mut title_key,
+    );
+    Ok(title_key)
+}
+
+/// Stores the title key in the ticket, encrypted with the common key and the
+/// title ID the ticket refers to.
+pub fn encrypt_title_key(ticket: &mut [u8], title_key: &[u8; 16]) -> Result<(), Error> {
+    ensure!(ticket.len() >= TICKET_LEN, "The ticket is truncated");
+    let mut encrypted = *title_key;
+    cbc_encrypt(
+        &Aes128::new(GenericArray::from_slice(common_key(ticket)?)),
+        title_key_iv(ticket),
+        &mut encrypted,
+    );
+    ticket[OFFSET_TITLE_KEY..][..16].copy_from_slice(&encrypted);
+    Ok(())
+}
+
+fn common_key(ticket: &[u8]) -> Result<&'static [u8; 16], Error> {
+    let index = ticket[OFFSET_COMMON_KEY_INDEX] as usize;
+    COMMON_KEYS
+        .get(index)
+        .ok_or_else(|| format_err!("The ticket uses the unknown common key {}", index))
+}
+
+fn title_key_iv(ticket: &[u8]) -> [u8; 16] {
+    let mut iv = [0; 16];
+    iv[..8].copy_from_slice(&ticket[OFFSET_TITLE_ID..][..8]);
+    iv
+}
+
+/// Encrypts the data with the title key. Its length needs to be a multiple of
+/// 16.
+pub fn encrypt(title_key: &[u8; 16], iv: [u8; 16], data: &mut [u8]) {
+    cbc_encrypt(&Aes128::new(GenericArray::from_slice(title_key)), iv, data);
+}
+
+/// Decrypts the data with the title key. Its length needs to be a multiple of
+/// 16.
+pub fn decrypt(title_key: &[u8; 16], iv: [u8; 16], data: &mut [u8]) {
+    cbc_decrypt(&Aes128::new(GenericArray::from_slice(title_key)), iv, data);
+}
+
+pub fn fakesign_ticket(ticket: &mut [u8]) -> Result<(), Error> {
+    Ok(fakesign(ticket, OFFSET_TICKET_FAKESIGN_PAD).context("Couldn't fakesign the ticket")?)
+}
+
+pub fn fakesign_tmd(tmd: &mut [u8]) -> Result<(), Error> {
+    Ok(fakesign(tmd, OFFSET_TMD_FAKESIGN_PAD).context("Couldn't fakesign the TMD")?)
+}
+
+/// IOS versions with the Trucha bug compare the decrypted signature with the
+/// hash of the signed data using `strncmp`, so comparing stops at the first
+/// zero byte. A zeroed signature passes if the hash starts with a zero byte,
+/// which takes about 256 tries of changing unused bytes to find.
+fn fakesign(data: &mut [u8], pad_offset: usize) -> Result<(), Error> {
+    ensure!(
+        data.len() >= cmp::max(OFFSET_SIGNED_DATA, pad_offset + 4),
+        "The signed data is truncated"
+    );
+    for byte in &mut data[OFFSET_SIGNATURE..][..SIGNATURE_LEN] {
+        *byte = 0;
+    }
+    for pad in 0..0x1_0000 {
+        BE::write_u32(&mut data[pad_offset..], pad);
+        if sha1(&data[OFFSET_SIGNED_DATA..])[0] == 0 {
+            return Ok(());
+        }
+    }
+    bail!("No hash starting with a zero byte was found")
+}
+
+fn sha1(data: &[u8]) -> [u8; HASH_SIZE] {
+    let mut hasher = Sha1::new();
+    hasher.update(data);
+    hasher.digest().bytes()
+}
+
+fn cbc_decrypt(cipher: &Aes128, mut iv: [u8; 16], data: &mut [u8]) {
+    for block in data.chunks_mut(16) {
+        let mut next_iv = [0; 16];
+        next_iv.copy_from_slice(block);
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+        for (byte, &iv) in block.iter_mut().zip(&iv) {
+            *byte ^= iv;
+        }
+        iv = next_iv;
+    }
+}
+
+fn cbc_encrypt(cipher: &Aes128, mut iv: [u8; 16], data: &mut [u8]) {
+    for block in data.chunks_mut(16) {
+        for (byte, &iv) in block.iter_mut().zip(&iv) {
+            *byte ^= iv;
+        }
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        iv.copy_from_slice(block);
+    }
+}
@@ -1,13 +1,187 @@
-use super::virtual_file_system::{Directory, Node};
+use super::apploader::Apploader;
+use super::header::offset_shift;
+use super::junk::Junk;
+use super::virtual_file_system::{Directory, File, FileData, Node};
 use super::{consts::*, FstEntry, FstNodeType};
-use byteorder::{WriteBytesExt, BE};
-use failure::{err_msg, Error};
-use std::io::{Seek, SeekFrom, Write};
+use byteorder::{ByteOrder, WriteBytesExt, BE};
+use failure::{err_msg, Error, ResultExt};
+use std::borrow::Cow;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+/// Where the DOL and the FST are placed instead of directly after the parts
+/// of the disc that precede them. The audio streams may be kept where they
+/// are on the original disc, as long as the parts before them still fit. The
+/// gaps between the files are filled with Nintendo's junk, unless they are
+/// zeroed, which compresses better.
+#[derive(Default)]
+pub struct Layout {
+    pub dol_offset: Option<usize>,
+    pub fst_offset: Option<usize>,
+    pub keep_audio_streams: bool,
+    pub zero_padding: bool,
+    pub files: Vec<FileLayout>,
+}
+
+/// Aligns the files that match the pattern or places them at a fixed offset.
+/// The pattern is the path of a file, which may contain a `*` that matches
+/// anything, like `*.thp` or `audio/*`.
+pub struct FileLayout {
+    pub pattern: String,
+    pub alignment: Option<usize>,
+    pub offset: Option<usize>,
+}
+
+impl FileLayout {
+    fn matches(&self, path: &str) -> bool {
+        let pattern = self.pattern.to_ascii_lowercase();
+        let path = path.to_ascii_lowercase();
+        match pattern.find('*') {
+            Some(index) => {
+                let (prefix, suffix) = (&pattern[..index], &pattern[index + 1..]);
+                path.len() >= prefix.len() + suffix.len()
+                    && path.starts_with(prefix)
+                    && path.ends_with(suffix)
+            }
+            None => path == pattern,
+        }
+    }
+}
 
-pub fn write_iso<W>(mut writer: W, root: &Directory) -> Result<(), Error>
+/// The drive streams audio in blocks of 32 KiB, so the streams need to be
+/// aligned to them.
+const AUDIO_STREAM_ALIGNMENT: usize = 0x8000;
+const FILE_ALIGNMENT: usize = 32;
+
+/// Writes the disc. The files that are still stored on the original disc are
+/// copied over from the reader. Everything gets laid out before any data is
+/// written, so the disc is written front to back without seeking.
+pub fn write_iso<R, W>(reader: &mut R, writer: W, root: &Directory) -> Result<(), Error>
 where
-    W: Write + Seek,
+    R: Read + Seek,
+    W: Write,
 {
+    write_iso_with_layout(reader, writer, root, &Layout::default())
+}
+
+/// Where everything ends up on the disc, before any of it is written.
+struct Plan<'a, 'b: 'a> {
+    header: Vec<u8>,
+    apploader: Vec<u8>,
+    dol_offset_without_padding: usize,
+    dol_offset: usize,
+    dol: Cow<'a, [u8]>,
+    fst_list_offset_without_padding: usize,
+    fst_list_offset: usize,
+    fst_len: usize,
+    output_fst: Vec<FstEntry<'static>>,
+    fst_name_bank: Vec<u8>,
+    files: Vec<(usize, &'a File<'b>)>,
+}
+
+/// The parts of the disc that the disc would be written with, which are the
+/// system data, the FST and the files, so the gaps between them can be left
+/// out.
+pub fn used_ranges<R: Read + Seek>(
+    reader: &mut R,
+    root: &Directory,
+    layout: &Layout,
+) -> Result<Vec<Range<u64>>, Error> {
+    let plan = plan(reader, root, layout)?;
+    let mut ranges = vec![0..(plan.fst_list_offset + plan.fst_len) as u64];
+    ranges.extend(
+        plan.files
+            .iter()
+            .map(|&(offset, file)| offset as u64..(offset + file.len() as usize) as u64),
+    );
+    Ok(ranges)
+}
+
+/// Writes the disc like `write_iso`, but places the DOL and the FST at the
+/// offsets of the layout.
+pub fn write_iso_with_layout<R, W>(
+    reader: &mut R,
+    mut writer: W,
+    root: &Directory,
+    layout: &Layout,
+) -> Result<(), Error>
+where
+    R: Read + Seek,
+    W: Write,
+{
+    let Plan {
+        header,
+        apploader,
+        dol_offset_without_padding,
+        dol_offset,
+        dol,
+        fst_list_offset_without_padding,
+        fst_list_offset,
+        fst_len,
+        output_fst,
+        fst_name_bank,
+        mut files,
+    } = plan(reader, root, layout)?;
+
+    writer.write_all(&header)?;
+    writer.write_all(&apploader)?;
+    write_padding(&mut writer, dol_offset - dol_offset_without_padding)?;
+    writer.write_all(&dol)?;
+    write_padding(
+        &mut writer,
+        fst_list_offset - fst_list_offset_without_padding,
+    )?;
+
+    for entry in &output_fst {
+        writer.write_u8(entry.kind as u8)?;
+        writer.write_u8(0)?;
+        writer.write_u16::<BE>(entry.file_name_offset as u16)?;
+        writer.write_i32::<BE>(entry.file_offset_parent_dir as i32)?;
+        writer.write_i32::<BE>(entry.file_size_next_dir_index as i32)?;
+    }
+
+    writer.write_all(&fst_name_bank)?;
+
+    let mut junk = Junk::new(&header);
+    let mut write_gap = |writer: &mut W, start: usize, end: usize| {
+        if layout.zero_padding {
+            write_padding(writer, end - start)
+        } else {
+            junk.write_gap(writer, start as u64, end as u64)
+        }
+    };
+
+    files.sort_by_key(|&(offset, _)| offset);
+    let mut position = fst_list_offset + fst_len;
+    for (offset, file) in files {
+        write_gap(&mut writer, position, offset)?;
+        match file.data {
+            FileData::Disc { offset, len } => {
+                reader.seek(SeekFrom::Start(offset))?;
+                let copied = io::copy(&mut reader.by_ref().take(len), &mut writer)?;
+                ensure!(
+                    copied == len,
+                    "The file \"{}\" is truncated on the original disc",
+                    file.name
+                );
+            }
+            FileData::Memory(ref data) => writer.write_all(data)?,
+        }
+        position = offset + file.len() as usize;
+    }
+    write_gap(&mut writer, position, (position + 31) & !31)?;
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Lays out the disc and builds its FST.
+fn plan<'a, 'b, R: Read + Seek>(
+    reader: &mut R,
+    root: &'a Directory<'b>,
+    layout: &Layout,
+) -> Result<Plan<'a, 'b>, Error> {
     let (sys_index, sys_dir) = root
         .children
         .iter()
@@ -22,7 +196,11 @@ where
         .filter_map(|c| c.as_file())
         .find(|f| f.name == "iso.hdr")
         .ok_or_else(|| err_msg("The &&systemdata folder contains no iso.hdr"))?;
-    writer.write_all(&header.data)?;
+    let mut header = header
+        .read(reader)
+        .context("Couldn't read the iso.hdr")?
+        .into_owned();
+    let shift = offset_shift(&header);
 
     let apploader = sys_dir
         .children
@@ -30,15 +208,20 @@ where
         .filter_map(|c| c.as_file())
         .find(|f| f.name == "AppLoader.ldr")
         .ok_or_else(|| err_msg("The &&systemdata folder contains no AppLoader.ldr"))?;
-    writer.write_all(&apploader.data)?;
-
-    let dol_offset_without_padding = header.data.len() + apploader.data.len();
-    let dol_offset =
-        (dol_offset_without_padding + (DOL_ALIGNMENT - 1)) / DOL_ALIGNMENT * DOL_ALIGNMENT;
-
-    for _ in dol_offset_without_padding..dol_offset {
-        writer.write_all(&[0])?;
-    }
+    let apploader = apploader
+        .read(reader)
+        .context("Couldn't read the AppLoader.ldr")?;
+    let apploader = Apploader::parse(&apploader)
+        .context("Couldn't parse the AppLoader.ldr")?
+        .to_bytes();
+
+    let dol_offset_without_padding = header.len() + apploader.len();
+    let dol_offset = place(
+        dol_offset_without_padding,
+        DOL_ALIGNMENT,
+        layout.dol_offset,
+        "DOL",
+    )?;
 
     let dol = sys_dir
         .children
@@ -46,15 +229,15 @@ where
         .filter_map(|c| c.as_file())
         .find(|f| f.name.ends_with(".dol"))
         .ok_or_else(|| err_msg("The &&systemdata folder contains no dol file"))?;
-    writer.write_all(&dol.data)?;
+    let dol = dol.read(reader).context("Couldn't read the dol file")?;
 
-    let fst_list_offset_without_padding = dol_offset + dol.data.len();
-    let fst_list_offset =
-        (fst_list_offset_without_padding + (FST_ALIGNMENT - 1)) / FST_ALIGNMENT * FST_ALIGNMENT;
-
-    for _ in fst_list_offset_without_padding..fst_list_offset {
-        writer.write_all(&[0])?;
-    }
+    let fst_list_offset_without_padding = dol_offset + dol.len();
+    let fst_list_offset = place(
+        fst_list_offset_without_padding,
+        FST_ALIGNMENT,
+        layout.fst_offset,
+        "FST",
+    )?;
 
     let mut fst_len = 12;
     for (_, node) in root
@@ -66,12 +249,6 @@ where
         fst_len = calculate_fst_len(fst_len, node);
     }
 
-    for _ in 0..fst_len {
-        // TODO Seems suboptimal
-        // Should not be a problem with BufWriter though
-        writer.write_all(&[0])?;
-    }
-
     let root_fst = FstEntry {
         kind: FstNodeType::Directory,
         ..Default::default()
@@ -80,6 +257,22 @@ where
     // Placeholder FST entry for the root
     let mut output_fst = vec![root_fst];
     let mut fst_name_bank = Vec::new();
+    let mut files = Vec::new();
+    let mut placement = Placement {
+        position: fst_list_offset + fst_len,
+        pinned: Vec::new(),
+        layout,
+    };
+    let mut paths = Vec::new();
+    for (_, node) in root
+        .children
+        .iter()
+        .enumerate()
+        .filter(|&(i, _)| i != sys_index)
+    {
+        collect_paths(node, "", &mut paths);
+    }
+    placement.pin(&paths)?;
 
     for (_, node) in root
         .children
@@ -87,30 +280,231 @@ where
         .enumerate()
         .filter(|&(i, _)| i != sys_index)
     {
-        do_output_prep(node, &mut output_fst, &mut fst_name_bank, &mut writer, 0)?;
+        do_output_prep(
+            node,
+            &mut output_fst,
+            &mut fst_name_bank,
+            &mut files,
+            &mut placement,
+            "",
+            0,
+            shift,
+        );
     }
 
     // Add actual root FST entry
     output_fst[0].file_size_next_dir_index = output_fst.len();
 
-    writer.seek(SeekFrom::Start(fst_list_offset as u64))?;
+    let fst_size = (fst_len + (1 << shift) - 1) >> shift;
+    BE::write_u32(
+        &mut header[OFFSET_DOL_OFFSET..],
+        (dol_offset >> shift) as u32,
+    );
+    BE::write_u32(
+        &mut header[OFFSET_FST_OFFSET..],
+        (fst_list_offset >> shift) as u32,
+    );
+    BE::write_u32(&mut header[OFFSET_FST_SIZE..], fst_size as u32);
+    BE::write_u32(&mut header[OFFSET_FST_SIZE + 4..], fst_size as u32);
+
+    Ok(Plan {
+        header,
+        apploader,
+        dol_offset_without_padding,
+        dol_offset,
+        dol,
+        fst_list_offset_without_padding,
+        fst_list_offset,
+        fst_len,
+        output_fst,
+        fst_name_bank,
+        files,
+    })
+}
 
-    for entry in &output_fst {
-        writer.write_u8(entry.kind as u8)?;
-        writer.write_u8(0)?;
-        writer.write_u16::<BE>(entry.file_name_offset as u16)?;
-        writer.write_i32::<BE>(entry.file_offset_parent_dir as i32)?;
-        writer.write_i32::<BE>(entry.file_size_next_dir_index as i32)?;
+/// Places a part of the disc at the next aligned offset, unless the layout
+/// places it elsewhere.
+fn place(
+    offset: usize,
+    alignment: usize,
+    layout_offset: Option<usize>,
+    name: &str,
+) -> Result<usize, Error> {
+    match layout_offset {
+        Some(layout_offset) => {
+            ensure!(
+                layout_offset % alignment == 0,
+                "The {} offset {:#x} needs to be aligned to {:#x} bytes",
+                name,
+                layout_offset,
+                alignment
+            );
+            ensure!(
+                layout_offset >= offset,
+                "The {} can't be placed at {:#x}, as what precedes it ends at {:#x}",
+                name,
+                layout_offset,
+                offset
+            );
+            Ok(layout_offset)
+        }
+        None => Ok((offset + (alignment - 1)) / alignment * alignment),
+    }
+}
+
+/// Places the files one after another behind the FST. The files that are
+/// pinned to their offsets are skipped over.
+struct Placement<'l> {
+    position: usize,
+    pinned: Vec<(Range<usize>, String)>,
+    layout: &'l Layout,
+}
+
+impl<'l> Placement<'l> {
+    /// The file layout for the path. A layout for exactly this path takes
+    /// precedence over the patterns.
+    fn file_layout(&self, path: &str) -> Option<&'l FileLayout> {
+        let layout: &'l Layout = self.layout;
+        let files = &layout.files;
+        files
+            .iter()
+            .find(|f| f.pattern.eq_ignore_ascii_case(path))
+            .or_else(|| files.iter().find(|f| f.matches(path)))
     }
 
-    writer.write_all(&fst_name_bank)?;
+    fn overlapping(&self, range: &Range<usize>) -> Option<&(Range<usize>, String)> {
+        self.pinned
+            .iter()
+            .find(|&&(ref r, _)| range.start < r.end && r.start < range.end)
+    }
+
+    /// Pins the files with fixed offsets first. Audio streams that are still
+    /// the ones from the original disc keep their offsets afterwards, unless
+    /// something else is in their way now.
+    fn pin(&mut self, paths: &[(String, &File)]) -> Result<(), Error> {
+        for &(ref path, file) in paths {
+            let offset = match self.file_layout(path).and_then(|f| f.offset) {
+                Some(offset) => offset,
+                None => continue,
+            };
+            ensure!(
+                offset >= self.position,
+                "The file \"{}\" can't be placed at {:#x}, as the FST ends at {:#x}",
+                path,
+                offset,
+                self.position
+            );
+            let range = offset..offset + file.len() as usize;
+            if let Some(&(ref other, ref other_path)) = self.overlapping(&range) {
+                bail!(
+                    "The file \"{}\" at {:#x}..{:#x} overlaps the file \"{}\" at {:#x}..{:#x}",
+                    path,
+                    range.start,
+                    range.end,
+                    other_path,
+                    other.start,
+                    other.end
+                );
+            }
+            self.pinned.push((range, path.clone()));
+        }
+
+        for layout in self.layout.files.iter().filter(|f| f.offset.is_some()) {
+            ensure!(
+                paths.iter().any(|&(ref p, _)| layout.matches(p)),
+                "There is no file \"{}\" to place at {:#x}",
+                layout.pattern,
+                layout.offset.unwrap()
+            );
+        }
+
+        if self.layout.keep_audio_streams {
+            for &(ref path, file) in paths {
+                if !is_audio_stream(file) || self.pinned_offset(path).is_some() {
+                    continue;
+                }
+                if let FileData::Disc { offset, len } = file.data {
+                    let range = offset as usize..(offset + len) as usize;
+                    if len > 0 && range.start >= self.position && self.overlapping(&range).is_none()
+                    {
+                        self.pinned.push((range, path.clone()));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn pinned_offset(&self, path: &str) -> Option<usize> {
+        self.pinned
+            .iter()
+            .find(|&&(_, ref p)| p == path)
+            .map(|&(ref range, _)| range.start)
+    }
+
+    fn place(&mut self, path: &str, file: &File) -> usize {
+        if let Some(offset) = self.pinned_offset(path) {
+            return offset;
+        }
 
-    writer.seek(SeekFrom::Start(OFFSET_DOL_OFFSET as u64))?;
-    writer.write_u32::<BE>(dol_offset as u32)?;
-    writer.write_u32::<BE>(fst_list_offset as u32)?;
-    writer.write_u32::<BE>(fst_len as u32)?;
-    writer.write_u32::<BE>(fst_len as u32)?;
+        let default_alignment = if is_audio_stream(file) {
+            AUDIO_STREAM_ALIGNMENT
+        } else {
+            FILE_ALIGNMENT
+        };
+        let alignment = self
+            .file_layout(path)
+            .and_then(|f| f.alignment)
+            .map_or(default_alignment, |a| a.max(default_alignment));
+        let len = file.len() as usize;
+        loop {
+            let offset = (self.position + (alignment - 1)) / alignment * alignment;
+            let end = self
+                .overlapping(&(offset..offset + len))
+                .map(|&(ref range, _)| range.end);
+            match end {
+                Some(end) => self.position = end,
+                None => {
+                    self.position = offset + len;
+                    return offset;
+                }
+            }
+        }
+    }
+}
+
+fn join_path(parent: &str, name: &str) -> String {
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+fn collect_paths<'a, 'b>(
+    node: &'b Node<'a>,
+    parent: &str,
+    paths: &mut Vec<(String, &'b File<'a>)>,
+) {
+    match *node {
+        Node::Directory(ref dir) => {
+            let path = join_path(parent, dir.name);
+            for child in &dir.children {
+                collect_paths(child, &path, paths);
+            }
+        }
+        Node::File(ref file) => paths.push((join_path(parent, file.name), file)),
+    }
+}
+
+fn is_audio_stream(file: &File) -> bool {
+    let name = file.name.to_ascii_lowercase();
+    name.ends_with(".adp") || name.ends_with(".dtk")
+}
 
+fn write_padding<W: Write>(writer: &mut W, len: usize) -> io::Result<()> {
+    io::copy(&mut io::repeat(0).take(len as u64), writer)?;
     Ok(())
 }
 
@@ -130,16 +524,16 @@ fn calculate_fst_len(mut cur_value: usize, node: &Node) -> usize {
     cur_value
 }
 
-fn do_output_prep<W>(
-    node: &Node,
+fn do_output_prep<'a, 'b>(
+    node: &'b Node<'a>,
     output_fst: &mut Vec<FstEntry>,
     fst_name_bank: &mut Vec<u8>,
-    writer: &mut W,
+    files: &mut Vec<(usize, &'b File<'a>)>,
+    placement: &mut Placement,
+    parent: &str,
     mut cur_parent_dir_index: usize,
-) -> Result<(), Error>
-where
-    W: Write + Seek,
-{
+    shift: u32,
+) {
     match *node {
         Node::Directory(ref dir) => {
             let fst_ent = FstEntry {
@@ -158,23 +552,30 @@ where
 
             output_fst.push(fst_ent); // Placeholder for this dir
 
+            let path = join_path(parent, dir.name);
+
             for child in &dir.children {
                 do_output_prep(
                     child,
                     output_fst,
                     fst_name_bank,
-                    writer,
+                    files,
+                    placement,
+                    &path,
                     cur_parent_dir_index,
-                )?;
+                    shift,
+                );
             }
 
             let dir_end_index = output_fst.len();
             output_fst[this_dir_index].file_size_next_dir_index = dir_end_index;
         }
         Node::File(ref file) => {
-            let mut fst_ent = FstEntry {
+            let offset = placement.place(&join_path(parent, file.name), file);
+            let fst_ent = FstEntry {
                 kind: FstNodeType::File,
-                file_size_next_dir_index: file.data.len(),
+                file_offset_parent_dir: offset >> shift,
+                file_size_next_dir_index: file.len() as usize,
                 file_name_offset: fst_name_bank.len(),
                 ..Default::default()
             };
@@ -182,21 +583,9 @@ where
             fst_name_bank.extend_from_slice(file.name.as_bytes());
             fst_name_bank.push(0);
 
-            let pos = writer.seek(SeekFrom::Current(0))?;
-            let new_pos = pos + (32 - (pos % 32)) % 32;
-            writer.seek(SeekFrom::Start(new_pos))?;
-
-            fst_ent.file_offset_parent_dir = new_pos as usize;
-
-            writer.write_all(&file.data)?;
-
-            for _ in 0..(32 - (file.data.len() % 32)) % 32 {
-                writer.write_all(&[0])?;
-            }
+            files.push((offset, file));
 
             output_fst.push(fst_ent);
         }
     }
-
-    Ok(())
 }
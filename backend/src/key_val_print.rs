@@ -1,10 +1,28 @@
 pub enum MessageKind {
     Warning,
     Error,
+    /// Details that are only worth printing when asked for, like every branch
+    /// that is redirected.
+    Verbose,
+    /// Details that are only useful for debugging the build itself, like
+    /// every allocation of generated code.
+    Debug,
+}
+
+/// What a message is about, so tools reading the structured log can point at
+/// it without parsing the message.
+#[derive(Default)]
+pub struct Fields<'a> {
+    pub file: Option<&'a str>,
+    pub address: Option<u32>,
 }
 
 pub trait KeyValPrint {
     fn print(&self, kind: Option<MessageKind>, key: &str, val: &str);
+
+    fn print_with(&self, kind: Option<MessageKind>, key: &str, val: &str, _fields: &Fields) {
+        self.print(kind, key, val)
+    }
 }
 
 pub struct DontPrint;
@@ -1,89 +1,614 @@
+extern crate aes;
 extern crate byteorder;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate bzip2;
 extern crate encoding_rs;
 #[macro_use]
 extern crate failure;
+extern crate flate2;
 extern crate goblin;
 extern crate image;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate memmap;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate rayon;
 extern crate regex;
 extern crate rustc_demangle;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+extern crate serde_json;
+extern crate sha1;
 extern crate standalone_syn as syn;
+extern crate time;
 extern crate toml;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate xz2;
 extern crate zip;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate zstd;
 
+mod ar;
+mod archive;
+mod arena;
 mod assembler;
-mod banner;
+pub mod audio;
+pub mod banner;
+pub mod bmg;
+mod bss;
+mod c_symbols;
+mod cache;
+mod codec;
 mod config;
+mod conflicts;
+mod decompressor;
 mod demangle;
+mod diff;
 mod dol;
+mod dolphin;
+mod dolphin_ini;
+mod elf;
+mod entry;
 mod file_source;
+pub mod font;
 mod framework_map;
+pub mod gci;
+mod gdbinit;
+mod gecko;
+mod hook;
+mod inject;
 pub mod iso;
 mod key_val_print;
 mod linker;
+mod linker_script;
+mod manifest;
+mod mem2;
+mod memory_map;
+pub mod patch_source;
+pub mod patchfile;
+mod port_map;
+pub mod rarc;
+mod redump;
+pub mod rel;
+mod replace;
+mod report;
+mod riff;
+mod riivolution;
+mod signature;
+mod stats;
+mod strings;
+mod symbol_export;
+mod symbols;
+mod text_encoding;
+pub mod texture;
+pub mod thp;
+pub mod u8arc;
+mod veneer;
+pub mod wad;
+mod watch;
 
+use archive::Archive;
 use assembler::Assembler;
-use assembler::Instruction;
+use assembler::{build_branch_instruction, Expectation, Instruction, Location};
 use banner::Banner;
-use config::Config;
-use dol::DolFile;
+use byteorder::{ByteOrder, BE};
+use bmg::Bmg;
+use cache::Cache;
+use codec::{Codec, Yaz0};
+use config::{Compression, Config, OutputFormat, Padding, Platform, Source};
+use dol::{DolFile, MEM1_END};
 use failure::{err_msg, Error, ResultExt};
 use file_source::{FileSource, FileSystem};
-use iso::virtual_file_system::Directory;
-pub use key_val_print::{DontPrint, KeyValPrint, MessageKind};
-use std::collections::HashMap;
+use framework_map::InjectedSymbol;
+use hook::Hook;
+use linker::LinkedSection;
+use manifest::{Change, Manifest};
+use patch_source::PatchSource;
+use port_map::PortMap;
+#[cfg(not(target_arch = "wasm32"))]
+use rayon::prelude::*;
+use rel::RelFile;
+use iso::apploader::Apploader;
+use iso::disc::Disc;
+use iso::header::{
+    Header, OFFSET_ARGUMENT_OFFSET, OFFSET_COUNTRY_CODE, OFFSET_DEBUG_FLAG,
+    OFFSET_SIMULATED_MEMORY_SIZE,
+};
+use iso::reader::SystemData;
+use iso::virtual_file_system::{Directory, FileData};
+use iso::writer::{FileLayout, Layout};
+pub use key_val_print::{DontPrint, Fields, KeyValPrint, MessageKind};
+pub use watch::{run, watch};
+use sha1::Sha1;
+use stats::Stats;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
 use std::fs::{self, File, OpenOptions};
-use std::io::{prelude::*, BufReader, BufWriter};
+use std::io::{prelude::*, BufReader, BufWriter, SeekFrom};
+use std::iter;
 use std::mem;
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::str;
+use text_encoding::{Table, TextEncoding};
 use zip::{write::FileOptions, ZipArchive, ZipWriter};
 
-pub fn build<P: KeyValPrint>(printer: &P, debug: bool, patch: bool) -> Result<(), Error> {
+/// How the Rom Hack is built, as chosen on the command line.
+#[derive(Default, Copy, Clone)]
+pub struct BuildOptions<'a> {
+    /// Compiles the Rom Hack in Rust's debug mode.
+    pub debug: bool,
+    pub patch: bool,
+    pub riivolution: bool,
+    /// Only checks that the Rom Hack builds, without writing any output.
+    pub check: bool,
+    pub verify_reproducible: bool,
+    pub defines: &'a [String],
+    pub features: &'a [String],
+}
+
+pub fn build<P: KeyValPrint>(printer: &P, options: &BuildOptions) -> Result<(), Error> {
+    let BuildOptions {
+        debug,
+        patch,
+        riivolution,
+        check,
+        verify_reproducible,
+        defines,
+        features,
+    } = *options;
+
     let mut toml_buf = String::new();
     File::open("RomHack.toml")
         .context("Couldn't find \"RomHack.toml\".")?
         .read_to_string(&mut toml_buf)
         .context("Failed to read \"RomHack.toml\".")?;
 
-    let config: Config = toml::from_str(&toml_buf).context("Can't parse RomHack.toml")?;
+    // Every region is built from its own copy of the config
+    let parse_config = || -> Result<Config, Error> {
+        let mut config: Config =
+            toml::from_str(&toml_buf).context("Can't parse RomHack.toml")?;
+        config.src.defines.extend(defines.iter().cloned());
+        if debug {
+            config.src.defines.push("DEBUG".into());
+        }
+        select_features(&mut config, features)?;
+        Ok(config)
+    };
+    let config = parse_config()?;
 
     printer.print(None, "Compiling", "");
+    let compiled_lib = compile(&config, debug, None)?;
+    // The second build starts from scratch, so nothing of the first one, like
+    // the compiled library, can hide a difference between the builds
+    let rebuilt_lib = if verify_reproducible {
+        printer.print(None, "Recompiling", "from scratch to verify the build");
+        let target_dir = env::current_dir()
+            .context("Couldn't determine the current directory")?
+            .join("target")
+            .join("reproducible");
+        if target_dir.exists() {
+            fs::remove_dir_all(&target_dir)
+                .context("Couldn't remove the previous build to verify")?;
+        }
+        Some(compile(&config, debug, Some(&target_dir))?)
+    } else {
+        None
+    };
 
-    {
-        let mut command = Command::new("cargo");
-        command
-            .args(&["build", "--target", "powerpc-unknown-linux-gnu"])
-            .env("RUSTFLAGS", "-C target-feature=+msync,+fres,+frsqrte");
+    // The command line flags take precedence over the configured format
+    let format = if patch {
+        OutputFormat::Patch
+    } else if riivolution {
+        OutputFormat::Riivolution
+    } else {
+        config.build.format
+    };
+
+    let regions = if config.regions.is_empty() {
+        vec![None]
+    } else {
+        config.regions.keys().map(Some).collect()
+    };
+    // The discs of a Riivolution patch are combined into a single patch
+    let discs = if config.discs.is_empty() || format == OutputFormat::Riivolution {
+        vec![None]
+    } else {
+        config.discs.keys().map(Some).collect()
+    };
 
-        if !debug {
-            command.arg("--release");
+    for region in &regions {
+        for disc in &discs {
+            let parse_target_config = || -> Result<Config, Error> {
+                let mut config = parse_config()?;
+                if let Some(region) = *region {
+                    select_region(&mut config, region);
+                }
+                if let Some(disc) = *disc {
+                    select_disc(&mut config, disc);
+                }
+                Ok(config)
+            };
+
+            let target = match (*region, *disc) {
+                (Some(region), Some(disc)) => Some(format!("region {} of disc {}", region, disc)),
+                (Some(region), None) => Some(format!("region {}", region)),
+                (None, Some(disc)) => Some(format!("disc {}", disc)),
+                (None, None) => None,
+            };
+            if let Some(ref target) = target {
+                printer.print(None, "Building", target);
+            }
+
+            let result = build_format(
+                printer,
+                format,
+                check,
+                compiled_lib.clone(),
+                rebuilt_lib.clone(),
+                &parse_target_config,
+                &toml_buf,
+            );
+            match target {
+                Some(target) => {
+                    result.with_context(|_| format!("Couldn't build the {}", target))?
+                }
+                None => result?,
+            }
         }
+    }
+
+    Ok(())
+}
+
+/// Compiles the Rom Hack's crate and reads the static library. It's compiled
+/// into the target directory, if there is one, instead of the usual one.
+fn compile(config: &Config, debug: bool, target_dir: Option<&Path>) -> Result<Vec<u8>, Error> {
+    let mut command = Command::new("cargo");
+    command
+        .args(&["build", "--target", "powerpc-unknown-linux-gnu"])
+        .env("RUSTFLAGS", "-C target-feature=+msync,+fres,+frsqrte");
+
+    if let Some(target_dir) = target_dir {
+        command.env("CARGO_TARGET_DIR", target_dir);
+    }
+
+    if !debug {
+        command.arg("--release");
+    }
+
+    if let Some(ref src_dir) = config.src.src {
+        command.current_dir(src_dir);
+    }
+
+    let exit_code = command
+        .spawn()
+        .context("Couldn't build the project")?
+        .wait()?;
+
+    ensure!(exit_code.success(), "Couldn't build the project");
+
+    let target_dir = target_dir.unwrap_or_else(|| Path::new("target"));
+    let path_to_compiled_lib = find_compiled_library(target_dir, debug)
+        .context("Couldn't find the compiled static library")?;
+    Ok(fs::read(path_to_compiled_lib).context("Couldn't read the compiled static library")?)
+}
+
+/// Builds the output in the given format, unless it is already up to date.
+/// When only checking the Rom Hack, the output is never considered up to date.
+/// Verifying that the build is reproducible builds the output a second time
+/// from the library that was compiled from scratch, and compares it and all
+/// the other outputs with the first build.
+fn build_format<P: KeyValPrint>(
+    printer: &P,
+    format: OutputFormat,
+    check: bool,
+    compiled_lib: Vec<u8>,
+    rebuilt_lib: Option<Vec<u8>>,
+    parse_config: &Fn() -> Result<Config, Error>,
+    toml_buf: &str,
+) -> Result<(), Error> {
+    let config = parse_config()?;
+    if check {
+        verify_original_game(printer, &config)?;
+        return check_game(printer, FileSystem, compiled_lib, config);
+    }
 
-        if let Some(ref src_dir) = config.src.src {
-            command.current_dir(src_dir);
+    let output = match format {
+        OutputFormat::Patch => config.build.iso.with_extension("patch"),
+        OutputFormat::Riivolution => config.build.iso.with_extension(""),
+        OutputFormat::Iso => config.build.iso.clone(),
+        OutputFormat::Wbfs => config.build.iso.with_extension("wbfs"),
+        OutputFormat::Ciso => config.build.iso.with_extension("ciso"),
+        OutputFormat::Wad => config.build.iso.with_extension("wad"),
+    };
+    let outputs = iter::once(output.clone())
+        .chain(config.build.map.clone())
+        .chain(config.build.report.clone())
+        .chain(config.build.elf.clone())
+        .chain(config.build.symbol_export.clone())
+        .chain(config.build.c_symbols.clone())
+        .chain(config.build.linker_script.clone())
+        .chain(config.build.gdbinit.clone())
+        .chain(config.build.manifest.clone())
+        .chain(config.build.stats.clone())
+        .chain(config.build.dolphin_ini.clone())
+        .collect::<Vec<_>>();
+    let settings = format!("{:?} {:?}\n{}", format, config.src.defines, toml_buf);
+    let cache = Cache::new(&config, output, &settings, &compiled_lib)?;
+    let rebuilt_lib = match rebuilt_lib {
+        Some(rebuilt_lib) => rebuilt_lib,
+        None => {
+            if cache.is_fresh() {
+                printer.print(None, "Fresh", "the output is up to date");
+                return Ok(());
+            }
+            verify_original_game(printer, &config)?;
+            emit_format(printer, format, compiled_lib, config)?;
+            return cache.store();
         }
+    };
+
+    verify_original_game(printer, &config)?;
+    emit_format(printer, format, compiled_lib, config)?;
+    // Split ISOs and WBFS files continue in more files than the output
+    let outputs = with_split_parts(format, outputs);
+    let hashes = outputs
+        .iter()
+        .map(|path| hash_output(path))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    printer.print(None, "Rebuilding", "to verify that the build is reproducible");
+    emit_format(printer, format, rebuilt_lib, parse_config()?)?;
+    for (path, hash) in outputs.iter().zip(hashes) {
+        ensure!(
+            hash_output(path)? == hash,
+            "Building the Rom Hack twice resulted in different versions of \"{}\"",
+            path.display()
+        );
+    }
+    printer.print(None, "Verified", "both builds are identical");
 
-        let exit_code = command
-            .spawn()
-            .context("Couldn't build the project")?
-            .wait()?;
+    cache.store()
+}
 
-        ensure!(exit_code.success(), "Couldn't build the project");
+fn emit_format<P: KeyValPrint>(
+    printer: &P,
+    format: OutputFormat,
+    compiled_lib: Vec<u8>,
+    mut config: Config,
+) -> Result<(), Error> {
+    match format {
+        OutputFormat::Patch => build_patch(printer, compiled_lib, config),
+        OutputFormat::Riivolution => {
+            build_and_emit_riivolution(printer, FileSystem, compiled_lib, config)
+        }
+        OutputFormat::Iso => build_and_emit_iso(printer, FileSystem, compiled_lib, config),
+        OutputFormat::Wbfs => {
+            config.build.iso.set_extension("wbfs");
+            build_and_emit_iso(printer, FileSystem, compiled_lib, config)
+        }
+        OutputFormat::Ciso => {
+            config.build.iso.set_extension("ciso");
+            build_and_emit_iso(printer, FileSystem, compiled_lib, config)
+        }
+        OutputFormat::Wad => build_and_emit_wad(printer, FileSystem, compiled_lib, config),
     }
+}
 
-    let path_to_compiled_lib =
-        find_compiled_library(debug).context("Couldn't find the compiled static library")?;
-    let compiled_lib =
-        fs::read(path_to_compiled_lib).context("Couldn't read the compiled static library")?;
+/// Adds the parts after the first one of the output, which is the first of
+/// the outputs, if it's split into several files.
+fn with_split_parts(format: OutputFormat, mut outputs: Vec<PathBuf>) -> Vec<PathBuf> {
+    let part_path: fn(&Path, usize) -> PathBuf = match format {
+        OutputFormat::Wbfs => iso::wbfs::part_path,
+        OutputFormat::Iso if iso::split::is_split(&outputs[0]) => iso::split::part_path,
+        _ => return outputs,
+    };
+    let parts = (1..)
+        .map(|index| part_path(&outputs[0], index))
+        .take_while(|path| path.exists())
+        .collect::<Vec<_>>();
+    outputs.extend(parts);
+    outputs
+}
+
+/// Hashes the file or all the files of the directory, along with their names.
+fn hash_output(path: &Path) -> Result<String, Error> {
+    let mut hasher = Sha1::new();
+    hash_path(path, &mut hasher)
+        .with_context(|_| format!("Couldn't hash the output \"{}\"", path.display()))?;
+    Ok(hasher.digest().to_string())
+}
 
-    if patch {
-        build_patch(printer, compiled_lib, config)
+fn hash_path(path: &Path, hasher: &mut Sha1) -> Result<(), Error> {
+    if path.is_dir() {
+        let mut entries = fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort();
+        for entry in entries {
+            let name = entry.file_name().unwrap_or_default().to_string_lossy();
+            hasher.update(name.as_bytes());
+            hash_path(&entry, hasher)?;
+        }
     } else {
-        build_and_emit_iso(printer, FileSystem, compiled_lib, config)
+        let mut file = File::open(path)?;
+        let mut buf = [0; 0x10000];
+        loop {
+            let len = file.read(&mut buf)?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buf[..len]);
+        }
+    }
+    Ok(())
+}
+
+/// Warns when the original game isn't one of the known good dumps of the
+/// configured DAT file. The result is remembered until the game or the DAT
+/// file changes.
+fn verify_original_game<P: KeyValPrint>(printer: &P, config: &Config) -> Result<(), Error> {
+    let dat = match config.src.dat {
+        Some(ref dat) => dat,
+        None => return Ok(()),
+    };
+
+    // Hashing the whole game takes a while, so it's only done once for every
+    // version of the game
+    let key = cache::verification_key(&config.src.iso, dat)?;
+    let name = match cache::verified_game(&key) {
+        Some(name) => name,
+        None => {
+            printer.print(None, "Verifying", "original game");
+            let name = identify_game(&config.src.iso, dat)?;
+            cache::store_verified_game(&key, name.as_ref().map(|n| &**n))?;
+            name
+        }
+    };
+
+    match name {
+        Some(name) => printer.print(None, "Verified", &name),
+        None => printer.print_with(
+            Some(MessageKind::Warning),
+            "Warning",
+            &format!(
+                "\"{}\" is not a known good dump. It's either a bad dump or a different \
+                 region or revision of the game, so the Rom Hack may not work for others.",
+                config.src.iso.display()
+            ),
+            &Fields {
+                file: config.src.iso.to_str(),
+                ..Default::default()
+            },
+        ),
+    }
+
+    Ok(())
+}
+
+/// Looks up the game in the DAT file and returns the name of its dump, if it
+/// is one of the known good dumps.
+fn identify_game(game: &Path, dat: &Path) -> Result<Option<String>, Error> {
+    let text = fs::read_to_string(dat)
+        .with_context(|_| format!("Couldn't read the DAT file \"{}\".", dat.display()))?;
+    let dumps = redump::parse_dat(&text)
+        .with_context(|_| format!("Couldn't parse the DAT file \"{}\".", dat.display()))?;
+
+    let mut disc = iso::disc::open(game)?;
+    let (len, crc) = redump::hash(&mut disc)
+        .with_context(|_| format!("Couldn't hash \"{}\".", game.display()))?;
+
+    Ok(redump::find(&dumps, len, crc).map(|d| d.name.clone()))
+}
+
+/// Checks whether the game is one of the known good dumps of the DAT file.
+pub fn verify<P: KeyValPrint>(printer: &P, game: PathBuf, dat: PathBuf) -> Result<(), Error> {
+    printer.print(None, "Verifying", "game");
+
+    match identify_game(&game, &dat)? {
+        Some(name) => {
+            printer.print(None, "Verified", &name);
+            Ok(())
+        }
+        None => bail!(
+            "\"{}\" is not a known good dump. It's either a bad dump or a different region or \
+             revision of the game.",
+            game.display()
+        ),
+    }
+}
+
+/// Adds the selected features to the Rom Hack. Multiple features may also be
+/// selected at once by separating them with commas. The features that aren't
+/// selected stay in the config, so they can become options of Riivolution
+/// patches.
+fn select_features(config: &mut Config, names: &[String]) -> Result<(), Error> {
+    for name in names.iter().flat_map(|n| n.split(',')).map(str::trim) {
+        if name.is_empty() {
+            continue;
+        }
+        let feature = config
+            .features
+            .remove(name)
+            .ok_or_else(|| format_err!("The feature \"{}\" doesn't exist", name))?;
+        config
+            .src
+            .defines
+            .push(format!("FEATURE_{}", name.to_uppercase().replace('-', "_")));
+        config.src.defines.extend(feature.defines);
+        config.src.feature_patches.extend(feature.patch);
+        config.files.extend(feature.files);
+    }
+    Ok(())
+}
+
+/// Overrides the config with the settings of the region. Unless the region
+/// specifies its own output, the region's name is appended to the output's
+/// file name, so the regions don't overwrite each other. `REGION_<NAME>` is
+/// defined for the conditional directives of the patch files.
+fn select_region(config: &mut Config, name: &str) {
+    let region = mem::replace(&mut config.regions, Default::default())
+        .remove(name)
+        .unwrap_or_default();
+
+    config.src.iso = region.iso;
+    if region.game_id.is_some() {
+        config.src.game_id = region.game_id;
+    }
+    config.src.symbols.extend(region.symbols);
+    if region.port_map.is_some() {
+        config.src.port_map = region.port_map;
+    }
+    config.src.addresses.extend(region.addresses);
+    config.src.defines.extend(region.defines);
+    config
+        .src
+        .defines
+        .push(format!("REGION_{}", name.to_uppercase().replace('-', "_")));
+    config.files.extend(region.files);
+
+    config.build.iso = match region.output {
+        Some(output) => output,
+        None => suffixed_output(&config.build.iso, name),
+    };
+}
+
+/// Overrides the config with the settings of the disc, like a region. The
+/// discs share everything else, so `DISC_<NAME>` is defined for the patch
+/// files to tell them apart.
+fn select_disc(config: &mut Config, name: &str) {
+    let disc = mem::replace(&mut config.discs, Default::default())
+        .remove(name)
+        .unwrap_or_default();
+
+    config.src.iso = disc.iso;
+    config.src.symbols.extend(disc.symbols);
+    config.src.addresses.extend(disc.addresses);
+    config.src.defines.extend(disc.defines);
+    config
+        .src
+        .defines
+        .push(format!("DISC_{}", name.to_uppercase().replace('-', "_")));
+    config.files.extend(disc.files);
+
+    config.build.iso = match disc.output {
+        Some(output) => output,
+        None => suffixed_output(&config.build.iso, name),
+    };
+}
+
+/// Appends the name to the output's file name, like `game_pal.iso`.
+fn suffixed_output(output: &Path, name: &str) -> PathBuf {
+    let mut file_name = output.file_stem().unwrap_or_default().to_os_string();
+    file_name.push("_");
+    file_name.push(name);
+    if let Some(extension) = output.extension() {
+        file_name.push(".");
+        file_name.push(extension);
     }
+    output.with_file_name(file_name)
 }
 
 pub fn apply_patch<P: KeyValPrint>(
@@ -136,6 +661,16 @@ pub fn open_config_from_patch<R: Read + Seek>(
     Ok((zip, buffer, config))
 }
 
+/// All the files of the patch get the same timestamp, so building the same
+/// Rom Hack twice results in the same patch file.
+fn file_options() -> FileOptions {
+    let mut timestamp = time::empty_tm();
+    // 1980-01-01 is the earliest date zip files can store
+    timestamp.tm_year = 80;
+    timestamp.tm_mday = 1;
+    FileOptions::default().last_modified_time(timestamp)
+}
+
 fn build_patch<P: KeyValPrint>(
     printer: &P,
     compiled_library: Vec<u8>,
@@ -148,109 +683,499 @@ fn build_patch<P: KeyValPrint>(
         File::create(&config.build.iso).context("Couldn't create the patch file")?,
     ));
 
+    // Every file is stored under a new name, which its path in the patch index
+    // is changed to
+    let mut entries = Vec::new();
+
     printer.print(None, "Storing", "replacement files");
+    store_patch_files(&mut entries, &mut config.files, "replace", "dat", "file")?;
 
-    let mut new_map = HashMap::new();
-    for (index, (iso_path, actual_path)) in config.files.iter().enumerate() {
-        let zip_path = format!("replace{}.dat", index);
-        new_map.insert(iso_path.clone(), PathBuf::from(&zip_path));
-        zip.start_file(zip_path, FileOptions::default())
-            .context("Failed creating a new patch file entry")?;
+    if !config.textures.is_empty() {
+        printer.print(None, "Storing", "textures");
+        store_patch_files(
+            &mut entries,
+            &mut config.textures,
+            "texture",
+            "png",
+            "image",
+        )?;
+    }
 
-        zip.write_all(&fs::read(actual_path).with_context(|_| {
-            format!(
-                "Couldn't read the file \"{}\" to store it in the patch.",
-                actual_path.display()
-            )
-        })?).context("Failed storing a file in the patch")?;
+    if !config.fonts.is_empty() {
+        printer.print(None, "Storing", "glyphs");
+        let glyphs = config
+            .fonts
+            .values_mut()
+            .flat_map(|glyphs| glyphs.values_mut());
+        for (index, image_path) in glyphs.enumerate() {
+            store_patch_file(
+                &mut entries,
+                image_path,
+                format!("glyph{}.png", index),
+                "image",
+            )?;
+        }
     }
-    config.files = new_map;
 
-    printer.print(None, "Storing", "libraries");
+    if !config.videos.is_empty() {
+        printer.print(None, "Storing", "videos");
+        store_patch_files(&mut entries, &mut config.videos, "video", "dat", "video")?;
+    }
 
-    zip.start_file("libcompiled.a", FileOptions::default())
-        .context("Failed creating a new patch file entry for the compiled library")?;
-    zip.write_all(&compiled_library)
-        .context("Failed storing the compiled library in the patch")?;
+    if !config.sounds.is_empty() {
+        printer.print(None, "Storing", "sounds");
+        store_patch_files(&mut entries, &mut config.sounds, "sound", "wav", "sound")?;
+    }
 
-    for (index, lib_path) in config.link.libs.iter().flat_map(|x| x).enumerate() {
-        let zip_path = format!("lib{}.a", index);
-        zip.start_file(zip_path, FileOptions::default())
-            .context("Failed creating a new patch file entry")?;
+    printer.print(None, "Storing", "libraries");
 
+    entries.push(("libcompiled.a".to_owned(), compiled_library));
+    for (index, lib_path) in config.link.libs.iter().flat_map(|x| x).enumerate() {
         let file_buf = fs::read(lib_path).with_context(|_| {
             format!(
                 "Couldn't load \"{}\". Did you build the project correctly?",
                 lib_path.display()
             )
         })?;
-        zip.write_all(&file_buf)
-            .context("Failed storing a library in the patch")?;
+        entries.push((format!("lib{}.a", index), file_buf));
+    }
+
+    for (index, path) in config.rels.values_mut().enumerate() {
+        store_patch_assembly(
+            &mut entries,
+            path,
+            format!("rel{}.asm", index),
+            "REL patch file",
+        )?;
+    }
+
+    if let Some(path) = &mut config.src.gecko {
+        printer.print(None, "Storing", "Gecko codes");
+        let zip_path = if path.extension() == Some("gct".as_ref()) {
+            "codes.gct"
+        } else {
+            "codes.txt"
+        };
+        store_patch_file(&mut entries, path, zip_path.to_owned(), "Gecko codes")?;
+    }
+
+    if let Some(path) = &mut config.src.code_handler {
+        printer.print(None, "Storing", "code handler");
+        store_patch_file(
+            &mut entries,
+            path,
+            "codehandler.bin".to_owned(),
+            "code handler",
+        )?;
+    }
+
+    if let Some(path) = &mut config.src.handler_codes {
+        printer.print(None, "Storing", "codes of the code handler");
+        let zip_path = if path.extension() == Some("gct".as_ref()) {
+            "handler_codes.gct"
+        } else {
+            "handler_codes.txt"
+        };
+        store_patch_file(
+            &mut entries,
+            path,
+            zip_path.to_owned(),
+            "code handler's codes",
+        )?;
+    }
+
+    if let Some(path) = &mut config.src.action_replay {
+        printer.print(None, "Storing", "Action Replay codes");
+        store_patch_file(
+            &mut entries,
+            path,
+            "action_replay.txt".to_owned(),
+            "Action Replay codes",
+        )?;
+    }
+
+    for (index, (name, entry)) in config.inject_bin.iter_mut().enumerate() {
+        printer.print(None, "Storing", &format!("binary \"{}\"", name));
+
+        let zip_path = format!("binary{}.bin", index);
+        let (path, address) = {
+            let (path, address) = config::split_inject_bin(entry);
+            (path.to_owned(), address.to_string())
+        };
+        let file_buf = fs::read(&path)
+            .with_context(|_| format!("Couldn't read the binary \"{}\"", path.display()))?;
+        *entry = format!("{} @ {}", zip_path, address);
+        entries.push((zip_path, file_buf));
+    }
+
+    for (index, source) in config.sources.iter_mut().enumerate() {
+        printer.print(None, "Storing", &format!("{} source", source.kind));
+
+        let zip_path = match source.path.extension().and_then(|e| e.to_str()) {
+            Some(extension) => format!("source{}.{}", index, extension),
+            None => format!("source{}", index),
+        };
+        store_patch_file(&mut entries, &mut source.path, zip_path, "patch source")?;
+    }
+
+    for (index, path) in config.src.symbols.iter_mut().enumerate() {
+        store_patch_file(
+            &mut entries,
+            path,
+            format!("symbols{}.map", index),
+            "symbol map",
+        )?;
+    }
+
+    for (index, path) in config.patches.iter_mut().enumerate() {
+        store_patch_file(
+            &mut entries,
+            path,
+            format!("base{}.patch", index),
+            "base patch",
+        )?;
     }
 
     if let Some(path) = &mut config.src.patch {
         printer.print(None, "Storing", "patch.asm");
+        store_patch_assembly(&mut entries, path, "patch.asm".to_owned(), "patch.asm file")?;
+    }
 
-        zip.start_file("patch.asm", FileOptions::default())
-            .context("Failed to create the patch.asm file in the patch")?;
-        let file_buf = fs::read(&*path).context("Couldn't read the patch.asm file")?;
-        zip.write_all(&file_buf)
-            .context("Failed storing the patch.asm file in the patch")?;
-        *path = PathBuf::from("patch.asm");
+    for (index, path) in config.src.feature_patches.iter_mut().enumerate() {
+        store_patch_assembly(
+            &mut entries,
+            path,
+            format!("feature{}.asm", index),
+            "feature's patch file",
+        )?;
     }
 
     if let Some(path) = &mut config.info.image {
         printer.print(None, "Storing", "banner");
-
-        zip.start_file("banner.dat", FileOptions::default())
-            .context("Failed to create the banner file in the patch")?;
-        let file_buf = fs::read(&*path).context("Couldn't read the banner file")?;
-        zip.write_all(&file_buf)
-            .context("Failed storing the banner file in the patch")?;
-        *path = PathBuf::from("banner.dat");
+        store_patch_file(&mut entries, path, "banner.dat".to_owned(), "banner file")?;
     }
 
     printer.print(None, "Storing", "patch index");
 
     config.src.iso = PathBuf::new();
     config.build = Default::default();
-    zip.start_file("RomHack.toml", FileOptions::default())
-        .context("Failed to create the patch index")?;
+    // Only the selected features are part of the patch
+    config.features.clear();
     let config = toml::to_vec(&config).context("Couldn't encode the patch index")?;
-    zip.write_all(&config)
-        .context("Failed storing the patch index")?;
+    entries.push(("RomHack.toml".to_owned(), config));
+
+    for (zip_path, data) in entries {
+        zip.start_file(&*zip_path, file_options())
+            .context("Failed creating a new patch file entry")?;
+        zip.write_all(&data)
+            .with_context(|_| format!("Failed storing \"{}\" in the patch", zip_path))?;
+    }
+
+    Ok(())
+}
+
+/// Reads a file to store it in the patch under the name and points its path
+/// to that name.
+fn store_patch_file(
+    entries: &mut Vec<(String, Vec<u8>)>,
+    path: &mut PathBuf,
+    zip_path: String,
+    kind: &str,
+) -> Result<(), Error> {
+    let file_buf = fs::read(&*path).with_context(|_| {
+        format!(
+            "Couldn't read the {} \"{}\" to store it in the patch",
+            kind,
+            path.display()
+        )
+    })?;
+    entries.push((zip_path.clone(), file_buf));
+    *path = PathBuf::from(zip_path);
+    Ok(())
+}
+
+/// Stores the files that replace the ones on the disc, numbered by their
+/// order and named after the prefix.
+fn store_patch_files(
+    entries: &mut Vec<(String, Vec<u8>)>,
+    files: &mut BTreeMap<String, PathBuf>,
+    prefix: &str,
+    extension: &str,
+    kind: &str,
+) -> Result<(), Error> {
+    for (index, path) in files.values_mut().enumerate() {
+        let zip_path = format!("{}{}.{}", prefix, index, extension);
+        store_patch_file(entries, path, zip_path, kind)?;
+    }
+    Ok(())
+}
 
+/// Stores an assembly file with its included files inlined, as their paths
+/// are only valid in the project's directory.
+fn store_patch_assembly(
+    entries: &mut Vec<(String, Vec<u8>)>,
+    path: &mut PathBuf,
+    zip_path: String,
+    kind: &str,
+) -> Result<(), Error> {
+    let file_buf = assembler::read_with_includes(&mut FileSystem, path)
+        .with_context(|_| format!("Couldn't read the {} \"{}\"", kind, path.display()))?;
+    entries.push((zip_path.clone(), file_buf.into_bytes()));
+    *path = PathBuf::from(zip_path);
     Ok(())
 }
 
-pub fn build_iso<'a, P: KeyValPrint, F: FileSource>(
+pub fn build_iso<'a, P: KeyValPrint, F: FileSource, R: Read + Seek>(
+    printer: &P,
+    files: F,
+    original_iso: &mut R,
+    system_data: &'a SystemData,
+    compiled_library: Vec<u8>,
+    config: &'a mut Config,
+) -> Result<Directory<'a>, Error> {
+    build_iso_with_sources(
+        printer,
+        files,
+        original_iso,
+        system_data,
+        compiled_library,
+        config,
+        &patch_source::BUILT_IN,
+    )
+}
+
+/// Builds the ISO like `build_iso`, with the patch sources of the config
+/// being read by the given kinds of sources.
+pub fn build_iso_with_sources<'a, P: KeyValPrint, F: FileSource, R: Read + Seek>(
     printer: &P,
     mut files: F,
-    original_iso: &'a [u8],
+    original_iso: &mut R,
+    system_data: &'a SystemData,
     compiled_library: Vec<u8>,
     config: &'a mut Config,
+    kinds: &[&dyn PatchSource],
 ) -> Result<Directory<'a>, Error> {
-    let mut iso = iso::reader::load_iso(original_iso).context("Couldn't parse the ISO")?;
+    if let Some(ref game_id) = config.src.game_id {
+        let actual_id = String::from_utf8_lossy(&system_data.header[..6]);
+        ensure!(
+            actual_id.starts_with(&game_id[..]),
+            "The Rom Hack is meant for the game \"{}\", but the original game is \"{}\"",
+            game_id,
+            actual_id
+        );
+    }
+
+    let mut iso = iso::reader::load_iso(system_data).context("Couldn't parse the ISO")?;
+
+    let platform = detect_platform(&iso, original_iso, system_data)?;
+    if let Some(expected) = config.src.platform {
+        ensure!(
+            platform == expected,
+            "The Rom Hack is meant for a {} game, but the original game is a {} game",
+            expected.name(),
+            platform.name()
+        );
+    }
+    if platform == Platform::Triforce {
+        check_triforce_config(config)?;
+    }
+
+    let mut header = system_data.header.clone();
+    if let Some(ref game_id) = config.build.game_id {
+        // A different game ID gives the Rom Hack its own saves and settings
+        let game_id = parse_game_id(game_id, &system_data.header)?;
+        printer.print(
+            None,
+            "Changing",
+            &format!("game ID to {}", String::from_utf8_lossy(&game_id)),
+        );
+        header[..game_id.len()].copy_from_slice(&game_id);
+    }
+    edit_header(&mut header, &config.header).context("Couldn't edit the disc header")?;
+    iso.resolve_path_mut("&&systemdata/iso.hdr")
+        .ok_or_else(|| err_msg("The disc header wasn't found"))?
+        .data = header.into();
+
+    if let Some(ref path) = config.src.apploader {
+        printer.print(None, "Replacing", "apploader");
+        let apploader = files
+            .read_to_vec(path)
+            .with_context(|_| format!("Couldn't read the apploader \"{}\".", path.display()))?;
+        let apploader = Apploader::parse(&apploader)
+            .with_context(|_| format!("Couldn't parse the apploader \"{}\".", path.display()))?
+            .to_bytes();
+        iso.resolve_path_mut("&&systemdata/AppLoader.ldr")
+            .ok_or_else(|| err_msg("The apploader wasn't found"))?
+            .data = apploader.into();
+    }
+
+    if !config.patches.is_empty() {
+        printer.print(None, "Applying", "base patches");
+
+        for path in &config.patches {
+            let patch = files.read_to_vec(path).with_context(|_| {
+                format!("Couldn't read the base patch \"{}\".", path.display())
+            })?;
+            let main_dol = iso
+                .main_dol_mut()
+                .ok_or_else(|| err_msg("Dol file not found"))?;
+            let patched = {
+                let dol = main_dol
+                    .read(original_iso)
+                    .context("Couldn't read the DOL")?;
+                patchfile::apply_to_vec(&patch, &dol).with_context(|_| {
+                    format!("Couldn't apply the base patch \"{}\"", path.display())
+                })?
+            };
+            main_dol.data = patched.into();
+        }
+    }
 
     printer.print(None, "Replacing", "files");
 
+    let mut manifest = Manifest::default();
+
+    let mut replacements = Vec::new();
     for (iso_path, actual_path) in &config.files {
-        iso.resolve_and_create_path(iso_path).data = files
-            .read_to_vec(actual_path)
-            .with_context(|_| {
-                format!(
-                    "Couldn't read the file \"{}\" to store it in the ISO.",
-                    actual_path.display()
-                )
-            })?.into();
+        let data = files.read_to_vec(actual_path).with_context(|_| {
+            format!(
+                "Couldn't read the file \"{}\" to store it in the ISO.",
+                actual_path.display()
+            )
+        })?;
+        replacements.push((iso_path.as_str(), data));
+    }
+
+    let text_table = read_text_table(config, &mut files)?;
+    let compression = config.build.compression;
+
+    if !config.messages.is_empty() {
+        printer.print(None, "Editing", "messages");
+        edit_messages(
+            &mut replacements,
+            &iso,
+            original_iso,
+            &config.messages,
+            text_table.as_ref(),
+            compression,
+        )?;
+    }
+
+    if !config.textures.is_empty() {
+        printer.print(None, "Converting", "textures");
+        convert_textures(
+            &mut replacements,
+            &iso,
+            original_iso,
+            &mut files,
+            &config.textures,
+            compression,
+        )?;
+    }
+
+    if !config.fonts.is_empty() {
+        printer.print(None, "Editing", "fonts");
+        edit_fonts(
+            &mut replacements,
+            &iso,
+            original_iso,
+            &mut files,
+            &config.fonts,
+            text_table.as_ref(),
+            compression,
+        )?;
+    }
+
+    if !config.videos.is_empty() {
+        printer.print(None, "Converting", "videos");
+        convert_videos(
+            &mut replacements,
+            &iso,
+            original_iso,
+            &mut files,
+            &config.videos,
+        )?;
+    }
+
+    if !config.sounds.is_empty() {
+        printer.print(None, "Encoding", "sounds");
+        encode_sounds(
+            &mut replacements,
+            &iso,
+            original_iso,
+            &mut files,
+            &config.sounds,
+        )?;
+    }
+
+    // Paths like `files/Stage/stage.arc:model.brres` point into U8 or RARC archives
+    let mut archive_files = BTreeMap::new();
+    for (iso_path, data) in replacements {
+        if let Some(index) = iso_path.find(':') {
+            manifest.add_file(iso_path, Change::Replaced);
+            archive_files
+                .entry(&iso_path[..index])
+                .or_insert_with(Vec::new)
+                .push((&iso_path[index + 1..], data));
+        } else if iso.resolve_path(iso_path).is_some() {
+            manifest.add_file(iso_path, Change::Replaced);
+            iso.replace_file(iso_path, data)?;
+        } else {
+            manifest.add_file(iso_path, Change::Added);
+            iso.add_file(iso_path, data)?;
+        }
+    }
+
+    for (archive_path, replacements) in archive_files {
+        let (codec, archive) = read_archive(&iso, original_iso, archive_path)?;
+        let compression = config.build.compression;
+        let repacked = {
+            let mut parsed = Archive::parse(&archive)
+                .with_context(|_| format!("Couldn't parse the archive \"{}\"", archive_path))?;
+            replace_files_in_archive(parsed.root_mut(), replacements, compression)?;
+            parsed
+                .write()
+                .with_context(|_| format!("Couldn't repack the archive \"{}\"", archive_path))?
+        };
+        // Compressed archives are compressed again after repacking them
+        let repacked = match codec {
+            Some(codec) => {
+                printer.print(
+                    None,
+                    "Compressing",
+                    &format!("{} as {}", archive_path, codec.name()),
+                );
+                codec.compress(&repacked, compression)
+            }
+            None => repacked,
+        };
+        iso.replace_file(archive_path, repacked)?;
     }
 
+    if !config.remove_files.is_empty() {
+        printer.print(None, "Removing", "files");
+
+        for iso_path in &config.remove_files {
+            manifest.add_file(iso_path, Change::Removed);
+            iso.remove_file(iso_path)?;
+        }
+    }
+
+    let framework_map = match config.src.map.as_ref().and_then(|m| iso.resolve_path(m)) {
+        Some(file) => Some(
+            file.read(original_iso)
+                .context("Couldn't read the game's symbol map")?
+                .into_owned(),
+        ),
+        None => None,
+    };
+
     let mut original_symbols = HashMap::new();
-    if let Some(framework_map) = config.src.map.as_ref().and_then(|m| iso.resolve_path(m)) {
+    if let Some(framework_map) = &framework_map {
         printer.print(None, "Parsing", "symbol map");
-        original_symbols = framework_map::parse(&framework_map.data)
-            .context("Couldn't parse the game's symbol map")?;
-    } else {
+        original_symbols =
+            symbols::parse(framework_map).context("Couldn't parse the game's symbol map")?;
+    } else if config.src.symbols.is_empty() {
         printer.print(
             Some(MessageKind::Warning),
             "Warning",
@@ -258,6 +1183,38 @@ pub fn build_iso<'a, P: KeyValPrint, F: FileSource>(
         );
     }
 
+    for path in &config.src.symbols {
+        printer.print(None, "Parsing", "symbol map");
+        let buf = files
+            .read_to_vec(path)
+            .with_context(|_| format!("Couldn't read the symbol map \"{}\".", path.display()))?;
+        let map_symbols = symbols::parse(&buf).with_context(|_| {
+            format!("Couldn't parse the symbol map \"{}\".", path.display())
+        })?;
+        original_symbols.extend(map_symbols);
+    }
+
+    for (name, address) in &config.src.addresses {
+        let address = parse_address(address)
+            .with_context(|_| format!("Invalid address \"{}\" for \"{}\"", address, name))?;
+        original_symbols.insert(name.clone(), address);
+    }
+
+    if !config.src.signatures.is_empty() {
+        printer.print(None, "Scanning", "signatures");
+        let dol = DolFile::parse(
+            &iso.main_dol()
+                .ok_or_else(|| err_msg("Dol file not found"))?
+                .read(original_iso)
+                .context("Couldn't read the DOL")?,
+        ).context("Couldn't parse the DOL")?;
+        for (name, signature) in &config.src.signatures {
+            let address = signature::find(&dol, signature)
+                .with_context(|_| format!("Couldn't find \"{}\" by its signature", name))?;
+            original_symbols.insert(name.clone(), address);
+        }
+    }
+
     printer.print(None, "Linking", "");
 
     let mut libs_to_link = Vec::with_capacity(config.link.libs.as_ref().map_or(0, |x| x.len()) + 2);
@@ -271,6 +1228,15 @@ pub fn build_iso<'a, P: KeyValPrint, F: FileSource>(
                 lib_path.display()
             )
         })?;
+        if linker::is_object(&file_buf) {
+            let name = lib_path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            file_buf = linker::archive_from_object(&name, &file_buf).with_context(|_| {
+                format!("Couldn't load the object file \"{}\".", lib_path.display())
+            })?;
+        }
         libs_to_link.push(file_buf);
     }
 
@@ -278,89 +1244,579 @@ pub fn build_iso<'a, P: KeyValPrint, F: FileSource>(
 
     let base_address: syn::LitInt =
         syn::parse_str(&config.link.base).context("Invalid Base Address")?;
+    let base_address = base_address.value() as u32;
+
+    let mut free_regions = Vec::with_capacity(config.link.free.len());
+    for region in &config.link.free {
+        free_regions.push(
+            parse_region(region)
+                .with_context(|_| format!("Invalid free region \"{}\"", region))?,
+        );
+    }
 
-    let linked = linker::link(
+    let mut linked = linker::link(
         printer,
         &libs_to_link,
-        base_address.value() as u32,
-        config.link.entries.clone(),
+        base_address,
+        config
+            .link
+            .entries
+            .iter()
+            .chain(&config.link.init)
+            .cloned()
+            .collect(),
         &original_symbols,
+        config.build.elf.is_some(),
+        config.build.trim_sections,
     ).context("Couldn't link the Rom Hack")?;
 
-    printer.print(None, "Creating", "symbol map");
+    let (binaries, binary_symbols) = inject_binaries(
+        printer,
+        config,
+        &mut files,
+        &mut linked.dol,
+        &mut original_symbols,
+    )?;
 
-    // TODO NLL bind framework_map to local variable
-    framework_map::create(
-        &config,
-        config
-            .src
-            .map
-            .as_ref()
-            .and_then(|m| iso.resolve_path(m))
-            .map(|f| &*f.data),
-        &linked.sections,
-    ).context("Couldn't create the new symbol map")?;
+    let port_map = match config.src.port_map {
+        Some(ref path) => {
+            let text = files.read_to_string(path).with_context(|_| {
+                format!("Couldn't read the port map \"{}\".", path.display())
+            })?;
+            let port_map = PortMap::parse(&text).with_context(|_| {
+                format!("Couldn't parse the port map \"{}\".", path.display())
+            })?;
+            Some(port_map)
+        }
+        None => None,
+    };
+
+    let mut assembler = Assembler::new(linked.symbol_table, &original_symbols);
+    if let Some(ref port_map) = port_map {
+        assembler.set_port_map(port_map);
+    }
+    for definition in &config.src.defines {
+        assembler.define(definition)?;
+    }
+    if let Some(encoding) = text_encoding(config, text_table)? {
+        assembler.set_text_encoding(encoding);
+    }
 
     let mut instructions = Vec::new();
-    if let Some(patch) = config.src.patch.take() {
+    // The patch files of the features are assembled along with the main patch
+    // file, so they can refer to each other's labels
+    let patches = config
+        .src
+        .patch
+        .take()
+        .into_iter()
+        .chain(mem::replace(&mut config.src.feature_patches, Vec::new()))
+        .collect::<Vec<_>>();
+    if !patches.is_empty() {
         printer.print(None, "Parsing", "patch");
 
-        let asm = files
-            .read_to_string(&patch)
-            .with_context(|_| format!("Couldn't read the patch file \"{}\".", patch.display()))?;
-
+        let mut asm = String::new();
+        let mut locations = Vec::new();
+        for patch in &patches {
+            let (source, source_locations) = assembler::read_with_locations(&mut files, patch)
+                .with_context(|_| {
+                    format!("Couldn't read the patch file \"{}\".", patch.display())
+                })?;
+            // Every line ends with a line break, so the files can simply be
+            // joined and the lines still match their locations
+            asm += &source;
+            locations.extend(source_locations);
+        }
         let lines = &asm.lines().collect::<Vec<_>>();
 
-        let mut assembler = Assembler::new(linked.symbol_table, &original_symbols);
         instructions = assembler
-            .assemble_all_lines(lines)
+            .assemble_lines_from(lines, locations)
             .context("Couldn't assemble the patch file lines")?;
     }
+    let mut injected_symbols = assembler
+        .labels()
+        .iter()
+        .map(|(label, &address)| InjectedSymbol {
+            address,
+            len: 4,
+            name: label.trim_left_matches('.').to_string(),
+        })
+        .collect::<Vec<_>>();
+    let compressed_binaries = if config.build.compress_binaries {
+        binary_symbols.iter().map(|s| s.address).collect()
+    } else {
+        Vec::new()
+    };
+    injected_symbols.extend(binary_symbols);
+    // The labels are stored unordered, but the outputs list them in a fixed order
+    injected_symbols.sort_by(|a, b| (a.address, &a.name).cmp(&(b.address, &b.name)));
 
-    {
+    let mut hooks = Vec::with_capacity(config.hooks.len());
+    for (address, hook) in &config.hooks {
+        let function = hook.function();
+        hooks.push(Hook {
+            address: assembler
+                .resolve_ported_address(address)
+                .with_context(|_| format!("Couldn't resolve the hook address \"{}\"", address))?,
+            function: assembler
+                .resolve_symbol(function)
+                .with_context(|_| format!("Couldn't resolve the hook function \"{}\"", function))?,
+            original: match hook.original() {
+                Some(original) => Some(assembler.resolve_address(original).with_context(|_| {
+                    format!("Couldn't parse the original instruction \"{}\"", original)
+                })?),
+                None => None,
+            },
+        });
+    }
+
+    let mut code_handler = None;
+    if let Some(ref path) = config.src.code_handler {
+        printer.print(None, "Embedding", "code handler");
+
+        let handler = files
+            .read_to_vec(path)
+            .with_context(|_| format!("Couldn't read the code handler \"{}\".", path.display()))?;
+        let gct = match config.src.handler_codes {
+            Some(ref path) => {
+                let buf = files
+                    .read_to_vec(path)
+                    .with_context(|_| format!("Couldn't read the codes \"{}\".", path.display()))?;
+                Some(if path.extension() == Some("gct".as_ref()) {
+                    buf
+                } else {
+                    let text = str::from_utf8(&buf).context("The codes are not valid UTF-8")?;
+                    gecko::text_to_gct(text).context("Couldn't parse the codes")?
+                })
+            }
+            None => None,
+        };
+        code_handler = Some(
+            gecko::embed_handler(&handler, gct.as_ref().map(|g| &g[..]))
+                .context("Couldn't embed the code handler")?,
+        );
+        // The generated code would otherwise be placed on top of the handler
+        // or its codes
+        let (handler_start, handler_end) = (gecko::HANDLER_ADDRESS, gecko::HANDLER_END);
+        if let Some(&(start, end)) = free_regions
+            .iter()
+            .find(|&&(start, end)| start < handler_end && handler_start < end)
+        {
+            bail!(
+                "The free region {:08X}..{:08X} overlaps the code handler at {:08X}..{:08X}",
+                start,
+                end,
+                handler_start,
+                handler_end
+            );
+        }
+
+        let hook = config
+            .src
+            .handler_hook
+            .as_ref()
+            .ok_or_else(|| err_msg("The code handler needs a hook to be called from"))?;
+        hooks.push(Hook {
+            address: assembler.resolve_address(hook).with_context(|_| {
+                format!("Couldn't resolve the code handler's hook \"{}\"", hook)
+            })?,
+            function: gecko::HANDLER_ENTRY,
+            original: None,
+        });
+    }
+    hooks.sort_by_key(|h| h.address);
+
+    let mut arena_clamps = Vec::new();
+    for &(bound, value) in &[
+        (arena::Bound::Lo, &config.link.arena_lo),
+        (arena::Bound::Hi, &config.link.arena_hi),
+        (arena::Bound::Mem2Hi, &config.link.mem2_arena_hi),
+    ] {
+        if let Some(ref value) = *value {
+            let function = bound.function();
+            arena_clamps.push(arena::Clamp {
+                bound,
+                function: assembler.resolve_symbol(function).with_context(|_| {
+                    format!("Couldn't resolve {} for shrinking the arena", function)
+                })?,
+                value: assembler
+                    .resolve_address(value)
+                    .with_context(|_| format!("Invalid arena bound \"{}\"", value))?,
+            });
+        }
+    }
+
+    let mut init_functions = Vec::with_capacity(config.link.init.len());
+    for function in &config.link.init {
+        init_functions.push(
+            assembler
+                .resolve_symbol(function)
+                .with_context(|_| format!("Couldn't resolve the init function \"{}\"", function))?,
+        );
+    }
+
+    // The Gecko codes and the Action Replay codes are sources like any other
+    let mut sources = mem::replace(&mut config.sources, Vec::new());
+    if let Some(path) = config.src.action_replay.take() {
+        sources.insert(0, Source::new("action-replay", path));
+    }
+    if let Some(path) = config.src.gecko.take() {
+        sources.insert(0, Source::new("gecko", path));
+    }
+    let mut gecko_codes = Vec::new();
+    for source in &sources {
+        let kind = patch_source::find(kinds, &source.kind)
+            .ok_or_else(|| format_err!("Unknown kind of patch source \"{}\"", source.kind))?;
+        let (name, path) = (kind.name(), source.path.display());
+        printer.print(None, "Parsing", name);
+
+        let buf = files
+            .read_to_vec(&source.path)
+            .with_context(|_| format!("Couldn't read the {} \"{}\".", name, path))?;
+        let patches = kind
+            .parse(&buf, source, &original_symbols)
+            .with_context(|_| format!("Couldn't parse the {} \"{}\".", name, path))?;
+        instructions.extend(patches.instructions);
+        gecko_codes.extend(patches.gecko_codes);
+    }
+
+    protect_saves(
+        printer,
+        config,
+        platform,
+        &instructions,
+        &hooks,
+        &linked.sections,
+        &mut assembler,
+        &mut iso,
+        original_iso,
+    )?;
+
+    if !config.rels.is_empty() {
+        printer.print(None, "Patching", "relocatable modules");
+    }
+
+    let mut rel_sources = Vec::with_capacity(config.rels.len());
+    for (iso_path, patch) in &config.rels {
+        let (asm, locations) = assembler::read_with_locations(&mut files, patch)
+            .with_context(|_| format!("Couldn't read the patch file \"{}\".", patch.display()))?;
+        rel_sources.push((iso_path, asm, locations));
+    }
+
+    // The patches of the RELs don't depend on each other, so they are
+    // assembled in parallel and applied in the order of the config afterwards
+    let defines = &config.src.defines;
+    let assemble_rel = |iso_path: &String, asm: &str, locations: &[Location]| -> Result<_, Error> {
+        let lines = &asm.lines().collect::<Vec<_>>();
+
+        let mut assembler = Assembler::new(Default::default(), &original_symbols);
+        for definition in defines {
+            assembler.define(definition)?;
+        }
+        let instructions = assembler
+            .assemble_lines_from(lines, locations.to_vec())
+            .with_context(|_| format!("Couldn't assemble the patch for \"{}\"", iso_path))?;
+        if let Some(replacement) = assembler.replacements().first() {
+            bail!(
+                "The function at {:08X} can't be replaced by the patch for \"{}\", only \
+                 the functions of the DOL can be replaced",
+                replacement.function,
+                iso_path
+            );
+        }
+        if let Some(string) = assembler.strings().first() {
+            bail!(
+                "The string at {:08X} can't be replaced by the patch for \"{}\", only \
+                 the strings of the DOL can be replaced",
+                string.address,
+                iso_path
+            );
+        }
+        if let Some(injection) = assembler.injections().first() {
+            bail!(
+                "The inject block at offset {:#x} of the patch for \"{}\" can't be \
+                 placed, only the DOL can be injected into",
+                injection.address,
+                iso_path
+            );
+        }
+        Ok((instructions, assembler))
+    };
+
+    // There are no threads to assemble them on in the browser
+    #[cfg(not(target_arch = "wasm32"))]
+    let sources = rel_sources.par_iter();
+    #[cfg(target_arch = "wasm32")]
+    let sources = rel_sources.iter();
+    let rel_patches = sources
+        .map(|&(iso_path, ref asm, ref locations)| {
+            assemble_rel(iso_path, asm, locations)
+                .map(|(instructions, assembler)| (iso_path, instructions, assembler))
+        }).collect::<Result<Vec<_>, _>>()?;
+
+    let mut rel_assemblers = Vec::with_capacity(rel_patches.len());
+    for (iso_path, mut instructions, assembler) in rel_patches {
+        // The module is loaded at an address that is only known once the game
+        // runs, so the branches out of it, like to the DOL, are left to the
+        // game to resolve through relocations
+        for branch in assembler.far_branches() {
+            instructions.push(Instruction {
+                address: branch.address,
+                data: build_branch_instruction(branch.address, branch.address, false, branch.link),
+                mask: !0,
+            });
+        }
+
+        let rel_file = iso
+            .resolve_path_mut(iso_path)
+            .ok_or_else(|| format_err!("The REL \"{}\" wasn't found", iso_path))?;
+        let mut rel = {
+            let original_rel = rel_file
+                .read(original_iso)
+                .with_context(|_| format!("Couldn't read the REL \"{}\"", iso_path))?;
+            check_expectations(
+                assembler.expectations().iter().filter(|e| e.file.is_none()),
+                |address| read_word(&original_rel, address),
+            ).with_context(|_| {
+                format!("The REL \"{}\" isn't the one its patch was written for", iso_path)
+            })?;
+            manifest.add_words(
+                iso_path,
+                "the patch file",
+                &instructions,
+                Some(assembler.sources()),
+                |address| read_word(&original_rel, address),
+            );
+            RelFile::parse(&original_rel)
+                .with_context(|_| format!("Couldn't parse the REL \"{}\"", iso_path))?
+        };
+        rel.patch(&instructions)
+            .with_context(|_| format!("Couldn't patch the REL \"{}\"", iso_path))?;
+        for branch in assembler.far_branches() {
+            rel.relocate_against_dol(branch.address, rel::R_PPC_REL24, branch.target)
+                .with_context(|_| format!("Couldn't relocate the REL \"{}\"", iso_path))?;
+        }
+        rel_file.data = rel.to_bytes().into();
+        rel_assemblers.push(assembler);
+    }
+
+    let (dol_lens, free_memory) = {
         printer.print(None, "Patching", "game");
 
         let main_dol = iso
             .main_dol_mut()
             .ok_or_else(|| err_msg("Dol file not found"))?;
 
-        let original = DolFile::parse(&main_dol.data);
-        main_dol.data = patch_instructions(original, linked.dol, &instructions)
-            .context("Couldn't patch the game")?
-            .into();
+        let (original_len, original) = {
+            let data = main_dol
+                .read(original_iso)
+                .context("Couldn't read the DOL")?;
+            let original = DolFile::parse(&data).context("Couldn't parse the DOL")?;
+            (data.len() as u32, original)
+        };
+        check_expectations(
+            assembler.expectations().iter().filter(|e| e.file.is_none()),
+            |address| original.read_u32(address),
+        ).context("The DOL isn't the one the patch file was written for")?;
+        let header =
+            Header::parse(&system_data.header).context("Couldn't parse the disc header")?;
+        let max_fst_size = header.max_fst_size.max(system_data.fst.len() as u32);
+        let inputs = PatchInputs {
+            is_wii: header.is_wii,
+            fst_address: arena::fst_address(max_fst_size),
+            binaries,
+            compressed_binaries: &compressed_binaries,
+            free_regions: &free_regions,
+            instructions: &instructions,
+            assembler: &assembler,
+            gecko_codes: &gecko_codes,
+            code_handler,
+            hooks: &hooks,
+            arena_clamps: &arena_clamps,
+            init_functions: &init_functions,
+            outputs: &config.build,
+            sources: assembler.sources(),
+        };
+        let (patched, free_memory) = patch_instructions(
+            printer,
+            original,
+            linked.dol,
+            inputs,
+            &mut injected_symbols,
+            &mut manifest,
+        ).context("Couldn't patch the game")?;
+
+        if let Some(path) = &config.build.elf {
+            printer.print(None, "Creating", "ELF");
+            let patched = DolFile::parse(&patched).context("Couldn't parse the patched DOL")?;
+            elf::create(
+                path,
+                &patched,
+                &original_symbols,
+                &linked.sections,
+                &linked.debug_sections,
+                &injected_symbols,
+            ).context("Couldn't create the ELF")?;
+        }
+
+        if let Some(path) = &config.build.linker_script {
+            printer.print(None, "Creating", "linker script");
+            // The free memory after the Rom Hack was added last to MEM1, so it
+            // stays last there
+            let rom_hack_end = free_memory
+                .iter()
+                .filter(|r| r.end <= MEM1_END)
+                .last()
+                .map_or(MEM1_END, |r| r.end);
+            let free_memory = free_memory
+                .iter()
+                .filter(|r| r.end <= base_address || r.start >= rom_hack_end)
+                .cloned()
+                .collect::<Vec<_>>();
+            linker_script::create(
+                path,
+                base_address,
+                rom_hack_end,
+                &free_memory,
+                &original_symbols,
+            ).context("Couldn't create the linker script")?;
+        }
+
+        let dol_lens = (original_len, patched.len() as u32);
+        main_dol.data = patched.into();
+        (dol_lens, free_memory)
+    };
+
+    // The files are patched last, so the patches apply on top of the patched
+    // DOL and RELs
+    for assembler in iter::once(&assembler).chain(&rel_assemblers) {
+        patch_files(&mut iso, original_iso, assembler, &mut manifest)?;
+    }
+
+    let stats = Stats::new(dol_lens.0, dol_lens.1, &manifest, &free_memory);
+    stats.print(printer);
+    if let Some(path) = &config.build.stats {
+        printer.print(None, "Creating", "statistics");
+        stats.create(path)?;
+    }
+
+    printer.print(None, "Creating", "symbol map");
+
+    injected_symbols.sort_by_key(|s| s.address);
+    framework_map::create(
+        &config,
+        framework_map.as_ref().map(|m| &m[..]),
+        &linked.sections,
+        &injected_symbols,
+    ).context("Couldn't create the new symbol map")?;
+
+    if let Some(path) = &config.build.symbol_export {
+        printer.print(None, "Exporting", "symbols");
+        symbol_export::create(path, &linked.sections, &injected_symbols)
+            .context("Couldn't export the symbols")?;
+    }
+
+    if let Some(path) = &config.build.c_symbols {
+        printer.print(None, "Exporting", "C symbols");
+        c_symbols::create(path, &injected_symbols).context("Couldn't export the C symbols")?;
+    }
+
+    if let Some(path) = &config.build.gdbinit {
+        printer.print(None, "Creating", "GDB script");
+        gdbinit::create(path, &libs_to_link, &linked.sections)
+            .context("Couldn't create the GDB script")?;
+    }
+
+    if let Some(path) = &config.build.manifest {
+        printer.print(None, "Creating", "manifest");
+        manifest.create(path)?;
     }
+
     {
         printer.print(None, "Patching", "banner");
 
+        let region = system_data.header[3];
+        let is_japanese = region == b'J';
+        let info = &mut config.info;
+        let has_banner_changes = info.game_name.is_some()
+            || info.developer_name.is_some()
+            || info.full_game_name.is_some()
+            || info.full_developer_name.is_some()
+            || info.description.is_some()
+            || info.image.is_some()
+            || !info.languages.is_empty();
+
+        if platform == Platform::Triforce {
+            ensure!(
+                !has_banner_changes,
+                "Triforce games don't have a banner to change"
+            );
+        } else if iso.banner_mut().is_none() && has_banner_changes {
+            // Multi-language PAL games use BNR2 banners
+            let language_count = if region == b'P' {
+                banner::LANGUAGES.len()
+            } else {
+                1
+            };
+            let banner = Banner::new(language_count).to_bytes(is_japanese)?;
+            iso.add_file("opening.bnr", banner)?;
+        }
+
         if let Some(banner_file) = iso.banner_mut() {
-            // TODO Not always true
-            let is_japanese = true;
-            let mut banner = Banner::parse(is_japanese, &banner_file.data)
-                .context("Couldn't parse the banner")?;
+            let mut banner = Banner::parse(
+                is_japanese,
+                &banner_file
+                    .read(original_iso)
+                    .context("Couldn't read the banner")?,
+            ).context("Couldn't parse the banner")?;
 
-            if let Some(game_name) = config.info.game_name.take() {
-                banner.game_name = game_name;
-            }
-            if let Some(developer_name) = config.info.developer_name.take() {
-                banner.developer_name = developer_name;
+            // The general texts apply to all the languages
+            for texts in &mut banner.texts {
+                override_banner_texts(
+                    texts,
+                    &config::BannerTexts {
+                        game_name: info.game_name.clone(),
+                        developer_name: info.developer_name.clone(),
+                        full_game_name: info.full_game_name.clone(),
+                        full_developer_name: info.full_developer_name.clone(),
+                        description: info.description.clone(),
+                    },
+                );
             }
-            if let Some(full_game_name) = config.info.full_game_name.take() {
-                banner.full_game_name = full_game_name;
+            for (language, overrides) in &info.languages {
+                let index = banner::LANGUAGES
+                    .iter()
+                    .position(|&l| l == language.to_lowercase())
+                    .ok_or_else(|| format_err!("Unknown banner language \"{}\"", language))?;
+                let texts = banner.texts.get_mut(index).ok_or_else(|| {
+                    format_err!(
+                        "The banner doesn't contain texts for the language \"{}\"",
+                        language
+                    )
+                })?;
+                override_banner_texts(texts, overrides);
             }
-            if let Some(full_developer_name) = config.info.full_developer_name.take() {
-                banner.full_developer_name = full_developer_name;
-            }
-            if let Some(game_description) = config.info.description.take() {
-                banner.game_description = game_description;
-            }
-            if let Some(image_path) = config.info.image.take() {
-                let image = files
+
+            if let Some(image_path) = info.image.take() {
+                let mut image = files
                     .open_image(image_path)
                     .context("Couldn't open the banner replacement image")?
                     .to_rgba();
+                let (width, height) = (banner::WIDTH as u32, banner::HEIGHT as u32);
+                if image.dimensions() != (width, height) {
+                    image = image::imageops::resize(
+                        &image,
+                        width,
+                        height,
+                        image::FilterType::Lanczos3,
+                    );
+                }
                 banner.image.copy_from_slice(&image);
             }
-            banner_file.data = banner.to_bytes(is_japanese).to_vec().into();
+            banner_file.data = banner
+                .to_bytes(is_japanese)
+                .context("Couldn't encode the banner")?
+                .into();
         } else {
             printer.print(Some(MessageKind::Warning), "Warning", "No banner to patch");
         }
@@ -369,171 +1825,2600 @@ pub fn build_iso<'a, P: KeyValPrint, F: FileSource>(
     Ok(iso)
 }
 
+fn override_banner_texts(texts: &mut banner::BannerTexts, overrides: &config::BannerTexts) {
+    if let Some(ref game_name) = overrides.game_name {
+        texts.game_name = game_name.clone();
+    }
+    if let Some(ref developer_name) = overrides.developer_name {
+        texts.developer_name = developer_name.clone();
+    }
+    if let Some(ref full_game_name) = overrides.full_game_name {
+        texts.full_game_name = full_game_name.clone();
+    }
+    if let Some(ref full_developer_name) = overrides.full_developer_name {
+        texts.full_developer_name = full_developer_name.clone();
+    }
+    if let Some(ref description) = overrides.description {
+        texts.game_description = description.clone();
+    }
+}
+
 pub fn build_and_emit_iso<P: KeyValPrint, F: FileSource>(
     printer: &P,
-    files: F,
+    mut files: F,
     compiled_library: Vec<u8>,
     mut config: Config,
 ) -> Result<(), Error> {
     printer.print(None, "Loading", "original game");
 
-    let buf = iso::reader::load_iso_buf(&config.src.iso)
-        .with_context(|_| format!("Couldn't find \"{}\".", config.src.iso.display()))?;
+    let mut original = iso::disc::open(&config.src.iso)?;
 
     let out_path = mem::replace(&mut config.build.iso, Default::default());
+    let is_wbfs = out_path.extension().map_or(false, |ext| ext == "wbfs");
 
-    let iso = build_iso(printer, files, &buf, compiled_library, &mut config)?;
+    // Declared before the reader, so the reader is closed before the patched
+    // original game is removed
+    let base_disc = if config.patches.is_empty() {
+        None
+    } else {
+        apply_disc_patches(printer, &mut files, &mut config, &mut original, &out_path)?
+    };
+    let mut reader = match base_disc {
+        Some(ref base_disc) => Disc::raw(
+            File::open(&base_disc.0).context("Couldn't open the patched original game")?,
+        ),
+        None => original,
+    };
 
-    printer.print(None, "Building", "ISO");
+    if iso::wii::is_wii(&mut reader).context("Couldn't read the original game")? {
+        let partition =
+            iso::wii::DataPartition::find(&mut reader).context("Couldn't parse the Wii disc")?;
+        let mut reader = partition.reader(reader);
+        let system_data =
+            SystemData::read(&mut reader).context("Couldn't parse the data partition")?;
+        let layout = disc_layout(&config)?;
 
-    iso::writer::write_iso(
-        BufWriter::with_capacity(
-            4 << 20,
-            File::create(out_path).context("Couldn't create the final ISO")?,
-        ),
-        &iso,
-    ).context("Couldn't write the final ISO")?;
+        let iso = build_iso(
+            printer,
+            files,
+            &mut reader,
+            &system_data,
+            compiled_library,
+            &mut config,
+        )?;
+
+        // Both the game ID of the build and the one that keeps the saves
+        // apart end up in the header, but the Wii goes by the title ID of the
+        // ticket and the TMD
+        let header = read_iso_file(&iso, &mut reader, "&&systemdata/iso.hdr")?;
+        let game_id = if header[..4] != *partition.game_code() {
+            Some(header[..6].to_owned())
+        } else {
+            None
+        };
+
+        let mut output = iso::disc::create(&out_path)?;
+        printer.print(None, "Building", output.name());
+        // Only the parts of the partition that the FST refers to are read
+        if let iso::disc::Output::Wbfs(ref mut wbfs) = output {
+            let used = iso::writer::used_ranges(&mut reader, &iso, &layout)
+                .context("Couldn't lay out the data partition")?;
+            wbfs.leave_out(&partition.unused_clusters(&used));
+        }
+
+        let mut writer = partition
+            .writer(reader.get_mut(), output, game_id.as_ref().map(|id| &id[..]))
+            .context("Couldn't write the final game")?;
+        iso::writer::write_iso_with_layout(&mut reader, &mut writer, &iso, &layout)
+            .context("Couldn't write the data partition")?;
+        let output = writer
+            .finish(reader.get_mut())
+            .context("Couldn't write the final game")?;
+        output.finish().context("Couldn't write the final game")?;
+
+        return Ok(());
+    }
+
+    ensure!(!is_wbfs, "WBFS files can only contain Wii games");
+
+    let system_data = SystemData::read(&mut reader).context("Couldn't parse the ISO")?;
+    let layout = disc_layout(&config)?;
+    let iso = build_iso(
+        printer,
+        files,
+        &mut reader,
+        &system_data,
+        compiled_library,
+        &mut config,
+    )?;
+
+    let mut output = iso::disc::create(&out_path)?;
+    printer.print(None, "Building", output.name());
+
+    iso::writer::write_iso_with_layout(&mut reader, &mut output, &iso, &layout)
+        .context("Couldn't write the final game")?;
+    output.finish().context("Couldn't write the final game")?;
 
     Ok(())
 }
 
-pub fn new(name: &str) -> Result<(), Error> {
-    let exit_code = Command::new("cargo")
-        .args(&["new", "--lib", &name])
-        .spawn()
-        .context("Couldn't create the cargo project")?
-        .wait()?;
+/// Runs the whole build of the Rom Hack, from linking it to patching the game,
+/// without writing the game or any of the other outputs. Every format is
+/// checked the same way, as they are all built from the patched game.
+pub fn check_game<P: KeyValPrint, F: FileSource>(
+    printer: &P,
+    mut files: F,
+    compiled_library: Vec<u8>,
+    mut config: Config,
+) -> Result<(), Error> {
+    clear_side_outputs(&mut config);
 
-    ensure!(exit_code.success(), "Couldn't create the cargo project");
+    printer.print(None, "Loading", "original game");
 
-    let mut file = File::create(format!("{}/RomHack.toml", name))
-        .context("Couldn't create the RomHack.toml")?;
-    write!(
-        file,
-        r#"[info]
-game-name = "{0}"
+    let mut original = iso::disc::open(&config.src.iso)?;
+    let out_path = config.build.iso.clone();
 
-[src]
-iso = "game.iso" # Provide the path of the game's ISO
-patch = "src/patch.asm"
-# Optionally specify the game's symbol map
-# map = "maps/framework.map"
+    // The base patches for the whole disc still need a temporary copy of it
+    let base_disc = if config.patches.is_empty() {
+        None
+    } else {
+        apply_disc_patches(printer, &mut files, &mut config, &mut original, &out_path)?
+    };
+    let mut disc = match base_disc {
+        Some(ref base_disc) => Disc::raw(
+            File::open(&base_disc.0).context("Couldn't open the patched original game")?,
+        ),
+        None => original,
+    };
 
-[files]
-# You may replace or add new files to the game here
-# "path/to/file/in/iso" = "path/to/file/on/harddrive"
+    let partition = find_data_partition(&mut disc).context("Couldn't read the original game")?;
+    let mut reader = game_reader(&partition, &mut disc);
+    let system_data = SystemData::read(&mut reader).context("Couldn't parse the original game")?;
 
-[build]
-map = "target/framework.map"
-iso = "target/{0}.iso"
+    build_iso(
+        printer,
+        files,
+        &mut reader,
+        &system_data,
+        compiled_library,
+        &mut config,
+    )?;
 
-[link]
-entries = ["init"] # Enter the exported function names here
-base = "0x8040_1000" # Enter the start address of the Rom Hack's code here
-"#,
-        name.replace('-', "_"),
-    ).context("Couldn't write the RomHack.toml")?;
+    printer.print(None, "Checked", "nothing was written");
 
-    let mut file = File::create(format!("{}/src/lib.rs", name))
-        .context("Couldn't create the lib.rs source file")?;
-    write!(
-        file,
-        "{}",
-        r#"#![no_std]
+    Ok(())
+}
 
-pub mod panic;
+/// Removes all the outputs that are created next to the game.
+fn clear_side_outputs(config: &mut Config) {
+    config.build.map = None;
+    config.build.report = None;
+    config.build.elf = None;
+    config.build.symbol_export = None;
+    config.build.c_symbols = None;
+    config.build.linker_script = None;
+    config.build.gdbinit = None;
+    config.build.manifest = None;
+    config.build.stats = None;
+    config.build.dolphin_ini = None;
+}
 
-#[no_mangle]
-pub extern "C" fn init() {}
-"#
-    ).context("Couldn't write the lib.rs source file")?;
+/// A file that is removed once it's not needed anymore.
+struct TempFile(PathBuf);
 
-    let mut file = File::create(format!("{}/src/panic.rs", name))
-        .context("Couldn't create the panic.rs source file")?;
-    write!(
-        file,
-        "{}",
-        r#"#[cfg(any(target_arch = "powerpc", target_arch = "wasm32"))]
-#[panic_handler]
-pub fn panic(_info: &::core::panic::PanicInfo) -> ! {
-    loop {}
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
 }
-"#
-    ).context("Couldn't write the panic.rs source file")?;
 
-    let mut file = File::create(format!("{}/src/patch.asm", name))
-        .context("Couldn't create the default patch file")?;
-    write!(
-        file,
-        r#"; You can use this to patch the game's code to call into the Rom Hack's code
-"#
-    ).context("Couldn't write the default patch file")?;
+/// Applies the base patches that are meant for the whole disc rather than
+/// the DOL, which are the BPS and UPS patches that expect a file of the
+/// disc's size. The patched disc is stored next to the output and returned,
+/// so it can be used as the original game. All the other base patches are
+/// left for `build_iso` to apply to the DOL.
+fn apply_disc_patches<P: KeyValPrint, F: FileSource>(
+    printer: &P,
+    files: &mut F,
+    config: &mut Config,
+    reader: &mut Disc,
+    out_path: &PathBuf,
+) -> Result<Option<TempFile>, Error> {
+    let mut disc_len = reader.seek(SeekFrom::End(0))?;
+    let mut patched: Option<TempFile> = None;
+    let mut index = 0;
+    // The applied patches are removed from the list, so this is added to the
+    // index to get the patch's position in the config
+    let mut applied = 0;
 
-    let mut file = OpenOptions::new()
-        .append(true)
-        .open(format!("{}/Cargo.toml", name))
-        .context("Couldn't open the Cargo.toml")?;
-    write!(
-        file,
-        "{}",
-        r#"# Comment this in if you want to use the gcn crate in your rom hack.
-# It requires the operating system symbols to be resolved via a map.
-# gcn = { git = "https://github.com/CryZe/gcn", features = ["panic"] }
+    while index < config.patches.len() {
+        let path = config.patches[index].clone();
+        let patch = files
+            .read_to_vec(&path)
+            .with_context(|_| format!("Couldn't read the base patch \"{}\".", path.display()))?;
+        if patchfile::source_len(&patch)? != Some(disc_len) {
+            index += 1;
+            continue;
+        }
+        config.patches.remove(index);
 
-[lib]
-crate-type = ["staticlib"]
+        printer.print(None, "Applying", &format!("{}", path.display()));
 
-[profile.dev]
-panic = "abort"
-opt-level = 1
+        // Each patch gets its own file, as the previous one is read from
+        let temp = TempFile(out_path.with_extension(format!("base{}.iso", index + applied)));
+        applied += 1;
+        let mut target = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp.0)
+            .context("Couldn't create the patched original game")?;
+        match patched {
+            Some(ref previous) => {
+                let mut source = BufReader::new(File::open(&previous.0)?);
+                patchfile::apply(&patch, &mut source, &mut target)
+            }
+            None => patchfile::apply(&patch, reader, &mut target),
+        }.with_context(|_| format!("Couldn't apply the base patch \"{}\"", path.display()))?;
 
-[profile.release]
-panic = "abort"
-lto = true
-"#
-    ).context("Couldn't write into the Cargo.toml")?;
+        disc_len = target.seek(SeekFrom::End(0))?;
+        patched = Some(temp);
+    }
 
-    let mut file = File::create(format!("{}/.gitignore", name))
-        .context("Couldn't create the gitignore file")?;
-    write!(
-        file,
-        r#"/target
-**/*.rs.bk
-"#
-    ).context("Couldn't write the gitignore file")?;
+    reader.seek(SeekFrom::Start(0))?;
 
-    Ok(())
+    Ok(patched)
 }
 
-fn patch_instructions(
-    mut original: DolFile,
-    intermediate: DolFile,
-    instructions: &[Instruction],
-) -> Result<Vec<u8>, Error> {
-    original.append(intermediate);
-    original
-        .patch(instructions)
-        .context("Couldn't patch the DOL")?;
+/// Builds the Rom Hack as a Riivolution patch. The output directory is named
+/// after the ISO that would've been built and mirrors the root of an SD card.
+/// The features that weren't selected are built on top of the Rom Hack one by
+/// one, so they can be enabled as options of the patch. The discs of a game
+/// that spans multiple discs are combined into the same patch, with an XML
+/// for each disc.
+pub fn build_and_emit_riivolution<P: KeyValPrint, F: FileSource>(
+    printer: &P,
+    mut files: F,
+    compiled_library: Vec<u8>,
+    mut config: Config,
+) -> Result<(), Error> {
+    let mut out_dir = mem::replace(&mut config.build.iso, Default::default());
+    out_dir.set_extension("");
+    let name = out_dir
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| err_msg("The output path has no valid file name"))?
+        .to_owned();
+
+    let sections = if config.discs.is_empty() {
+        vec![build_riivolution_section(
+            printer,
+            &mut files,
+            compiled_library,
+            config,
+            (&name, &name),
+            &out_dir,
+        )?]
+    } else {
+        // Each disc gets its own XML in the same patch
+        let discs = config.discs.keys().cloned().collect::<Vec<_>>();
+        let mut sections = Vec::with_capacity(discs.len());
+        for disc in discs {
+            printer.print(None, "Building", &format!("disc {}", disc));
+
+            let mut disc_config = config.clone();
+            select_disc(&mut disc_config, &disc);
+            let section = build_riivolution_section(
+                printer,
+                &mut files,
+                compiled_library.clone(),
+                disc_config,
+                (&format!("{} (Disc {})", name, disc), &format!("{}/{}", name, disc)),
+                &out_dir,
+            ).with_context(|_| format!("Couldn't build the disc {}", disc))?;
+            sections.push(section);
+        }
+        sections
+    };
 
-    Ok(original.to_bytes())
+    riivolution::write_xml(&sections, &out_dir)
+        .context("Couldn't write the Riivolution patch")?;
+
+    Ok(())
 }
 
-fn find_compiled_library(debug: bool) -> Result<PathBuf, Error> {
-    use std::iter::FromIterator;
+/// Builds the Rom Hack and its features for a single disc and writes their
+/// files and memory patches to the disc's directory.
+fn build_riivolution_section<P: KeyValPrint, F: FileSource>(
+    printer: &P,
+    mut files: F,
+    compiled_library: Vec<u8>,
+    mut config: Config,
+    (name, dir): (&str, &str),
+    out_dir: &Path,
+) -> Result<riivolution::Section, Error> {
+    printer.print(None, "Loading", "original game");
+
+    let mut reader = iso::disc::open(&config.src.iso)?;
 
-    let dir = fs::read_dir(PathBuf::from_iter(&[
-        "target",
-        "powerpc-unknown-linux-gnu",
-        if debug { "debug" } else { "release" },
-    ])).context("Couldn't list entries of the compiler's target directory")?;
+    ensure!(
+        iso::wii::is_wii(&mut reader).context("Couldn't read the original game")?,
+        "Riivolution patches can only be built for Wii games"
+    );
 
-    for entry in dir {
-        let entry = entry.context("Couldn't list an entry of the compiler's target directory")?;
-        let path = entry.path();
-        if path.extension() == Some("a".as_ref()) {
-            return Ok(path);
-        }
+    let partition =
+        iso::wii::DataPartition::find(&mut reader).context("Couldn't parse the Wii disc")?;
+    let mut reader = partition.reader(reader);
+    let system_data = SystemData::read(&mut reader).context("Couldn't parse the data partition")?;
+
+    let mut feature_configs = Vec::with_capacity(config.features.len());
+    for feature in config.features.keys() {
+        let mut feature_config = config.clone();
+        clear_side_outputs(&mut feature_config);
+        select_features(&mut feature_config, &[feature.clone()])?;
+        feature_configs.push((feature.clone(), feature_config));
     }
 
-    bail!("None of the files in the compiler's target directory match *.a")
+    let iso = build_iso(
+        printer,
+        &mut files,
+        &mut reader,
+        &system_data,
+        compiled_library.clone(),
+        &mut config,
+    )?;
+
+    let mut features = Vec::with_capacity(feature_configs.len());
+    for &mut (ref feature, ref mut feature_config) in &mut feature_configs {
+        printer.print(None, "Building", &format!("feature {}", feature));
+
+        let feature_iso = build_iso(
+            printer,
+            &mut files,
+            &mut reader,
+            &system_data,
+            compiled_library.clone(),
+            feature_config,
+        ).with_context(|_| format!("Couldn't build the feature \"{}\"", feature))?;
+        features.push((feature.clone(), feature_iso));
+    }
+
+    printer.print(None, "Building", "Riivolution patch");
+
+    let section = riivolution::write_section(
+        printer,
+        &mut reader,
+        &system_data,
+        (&iso, &features),
+        (name, dir),
+        out_dir,
+    ).context("Couldn't write the Riivolution patch")?;
+
+    Ok(section)
+}
+
+/// Builds the Rom Hack as a channel that boots the patched DOL. The channel
+/// contains no other files, so they are still read from the disc in the drive.
+pub fn build_and_emit_wad<P: KeyValPrint, F: FileSource>(
+    printer: &P,
+    mut files: F,
+    compiled_library: Vec<u8>,
+    mut config: Config,
+) -> Result<(), Error> {
+    printer.print(None, "Loading", "original game");
+
+    let mut original = iso::disc::open(&config.src.iso)?;
+
+    let mut out_path = mem::replace(&mut config.build.iso, Default::default());
+    out_path.set_extension("wad");
+
+    // Declared before the reader, so the reader is closed before the patched
+    // original game is removed
+    let base_disc = if config.patches.is_empty() {
+        None
+    } else {
+        apply_disc_patches(printer, &mut files, &mut config, &mut original, &out_path)?
+    };
+    let mut reader = match base_disc {
+        Some(ref base_disc) => Disc::raw(
+            File::open(&base_disc.0).context("Couldn't open the patched original game")?,
+        ),
+        None => original,
+    };
+
+    ensure!(
+        iso::wii::is_wii(&mut reader).context("Couldn't read the original game")?,
+        "Channels can only be built for Wii games"
+    );
+
+    let partition =
+        iso::wii::DataPartition::find(&mut reader).context("Couldn't parse the Wii disc")?;
+    let mut reader = partition.reader(reader);
+    let system_data = SystemData::read(&mut reader).context("Couldn't parse the data partition")?;
+
+    let iso = build_iso(
+        printer,
+        files,
+        &mut reader,
+        &system_data,
+        compiled_library,
+        &mut config,
+    )?;
+
+    printer.print(None, "Building", "channel");
+
+    let header = read_iso_file(&iso, &mut reader, "&&systemdata/iso.hdr")?;
+    let banner = read_iso_file(&iso, &mut reader, "opening.bnr")?;
+    let dol = iso
+        .main_dol()
+        .ok_or_else(|| err_msg("Dol file not found"))?
+        .read(&mut reader)
+        .context("Couldn't read the DOL")?
+        .into_owned();
+
+    let wad = wad::Wad::channel(
+        partition.cert_chain(),
+        partition.ticket(),
+        partition.tmd(),
+        &header[..4],
+        banner,
+        dol,
+    ).context("Couldn't build the channel")?;
+    wad.write(BufWriter::new(
+        File::create(out_path).context("Couldn't create the WAD file")?,
+    )).context("Couldn't write the WAD file")?;
+
+    Ok(())
+}
+
+trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+fn find_data_partition(disc: &mut Disc) -> Result<Option<iso::wii::DataPartition>, Error> {
+    Ok(if iso::wii::is_wii(disc)? {
+        Some(iso::wii::DataPartition::find(disc).context("Couldn't parse the Wii disc")?)
+    } else {
+        None
+    })
+}
+
+/// Reads the data partition of Wii games and the whole disc of GameCube games.
+fn game_reader<'a>(
+    partition: &'a Option<iso::wii::DataPartition>,
+    disc: &'a mut Disc,
+) -> Box<ReadSeek + 'a> {
+    match *partition {
+        Some(ref partition) => Box::new(partition.reader(disc)),
+        None => Box::new(disc),
+    }
+}
+
+/// Compares the patched game with the original game, like two builds of a Rom
+/// Hack or a build with the game it's based on. Returns the report of the
+/// differences.
+pub fn diff<P: KeyValPrint>(
+    printer: &P,
+    original_game: PathBuf,
+    patched_game: PathBuf,
+) -> Result<String, Error> {
+    printer.print(None, "Loading", "games");
+
+    let mut original = iso::disc::open(&original_game)?;
+    let mut patched = iso::disc::open(&patched_game)?;
+    let original_partition =
+        find_data_partition(&mut original).context("Couldn't read the original game")?;
+    let patched_partition =
+        find_data_partition(&mut patched).context("Couldn't read the patched game")?;
+    let mut original_reader = game_reader(&original_partition, &mut original);
+    let mut patched_reader = game_reader(&patched_partition, &mut patched);
+
+    let original_data =
+        SystemData::read(&mut original_reader).context("Couldn't parse the original game")?;
+    let patched_data =
+        SystemData::read(&mut patched_reader).context("Couldn't parse the patched game")?;
+    let original_iso =
+        iso::reader::load_iso(&original_data).context("Couldn't parse the original game")?;
+    let patched_iso =
+        iso::reader::load_iso(&patched_data).context("Couldn't parse the patched game")?;
+    let original_dol =
+        DolFile::parse(&original_data.dol).context("Couldn't parse the original DOL")?;
+    let patched_dol = DolFile::parse(&patched_data.dol).context("Couldn't parse the patched DOL")?;
+
+    printer.print(None, "Comparing", "games");
+
+    let mut report = String::new();
+    diff::diff_dols(&mut report, &original_dol, &patched_dol)?;
+    diff::diff_files(
+        &mut report,
+        &original_iso,
+        &mut original_reader,
+        &patched_iso,
+        &mut patched_reader,
+    )?;
+
+    Ok(report)
+}
+
+/// Creates an IPS, BPS, UPS or VCDIFF patch that turns the original game into the
+/// patched one. Either the full discs or only their main DOLs are diffed.
+pub fn create_patch_file<P: KeyValPrint>(
+    printer: &P,
+    format: patchfile::Format,
+    original_game: PathBuf,
+    patched_game: PathBuf,
+    output: PathBuf,
+    dol_only: bool,
+) -> Result<(), Error> {
+    let writer = BufWriter::new(File::create(output).context("Couldn't create the patch file")?);
+
+    if dol_only {
+        printer.print(None, "Loading", "DOLs");
+
+        let original = read_main_dol(&original_game).context("Couldn't read the original game")?;
+        let patched = read_main_dol(&patched_game).context("Couldn't read the patched game")?;
+
+        printer.print(None, "Diffing", "DOLs");
+
+        patchfile::create(
+            format,
+            &original[..],
+            original.len() as u64,
+            &patched[..],
+            patched.len() as u64,
+            writer,
+        ).context("Couldn't create the patch")?;
+    } else {
+        let mut original = iso::disc::open(&original_game)?;
+        let mut patched = iso::disc::open(&patched_game)?;
+        let original_len = original.seek(SeekFrom::End(0))?;
+        let patched_len = patched.seek(SeekFrom::End(0))?;
+        original.seek(SeekFrom::Start(0))?;
+        patched.seek(SeekFrom::Start(0))?;
+
+        printer.print(None, "Diffing", "games");
+
+        patchfile::create(
+            format,
+            original,
+            original_len,
+            patched,
+            patched_len,
+            writer,
+        ).context("Couldn't create the patch")?;
+    }
+
+    Ok(())
+}
+
+fn read_main_dol(path: &PathBuf) -> Result<Vec<u8>, Error> {
+    let mut reader = iso::disc::open(path)?;
+
+    let system_data = if iso::wii::is_wii(&mut reader)? {
+        let partition =
+            iso::wii::DataPartition::find(&mut reader).context("Couldn't parse the Wii disc")?;
+        SystemData::read(&mut partition.reader(reader))
+            .context("Couldn't parse the data partition")?
+    } else {
+        SystemData::read(&mut reader).context("Couldn't parse the ISO")?
+    };
+
+    Ok(system_data.dol)
+}
+
+/// Extracts the main DOL of the game. The DOL of Wii games is taken from the
+/// data partition.
+pub fn extract_dol<P: KeyValPrint>(
+    printer: &P,
+    original_game: PathBuf,
+    output: PathBuf,
+) -> Result<(), Error> {
+    printer.print(None, "Loading", "game");
+
+    let dol = read_main_dol(&original_game)?;
+
+    printer.print(None, "Extracting", "DOL");
+
+    fs::write(output, &dol).context("Couldn't write the DOL")?;
+
+    Ok(())
+}
+
+/// Lists the messages of a BMG file of the game like the `[messages]` section
+/// of the config, so they can be translated. The texts are decoded through
+/// the TBL file, if there is one. Returns the listed messages.
+pub fn extract_messages<P: KeyValPrint>(
+    printer: &P,
+    original_game: PathBuf,
+    iso_path: String,
+    table: Option<PathBuf>,
+) -> Result<String, Error> {
+    printer.print(None, "Loading", "game");
+
+    let mut disc = iso::disc::open(&original_game)?;
+    let partition = find_data_partition(&mut disc).context("Couldn't read the game")?;
+    let mut reader = game_reader(&partition, &mut disc);
+    let system_data = SystemData::read(&mut reader).context("Couldn't parse the ISO")?;
+    let iso = iso::reader::load_iso(&system_data).context("Couldn't parse the ISO")?;
+
+    let table = match table {
+        Some(path) => Some(load_text_table(&mut FileSystem, &path)?),
+        None => None,
+    };
+
+    printer.print(None, "Extracting", "messages");
+
+    let data = read_iso_file(&iso, &mut reader, &iso_path)?;
+    let data = match codec::detect(&data) {
+        Some(codec) => codec.decompress(&data)?,
+        None => data,
+    };
+    let bmg = Bmg::parse(&data)
+        .with_context(|_| format!("Couldn't parse the message file \"{}\"", iso_path))?;
+
+    let mut messages = format!("[messages.{}]\n", toml::Value::String(iso_path.clone()));
+    for (id, text) in bmg.messages(table.as_ref())? {
+        messages += &format!("0x{:X} = {}\n", id, toml::Value::String(text));
+    }
+
+    Ok(messages)
+}
+
+/// Replaces the main DOL of the game. The data partition of Wii games is
+/// rebuilt around the new DOL, like when building a Rom Hack.
+pub fn replace_dol<P: KeyValPrint>(
+    printer: &P,
+    original_game: PathBuf,
+    dol: PathBuf,
+    output: PathBuf,
+) -> Result<(), Error> {
+    printer.print(None, "Loading", "game");
+
+    let dol_buf = fs::read(&dol)
+        .with_context(|_| format!("Couldn't read the DOL \"{}\".", dol.display()))?;
+    DolFile::parse(&dol_buf).context("The replacement DOL is invalid")?;
+
+    let mut disc = iso::disc::open(&original_game)?;
+    let output = BufWriter::with_capacity(
+        4 << 20,
+        File::create(output).context("Couldn't create the final ISO")?,
+    );
+
+    if let Some(partition) = find_data_partition(&mut disc).context("Couldn't read the game")? {
+        let mut reader = partition.reader(disc);
+        let system_data =
+            SystemData::read(&mut reader).context("Couldn't parse the data partition")?;
+        let iso = load_iso_with_dol(printer, &system_data, dol_buf)?;
+
+        printer.print(None, "Building", "ISO");
+
+        let mut writer = partition
+            .writer(reader.get_mut(), output, None)
+            .context("Couldn't write the final ISO")?;
+        iso::writer::write_iso(&mut reader, &mut writer, &iso)
+            .context("Couldn't write the data partition")?;
+        writer
+            .finish(reader.get_mut())
+            .context("Couldn't write the final ISO")?;
+    } else {
+        let system_data = SystemData::read(&mut disc).context("Couldn't parse the ISO")?;
+        let iso = load_iso_with_dol(printer, &system_data, dol_buf)?;
+
+        printer.print(None, "Building", "ISO");
+
+        iso::writer::write_iso(&mut disc, output, &iso).context("Couldn't write the final ISO")?;
+    }
+
+    Ok(())
+}
+
+fn load_iso_with_dol<'a, P: KeyValPrint>(
+    printer: &P,
+    system_data: &'a SystemData,
+    dol: Vec<u8>,
+) -> Result<Directory<'a>, Error> {
+    let mut iso = iso::reader::load_iso(system_data).context("Couldn't parse the ISO")?;
+
+    printer.print(None, "Replacing", "DOL");
+
+    iso.main_dol_mut()
+        .ok_or_else(|| err_msg("Dol file not found"))?
+        .data = dol.into();
+
+    Ok(iso)
+}
+
+pub fn new(name: &str) -> Result<(), Error> {
+    let exit_code = Command::new("cargo")
+        .args(&["new", "--lib", &name])
+        .spawn()
+        .context("Couldn't create the cargo project")?
+        .wait()?;
+
+    ensure!(exit_code.success(), "Couldn't create the cargo project");
+
+    let mut file = File::create(format!("{}/RomHack.toml", name))
+        .context("Couldn't create the RomHack.toml")?;
+    write!(
+        file,
+        r#"# You may remove files from the game here
+# remove-files = ["path/to/file/in/iso"]
+# You may build on top of IPS, BPS or UPS patches, like translations. They are
+# applied to the whole game if they were made for it, and to the DOL otherwise.
+# patch = ["base_translation.bps"]
+
+[info]
+game-name = "{0}"
+# Optionally replace the banner's image, which is scaled to 96x32 pixels
+# image = "banner.png"
+# Optionally override the banner's texts for single languages of a PAL game
+# languages = {{ german = {{ description = "Beschreibung" }} }}
+
+[src]
+iso = "game.iso" # Provide the path of the game's ISO, CISO, GCZ, WIA or RVZ file
+# Optionally make sure the Rom Hack is only built for a specific game and region
+# game-id = "GZLE01"
+# Optionally make sure the game runs on a specific platform: "gamecube", "wii"
+# or "triforce" for the arcade board. Only decrypted Triforce images are
+# supported: encrypted ones, like GD-ROM dumps, are refused, and the built image
+# stays decrypted, so the media board needs to net boot it
+# platform = "gamecube"
+# Optionally warn when the game isn't one of the known good dumps of a DAT
+# file, like the ones Redump publishes
+# dat = "GameCube.dat"
+# Optionally replace the game's apploader with a custom one
+# apploader = "apploader.img"
+patch = "src/patch.asm"
+# Optionally specify Gecko codes to apply, either as text or as a GCT file
+# gecko = "codes.txt"
+# Optionally embed a Gecko code handler, like Gecko OS's codehandler.bin, that
+# runs its codes every time the game executes the hook. Unlike the codes above,
+# they can use every code type. The handler is placed at 0x8000_1800 with its
+# code list directly after it, either as text or as a GCT file.
+# code-handler = "codehandler.bin"
+# handler-codes = "handler_codes.txt"
+# handler-hook = "0x8000_1234"
+# Optionally specify decrypted Action Replay codes to apply
+# action-replay = "action_replay.txt"
+# Optionally specify the game's symbol map
+# map = "maps/framework.map"
+# Optionally specify additional CodeWarrior or Dolphin symbol maps, so the
+# patch can refer to the game's functions by name, like `bl OSReport`
+# symbols = ["symbols/dolphin.map"]
+# Optionally define symbols for the .if and .ifdef directives of the patch
+# files, either as a name or as `NAME=value`. Debug builds define DEBUG.
+# defines = ["REGION_PAL"]
+# Optionally name addresses of the game, so the patch and the Rom Hack can refer
+# to them like to any other symbol
+# addresses = {{ player_update = "0x8005_1234" }}
+# Optionally find functions of the game by their bytes instead, so they're found
+# in every version of the game. ?? matches any byte.
+# signatures = {{ player_update = "9421FFE0 7C0802A6 90010024 ???????? 3BE30000" }}
+# Optionally encode the strings the patch files replace, like
+# `string 0x803A_1234 "Press Start"`, in "shift-jis" or "utf-16"
+# text-encoding = "shift-jis"
+# Or in the game's own encoding from a TBL file with lines like "41=A", which
+# the messages are encoded with as well
+# text-table = "src/game.tbl"
+
+[files]
+# You may replace or add new files to the game here
+# "path/to/file/in/iso" = "path/to/file/on/harddrive"
+# Files inside of U8 and RARC archives are separated from the archive's path by a colon
+# "path/to/archive.arc:path/in/archive" = "path/to/file/on/harddrive"
+
+[messages]
+# You may change the texts of the messages in BMG files by their IDs. Commands
+# like colors are written as their bytes in hex, like {{FF 00 00 01}}.
+# [messages."path/to/file.bmg"]
+# 0x12 = "Hello {{FF 00 00 01}}World"
+
+[textures]
+# You may replace the textures of TPL and BTI files with images. They are
+# converted into the original texture's format, including its mipmaps.
+# "path/to/texture.bti" = "path/to/image.png"
+
+[fonts]
+# You may add glyphs to BRFNT and BFN fonts by their characters, which are
+# encoded in the font's encoding or through the text table. They use the
+# format of the font's sheets and need to fit into the font's cells.
+# [fonts."path/to/font.brfnt"]
+# "é" = "glyphs/e_acute.png"
+
+[videos]
+# You may replace THP videos with THP files or AVI files with MJPEG video and
+# 16 bit PCM audio. They need the original video's dimensions and frame rate.
+# "path/to/video.thp" = "path/to/video.avi"
+
+[sounds]
+# You may replace DSP files and BRSTM streams with 16 bit PCM WAV files. They
+# keep looping if the original sound did, unless the WAV file sets its own loop.
+# Sound archives like BRSAR, BRWAR and BRWSD files or the wave banks of JAudio
+# aren't repacked, so the sounds inside of them can't be replaced.
+# "path/to/music.brstm" = "path/to/music.wav"
+
+[inject-bin]
+# You may place binaries into new sections of the DOL, either at an address or
+# after the Rom Hack. Their addresses are defined as symbols for the patches.
+# An address needs to be in the memory that the arena is shrunk by, like above
+# arena-hi, so the game doesn't overwrite the binary.
+# table = "data/table.bin @ 0x8170_0000"
+# palette = "data/palette.bin @ auto"
+
+[save]
+# Optionally annotate the structures of the game that end up in its saves. If
+# the patches, the hooks, the inject blocks or the Rom Hack itself change them,
+# old saves may get corrupted, so there's a warning.
+# structures = ["0x803C_4C08..0x803C_5380"]
+# Optionally keep the saves of the Rom Hack apart then, by changing the game ID
+# (the title ID on the Wii) and the comment the game gives its saves
+# game-id = "GZLE99"
+# comment = {{ address = "0x803A_1234", text = "My Rom Hack" }}
+
+[hooks]
+# You may call your functions whenever the game executes an instruction. The
+# overwritten instruction is still executed after the function returns.
+# "0x8000_1234" = "on_frame"
+# "[OSReport] + 0x10" = "on_report"
+# The instruction the hook replaces may be given as well, so the build fails
+# for other versions of the game.
+# "0x8000_5678" = {{ function = "on_load", original = "0x7C0802A6" }}
+
+[header]
+# You may override the fields of the disc header
+# country-code = "pal"
+# debug-flag = "0"
+# simulated-memory-size = "0x0180_0000"
+# argument-offset = "0"
+# The DOL and the FST may be placed at fixed offsets of the disc as well
+# dol-offset = "0x0002_0000"
+# fst-offset = "0x0040_0000"
+# The files after the FST are moved when the DOL or the FST grow. Audio
+# streams can be kept where they are instead, if nothing else grows into them.
+# keep-audio-streams-in-place = true
+
+[layout]
+# Files that are streamed may need to be aligned further or placed at a fixed
+# offset of the disc. A "*" in the path matches anything.
+# "*.thp" = {{ alignment = "0x8000" }}
+# "audio/bgm.ast" = {{ offset = "0x4000_0000" }}
+
+[build]
+map = "target/framework.map"
+iso = "target/{0}.iso"
+# Optionally list every instruction the patches overwrite next to its replacement
+# report = "target/report.txt"
+# Optionally change the game ID, so the Rom Hack gets its own saves and
+# emulator settings. The maker code may be left out to keep the original one.
+# game-id = "GZLH"
+# Optionally create an ELF of the patched game with all the known symbols and
+# the debug info of the Rom Hack, which can be loaded into disassemblers and
+# debuggers
+# elf = "target/{0}.elf"
+# Optionally export the Rom Hack's functions and data for disassemblers, as a
+# Ghidra script (".py"), an IDA script (".idc") or a plain list
+# symbol-export = "target/symbols.py"
+# Optionally export the addresses of the patch labels and the generated stubs
+# for C code, as a header with defines or a linker script (".ld")
+# c-symbols = "target/symbols.h"
+# Optionally create a GNU ld linker script with the memory layout of the Rom
+# Hack and the game's symbols, for linking objects with devkitPPC
+# linker-script = "target/romhack.ld"
+# Optionally create a GDB script that loads the debug info of the linked objects
+# at their addresses, for debugging the Rom Hack through Dolphin's GDB stub
+# gdbinit = "target/gdbinit"
+# Optionally create a JSON manifest of every word, section and file the build
+# changes, for reviewing the changes or processing them with other tools
+# manifest = "target/manifest.json"
+# Optionally write the sizes of the added sections and patches and the memory
+# that is left free, which are printed after every build, as JSON
+# stats = "target/stats.json"
+# Optionally create a Dolphin game INI that applies the Rom Hack to the
+# original game as patches and Gecko codes. The Rom Hack needs to be linked
+# into the free regions, as the original game's arena isn't shrunk
+# dolphin-ini = "target/{0}.ini"
+# The output to build: "iso", "wbfs", "ciso", "wad", "patch" or "riivolution".
+# ISOs ending with ".part0.iso" are split into parts that fit onto FAT32 drives
+# format = "iso"
+# How compressed archives are compressed again: "fast" or "optimal"
+# compression = "fast"
+# Optionally store the injected binaries compressed in the DOL, which are
+# decompressed when the game starts
+# compress-binaries = true
+# Optionally shrink the DOL by clearing the zeros at the end of the Rom Hack's
+# data at startup instead of storing them
+# trim-sections = true
+# What fills the gaps between the files of the disc: "junk" like the original
+# disc, or "zeros", which compress better
+# padding = "junk"
+
+[link]
+entries = ["init"] # Enter the exported function names here
+base = "0x8040_1000" # Enter the start address of the Rom Hack's code here
+# Optionally link additional static libraries or object files, for example
+# ones compiled from C or C++ with devkitPPC
+# libs = ["path/to/lib.a", "path/to/object.o"]
+# Optionally specify unused parts of memory, like code that is never
+# executed, that may be used for the code generated for Gecko codes and hooks.
+# The memory at 0x8000_1800..0x8000_3000 is only free without a code handler.
+# free = ["0x8000_5F34..0x8000_6A00"]
+# Optionally call exported functions in order before the game starts, for
+# example to set up the state the hooks rely on
+# init = ["setup"]
+# Optionally shrink the arena the game allocates its heaps from, which
+# reserves the memory outside of it for the Rom Hack. OSSetArenaLo and
+# OSSetArenaHi need to be in the symbol map.
+# arena-lo = "0x8050_0000"
+# arena-hi = "0x8170_0000"
+# Optionally shrink the heaps of Wii games in MEM2 through OSSetMEM2ArenaHi,
+# so the generated code and data may be placed above them once MEM1 is full
+# mem2-arena-hi = "0x9300_0000"
+
+# Optionally read patches from more sources: "asm" files that only write to the
+# DOL, linked "elf" executables, raw "binary" files written to their address,
+# "gecko" and "action-replay" codes or JSON lists of "writes"
+# [[sources]]
+# kind = "binary"
+# path = "src/table.bin"
+# address = "0x8040_0000"
+
+# Optionally define features that are only built when they are selected, like
+# `romhack build --features widescreen`. FEATURE_<NAME> is defined for the
+# patch files. Riivolution patches turn the other features into options.
+# [features.widescreen]
+# patch = "src/widescreen.asm"
+# files = {{ "path/to/file/in/iso" = "path/to/file/on/harddrive" }}
+
+# Optionally build multiple regions of the game at once. Each region is built
+# from its own game with its own symbols, addresses, defines and files, and
+# REGION_<NAME> is defined for the patch files.
+# [regions.pal]
+# iso = "game_pal.iso"
+# game-id = "GZLP01"
+# symbols = ["symbols/pal.map"]
+# addresses = {{ player_update = "0x8005_5678" }}
+# Optionally translate the addresses the patch files and the hooks were written
+# with, like "0x8005_1234 0x8005_5678" per line, instead of repeating them
+# port-map = "ports/pal.txt"
+
+# Optionally build every disc of a game that spans multiple discs. The discs
+# share the patches and symbols, and DISC_<NAME> is defined for the patch
+# files. Riivolution patches combine the discs into a single patch, with an
+# XML for each disc.
+# [discs.1]
+# iso = "game_disc1.iso"
+# [discs.2]
+# iso = "game_disc2.iso"
+# files = {{ "path/to/file/in/iso" = "path/to/file/on/harddrive" }}
+"#,
+        name.replace('-', "_"),
+    ).context("Couldn't write the RomHack.toml")?;
+
+    let mut file = File::create(format!("{}/src/lib.rs", name))
+        .context("Couldn't create the lib.rs source file")?;
+    write!(
+        file,
+        "{}",
+        r#"#![no_std]
+
+pub mod panic;
+
+#[no_mangle]
+pub extern "C" fn init() {}
+"#
+    ).context("Couldn't write the lib.rs source file")?;
+
+    let mut file = File::create(format!("{}/src/panic.rs", name))
+        .context("Couldn't create the panic.rs source file")?;
+    write!(
+        file,
+        "{}",
+        r#"#[cfg(any(target_arch = "powerpc", target_arch = "wasm32"))]
+#[panic_handler]
+pub fn panic(_info: &::core::panic::PanicInfo) -> ! {
+    loop {}
+}
+"#
+    ).context("Couldn't write the panic.rs source file")?;
+
+    let mut file = File::create(format!("{}/src/patch.asm", name))
+        .context("Couldn't create the default patch file")?;
+    write!(
+        file,
+        r#"; You can use this to patch the game's code to call into the Rom Hack's code
+"#
+    ).context("Couldn't write the default patch file")?;
+
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(format!("{}/Cargo.toml", name))
+        .context("Couldn't open the Cargo.toml")?;
+    write!(
+        file,
+        "{}",
+        r#"# Comment this in if you want to use the gcn crate in your rom hack.
+# It requires the operating system symbols to be resolved via a map.
+# gcn = { git = "https://github.com/CryZe/gcn", features = ["panic"] }
+
+[lib]
+crate-type = ["staticlib"]
+
+[profile.dev]
+panic = "abort"
+opt-level = 1
+
+[profile.release]
+panic = "abort"
+lto = true
+"#
+    ).context("Couldn't write into the Cargo.toml")?;
+
+    let mut file = File::create(format!("{}/.gitignore", name))
+        .context("Couldn't create the gitignore file")?;
+    write!(
+        file,
+        r#"/target
+**/*.rs.bk
+*.romhack-cache
+"#
+    ).context("Couldn't write the gitignore file")?;
+
+    Ok(())
+}
+
+/// Reads the binaries of `inject-bin` into data sections and defines the
+/// symbols of their addresses. The ones with an automatic address are placed
+/// after the Rom Hack, while the others are returned, to be placed at their
+/// address.
+fn inject_binaries<P: KeyValPrint, F: FileSource>(
+    printer: &P,
+    config: &Config,
+    files: &mut F,
+    rom_hack: &mut DolFile,
+    symbols: &mut HashMap<String, u32>,
+) -> Result<(Vec<dol::Section>, Vec<InjectedSymbol>), Error> {
+    let mut next_address = rom_hack
+        .end_address()
+        .unwrap_or(0)
+        .max(rom_hack.bss_address + rom_hack.bss_size);
+    let mut binaries = Vec::new();
+    let mut injected_symbols = Vec::new();
+
+    for (name, entry) in &config.inject_bin {
+        let (path, address) = config::split_inject_bin(entry);
+        printer.print(None, "Injecting", &format!("binary \"{}\"", path.display()));
+        let data = files
+            .read_to_vec(path)
+            .with_context(|_| format!("Couldn't read the binary \"{}\".", path.display()))?;
+        ensure!(
+            !data.is_empty(),
+            "The binary \"{}\" is empty",
+            path.display()
+        );
+
+        let is_auto = address == "auto";
+        let address = if is_auto {
+            // Payloads like textures are expected to be aligned to a cache line
+            (next_address + 31) & !31
+        } else {
+            parse_address(address)
+                .with_context(|_| format!("Invalid address \"{}\" for \"{}\"", address, name))?
+        };
+        symbols.insert(name.clone(), address);
+        injected_symbols.push(InjectedSymbol {
+            address,
+            len: data.len() as u32,
+            name: name.clone(),
+        });
+
+        let section = dol::Section {
+            address,
+            data: data.into_boxed_slice(),
+        };
+        if is_auto {
+            next_address = section.end_address();
+            rom_hack.data_sections.push(section);
+        } else {
+            binaries.push(section);
+        }
+    }
+
+    Ok((binaries, injected_symbols))
+}
+
+/// Everything that is added to the DOL besides the linked Rom Hack.
+struct PatchInputs<'a, 'b: 'a> {
+    is_wii: bool,
+    /// Where the FST ends up in memory, which is the top of the arena.
+    fst_address: u32,
+    binaries: Vec<dol::Section>,
+    /// The addresses of the binaries that are stored compressed.
+    compressed_binaries: &'a [u32],
+    free_regions: &'a [(u32, u32)],
+    instructions: &'a [Instruction],
+    assembler: &'a Assembler<'b>,
+    gecko_codes: &'a [gecko::Code],
+    code_handler: Option<dol::Section>,
+    hooks: &'a [Hook],
+    arena_clamps: &'a [arena::Clamp],
+    init_functions: &'a [u32],
+    outputs: &'a config::Build,
+    sources: &'a BTreeMap<u32, String>,
+}
+
+fn patch_instructions<P: KeyValPrint>(
+    printer: &P,
+    mut original: DolFile,
+    mut intermediate: DolFile,
+    inputs: PatchInputs,
+    injected_symbols: &mut Vec<InjectedSymbol>,
+    manifest: &mut Manifest,
+) -> Result<(Vec<u8>, Vec<Range<u32>>), Error> {
+    let PatchInputs {
+        is_wii,
+        fst_address,
+        binaries,
+        compressed_binaries,
+        free_regions,
+        instructions,
+        assembler,
+        gecko_codes,
+        code_handler,
+        hooks,
+        arena_clamps,
+        init_functions,
+        outputs,
+        sources,
+    } = inputs;
+
+    if outputs.trim_sections {
+        let trimmed = intermediate.trim_into_bss();
+        if trimmed != 0 {
+            printer.print(
+                None,
+                "Trimmed",
+                &format!("{:#x} bytes of zeros off the Rom Hack's data", trimmed),
+            );
+        }
+    }
+    let end_address = intermediate
+        .end_address()
+        .ok_or_else(|| err_msg("The Rom Hack doesn't contain any sections"))?;
+    // The bss of the Rom Hack follows its sections
+    let end_address = end_address.max((intermediate.bss_address + intermediate.bss_size + 3) & !3);
+    let original_section_counts = (original.text_sections.len(), original.data_sections.len());
+    let bss = original.append(intermediate);
+
+    let arena_lo = arena::bound(arena_clamps, arena::Bound::Lo);
+    let arena_hi = arena::bound(arena_clamps, arena::Bound::Hi);
+    let mem2_arena_hi = arena::bound(arena_clamps, arena::Bound::Mem2Hi);
+    for binary in binaries {
+        let (start, end) = (binary.address, binary.end_address());
+        if let Some(&(free_start, free_end)) = free_regions
+            .iter()
+            .find(|&&(free_start, free_end)| free_start < end && start < free_end)
+        {
+            bail!(
+                "The binary at {:08X}..{:08X} overlaps the free region {:08X}..{:08X}",
+                start,
+                end,
+                free_start,
+                free_end
+            );
+        }
+        // The game allocates its heaps from the arena, which ends at the FST,
+        // so only the memory the arena is shrunk by stays untouched
+        let is_reserved = arena_lo.map_or(false, |lo| start >= end_address && end <= lo)
+            || arena_hi.map_or(false, |hi| start >= hi && end <= fst_address)
+            || mem2_arena_hi.map_or(false, |hi| start >= hi && end <= mem2::END);
+        ensure!(
+            is_reserved,
+            "The binary at {:08X}..{:08X} is not in the memory that the arena is shrunk by, \
+             so the game would overwrite it. The arena ends at the FST at {:08X}.",
+            start,
+            end,
+            fst_address
+        );
+
+        if let Some(section) = original
+            .text_sections
+            .iter()
+            .chain(&original.data_sections)
+            .find(|s| s.address < binary.end_address() && binary.address < s.end_address())
+        {
+            bail!(
+                "The binary at {:08X}..{:08X} overlaps the section at {:08X}..{:08X}",
+                binary.address,
+                binary.end_address(),
+                section.address,
+                section.end_address()
+            );
+        }
+        original.data_sections.push(binary);
+    }
+
+    // The sections of the game are aligned to 32 bytes, so the rest of their
+    // last block is padding that may still be in use. Both the game's bss and
+    // the Rom Hack's are cleared at startup, which would erase injected code.
+    let used_memory = original.text_sections[..original_section_counts.0]
+        .iter()
+        .chain(&original.data_sections[..original_section_counts.1])
+        .map(|s| s.address..(s.end_address() + 31) & !31)
+        .chain(iter::once(
+            original.bss_address..original.bss_address + original.bss_size,
+        ))
+        .chain(bss.clone())
+        .filter(|r| r.start < r.end)
+        .collect::<Vec<_>>();
+    for &(start, end) in free_regions {
+        if let Some(used) = used_memory.iter().find(|r| r.start < end && start < r.end) {
+            bail!(
+                "The free region {:08X}..{:08X} overlaps the game's sections, their padding or \
+                 the bss at {:08X}..{:08X}",
+                start,
+                end,
+                used.start,
+                used.end
+            );
+        }
+        original.add_free_region(start, end)?;
+    }
+    // Everything after the Rom Hack up to the next section or the arena is
+    // free as well, so code can always be placed after the Rom Hack. The
+    // memory the arena is shrunk by is all that's free if it starts later.
+    // Without a lower bound, it's the arena that follows the Rom Hack, which
+    // ends at the FST at the latest.
+    if let Some(arena_lo) = arena_lo {
+        ensure!(
+            arena_lo >= end_address,
+            "The arena would start at {:08X}, before the end of the Rom Hack at {:08X}",
+            arena_lo,
+            end_address
+        );
+    }
+    // The memory the arena is shrunk by at its top is free up to the FST. It's
+    // added before the memory after the Rom Hack, which needs to stay last.
+    if let Some(arena_hi) = arena_hi {
+        ensure!(
+            arena_hi >= end_address && arena_hi <= fst_address,
+            "The arena would end at {:08X}, outside of the memory between the end of the Rom \
+             Hack at {:08X} and the FST at {:08X}",
+            arena_hi,
+            end_address,
+            fst_address
+        );
+        original.add_free_regions_around_sections(arena_hi, fst_address);
+    }
+    let next_section = original
+        .text_sections
+        .iter()
+        .chain(&original.data_sections)
+        .map(|s| s.address)
+        .filter(|&a| a >= end_address)
+        .min();
+    let arena_end = arena_lo.or(arena_hi).unwrap_or(fst_address);
+    original.add_free_region(
+        end_address,
+        next_section.unwrap_or_else(|| arena_end.max(end_address)),
+    )?;
+    // The memory above the heaps in MEM2 is only used once MEM1 is full. The
+    // code that ends up there is out of reach of MEM1's relative branches, so
+    // every branch to generated code is checked, and the far branches of the
+    // patches go through veneers.
+    if let Some(mem2_arena_hi) = mem2_arena_hi {
+        ensure!(
+            is_wii,
+            "Only the Wii has a second memory to shrink the heaps of"
+        );
+        ensure!(
+            mem2::START <= mem2_arena_hi && mem2_arena_hi <= mem2::END,
+            "The heaps in MEM2 can't end at {:08X}, outside of MEM2",
+            mem2_arena_hi
+        );
+        original.add_free_regions_around_sections(mem2_arena_hi, mem2::END);
+    }
+
+    // Only an embedded code handler may take up the low memory it's left
+    let has_code_handler = code_handler.is_some();
+    if let Some(code_handler) = code_handler {
+        injected_symbols.push(InjectedSymbol {
+            address: code_handler.address,
+            len: code_handler.data.len() as u32,
+            name: "romhack_code_handler".to_string(),
+        });
+        original.text_sections.push(code_handler);
+    }
+
+    let gecko_len = gecko::stubs_len(gecko_codes);
+    let gecko_address = if gecko_len != 0 {
+        original
+            .allocate(gecko_len, 4)
+            .context("Couldn't find space for the Gecko codes")?
+    } else {
+        end_address
+    };
+    let (gecko_instructions, gecko_section) = gecko::lower(gecko_codes, &original, gecko_address)
+        .context("Couldn't apply the Gecko codes")?;
+    original.text_sections.extend(gecko_section);
+    if gecko_len != 0 {
+        injected_symbols.push(InjectedSymbol {
+            address: gecko_address,
+            len: gecko_len,
+            name: "romhack_gecko_stubs".to_string(),
+        });
+    }
+
+    let hooks_len = hook::trampolines_len(hooks);
+    let hook_address = if hooks_len != 0 {
+        original
+            .allocate(hooks_len, 4)
+            .context("Couldn't find space for the hooks")?
+    } else {
+        end_address
+    };
+    let (hook_instructions, hook_section) =
+        hook::lower(hooks, &original, hook_address).context("Couldn't generate the hooks")?;
+    original.text_sections.extend(hook_section);
+    for (index, hook) in hooks.iter().enumerate() {
+        injected_symbols.push(InjectedSymbol {
+            address: hook_address + index as u32 * hook::TRAMPOLINE_LEN,
+            len: hook::TRAMPOLINE_LEN,
+            name: format!("romhack_hook_{:08X}", hook.address),
+        });
+    }
+
+    let injections = assembler.injections();
+    let injections_len = inject::stubs_len(injections);
+    let inject_address = if injections_len != 0 {
+        original
+            .allocate(injections_len, 4)
+            .context("Couldn't find space for the inject blocks")?
+    } else {
+        end_address
+    };
+    let (inject_instructions, inject_section) =
+        inject::lower(injections, assembler, &original, inject_address)
+            .context("Couldn't generate the inject blocks")?;
+    original.text_sections.extend(inject_section);
+    let mut block_address = inject_address;
+    for injection in injections {
+        injected_symbols.push(InjectedSymbol {
+            address: block_address,
+            len: inject::stub_len(injection),
+            name: format!("romhack_inject_{:08X}", injection.address),
+        });
+        block_address += inject::stub_len(injection);
+    }
+
+    // Only the calls of the game's code are redirected, so the replacements
+    // can still call the original functions
+    let (replacement_instructions, redirected) = replace::lower(
+        assembler.replacements(),
+        &original,
+        &original.text_sections[..original_section_counts.0],
+    ).context("Couldn't replace the functions")?;
+    for (function, count) in redirected {
+        printer.print(
+            Some(MessageKind::Verbose),
+            "Redirected",
+            &format!("{} branches to {:08X}", count, function),
+        );
+    }
+
+    let strings = assembler.strings();
+    let strings_len =
+        strings::relocated_len(strings, &original).context("Couldn't replace the strings")?;
+    let strings_address = if strings_len != 0 {
+        original
+            .allocate(strings_len, 4)
+            .context("Couldn't find space for the relocated strings")?
+    } else {
+        end_address
+    };
+    let (string_instructions, strings_section, relocations) = strings::lower(
+        strings,
+        &original,
+        &original.data_sections[..original_section_counts.1],
+        strings_address,
+    ).context("Couldn't replace the strings")?;
+    original.data_sections.extend(strings_section);
+    for relocation in relocations {
+        printer.print(
+            Some(MessageKind::Verbose),
+            "Relocated",
+            &format!(
+                "the string at {:08X} to {:08X} and {} pointers to it",
+                relocation.original, relocation.address, relocation.pointers
+            ),
+        );
+    }
+    if strings_len != 0 {
+        injected_symbols.push(InjectedSymbol {
+            address: strings_address,
+            len: strings_len,
+            name: "romhack_strings".to_string(),
+        });
+    }
+
+    let far_branches = assembler.far_branches();
+    let veneers_len = veneer::veneers_len(far_branches);
+    let veneer_address = if veneers_len != 0 {
+        original
+            .allocate(veneers_len, 4)
+            .context("Couldn't find space for the veneers of the far branches")?
+    } else {
+        end_address
+    };
+    let (veneer_instructions, veneer_section) =
+        veneer::lower(far_branches, veneer_address).context("Couldn't generate the veneers")?;
+    original.text_sections.extend(veneer_section);
+    if veneers_len != 0 {
+        injected_symbols.push(InjectedSymbol {
+            address: veneer_address,
+            len: veneers_len,
+            name: "romhack_veneers".to_string(),
+        });
+    }
+
+    let arena_len = arena::stubs_len(arena_clamps);
+    let arena_address = if arena_len != 0 {
+        original
+            .allocate(arena_len, 4)
+            .context("Couldn't find space for shrinking the arena")?
+    } else {
+        end_address
+    };
+    let (arena_instructions, arena_section) = arena::lower(arena_clamps, &original, arena_address)
+        .context("Couldn't shrink the arena")?;
+    original.text_sections.extend(arena_section);
+    if arena_len != 0 {
+        injected_symbols.push(InjectedSymbol {
+            address: arena_address,
+            len: arena_len,
+            name: "romhack_arena".to_string(),
+        });
+    }
+
+    if !init_functions.is_empty() {
+        let init_len = entry::stub_len(init_functions);
+        let init_address = original
+            .allocate(init_len, 4)
+            .context("Couldn't find space for calling the init functions")?;
+        let stub = entry::lower(init_functions, &original, init_address)
+            .context("Couldn't call the init functions")?;
+        original.text_sections.push(stub);
+        original.entry_point = init_address;
+        injected_symbols.push(InjectedSymbol {
+            address: init_address,
+            len: init_len,
+            name: "romhack_init".to_string(),
+        });
+    }
+
+    // Loaders that patch the game after loading it may leave stale code in
+    // the caches, so they are synced for the Rom Hack's code in both MEM1 and
+    // MEM2, which are too far apart to be synced at once
+    let new_code = original.text_sections[original_section_counts.0..]
+        .iter()
+        .filter(|s| !s.data.is_empty())
+        .map(|s| s.address..s.end_address())
+        .collect::<Vec<_>>();
+    let code_ranges = [false, true]
+        .iter()
+        .filter_map(|&in_mem2| {
+            let sections = new_code
+                .iter()
+                .filter(|r| (r.start >= mem2::START) == in_mem2);
+            let start = sections.clone().map(|r| r.start).min()?;
+            let end = sections.map(|r| r.end).max()?;
+            Some(start..end)
+        })
+        .collect::<Vec<_>>();
+    if !code_ranges.is_empty() {
+        let stub_len = entry::sync_stub_len(&code_ranges);
+        let stub_address = original
+            .allocate(stub_len, 4)
+            .context("Couldn't find space for syncing the caches")?;
+        let stub = entry::sync_stub(&code_ranges, original.entry_point, stub_address);
+        original.text_sections.push(stub);
+        original.entry_point = stub_address;
+        injected_symbols.push(InjectedSymbol {
+            address: stub_address,
+            len: stub_len,
+            name: "romhack_sync_caches".to_string(),
+        });
+    }
+
+    // The bss is cleared first, so the init functions can rely on it
+    if let Some(ref bss) = bss {
+        let stub_address = original
+            .allocate(bss::STUB_LEN, 4)
+            .context("Couldn't find space for clearing the bss")?;
+        let stub = bss::lower(bss.clone(), original.entry_point, stub_address);
+        original.text_sections.push(stub);
+        original.entry_point = stub_address;
+        injected_symbols.push(InjectedSymbol {
+            address: stub_address,
+            len: bss::STUB_LEN,
+            name: "romhack_bss_init".to_string(),
+        });
+    }
+
+    for reservation in &original.reservations {
+        printer.print(
+            Some(MessageKind::Debug),
+            "Reserved",
+            &format!(
+                "{:#x} bytes at {:08X}",
+                reservation.size, reservation.address
+            ),
+        );
+    }
+    if arena_lo.is_none() {
+        if let Some(reservation) = original
+            .reservations
+            .iter()
+            .find(|r| r.address >= end_address && r.address < arena_end)
+        {
+            printer.print(
+                Some(MessageKind::Warning),
+                "Warning",
+                &format!(
+                    "The generated code at {:08X} is in the arena after the Rom Hack, which \
+                     the game may allocate its heaps from. Set arena-lo to reserve it.",
+                    reservation.address
+                ),
+            );
+        }
+    }
+
+    let inject_locations = injections
+        .iter()
+        .map(|i| (i.address, i.location.clone()))
+        .collect::<BTreeMap<_, _>>();
+    let patches = [
+        conflicts::Patch {
+            name: "the patch file",
+            instructions,
+            locations: Some(assembler.source_locations()),
+        },
+        conflicts::Patch {
+            name: "the Gecko codes",
+            instructions: &gecko_instructions,
+            locations: None,
+        },
+        conflicts::Patch {
+            name: "the hooks",
+            instructions: &hook_instructions,
+            locations: None,
+        },
+        conflicts::Patch {
+            name: "the inject blocks",
+            instructions: &inject_instructions,
+            locations: Some(&inject_locations),
+        },
+        conflicts::Patch {
+            name: "the function replacements",
+            instructions: &replacement_instructions,
+            locations: None,
+        },
+        conflicts::Patch {
+            name: "the strings",
+            instructions: &string_instructions,
+            locations: None,
+        },
+        conflicts::Patch {
+            name: "the veneers",
+            instructions: &veneer_instructions,
+            locations: None,
+        },
+        conflicts::Patch {
+            name: "the arena bounds",
+            instructions: &arena_instructions,
+            locations: None,
+        },
+    ];
+    conflicts::check(&original, &patches).context("The patches conflict with each other")?;
+
+    if let Some(path) = &outputs.report {
+        report::create(path, &original, &patches).context("Couldn't create the patch report")?;
+    }
+
+    for (index, patch) in patches.iter().enumerate() {
+        // Only the patch file is assembled from lines
+        let sources = if index == 0 { Some(sources) } else { None };
+        manifest.add_words(
+            manifest::MAIN_DOL,
+            patch.name,
+            patch.instructions,
+            sources,
+            |address| original.read_u32(address),
+        );
+    }
+    let (text_count, data_count) = original_section_counts;
+    for section in &original.text_sections[text_count..] {
+        manifest.add_section("text", section.address, section.data.len() as u32);
+    }
+    for section in &original.data_sections[data_count..] {
+        manifest.add_section("data", section.address, section.data.len() as u32);
+    }
+    if let Some(ref bss) = bss {
+        manifest.add_section("bss", bss.start, bss.end - bss.start);
+    }
+
+    if let Some(path) = &outputs.dolphin_ini {
+        let new_sections = original.text_sections[text_count..]
+            .iter()
+            .chain(&original.data_sections[data_count..])
+            .collect::<Vec<_>>();
+        dolphin_ini::create(path, &new_sections, bss.as_ref(), free_regions, &patches)
+            .context("Couldn't create the Dolphin INI")?;
+    }
+
+    original
+        .patch(instructions)
+        .context("Couldn't patch the DOL")?;
+    original
+        .patch(&gecko_instructions)
+        .context("Couldn't patch the DOL with the Gecko codes")?;
+    original
+        .patch(&hook_instructions)
+        .context("Couldn't patch the DOL with the hooks")?;
+    original
+        .patch(&inject_instructions)
+        .context("Couldn't patch the DOL with the branches to the inject blocks")?;
+    original
+        .patch(&replacement_instructions)
+        .context("Couldn't patch the DOL with the function replacements")?;
+    original
+        .patch(&string_instructions)
+        .context("Couldn't patch the DOL with the strings")?;
+    original
+        .patch(&veneer_instructions)
+        .context("Couldn't patch the DOL with the branches to the veneers")?;
+    original
+        .patch(&arena_instructions)
+        .context("Couldn't patch the DOL with the arena bounds")?;
+
+    // The binaries are compressed once everything is patched into them, and
+    // decompressed before anything else runs
+    for &address in compressed_binaries {
+        let index = original
+            .data_sections
+            .iter()
+            .position(|s| s.address == address)
+            .ok_or_else(|| format_err!("The binary at {:08X} wasn't injected", address))?;
+        let len = original.data_sections[index].data.len() as u32;
+        let compressed = Yaz0.compress(&original.data_sections[index].data, outputs.compression);
+        // Small or incompressible binaries don't get any smaller
+        if compressed.len() as u32 + decompressor::STUB_LEN >= len {
+            continue;
+        }
+        original.data_sections.remove(index);
+
+        let compressed_address = original
+            .allocate(compressed.len() as u32, 4)
+            .context("Couldn't find space for the compressed binaries")?;
+        let stub_address = original
+            .allocate(decompressor::STUB_LEN, 4)
+            .context("Couldn't find space for decompressing the binaries")?;
+        printer.print(
+            None,
+            "Compressed",
+            &format!(
+                "the binary at {:08X} from {:#x} to {:#x} bytes",
+                address,
+                len,
+                compressed.len()
+            ),
+        );
+        let stub = decompressor::lower(
+            compressed_address,
+            address,
+            len,
+            original.entry_point,
+            stub_address,
+        );
+        original.text_sections.push(stub);
+        original.entry_point = stub_address;
+        original.data_sections.push(dol::Section {
+            address: compressed_address,
+            data: compressed.into_boxed_slice(),
+        });
+        injected_symbols.push(InjectedSymbol {
+            address: stub_address,
+            len: decompressor::STUB_LEN,
+            name: format!("romhack_decompress_{:08X}", address),
+        });
+    }
+
+    original
+        .merge_sections()
+        .context("Couldn't fit the sections into the DOL")?;
+    memory_map::check(
+        &original,
+        is_wii,
+        has_code_handler,
+        arena_clamps,
+        fst_address,
+    ).context("The DOL doesn't fit into memory")?;
+
+    // The memory that is still free after placing all the generated code
+    let free_regions = mem::replace(&mut original.free_regions, Vec::new());
+
+    Ok((original.to_bytes()?, free_regions))
+}
+
+/// Reads the TBL file with the game's own text encoding.
+fn read_text_table<F: FileSource>(config: &Config, files: &mut F) -> Result<Option<Table>, Error> {
+    match config.src.text_table {
+        Some(ref path) => Ok(Some(load_text_table(files, path)?)),
+        None => Ok(None),
+    }
+}
+
+fn load_text_table<F: FileSource>(files: &mut F, path: &Path) -> Result<Table, Error> {
+    let text = files
+        .read_to_string(path)
+        .with_context(|_| format!("Couldn't read the text table \"{}\".", path.display()))?;
+    let table = Table::parse(&text)
+        .with_context(|_| format!("Couldn't parse the text table \"{}\".", path.display()))?;
+    Ok(table)
+}
+
+/// The encoding of the strings that the patch files replace, either one of
+/// the common ones or the game's own one from the text table.
+fn text_encoding(config: &Config, table: Option<Table>) -> Result<Option<TextEncoding>, Error> {
+    match (config.src.text_encoding, table) {
+        (Some(_), Some(_)) => {
+            bail!("Only one of the text encoding and the text table may be given")
+        }
+        (Some(config::TextEncoding::ShiftJis), None) => Ok(Some(TextEncoding::ShiftJis)),
+        (Some(config::TextEncoding::Utf16), None) => Ok(Some(TextEncoding::Utf16)),
+        (None, Some(table)) => Ok(Some(TextEncoding::Table(table))),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Checks whether the patches change the structures of the game that end up
+/// in its saves, as old saves would silently get corrupted then. The Rom
+/// Hack either gets its own game ID and save comment, so its saves are kept
+/// apart from the original game's ones, or a warning is shown.
+fn protect_saves<P: KeyValPrint, R: Read + Seek>(
+    printer: &P,
+    config: &Config,
+    platform: Platform,
+    instructions: &[Instruction],
+    hooks: &[Hook],
+    linked_sections: &[LinkedSection],
+    assembler: &mut Assembler,
+    iso: &mut Directory,
+    original_iso: &mut R,
+) -> Result<(), Error> {
+    // Everything that ends up in the game's memory, each as the written
+    // range and what writes it
+    let mut writes = Vec::new();
+    writes.extend(
+        instructions
+            .iter()
+            .map(|i| (i.address, i.address + 4, "the patch files")),
+    );
+    writes.extend(
+        hooks
+            .iter()
+            .map(|h| (h.address, h.address + 4, "the hooks")),
+    );
+    writes.extend(
+        assembler
+            .injections()
+            .iter()
+            .map(|i| (i.address, i.address + 4, "the inject blocks")),
+    );
+    writes.extend(
+        assembler
+            .replacements()
+            .iter()
+            .filter(|r| !r.calls_only)
+            .map(|r| (r.function, r.function + 4, "the replaced functions")),
+    );
+    for string in assembler.strings() {
+        let end = string.address + string.data.len() as u32;
+        writes.push((string.address, end, "the replaced strings"));
+        writes.extend(
+            string
+                .pointers
+                .iter()
+                .map(|&p| (p, p + 4, "the replaced strings")),
+        );
+    }
+    writes.extend(
+        linked_sections
+            .iter()
+            .map(|s| (s.address, s.address + s.len, "the Rom Hack")),
+    );
+
+    let save = &config.save;
+    let mut changed = false;
+    for structure in &save.structures {
+        let (start, end) = parse_region(structure)
+            .with_context(|_| format!("Invalid save structure \"{}\"", structure))?;
+        let mut writers = Vec::new();
+        for &(_, _, writer) in writes.iter().filter(|w| w.0 < end && start < w.1) {
+            if !writers.contains(&writer) {
+                writers.push(writer);
+            }
+        }
+        if !writers.is_empty() {
+            printer.print_with(
+                Some(MessageKind::Warning),
+                "Warning",
+                &format!(
+                    "The save data at {:08X}..{:08X} is changed by {}",
+                    start,
+                    end,
+                    writers.join(", ")
+                ),
+                &Fields {
+                    address: Some(start),
+                    ..Default::default()
+                },
+            );
+            changed = true;
+        }
+    }
+    if !changed {
+        return Ok(());
+    }
+
+    if save.game_id.is_none() && save.comment.is_none() {
+        printer.print(
+            Some(MessageKind::Warning),
+            "Warning",
+            "The saves of the original game may be incompatible with the Rom Hack",
+        );
+    }
+
+    // A game ID of the build already keeps the saves apart. On the Wii, the
+    // title ID of the ticket and the TMD is changed to it when the disc is
+    // written.
+    if let (Some(game_id), None) = (&save.game_id, &config.build.game_id) {
+        let mut header = read_iso_file(iso, original_iso, "&&systemdata/iso.hdr")?;
+        let game_id = parse_game_id(game_id, &header)?;
+        let kind = if platform == Platform::Wii {
+            "title ID"
+        } else {
+            "game ID"
+        };
+        printer.print(
+            None,
+            "Changing",
+            &format!(
+                "{} to {} for the saves",
+                kind,
+                String::from_utf8_lossy(&game_id)
+            ),
+        );
+        header[..game_id.len()].copy_from_slice(&game_id);
+        iso.resolve_path_mut("&&systemdata/iso.hdr")
+            .ok_or_else(|| err_msg("The disc header wasn't found"))?
+            .data = header.into();
+    }
+
+    if let Some(ref comment) = save.comment {
+        printer.print(None, "Changing", "save comment");
+        let address = assembler
+            .resolve_address(&comment.address)
+            .with_context(|_| {
+                format!("Couldn't resolve the save comment \"{}\"", comment.address)
+            })?;
+        assembler
+            .add_string(address, &comment.text)
+            .context("Couldn't change the save comment")?;
+    }
+
+    Ok(())
+}
+
+/// Parses a region of memory like `0x8000_1800..0x8000_3000`.
+fn parse_region(region: &str) -> Result<(u32, u32), Error> {
+    let mut bounds = region.splitn(2, "..");
+    let start = bounds.next().unwrap_or_default().trim();
+    let end = bounds
+        .next()
+        .ok_or_else(|| err_msg("Expected a range like 0x8000_1800..0x8000_3000"))?
+        .trim();
+    let start: syn::LitInt = syn::parse_str(start).context("Invalid start address")?;
+    let end: syn::LitInt = syn::parse_str(end).context("Invalid end address")?;
+    Ok((start.value() as u32, end.value() as u32))
+}
+
+/// Patches the files on the disc that the patch files target with labels like
+/// `file:maps/stage.dat+0x40:`. The addresses of their instructions are
+/// offsets into the files.
+fn patch_files<R: Read + Seek>(
+    iso: &mut Directory,
+    original_iso: &mut R,
+    assembler: &Assembler,
+    manifest: &mut Manifest,
+) -> Result<(), Error> {
+    let mut paths = assembler.file_instructions().keys().collect::<BTreeSet<_>>();
+    paths.extend(assembler.expectations().iter().filter_map(|e| e.file.as_ref()));
+
+    for path in paths {
+        let file = iso.resolve_path_mut(path).ok_or_else(|| {
+            format_err!("The file \"{}\" to patch doesn't exist on the disc", path)
+        })?;
+        let mut data = file
+            .read(original_iso)
+            .with_context(|_| format!("Couldn't read the file \"{}\"", path))?
+            .into_owned();
+        check_expectations(
+            assembler
+                .expectations()
+                .iter()
+                .filter(|e| e.file.as_ref() == Some(path)),
+            |offset| read_word(&data, offset),
+        ).with_context(|_| {
+            format!("The file \"{}\" isn't the one the patch was written for", path)
+        })?;
+
+        let instructions = match assembler.file_instructions().get(path) {
+            Some(instructions) => instructions,
+            None => continue,
+        };
+        manifest.add_words(path, "the patch file", instructions, None, |offset| {
+            read_word(&data, offset)
+        });
+
+        // Only the bytes in the masks are written, so the patches may end
+        // with the file even if its size isn't a multiple of 4
+        for instruction in instructions {
+            for index in 0..4 {
+                let shift = 8 * (3 - index);
+                if (instruction.mask >> shift) & 0xFF == 0 {
+                    continue;
+                }
+                let offset = instruction.address as usize + index;
+                let byte = data.get_mut(offset).ok_or_else(|| {
+                    format_err!("The patch at offset {:#x} is outside of \"{}\"", offset, path)
+                })?;
+                *byte = (instruction.data >> shift) as u8;
+            }
+        }
+
+        file.data = data.into();
+    }
+    Ok(())
+}
+
+fn read_word(data: &[u8], offset: u32) -> Option<u32> {
+    data.get(offset as usize..)
+        .filter(|d| d.len() >= 4)
+        .map(BE::read_u32)
+}
+
+/// Makes sure that the game contains the words the patch file expects before
+/// it's patched. Every mismatch is reported at once.
+fn check_expectations<'a, I, F>(expectations: I, read_original: F) -> Result<(), Error>
+where
+    I: IntoIterator<Item = &'a Expectation>,
+    F: Fn(u32) -> Option<u32>,
+{
+    let mismatches = expectations
+        .into_iter()
+        .filter_map(|e| match read_original(e.address) {
+            Some(word) if word == e.value => None,
+            Some(word) => Some(format!(
+                "Expected {:08X} at {:08X}, but found {:08X}",
+                e.value, e.address, word
+            )),
+            None => Some(format!(
+                "Expected {:08X} at {:08X}, which is outside of the file",
+                e.value, e.address
+            )),
+        }).collect::<Vec<_>>();
+
+    if !mismatches.is_empty() {
+        bail!("{}", mismatches.join("\n"));
+    }
+
+    Ok(())
+}
+
+/// Reads an archive from the disc and decompresses it. The codec it was
+/// compressed with is returned as well.
+fn read_archive<R: Read + Seek>(
+    iso: &Directory,
+    original_iso: &mut R,
+    archive_path: &str,
+) -> Result<(Option<&'static dyn Codec>, Vec<u8>), Error> {
+    let archive = iso
+        .resolve_path(archive_path)
+        .ok_or_else(|| {
+            format_err!("The archive \"{}\" doesn't exist on the disc", archive_path)
+        })?
+        .read(original_iso)
+        .with_context(|_| format!("Couldn't read the archive \"{}\"", archive_path))?
+        .into_owned();
+    let codec = codec::detect(&archive);
+    let archive = match codec {
+        Some(codec) => codec.decompress(&archive).with_context(|_| {
+            format!("Couldn't decompress the archive \"{}\"", archive_path)
+        })?,
+        None => archive,
+    };
+    Ok((codec, archive))
+}
+
+fn edit_messages<'a, R: Read + Seek>(
+    replacements: &mut Vec<(&'a str, Vec<u8>)>,
+    iso: &Directory,
+    original_iso: &mut R,
+    messages: &'a BTreeMap<String, BTreeMap<String, String>>,
+    text_table: Option<&Table>,
+    compression: Compression,
+) -> Result<(), Error> {
+    for (iso_path, messages) in messages {
+        let (data, codec) = take_or_read_decompressed(replacements, iso, original_iso, iso_path)?;
+        let mut bmg = Bmg::parse(&data)
+            .with_context(|_| format!("Couldn't parse the message file \"{}\"", iso_path))?;
+        for (id, text) in messages {
+            bmg.set_message(parse_message_id(id)?, text, text_table)
+                .with_context(|_| {
+                    format!("Couldn't edit the message {} of \"{}\"", id, iso_path)
+                })?;
+        }
+        push_recompressed(replacements, iso_path, bmg.to_bytes()?, codec, compression);
+    }
+    Ok(())
+}
+
+fn convert_textures<'a, R: Read + Seek, F: FileSource>(
+    replacements: &mut Vec<(&'a str, Vec<u8>)>,
+    iso: &Directory,
+    original_iso: &mut R,
+    files: &mut F,
+    textures: &'a BTreeMap<String, PathBuf>,
+    compression: Compression,
+) -> Result<(), Error> {
+    for (iso_path, image_path) in textures {
+        let (data, codec) = take_or_read_decompressed(replacements, iso, original_iso, iso_path)?;
+        let image = files
+            .open_image(image_path)
+            .with_context(|_| format!("Couldn't open the image \"{}\"", image_path.display()))?
+            .to_rgba();
+        let data = if texture::tpl::is_tpl(&data) {
+            texture::tpl::replace_texture(&data, &image)
+        } else {
+            texture::bti::replace_texture(&data, &image)
+        };
+        let data =
+            data.with_context(|_| format!("Couldn't convert the texture \"{}\"", iso_path))?;
+        push_recompressed(replacements, iso_path, data, codec, compression);
+    }
+    Ok(())
+}
+
+fn edit_fonts<'a, R: Read + Seek, F: FileSource>(
+    replacements: &mut Vec<(&'a str, Vec<u8>)>,
+    iso: &Directory,
+    original_iso: &mut R,
+    files: &mut F,
+    fonts: &'a BTreeMap<String, BTreeMap<String, PathBuf>>,
+    text_table: Option<&Table>,
+    compression: Compression,
+) -> Result<(), Error> {
+    for (iso_path, glyphs) in fonts {
+        let (data, codec) = take_or_read_decompressed(replacements, iso, original_iso, iso_path)?;
+        let encoding = font::encoding(&data)
+            .with_context(|_| format!("Couldn't parse the font \"{}\"", iso_path))?;
+        let mut new_glyphs = Vec::with_capacity(glyphs.len());
+        for (character, image_path) in glyphs {
+            let code = font_code(character, encoding, text_table).with_context(|_| {
+                format!(
+                    "Couldn't encode \"{}\" for the font \"{}\"",
+                    character, iso_path
+                )
+            })?;
+            let image = files
+                .open_image(image_path)
+                .with_context(|_| format!("Couldn't open the image \"{}\"", image_path.display()))?
+                .to_rgba();
+            new_glyphs.push(font::Glyph { code, image });
+        }
+        let data = font::add_glyphs(&data, &new_glyphs)
+            .with_context(|_| format!("Couldn't add the glyphs to the font \"{}\"", iso_path))?;
+        push_recompressed(replacements, iso_path, data, codec, compression);
+    }
+    Ok(())
+}
+
+/// The FST picks up the new sizes of the videos. A replaced video is what the
+/// new one needs to match then.
+fn convert_videos<'a, R: Read + Seek, F: FileSource>(
+    replacements: &mut Vec<(&'a str, Vec<u8>)>,
+    iso: &Directory,
+    original_iso: &mut R,
+    files: &mut F,
+    videos: &'a BTreeMap<String, PathBuf>,
+) -> Result<(), Error> {
+    for (iso_path, video_path) in videos {
+        let original = take_or_read(replacements, iso, original_iso, iso_path)?;
+        let video = files
+            .read_to_vec(video_path)
+            .with_context(|_| format!("Couldn't read the video \"{}\"", video_path.display()))?;
+        let data = thp::replace_video(&original, &video)
+            .with_context(|_| format!("Couldn't replace the video \"{}\"", iso_path))?;
+        replacements.push((iso_path.as_str(), data));
+    }
+    Ok(())
+}
+
+fn encode_sounds<'a, R: Read + Seek, F: FileSource>(
+    replacements: &mut Vec<(&'a str, Vec<u8>)>,
+    iso: &Directory,
+    original_iso: &mut R,
+    files: &mut F,
+    sounds: &'a BTreeMap<String, PathBuf>,
+) -> Result<(), Error> {
+    for (iso_path, sound_path) in sounds {
+        let data = take_or_read(replacements, iso, original_iso, iso_path)?;
+        let wav = files
+            .read_to_vec(sound_path)
+            .with_context(|_| format!("Couldn't read the sound \"{}\"", sound_path.display()))?;
+        let data = audio::replace_sound(iso_path, &data, &wav)
+            .with_context(|_| format!("Couldn't replace the sound \"{}\"", iso_path))?;
+        replacements.push((iso_path.as_str(), data));
+    }
+    Ok(())
+}
+
+/// Takes the file out of the replacements, so that replaced files can be
+/// edited as well, or reads it from the disc if it isn't replaced.
+fn take_or_read<R: Read + Seek>(
+    replacements: &mut Vec<(&str, Vec<u8>)>,
+    iso: &Directory,
+    original_iso: &mut R,
+    iso_path: &str,
+) -> Result<Vec<u8>, Error> {
+    match replacements.iter().position(|&(p, _)| p == iso_path) {
+        Some(index) => Ok(replacements.remove(index).1),
+        None => read_iso_file(iso, original_iso, iso_path),
+    }
+}
+
+/// Takes or reads the file like `take_or_read` and decompresses it. The codec
+/// it was compressed with is returned along with it.
+fn take_or_read_decompressed<R: Read + Seek>(
+    replacements: &mut Vec<(&str, Vec<u8>)>,
+    iso: &Directory,
+    original_iso: &mut R,
+    iso_path: &str,
+) -> Result<(Vec<u8>, Option<&'static dyn Codec>), Error> {
+    let data = take_or_read(replacements, iso, original_iso, iso_path)?;
+    let codec = codec::detect(&data);
+    let data = match codec {
+        Some(codec) => codec.decompress(&data)?,
+        None => data,
+    };
+    Ok((data, codec))
+}
+
+/// Compresses the edited file again with the codec the original one was
+/// compressed with and replaces the file with it.
+fn push_recompressed<'a>(
+    replacements: &mut Vec<(&'a str, Vec<u8>)>,
+    iso_path: &'a str,
+    data: Vec<u8>,
+    codec: Option<&dyn Codec>,
+    compression: Compression,
+) {
+    let data = match codec {
+        Some(codec) => codec.compress(&data, compression),
+        None => data,
+    };
+    replacements.push((iso_path, data));
+}
+
+/// Reads a file from the disc. Like in the `files` of the config, paths may
+/// point into archives.
+fn read_iso_file<R: Read + Seek>(
+    iso: &Directory,
+    original_iso: &mut R,
+    iso_path: &str,
+) -> Result<Vec<u8>, Error> {
+    if let Some(index) = iso_path.find(':') {
+        let archive_path = &iso_path[..index];
+        let (_, archive) = read_archive(iso, original_iso, archive_path)?;
+        let archive = Archive::parse(&archive)
+            .with_context(|_| format!("Couldn't parse the archive \"{}\"", archive_path))?;
+        Ok(archive.file(&iso_path[index + 1..])?.to_owned())
+    } else {
+        Ok(iso
+            .resolve_path(iso_path)
+            .ok_or_else(|| format_err!("The file \"{}\" doesn't exist on the disc", iso_path))?
+            .read(original_iso)
+            .with_context(|_| format!("Couldn't read the file \"{}\"", iso_path))?
+            .into_owned())
+    }
+}
+
+/// Replaces files inside of an archive. Replacements for compressed files are
+/// compressed as well, unless they already are.
+fn replace_files_in_archive<'a>(
+    archive: &mut Directory<'a>,
+    replacements: Vec<(&'a str, Vec<u8>)>,
+    compression: Compression,
+) -> Result<(), Error> {
+    for (path, data) in replacements {
+        let original_codec = match archive.resolve_path(path).map(|f| &f.data) {
+            Some(&FileData::Memory(ref original)) => Some(codec::detect(original)),
+            Some(&FileData::Disc { .. }) => Some(None),
+            None => None,
+        };
+        if let Some(original_codec) = original_codec {
+            let data = match original_codec {
+                Some(codec) if codec::detect(&data).is_none() => {
+                    codec.compress(&data, compression)
+                }
+                _ => data,
+            };
+            archive.replace_file(path, data)?;
+        } else {
+            archive.add_file(path, data)?;
+        }
+    }
+    Ok(())
+}
+
+/// Parses the game ID the Rom Hack is built with. The maker code can be left
+/// out, in which case the original one is kept.
+fn parse_game_id(game_id: &str, header: &[u8]) -> Result<Vec<u8>, Error> {
+    ensure!(
+        (game_id.len() == 4 || game_id.len() == 6)
+            && game_id
+                .bytes()
+                .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit()),
+        "The game ID \"{}\" needs to consist of 4 or 6 uppercase letters and digits",
+        game_id
+    );
+    let mut id = game_id.as_bytes().to_owned();
+    if id.len() == 4 {
+        id.extend_from_slice(&header[4..6]);
+    }
+    Ok(id)
+}
+
+/// Overrides the fields of the disc header with the ones of the config. The
+/// offsets of the DOL and the FST are only known when the disc is written.
+fn edit_header(header: &mut [u8], fields: &config::Header) -> Result<(), Error> {
+    if let Some(country_code) = fields.country_code {
+        BE::write_u32(&mut header[OFFSET_COUNTRY_CODE..], country_code.value());
+    }
+    for &(offset, value, name) in &[
+        (OFFSET_DEBUG_FLAG, &fields.debug_flag, "debug flag"),
+        (
+            OFFSET_SIMULATED_MEMORY_SIZE,
+            &fields.simulated_memory_size,
+            "simulated memory size",
+        ),
+        (
+            OFFSET_ARGUMENT_OFFSET,
+            &fields.argument_offset,
+            "argument offset",
+        ),
+    ] {
+        if let Some(value) = value {
+            let value = parse_address(value)
+                .with_context(|_| format!("Invalid {} \"{}\"", name, value))?;
+            BE::write_u32(&mut header[offset..], value);
+        }
+    }
+    Ok(())
+}
+
+/// Tells GameCube, Wii and Triforce games apart. Triforce games are GameCube
+/// discs with a boot.id for the media board.
+fn detect_platform<R: Read + Seek>(
+    iso: &Directory,
+    original_iso: &mut R,
+    system_data: &SystemData,
+) -> Result<Platform, Error> {
+    if Header::parse(&system_data.header)?.is_wii {
+        Ok(Platform::Wii)
+    } else if iso::triforce::is_triforce(iso, original_iso)? {
+        Ok(Platform::Triforce)
+    } else {
+        Ok(Platform::GameCube)
+    }
+}
+
+/// The media board identifies Triforce games by their boot.id, so the game ID
+/// and the boot.id need to stay the same.
+fn check_triforce_config(config: &Config) -> Result<(), Error> {
+    ensure!(
+        config.build.game_id.is_none(),
+        "The game ID of Triforce games can't be changed, as the media board expects the \
+         one of the boot.id"
+    );
+    let touches_boot_id = config
+        .remove_files
+        .iter()
+        .chain(config.files.keys())
+        .any(|path| path.eq_ignore_ascii_case(iso::triforce::BOOT_ID_PATH));
+    ensure!(
+        !touches_boot_id,
+        "The boot.id of Triforce games can't be replaced or removed"
+    );
+    Ok(())
+}
+
+fn disc_layout(config: &Config) -> Result<Layout, Error> {
+    let fields = &config.header;
+    let mut layout = Layout::default();
+    if let Some(ref offset) = fields.dol_offset {
+        let offset = parse_address(offset)
+            .with_context(|_| format!("Invalid DOL offset \"{}\"", offset))?;
+        layout.dol_offset = Some(offset as usize);
+    }
+    if let Some(ref offset) = fields.fst_offset {
+        let offset = parse_address(offset)
+            .with_context(|_| format!("Invalid FST offset \"{}\"", offset))?;
+        layout.fst_offset = Some(offset as usize);
+    }
+    layout.keep_audio_streams = fields.keep_audio_streams_in_place.unwrap_or(false);
+
+    layout.zero_padding = config.build.padding == Padding::Zeros;
+
+    for (pattern, file) in &config.layout {
+        let alignment = match file.alignment {
+            Some(ref alignment) => {
+                let alignment = parse_address(alignment).with_context(|_| {
+                    format!("Invalid alignment \"{}\" of \"{}\"", alignment, pattern)
+                })?;
+                ensure!(
+                    alignment.is_power_of_two(),
+                    "The alignment {:#x} of \"{}\" is not a power of two",
+                    alignment,
+                    pattern
+                );
+                Some(alignment as usize)
+            }
+            None => None,
+        };
+        let offset = match file.offset {
+            Some(ref offset) => {
+                let offset = parse_address(offset).with_context(|_| {
+                    format!("Invalid offset \"{}\" of \"{}\"", offset, pattern)
+                })?;
+                ensure!(
+                    offset % 32 == 0,
+                    "The offset {:#x} of \"{}\" needs to be aligned to 32 bytes",
+                    offset,
+                    pattern
+                );
+                ensure!(
+                    alignment.map_or(true, |a| offset as usize % a == 0),
+                    "The offset {:#x} of \"{}\" doesn't match its alignment",
+                    offset,
+                    pattern
+                );
+                Some(offset as usize)
+            }
+            None => None,
+        };
+        layout.files.push(FileLayout {
+            pattern: pattern.clone(),
+            alignment,
+            offset,
+        });
+    }
+
+    Ok(layout)
+}
+
+/// The code of a character of a font, which is either given directly, like
+/// `0x00E9`, or encoded through the text table or the font's own encoding.
+fn font_code(
+    character: &str,
+    encoding: font::Encoding,
+    table: Option<&Table>,
+) -> Result<u16, Error> {
+    if character.starts_with("0x") && character.len() > 2 {
+        return Ok(u16::from_str_radix(&character[2..], 16)
+            .with_context(|_| format!("\"{}\" is not a valid character code", character))?);
+    }
+    let bytes = match table {
+        Some(table) => table.encode(character)?,
+        None => encoding.encode(character)?,
+    };
+    ensure!(
+        !bytes.is_empty() && bytes.len() <= 2,
+        "\"{}\" is not a single character",
+        character
+    );
+    Ok(bytes.iter().fold(0, |code, &byte| code << 8 | u16::from(byte)))
+}
+
+/// Parses a message ID, which may be given in decimal or in hex.
+fn parse_message_id(id: &str) -> Result<u32, Error> {
+    let id = id.trim();
+    let parsed = if id.starts_with("0x") {
+        u32::from_str_radix(&id[2..], 16)
+    } else {
+        id.parse()
+    };
+    Ok(parsed.with_context(|_| format!("\"{}\" is not a valid message ID", id))?)
+}
+
+/// Parses an address like `0x8000_1800`.
+fn parse_address(address: &str) -> Result<u32, Error> {
+    let address: syn::LitInt = syn::parse_str(address.trim())?;
+    Ok(address.value() as u32)
+}
+
+fn find_compiled_library(target_dir: &Path, debug: bool) -> Result<PathBuf, Error> {
+    let profile = if debug { "debug" } else { "release" };
+    let dir = fs::read_dir(target_dir.join("powerpc-unknown-linux-gnu").join(profile))
+        .context("Couldn't list entries of the compiler's target directory")?;
+
+    for entry in dir {
+        let entry = entry.context("Couldn't list an entry of the compiler's target directory")?;
+        let path = entry.path();
+        if path.extension() == Some("a".as_ref()) {
+            return Ok(path);
+        }
+    }
+
+    bail!("None of the files in the compiler's target directory match *.a")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iso::consts::*;
+    use iso::header::{GAMECUBE_MAGIC, WII_MAGIC};
+    use iso::wii::DataPartition;
+    use std::io::Cursor;
+    use std::process;
+
+    const FILE_DATA: &[u8] = b"file";
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("romhack-{}-{}", process::id(), name))
+    }
+
+    /// A DOL with a single text section filled with the byte.
+    fn dol(fill: u8) -> Vec<u8> {
+        let mut dol = vec![fill; dol::HEADER_LEN + 0x20];
+        for byte in &mut dol[..dol::HEADER_LEN] {
+            *byte = 0;
+        }
+        BE::write_u32(&mut dol, dol::HEADER_LEN as u32);
+        BE::write_u32(&mut dol[0x48..], 0x8000_3100);
+        BE::write_u32(&mut dol[0x90..], 0x20);
+        BE::write_u32(&mut dol[0xE0..], 0x8000_3100);
+        dol
+    }
+
+    /// The contents of a GameCube disc or of the data partition of a Wii
+    /// disc, whose offsets are shifted, with the DOL and a single file.
+    fn contents(dol: &[u8], is_wii: bool) -> Vec<u8> {
+        let shift = if is_wii { 2 } else { 0 };
+        let apploader_len = 0x40;
+        let dol_offset = HEADER_LENGTH + apploader_len;
+        let fst_offset = dol_offset + dol.len();
+        let fst_len = 0x20;
+        let file_offset = fst_offset + fst_len;
+
+        let mut data = vec![0; file_offset];
+        data[..6].copy_from_slice(b"RTST01");
+        if is_wii {
+            BE::write_u32(&mut data[0x18..], WII_MAGIC);
+        } else {
+            BE::write_u32(&mut data[0x1C..], GAMECUBE_MAGIC);
+        }
+        BE::write_u32(&mut data[OFFSET_DOL_OFFSET..], (dol_offset >> shift) as u32);
+        BE::write_u32(&mut data[OFFSET_FST_OFFSET..], (fst_offset >> shift) as u32);
+        BE::write_u32(&mut data[OFFSET_FST_SIZE..], (fst_len >> shift) as u32);
+
+        let apploader = &mut data[HEADER_LENGTH..][..apploader_len];
+        apploader[..10].copy_from_slice(b"2018/01/01");
+        BE::write_u32(&mut apploader[0x10..], 0x8120_0000);
+        BE::write_u32(&mut apploader[0x14..], 0x20);
+        data[dol_offset..][..dol.len()].copy_from_slice(dol);
+
+        // The root directory and the file, followed by their names
+        let fst = &mut data[fst_offset..][..fst_len];
+        fst[0] = 1;
+        BE::write_u32(&mut fst[0x08..], 2);
+        BE::write_u32(&mut fst[0x10..], (file_offset >> shift) as u32);
+        BE::write_u32(&mut fst[0x14..], FILE_DATA.len() as u32);
+        fst[0x19..][..5].copy_from_slice(b"file\0");
+        BE::write_u16(&mut fst[0x0E..], 1);
+
+        data.extend_from_slice(FILE_DATA);
+        data
+    }
+
+    /// A Wii disc with a fakesigned data partition of the contents.
+    fn wii_disc(contents: &[u8]) -> Vec<u8> {
+        let partition_offset = 0x5_0000;
+        let data_offset = 0x2_0000;
+        let mut disc = vec![0; partition_offset + data_offset];
+        disc[..6].copy_from_slice(b"RTST01");
+        BE::write_u32(&mut disc[0x18..], WII_MAGIC);
+        BE::write_u32(&mut disc[0x4_0000..], 1);
+        BE::write_u32(&mut disc[0x4_0004..], 0x4_0020 >> 2);
+        BE::write_u32(&mut disc[0x4_0020..], partition_offset as u32 >> 2);
+
+        {
+            let partition = &mut disc[partition_offset..];
+            partition[0x1DC..][..8].copy_from_slice(b"\0\x01\0\0RTST");
+            BE::write_u32(&mut partition[0x2A4..], 0x208);
+            BE::write_u32(&mut partition[0x2A8..], 0x2C0 >> 2);
+            BE::write_u32(&mut partition[0x2B4..], 0x8000 >> 2);
+            BE::write_u32(&mut partition[0x2B8..], data_offset as u32 >> 2);
+        }
+
+        let mut template = Cursor::new(disc);
+        let partition = DataPartition::find(&mut template).unwrap();
+        let mut writer = partition
+            .writer(&mut template, Cursor::new(Vec::new()), None)
+            .unwrap();
+        writer.write_all(contents).unwrap();
+        writer.finish(&mut template).unwrap().into_inner()
+    }
+
+    fn replace_and_extract_dol(original: &[u8], name: &str) -> (Vec<u8>, Vec<u8>) {
+        let path = |file: &str| temp_path(&format!("{}-{}", name, file));
+        let (original_path, dol_path) = (path("original.iso"), path("new.dol"));
+        let (patched_path, extracted_path) = (path("patched.iso"), path("extracted.dol"));
+        fs::write(&original_path, original).unwrap();
+        fs::write(&dol_path, dol(2)).unwrap();
+
+        replace_dol(
+            &DontPrint,
+            original_path.clone(),
+            dol_path.clone(),
+            patched_path.clone(),
+        ).unwrap();
+        extract_dol(&DontPrint, patched_path.clone(), extracted_path.clone()).unwrap();
+        let patched = fs::read(&patched_path).unwrap();
+        let extracted = fs::read(&extracted_path).unwrap();
+
+        for path in &[original_path, dol_path, patched_path, extracted_path] {
+            fs::remove_file(path).unwrap();
+        }
+        (patched, extracted)
+    }
+
+    #[test]
+    fn replaces_the_dol_of_gamecube_games() {
+        let original = contents(&dol(1), false);
+        let (patched, extracted) = replace_and_extract_dol(&original, "gamecube");
+        assert_eq!(extracted, dol(2));
+
+        let mut reader = Cursor::new(&patched[..]);
+        let system_data = SystemData::read(&mut reader).unwrap();
+        let iso = iso::reader::load_iso(&system_data).unwrap();
+        assert_eq!(read_iso_file(&iso, &mut reader, "file").unwrap(), FILE_DATA);
+    }
+
+    #[test]
+    fn replaces_the_dol_in_the_data_partition_of_wii_games() {
+        let original = wii_disc(&contents(&dol(1), true));
+        let (patched, extracted) = replace_and_extract_dol(&original, "wii");
+        assert_eq!(extracted, dol(2));
+
+        // The partition is still encrypted and its contents are intact
+        let code = &dol(2)[dol::HEADER_LEN..];
+        assert!(!patched.windows(code.len()).any(|w| w == code));
+        let mut disc = Cursor::new(&patched[..]);
+        let partition = DataPartition::find(&mut disc).unwrap();
+        let mut reader = partition.reader(disc);
+        let system_data = SystemData::read(&mut reader).unwrap();
+        let iso = iso::reader::load_iso(&system_data).unwrap();
+        assert_eq!(read_iso_file(&iso, &mut reader, "file").unwrap(), FILE_DATA);
+    }
 }
@@ -1,13 +1,97 @@
 use byteorder::{ByteOrder, BE};
 use dol::{DolFile, Section};
-use failure::Error;
+use failure::{Error, ResultExt};
 use goblin::archive::{Archive, Member};
-use goblin::elf::{section_header, sym, Elf, Reloc};
+use goblin::elf::{header, section_header, sym, Elf, Reloc};
 use key_val_print::KeyValPrint;
 use std::collections::{BTreeMap, HashMap, HashSet};
 
 pub static BASIC_LIB: &[u8] = include_bytes!("../../resources/libbasic.a");
 
+const ELF_MAGIC: &[u8] = b"\x7FELF";
+const ARCHIVE_MAGIC: &[u8] = b"!<arch>\n";
+
+pub fn is_object(buf: &[u8]) -> bool {
+    buf.starts_with(ELF_MAGIC)
+}
+
+/// Wraps a single object file, like the ones produced by devkitPPC or clang,
+/// into an archive with a symbol index, so it can be linked just like the
+/// libraries.
+pub fn archive_from_object(name: &str, object: &[u8]) -> Result<Vec<u8>, Error> {
+    let elf = Elf::parse(object).context("Couldn't parse the object file")?;
+    ensure!(
+        elf.header.e_machine == header::EM_PPC && !elf.little_endian,
+        "\"{}\" is not a big endian PowerPC object file",
+        name
+    );
+
+    let mut symbols = Vec::new();
+    for symbol in elf.syms.iter() {
+        let bind = symbol.st_bind();
+        if (bind == sym::STB_GLOBAL || bind == sym::STB_WEAK) && symbol.st_shndx != 0 {
+            if let Some(Ok(name)) = elf.strtab.get(symbol.st_name) {
+                if !name.is_empty() {
+                    symbols.push(name);
+                }
+            }
+        }
+    }
+
+    // GNU archives store the symbol index as a big endian table of member
+    // offsets followed by the symbol names.
+    let mut symbol_index = Vec::new();
+    let mut buf = [0; 4];
+    BE::write_u32(&mut buf, symbols.len() as u32);
+    symbol_index.extend_from_slice(&buf);
+    let names_len = symbols.iter().map(|s| s.len() + 1).sum::<usize>();
+    let symbol_index_len = 4 + 4 * symbols.len() + names_len;
+    let member_offset = ARCHIVE_MAGIC.len() + 60 + symbol_index_len + symbol_index_len % 2;
+    BE::write_u32(&mut buf, member_offset as u32);
+    for _ in &symbols {
+        symbol_index.extend_from_slice(&buf);
+    }
+    for symbol in &symbols {
+        symbol_index.extend_from_slice(symbol.as_bytes());
+        symbol_index.push(0);
+    }
+
+    // Short member names are terminated by a slash and may not be longer than
+    // 15 characters.
+    let member_name = format!("{}/", name.chars().take(15).collect::<String>());
+
+    let mut archive = ARCHIVE_MAGIC.to_vec();
+    write_archive_member(&mut archive, "/", &symbol_index);
+    write_archive_member(&mut archive, &member_name, object);
+    Ok(archive)
+}
+
+/// The contents of the member of the archive, like one of its object files.
+pub fn archive_member<'a>(archive_buf: &'a [u8], member_name: &str) -> Result<&'a [u8], Error> {
+    let archive = Archive::parse(archive_buf).context("Couldn't parse the archive")?;
+    let member = archive
+        .get(member_name)
+        .ok_or_else(|| format_err!("The archive doesn't contain \"{}\"", member_name))?;
+    Ok(&archive_buf[member.offset as usize..][..member.header.size as usize])
+}
+
+fn write_archive_member(archive: &mut Vec<u8>, name: &str, data: &[u8]) {
+    let header = format!(
+        "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+        name,
+        0,
+        0,
+        0,
+        644,
+        data.len()
+    );
+    archive.extend_from_slice(header.as_bytes());
+    archive.extend_from_slice(data);
+    if data.len() % 2 != 0 {
+        archive.push(b'\n');
+    }
+}
+
 fn symbols_referenced_in_section<F>(section_index: usize, elf: &Elf, mut f: F)
 where
     F: FnMut(usize),
@@ -72,11 +156,21 @@ pub struct Linked<'a> {
     pub dol: DolFile,
     pub symbol_table: BTreeMap<&'a str, u32>,
     pub sections: Vec<LinkedSection<'a>>,
+    pub debug_sections: Vec<DebugSection<'a>>,
+}
+
+/// A DWARF section like `.debug_info` with the contributions of all the linked
+/// objects concatenated and relocated.
+pub struct DebugSection<'a> {
+    pub name: &'a str,
+    pub data: Vec<u8>,
 }
 
 pub struct LinkedSection<'a> {
     pub address: u32,
     pub len: u32,
+    /// The index of the archive in the archives that were linked.
+    pub archive_index: usize,
     pub member_name: &'a str,
     pub section_name: &'a str,
     pub sym_offset: u32,
@@ -304,7 +398,7 @@ fn relocate_and_collect<'a, P: KeyValPrint>(
     archive_bufs: &'a [Vec<u8>],
     parsed_elfs: &BTreeMap<(usize, &'a str), Elf<'a>>,
     prelinked_symbols: &HashMap<String, u32>,
-) -> (Vec<u8>, Vec<u8>) {
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
     let (mut text_section, mut data_section) = (Vec::new(), Vec::new());
 
     for &LocatedSection {
@@ -339,6 +433,11 @@ fn relocate_and_collect<'a, P: KeyValPrint>(
             section_buf = section_slice.to_owned();
 
             for reloc in reloc_table {
+                // R_PPC_NONE
+                if reloc.r_type == 0 {
+                    continue;
+                }
+
                 let instruction = &mut section_buf[reloc.r_offset as usize..][..4];
                 let symbol_index = reloc.r_sym as usize;
                 let symbol = elf.syms.get(symbol_index).unwrap();
@@ -412,19 +511,34 @@ fn relocate_and_collect<'a, P: KeyValPrint>(
                 // The enum can be found here:
                 // https://github.com/vocho/openqnx/blob/master/trunk/lib/elf/public/sys/elf_ppc.h#L50
                 const R_PPC_ADDR32: u32 = 1;
+                const R_PPC_ADDR24: u32 = 2;
+                const R_PPC_ADDR16: u32 = 3;
                 const R_PPC_ADDR16_LO: u32 = 4;
                 const R_PPC_ADDR16_HI: u32 = 5;
                 const R_PPC_ADDR16_HA: u32 = 6;
+                const R_PPC_ADDR14: u32 = 7;
+                const R_PPC_ADDR14_BRTAKEN: u32 = 8;
+                const R_PPC_ADDR14_BRNTAKEN: u32 = 9;
                 const R_PPC_REL24: u32 = 10;
+                const R_PPC_REL14: u32 = 11;
+                const R_PPC_REL14_BRTAKEN: u32 = 12;
+                const R_PPC_REL14_BRNTAKEN: u32 = 13;
                 const R_PPC_PLTREL24: u32 = 18;
+                const R_PPC_LOCAL24PC: u32 = 23;
+                const R_PPC_UADDR32: u32 = 24;
+                const R_PPC_UADDR16: u32 = 25;
                 const R_PPC_REL32: u32 = 26;
+                const R_PPC_EMB_SDA21: u32 = 109;
 
                 let value = match reloc.r_type {
-                    R_PPC_ADDR32 | R_PPC_ADDR16_HA | R_PPC_ADDR16_HI | R_PPC_ADDR16_LO => {
+                    R_PPC_ADDR32 | R_PPC_UADDR32 | R_PPC_ADDR24 | R_PPC_ADDR16
+                    | R_PPC_UADDR16 | R_PPC_ADDR16_HA | R_PPC_ADDR16_HI | R_PPC_ADDR16_LO
+                    | R_PPC_ADDR14 | R_PPC_ADDR14_BRTAKEN | R_PPC_ADDR14_BRNTAKEN => {
                         // R_ABS -> S + A -> Sym.getVA(A)
                         symbol_address.wrapping_add(a)
                     }
-                    R_PPC_REL24 | R_PPC_REL32 => {
+                    R_PPC_REL24 | R_PPC_REL32 | R_PPC_REL14 | R_PPC_REL14_BRTAKEN
+                    | R_PPC_REL14_BRNTAKEN | R_PPC_LOCAL24PC => {
                         // R_PC -> S + A - P -> Sym.getVA(A) - P
                         symbol_address.wrapping_add(a).wrapping_sub(p)
                     }
@@ -433,7 +547,16 @@ fn relocate_and_collect<'a, P: KeyValPrint>(
                         // There is not dynamic linking, lower this as S + A - P
                         symbol_address.wrapping_add(a).wrapping_sub(p)
                     }
-                    t => panic!("Unknown reloc type {}", t),
+                    R_PPC_EMB_SDA21 => bail!(
+                        "\"{}\" uses small data relocations, which are not supported. \
+                         Please compile it with -G0.",
+                        member_name
+                    ),
+                    t => bail!(
+                        "\"{}\" uses the unsupported relocation type {}",
+                        member_name,
+                        t
+                    ),
                 };
 
                 assert_ne!(
@@ -446,13 +569,34 @@ fn relocate_and_collect<'a, P: KeyValPrint>(
                 match reloc.r_type {
                     R_PPC_ADDR16_HA => BE::write_u16(instruction, (value.wrapping_add(0x8000) >> 16) as u16),
                     R_PPC_ADDR16_HI => BE::write_u16(instruction, (value >> 16) as u16),
-                    R_PPC_ADDR16_LO => BE::write_u16(instruction, value as u16),
-                    R_PPC_ADDR32 | R_PPC_REL32 => BE::write_u32(instruction, value),
-                    R_PPC_PLTREL24 | R_PPC_REL24 => {
-                        let val = BE::read_u32(instruction) | (value & 0x3FFFFFC);
+                    R_PPC_ADDR16_LO | R_PPC_ADDR16 | R_PPC_UADDR16 => {
+                        BE::write_u16(instruction, value as u16)
+                    }
+                    R_PPC_ADDR32 | R_PPC_UADDR32 | R_PPC_REL32 => {
+                        BE::write_u32(instruction, value)
+                    }
+                    R_PPC_PLTREL24 | R_PPC_REL24 | R_PPC_LOCAL24PC | R_PPC_ADDR24 => {
+                        ensure!(
+                            fits_signed(value, 26),
+                            "The branch at {:08x} in \"{}\" is out of range",
+                            p,
+                            member_name
+                        );
+                        let val = (BE::read_u32(instruction) & !0x3FFFFFC) | (value & 0x3FFFFFC);
+                        BE::write_u32(instruction, val);
+                    }
+                    R_PPC_REL14 | R_PPC_REL14_BRTAKEN | R_PPC_REL14_BRNTAKEN | R_PPC_ADDR14
+                    | R_PPC_ADDR14_BRTAKEN | R_PPC_ADDR14_BRNTAKEN => {
+                        ensure!(
+                            fits_signed(value, 16),
+                            "The conditional branch at {:08x} in \"{}\" is out of range",
+                            p,
+                            member_name
+                        );
+                        let val = (BE::read_u32(instruction) & !0xFFFC) | (value & 0xFFFC);
                         BE::write_u32(instruction, val);
                     }
-                    t => panic!("Unknown reloc type {}", t),
+                    _ => unreachable!(),
                 }
             }
 
@@ -464,21 +608,124 @@ fn relocate_and_collect<'a, P: KeyValPrint>(
                 text_section.push(0);
             }
             text_section.extend(section_slice);
-        } else {
+        } else if section_kind == SectionKind::DataSection {
             for _ in 0..located_section_padding {
                 data_section.push(0);
             }
-            if section_kind == SectionKind::DataSection {
-                data_section.extend(section_slice);
-            } else {
-                for _ in 0..section.sh_size {
-                    data_section.push(0);
+            data_section.extend(section_slice);
+        }
+        // The bss sections come last and aren't stored, as they are cleared
+        // by a stub before the game starts
+    }
+
+    Ok((text_section, data_section))
+}
+
+fn debug_section_name<'a>(elf: &Elf<'a>, section_index: usize) -> Option<&'a str> {
+    let section = &elf.section_headers[section_index];
+    if section.sh_type != section_header::SHT_PROGBITS {
+        return None;
+    }
+    elf.shdr_strtab
+        .get(section.sh_name as usize)
+        .and_then(|n| n.ok())
+        .filter(|n| n.starts_with(".debug_"))
+}
+
+/// Concatenates the debug sections of the linked objects. References into
+/// the code and data are relocated to their linked addresses and references
+/// between the debug sections are adjusted to the offset at which the
+/// object's contribution ends up. Code that wasn't linked is referred to as
+/// address 0, like other linkers do.
+fn collect_debug_sections<'a>(
+    layout: &Layout<'a>,
+    archives: &[Option<Archive<'a>>],
+    archive_bufs: &'a [Vec<u8>],
+    parsed_elfs: &BTreeMap<(usize, &'a str), Elf<'a>>,
+) -> Result<Vec<DebugSection<'a>>, Error> {
+    let mut debug_sections: Vec<DebugSection<'a>> = Vec::new();
+
+    for (&(archive_index, member_name), elf) in parsed_elfs {
+        let archive = archives[archive_index].as_ref().unwrap();
+        let member = archive.get(member_name).unwrap();
+        let elf_buf = &archive_bufs[archive_index][member.offset as usize..]
+            [..member.header.size as usize];
+
+        // The output section and the offset of the object's contribution to
+        // it for each of the object's debug sections
+        let mut contributions = HashMap::new();
+        for section_index in 0..elf.section_headers.len() {
+            let name = match debug_section_name(elf, section_index) {
+                Some(name) => name,
+                None => continue,
+            };
+            let output_index = match debug_sections.iter().position(|s| s.name == name) {
+                Some(index) => index,
+                None => {
+                    debug_sections.push(DebugSection {
+                        name,
+                        data: Vec::new(),
+                    });
+                    debug_sections.len() - 1
+                }
+            };
+            let section = &elf.section_headers[section_index];
+            let data = &mut debug_sections[output_index].data;
+            contributions.insert(section_index, (output_index, data.len()));
+            data.extend_from_slice(
+                &elf_buf[section.sh_offset as usize..][..section.sh_size as usize],
+            );
+        }
+
+        for (&section_index, &(output_index, offset)) in &contributions {
+            let reloc_table = match reloc_table_for_section(section_index, elf) {
+                Some(reloc_table) => reloc_table,
+                None => continue,
+            };
+            for reloc in reloc_table {
+                // R_PPC_NONE
+                if reloc.r_type == 0 {
+                    continue;
                 }
+                // R_PPC_ADDR32 and R_PPC_UADDR32
+                ensure!(
+                    reloc.r_type == 1 || reloc.r_type == 24,
+                    "\"{}\" uses the unsupported relocation type {} in its debug info",
+                    member_name,
+                    reloc.r_type
+                );
+
+                let symbol = elf.syms.get(reloc.r_sym as usize).unwrap();
+                let symbol_section_index = symbol.st_shndx as usize;
+                let base = match contributions.get(&symbol_section_index) {
+                    Some(&(_, offset)) => offset as u32,
+                    None => layout
+                        .lookup
+                        .get(&LookupKey {
+                            archive_index,
+                            member_name,
+                            section_index: symbol_section_index,
+                        }).map_or(0, |&index| layout.sections[index].address),
+                };
+                let value = base
+                    .wrapping_add(symbol.st_value as u32)
+                    .wrapping_add(reloc.r_addend.unwrap_or(0) as u32);
+
+                let data = &mut debug_sections[output_index].data;
+                BE::write_u32(&mut data[offset + reloc.r_offset as usize..][..4], value);
             }
         }
     }
 
-    (text_section, data_section)
+    Ok(debug_sections)
+}
+
+/// Checks whether the value fits into a signed integer with the amount of
+/// bits.
+fn fits_signed(value: u32, bits: u32) -> bool {
+    let value = value as i32;
+    let max = 1 << (bits - 1);
+    value >= -max && value < max
 }
 
 pub fn link<'a, P: KeyValPrint>(
@@ -487,6 +734,8 @@ pub fn link<'a, P: KeyValPrint>(
     base_address: u32,
     mut global_symbols_to_visit: Vec<String>,
     prelinked_symbols: &HashMap<String, u32>,
+    with_debug_info: bool,
+    strip_unallocated: bool,
 ) -> Result<Linked<'a>, Error> {
     // TODO Handle "weak" and "merge" symbols
 
@@ -507,6 +756,15 @@ pub fn link<'a, P: KeyValPrint>(
         prelinked_symbols,
     )?;
 
+    // Sections that aren't allocated, like debug info and comments that
+    // symbols happen to be defined in, are never loaded by the game
+    if strip_unallocated {
+        visited_sections.retain(|s| {
+            let elf = &parsed_elfs[&(s.archive_index, s.member_name)];
+            elf.section_headers[s.section_index].is_alloc()
+        });
+    }
+
     let layout = create_layout(base_address, visited_sections, &parsed_elfs);
 
     let (text_section, data_section) = relocate_and_collect(
@@ -516,7 +774,23 @@ pub fn link<'a, P: KeyValPrint>(
         &archive_bufs,
         &parsed_elfs,
         prelinked_symbols,
-    );
+    )?;
+
+    let debug_sections = if with_debug_info {
+        collect_debug_sections(&layout, &archives, archive_bufs, &parsed_elfs)?
+    } else {
+        Vec::new()
+    };
+
+    let bss = layout
+        .sections
+        .iter()
+        .filter(|s| s.section_info.kind == SectionKind::BlockStartedBySymbol)
+        .map(|s| (s.address, s.address + s.len))
+        .fold(None, |bss, (start, end)| match bss {
+            Some((bss_start, _)) => Some((bss_start, end)),
+            None => Some((start, end)),
+        });
 
     let dol = DolFile {
         text_sections: vec![Section {
@@ -527,14 +801,17 @@ pub fn link<'a, P: KeyValPrint>(
             address: layout.data_section_address.unwrap_or(base_address),
             data: data_section.into_boxed_slice(),
         }],
-        bss_address: 0,
-        bss_size: 0,
+        bss_address: bss.map_or(0, |(start, _)| start),
+        bss_size: bss.map_or(0, |(start, end)| end - start),
         entry_point: 0,
+        free_regions: Vec::new(),
+        reservations: Vec::new(),
     };
 
     Ok(Linked {
         dol,
         symbol_table: layout.symbol_table,
+        debug_sections,
         sections: layout
             .sections
             .into_iter()
@@ -558,6 +835,7 @@ pub fn link<'a, P: KeyValPrint>(
                 LinkedSection {
                     address: s.address,
                     len: s.len,
+                    archive_index: s.section_info.archive_index,
                     member_name: s.section_info.member_name,
                     section_name: section_name,
                     kind: s.section_info.kind,
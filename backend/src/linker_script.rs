@@ -0,0 +1,76 @@
+//! Writes a GNU ld linker script for toolchains like devkitPPC, so objects
+//! that are linked outside of the compiler are laid out where the Rom Hack is
+//! placed and can call into the game. The memory that was left free after
+//! placing the generated code is described as well.
+
+use failure::{Error, ResultExt};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+
+const PRELUDE: &str = "\
+/* The memory layout of the Rom Hack and the symbols of the game. This file is
+   created by every build, so any changes are overwritten. */
+
+";
+
+const SECTIONS: &str = "
+SECTIONS
+{
+    .text : { *(.text .text.*) } > romhack
+    .rodata : { *(.rodata .rodata.*) } > romhack
+    .data : { *(.data .data.*) *(.sdata .sdata.*) *(.sdata2 .sdata2.*) } > romhack
+    .bss : { *(.bss .bss.*) *(.sbss .sbss.*) *(COMMON) } > romhack
+}
+
+";
+
+/// The Rom Hack may grow up to the end of the free memory that follows it.
+pub fn create(
+    path: &Path,
+    base_address: u32,
+    rom_hack_end: u32,
+    free_regions: &[Range<u32>],
+    symbols: &HashMap<String, u32>,
+) -> Result<(), Error> {
+    let mut file = BufWriter::new(File::create(path).context("Couldn't create the linker script")?);
+
+    file.write_all(PRELUDE.as_bytes())?;
+    writeln!(file, "MEMORY\n{{")?;
+    writeln!(
+        file,
+        "    romhack (rwx) : ORIGIN = 0x{:08X}, LENGTH = 0x{:X}",
+        base_address,
+        rom_hack_end.saturating_sub(base_address)
+    )?;
+    for (index, region) in free_regions.iter().filter(|r| r.start < r.end).enumerate() {
+        writeln!(
+            file,
+            "    free{} (rwx) : ORIGIN = 0x{:08X}, LENGTH = 0x{:X}",
+            index,
+            region.start,
+            region.end - region.start
+        )?;
+    }
+    writeln!(file, "}}")?;
+    file.write_all(SECTIONS.as_bytes())?;
+
+    let mut symbols = symbols.iter().collect::<Vec<_>>();
+    symbols.sort();
+    for (name, address) in symbols {
+        let is_plain = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$');
+        if is_plain {
+            writeln!(file, "{} = 0x{:08X};", name, address)?;
+        } else if !name.contains('"') {
+            writeln!(file, "\"{}\" = 0x{:08X};", name, address)?;
+        }
+    }
+
+    file.flush()?;
+
+    Ok(())
+}
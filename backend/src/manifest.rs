@@ -0,0 +1,110 @@
+//! Writes a machine readable manifest of everything a build changes in the
+//! original game as JSON. It lists every word the patches write along with
+//! the word it replaces and the line of the patch it originates from, every
+//! section that is added to the DOL and every file that is added, replaced or
+//! removed.
+
+use assembler::Instruction;
+use failure::{Error, ResultExt};
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// The path of the main DOL on the disc.
+pub const MAIN_DOL: &str = "&&systemdata/Start.dol";
+
+#[derive(Serialize, Default)]
+pub struct Manifest {
+    pub words: Vec<PatchedWord>,
+    pub sections: Vec<AddedSection>,
+    pub files: Vec<ChangedFile>,
+}
+
+#[derive(Serialize)]
+pub struct PatchedWord {
+    pub file: String,
+    pub patch: String,
+    pub address: u32,
+    pub old: Option<String>,
+    pub new: String,
+    pub source: Option<String>,
+    /// The amount of bytes of the word the patch writes, as partial words
+    /// only write the bytes of their masks.
+    #[serde(skip)]
+    pub len: u32,
+}
+
+#[derive(Serialize)]
+pub struct AddedSection {
+    pub kind: &'static str,
+    pub address: u32,
+    pub len: u32,
+}
+
+#[derive(Serialize)]
+pub struct ChangedFile {
+    pub path: String,
+    pub change: Change,
+}
+
+#[derive(Serialize, Copy, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Added,
+    Replaced,
+    Removed,
+}
+
+impl Manifest {
+    /// Records the words the patch writes to the file. The original words are
+    /// read before the patch is applied. Patches that aren't assembled from
+    /// lines, like the Gecko codes, have no sources.
+    pub fn add_words<F>(
+        &mut self,
+        file: &str,
+        patch: &str,
+        instructions: &[Instruction],
+        sources: Option<&BTreeMap<u32, String>>,
+        read_original: F,
+    ) where
+        F: Fn(u32) -> Option<u32>,
+    {
+        for instruction in instructions {
+            let old = read_original(instruction.address);
+            self.words.push(PatchedWord {
+                file: file.to_string(),
+                patch: patch.to_string(),
+                address: instruction.address,
+                old: old.map(|w| format!("{:08X}", w)),
+                new: format!(
+                    "{:08X}",
+                    old.map_or(instruction.data, |w| instruction.apply(w))
+                ),
+                source: sources.and_then(|s| s.get(&instruction.address)).cloned(),
+                len: (0..4)
+                    .filter(|i| instruction.mask >> (8 * i) & 0xFF != 0)
+                    .count() as u32,
+            });
+        }
+    }
+
+    pub fn add_section(&mut self, kind: &'static str, address: u32, len: u32) {
+        self.sections.push(AddedSection { kind, address, len });
+    }
+
+    pub fn add_file(&mut self, path: &str, change: Change) {
+        self.files.push(ChangedFile {
+            path: path.to_string(),
+            change,
+        });
+    }
+
+    pub fn create(&self, path: &Path) -> Result<(), Error> {
+        let mut file = BufWriter::new(File::create(path).context("Couldn't create the manifest")?);
+        serde_json::to_writer_pretty(&mut file, self).context("Couldn't write the manifest")?;
+        file.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,14 @@
+//! Lets Wii Rom Hacks place code into the console's second memory, as the
+//! main memory of late games hardly has any space left. The game's heaps in
+//! MEM2 are shrunk like the arena in MEM1, which frees up the memory above
+//! them. Branches from MEM1 can't reach that far, so the code there is only
+//! called through veneers or pointers.
+//!
+//! Like for the rest of the Rom Hack's code, the caches are synced for the
+//! code in MEM2 before the game starts, so no stale instructions from before
+//! the DOL was loaded are executed there.
+
+/// The start of the Wii's second memory, as seen through the cached mirror.
+pub const START: u32 = 0x9000_0000;
+/// The end of the part of the second memory that isn't reserved for IOS.
+pub const END: u32 = 0x933E_0000;
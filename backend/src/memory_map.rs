@@ -0,0 +1,131 @@
+use arena::{self, Bound, Clamp};
+use dol::{self, DolFile, MEM1_END};
+use failure::Error;
+use gecko;
+use mem2;
+use std::fmt::Write;
+
+/// The exception vectors and the globals of the OS come before any DOL.
+const OS_GLOBALS_END: u32 = 0x8000_3100;
+/// The apploader runs from here while it loads the DOL, so the DOL may not
+/// overwrite it.
+const APPLOADER: (u32, u32) = (0x8120_0000, 0x8130_0000);
+
+/// The largest DOL the apploader can load, as all of its sections need to fit
+/// into the main memory after the OS globals, besides the apploader itself.
+const MAX_DOL_LEN: u32 =
+    dol::HEADER_LEN as u32 + (MEM1_END - OS_GLOBALS_END) - (APPLOADER.1 - APPLOADER.0);
+
+/// Makes sure that the DOL's sections and its bss land in the console's main
+/// memory, after the OS globals and outside of the apploader, the FST and the
+/// shrunk arena. Wii games may use the second memory above their heaps as
+/// well. The part of the low memory that's left to the code handler may only
+/// be used if a code handler is embedded, as the exception vectors are
+/// overwritten otherwise. The DOL itself needs to fit into the memory the apploader loads it
+/// into. Every violation is reported at once, along with a map of all the
+/// sections.
+pub fn check(
+    dol: &DolFile,
+    is_wii: bool,
+    has_code_handler: bool,
+    arena_clamps: &[Clamp],
+    fst_address: u32,
+) -> Result<(), Error> {
+    let mut sections = dol
+        .text_sections
+        .iter()
+        .map(|s| ("text", s.address, s.end_address()))
+        .chain(
+            dol.data_sections
+                .iter()
+                .map(|s| ("data", s.address, s.end_address())),
+        ).filter(|&(_, start, end)| start != end)
+        .collect::<Vec<_>>();
+    if dol.bss_size != 0 {
+        let bss_end = dol.bss_address.wrapping_add(dol.bss_size);
+        sections.push(("bss", dol.bss_address, bss_end));
+    }
+    sections.sort_by_key(|&(_, start, _)| start);
+
+    // Without a lower bound, the arena starts right after the DOL, so only the
+    // memory the arena is shrunk by is known to be left alone
+    let mut reserved = vec![("FST", fst_address, arena::FST_END)];
+    if let Some(lo) = arena::bound(arena_clamps, Bound::Lo) {
+        let hi = arena::bound(arena_clamps, Bound::Hi).unwrap_or(fst_address);
+        reserved.push(("arena", lo, hi));
+    }
+    if let Some(hi) = arena::bound(arena_clamps, Bound::Mem2Hi) {
+        reserved.push(("heaps in MEM2", mem2::START, hi));
+    }
+
+    let mut violations = Vec::new();
+    for &(kind, start, end) in &sections {
+        let in_mem1 = start >= OS_GLOBALS_END && end <= MEM1_END;
+        let in_handler =
+            has_code_handler && start >= gecko::HANDLER_ADDRESS && end <= gecko::HANDLER_END;
+        let in_mem2 = is_wii && start >= mem2::START && end <= mem2::END;
+        if end < start || !(in_mem1 || in_mem2 || in_handler) {
+            violations.push(format!(
+                "The {} section {:08X}..{:08X} is outside of the usable memory",
+                kind, start, end
+            ));
+        } else if kind != "bss" && start < APPLOADER.1 && APPLOADER.0 < end {
+            violations.push(format!(
+                "The {} section {:08X}..{:08X} overwrites the apploader at {:08X}..{:08X}",
+                kind, start, end, APPLOADER.0, APPLOADER.1
+            ));
+        } else if let Some(&(name, reserved_start, reserved_end)) =
+            reserved.iter().find(|&&(_, reserved_start, reserved_end)| {
+                start < reserved_end && reserved_start < end
+            })
+        {
+            violations.push(format!(
+                "The {} section {:08X}..{:08X} is overwritten by the {} at {:08X}..{:08X}",
+                kind, start, end, name, reserved_start, reserved_end
+            ));
+        }
+    }
+
+    let dol_len = dol::HEADER_LEN as u32
+        + dol
+            .text_sections
+            .iter()
+            .chain(&dol.data_sections)
+            .map(|s| s.data.len() as u32)
+            .sum::<u32>();
+    if dol_len > MAX_DOL_LEN {
+        violations.push(format!(
+            "The DOL takes up {:#x} bytes, but at most {:#x} bytes can be loaded",
+            dol_len, MAX_DOL_LEN
+        ));
+    }
+
+    if !is_entry_point_valid(dol) {
+        violations.push(format!(
+            "The entry point {:08X} is not inside of a text section",
+            dol.entry_point
+        ));
+    }
+
+    if !violations.is_empty() {
+        let mut report = String::new();
+        for &(kind, start, end) in &sections {
+            writeln!(report, "  {:08X}..{:08X} {}", start, end, kind)?;
+        }
+        bail!(
+            "Found {} memory map violation{}:\n{}\nThe memory map of the DOL is:\n{}",
+            violations.len(),
+            if violations.len() == 1 { "" } else { "s" },
+            violations.join("\n"),
+            report.trim_right()
+        );
+    }
+
+    Ok(())
+}
+
+fn is_entry_point_valid(dol: &DolFile) -> bool {
+    dol.text_sections
+        .iter()
+        .any(|s| s.address <= dol.entry_point && dol.entry_point < s.end_address())
+}
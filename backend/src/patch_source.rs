@@ -0,0 +1,266 @@
+//! The kinds of files that patches are read from, besides the patch files and
+//! the Rom Hack's own code. Each kind turns a file into the words it writes to
+//! the DOL and the Gecko codes it applies, which are then applied along with
+//! the patch files. Other kinds only need to implement `PatchSource` and are
+//! passed to `build_iso_with_sources` along with the ones in `BUILT_IN`.
+//!
+//! The patch files and the Rom Hack's code are deliberately not sources. The
+//! patch files share their labels with the Rom Hack's code, place inject
+//! blocks and veneers into its free memory and patch the other files of the
+//! disc, so they are built along with it rather than turned into words on
+//! their own. Sources only get to see the game's symbols.
+
+use ar;
+use assembler::{bytes_to_instructions, Assembler, Instruction, Location};
+use config::Source;
+use failure::{err_msg, Error, ResultExt};
+use gecko;
+use goblin::elf::{program_header, Elf as ElfFile};
+use parse_address;
+use serde_json;
+use std::collections::HashMap;
+use std::str;
+
+#[derive(Default)]
+pub struct Patches {
+    /// The words written to the DOL.
+    pub instructions: Vec<Instruction>,
+    pub gecko_codes: Vec<gecko::Code>,
+}
+
+pub trait PatchSource {
+    /// The kind of the sources in the config, like `gecko`.
+    fn kind(&self) -> &'static str;
+    /// What the patches are called when they are parsed, like "Gecko codes".
+    fn name(&self) -> &'static str;
+    /// Reads the patches from the contents of the source's file. They may
+    /// refer to the game's symbols.
+    fn parse(
+        &self,
+        data: &[u8],
+        source: &Source,
+        symbols: &HashMap<String, u32>,
+    ) -> Result<Patches, Error>;
+}
+
+/// Assembly files that only write to the DOL. Unlike the patch files, they
+/// can only refer to the game's symbols.
+pub struct Asm;
+/// Executables that are linked to their final addresses, like the ones built
+/// with devkitPPC. Their loadable segments are written to the DOL.
+pub struct Elf;
+/// Raw bytes that are written to the source's address.
+pub struct Binary;
+/// Gecko codes, either as text or as a GCT file.
+pub struct Gecko;
+/// Decrypted Action Replay codes.
+pub struct ActionReplay;
+/// JSON lists of writes, like `[{ "address": "0x80001234", "data": "60000000" }]`.
+/// The addresses may also be symbols of the game.
+pub struct WriteList;
+
+pub const BUILT_IN: [&dyn PatchSource; 6] =
+    [&Asm, &Elf, &Binary, &Gecko, &ActionReplay, &WriteList];
+
+/// Finds the kind of source with the name the config refers to it by.
+pub fn find<'a>(kinds: &[&'a dyn PatchSource], kind: &str) -> Option<&'a dyn PatchSource> {
+    kinds.iter().cloned().find(|k| k.kind() == kind)
+}
+
+/// Resolves an address that is either a symbol of the game or a literal.
+fn resolve_address(address: &str, symbols: &HashMap<String, u32>) -> Result<u32, Error> {
+    if let Some(&address) = symbols.get(address) {
+        return Ok(address);
+    }
+    Ok(parse_address(address).with_context(|_| format!("Invalid address \"{}\"", address))?)
+}
+
+impl PatchSource for Asm {
+    fn kind(&self) -> &'static str {
+        "asm"
+    }
+
+    fn name(&self) -> &'static str {
+        "assembly"
+    }
+
+    fn parse(
+        &self,
+        data: &[u8],
+        source: &Source,
+        symbols: &HashMap<String, u32>,
+    ) -> Result<Patches, Error> {
+        let text = str::from_utf8(data).context("The assembly is not valid UTF-8")?;
+        let lines = text.lines().collect::<Vec<_>>();
+        let locations = (1..=lines.len())
+            .map(|line| Location {
+                path: source.path.clone(),
+                line,
+            }).collect();
+        let mut assembler = Assembler::new(Default::default(), symbols);
+        let instructions = assembler.assemble_lines_from(&lines, locations)?;
+        ensure!(
+            assembler.far_branches().is_empty()
+                && assembler.injections().is_empty()
+                && assembler.replacements().is_empty()
+                && assembler.strings().is_empty()
+                && assembler.file_instructions().is_empty(),
+            "Only the patch files can branch far, inject, replace functions or strings \
+             and patch other files"
+        );
+        Ok(Patches {
+            instructions,
+            ..Default::default()
+        })
+    }
+}
+
+impl PatchSource for Elf {
+    fn kind(&self) -> &'static str {
+        "elf"
+    }
+
+    fn name(&self) -> &'static str {
+        "ELF"
+    }
+
+    fn parse(&self, data: &[u8], _: &Source, _: &HashMap<String, u32>) -> Result<Patches, Error> {
+        let elf = ElfFile::parse(data).context("Couldn't parse the ELF")?;
+        let mut instructions = Vec::new();
+        for segment in &elf.program_headers {
+            if segment.p_type != program_header::PT_LOAD || segment.p_filesz == 0 {
+                continue;
+            }
+            let start = segment.p_offset as usize;
+            let bytes = data
+                .get(start..start + segment.p_filesz as usize)
+                .ok_or_else(|| err_msg("A segment of the ELF is truncated"))?;
+            instructions.extend(bytes_to_instructions(segment.p_vaddr as u32, bytes));
+        }
+        Ok(Patches {
+            instructions,
+            ..Default::default()
+        })
+    }
+}
+
+impl PatchSource for Binary {
+    fn kind(&self) -> &'static str {
+        "binary"
+    }
+
+    fn name(&self) -> &'static str {
+        "binary"
+    }
+
+    fn parse(
+        &self,
+        data: &[u8],
+        source: &Source,
+        symbols: &HashMap<String, u32>,
+    ) -> Result<Patches, Error> {
+        let address = source
+            .address
+            .as_ref()
+            .ok_or_else(|| err_msg("The binary needs an address to be written to"))?;
+        let address = resolve_address(address, symbols)?;
+        Ok(Patches {
+            instructions: bytes_to_instructions(address, data),
+            ..Default::default()
+        })
+    }
+}
+
+impl PatchSource for Gecko {
+    fn kind(&self) -> &'static str {
+        "gecko"
+    }
+
+    fn name(&self) -> &'static str {
+        "Gecko codes"
+    }
+
+    fn parse(
+        &self,
+        data: &[u8],
+        source: &Source,
+        _: &HashMap<String, u32>,
+    ) -> Result<Patches, Error> {
+        let gecko_codes = if source.path.extension() == Some("gct".as_ref()) {
+            gecko::parse_gct(data)?
+        } else {
+            let text = str::from_utf8(data).context("The Gecko codes are not valid UTF-8")?;
+            gecko::parse_text(text)?
+        };
+        Ok(Patches {
+            gecko_codes,
+            ..Default::default()
+        })
+    }
+}
+
+impl PatchSource for ActionReplay {
+    fn kind(&self) -> &'static str {
+        "action-replay"
+    }
+
+    fn name(&self) -> &'static str {
+        "Action Replay codes"
+    }
+
+    fn parse(&self, data: &[u8], _: &Source, _: &HashMap<String, u32>) -> Result<Patches, Error> {
+        let text = str::from_utf8(data).context("The Action Replay codes are not valid UTF-8")?;
+        Ok(Patches {
+            gecko_codes: ar::parse_codes(text)?,
+            ..Default::default()
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Write {
+    address: String,
+    data: String,
+}
+
+impl PatchSource for WriteList {
+    fn kind(&self) -> &'static str {
+        "writes"
+    }
+
+    fn name(&self) -> &'static str {
+        "writes"
+    }
+
+    fn parse(
+        &self,
+        data: &[u8],
+        _: &Source,
+        symbols: &HashMap<String, u32>,
+    ) -> Result<Patches, Error> {
+        let writes: Vec<Write> =
+            serde_json::from_slice(data).context("Couldn't parse the list of writes")?;
+        let mut instructions = Vec::new();
+        for write in writes {
+            let address = resolve_address(&write.address, symbols)?;
+            let digits = write
+                .data
+                .replace(|c: char| c.is_whitespace() || c == '_', "");
+            ensure!(
+                digits.is_ascii() && digits.len() % 2 == 0,
+                "The data written to \"{}\" doesn't consist of whole bytes",
+                write.address
+            );
+            let bytes = (0..digits.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|_| format!("Invalid data written to \"{}\"", write.address))?;
+            instructions.extend(bytes_to_instructions(address, &bytes));
+        }
+        Ok(Patches {
+            instructions,
+            ..Default::default()
+        })
+    }
+}
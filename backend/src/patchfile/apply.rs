@@ -0,0 +1,346 @@
+//! Applies IPS, BPS and UPS patches, so a Rom Hack can be built on top of
+//! another patch, like a translation.
+
+use super::{Crc32, BPS_SOURCE_READ, BPS_TARGET_READ};
+use byteorder::{ByteOrder, BE, LE};
+use failure::{err_msg, Error};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+
+const BPS_SOURCE_COPY: u64 = 2;
+
+const COPY_BUF_LEN: usize = 1 << 16;
+
+/// The size of the file the patch needs to be applied to. IPS patches don't
+/// store it, so `None` is returned for them.
+pub fn source_len(patch: &[u8]) -> Result<Option<u64>, Error> {
+    if patch.starts_with(b"PATCH") {
+        Ok(None)
+    } else if patch.starts_with(b"BPS1") || patch.starts_with(b"UPS1") {
+        let mut reader = PatchReader::new(patch, 4);
+        Ok(Some(reader.varint()?))
+    } else {
+        bail!("The patch is neither an IPS, BPS nor UPS patch")
+    }
+}
+
+/// Applies the patch to data that is already in memory.
+pub fn apply_to_vec(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, Error> {
+    if patch.starts_with(b"PATCH") {
+        return apply_ips(patch, source);
+    }
+    let mut target = Cursor::new(Vec::new());
+    apply(patch, &mut Cursor::new(source), &mut target)?;
+    Ok(target.into_inner())
+}
+
+/// Applies a BPS or UPS patch, streaming the source and the target. The
+/// target needs to be readable, as BPS patches may copy from the data that
+/// was already written.
+pub fn apply<R, W>(patch: &[u8], source: &mut R, target: &mut W) -> Result<(), Error>
+where
+    R: Read + Seek,
+    W: Read + Write + Seek,
+{
+    ensure!(
+        patch.len() >= 16,
+        "The patch is too small to contain its checksums"
+    );
+    let footer = &patch[patch.len() - 12..];
+    let mut crc = Crc32::new();
+    crc.update(&patch[..patch.len() - 4]);
+    ensure!(
+        crc.finish() == LE::read_u32(&footer[8..]),
+        "The patch is corrupted"
+    );
+
+    let source_len = source.seek(SeekFrom::End(0))?;
+    let expected_len = source_len_of(patch)?;
+    ensure!(
+        source_len == expected_len,
+        "The patch expects a file of {} bytes, but it's {} bytes",
+        expected_len,
+        source_len
+    );
+    source.seek(SeekFrom::Start(0))?;
+    let source_crc = crc_of(source)?;
+    ensure!(
+        source_crc == LE::read_u32(footer),
+        "The patch is meant for a different file"
+    );
+    source.seek(SeekFrom::Start(0))?;
+    target.seek(SeekFrom::Start(0))?;
+
+    if patch.starts_with(b"BPS1") {
+        apply_bps(&patch[..patch.len() - 12], source, target)?;
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(&patch[..patch.len() - 12], source, target)?;
+    } else {
+        bail!("Only BPS and UPS patches can be applied to a whole disc");
+    }
+
+    target.seek(SeekFrom::Start(0))?;
+    ensure!(
+        crc_of(target)? == LE::read_u32(&footer[4..]),
+        "The patched file doesn't match the patch's checksum"
+    );
+
+    Ok(())
+}
+
+fn source_len_of(patch: &[u8]) -> Result<u64, Error> {
+    source_len(patch)?.ok_or_else(|| err_msg("IPS patches don't store the source's size"))
+}
+
+fn crc_of<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut crc = Crc32::new();
+    let mut buf = vec![0; COPY_BUF_LEN];
+    loop {
+        let len = reader.read(&mut buf)?;
+        if len == 0 {
+            return Ok(crc.finish());
+        }
+        crc.update(&buf[..len]);
+    }
+}
+
+fn apply_ips(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut target = source.to_vec();
+    let mut reader = PatchReader::new(patch, 5);
+
+    loop {
+        let offset = reader.bytes(3)?;
+        if offset == b"EOF" {
+            break;
+        }
+        let offset = BE::read_u24(offset) as usize;
+        let len = BE::read_u16(reader.bytes(2)?) as usize;
+        if len == 0 {
+            // A run of the same byte
+            let len = BE::read_u16(reader.bytes(2)?) as usize;
+            let byte = reader.bytes(1)?[0];
+            if target.len() < offset + len {
+                target.resize(offset + len, 0);
+            }
+            for b in &mut target[offset..][..len] {
+                *b = byte;
+            }
+        } else {
+            let data = reader.bytes(len)?;
+            if target.len() < offset + len {
+                target.resize(offset + len, 0);
+            }
+            target[offset..][..len].copy_from_slice(data);
+        }
+    }
+
+    if let Ok(len) = reader.bytes(3) {
+        target.truncate(BE::read_u24(len) as usize);
+    }
+
+    Ok(target)
+}
+
+fn apply_bps<R, W>(patch: &[u8], source: &mut R, target: &mut W) -> Result<(), Error>
+where
+    R: Read + Seek,
+    W: Read + Write + Seek,
+{
+    let mut reader = PatchReader::new(patch, 4);
+    let _source_len = reader.varint()?;
+    let target_len = reader.varint()?;
+    let metadata_len = reader.varint()?;
+    reader.bytes(metadata_len as usize)?;
+
+    let mut output_offset = 0u64;
+    let mut source_offset = 0i64;
+    let mut target_offset = 0i64;
+    let mut buf = vec![0; COPY_BUF_LEN];
+
+    while !reader.is_empty() {
+        let data = reader.varint()?;
+        let len = (data >> 2) + 1;
+        match data & 3 {
+            BPS_SOURCE_READ => {
+                source.seek(SeekFrom::Start(output_offset))?;
+                copy(source, target, len, &mut buf)?;
+            }
+            BPS_TARGET_READ => {
+                target.write_all(reader.bytes(len as usize)?)?;
+            }
+            BPS_SOURCE_COPY => {
+                source_offset += reader.signed_varint()?;
+                ensure!(source_offset >= 0, "The patch reads before the source");
+                source.seek(SeekFrom::Start(source_offset as u64))?;
+                copy(source, target, len, &mut buf)?;
+                source_offset += len as i64;
+            }
+            _ => {
+                // Target copy
+                target_offset += reader.signed_varint()?;
+                ensure!(
+                    target_offset >= 0 && (target_offset as u64) < output_offset,
+                    "The patch copies data that wasn't written yet"
+                );
+                // The copy may overlap the data it produces, so it's done in
+                // steps of at most the distance between both
+                let distance = output_offset - target_offset as u64;
+                let mut remaining = len;
+                while remaining != 0 {
+                    let step = remaining.min(distance).min(buf.len() as u64) as usize;
+                    target.seek(SeekFrom::Start(target_offset as u64))?;
+                    target.read_exact(&mut buf[..step])?;
+                    target.seek(SeekFrom::Start(target_offset as u64 + distance))?;
+                    target.write_all(&buf[..step])?;
+                    target_offset += step as i64;
+                    remaining -= step as u64;
+                }
+            }
+        }
+        output_offset += len;
+        target.seek(SeekFrom::Start(output_offset))?;
+    }
+
+    ensure!(
+        output_offset == target_len,
+        "The patch produced {} bytes instead of {}",
+        output_offset,
+        target_len
+    );
+
+    Ok(())
+}
+
+fn apply_ups<R: Read, W: Write>(patch: &[u8], source: &mut R, target: &mut W) -> Result<(), Error> {
+    let mut reader = PatchReader::new(patch, 4);
+    let source_len = reader.varint()?;
+    let target_len = reader.varint()?;
+
+    // Bytes past the end of the source are zeros and bytes past the end of
+    // the target are dropped
+    let mut source = source.take(source_len).chain(io::repeat(0));
+    let mut target = target.take_limited(target_len);
+    let mut buf = vec![0; COPY_BUF_LEN];
+    let mut position = 0u64;
+
+    while !reader.is_empty() {
+        let skip = reader.varint()?;
+        copy(&mut source, &mut target, skip, &mut buf)?;
+        position += skip;
+
+        loop {
+            let xor = reader.bytes(1)?[0];
+            let mut byte = [0];
+            source.read_exact(&mut byte)?;
+            target.write_all(&[byte[0] ^ xor])?;
+            position += 1;
+            if xor == 0 {
+                break;
+            }
+        }
+    }
+
+    let remaining = target_len.saturating_sub(position);
+    copy(&mut source, &mut target, remaining, &mut buf)?;
+
+    Ok(())
+}
+
+fn copy<R: Read, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    mut len: u64,
+    buf: &mut [u8],
+) -> io::Result<()> {
+    while len != 0 {
+        let step = len.min(buf.len() as u64) as usize;
+        reader.read_exact(&mut buf[..step])?;
+        writer.write_all(&buf[..step])?;
+        len -= step as u64;
+    }
+    Ok(())
+}
+
+/// Drops everything written past the limit.
+struct LimitedWriter<W> {
+    writer: W,
+    remaining: u64,
+}
+
+trait TakeLimited: Sized {
+    fn take_limited(self, limit: u64) -> LimitedWriter<Self>;
+}
+
+impl<W: Write> TakeLimited for W {
+    fn take_limited(self, limit: u64) -> LimitedWriter<Self> {
+        LimitedWriter {
+            writer: self,
+            remaining: limit,
+        }
+    }
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = (buf.len() as u64).min(self.remaining) as usize;
+        self.writer.write_all(&buf[..len])?;
+        self.remaining -= len as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+struct PatchReader<'a> {
+    patch: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(patch: &'a [u8], position: usize) -> Self {
+        PatchReader { patch, position }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.patch.len()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let bytes = self
+            .patch
+            .get(self.position..)
+            .and_then(|b| b.get(..len))
+            .ok_or_else(|| err_msg("The patch is truncated"))?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn varint(&mut self) -> Result<u64, Error> {
+        let mut value = 0u64;
+        let mut shift = 1u64;
+        loop {
+            let byte = self.bytes(1)?[0];
+            value = value
+                .checked_add((byte as u64 & 0x7F).saturating_mul(shift))
+                .ok_or_else(|| err_msg("The patch contains an invalid number"))?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.saturating_mul(0x80);
+            value = value
+                .checked_add(shift)
+                .ok_or_else(|| err_msg("The patch contains an invalid number"))?;
+        }
+    }
+
+    fn signed_varint(&mut self) -> Result<i64, Error> {
+        let value = self.varint()?;
+        let magnitude = (value >> 1) as i64;
+        Ok(if value & 1 != 0 {
+            -magnitude
+        } else {
+            magnitude
+        })
+    }
+}
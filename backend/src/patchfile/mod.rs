@@ -0,0 +1,388 @@
+//! Creates IPS, BPS, UPS and VCDIFF patches, so Rom Hacks can be distributed
+//! without any of the game's data, and applies IPS, BPS and UPS patches. The
+//! original and the patched file are streamed, so even full discs can be
+//! diffed without loading them into memory.
+
+use byteorder::{WriteBytesExt, BE, LE};
+use failure::{Error, ResultExt};
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+mod apply;
+mod vcdiff;
+
+pub use self::apply::{apply, apply_to_vec, source_len};
+
+const CHUNK_LEN: usize = 1 << 16;
+
+const IPS_MAX_OFFSET: u64 = 0xFF_FFFF;
+const IPS_MAX_RECORD_LEN: usize = 0xFFFF;
+// A record at this offset would be mistaken for the end of the patch
+const IPS_EOF_OFFSET: u64 = 0x45_4F46;
+
+const BPS_SOURCE_READ: u64 = 0;
+const BPS_TARGET_READ: u64 = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    Ips,
+    Bps,
+    Ups,
+    Vcdiff,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match &*s.to_lowercase() {
+            "ips" => Format::Ips,
+            "bps" => Format::Bps,
+            "ups" => Format::Ups,
+            "vcdiff" | "xdelta" => Format::Vcdiff,
+            _ => bail!(
+                "Unknown patch format \"{}\", expected ips, bps, ups or vcdiff",
+                s
+            ),
+        })
+    }
+}
+
+/// Diffs the original against the patched data and writes the patch in the
+/// requested format.
+pub fn create<R1: Read, R2: Read, W: Write>(
+    format: Format,
+    original: R1,
+    original_len: u64,
+    patched: R2,
+    patched_len: u64,
+    writer: W,
+) -> Result<(), Error> {
+    let chunks = Chunks::new(original, patched);
+    match format {
+        Format::Ips => create_ips(chunks, original_len, patched_len, writer),
+        Format::Bps => create_bps(chunks, original_len, patched_len, writer),
+        Format::Ups => create_ups(chunks, original_len, patched_len, writer),
+        Format::Vcdiff => vcdiff::create(chunks.original, chunks.patched, writer),
+    }
+}
+
+/// Reads both files in lockstep. Either chunk is shorter than the other once
+/// its file ends.
+struct Chunks<R1, R2> {
+    original: R1,
+    patched: R2,
+    original_buf: Vec<u8>,
+    patched_buf: Vec<u8>,
+    offset: u64,
+}
+
+impl<R1: Read, R2: Read> Chunks<R1, R2> {
+    fn new(original: R1, patched: R2) -> Self {
+        Self {
+            original,
+            patched,
+            original_buf: vec![0; CHUNK_LEN],
+            patched_buf: vec![0; CHUNK_LEN],
+            offset: 0,
+        }
+    }
+
+    /// Returns the offset of the next chunks and their lengths, or `None` once
+    /// both files are exhausted.
+    fn next(&mut self) -> io::Result<Option<(u64, usize, usize)>> {
+        let original_len = read_full(&mut self.original, &mut self.original_buf)?;
+        let patched_len = read_full(&mut self.patched, &mut self.patched_buf)?;
+        if original_len == 0 && patched_len == 0 {
+            return Ok(None);
+        }
+        let offset = self.offset;
+        self.offset += original_len.max(patched_len) as u64;
+        Ok(Some((offset, original_len, patched_len)))
+    }
+}
+
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+fn create_ips<R1: Read, R2: Read, W: Write>(
+    mut chunks: Chunks<R1, R2>,
+    original_len: u64,
+    patched_len: u64,
+    mut writer: W,
+) -> Result<(), Error> {
+    ensure!(
+        patched_len <= IPS_MAX_OFFSET + 1,
+        "IPS patches can't address files larger than 16 MiB"
+    );
+
+    writer.write_all(b"PATCH")?;
+
+    let mut record_start = 0;
+    let mut record = Vec::with_capacity(IPS_MAX_RECORD_LEN);
+    let mut previous_byte = 0;
+
+    while let Some((offset, original_len, chunk_len)) = chunks.next()? {
+        for index in 0..chunk_len {
+            let byte = chunks.patched_buf[index];
+            let is_changed = index >= original_len || chunks.original_buf[index] != byte;
+            if is_changed {
+                if record.is_empty() {
+                    record_start = offset + index as u64;
+                    if record_start == IPS_EOF_OFFSET {
+                        record_start -= 1;
+                        record.push(previous_byte);
+                    }
+                }
+                record.push(byte);
+                if record.len() == IPS_MAX_RECORD_LEN {
+                    write_ips_record(&mut writer, record_start, &record)?;
+                    record.clear();
+                }
+            } else if !record.is_empty() {
+                write_ips_record(&mut writer, record_start, &record)?;
+                record.clear();
+            }
+            previous_byte = byte;
+        }
+    }
+    if !record.is_empty() {
+        write_ips_record(&mut writer, record_start, &record)?;
+    }
+
+    writer.write_all(b"EOF")?;
+    if patched_len < original_len {
+        // The truncation extension understood by most patchers
+        writer.write_u24::<BE>(patched_len as u32)?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn write_ips_record<W: Write>(writer: &mut W, offset: u64, data: &[u8]) -> io::Result<()> {
+    writer.write_u24::<BE>(offset as u32)?;
+    writer.write_u16::<BE>(data.len() as u16)?;
+    writer.write_all(data)
+}
+
+fn create_ups<R1: Read, R2: Read, W: Write>(
+    mut chunks: Chunks<R1, R2>,
+    original_len: u64,
+    patched_len: u64,
+    writer: W,
+) -> Result<(), Error> {
+    let mut writer = Crc32Writer::new(writer);
+    let mut original_crc = Crc32::new();
+    let mut patched_crc = Crc32::new();
+
+    writer.write_all(b"UPS1")?;
+    write_varint(&mut writer, original_len)?;
+    write_varint(&mut writer, patched_len)?;
+
+    let mut last_end = 0;
+    let mut in_hunk = false;
+
+    while let Some((offset, original_chunk_len, patched_chunk_len)) = chunks.next()? {
+        let original = &chunks.original_buf[..original_chunk_len];
+        let patched = &chunks.patched_buf[..patched_chunk_len];
+        original_crc.update(original);
+        patched_crc.update(patched);
+
+        for index in 0..original_chunk_len.max(patched_chunk_len) {
+            let xor = original.get(index).cloned().unwrap_or(0)
+                ^ patched.get(index).cloned().unwrap_or(0);
+            let position = offset + index as u64;
+            if xor != 0 {
+                if !in_hunk {
+                    write_varint(&mut writer, position - last_end)?;
+                    in_hunk = true;
+                }
+                writer.write_u8(xor)?;
+            } else if in_hunk {
+                writer.write_u8(0)?;
+                in_hunk = false;
+                last_end = position + 1;
+            }
+        }
+    }
+    if in_hunk {
+        writer.write_u8(0)?;
+    }
+
+    writer.write_u32::<LE>(original_crc.finish())?;
+    writer.write_u32::<LE>(patched_crc.finish())?;
+    let patch_crc = writer.crc.finish();
+    writer.write_u32::<LE>(patch_crc)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn create_bps<R1: Read, R2: Read, W: Write>(
+    mut chunks: Chunks<R1, R2>,
+    original_len: u64,
+    patched_len: u64,
+    writer: W,
+) -> Result<(), Error> {
+    let mut writer = Crc32Writer::new(writer);
+    let mut original_crc = Crc32::new();
+    let mut patched_crc = Crc32::new();
+
+    writer.write_all(b"BPS1")?;
+    write_varint(&mut writer, original_len)?;
+    write_varint(&mut writer, patched_len)?;
+    // No metadata
+    write_varint(&mut writer, 0)?;
+
+    // Bytes that match the original at the same offset are read from the
+    // source, everything else is stored in the patch.
+    let mut source_run = 0u64;
+    let mut target_run = Vec::new();
+
+    while let Some((_, original_chunk_len, patched_chunk_len)) = chunks.next()? {
+        let original = &chunks.original_buf[..original_chunk_len];
+        let patched = &chunks.patched_buf[..patched_chunk_len];
+        original_crc.update(original);
+        patched_crc.update(patched);
+
+        for (index, &byte) in patched.iter().enumerate() {
+            if original.get(index) == Some(&byte) {
+                if !target_run.is_empty() {
+                    write_bps_target_read(&mut writer, &target_run)?;
+                    target_run.clear();
+                }
+                source_run += 1;
+            } else {
+                if source_run != 0 {
+                    write_varint(&mut writer, ((source_run - 1) << 2) | BPS_SOURCE_READ)?;
+                    source_run = 0;
+                }
+                target_run.push(byte);
+            }
+        }
+    }
+    if source_run != 0 {
+        write_varint(&mut writer, ((source_run - 1) << 2) | BPS_SOURCE_READ)?;
+    }
+    if !target_run.is_empty() {
+        write_bps_target_read(&mut writer, &target_run)?;
+    }
+
+    writer.write_u32::<LE>(original_crc.finish())?;
+    writer.write_u32::<LE>(patched_crc.finish())?;
+    let patch_crc = writer.crc.finish();
+    writer.write_u32::<LE>(patch_crc)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn write_bps_target_read<W: Write>(writer: &mut W, data: &[u8]) -> io::Result<()> {
+    write_varint(writer, ((data.len() as u64 - 1) << 2) | BPS_TARGET_READ)?;
+    writer.write_all(data)
+}
+
+/// The variable length integers used by both BPS and UPS.
+fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            return writer.write_u8(0x80 | byte);
+        }
+        writer.write_u8(byte)?;
+        value -= 1;
+    }
+}
+
+struct Crc32 {
+    table: [u32; 256],
+    value: u32,
+}
+
+impl Crc32 {
+    fn new() -> Self {
+        let mut table = [0; 256];
+        for (index, entry) in table.iter_mut().enumerate() {
+            let mut value = index as u32;
+            for _ in 0..8 {
+                value = if value & 1 != 0 {
+                    (value >> 1) ^ 0xEDB8_8320
+                } else {
+                    value >> 1
+                };
+            }
+            *entry = value;
+        }
+        Crc32 { table, value: !0 }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.value =
+                self.table[((self.value ^ byte as u32) & 0xFF) as usize] ^ (self.value >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        !self.value
+    }
+}
+
+/// Calculates the checksum of everything written so far, which BPS and UPS
+/// patches end with.
+struct Crc32Writer<W> {
+    writer: W,
+    crc: Crc32,
+}
+
+impl<W: Write> Crc32Writer<W> {
+    fn new(writer: W) -> Self {
+        Crc32Writer {
+            writer,
+            crc: Crc32::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for Crc32Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.crc.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Creates the patch for data that is already in memory.
+pub fn create_from_slices(
+    format: Format,
+    original: &[u8],
+    patched: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let mut patch = Vec::new();
+    create(
+        format,
+        original,
+        original.len() as u64,
+        patched,
+        patched.len() as u64,
+        &mut patch,
+    )
+    .context("Couldn't create the patch")?;
+    Ok(patch)
+}
@@ -0,0 +1,163 @@
+//! Encodes VCDIFF patches as described in RFC 3284, which xdelta3 can apply.
+//! The target is split into windows that are each diffed against the window
+//! of the source at the same offset, so only two windows are ever kept in
+//! memory, no matter how large the disc is.
+
+use super::read_full;
+use failure::Error;
+use std::io::{Read, Write};
+
+const WINDOW_LEN: usize = 4 << 20;
+
+// Shorter matches and runs are cheaper to store as part of an ADD
+const MIN_COPY_LEN: usize = 8;
+const MIN_RUN_LEN: usize = 8;
+
+const VCD_SOURCE: u8 = 0x01;
+
+// Indices into the default code table that take their size from the
+// instruction section
+const CODE_RUN: u8 = 0;
+const CODE_ADD: u8 = 1;
+const CODE_COPY_SELF: u8 = 19;
+
+pub fn create<R1: Read, R2: Read, W: Write>(
+    mut original: R1,
+    mut patched: R2,
+    mut writer: W,
+) -> Result<(), Error> {
+    // The magic, followed by a header indicator without any extensions
+    writer.write_all(&[0xD6, 0xC3, 0xC4, 0x00, 0x00])?;
+
+    let mut source = vec![0; WINDOW_LEN];
+    let mut target = vec![0; WINDOW_LEN];
+    let mut position = 0u64;
+    let mut encoder = WindowEncoder::default();
+
+    loop {
+        let source_len = read_full(&mut original, &mut source)?;
+        let target_len = read_full(&mut patched, &mut target)?;
+        if target_len == 0 {
+            break;
+        }
+
+        encoder.encode(&source[..source_len], &target[..target_len]);
+        encoder.write(&mut writer, position, source_len)?;
+        position += target_len as u64;
+    }
+
+    writer.flush()?;
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct WindowEncoder {
+    target_len: usize,
+    data: Vec<u8>,
+    instructions: Vec<u8>,
+    addresses: Vec<u8>,
+    pending_add: Vec<u8>,
+}
+
+impl WindowEncoder {
+    fn encode(&mut self, source: &[u8], target: &[u8]) {
+        self.target_len = target.len();
+        self.data.clear();
+        self.instructions.clear();
+        self.addresses.clear();
+        self.pending_add.clear();
+
+        let mut index = 0;
+        while index < target.len() {
+            let copy_len = target[index..]
+                .iter()
+                .zip(source.get(index..).unwrap_or(&[]))
+                .take_while(|&(t, s)| t == s)
+                .count();
+            if copy_len >= MIN_COPY_LEN {
+                self.flush_add();
+                self.instructions.push(CODE_COPY_SELF);
+                write_varint(&mut self.instructions, copy_len as u64);
+                // The source segment comes first in the address space
+                write_varint(&mut self.addresses, index as u64);
+                index += copy_len;
+                continue;
+            }
+
+            let byte = target[index];
+            let run_len = target[index..].iter().take_while(|&&b| b == byte).count();
+            if run_len >= MIN_RUN_LEN {
+                self.flush_add();
+                self.instructions.push(CODE_RUN);
+                write_varint(&mut self.instructions, run_len as u64);
+                self.data.push(byte);
+                index += run_len;
+                continue;
+            }
+
+            self.pending_add.push(byte);
+            index += 1;
+        }
+        self.flush_add();
+    }
+
+    fn flush_add(&mut self) {
+        if !self.pending_add.is_empty() {
+            self.instructions.push(CODE_ADD);
+            write_varint(&mut self.instructions, self.pending_add.len() as u64);
+            self.data.extend_from_slice(&self.pending_add);
+            self.pending_add.clear();
+        }
+    }
+
+    fn write<W: Write>(
+        &self,
+        writer: &mut W,
+        position: u64,
+        source_len: usize,
+    ) -> Result<(), Error> {
+        let mut header = Vec::new();
+        if source_len != 0 {
+            header.push(VCD_SOURCE);
+            write_varint(&mut header, source_len as u64);
+            write_varint(&mut header, position);
+        } else {
+            header.push(0);
+        }
+
+        let mut delta = Vec::new();
+        write_varint(&mut delta, self.target_len as u64);
+        // No compression of any of the sections
+        delta.push(0);
+        write_varint(&mut delta, self.data.len() as u64);
+        write_varint(&mut delta, self.instructions.len() as u64);
+        write_varint(&mut delta, self.addresses.len() as u64);
+
+        let delta_len =
+            delta.len() + self.data.len() + self.instructions.len() + self.addresses.len();
+        write_varint(&mut header, delta_len as u64);
+
+        writer.write_all(&header)?;
+        writer.write_all(&delta)?;
+        writer.write_all(&self.data)?;
+        writer.write_all(&self.instructions)?;
+        writer.write_all(&self.addresses)?;
+
+        Ok(())
+    }
+}
+
+/// VCDIFF's variable length integers store the most significant bits first.
+fn write_varint(buf: &mut Vec<u8>, value: u64) {
+    let mut bytes = [0; 10];
+    let mut index = bytes.len() - 1;
+    bytes[index] = (value & 0x7F) as u8;
+    let mut value = value >> 7;
+    while value != 0 {
+        index -= 1;
+        bytes[index] = 0x80 | (value & 0x7F) as u8;
+        value >>= 7;
+    }
+    buf.extend_from_slice(&bytes[index..]);
+}
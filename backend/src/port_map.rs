@@ -0,0 +1,85 @@
+//! Ports a Rom Hack written for one version of a game to another one. A port
+//! map translates the addresses of the version the patch files were written
+//! for into the addresses of the version that is built. Each line either maps
+//! a single address or a range of addresses, like a function, which moves by
+//! the same distance as its start:
+//!
+//! ```text
+//! # The old address, then the new one
+//! 0x8005_1234 0x8005_2234
+//! 0x8006_0000..0x8006_0400 0x8006_1000
+//! ```
+//!
+//! The single addresses take precedence over the ranges. Only the addresses
+//! that the patch files patch, branch to or hook are translated, so values
+//! like immediates stay as they are, even if they look like addresses.
+
+use failure::{Error, ResultExt};
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+pub struct PortMap {
+    addresses: BTreeMap<u32, u32>,
+    ranges: Vec<(Range<u32>, u32)>,
+}
+
+impl PortMap {
+    pub fn parse(text: &str) -> Result<PortMap, Error> {
+        let mut port_map = PortMap {
+            addresses: BTreeMap::new(),
+            ranges: Vec::new(),
+        };
+
+        for (index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            port_map
+                .parse_line(line)
+                .with_context(|_| format!("Invalid line {} of the port map", index + 1))?;
+        }
+
+        Ok(port_map)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), Error> {
+        let mut columns = line.split_whitespace();
+        let (old, new) = match (columns.next(), columns.next(), columns.next()) {
+            (Some(old), Some(new), None) => (old, parse_address(new)?),
+            _ => bail!("Expected the old address and the new address"),
+        };
+
+        if let Some(index) = old.find("..") {
+            let start = parse_address(&old[..index])?;
+            let end = parse_address(&old[index + 2..])?;
+            ensure!(start < end, "The range {} is empty", old);
+            self.ranges.push((start..end, new));
+        } else {
+            self.addresses.insert(parse_address(old)?, new);
+        }
+
+        Ok(())
+    }
+
+    pub fn translate(&self, address: u32) -> u32 {
+        if let Some(&new) = self.addresses.get(&address) {
+            return new;
+        }
+        self.ranges
+            .iter()
+            .find(|&&(ref range, _)| range.start <= address && address < range.end)
+            .map_or(address, |&(ref range, new)| {
+                new.wrapping_add(address - range.start)
+            })
+    }
+}
+
+fn parse_address(text: &str) -> Result<u32, Error> {
+    let digits = text
+        .trim_left_matches("0x")
+        .trim_left_matches("0X")
+        .replace('_', "");
+    Ok(u32::from_str_radix(&digits, 16)
+        .with_context(|_| format!("Invalid address \"{}\"", text))?)
+}
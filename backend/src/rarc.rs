@@ -0,0 +1,429 @@
+//! Based on http://wiki.tockdom.com/wiki/RARC_(File_Format)
+//!
+//! RARC archives are the `.arc` files of GameCube games. Like U8 archives,
+//! they are parsed into a `Directory`, but each file also has attributes,
+//! like whether it gets loaded into main memory or ARAM. These are kept
+//! separately and written back for every file that still exists when the
+//! archive is rebuilt. The name hashes are recalculated.
+
+use byteorder::{ByteOrder, BE};
+use failure::{Error, ResultExt};
+use iso::virtual_file_system::{Directory, File, FileData, Node};
+use std::collections::HashMap;
+use std::str;
+
+const MAGIC: &[u8] = b"RARC";
+const HEADER_LEN: usize = 0x20;
+const INFO_LEN: usize = 0x20;
+const NODE_LEN: usize = 0x10;
+const ENTRY_LEN: usize = 0x14;
+const ALIGNMENT: usize = 0x20;
+
+// Relative to the start of the archive
+const OFFSET_FILE_SIZE: usize = 0x04;
+const OFFSET_HEADER_SIZE: usize = 0x08;
+const OFFSET_DATA_OFFSET: usize = 0x0C;
+const OFFSET_DATA_LEN: usize = 0x10;
+const OFFSET_MRAM_LEN: usize = 0x14;
+const OFFSET_ARAM_LEN: usize = 0x18;
+const OFFSET_DVD_LEN: usize = 0x1C;
+
+// Relative to the start of the info block
+const OFFSET_NUM_NODES: usize = 0x00;
+const OFFSET_NODES_OFFSET: usize = 0x04;
+const OFFSET_NUM_ENTRIES: usize = 0x08;
+const OFFSET_ENTRIES_OFFSET: usize = 0x0C;
+const OFFSET_STRING_TABLE_LEN: usize = 0x10;
+const OFFSET_STRING_TABLE_OFFSET: usize = 0x14;
+const OFFSET_NEXT_FILE_ID: usize = 0x18;
+const OFFSET_SYNC_FILE_IDS: usize = 0x1A;
+
+const FLAG_FILE: u8 = 0x01;
+const FLAG_DIRECTORY: u8 = 0x02;
+const FLAG_COMPRESSED: u8 = 0x04;
+const FLAG_MRAM: u8 = 0x10;
+const FLAG_ARAM: u8 = 0x20;
+const FLAG_DVD: u8 = 0x40;
+const FLAG_YAZ0: u8 = 0x80;
+
+const NO_PARENT: u32 = 0xFFFF_FFFF;
+const NO_FILE_ID: u16 = 0xFFFF;
+
+pub struct Archive<'a> {
+    pub root: Directory<'a>,
+    /// The attributes of each file by its path relative to the root.
+    flags: HashMap<String, u8>,
+    /// Whether the file IDs are the indices of the files' entries.
+    sync_file_ids: bool,
+}
+
+pub fn is_rarc(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN + INFO_LEN && data.starts_with(MAGIC)
+}
+
+/// The hash the games use to speed up looking up names.
+fn hash(name: &str) -> u16 {
+    name.bytes()
+        .fold(0u16, |hash, b| hash.wrapping_mul(3).wrapping_add(b as u16))
+}
+
+fn align(offset: usize) -> usize {
+    (offset + ALIGNMENT - 1) & !(ALIGNMENT - 1)
+}
+
+/// The flags a file gets based on whether it's compressed.
+fn compression_flags(data: &[u8]) -> u8 {
+    if data.starts_with(b"Yaz0") {
+        FLAG_COMPRESSED | FLAG_YAZ0
+    } else if data.starts_with(b"Yay0") {
+        FLAG_COMPRESSED
+    } else {
+        0
+    }
+}
+
+fn read_name(string_table: &[u8], offset: usize) -> Result<&str, Error> {
+    ensure!(
+        offset < string_table.len(),
+        "A name in the archive is out of bounds"
+    );
+    let name = &string_table[offset..];
+    let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    Ok(str::from_utf8(&name[..end]).context("A name in the archive is invalid")?)
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    nodes: &'a [u8],
+    entries: &'a [u8],
+    string_table: &'a [u8],
+    data_offset: usize,
+    flags: HashMap<String, u8>,
+}
+
+impl<'a> Parser<'a> {
+    fn parse_node(
+        &mut self,
+        index: usize,
+        path: &str,
+        depth: usize,
+    ) -> Result<Directory<'a>, Error> {
+        ensure!(
+            depth <= self.nodes.len() / NODE_LEN,
+            "The directories of the archive contain a cycle"
+        );
+        let node = &self.nodes[index * NODE_LEN..][..NODE_LEN];
+        let mut dir = Directory::new(read_name(
+            self.string_table,
+            BE::read_u32(&node[4..]) as usize,
+        )?);
+
+        let num_entries = BE::read_u16(&node[0xA..]) as usize;
+        let first_entry = BE::read_u32(&node[0xC..]) as usize;
+        ensure!(
+            (first_entry + num_entries) * ENTRY_LEN <= self.entries.len(),
+            "The entries of the directory \"{}\" are out of bounds",
+            dir.name
+        );
+
+        for index in first_entry..first_entry + num_entries {
+            let entry = &self.entries[index * ENTRY_LEN..][..ENTRY_LEN];
+            let flags = entry[4];
+            let name = read_name(self.string_table, BE::read_u16(&entry[6..]) as usize)?;
+            let offset = BE::read_u32(&entry[8..]) as usize;
+            let size = BE::read_u32(&entry[0xC..]) as usize;
+            let child_path = format!("{}{}", path, name);
+
+            if flags & FLAG_DIRECTORY != 0 {
+                if name == "." || name == ".." {
+                    continue;
+                }
+                ensure!(
+                    offset < self.nodes.len() / NODE_LEN,
+                    "The directory \"{}\" of the archive is out of bounds",
+                    child_path
+                );
+                let child = self.parse_node(offset, &format!("{}/", child_path), depth + 1)?;
+                dir.children.push(Node::Directory(Box::new(child)));
+            } else {
+                ensure!(
+                    self.data_offset + offset + size <= self.data.len(),
+                    "The file \"{}\" of the archive is out of bounds",
+                    child_path
+                );
+                let data = &self.data[self.data_offset + offset..][..size];
+                dir.children.push(Node::File(File::new(name, data)));
+                self.flags.insert(child_path, flags);
+            }
+        }
+
+        Ok(dir)
+    }
+}
+
+impl<'a> Archive<'a> {
+    /// Parses a RARC archive. Paths into the archive are relative to its root
+    /// directory. The files borrow their data from the original archive.
+    pub fn parse(data: &'a [u8]) -> Result<Self, Error> {
+        ensure!(is_rarc(data), "The file is not a RARC archive");
+
+        let info = &data[HEADER_LEN..];
+        let section = |offset: usize, len: usize| -> Result<&'a [u8], Error> {
+            let offset = HEADER_LEN + offset;
+            ensure!(offset + len <= data.len(), "The archive is truncated");
+            Ok(&data[offset..][..len])
+        };
+
+        let nodes = section(
+            BE::read_u32(&info[OFFSET_NODES_OFFSET..]) as usize,
+            BE::read_u32(&info[OFFSET_NUM_NODES..]) as usize * NODE_LEN,
+        )?;
+        let entries = section(
+            BE::read_u32(&info[OFFSET_ENTRIES_OFFSET..]) as usize,
+            BE::read_u32(&info[OFFSET_NUM_ENTRIES..]) as usize * ENTRY_LEN,
+        )?;
+        let string_table = section(
+            BE::read_u32(&info[OFFSET_STRING_TABLE_OFFSET..]) as usize,
+            BE::read_u32(&info[OFFSET_STRING_TABLE_LEN..]) as usize,
+        )?;
+        ensure!(!nodes.is_empty(), "The archive has no root directory");
+
+        let mut parser = Parser {
+            data,
+            nodes,
+            entries,
+            string_table,
+            data_offset: HEADER_LEN + BE::read_u32(&data[OFFSET_DATA_OFFSET..]) as usize,
+            flags: HashMap::new(),
+        };
+        let root = parser.parse_node(0, "", 0)?;
+
+        Ok(Self {
+            root,
+            flags: parser.flags,
+            sync_file_ids: info[OFFSET_SYNC_FILE_IDS] != 0,
+        })
+    }
+
+    /// Rebuilds the archive. The directories are stored breadth first and the
+    /// files are grouped by whether they are loaded into main memory, ARAM or
+    /// are left on the disc, aligning each of them to 32 bytes.
+    pub fn write(&self) -> Result<Vec<u8>, Error> {
+        enum Contents<'b> {
+            File(&'b [u8]),
+            /// The index of the directory's node.
+            Directory(u32),
+        }
+
+        struct Entry<'b> {
+            name: &'b str,
+            flags: u8,
+            contents: Contents<'b>,
+        }
+
+        let mut strings = StringTable::default();
+        strings.add(".");
+        strings.add("..");
+
+        let mut dirs = vec![(&self.root, NO_PARENT, String::new())];
+        let mut nodes = Vec::new();
+        let mut entries = Vec::new();
+
+        let mut index = 0;
+        while index < dirs.len() {
+            let (dir, parent, path) = (dirs[index].0, dirs[index].1, dirs[index].2.clone());
+            let first_entry = entries.len();
+
+            for child in &dir.children {
+                match *child {
+                    Node::Directory(ref child) => {
+                        entries.push(Entry {
+                            name: child.name,
+                            flags: FLAG_DIRECTORY,
+                            contents: Contents::Directory(dirs.len() as u32),
+                        });
+                        dirs.push((child, index as u32, format!("{}{}/", path, child.name)));
+                    }
+                    Node::File(ref file) => {
+                        let data = match file.data {
+                            FileData::Memory(ref data) => &**data,
+                            FileData::Disc { .. } => bail!(
+                                "The file \"{}\" of the archive wasn't loaded into memory",
+                                file.name
+                            ),
+                        };
+                        let flags = self
+                            .flags
+                            .get(&format!("{}{}", path, file.name))
+                            .cloned()
+                            .unwrap_or(FLAG_FILE | FLAG_MRAM);
+                        entries.push(Entry {
+                            name: file.name,
+                            flags: (flags & !(FLAG_COMPRESSED | FLAG_YAZ0))
+                                | compression_flags(data),
+                            contents: Contents::File(data),
+                        });
+                    }
+                }
+            }
+            entries.push(Entry {
+                name: ".",
+                flags: FLAG_DIRECTORY,
+                contents: Contents::Directory(index as u32),
+            });
+            entries.push(Entry {
+                name: "..",
+                flags: FLAG_DIRECTORY,
+                contents: Contents::Directory(parent),
+            });
+
+            nodes.push((dir.name, first_entry, entries.len() - first_entry));
+            index += 1;
+        }
+
+        for &(name, _, _) in &nodes {
+            strings.add(name);
+        }
+        for entry in &entries {
+            strings.add(entry.name);
+        }
+        ensure!(
+            strings.data.len() <= 0x1_0000,
+            "The names of the archive's files don't fit into the archive"
+        );
+
+        // The files are grouped by where they get loaded to
+        let mut file_offsets = HashMap::new();
+        let mut group_lens = [0; 3];
+        let mut data_len = 0;
+        for (group, &location) in [FLAG_MRAM, FLAG_ARAM, FLAG_DVD].iter().enumerate() {
+            let start = data_len;
+            for (index, entry) in entries.iter().enumerate() {
+                if let Contents::File(data) = entry.contents {
+                    let entry_location = match entry.flags & (FLAG_MRAM | FLAG_ARAM | FLAG_DVD) {
+                        0 => FLAG_MRAM,
+                        flags => flags,
+                    };
+                    if entry_location & location != 0 && !file_offsets.contains_key(&index) {
+                        file_offsets.insert(index, data_len);
+                        data_len = align(data_len + data.len());
+                    }
+                }
+            }
+            group_lens[group] = data_len - start;
+        }
+
+        let nodes_offset = INFO_LEN;
+        let entries_offset = align(nodes_offset + nodes.len() * NODE_LEN);
+        let string_table_offset = align(entries_offset + entries.len() * ENTRY_LEN);
+        let data_offset = align(string_table_offset + strings.data.len());
+        let file_size = HEADER_LEN + data_offset + data_len;
+
+        let mut buf = vec![0; file_size];
+        buf[..MAGIC.len()].copy_from_slice(MAGIC);
+        BE::write_u32(&mut buf[OFFSET_FILE_SIZE..], file_size as u32);
+        BE::write_u32(&mut buf[OFFSET_HEADER_SIZE..], HEADER_LEN as u32);
+        BE::write_u32(&mut buf[OFFSET_DATA_OFFSET..], data_offset as u32);
+        BE::write_u32(&mut buf[OFFSET_DATA_LEN..], data_len as u32);
+        BE::write_u32(&mut buf[OFFSET_MRAM_LEN..], group_lens[0] as u32);
+        BE::write_u32(&mut buf[OFFSET_ARAM_LEN..], group_lens[1] as u32);
+        BE::write_u32(&mut buf[OFFSET_DVD_LEN..], group_lens[2] as u32);
+
+        let file_count = entries
+            .iter()
+            .filter(|e| match e.contents {
+                Contents::File(_) => true,
+                Contents::Directory(_) => false,
+            })
+            .count();
+        {
+            let info = &mut buf[HEADER_LEN..];
+            BE::write_u32(&mut info[OFFSET_NUM_NODES..], nodes.len() as u32);
+            BE::write_u32(&mut info[OFFSET_NODES_OFFSET..], nodes_offset as u32);
+            BE::write_u32(&mut info[OFFSET_NUM_ENTRIES..], entries.len() as u32);
+            BE::write_u32(&mut info[OFFSET_ENTRIES_OFFSET..], entries_offset as u32);
+            BE::write_u32(
+                &mut info[OFFSET_STRING_TABLE_LEN..],
+                strings.data.len() as u32,
+            );
+            BE::write_u32(
+                &mut info[OFFSET_STRING_TABLE_OFFSET..],
+                string_table_offset as u32,
+            );
+            let next_file_id = if self.sync_file_ids {
+                entries.len()
+            } else {
+                file_count
+            };
+            BE::write_u16(&mut info[OFFSET_NEXT_FILE_ID..], next_file_id as u16);
+            info[OFFSET_SYNC_FILE_IDS] = self.sync_file_ids as u8;
+        }
+
+        for (index, &(name, first_entry, num_entries)) in nodes.iter().enumerate() {
+            let node = &mut buf[HEADER_LEN + nodes_offset + index * NODE_LEN..][..NODE_LEN];
+            if index == 0 {
+                node[..4].copy_from_slice(b"ROOT");
+            } else {
+                let mut kind = [b' '; 4];
+                for (dst, src) in kind.iter_mut().zip(name.bytes()) {
+                    *dst = src.to_ascii_uppercase();
+                }
+                node[..4].copy_from_slice(&kind);
+            }
+            BE::write_u32(&mut node[4..], strings.offset(name) as u32);
+            BE::write_u16(&mut node[8..], hash(name));
+            BE::write_u16(&mut node[0xA..], num_entries as u16);
+            BE::write_u32(&mut node[0xC..], first_entry as u32);
+        }
+
+        let mut file_id = 0;
+        for (index, entry) in entries.iter().enumerate() {
+            let offset = HEADER_LEN + entries_offset + index * ENTRY_LEN;
+            let (id, offset_node, size) = match entry.contents {
+                Contents::File(data) => {
+                    let file_offset = file_offsets[&index];
+                    buf[HEADER_LEN + data_offset + file_offset..][..data.len()]
+                        .copy_from_slice(data);
+                    let id = if self.sync_file_ids { index } else { file_id };
+                    file_id += 1;
+                    (id as u16, file_offset as u32, data.len() as u32)
+                }
+                Contents::Directory(node) => (NO_FILE_ID, node, NODE_LEN as u32),
+            };
+
+            let raw = &mut buf[offset..][..ENTRY_LEN];
+            BE::write_u16(raw, id);
+            BE::write_u16(&mut raw[2..], hash(entry.name));
+            raw[4] = entry.flags;
+            BE::write_u16(&mut raw[6..], strings.offset(entry.name) as u16);
+            BE::write_u32(&mut raw[8..], offset_node);
+            BE::write_u32(&mut raw[0xC..], size);
+        }
+
+        let string_table = &mut buf[HEADER_LEN + string_table_offset..][..strings.data.len()];
+        string_table.copy_from_slice(&strings.data);
+
+        Ok(buf)
+    }
+}
+
+/// Stores each distinct name only once.
+#[derive(Default)]
+struct StringTable {
+    data: Vec<u8>,
+    offsets: HashMap<String, usize>,
+}
+
+impl StringTable {
+    fn add(&mut self, name: &str) {
+        if !self.offsets.contains_key(name) {
+            self.offsets.insert(name.to_owned(), self.data.len());
+            self.data.extend_from_slice(name.as_bytes());
+            self.data.push(0);
+        }
+    }
+
+    fn offset(&self, name: &str) -> usize {
+        self.offsets[name]
+    }
+}
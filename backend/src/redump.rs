@@ -0,0 +1,85 @@
+//! Verifies the original game against a DAT file of known good dumps, like the
+//! ones Redump publishes for GameCube and Wii games. Rom Hacks built from bad
+//! dumps or from other revisions of the game may not work for the players, so
+//! the games are identified by the size and the CRC32 of their raw disc image.
+
+use failure::{Error, ResultExt};
+use flate2::Crc;
+use regex::Regex;
+use std::io::Read;
+
+pub struct Dump {
+    pub name: String,
+    pub len: u64,
+    pub crc: u32,
+}
+
+/// Parses the games of a DAT file in the Logiqx XML format. Only the size and
+/// the CRC32 of the disc images are used, as they identify the dumps already.
+pub fn parse_dat(text: &str) -> Result<Vec<Dump>, Error> {
+    let game_regex = Regex::new(r#"(?s)<game\s+name="([^"]*)"(.*?)</game>"#).unwrap();
+    let rom_regex = Regex::new(r"<rom\s[^>]*>").unwrap();
+    let attribute_regex = Regex::new(r#"(\w+)="([^"]*)""#).unwrap();
+
+    let mut dumps = Vec::new();
+    for game in game_regex.captures_iter(text) {
+        let name = unescape(&game[1]);
+        for rom in rom_regex.find_iter(&game[2]) {
+            let (mut len, mut crc) = (None, None);
+            for attribute in attribute_regex.captures_iter(rom.as_str()) {
+                match &attribute[1] {
+                    "size" => {
+                        len = Some(attribute[2].parse().with_context(|_| {
+                            format!("Invalid size \"{}\" of \"{}\"", &attribute[2], name)
+                        })?)
+                    }
+                    "crc" => {
+                        crc = Some(u32::from_str_radix(&attribute[2], 16).with_context(|_| {
+                            format!("Invalid CRC32 \"{}\" of \"{}\"", &attribute[2], name)
+                        })?)
+                    }
+                    _ => {}
+                }
+            }
+            if let (Some(len), Some(crc)) = (len, crc) {
+                dumps.push(Dump {
+                    name: name.clone(),
+                    len,
+                    crc,
+                });
+            }
+        }
+    }
+
+    ensure!(!dumps.is_empty(), "The DAT file doesn't contain any dumps");
+
+    Ok(dumps)
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Reads the whole disc and returns its size and CRC32.
+pub fn hash<R: Read>(reader: &mut R) -> Result<(u64, u32), Error> {
+    let mut crc = Crc::new();
+    let mut len = 0;
+    let mut buf = vec![0; 4 << 20];
+    loop {
+        let read = reader.read(&mut buf).context("Couldn't read the disc")?;
+        if read == 0 {
+            break;
+        }
+        crc.update(&buf[..read]);
+        len += read as u64;
+    }
+    Ok((len, crc.sum()))
+}
+
+pub fn find(dumps: &[Dump], len: u64, crc: u32) -> Option<&Dump> {
+    dumps.iter().find(|d| d.len == len && d.crc == crc)
+}
@@ -0,0 +1,446 @@
+//! Based on http://wiki.tockdom.com/wiki/REL_(File_Format)
+//! and http://www.gc-forever.com/yagcd/chap17.html
+
+use assembler::Instruction;
+use byteorder::{ByteOrder, BE};
+use failure::Error;
+
+const HEADER_LEN_V1: usize = 0x40;
+const HEADER_LEN_V2: usize = 0x48;
+const HEADER_LEN_V3: usize = 0x4C;
+const SECTION_INFO_LEN: usize = 8;
+const IMPORT_LEN: usize = 8;
+const RELOCATION_LEN: usize = 8;
+
+pub const R_PPC_NONE: u8 = 0;
+pub const R_PPC_ADDR32: u8 = 1;
+pub const R_PPC_ADDR24: u8 = 2;
+pub const R_PPC_ADDR16: u8 = 3;
+pub const R_PPC_ADDR16_LO: u8 = 4;
+pub const R_PPC_ADDR16_HI: u8 = 5;
+pub const R_PPC_ADDR16_HA: u8 = 6;
+pub const R_PPC_ADDR14: u8 = 7;
+pub const R_PPC_ADDR14_BRTAKEN: u8 = 8;
+pub const R_PPC_ADDR14_BRNTAKEN: u8 = 9;
+pub const R_PPC_REL24: u8 = 10;
+pub const R_PPC_REL14: u8 = 11;
+pub const R_PPC_REL14_BRTAKEN: u8 = 12;
+pub const R_PPC_REL14_BRNTAKEN: u8 = 13;
+pub const R_DOLPHIN_NOP: u8 = 201;
+pub const R_DOLPHIN_SECTION: u8 = 202;
+pub const R_DOLPHIN_END: u8 = 203;
+
+pub struct RelSection {
+    pub offset: u32,
+    pub len: u32,
+    pub executable: bool,
+}
+
+pub struct Import {
+    /// The module the relocations resolve against. Module 0 is the main DOL.
+    pub module_id: u32,
+    pub relocations: Vec<Relocation>,
+}
+
+pub struct Relocation {
+    /// Offset of the relocation entry itself within the REL file, unless the
+    /// relocation was added to it.
+    entry_offset: Option<usize>,
+    pub kind: u8,
+    /// The section of this module that gets relocated.
+    pub section: usize,
+    /// The offset within that section that gets relocated.
+    pub offset: u32,
+    /// The section of the imported module the relocation points into. This
+    /// is unused for relocations against the main DOL.
+    pub target_section: u8,
+    pub addend: u32,
+}
+
+pub struct RelFile {
+    pub id: u32,
+    pub version: u32,
+    pub bss_size: u32,
+    pub sections: Vec<RelSection>,
+    pub imports: Vec<Import>,
+    data: Vec<u8>,
+    /// Whether relocations were added, which means that the relocation tables
+    /// need to be written anew.
+    relocations_added: bool,
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    BE::read_u32(data)
+}
+
+fn write_u32(data: &mut [u8], value: u32) {
+    BE::write_u32(data, value)
+}
+
+fn relocation_len(kind: u8) -> u32 {
+    match kind {
+        R_PPC_ADDR16 | R_PPC_ADDR16_LO | R_PPC_ADDR16_HI | R_PPC_ADDR16_HA => 2,
+        _ => 4,
+    }
+}
+
+impl RelSection {
+    pub fn is_bss(&self) -> bool {
+        self.offset == 0 && self.len != 0
+    }
+}
+
+impl RelFile {
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        ensure!(data.len() >= HEADER_LEN_V1, "The REL file is too small");
+
+        let id = read_u32(&data[0x00..]);
+        let section_count = read_u32(&data[0x0C..]) as usize;
+        let section_info_offset = read_u32(&data[0x10..]) as usize;
+        let version = read_u32(&data[0x1C..]);
+        let bss_size = read_u32(&data[0x20..]);
+        let import_offset = read_u32(&data[0x28..]) as usize;
+        let import_len = read_u32(&data[0x2C..]) as usize;
+
+        let header_len = match version {
+            1 => HEADER_LEN_V1,
+            2 => HEADER_LEN_V2,
+            3 => HEADER_LEN_V3,
+            _ => bail!("Unsupported REL version {}", version),
+        };
+        ensure!(data.len() >= header_len, "The REL file is too small");
+
+        ensure!(
+            section_info_offset + section_count * SECTION_INFO_LEN <= data.len(),
+            "The REL's section table is out of bounds"
+        );
+
+        let mut sections = Vec::with_capacity(section_count);
+        for index in 0..section_count {
+            let entry = &data[section_info_offset + index * SECTION_INFO_LEN..];
+            let offset = read_u32(entry);
+            let len = read_u32(&entry[4..]);
+            let section = RelSection {
+                offset: offset & !1,
+                len,
+                executable: offset & 1 != 0,
+            };
+            ensure!(
+                section.offset as usize + section.len as usize <= data.len() || section.is_bss(),
+                "Section {} of the REL is out of bounds",
+                index
+            );
+            sections.push(section);
+        }
+
+        ensure!(
+            import_offset + import_len <= data.len(),
+            "The REL's import table is out of bounds"
+        );
+
+        let mut imports = Vec::with_capacity(import_len / IMPORT_LEN);
+        for entry in data[import_offset..][..import_len].chunks(IMPORT_LEN) {
+            ensure!(entry.len() == IMPORT_LEN, "The REL's import table is truncated");
+            let module_id = read_u32(entry);
+            let relocations = parse_relocations(data, read_u32(&entry[4..]) as usize)?;
+            imports.push(Import {
+                module_id,
+                relocations,
+            });
+        }
+
+        Ok(RelFile {
+            id,
+            version,
+            bss_size,
+            sections,
+            imports,
+            data: data.to_owned(),
+            relocations_added: false,
+        })
+    }
+
+    /// Patches the module's code and data. The addresses of the instructions
+    /// are relative to the start of the module, which is where the module is
+    /// located in memory once it's loaded. Any relocations that would
+    /// overwrite the patched instructions when the game links the module are
+    /// removed.
+    pub fn patch(&mut self, instructions: &[Instruction]) -> Result<(), Error> {
+        for instruction in instructions {
+            let address = instruction.address;
+            let (section_index, section_offset) = self.locate(address).ok_or_else(|| {
+                format_err!(
+                    "Patch at offset {:#x} couldn't be applied to the REL",
+                    address
+                )
+            })?;
+
+            let word = instruction.apply(read_u32(&self.data[address as usize..]));
+            write_u32(&mut self.data[address as usize..], word);
+
+            for import in &mut self.imports {
+                for relocation in &mut import.relocations {
+                    if relocation.section == section_index
+                        && relocation.kind != R_DOLPHIN_NOP
+                        && relocation.offset < section_offset + 4
+                        && section_offset < relocation.offset + relocation_len(relocation.kind)
+                    {
+                        relocation.kind = R_DOLPHIN_NOP;
+                        if let Some(entry_offset) = relocation.entry_offset {
+                            self.data[entry_offset + 2] = R_DOLPHIN_NOP;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds a relocation of the word at the offset of the module against the
+    /// main DOL, like a branch to one of the DOL's functions. The game only
+    /// resolves it once it links the module, as the module's address isn't
+    /// known before.
+    pub fn relocate_against_dol(
+        &mut self,
+        offset: u32,
+        kind: u8,
+        address: u32,
+    ) -> Result<(), Error> {
+        let (section, offset) = self.locate(offset).ok_or_else(|| {
+            format_err!(
+                "The relocation at offset {:#x} is outside of the REL's sections",
+                offset
+            )
+        })?;
+
+        let relocation = Relocation {
+            entry_offset: None,
+            kind,
+            section,
+            offset,
+            target_section: 0,
+            addend: address,
+        };
+        // The relocations against the DOL conventionally come last
+        match self.imports.iter_mut().find(|i| i.module_id == 0) {
+            Some(import) => import.relocations.push(relocation),
+            None => self.imports.push(Import {
+                module_id: 0,
+                relocations: vec![relocation],
+            }),
+        }
+        self.relocations_added = true;
+
+        Ok(())
+    }
+
+    /// Finds the section that contains the word at the offset of the module
+    /// and the offset within that section.
+    fn locate(&self, offset: u32) -> Option<(usize, u32)> {
+        let index = self
+            .sections
+            .iter()
+            .position(|s| !s.is_bss() && s.offset <= offset && s.offset + s.len >= offset + 4)?;
+        Some((index, offset - self.sections[index].offset))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.data.clone();
+        if self.relocations_added {
+            self.write_imports(&mut data);
+        }
+        data
+    }
+
+    /// Writes the imports and their relocations to the end of the module, as
+    /// the added relocations don't fit into the original tables. The original
+    /// tables are left unused.
+    fn write_imports(&self, data: &mut Vec<u8>) {
+        while data.len() % 4 != 0 {
+            data.push(0);
+        }
+        let import_offset = data.len();
+        let import_len = IMPORT_LEN * self.imports.len();
+        data.resize(import_offset + import_len, 0);
+        let relocation_offset = data.len();
+
+        for (index, import) in self.imports.iter().enumerate() {
+            let entry = import_offset + IMPORT_LEN * index;
+            let offset = data.len() as u32;
+            write_u32(&mut data[entry..], import.module_id);
+            write_u32(&mut data[entry + 4..], offset);
+
+            let mut relocations = import
+                .relocations
+                .iter()
+                .filter(|r| r.kind != R_DOLPHIN_NOP)
+                .collect::<Vec<_>>();
+            relocations.sort_by_key(|r| (r.section, r.offset));
+
+            let mut section = None;
+            let mut position = 0;
+            for relocation in relocations {
+                if section != Some(relocation.section) {
+                    push_relocation(data, 0, R_DOLPHIN_SECTION, relocation.section as u8, 0);
+                    section = Some(relocation.section);
+                    position = 0;
+                }
+                // The distance to the previous relocation only has 16 bits
+                let mut delta = relocation.offset - position;
+                while delta > 0xFFFF {
+                    push_relocation(data, 0xFFFF, R_DOLPHIN_NOP, 0, 0);
+                    delta -= 0xFFFF;
+                }
+                push_relocation(
+                    data,
+                    delta as u16,
+                    relocation.kind,
+                    relocation.target_section,
+                    relocation.addend,
+                );
+                position = relocation.offset;
+            }
+            push_relocation(data, 0, R_DOLPHIN_END, 0, 0);
+        }
+
+        write_u32(&mut data[0x24..], relocation_offset as u32);
+        write_u32(&mut data[0x28..], import_offset as u32);
+        write_u32(&mut data[0x2C..], import_len as u32);
+    }
+}
+
+fn push_relocation(data: &mut Vec<u8>, delta: u16, kind: u8, section: u8, addend: u32) {
+    let mut entry = [0; RELOCATION_LEN];
+    BE::write_u16(&mut entry, delta);
+    entry[2] = kind;
+    entry[3] = section;
+    write_u32(&mut entry[4..], addend);
+    data.extend_from_slice(&entry);
+}
+
+fn parse_relocations(data: &[u8], mut offset: usize) -> Result<Vec<Relocation>, Error> {
+    let mut relocations = Vec::new();
+    let mut section = 0;
+    let mut section_offset = 0u32;
+
+    loop {
+        ensure!(
+            offset + RELOCATION_LEN <= data.len(),
+            "The REL's relocation table is out of bounds"
+        );
+        let entry = &data[offset..][..RELOCATION_LEN];
+        let delta = BE::read_u16(entry) as u32;
+        let kind = entry[2];
+        let target_section = entry[3];
+        let addend = read_u32(&entry[4..]);
+
+        match kind {
+            R_DOLPHIN_END => break,
+            R_DOLPHIN_SECTION => {
+                section = target_section as usize;
+                section_offset = 0;
+            }
+            R_DOLPHIN_NOP => section_offset += delta,
+            _ => {
+                section_offset += delta;
+                relocations.push(Relocation {
+                    entry_offset: Some(offset),
+                    kind,
+                    section,
+                    offset: section_offset,
+                    target_section,
+                    addend,
+                });
+            }
+        }
+
+        offset += RELOCATION_LEN;
+    }
+
+    Ok(relocations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A module with a single code section of two words, where the second
+    /// word is relocated against the module itself.
+    fn module() -> Vec<u8> {
+        let mut data = vec![0; 0x78];
+        write_u32(&mut data[0x00..], 1);
+        write_u32(&mut data[0x0C..], 2);
+        write_u32(&mut data[0x10..], 0x40);
+        write_u32(&mut data[0x1C..], 1);
+        write_u32(&mut data[0x24..], 0x60);
+        write_u32(&mut data[0x28..], 0x58);
+        write_u32(&mut data[0x2C..], 8);
+        write_u32(&mut data[0x48..], 0x50 | 1);
+        write_u32(&mut data[0x4C..], 8);
+        write_u32(&mut data[0x58..], 1);
+        write_u32(&mut data[0x5C..], 0x60);
+        let mut relocations = Vec::new();
+        push_relocation(&mut relocations, 0, R_DOLPHIN_SECTION, 1, 0);
+        push_relocation(&mut relocations, 4, R_PPC_ADDR32, 1, 0);
+        push_relocation(&mut relocations, 0, R_DOLPHIN_END, 0, 0);
+        data[0x60..].copy_from_slice(&relocations);
+        data
+    }
+
+    fn word(address: u32, data: u32) -> Instruction {
+        Instruction {
+            address,
+            data,
+            mask: !0,
+        }
+    }
+
+    #[test]
+    fn parses_relocations() {
+        let rel = RelFile::parse(&module()).unwrap();
+        assert_eq!(rel.sections.len(), 2);
+        assert!(rel.sections[1].executable);
+        assert_eq!(rel.imports.len(), 1);
+        let relocation = &rel.imports[0].relocations[0];
+        assert_eq!(
+            (relocation.kind, relocation.section, relocation.offset),
+            (R_PPC_ADDR32, 1, 4)
+        );
+    }
+
+    #[test]
+    fn patching_removes_overwritten_relocations() {
+        let mut rel = RelFile::parse(&module()).unwrap();
+        rel.patch(&[word(0x54, 0x6000_0000)]).unwrap();
+        let rel = RelFile::parse(&rel.to_bytes()).unwrap();
+        assert!(rel.imports[0].relocations.is_empty());
+
+        let mut rel = RelFile::parse(&module()).unwrap();
+        assert!(rel.patch(&[word(0x58, 0)]).is_err());
+    }
+
+    #[test]
+    fn relocates_branches_against_the_dol() {
+        let mut rel = RelFile::parse(&module()).unwrap();
+        rel.patch(&[word(0x50, 0x4800_0001)]).unwrap();
+        rel.relocate_against_dol(0x50, R_PPC_REL24, 0x8000_1234).unwrap();
+        let data = rel.to_bytes();
+        assert_eq!(read_u32(&data[0x50..]), 0x4800_0001);
+
+        let rel = RelFile::parse(&data).unwrap();
+        assert_eq!(rel.imports.len(), 2);
+        let relocation = &rel.imports[0].relocations[0];
+        assert_eq!(
+            (relocation.kind, relocation.section, relocation.offset),
+            (R_PPC_ADDR32, 1, 4)
+        );
+        assert_eq!(rel.imports[1].module_id, 0);
+        let relocation = &rel.imports[1].relocations[0];
+        assert_eq!(
+            (relocation.kind, relocation.section, relocation.offset),
+            (R_PPC_REL24, 1, 0)
+        );
+        assert_eq!(relocation.addend, 0x8000_1234);
+    }
+}
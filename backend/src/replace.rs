@@ -0,0 +1,93 @@
+//! Lowers the function replacements of the patch files. A replaced function
+//! branches to its replacement right at its start, so every call ends up
+//! there, including the calls through function pointers. Redirecting only the
+//! calls instead rewrites every `bl` to the function in the game's code, as
+//! well as every `b` to it, which the compiler emits for tail calls.
+
+use assembler::{build_branch_instruction, is_branch_in_range, Instruction, Replacement};
+use byteorder::{ByteOrder, BE};
+use dol::{DolFile, Section};
+use failure::Error;
+use hook::read_displaced_instruction;
+
+/// Lowers the replacements to the instructions that patch the game's code,
+/// which consists of the text sections. The amount of branches that were
+/// redirected is returned for each function whose calls are redirected.
+pub fn lower(
+    replacements: &[Replacement],
+    dol: &DolFile,
+    game_code: &[Section],
+) -> Result<(Vec<Instruction>, Vec<(u32, usize)>), Error> {
+    let mut instructions = Vec::new();
+    let mut redirected = Vec::new();
+
+    for replacement in replacements {
+        if replacement.calls_only {
+            let count = instructions.len();
+            redirect_calls(replacement, game_code, &mut instructions)?;
+            ensure!(
+                instructions.len() > count,
+                "There are no calls to the function {:08X} to redirect",
+                replacement.function
+            );
+            redirected.push((replacement.function, instructions.len() - count));
+        } else {
+            read_displaced_instruction(dol, replacement.function, "replaced function")?;
+            ensure!(
+                is_branch_in_range(replacement.function, replacement.replacement),
+                "The function {:08X} can't branch to its replacement {:08X}",
+                replacement.function,
+                replacement.replacement
+            );
+            instructions.push(Instruction {
+                address: replacement.function,
+                data: build_branch_instruction(
+                    replacement.function,
+                    replacement.replacement,
+                    false,
+                    false,
+                ),
+                mask: !0,
+            });
+        }
+    }
+
+    Ok((instructions, redirected))
+}
+
+fn redirect_calls(
+    replacement: &Replacement,
+    game_code: &[Section],
+    instructions: &mut Vec<Instruction>,
+) -> Result<(), Error> {
+    for section in game_code {
+        for (index, word) in section.data.chunks(4).enumerate() {
+            if word.len() < 4 {
+                continue;
+            }
+            let word = BE::read_u32(word);
+            // Only relative branches, like `b` and `bl`
+            if word >> 26 != 18 || word & 2 != 0 {
+                continue;
+            }
+            let address = section.address + 4 * index as u32;
+            let displacement = ((word & 0x03FF_FFFC) << 6) as i32 >> 6;
+            if address.wrapping_add(displacement as u32) != replacement.function {
+                continue;
+            }
+            ensure!(
+                is_branch_in_range(address, replacement.replacement),
+                "The branch at {:08X} can't reach the replacement {:08X}",
+                address,
+                replacement.replacement
+            );
+            let link = word & 1 != 0;
+            instructions.push(Instruction {
+                address,
+                data: build_branch_instruction(address, replacement.replacement, false, link),
+                mask: !0,
+            });
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,53 @@
+//! Writes a human readable report of every instruction the patches replace,
+//! so it's easy to review what a Rom Hack changes in the original code.
+
+use assembler::disassemble;
+use conflicts::Patch;
+use dol::DolFile;
+use failure::{Error, ResultExt};
+use std::fs::File;
+use std::io::{prelude::*, BufWriter};
+use std::path::Path;
+
+pub fn create(path: &Path, original: &DolFile, patches: &[Patch]) -> Result<(), Error> {
+    let mut file = BufWriter::new(File::create(path).context("Couldn't create the report")?);
+
+    for patch in patches {
+        if patch.instructions.is_empty() {
+            continue;
+        }
+
+        writeln!(file, "; {}", patch.name)?;
+
+        let mut instructions = patch.instructions.iter().collect::<Vec<_>>();
+        instructions.sort_by_key(|i| i.address);
+
+        for instruction in instructions {
+            match original.read_u32(instruction.address) {
+                Some(word) => writeln!(
+                    file,
+                    "{:08X}: {} -> {}",
+                    instruction.address,
+                    format_word(instruction.address, word),
+                    format_word(instruction.address, instruction.apply(word))
+                )?,
+                None => writeln!(
+                    file,
+                    "{:08X}: {}",
+                    instruction.address,
+                    format_word(instruction.address, instruction.data)
+                )?,
+            }
+        }
+
+        writeln!(file)?;
+    }
+
+    file.flush()?;
+
+    Ok(())
+}
+
+pub fn format_word(address: u32, word: u32) -> String {
+    disassemble(address, word).unwrap_or_else(|| format!(".long 0x{:08X}", word))
+}
@@ -0,0 +1,47 @@
+//! RIFF files consist of chunks that start with a four character code and
+//! their little endian size and are padded to an even size. Lists are chunks
+//! that contain more chunks after the kind of list. The file itself is a list
+//! whose kind is the form of the file, like `WAVE` or `AVI `.
+
+use byteorder::{ByteOrder, LE};
+use failure::Error;
+
+const CHUNK_HEADER_LEN: usize = 8;
+const FORM_HEADER_LEN: usize = 12;
+
+pub fn is_riff(data: &[u8], form: &[u8]) -> bool {
+    data.len() >= FORM_HEADER_LEN && &data[..4] == b"RIFF" && &data[8..12] == form
+}
+
+/// Splits the file into the IDs and contents of its chunks.
+pub fn form_chunks(data: &[u8]) -> Result<Vec<(&[u8], &[u8])>, Error> {
+    ensure!(data.len() >= FORM_HEADER_LEN, "The RIFF file is truncated");
+    let len = (LE::read_u32(&data[4..]) as usize + CHUNK_HEADER_LEN).min(data.len());
+    ensure!(len >= FORM_HEADER_LEN, "The RIFF file is truncated");
+    chunks(&data[FORM_HEADER_LEN..len])
+}
+
+/// Splits the contents of a list into the IDs and contents of its chunks.
+pub fn chunks(mut data: &[u8]) -> Result<Vec<(&[u8], &[u8])>, Error> {
+    let mut chunks = Vec::new();
+    while data.len() >= CHUNK_HEADER_LEN {
+        let len = LE::read_u32(&data[4..]) as usize;
+        ensure!(
+            CHUNK_HEADER_LEN + len <= data.len(),
+            "A chunk of the RIFF file is out of bounds"
+        );
+        chunks.push((&data[..4], &data[CHUNK_HEADER_LEN..][..len]));
+        let next = (CHUNK_HEADER_LEN + len + 1) & !1;
+        data = &data[next.min(data.len())..];
+    }
+    Ok(chunks)
+}
+
+/// Returns the contents of the chunk if it's a list of the given kind.
+pub fn list<'a>(id: &[u8], contents: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    if id == b"LIST" && contents.len() >= 4 && &contents[..4] == kind {
+        Some(&contents[4..])
+    } else {
+        None
+    }
+}
@@ -0,0 +1,427 @@
+//! Exports the changes of a Rom Hack as a Riivolution patch, so it can be
+//! applied to a Wii game at launch without modifying the disc. The output
+//! directory mirrors the root of an SD card. The optional features of the Rom
+//! Hack become options of their own, which patch the Rom Hack further.
+//! Features whose patches overlap share an option instead, so they can't be
+//! enabled together. Games that span multiple discs get an XML for each disc,
+//! as Riivolution only offers the options of the XMLs that match the disc.
+
+use byteorder::{ByteOrder, BE};
+use dol::DolFile;
+use failure::{err_msg, Error, ResultExt};
+use iso::header::Header;
+use iso::reader::{self, SystemData};
+use iso::virtual_file_system::{Directory, File, FileData, Node};
+use key_val_print::{Fields, KeyValPrint, MessageKind};
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io::{Read, Seek};
+use std::ops::Range;
+use std::path::Path;
+use std::str;
+
+/// Memory patches longer than this are stored in separate files instead of
+/// inline in the XML.
+const MAX_INLINE_LEN: usize = 0x40;
+
+/// The options of a disc and the patches they enable.
+pub struct Section {
+    game_id: String,
+    disc_number: u8,
+    name: String,
+    /// The ID of the patches, which also names the disc's XML.
+    id: String,
+    options: String,
+    patches: String,
+}
+
+/// Writes the patch of the Rom Hack along with the patches of its features,
+/// which were each built on top of the Rom Hack. The files and memory patches
+/// are written to the directory, which also serves as the ID of the patches.
+pub fn write_section<P: KeyValPrint, R: Read + Seek>(
+    printer: &P,
+    reader: &mut R,
+    system_data: &SystemData,
+    (patched, features): (&Directory, &[(String, Directory)]),
+    (name, dir): (&str, &str),
+    out_dir: &Path,
+) -> Result<Section, Error> {
+    let original = reader::load_iso(system_data).context("Couldn't parse the original game")?;
+    let game_id =
+        str::from_utf8(&system_data.header[..4]).context("The game ID is not valid ASCII")?;
+    let disc_number = Header::parse(&system_data.header)
+        .context("Couldn't parse the disc header")?
+        .disc_number;
+
+    let original_dol = DolFile::parse(&system_data.dol).context("Couldn't parse the DOL")?;
+    let patched_dol = read_dol(reader, patched).context("Couldn't read the patched DOL")?;
+
+    let id = dir.replace('/', "_");
+    let (mut options, mut patches) = (String::new(), String::new());
+    let changes = collect_changes(
+        printer,
+        reader,
+        (&original, &original_dol),
+        (patched, &patched_dol),
+        dir,
+        out_dir,
+    )?;
+    let choices = [(name, &id[..], &changes.xml[..])];
+    write_patch(&mut options, &mut patches, name, &choices)?;
+
+    let mut feature_changes = Vec::with_capacity(features.len());
+    for &(ref feature, ref feature_iso) in features {
+        let feature_dol = read_dol(reader, feature_iso)
+            .with_context(|_| format!("Couldn't read the DOL of the feature \"{}\"", feature))?;
+        let changes = collect_changes(
+            printer,
+            reader,
+            (patched, &patched_dol),
+            (feature_iso, &feature_dol),
+            &format!("{}/features/{}", dir, feature),
+            out_dir,
+        )?;
+        feature_changes.push((feature, format!("{}_{}", id, feature), changes));
+    }
+
+    // Each feature is built on top of the Rom Hack alone, so the patches of
+    // two features that change the same memory or files can't be applied
+    // together. Such features become choices of the same option, so only one
+    // of them can be enabled at a time.
+    let conflicts = |a: usize, b: usize| feature_changes[a].2.conflicts(&feature_changes[b].2);
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for index in 0..feature_changes.len() {
+        let conflicting = groups
+            .iter()
+            .enumerate()
+            .filter(|&(_, group)| group.iter().any(|&other| conflicts(index, other)))
+            .map(|(group_index, _)| group_index)
+            .collect::<Vec<_>>();
+        let mut group = vec![index];
+        for &group_index in conflicting.iter().rev() {
+            group.extend(groups.remove(group_index));
+        }
+        group.sort();
+        groups.push(group);
+    }
+    groups.sort();
+
+    for group in groups {
+        let choices = group
+            .iter()
+            .map(|&i| {
+                let (feature, ref id, ref changes) = feature_changes[i];
+                (&feature[..], &id[..], &changes.xml[..])
+            }).collect::<Vec<_>>();
+        if choices.len() > 1 {
+            printer.print(
+                Some(MessageKind::Warning),
+                "Combining",
+                &format!(
+                    "the features {} into one option, as they change the same memory or files",
+                    choices
+                        .iter()
+                        .map(|c| format!("\"{}\"", c.0))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            );
+        }
+        let option = choices.iter().map(|c| c.0).collect::<Vec<_>>().join(" / ");
+        write_patch(&mut options, &mut patches, &option, &choices)?;
+    }
+
+    Ok(Section {
+        game_id: game_id.to_owned(),
+        disc_number,
+        name: name.to_owned(),
+        id: id.clone(),
+        options,
+        patches,
+    })
+}
+
+/// Writes an XML for each of the discs, which need to be discs of the same
+/// game. The XMLs of games that span multiple discs are restricted to their
+/// discs.
+pub fn write_xml(sections: &[Section], out_dir: &Path) -> Result<(), Error> {
+    let game_id = &sections
+        .first()
+        .ok_or_else(|| err_msg("There are no discs to patch"))?
+        .game_id;
+    ensure!(
+        sections.iter().all(|s| s.game_id == *game_id),
+        "The discs are of different games"
+    );
+    for (index, section) in sections.iter().enumerate() {
+        if let Some(other) = sections[..index]
+            .iter()
+            .find(|s| s.disc_number == section.disc_number)
+        {
+            bail!(
+                "\"{}\" and \"{}\" are the same disc of the game",
+                other.name,
+                section.name
+            );
+        }
+    }
+
+    fs::create_dir_all(out_dir.join("riivolution"))
+        .context("Couldn't create the Riivolution directory")?;
+
+    for section in sections {
+        let disc = if sections.len() > 1 {
+            format!(" disc=\"{}\"", section.disc_number)
+        } else {
+            String::new()
+        };
+        let xml = format!(
+            r#"<wiidisc version="1">
+	<id game="{game_id}"{disc} />
+	<options>
+		<section name="{name}">
+{options}		</section>
+	</options>
+{patches}</wiidisc>
+"#,
+            game_id = escape(game_id),
+            disc = disc,
+            name = escape(&section.name),
+            options = section.options,
+            patches = section.patches
+        );
+
+        let xml_path = out_dir
+            .join("riivolution")
+            .join(format!("{}.xml", section.id));
+        fs::write(&xml_path, xml).context("Couldn't write the Riivolution XML")?;
+    }
+
+    Ok(())
+}
+
+fn read_dol<R: Read + Seek>(reader: &mut R, iso: &Directory) -> Result<DolFile, Error> {
+    let dol = iso
+        .main_dol()
+        .ok_or_else(|| err_msg("Dol file not found"))?
+        .read(reader)?;
+    DolFile::parse(&dol)
+}
+
+/// Adds an option with a choice for each of the patches, which are given by
+/// their names, IDs and changes. An option with a single patch is simply
+/// enabled or not.
+fn write_patch(
+    options: &mut String,
+    patches: &mut String,
+    option: &str,
+    choices: &[(&str, &str, &str)],
+) -> Result<(), Error> {
+    writeln!(options, "\t\t\t<option name=\"{}\">", escape(option))?;
+    for &(name, id, changes) in choices {
+        let name = if choices.len() == 1 { "Enabled" } else { name };
+        write!(
+            options,
+            r#"				<choice name="{name}">
+					<patch id="{id}" />
+				</choice>
+"#,
+            name = escape(name),
+            id = escape(id)
+        )?;
+        write!(
+            patches,
+            "\t<patch id=\"{}\">\n{}\t</patch>\n",
+            escape(id),
+            changes
+        )?;
+    }
+    writeln!(options, "\t\t\t</option>")?;
+    Ok(())
+}
+
+/// The changes of a patch, as the XML that applies them, along with the
+/// memory and files they change.
+struct Changes {
+    xml: String,
+    memory: Vec<Range<u32>>,
+    files: Vec<String>,
+}
+
+impl Changes {
+    fn conflicts(&self, other: &Changes) -> bool {
+        self.memory.iter().any(|a| {
+            other
+                .memory
+                .iter()
+                .any(|b| a.start < b.end && b.start < a.end)
+        }) || self.files.iter().any(|f| other.files.contains(f))
+    }
+}
+
+/// Collects the file and memory patches that turn the base game into the
+/// patched game. The files and memory patches are stored in the directory,
+/// which is relative to the root of the SD card.
+fn collect_changes<P: KeyValPrint, R: Read + Seek>(
+    printer: &P,
+    reader: &mut R,
+    (base, base_dol): (&Directory, &DolFile),
+    (patched, patched_dol): (&Directory, &DolFile),
+    dir: &str,
+    out_dir: &Path,
+) -> Result<Changes, Error> {
+    let patch_dir = out_dir.join(dir);
+    let mut changes = String::new();
+    let mut memory = Vec::new();
+    let mut changed_files = Vec::new();
+
+    let mut base_files = Vec::new();
+    collect_files(base, String::new(), &mut base_files);
+    for &(ref path, _) in &base_files {
+        if patched.resolve_path(&path[1..]).is_none() {
+            printer.print_with(
+                Some(MessageKind::Warning),
+                "Skipping",
+                &format!("removal of \"{}\", Riivolution can't remove files", path),
+                &Fields {
+                    file: Some(path),
+                    ..Default::default()
+                },
+            );
+        }
+    }
+
+    let mut patched_files = Vec::new();
+    collect_files(patched, String::new(), &mut patched_files);
+    for (path, file) in patched_files {
+        let data = match file.data {
+            FileData::Memory(ref data) => data,
+            FileData::Disc { .. } => continue,
+        };
+
+        let base_file = base.resolve_path(&path[1..]);
+        if let Some(base_file) = base_file {
+            let base_data = base_file
+                .read(reader)
+                .with_context(|_| format!("Couldn't read \"{}\"", path))?;
+            if base_data == *data {
+                continue;
+            }
+        }
+
+        let external = format!("/{}/files{}", dir, path);
+        let out_path = patch_dir.join("files").join(&path[1..]);
+        if let Some(parent) = out_path.parent() {
+            fs::create_dir_all(parent).context("Couldn't create a file directory")?;
+        }
+        fs::write(&out_path, data)
+            .with_context(|_| format!("Couldn't write the file \"{}\"", out_path.display()))?;
+
+        let create = if base_file.is_none() {
+            " create=\"true\""
+        } else {
+            ""
+        };
+        writeln!(
+            changes,
+            "\t\t<file disc=\"{}\" external=\"{}\"{} />",
+            escape(&path),
+            escape(&external),
+            create
+        )?;
+        changed_files.push(path);
+    }
+
+    for (address, data) in diff_memory(base_dol, patched_dol) {
+        memory.push(address..address + data.len() as u32);
+        if data.len() <= MAX_INLINE_LEN {
+            let mut value = String::with_capacity(2 * data.len());
+            for byte in data {
+                write!(value, "{:02X}", byte)?;
+            }
+            writeln!(
+                changes,
+                "\t\t<memory offset=\"0x{:08X}\" value=\"{}\" />",
+                address, value
+            )?;
+        } else {
+            let file_name = format!("{:08X}.bin", address);
+            let out_path = patch_dir.join("memory").join(&file_name);
+            fs::create_dir_all(patch_dir.join("memory"))
+                .context("Couldn't create the memory directory")?;
+            fs::write(&out_path, data)
+                .with_context(|_| format!("Couldn't write \"{}\"", out_path.display()))?;
+            writeln!(
+                changes,
+                "\t\t<memory offset=\"0x{:08X}\" valuefile=\"/{}/memory/{}\" />",
+                address,
+                escape(dir),
+                file_name
+            )?;
+        }
+    }
+
+    Ok(Changes {
+        xml: changes,
+        memory,
+        files: changed_files,
+    })
+}
+
+/// Collects all the files outside of the system data together with their
+/// absolute paths.
+fn collect_files<'a, 'b>(
+    dir: &'b Directory<'a>,
+    path: String,
+    files: &mut Vec<(String, &'b File<'a>)>,
+) {
+    for child in &dir.children {
+        match *child {
+            Node::Directory(ref child) => {
+                if path.is_empty() && child.name == "&&systemdata" {
+                    continue;
+                }
+                collect_files(child, format!("{}/{}", path, child.name), files);
+            }
+            Node::File(ref file) => files.push((format!("{}/{}", path, file.name), file)),
+        }
+    }
+}
+
+/// Finds the runs of bytes that the patched DOL loads into memory and that
+/// differ from what the original DOL loads there.
+fn diff_memory<'a>(original: &DolFile, patched: &'a DolFile) -> Vec<(u32, &'a [u8])> {
+    let mut runs = Vec::new();
+
+    for section in patched.text_sections.iter().chain(&patched.data_sections) {
+        let mut run_start = None;
+        for (index, word) in section.data.chunks(4).enumerate() {
+            let address = section.address + 4 * index as u32;
+            let is_changed =
+                word.len() < 4 || original.read_u32(address) != Some(BE::read_u32(word));
+            match (is_changed, run_start) {
+                (true, None) => run_start = Some(4 * index),
+                (false, Some(start)) => {
+                    runs.push((
+                        section.address + start as u32,
+                        &section.data[start..4 * index],
+                    ));
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(start) = run_start {
+            runs.push((section.address + start as u32, &section.data[start..]));
+        }
+    }
+
+    runs
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,66 @@
+//! Finds the addresses of functions in the DOL by their bytes, so a Rom Hack
+//! can refer to them without knowing where each version of the game places
+//! them. A signature is a string of hex bytes like `7C0802A6 9421??F0`, where
+//! `??` matches any byte. The signature needs to match exactly once.
+
+use dol::DolFile;
+use failure::Error;
+
+fn parse(signature: &str) -> Result<Vec<Option<u8>>, Error> {
+    let mut pattern = Vec::new();
+    for group in signature.split_whitespace() {
+        ensure!(
+            group.is_ascii() && group.len() % 2 == 0,
+            "\"{}\" doesn't consist of whole bytes",
+            group
+        );
+        for index in (0..group.len()).step_by(2) {
+            let byte = &group[index..index + 2];
+            if byte == "??" {
+                pattern.push(None);
+            } else {
+                let byte = u8::from_str_radix(byte, 16)
+                    .map_err(|_| format_err!("Invalid byte \"{}\"", byte))?;
+                pattern.push(Some(byte));
+            }
+        }
+    }
+    ensure!(
+        pattern.iter().any(|b| b.is_some()),
+        "The signature doesn't contain any bytes to match"
+    );
+    Ok(pattern)
+}
+
+/// Finds the address of the only place in the DOL's text sections that
+/// matches the signature.
+pub fn find(dol: &DolFile, signature: &str) -> Result<u32, Error> {
+    let pattern = parse(signature)?;
+
+    let mut matches = Vec::new();
+    for section in &dol.text_sections {
+        if section.data.len() < pattern.len() {
+            continue;
+        }
+        for start in 0..=section.data.len() - pattern.len() {
+            let is_match = pattern
+                .iter()
+                .zip(&section.data[start..])
+                .all(|(expected, &byte)| expected.map_or(true, |e| e == byte));
+            if is_match {
+                matches.push(section.address + start as u32);
+            }
+        }
+    }
+
+    match matches.len() {
+        0 => bail!("The signature doesn't match anything"),
+        1 => Ok(matches[0]),
+        _ => bail!(
+            "The signature matches {} places, like {:08X} and {:08X}",
+            matches.len(),
+            matches[0],
+            matches[1]
+        ),
+    }
+}
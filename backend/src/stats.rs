@@ -0,0 +1,154 @@
+//! Sums up how much a build adds to the DOL and how much memory is left for
+//! more, which matters once a Rom Hack gets close to the limits of the DOL
+//! and of the console's memory. The statistics are printed after every build
+//! and can also be written as JSON.
+
+use failure::{Error, ResultExt};
+use key_val_print::{KeyValPrint, MessageKind};
+use manifest::Manifest;
+use serde_json;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+
+#[derive(Serialize)]
+pub struct Stats {
+    /// The length of the DOL before it was patched.
+    pub original_len: u32,
+    pub patched_len: u32,
+    pub sections: Vec<Section>,
+    pub patches: Vec<Patch>,
+    /// The memory that is still free after placing all the generated code.
+    pub free_regions: Vec<Region>,
+}
+
+#[derive(Serialize)]
+pub struct Section {
+    pub kind: &'static str,
+    pub address: u32,
+    pub len: u32,
+}
+
+#[derive(Serialize)]
+pub struct Patch {
+    pub file: String,
+    pub patch: String,
+    /// The amount of bytes the patch writes to the file.
+    pub len: u32,
+}
+
+#[derive(Serialize)]
+pub struct Region {
+    pub start: u32,
+    pub end: u32,
+    pub len: u32,
+}
+
+impl Stats {
+    pub fn new(
+        original_len: u32,
+        patched_len: u32,
+        manifest: &Manifest,
+        free_regions: &[Range<u32>],
+    ) -> Self {
+        let sections = manifest
+            .sections
+            .iter()
+            .map(|s| Section {
+                kind: s.kind,
+                address: s.address,
+                len: s.len,
+            }).collect();
+
+        // The patches are listed in the order they first write a word in
+        let mut patches: Vec<Patch> = Vec::new();
+        let mut indices = HashMap::new();
+        for word in &manifest.words {
+            let key = (&word.file[..], &word.patch[..]);
+            if let Some(&index) = indices.get(&key) {
+                patches[index].len += word.len;
+                continue;
+            }
+            indices.insert(key, patches.len());
+            patches.push(Patch {
+                file: word.file.clone(),
+                patch: word.patch.clone(),
+                len: word.len,
+            });
+        }
+
+        let free_regions = free_regions
+            .iter()
+            .filter(|r| r.start < r.end)
+            .map(|r| Region {
+                start: r.start,
+                end: r.end,
+                len: r.end - r.start,
+            }).collect();
+
+        Stats {
+            original_len,
+            patched_len,
+            sections,
+            patches,
+            free_regions,
+        }
+    }
+
+    pub fn print<P: KeyValPrint>(&self, printer: &P) {
+        for section in &self.sections {
+            printer.print(
+                Some(MessageKind::Verbose),
+                "Added",
+                &format!(
+                    "{} section of {:#x} bytes at {:08X}",
+                    section.kind, section.len, section.address
+                ),
+            );
+        }
+        for patch in &self.patches {
+            printer.print(
+                Some(MessageKind::Verbose),
+                "Patched",
+                &format!(
+                    "{:#x} bytes of \"{}\" with {}",
+                    patch.len, patch.file, patch.patch
+                ),
+            );
+        }
+        for region in &self.free_regions {
+            printer.print(
+                Some(MessageKind::Verbose),
+                "Free",
+                &format!(
+                    "{:#x} bytes at {:08X}..{:08X}",
+                    region.len, region.start, region.end
+                ),
+            );
+        }
+
+        let (verb, difference) = if self.patched_len >= self.original_len {
+            ("Grew", self.patched_len - self.original_len)
+        } else {
+            ("Shrunk", self.original_len - self.patched_len)
+        };
+        printer.print(
+            None,
+            verb,
+            &format!(
+                "the DOL by {:#x} bytes to {:#x} bytes",
+                difference, self.patched_len
+            ),
+        );
+    }
+
+    pub fn create(&self, path: &Path) -> Result<(), Error> {
+        let mut file =
+            BufWriter::new(File::create(path).context("Couldn't create the statistics")?);
+        serde_json::to_writer_pretty(&mut file, self).context("Couldn't write the statistics")?;
+        file.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! Lowers the string replacements of the patch files. A string that fits
+//! where the original one is, including the zeros that pad it to the next
+//! word, is written in place. A longer string is relocated to free space
+//! instead of being truncated, and the pointers to the original string that
+//! the patch lists are rewritten to point to it. DOLs have no relocations, so
+//! a word that happens to contain the string's address can't be told apart
+//! from a pointer, which is why the pointers aren't searched for. Strings
+//! whose addresses are only built by code, like with `lis` and `addi`, can't
+//! be relocated this way. The end of the original string is found by the
+//! terminator of the text encoding, so it needs to be in the same encoding.
+
+use assembler::{bytes_to_instructions, Instruction, StringReplacement};
+use byteorder::{ByteOrder, BE};
+use dol::{DolFile, Section};
+use failure::Error;
+
+/// A string that was relocated and the amount of pointers to it that were
+/// rewritten.
+pub struct Relocation {
+    pub original: u32,
+    pub address: u32,
+    pub pointers: usize,
+}
+
+fn read_u8(dol: &DolFile, address: u32) -> Option<u8> {
+    dol.read_u32(address & !3)
+        .map(|word| (word >> (24 - 8 * (address & 3))) as u8)
+}
+
+fn read_data_word(game_data: &[Section], address: u32) -> Option<u32> {
+    if address & 3 != 0 {
+        return None;
+    }
+    game_data
+        .iter()
+        .find(|s| address >= s.address && address + 4 <= s.address + s.data.len() as u32)
+        .map(|s| BE::read_u32(&s.data[(address - s.address) as usize..]))
+}
+
+fn is_terminator(dol: &DolFile, string: &StringReplacement, address: u32) -> Result<bool, Error> {
+    for (offset, &byte) in string.terminator.iter().enumerate() {
+        match read_u8(dol, address + offset as u32) {
+            Some(b) if b == byte => {}
+            Some(_) => return Ok(false),
+            None => bail!(
+                "The string at {:08X} doesn't end within one of the DOL's sections",
+                string.address
+            ),
+        }
+    }
+    Ok(true)
+}
+
+/// The amount of bytes the original string takes up, including its
+/// terminator and the zeros up to the next word. The terminator is looked
+/// for in the encoding of the replacement.
+fn capacity(dol: &DolFile, string: &StringReplacement) -> Result<u32, Error> {
+    let mut end = string.address;
+    while !is_terminator(dol, string, end)? {
+        end += string.unit_len;
+    }
+    end += string.terminator.len() as u32;
+    while end & 3 != 0 && read_u8(dol, end) == Some(0) {
+        end += 1;
+    }
+    Ok(end - string.address)
+}
+
+fn needs_relocation(string: &StringReplacement, dol: &DolFile) -> Result<bool, Error> {
+    Ok(string.data.len() as u32 > capacity(dol, string)?)
+}
+
+/// The amount of bytes the strings that don't fit in place take up.
+pub fn relocated_len(strings: &[StringReplacement], dol: &DolFile) -> Result<u32, Error> {
+    let mut len = 0;
+    for string in strings {
+        if needs_relocation(string, dol)? {
+            len += (string.data.len() as u32 + 3) & !3;
+        }
+    }
+    Ok(len)
+}
+
+/// Lowers the strings to the instructions that write them and rewrite the
+/// pointers in the game's data, which consists of the data sections, and
+/// the section containing the relocated strings, which starts at the
+/// relocation address.
+pub fn lower(
+    strings: &[StringReplacement],
+    dol: &DolFile,
+    game_data: &[Section],
+    relocation_address: u32,
+) -> Result<(Vec<Instruction>, Option<Section>, Vec<Relocation>), Error> {
+    let mut instructions = Vec::new();
+    let mut relocated = Vec::new();
+    let mut relocations = Vec::new();
+
+    for string in strings {
+        if !needs_relocation(string, dol)? {
+            instructions.extend(bytes_to_instructions(string.address, &string.data));
+            continue;
+        }
+
+        ensure!(
+            !string.pointers.is_empty(),
+            "The string at {:08X} doesn't fit in place, so the pointers to it need to be listed \
+             to relocate it",
+            string.address
+        );
+        let address = relocation_address + relocated.len() as u32;
+        for &pointer in &string.pointers {
+            ensure!(
+                read_data_word(game_data, pointer) == Some(string.address),
+                "The word at {:08X} is not a pointer to the string at {:08X} in the game's data",
+                pointer,
+                string.address
+            );
+            instructions.push(Instruction {
+                address: pointer,
+                data: address,
+                mask: !0,
+            });
+        }
+        relocations.push(Relocation {
+            original: string.address,
+            address,
+            pointers: string.pointers.len(),
+        });
+
+        relocated.extend_from_slice(&string.data);
+        let len = (relocated.len() + 3) & !3;
+        relocated.resize(len, 0);
+    }
+
+    let section = if relocated.is_empty() {
+        None
+    } else {
+        Some(Section {
+            address: relocation_address,
+            data: relocated.into_boxed_slice(),
+        })
+    };
+
+    Ok((instructions, section, relocations))
+}
@@ -0,0 +1,138 @@
+//! Exports the functions and data of the Rom Hack for disassemblers, so their
+//! databases of the game can be annotated after every build. Paths ending in
+//! `.py` become Ghidra scripts, paths ending in `.idc` become IDA scripts and
+//! everything else becomes a plain list of addresses, sizes and names.
+
+use failure::{Error, ResultExt};
+use framework_map::{self, InjectedSymbol};
+use linker::{LinkedSection, SectionKind};
+use std::fs::File;
+use std::io::{prelude::*, BufWriter};
+use std::path::Path;
+
+// Run with Ghidra's Script Manager
+const GHIDRA_PRELUDE: &str = r#"# Names the Rom Hack's functions and data
+from ghidra.program.model.symbol import SourceType
+
+def define(address, name, is_function):
+    address = toAddr(address)
+    createLabel(address, name, True, SourceType.USER_DEFINED)
+    if is_function and getFunctionAt(address) is None:
+        disassemble(address)
+        createFunction(address, name)
+
+"#;
+
+// Run with IDA's File > Script file
+const IDA_PRELUDE: &str = r#"// Names the Rom Hack's functions and data
+#include <idc.idc>
+
+static main() {
+"#;
+
+struct Symbol {
+    address: u32,
+    len: u32,
+    name: String,
+    is_function: bool,
+}
+
+pub fn create(
+    path: &Path,
+    sections: &[LinkedSection],
+    injected: &[InjectedSymbol],
+) -> Result<(), Error> {
+    let mut symbols = sections
+        .iter()
+        .filter(|s| s.len > s.sym_offset)
+        .map(|s| Symbol {
+            address: s.address + s.sym_offset,
+            len: s.len - s.sym_offset,
+            name: framework_map::symbol_name(s).into_owned(),
+            is_function: s.kind == SectionKind::TextSection,
+        }).chain(injected.iter().map(|s| Symbol {
+            address: s.address,
+            len: s.len,
+            name: s.name.clone(),
+            // The patch labels and the generated stubs are all code
+            is_function: true,
+        })).collect::<Vec<_>>();
+    symbols.sort_by_key(|s| s.address);
+
+    let mut file = BufWriter::new(File::create(path).context("Couldn't create the symbol export")?);
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("py") => write_ghidra(&mut file, &symbols)?,
+        Some("idc") => write_ida(&mut file, &symbols)?,
+        _ => {
+            for symbol in &symbols {
+                writeln!(
+                    file,
+                    "{:08X} {:08X} {} {}",
+                    symbol.address,
+                    symbol.len,
+                    if symbol.is_function {
+                        "function"
+                    } else {
+                        "data"
+                    },
+                    symbol.name
+                )?;
+            }
+        }
+    }
+
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Disassemblers don't allow whitespace in their names, and the names are
+/// written as string literals of the scripts.
+fn script_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_whitespace() { '_' } else { c })
+        .flat_map(|c| {
+            if c == '"' || c == '\\' {
+                vec!['\\', c]
+            } else {
+                vec![c]
+            }
+        }).collect()
+}
+
+fn write_ghidra<W: Write>(file: &mut W, symbols: &[Symbol]) -> Result<(), Error> {
+    file.write_all(GHIDRA_PRELUDE.as_bytes())?;
+    for symbol in symbols {
+        writeln!(
+            file,
+            "define(0x{:08X}, \"{}\", {})",
+            symbol.address,
+            script_name(&symbol.name),
+            if symbol.is_function { "True" } else { "False" }
+        )?;
+    }
+    Ok(())
+}
+
+fn write_ida<W: Write>(file: &mut W, symbols: &[Symbol]) -> Result<(), Error> {
+    file.write_all(IDA_PRELUDE.as_bytes())?;
+    for symbol in symbols {
+        if symbol.is_function {
+            writeln!(
+                file,
+                "    add_func(0x{:08X}, 0x{:08X});",
+                symbol.address,
+                symbol.address + symbol.len
+            )?;
+        }
+        writeln!(
+            file,
+            "    set_name(0x{:08X}, \"{}\", SN_NOCHECK | SN_NOWARN);",
+            symbol.address,
+            script_name(&symbol.name)
+        )?;
+    }
+    writeln!(file, "}}")?;
+    Ok(())
+}
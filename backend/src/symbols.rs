@@ -0,0 +1,59 @@
+//! Parses the symbol maps written by the CodeWarrior linker and by Dolphin.
+//!
+//! CodeWarrior maps contain lines like
+//! `  00000000 000024 80003100  4 __start os.o`, where newer versions
+//! add the file offset as another column after the virtual address. Dolphin
+//! writes lines like `80003100 00000024 80003100 0 __start`.
+
+use demangle::demangle as demangle_tww;
+use failure::{Error, ResultExt};
+use std::collections::HashMap;
+use std::str;
+
+pub fn parse(buf: &[u8]) -> Result<HashMap<String, u32>, Error> {
+    let mut symbols = HashMap::new();
+    let text = str::from_utf8(buf).context("The symbol map has invalid UTF-8")?;
+
+    for line in text.lines() {
+        if let Some((name, address)) = parse_line(line) {
+            symbols.insert(
+                demangle_tww(name)
+                    .map(|n| n.into_owned())
+                    .unwrap_or_else(|_| name.to_owned()),
+                address,
+            );
+        }
+    }
+
+    Ok(symbols)
+}
+
+fn parse_line(line: &str) -> Option<(&str, u32)> {
+    let mut columns = line.split_whitespace();
+
+    let _offset = parse_hex(columns.next()?)?;
+    let _size = parse_hex(columns.next()?)?;
+    let address = parse_hex(columns.next()?)?;
+
+    let mut column = columns.next()?;
+    if column.len() == 8 && parse_hex(column).is_some() {
+        // The file offset of newer CodeWarrior maps
+        column = columns.next()?;
+    }
+    let _alignment = column.parse::<u32>().ok()?;
+
+    let name = columns.next()?;
+    // Section symbols like .text and .data are not interesting
+    if name.starts_with('.') || address == 0 {
+        return None;
+    }
+
+    Some((name, address))
+}
+
+fn parse_hex(column: &str) -> Option<u32> {
+    if column.is_empty() || column.len() > 8 {
+        return None;
+    }
+    u32::from_str_radix(column, 16).ok()
+}
@@ -0,0 +1,163 @@
+//! Encodes the text of the string replacements in the encoding of the game,
+//! as game text is rarely ASCII. Besides Shift-JIS and UTF-16BE, games with
+//! their own encodings are supported through TBL files, which map the bytes
+//! of the game to text, one entry per line:
+//!
+//! ```text
+//! 41=A
+//! 8260=Ａ
+//! *0A
+//! /FF
+//! ```
+//!
+//! An entry starting with `*` is a line break and one starting with `/` is
+//! the terminator of the strings, which is a zero byte otherwise. The text is
+//! encoded by always taking the longest entry that matches, and decoded by
+//! taking the longest bytes that match. The tables are used for the messages
+//! of BMG files as well.
+
+use encoding_rs::SHIFT_JIS;
+use failure::{Error, ResultExt};
+
+pub enum TextEncoding {
+    ShiftJis,
+    Utf16,
+    Table(Table),
+}
+
+pub struct Table {
+    /// Sorted by the length of their text, longest first.
+    entries: Vec<(String, Vec<u8>)>,
+    terminator: Vec<u8>,
+}
+
+impl TextEncoding {
+    /// Encodes the text without its terminator.
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, Error> {
+        match *self {
+            TextEncoding::ShiftJis => {
+                let (bytes, _, had_errors) = SHIFT_JIS.encode(text);
+                if had_errors {
+                    let c = text
+                        .chars()
+                        .find(|c| SHIFT_JIS.encode(&c.to_string()).2)
+                        .unwrap_or_default();
+                    bail!("The character '{}' can't be encoded in Shift-JIS", c);
+                }
+                Ok(bytes.into_owned())
+            }
+            TextEncoding::Utf16 => {
+                let mut bytes = Vec::with_capacity(2 * text.len());
+                for unit in text.encode_utf16() {
+                    bytes.push((unit >> 8) as u8);
+                    bytes.push(unit as u8);
+                }
+                Ok(bytes)
+            }
+            TextEncoding::Table(ref table) => table.encode(text),
+        }
+    }
+
+    pub fn terminator(&self) -> &[u8] {
+        match *self {
+            TextEncoding::ShiftJis => &[0],
+            TextEncoding::Utf16 => &[0, 0],
+            TextEncoding::Table(ref table) => &table.terminator,
+        }
+    }
+
+    /// The size of the units the text consists of. The terminator of a string
+    /// is only found at the start of a unit.
+    pub fn unit_len(&self) -> u32 {
+        match *self {
+            TextEncoding::Utf16 => 2,
+            _ => 1,
+        }
+    }
+}
+
+impl Table {
+    pub fn parse(text: &str) -> Result<Table, Error> {
+        let mut entries = Vec::new();
+        let mut terminator = None;
+
+        for (index, line) in text.trim_left_matches('\u{feff}').lines().enumerate() {
+            let line = line.trim_right_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            let (bytes, value) = match line.find('=') {
+                Some(position) => (&line[..position], Some(&line[position + 1..])),
+                None => (line, None),
+            };
+            let context = || format!("Invalid line {} of the table", index + 1);
+            if bytes.starts_with('/') {
+                terminator = Some(parse_bytes(&bytes[1..]).with_context(|_| context())?);
+            } else if bytes.starts_with('*') {
+                let bytes = parse_bytes(&bytes[1..]).with_context(|_| context())?;
+                entries.push(("\n".to_string(), bytes));
+            } else {
+                let value = value
+                    .filter(|v| !v.is_empty())
+                    .ok_or_else(|| format_err!("Line {} of the table has no text", index + 1))?;
+                let bytes = parse_bytes(bytes).with_context(|_| context())?;
+                entries.push((value.to_string(), bytes));
+            }
+        }
+
+        entries.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+        Ok(Table {
+            entries,
+            terminator: terminator.unwrap_or_else(|| vec![0]),
+        })
+    }
+
+    pub fn encode(&self, text: &str) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            let &(ref value, ref entry) = self
+                .entries
+                .iter()
+                .find(|&&(ref value, _)| rest.starts_with(&value[..]))
+                .ok_or_else(|| format_err!("The table has no entry for '{}'", c))?;
+            bytes.extend_from_slice(entry);
+            rest = &rest[value.len()..];
+        }
+        Ok(bytes)
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> Result<String, Error> {
+        let mut text = String::new();
+        let mut position = 0;
+        while position < bytes.len() {
+            let rest = &bytes[position..];
+            let &(ref value, ref entry) = self
+                .entries
+                .iter()
+                .filter(|&&(_, ref entry)| rest.starts_with(entry))
+                .max_by_key(|&&(_, ref entry)| entry.len())
+                .ok_or_else(|| {
+                    format_err!("The table has no entry for the byte {:02X}", rest[0])
+                })?;
+            text.push_str(value);
+            position += entry.len();
+        }
+        Ok(text)
+    }
+}
+
+fn parse_bytes(text: &str) -> Result<Vec<u8>, Error> {
+    let text = text.trim();
+    ensure!(
+        !text.is_empty() && text.is_ascii() && text.len() % 2 == 0,
+        "\"{}\" doesn't consist of whole bytes",
+        text
+    );
+    (0..text.len())
+        .step_by(2)
+        .map(|index| {
+            u8::from_str_radix(&text[index..index + 2], 16)
+                .map_err(|_| format_err!("Invalid byte \"{}\"", &text[index..index + 2]))
+        }).collect()
+}
@@ -0,0 +1,64 @@
+//! Based on http://wiki.tockdom.com/wiki/BTI_(File_Format)
+//!
+//! BTI files contain a single texture. The header is kept as it is, apart
+//! from the dimensions, the palette and the offsets, which are relative to
+//! the start of the header.
+
+use super::{encode, Format, PaletteFormat};
+use byteorder::{ByteOrder, BE};
+use failure::Error;
+use image::RgbaImage;
+
+const HEADER_LEN: usize = 0x20;
+const ALIGNMENT: usize = 0x20;
+
+const OFFSET_FORMAT: usize = 0x00;
+const OFFSET_WIDTH: usize = 0x02;
+const OFFSET_HEIGHT: usize = 0x04;
+const OFFSET_PALETTE_FORMAT: usize = 0x09;
+const OFFSET_NUM_PALETTE_ENTRIES: usize = 0x0A;
+const OFFSET_PALETTE_OFFSET: usize = 0x0C;
+const OFFSET_NUM_IMAGES: usize = 0x18;
+const OFFSET_DATA_OFFSET: usize = 0x1C;
+
+fn align(offset: usize) -> usize {
+    (offset + ALIGNMENT - 1) & !(ALIGNMENT - 1)
+}
+
+/// Replaces the texture with the image, encoding it in the original format
+/// with the same number of mipmaps.
+pub fn replace_texture(original: &[u8], image: &RgbaImage) -> Result<Vec<u8>, Error> {
+    ensure!(original.len() >= HEADER_LEN, "The BTI file is truncated");
+    let format = Format::from_id(original[OFFSET_FORMAT] as u32)?;
+    let palette_format = if format.uses_palette() {
+        PaletteFormat::from_id(original[OFFSET_PALETTE_FORMAT] as u32)?
+    } else {
+        PaletteFormat::Rgb5a3
+    };
+    let levels = (original[OFFSET_NUM_IMAGES] as usize).max(1);
+    ensure!(
+        image.width() <= 0xFFFF && image.height() <= 0xFFFF,
+        "The image is too large"
+    );
+
+    let (data, palette) = encode(image, format, palette_format, levels)?;
+
+    let mut out = original[..HEADER_LEN].to_owned();
+    BE::write_u16(&mut out[OFFSET_WIDTH..], image.width() as u16);
+    BE::write_u16(&mut out[OFFSET_HEIGHT..], image.height() as u16);
+
+    let num_entries = palette.len() / 2;
+    let palette_offset = if num_entries > 0 { out.len() } else { 0 };
+    BE::write_u16(&mut out[OFFSET_NUM_PALETTE_ENTRIES..], num_entries as u16);
+    BE::write_u32(&mut out[OFFSET_PALETTE_OFFSET..], palette_offset as u32);
+    out.extend_from_slice(&palette);
+
+    let data_offset = align(out.len());
+    out.resize(data_offset, 0);
+    BE::write_u32(&mut out[OFFSET_DATA_OFFSET..], data_offset as u32);
+    out.extend_from_slice(&data);
+
+    let len = align(out.len());
+    out.resize(len, 0);
+    Ok(out)
+}
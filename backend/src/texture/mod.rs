@@ -0,0 +1,491 @@
+//! Based on http://wiki.tockdom.com/wiki/Image_Formats
+//!
+//! Encodes images into the texture formats of the GameCube's and the Wii's
+//! GPU. The textures are split into blocks of 32 bytes, which are stored row
+//! by row, while the pixels inside of each block are stored row by row as
+//! well. The color index formats refer to a separate palette. The formats
+//! of the replaced textures are kept, so the games can load them as before.
+
+use byteorder::{ByteOrder, BE};
+use failure::Error;
+use image::{imageops, FilterType, RgbaImage};
+use std::collections::HashMap;
+use std::mem;
+
+pub mod bti;
+pub mod tpl;
+
+const BLOCK_LEN: usize = 32;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Format {
+    I4,
+    I8,
+    Ia4,
+    Ia8,
+    Rgb565,
+    Rgb5a3,
+    Rgba8,
+    C4,
+    C8,
+    Cmpr,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PaletteFormat {
+    Ia8,
+    Rgb565,
+    Rgb5a3,
+}
+
+impl Format {
+    pub fn from_id(id: u32) -> Result<Self, Error> {
+        Ok(match id {
+            0x0 => Format::I4,
+            0x1 => Format::I8,
+            0x2 => Format::Ia4,
+            0x3 => Format::Ia8,
+            0x4 => Format::Rgb565,
+            0x5 => Format::Rgb5a3,
+            0x6 => Format::Rgba8,
+            0x8 => Format::C4,
+            0x9 => Format::C8,
+            0xE => Format::Cmpr,
+            _ => bail!("The texture format {:#X} is not supported", id),
+        })
+    }
+
+    /// The width and the height of each block in pixels.
+    fn block_dimensions(self) -> (usize, usize) {
+        match self {
+            Format::I4 | Format::C4 | Format::Cmpr => (8, 8),
+            Format::I8 | Format::Ia4 | Format::C8 => (8, 4),
+            Format::Ia8 | Format::Rgb565 | Format::Rgb5a3 | Format::Rgba8 => (4, 4),
+        }
+    }
+
+    fn block_len(self) -> usize {
+        // RGBA8 stores each block in two passes
+        if self == Format::Rgba8 {
+            2 * BLOCK_LEN
+        } else {
+            BLOCK_LEN
+        }
+    }
+
+    pub fn uses_palette(self) -> bool {
+        self == Format::C4 || self == Format::C8
+    }
+
+    /// The size of a texture with the given dimensions and mipmap count.
+    pub fn data_len(self, width: usize, height: usize, levels: usize) -> usize {
+        let (block_width, block_height) = self.block_dimensions();
+        (0..levels)
+            .map(|level| {
+                let (width, height) = level_dimensions(width, height, level);
+                let blocks_x = (width + block_width - 1) / block_width;
+                let blocks_y = (height + block_height - 1) / block_height;
+                blocks_x * blocks_y * self.block_len()
+            })
+            .sum()
+    }
+}
+
+impl PaletteFormat {
+    pub fn from_id(id: u32) -> Result<Self, Error> {
+        Ok(match id {
+            0 => PaletteFormat::Ia8,
+            1 => PaletteFormat::Rgb565,
+            2 => PaletteFormat::Rgb5a3,
+            _ => bail!("The palette format {} is not supported", id),
+        })
+    }
+
+    fn encode(self, pixel: [u8; 4]) -> u16 {
+        match self {
+            PaletteFormat::Ia8 => (pixel[3] as u16) << 8 | intensity(pixel) as u16,
+            PaletteFormat::Rgb565 => rgba_to_rgb565(pixel),
+            PaletteFormat::Rgb5a3 => BE::read_u16(&rgba_to_rgb5a3(&pixel)),
+        }
+    }
+
+    fn decode(self, value: u16) -> [u8; 4] {
+        match self {
+            PaletteFormat::Ia8 => {
+                let i = value as u8;
+                [i, i, i, (value >> 8) as u8]
+            }
+            PaletteFormat::Rgb565 => rgb565_to_rgba(value),
+            PaletteFormat::Rgb5a3 => {
+                let mut buf = [0; 2];
+                BE::write_u16(&mut buf, value);
+                rgb5a3_to_rgba(&buf)
+            }
+        }
+    }
+}
+
+/// The dimensions of a mipmap, which halve with each level.
+fn level_dimensions(width: usize, height: usize, level: usize) -> (usize, usize) {
+    ((width >> level).max(1), (height >> level).max(1))
+}
+
+/// The most mipmaps a texture with these dimensions can have.
+pub fn max_levels(width: usize, height: usize) -> usize {
+    let mut levels = 1;
+    while width >> levels > 0 || height >> levels > 0 {
+        levels += 1;
+    }
+    levels
+}
+
+fn intensity(p: [u8; 4]) -> u8 {
+    (0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32).round() as u8
+}
+
+fn scale_down(c: u8, bits: u32) -> u16 {
+    (c as f32 * (((1 << bits) - 1) as f32 / 255.0)).round() as u16
+}
+
+fn scale_up(c: u16, bits: u32) -> u8 {
+    let max = (1 << bits) - 1;
+    ((c & max) as f32 * (255.0 / max as f32)).round() as u8
+}
+
+fn rgba_to_rgb565(p: [u8; 4]) -> u16 {
+    scale_down(p[0], 5) << 11 | scale_down(p[1], 6) << 5 | scale_down(p[2], 5)
+}
+
+fn rgb565_to_rgba(v: u16) -> [u8; 4] {
+    [
+        scale_up(v >> 11, 5),
+        scale_up(v >> 5, 6),
+        scale_up(v, 5),
+        255,
+    ]
+}
+
+/// Decodes a pixel in the RGB5A3 format. Opaque pixels have the top bit set
+/// and 5 bits per color, while translucent ones have 3 bits of alpha and 4
+/// bits per color.
+pub fn rgb5a3_to_rgba(v: &[u8]) -> [u8; 4] {
+    let v = BE::read_u16(v);
+    if v & 0x8000 != 0 {
+        // 1RRRRRGG GGGBBBBB
+        [
+            scale_up(v >> 10, 5),
+            scale_up(v >> 5, 5),
+            scale_up(v, 5),
+            255,
+        ]
+    } else {
+        // 0AAARRRR GGGGBBBB
+        [
+            scale_up(v >> 8, 4),
+            scale_up(v >> 4, 4),
+            scale_up(v, 4),
+            scale_up(v >> 12, 3),
+        ]
+    }
+}
+
+pub fn rgba_to_rgb5a3(v: &[u8]) -> [u8; 2] {
+    let (r, g, b, a) = (v[0], v[1], v[2], v[3]);
+    let value = if a >= 0xE0 {
+        0x8000 | scale_down(r, 5) << 10 | scale_down(g, 5) << 5 | scale_down(b, 5)
+    } else {
+        scale_down(a, 3) << 12 | scale_down(r, 4) << 8 | scale_down(g, 4) << 4 | scale_down(b, 4)
+    };
+    let mut buf = [0; 2];
+    BE::write_u16(&mut buf, value);
+    buf
+}
+
+/// Encodes the image and the given number of mipmaps, which are scaled down
+/// from it. The color index formats also return the palette, which is shared
+/// by all the mipmaps.
+pub fn encode(
+    image: &RgbaImage,
+    format: Format,
+    palette_format: PaletteFormat,
+    levels: usize,
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    ensure!(
+        levels <= max_levels(width, height),
+        "The image is too small for {} mipmaps",
+        levels
+    );
+
+    let palette = if format.uses_palette() {
+        let max_colors = if format == Format::C4 { 16 } else { 256 };
+        build_palette(image, palette_format, max_colors)
+    } else {
+        Vec::new()
+    };
+
+    let mut data = Vec::with_capacity(format.data_len(width, height, levels));
+    for level in 0..levels {
+        let (level_width, level_height) = level_dimensions(width, height, level);
+        if level == 0 {
+            encode_level(image, format, &palette, palette_format, &mut data);
+        } else {
+            let mipmap = imageops::resize(
+                image,
+                level_width as u32,
+                level_height as u32,
+                FilterType::Triangle,
+            );
+            encode_level(&mipmap, format, &palette, palette_format, &mut data);
+        }
+    }
+
+    let mut palette_data = vec![0; 2 * palette.len()];
+    for (color, dst) in palette.iter().zip(palette_data.chunks_mut(2)) {
+        BE::write_u16(dst, palette_format.encode(*color));
+    }
+
+    Ok((data, palette_data))
+}
+
+fn pixel(image: &RgbaImage, x: usize, y: usize) -> [u8; 4] {
+    if x < image.width() as usize && y < image.height() as usize {
+        image.get_pixel(x as u32, y as u32).data
+    } else {
+        [0; 4]
+    }
+}
+
+fn encode_level(
+    image: &RgbaImage,
+    format: Format,
+    palette: &[[u8; 4]],
+    palette_format: PaletteFormat,
+    out: &mut Vec<u8>,
+) {
+    let (block_width, block_height) = format.block_dimensions();
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut nearest_indices = HashMap::new();
+
+    for block_y in (0..height).step_by(block_height) {
+        for block_x in (0..width).step_by(block_width) {
+            if format == Format::Cmpr {
+                for &(x, y) in &[(0, 0), (4, 0), (0, 4), (4, 4)] {
+                    encode_cmpr_block(image, block_x + x, block_y + y, out);
+                }
+                continue;
+            }
+
+            let pixels = (0..block_height)
+                .flat_map(|y| (0..block_width).map(move |x| (x, y)))
+                .map(|(x, y)| pixel(image, block_x + x, block_y + y))
+                .collect::<Vec<_>>();
+
+            match format {
+                Format::I4 => {
+                    for pair in pixels.chunks(2) {
+                        out.push(intensity(pair[0]) & 0xF0 | intensity(pair[1]) >> 4);
+                    }
+                }
+                Format::I8 => out.extend(pixels.iter().map(|&p| intensity(p))),
+                Format::Ia4 => out.extend(pixels.iter().map(|&p| p[3] & 0xF0 | intensity(p) >> 4)),
+                Format::Ia8 => {
+                    for &p in &pixels {
+                        out.push(p[3]);
+                        out.push(intensity(p));
+                    }
+                }
+                Format::Rgb565 => {
+                    for &p in &pixels {
+                        let mut buf = [0; 2];
+                        BE::write_u16(&mut buf, rgba_to_rgb565(p));
+                        out.extend_from_slice(&buf);
+                    }
+                }
+                Format::Rgb5a3 => {
+                    for p in &pixels {
+                        out.extend_from_slice(&rgba_to_rgb5a3(p));
+                    }
+                }
+                Format::Rgba8 => {
+                    for p in &pixels {
+                        out.push(p[3]);
+                        out.push(p[0]);
+                    }
+                    for p in &pixels {
+                        out.push(p[1]);
+                        out.push(p[2]);
+                    }
+                }
+                Format::C4 | Format::C8 => {
+                    let indices = pixels
+                        .iter()
+                        .map(|&p| {
+                            *nearest_indices
+                                .entry(p)
+                                .or_insert_with(|| nearest(palette, palette_format, p))
+                        })
+                        .collect::<Vec<_>>();
+                    if format == Format::C4 {
+                        for pair in indices.chunks(2) {
+                            out.push((pair[0] << 4 | pair[1]) as u8);
+                        }
+                    } else {
+                        out.extend(indices.iter().map(|&i| i as u8));
+                    }
+                }
+                Format::Cmpr => unreachable!(),
+            }
+        }
+    }
+}
+
+fn distance(a: [u8; 4], b: [u8; 4]) -> u32 {
+    a.iter()
+        .zip(&b)
+        .map(|(&a, &b)| (a as i32 - b as i32).pow(2) as u32)
+        .sum()
+}
+
+/// Finds the palette entry that looks the most like the color once it's
+/// decoded by the GPU.
+fn nearest(palette: &[[u8; 4]], palette_format: PaletteFormat, color: [u8; 4]) -> usize {
+    (0..palette.len())
+        .min_by_key(|&i| {
+            distance(
+                palette_format.decode(palette_format.encode(palette[i])),
+                color,
+            )
+        })
+        .unwrap_or(0)
+}
+
+/// Reduces the colors of the image to fit into the palette by repeatedly
+/// splitting the group of colors with the widest range of any channel at its
+/// median.
+fn build_palette(
+    image: &RgbaImage,
+    palette_format: PaletteFormat,
+    max_colors: usize,
+) -> Vec<[u8; 4]> {
+    let colors = image
+        .pixels()
+        .map(|p| palette_format.decode(palette_format.encode(p.data)))
+        .collect::<Vec<_>>();
+
+    let mut unique = colors.clone();
+    unique.sort();
+    unique.dedup();
+    if unique.len() <= max_colors {
+        return unique;
+    }
+
+    let range = |colors: &[[u8; 4]], channel: usize| {
+        let min = colors.iter().map(|c| c[channel]).min().unwrap_or(0);
+        let max = colors.iter().map(|c| c[channel]).max().unwrap_or(0);
+        max - min
+    };
+
+    let mut groups = vec![colors];
+    while groups.len() < max_colors {
+        let widest = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(index, group)| {
+                (0..4).map(move |channel| (index, channel, range(group, channel)))
+            })
+            .max_by_key(|&(_, _, range)| range);
+        let (index, channel) = match widest {
+            Some((index, channel, range)) if range > 0 => (index, channel),
+            _ => break,
+        };
+
+        let mut group = groups.swap_remove(index);
+        group.sort_by_key(|c| c[channel]);
+        let upper = group.split_off(group.len() / 2);
+        groups.push(group);
+        groups.push(upper);
+    }
+
+    groups
+        .iter()
+        .map(|group| {
+            let mut sum = [0usize; 4];
+            for color in group {
+                for (sum, &c) in sum.iter_mut().zip(color) {
+                    *sum += c as usize;
+                }
+            }
+            let mut average = [0; 4];
+            for (average, sum) in average.iter_mut().zip(&sum) {
+                *average = (sum / group.len()) as u8;
+            }
+            average
+        })
+        .collect()
+}
+
+/// Encodes a 4x4 block of CMPR, which is DXT1 with big endian colors and the
+/// indices of the first pixels in the top bits. Blocks with transparent
+/// pixels use the mode with three colors.
+fn encode_cmpr_block(image: &RgbaImage, x: usize, y: usize, out: &mut Vec<u8>) {
+    let pixels = (0..4)
+        .flat_map(|dy| (0..4).map(move |dx| (dx, dy)))
+        .map(|(dx, dy)| pixel(image, x + dx, y + dy))
+        .collect::<Vec<_>>();
+    let opaque = pixels.iter().filter(|p| p[3] >= 0x80).collect::<Vec<_>>();
+    let has_alpha = opaque.len() < pixels.len();
+
+    // The two colors furthest apart become the end points
+    let (mut c0, mut c1) = (0, 0);
+    let mut max_distance = 0;
+    for (i, &&a) in opaque.iter().enumerate() {
+        for &&b in &opaque[i..] {
+            let d = distance(a, b);
+            if d >= max_distance {
+                max_distance = d;
+                c0 = rgba_to_rgb565(a);
+                c1 = rgba_to_rgb565(b);
+            }
+        }
+    }
+    if has_alpha == (c0 > c1) {
+        mem::swap(&mut c0, &mut c1);
+    }
+
+    let (p0, p1) = (rgb565_to_rgba(c0), rgb565_to_rgba(c1));
+    let mix = |a: u8, b: u8, wa: u16, wb: u16| ((a as u16 * wa + b as u16 * wb) / (wa + wb)) as u8;
+    let mix_colors = |wa, wb| {
+        [
+            mix(p0[0], p1[0], wa, wb),
+            mix(p0[1], p1[1], wa, wb),
+            mix(p0[2], p1[2], wa, wb),
+            255,
+        ]
+    };
+    let colors = if has_alpha {
+        vec![p0, p1, mix_colors(1, 1)]
+    } else {
+        vec![p0, p1, mix_colors(2, 1), mix_colors(1, 2)]
+    };
+
+    let mut buf = [0; 4];
+    BE::write_u16(&mut buf, c0);
+    BE::write_u16(&mut buf[2..], c1);
+    out.extend_from_slice(&buf);
+
+    for row in pixels.chunks(4) {
+        let mut byte = 0;
+        for (index, &p) in row.iter().enumerate() {
+            let color_index = if p[3] < 0x80 {
+                3
+            } else {
+                (0..colors.len())
+                    .min_by_key(|&i| distance(colors[i], p))
+                    .unwrap_or(0)
+            };
+            byte |= (color_index as u8) << (6 - 2 * index);
+        }
+        out.push(byte);
+    }
+}
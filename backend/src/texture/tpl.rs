@@ -0,0 +1,194 @@
+//! Based on http://wiki.tockdom.com/wiki/TPL_(File_Format)
+//!
+//! TPL files contain a list of textures, each with its own header and an
+//! optional palette. Only the first texture is replaced, the others are
+//! copied over as they are.
+
+use super::{encode, Format, PaletteFormat};
+use byteorder::{ByteOrder, BE};
+use failure::Error;
+use image::RgbaImage;
+
+const MAGIC: u32 = 0x0020_AF30;
+const HEADER_LEN: usize = 0x0C;
+const TABLE_ENTRY_LEN: usize = 8;
+const IMAGE_HEADER_LEN: usize = 0x24;
+const PALETTE_HEADER_LEN: usize = 0x0C;
+const ALIGNMENT: usize = 0x20;
+
+const OFFSET_NUM_IMAGES: usize = 0x04;
+const OFFSET_TABLE_OFFSET: usize = 0x08;
+
+// Relative to the image header
+const OFFSET_HEIGHT: usize = 0x00;
+const OFFSET_WIDTH: usize = 0x02;
+const OFFSET_FORMAT: usize = 0x04;
+const OFFSET_DATA_OFFSET: usize = 0x08;
+const OFFSET_MIN_FILTER: usize = 0x14;
+const OFFSET_MAX_LOD: usize = 0x22;
+
+// Relative to the palette header
+const OFFSET_NUM_ENTRIES: usize = 0x00;
+const OFFSET_PALETTE_FORMAT: usize = 0x04;
+const OFFSET_PALETTE_DATA_OFFSET: usize = 0x08;
+
+/// The first minification filter that uses mipmaps.
+const FILTER_NEAR_MIP_NEAR: u32 = 2;
+
+struct Texture {
+    header: Vec<u8>,
+    data: Vec<u8>,
+    palette: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+fn align(offset: usize) -> usize {
+    (offset + ALIGNMENT - 1) & !(ALIGNMENT - 1)
+}
+
+fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8], Error> {
+    ensure!(offset + len <= data.len(), "The TPL file is truncated");
+    Ok(&data[offset..][..len])
+}
+
+pub fn is_tpl(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN && BE::read_u32(data) == MAGIC
+}
+
+/// Replaces the first texture with the image, encoding it in the original
+/// format with the same number of mipmaps.
+pub fn replace_texture(original: &[u8], image: &RgbaImage) -> Result<Vec<u8>, Error> {
+    ensure!(is_tpl(original), "The file is not a TPL file");
+    let num_images = BE::read_u32(&original[OFFSET_NUM_IMAGES..]) as usize;
+    let table_offset = BE::read_u32(&original[OFFSET_TABLE_OFFSET..]) as usize;
+    let table = slice(original, table_offset, num_images * TABLE_ENTRY_LEN)?;
+    ensure!(num_images > 0, "The TPL file contains no textures");
+
+    let mut textures = Vec::with_capacity(num_images);
+    for entry in table.chunks(TABLE_ENTRY_LEN) {
+        let header = slice(original, BE::read_u32(entry) as usize, IMAGE_HEADER_LEN)?;
+        let format = Format::from_id(BE::read_u32(&header[OFFSET_FORMAT..]))?;
+        let width = BE::read_u16(&header[OFFSET_WIDTH..]) as usize;
+        let height = BE::read_u16(&header[OFFSET_HEIGHT..]) as usize;
+        let data_offset = BE::read_u32(&header[OFFSET_DATA_OFFSET..]) as usize;
+        let len = format.data_len(width, height, levels(header));
+
+        let palette_offset = BE::read_u32(&entry[4..]) as usize;
+        let palette = if palette_offset != 0 {
+            let palette_header = slice(original, palette_offset, PALETTE_HEADER_LEN)?;
+            let num_entries = BE::read_u16(&palette_header[OFFSET_NUM_ENTRIES..]) as usize;
+            let palette_data_offset =
+                BE::read_u32(&palette_header[OFFSET_PALETTE_DATA_OFFSET..]) as usize;
+            Some((
+                palette_header.to_owned(),
+                slice(original, palette_data_offset, 2 * num_entries)?.to_owned(),
+            ))
+        } else {
+            None
+        };
+
+        textures.push(Texture {
+            header: header.to_owned(),
+            data: slice(original, data_offset, len)?.to_owned(),
+            palette,
+        });
+    }
+
+    {
+        let texture = &mut textures[0];
+        let format = Format::from_id(BE::read_u32(&texture.header[OFFSET_FORMAT..]))?;
+        let palette_format = match texture.palette {
+            Some((ref header, _)) => {
+                PaletteFormat::from_id(BE::read_u32(&header[OFFSET_PALETTE_FORMAT..]))?
+            }
+            None => PaletteFormat::Rgb5a3,
+        };
+        ensure!(
+            image.width() <= 0xFFFF && image.height() <= 0xFFFF,
+            "The image is too large"
+        );
+
+        let (data, palette) = encode(image, format, palette_format, levels(&texture.header))?;
+        BE::write_u16(&mut texture.header[OFFSET_WIDTH..], image.width() as u16);
+        BE::write_u16(&mut texture.header[OFFSET_HEIGHT..], image.height() as u16);
+        texture.data = data;
+        if let Some((ref mut header, ref mut palette_data)) = texture.palette {
+            BE::write_u16(
+                &mut header[OFFSET_NUM_ENTRIES..],
+                (palette.len() / 2) as u16,
+            );
+            *palette_data = palette;
+        }
+    }
+
+    write(&mut textures)
+}
+
+/// The number of mipmaps, including the full size texture.
+fn levels(header: &[u8]) -> usize {
+    if BE::read_u32(&header[OFFSET_MIN_FILTER..]) >= FILTER_NEAR_MIP_NEAR {
+        header[OFFSET_MAX_LOD] as usize + 1
+    } else {
+        1
+    }
+}
+
+fn write(textures: &mut [Texture]) -> Result<Vec<u8>, Error> {
+    let table_offset = HEADER_LEN;
+    let mut offset = table_offset + textures.len() * TABLE_ENTRY_LEN;
+    let mut header_offsets = Vec::with_capacity(textures.len());
+    for texture in textures.iter() {
+        let palette_header_offset = offset + IMAGE_HEADER_LEN;
+        header_offsets.push((
+            offset,
+            texture.palette.as_ref().map(|_| palette_header_offset),
+        ));
+        offset = palette_header_offset;
+        if texture.palette.is_some() {
+            offset += PALETTE_HEADER_LEN;
+        }
+    }
+
+    let mut out = vec![0; offset];
+    BE::write_u32(&mut out, MAGIC);
+    BE::write_u32(&mut out[OFFSET_NUM_IMAGES..], textures.len() as u32);
+    BE::write_u32(&mut out[OFFSET_TABLE_OFFSET..], table_offset as u32);
+
+    for (index, (texture, &(header_offset, palette_header_offset))) in
+        textures.iter_mut().zip(&header_offsets).enumerate()
+    {
+        let entry = &mut out[table_offset + index * TABLE_ENTRY_LEN..][..TABLE_ENTRY_LEN];
+        BE::write_u32(entry, header_offset as u32);
+        BE::write_u32(&mut entry[4..], palette_header_offset.unwrap_or(0) as u32);
+
+        if let Some((ref mut header, ref palette_data)) = texture.palette {
+            let data_offset = align(out.len());
+            out.resize(data_offset, 0);
+            out.extend_from_slice(palette_data);
+            BE::write_u32(
+                &mut header[OFFSET_PALETTE_DATA_OFFSET..],
+                data_offset as u32,
+            );
+        }
+
+        let data_offset = align(out.len());
+        out.resize(data_offset, 0);
+        out.extend_from_slice(&texture.data);
+        BE::write_u32(
+            &mut texture.header[OFFSET_DATA_OFFSET..],
+            data_offset as u32,
+        );
+    }
+
+    for (texture, &(header_offset, palette_header_offset)) in textures.iter().zip(&header_offsets) {
+        out[header_offset..][..IMAGE_HEADER_LEN].copy_from_slice(&texture.header);
+        if let (Some(&(ref header, _)), Some(offset)) =
+            (texture.palette.as_ref(), palette_header_offset)
+        {
+            out[offset..][..PALETTE_HEADER_LEN].copy_from_slice(header);
+        }
+    }
+
+    let len = align(out.len());
+    out.resize(len, 0);
+    Ok(out)
+}
@@ -0,0 +1,189 @@
+//! Based on https://docs.microsoft.com/en-us/windows/desktop/directshow/avi-riff-file-reference
+//!
+//! AVI files are RIFF files, which contain a list of the streams' headers and
+//! a list of the streams' data. Only the first video and the first audio
+//! stream are read, which need to be MJPEG and 16 bit PCM.
+
+use byteorder::{ByteOrder, LE};
+use failure::Error;
+use riff::{self, chunks, list};
+
+// Relative to the stream header
+const OFFSET_STREAM_TYPE: usize = 0x00;
+const OFFSET_SCALE: usize = 0x14;
+const OFFSET_RATE: usize = 0x18;
+const STREAM_HEADER_LEN: usize = 0x1C;
+
+// Relative to the BITMAPINFOHEADER of video streams
+const OFFSET_WIDTH: usize = 0x04;
+const OFFSET_HEIGHT: usize = 0x08;
+const OFFSET_COMPRESSION: usize = 0x10;
+const BITMAP_INFO_LEN: usize = 0x14;
+
+// Relative to the WAVEFORMATEX of audio streams
+const OFFSET_FORMAT_TAG: usize = 0x00;
+const OFFSET_CHANNELS: usize = 0x02;
+const OFFSET_FREQUENCY: usize = 0x04;
+const OFFSET_BITS_PER_SAMPLE: usize = 0x0E;
+const WAVE_FORMAT_LEN: usize = 0x10;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+
+pub struct Video<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub fps: f32,
+    /// The JPEG images of the frames.
+    pub frames: Vec<&'a [u8]>,
+    pub audio: Option<Audio>,
+}
+
+pub struct Audio {
+    pub channels: usize,
+    pub frequency: u32,
+    /// The samples of all the channels, interleaved.
+    pub samples: Vec<i16>,
+}
+
+struct Stream<'a> {
+    kind: &'a [u8],
+    header: &'a [u8],
+    format: &'a [u8],
+}
+
+pub fn is_avi(data: &[u8]) -> bool {
+    riff::is_riff(data, b"AVI ")
+}
+
+/// Collects the chunks of the streams, flattening the `rec ` lists that
+/// group chunks of different streams.
+fn stream_chunks<'a>(
+    data: &'a [u8],
+    chunks_out: &mut Vec<(&'a [u8], &'a [u8])>,
+) -> Result<(), Error> {
+    for (id, contents) in chunks(data)? {
+        if let Some(contents) = list(id, contents, b"rec ") {
+            stream_chunks(contents, chunks_out)?;
+        } else {
+            chunks_out.push((id, contents));
+        }
+    }
+    Ok(())
+}
+
+pub fn parse(data: &[u8]) -> Result<Video, Error> {
+    ensure!(is_avi(data), "The file is not an AVI file");
+
+    let mut streams = Vec::new();
+    let mut movie = None;
+    for (id, contents) in riff::form_chunks(data)? {
+        if let Some(header_list) = list(id, contents, b"hdrl") {
+            for (id, contents) in chunks(header_list)? {
+                if let Some(stream_list) = list(id, contents, b"strl") {
+                    let stream_list = chunks(stream_list)?;
+                    let find = |kind: &[u8]| {
+                        stream_list
+                            .iter()
+                            .find(|&&(id, _)| id == kind)
+                            .map(|&(_, contents)| contents)
+                    };
+                    match (find(b"strh"), find(b"strf")) {
+                        (Some(header), Some(format)) if header.len() >= STREAM_HEADER_LEN => {
+                            streams.push(Stream {
+                                kind: &header[OFFSET_STREAM_TYPE..][..4],
+                                header,
+                                format,
+                            })
+                        }
+                        _ => bail!("A stream of the AVI file has an invalid header"),
+                    }
+                }
+            }
+        } else if let Some(contents) = list(id, contents, b"movi") {
+            movie = Some(contents);
+        }
+    }
+
+    let video_index = streams
+        .iter()
+        .position(|s| s.kind == b"vids")
+        .ok_or_else(|| format_err!("The AVI file contains no video"))?;
+    let audio_index = streams.iter().position(|s| s.kind == b"auds");
+    let movie = movie.ok_or_else(|| format_err!("The AVI file contains no frames"))?;
+
+    let video = &streams[video_index];
+    ensure!(
+        video.format.len() >= BITMAP_INFO_LEN,
+        "The video stream of the AVI file has an invalid format"
+    );
+    ensure!(
+        video.format[OFFSET_COMPRESSION..][..4].eq_ignore_ascii_case(b"MJPG"),
+        "The video of the AVI file needs to be MJPEG encoded"
+    );
+    let scale = LE::read_u32(&video.header[OFFSET_SCALE..]);
+    let rate = LE::read_u32(&video.header[OFFSET_RATE..]);
+    ensure!(
+        scale != 0 && rate != 0,
+        "The AVI file has an invalid frame rate"
+    );
+
+    let audio = match audio_index {
+        Some(index) => {
+            let format = streams[index].format;
+            ensure!(
+                format.len() >= WAVE_FORMAT_LEN
+                    && LE::read_u16(&format[OFFSET_FORMAT_TAG..]) == WAVE_FORMAT_PCM
+                    && LE::read_u16(&format[OFFSET_BITS_PER_SAMPLE..]) == 16,
+                "The audio of the AVI file needs to be 16 bit PCM"
+            );
+            let channels = LE::read_u16(&format[OFFSET_CHANNELS..]) as usize;
+            ensure!(
+                channels == 1 || channels == 2,
+                "The audio of the AVI file needs to be mono or stereo"
+            );
+            Some(Audio {
+                channels,
+                frequency: LE::read_u32(&format[OFFSET_FREQUENCY..]),
+                samples: Vec::new(),
+            })
+        }
+        None => None,
+    };
+
+    let mut video = Video {
+        width: LE::read_u32(&video.format[OFFSET_WIDTH..]),
+        height: LE::read_i32(&video.format[OFFSET_HEIGHT..]).abs() as u32,
+        fps: rate as f32 / scale as f32,
+        frames: Vec::new(),
+        audio,
+    };
+
+    let mut movie_chunks = Vec::new();
+    stream_chunks(movie, &mut movie_chunks)?;
+    for (id, contents) in movie_chunks {
+        // The IDs consist of the stream's index and the kind of data
+        let index = match String::from_utf8_lossy(&id[..2]).parse::<usize>() {
+            Ok(index) => index,
+            Err(_) => continue,
+        };
+        if index == video_index {
+            // Empty frames repeat the previous frame
+            let frame = match video.frames.last() {
+                Some(&previous) if contents.is_empty() => previous,
+                _ => contents,
+            };
+            video.frames.push(frame);
+        } else if Some(index) == audio_index {
+            if let Some(ref mut audio) = video.audio {
+                audio.samples.extend(
+                    contents
+                        .chunks(2)
+                        .filter(|c| c.len() == 2)
+                        .map(LE::read_i16),
+                );
+            }
+        }
+    }
+
+    Ok(video)
+}
@@ -0,0 +1,281 @@
+//! THP videos consist of JPEG images and optional DSP ADPCM audio, which are
+//! stored frame by frame. Each frame knows the sizes of the frames around it,
+//! so the games can stream them from the disc. Replacement videos are either
+//! THP files, which are copied as they are, or AVI files with MJPEG video and
+//! PCM audio, which are converted. Either way they need to have the
+//! dimensions and the frame rate of the original video, as the games usually
+//! rely on them.
+
+use audio::adpcm;
+use byteorder::{ByteOrder, BE};
+use failure::{Error, ResultExt};
+
+mod avi;
+
+const MAGIC: &[u8] = b"THP\0";
+const VERSION_1_0: u32 = 0x0001_0000;
+const VERSION_1_1: u32 = 0x0001_1000;
+const HEADER_LEN: usize = 0x30;
+const ALIGNMENT: usize = 0x20;
+
+const OFFSET_VERSION: usize = 0x04;
+const OFFSET_MAX_BUFFER_SIZE: usize = 0x08;
+const OFFSET_MAX_AUDIO_SAMPLES: usize = 0x0C;
+const OFFSET_FPS: usize = 0x10;
+const OFFSET_NUM_FRAMES: usize = 0x14;
+const OFFSET_FIRST_FRAME_SIZE: usize = 0x18;
+const OFFSET_DATA_SIZE: usize = 0x1C;
+const OFFSET_COMPONENTS_OFFSET: usize = 0x20;
+const OFFSET_FIRST_FRAME_OFFSET: usize = 0x28;
+const OFFSET_LAST_FRAME_OFFSET: usize = 0x2C;
+
+const MAX_COMPONENTS: usize = 16;
+const COMPONENT_VIDEO: u8 = 0;
+const COMPONENT_AUDIO: u8 = 1;
+const COMPONENT_NONE: u8 = 0xFF;
+
+/// The coefficients for both channels and the last two samples of each.
+const AUDIO_HEADER_LEN: usize = 0x50;
+const OFFSET_AUDIO_COEFFICIENTS: usize = 0x08;
+const OFFSET_AUDIO_HISTORY: usize = 0x48;
+
+/// The properties of a video that need to stay the same.
+struct Info {
+    width: u32,
+    height: u32,
+    fps: f32,
+}
+
+fn align(offset: usize) -> usize {
+    (offset + ALIGNMENT - 1) & !(ALIGNMENT - 1)
+}
+
+fn push_u32(out: &mut Vec<u8>, value: u32) {
+    let mut buf = [0; 4];
+    BE::write_u32(&mut buf, value);
+    out.extend_from_slice(&buf);
+}
+
+pub fn is_thp(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN && data.starts_with(MAGIC)
+}
+
+fn info(data: &[u8]) -> Result<Info, Error> {
+    ensure!(is_thp(data), "The file is not a THP video");
+    let version = BE::read_u32(&data[OFFSET_VERSION..]);
+    ensure!(
+        version == VERSION_1_0 || version == VERSION_1_1,
+        "The THP video has the unknown version {:#X}",
+        version
+    );
+    let (video_info_len, audio_info_len) = if version == VERSION_1_1 {
+        (12, 16)
+    } else {
+        (8, 12)
+    };
+
+    let offset = BE::read_u32(&data[OFFSET_COMPONENTS_OFFSET..]) as usize;
+    ensure!(
+        offset + 4 + MAX_COMPONENTS <= data.len(),
+        "The components of the THP video are out of bounds"
+    );
+    let num_components = BE::read_u32(&data[offset..]) as usize;
+    let kinds = &data[offset + 4..][..MAX_COMPONENTS];
+    let mut offset = offset + 4 + MAX_COMPONENTS;
+    for &kind in kinds.iter().take(num_components) {
+        match kind {
+            COMPONENT_VIDEO => {
+                ensure!(
+                    offset + video_info_len <= data.len(),
+                    "The components of the THP video are out of bounds"
+                );
+                return Ok(Info {
+                    width: BE::read_u32(&data[offset..]),
+                    height: BE::read_u32(&data[offset + 4..]),
+                    fps: BE::read_f32(&data[OFFSET_FPS..]),
+                });
+            }
+            COMPONENT_AUDIO => offset += audio_info_len,
+            _ => {}
+        }
+    }
+    bail!("The THP file contains no video")
+}
+
+/// Returns the replacement for the original video, converting it into a THP
+/// video if it's an AVI file.
+pub fn replace_video(original: &[u8], replacement: &[u8]) -> Result<Vec<u8>, Error> {
+    let expected = info(original).context("Couldn't parse the original video")?;
+    let video = if is_thp(replacement) {
+        replacement.to_owned()
+    } else if avi::is_avi(replacement) {
+        convert(&avi::parse(replacement)?)?
+    } else {
+        bail!("The video is neither a THP nor an AVI file")
+    };
+
+    let actual = info(&video)?;
+    ensure!(
+        actual.width == expected.width && actual.height == expected.height,
+        "The video is {}x{} pixels, but the original one is {}x{} pixels",
+        actual.width,
+        actual.height,
+        expected.width,
+        expected.height
+    );
+    ensure!(
+        (actual.fps - expected.fps).abs() < 0.01,
+        "The video runs at {:.2} FPS, but the original one runs at {:.2} FPS",
+        actual.fps,
+        expected.fps
+    );
+
+    Ok(video)
+}
+
+fn convert(video: &avi::Video) -> Result<Vec<u8>, Error> {
+    ensure!(!video.frames.is_empty(), "The video has no frames");
+    for (index, frame) in video.frames.iter().enumerate() {
+        ensure!(
+            frame.starts_with(&[0xFF, 0xD8]),
+            "Frame {} of the video is not a JPEG image",
+            index
+        );
+    }
+
+    // The samples of each channel are encoded separately
+    let channels = match video.audio {
+        Some(ref audio) => (0..audio.channels)
+            .map(|channel| {
+                audio
+                    .samples
+                    .iter()
+                    .skip(channel)
+                    .step_by(audio.channels)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>(),
+        None => Vec::new(),
+    };
+    let num_samples = channels.first().map_or(0, Vec::len);
+    let mut encoders = channels
+        .iter()
+        .map(|samples| adpcm::Encoder::new(samples))
+        .collect::<Vec<_>>();
+
+    let num_components = if video.audio.is_some() { 2 } else { 1 };
+    let frame_header_len = 8 + 4 * num_components;
+    let mut frames = Vec::with_capacity(video.frames.len());
+    let mut max_audio_samples = 0;
+
+    for (index, image) in video.frames.iter().enumerate() {
+        let mut frame = vec![0; frame_header_len];
+        BE::write_u32(&mut frame[8..], image.len() as u32);
+        frame.extend_from_slice(image);
+
+        if let Some(ref audio) = video.audio {
+            // Each frame contains the audio that plays until the next frame
+            let samples_until = |frame: usize| {
+                let samples = (frame as f64 * audio.frequency as f64 / video.fps as f64).round();
+                (samples as usize).min(num_samples)
+            };
+            let (start, end) = if index + 1 == video.frames.len() {
+                (samples_until(index), num_samples)
+            } else {
+                (samples_until(index), samples_until(index + 1))
+            };
+            max_audio_samples = max_audio_samples.max(end - start);
+
+            let channel_len = adpcm::encoded_len(end - start);
+            let mut data = vec![0; AUDIO_HEADER_LEN];
+            BE::write_u32(&mut data, channel_len as u32);
+            BE::write_u32(&mut data[4..], (end - start) as u32);
+            for (channel, encoder) in encoders.iter().enumerate() {
+                adpcm::write_coefficients(
+                    &encoder.coefficients,
+                    &mut data[OFFSET_AUDIO_COEFFICIENTS + adpcm::COEFFICIENTS_LEN * channel..],
+                );
+                let offset = OFFSET_AUDIO_HISTORY + 4 * channel;
+                BE::write_i16(&mut data[offset..], encoder.hist1);
+                BE::write_i16(&mut data[offset + 2..], encoder.hist2);
+            }
+            for (samples, encoder) in channels.iter().zip(&mut encoders) {
+                encoder.encode(&samples[start..end], &mut data);
+            }
+
+            BE::write_u32(&mut frame[12..], data.len() as u32);
+            frame.extend_from_slice(&data);
+        }
+
+        let len = align(frame.len());
+        frame.resize(len, 0);
+        frames.push(frame);
+    }
+
+    // The first and the last frame refer to each other, so the video can loop
+    let num_frames = frames.len();
+    let sizes = frames.iter().map(Vec::len).collect::<Vec<_>>();
+    for (index, frame) in frames.iter_mut().enumerate() {
+        BE::write_u32(frame, sizes[(index + 1) % num_frames] as u32);
+        BE::write_u32(
+            &mut frame[4..],
+            sizes[(index + num_frames - 1) % num_frames] as u32,
+        );
+    }
+
+    let mut out = vec![0; HEADER_LEN];
+    out[..MAGIC.len()].copy_from_slice(MAGIC);
+    BE::write_u32(&mut out[OFFSET_VERSION..], VERSION_1_1);
+    BE::write_u32(
+        &mut out[OFFSET_MAX_BUFFER_SIZE..],
+        sizes.iter().cloned().max().unwrap_or(0) as u32,
+    );
+    BE::write_u32(
+        &mut out[OFFSET_MAX_AUDIO_SAMPLES..],
+        max_audio_samples as u32,
+    );
+    BE::write_f32(&mut out[OFFSET_FPS..], video.fps);
+    BE::write_u32(&mut out[OFFSET_NUM_FRAMES..], num_frames as u32);
+    BE::write_u32(&mut out[OFFSET_FIRST_FRAME_SIZE..], sizes[0] as u32);
+    BE::write_u32(&mut out[OFFSET_COMPONENTS_OFFSET..], HEADER_LEN as u32);
+
+    push_u32(&mut out, num_components as u32);
+    let mut kinds = [COMPONENT_NONE; MAX_COMPONENTS];
+    kinds[0] = COMPONENT_VIDEO;
+    if video.audio.is_some() {
+        kinds[1] = COMPONENT_AUDIO;
+    }
+    out.extend_from_slice(&kinds);
+
+    push_u32(&mut out, video.width);
+    push_u32(&mut out, video.height);
+    // Progressive rather than interlaced
+    push_u32(&mut out, 0);
+    if let Some(ref audio) = video.audio {
+        push_u32(&mut out, audio.channels as u32);
+        push_u32(&mut out, audio.frequency);
+        push_u32(&mut out, num_samples as u32);
+        // The number of audio tracks
+        push_u32(&mut out, 1);
+    }
+
+    let first_frame_offset = align(out.len());
+    out.resize(first_frame_offset, 0);
+    let data_size = sizes.iter().sum::<usize>();
+    BE::write_u32(&mut out[OFFSET_DATA_SIZE..], data_size as u32);
+    BE::write_u32(
+        &mut out[OFFSET_FIRST_FRAME_OFFSET..],
+        first_frame_offset as u32,
+    );
+    BE::write_u32(
+        &mut out[OFFSET_LAST_FRAME_OFFSET..],
+        (first_frame_offset + data_size - sizes[num_frames - 1]) as u32,
+    );
+
+    for frame in frames {
+        out.extend_from_slice(&frame);
+    }
+
+    Ok(out)
+}
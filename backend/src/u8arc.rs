@@ -0,0 +1,251 @@
+//! Based on http://wiki.tockdom.com/wiki/U8_(File_Format)
+//!
+//! U8 archives (usually `.arc` files) bundle up a whole directory tree. The
+//! archive is parsed into the same `Directory` the disc's file system uses, so
+//! files inside of it can be replaced the same way. Parsed files borrow their
+//! data from the original archive.
+
+use byteorder::{ByteOrder, BE};
+use failure::{Error, ResultExt};
+use iso::virtual_file_system::{Directory, File, FileData, Node};
+use std::str;
+
+const MAGIC: u32 = 0x55AA_382D;
+const HEADER_LEN: usize = 0x20;
+const NODE_LEN: usize = 12;
+const ALIGNMENT: usize = 0x20;
+
+const OFFSET_ROOT_NODE: usize = 0x04;
+const OFFSET_NODES_LEN: usize = 0x08;
+const OFFSET_DATA_OFFSET: usize = 0x0C;
+
+const KIND_FILE: u8 = 0;
+const KIND_DIRECTORY: u8 = 1;
+
+struct NodeEntry<'a> {
+    is_directory: bool,
+    name: &'a str,
+    /// The offset of a file's data or the index of a directory's parent.
+    offset_parent: usize,
+    /// The size of a file or the index of the node after a directory.
+    size_next: usize,
+}
+
+fn align(offset: usize) -> usize {
+    (offset + ALIGNMENT - 1) & !(ALIGNMENT - 1)
+}
+
+pub fn is_u8_archive(data: &[u8]) -> bool {
+    data.len() >= HEADER_LEN && BE::read_u32(data) == MAGIC
+}
+
+/// Parses a U8 archive. The root node becomes the returned directory, so
+/// paths into the archive are relative to it.
+pub fn parse(data: &[u8]) -> Result<Directory, Error> {
+    ensure!(is_u8_archive(data), "The file is not a U8 archive");
+
+    let root_offset = BE::read_u32(&data[OFFSET_ROOT_NODE..]) as usize;
+    ensure!(
+        root_offset + NODE_LEN <= data.len(),
+        "The root node of the archive is out of bounds"
+    );
+    let num_nodes = BE::read_u32(&data[root_offset + 8..]) as usize;
+    let string_table_offset = root_offset + num_nodes * NODE_LEN;
+    ensure!(
+        num_nodes > 0 && string_table_offset <= data.len(),
+        "The nodes of the archive are out of bounds"
+    );
+    let string_table = &data[string_table_offset..];
+
+    let mut nodes = Vec::with_capacity(num_nodes);
+    for (index, node) in data[root_offset..string_table_offset]
+        .chunks(NODE_LEN)
+        .enumerate()
+    {
+        let is_directory = match node[0] {
+            KIND_FILE => false,
+            KIND_DIRECTORY => true,
+            kind => bail!(
+                "Node {} of the archive has the unknown type {}",
+                index,
+                kind
+            ),
+        };
+
+        let name_offset = BE::read_u32(node) as usize & 0x00FF_FFFF;
+        ensure!(
+            name_offset < string_table.len(),
+            "The name of node {} of the archive is out of bounds",
+            index
+        );
+        let name = &string_table[name_offset..];
+        let end = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        let name = str::from_utf8(&name[..end])
+            .with_context(|_| format!("The name of node {} of the archive is invalid", index))?;
+
+        let offset_parent = BE::read_u32(&node[4..]) as usize;
+        let size_next = BE::read_u32(&node[8..]) as usize;
+        if is_directory {
+            ensure!(
+                size_next > index && size_next <= num_nodes,
+                "The directory \"{}\" of the archive has an invalid node",
+                name
+            );
+        } else {
+            ensure!(
+                offset_parent + size_next <= data.len(),
+                "The file \"{}\" of the archive is out of bounds",
+                name
+            );
+        }
+
+        nodes.push(NodeEntry {
+            is_directory,
+            name,
+            offset_parent,
+            size_next,
+        });
+    }
+    ensure!(
+        nodes[0].is_directory,
+        "The root node of the archive is not a directory"
+    );
+
+    let mut root = Directory::new(nodes[0].name);
+    parse_children(data, &nodes, 1, num_nodes, &mut root);
+
+    Ok(root)
+}
+
+/// Adds the nodes in `index..end` to the directory and returns `end`.
+fn parse_children<'a>(
+    data: &'a [u8],
+    nodes: &[NodeEntry<'a>],
+    mut index: usize,
+    end: usize,
+    dir: &mut Directory<'a>,
+) -> usize {
+    while index < end {
+        let node = &nodes[index];
+        if node.is_directory {
+            let mut child = Directory::new(node.name);
+            index = parse_children(data, nodes, index + 1, node.size_next, &mut child);
+            dir.children.push(Node::Directory(Box::new(child)));
+        } else {
+            let file_data = &data[node.offset_parent..][..node.size_next];
+            dir.children
+                .push(Node::File(File::new(node.name, file_data)));
+            index += 1;
+        }
+    }
+    end
+}
+
+/// Packs the directory into a U8 archive, aligning each file to 32 bytes.
+pub fn write(root: &Directory) -> Result<Vec<u8>, Error> {
+    let mut nodes = Vec::new();
+    let mut name_offsets = Vec::new();
+    let mut string_table = Vec::new();
+    let mut files = Vec::new();
+
+    nodes.push(NodeEntry {
+        is_directory: true,
+        name: root.name,
+        offset_parent: 0,
+        size_next: 0,
+    });
+    flatten(root, 0, &mut nodes, &mut files)?;
+    nodes[0].size_next = nodes.len();
+
+    for node in &nodes {
+        name_offsets.push(string_table.len());
+        string_table.extend_from_slice(node.name.as_bytes());
+        string_table.push(0);
+    }
+    ensure!(
+        string_table.len() <= 0x0100_0000,
+        "The names of the archive's files don't fit into the archive"
+    );
+
+    let nodes_len = nodes.len() * NODE_LEN + string_table.len();
+    let data_offset = align(HEADER_LEN + nodes_len);
+
+    let mut file_offsets = Vec::with_capacity(files.len());
+    let mut len = data_offset;
+    for data in &files {
+        file_offsets.push(len);
+        len = align(len + data.len());
+    }
+
+    let mut buf = vec![0; len];
+    BE::write_u32(&mut buf, MAGIC);
+    BE::write_u32(&mut buf[OFFSET_ROOT_NODE..], HEADER_LEN as u32);
+    BE::write_u32(&mut buf[OFFSET_NODES_LEN..], nodes_len as u32);
+    BE::write_u32(&mut buf[OFFSET_DATA_OFFSET..], data_offset as u32);
+
+    let mut file_index = 0;
+    for (index, node) in nodes.iter().enumerate() {
+        let entry = &mut buf[HEADER_LEN + index * NODE_LEN..][..NODE_LEN];
+        let (kind, offset_parent) = if node.is_directory {
+            (KIND_DIRECTORY, node.offset_parent)
+        } else {
+            file_index += 1;
+            (KIND_FILE, file_offsets[file_index - 1])
+        };
+        BE::write_u32(entry, name_offsets[index] as u32);
+        entry[0] = kind;
+        BE::write_u32(&mut entry[4..], offset_parent as u32);
+        BE::write_u32(&mut entry[8..], node.size_next as u32);
+    }
+
+    let string_table_offset = HEADER_LEN + nodes.len() * NODE_LEN;
+    buf[string_table_offset..][..string_table.len()].copy_from_slice(&string_table);
+
+    for (data, offset) in files.iter().zip(file_offsets) {
+        buf[offset..][..data.len()].copy_from_slice(data);
+    }
+
+    Ok(buf)
+}
+
+/// Appends the nodes of the directory's children in the order U8 archives
+/// store them, which is depth first.
+fn flatten<'a>(
+    dir: &'a Directory,
+    parent: usize,
+    nodes: &mut Vec<NodeEntry<'a>>,
+    files: &mut Vec<&'a [u8]>,
+) -> Result<(), Error> {
+    for child in &dir.children {
+        match *child {
+            Node::Directory(ref child) => {
+                let index = nodes.len();
+                nodes.push(NodeEntry {
+                    is_directory: true,
+                    name: child.name,
+                    offset_parent: parent,
+                    size_next: 0,
+                });
+                flatten(child, index, nodes, files)?;
+                nodes[index].size_next = nodes.len();
+            }
+            Node::File(ref file) => {
+                let data = match file.data {
+                    FileData::Memory(ref data) => &**data,
+                    FileData::Disc { .. } => bail!(
+                        "The file \"{}\" of the archive wasn't loaded into memory",
+                        file.name
+                    ),
+                };
+                nodes.push(NodeEntry {
+                    is_directory: false,
+                    name: file.name,
+                    offset_parent: 0,
+                    size_next: data.len(),
+                });
+                files.push(data);
+            }
+        }
+    }
+    Ok(())
+}
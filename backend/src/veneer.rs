@@ -0,0 +1,77 @@
+//! Lets the branches of the patch files reach targets that are more than
+//! 32 MiB away, like functions in MEM2 on the Wii. Such branches go to a
+//! veneer instead, which loads the target into the count register and jumps
+//! there. `bl` still returns to the caller, as the veneer leaves the link
+//! register alone. The veneers clobber r12, which the ABI reserves for them.
+
+use assembler::{build_branch_instruction, is_branch_in_range, FarBranch, Instruction};
+use dol::Section;
+use entry;
+use failure::Error;
+use std::collections::BTreeMap;
+
+const VENEER_LEN: u32 = 4 * 4;
+
+const MTCTR_R12: u32 = 0x7D89_03A6;
+const BCTR: u32 = 0x4E80_0420;
+
+/// The branches to the same target share a veneer.
+fn targets(branches: &[FarBranch]) -> BTreeMap<u32, u32> {
+    let mut targets = BTreeMap::new();
+    for branch in branches {
+        let index = targets.len() as u32;
+        targets.entry(branch.target).or_insert(index);
+    }
+    targets
+}
+
+/// The amount of bytes the veneers for the branches take up.
+pub fn veneers_len(branches: &[FarBranch]) -> u32 {
+    targets(branches).len() as u32 * VENEER_LEN
+}
+
+/// Lowers the branches to branches to their veneers and the section
+/// containing the veneers, which starts at the stub address.
+pub fn lower(
+    branches: &[FarBranch],
+    stub_address: u32,
+) -> Result<(Vec<Instruction>, Option<Section>), Error> {
+    let targets = targets(branches);
+
+    let mut instructions = Vec::with_capacity(branches.len());
+    for branch in branches {
+        let veneer_address = stub_address + targets[&branch.target] * VENEER_LEN;
+        ensure!(
+            is_branch_in_range(branch.address, veneer_address),
+            "The branch at {:08X} can't reach its veneer at {:08X}",
+            branch.address,
+            veneer_address
+        );
+        instructions.push(Instruction {
+            address: branch.address,
+            data: build_branch_instruction(branch.address, veneer_address, false, branch.link),
+            mask: !0,
+        });
+    }
+
+    let mut veneers = vec![0; 4 * targets.len()];
+    for (&target, &index) in &targets {
+        let index = 4 * index as usize;
+        veneers[index..index + 4].copy_from_slice(&[
+            // lis r12, target@h
+            (15 << 26) | (12 << 21) | (target >> 16),
+            // ori r12, r12, target@l
+            (24 << 26) | (12 << 21) | (12 << 16) | (target & 0xFFFF),
+            MTCTR_R12,
+            BCTR,
+        ]);
+    }
+
+    let section = if veneers.is_empty() {
+        None
+    } else {
+        Some(entry::to_section(stub_address, &veneers))
+    };
+
+    Ok((instructions, section))
+}
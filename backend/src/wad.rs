@@ -0,0 +1,390 @@
+//! Based on http://wiibrew.org/wiki/WAD_files
+//!
+//! WADs are installed onto the NAND of a Wii as channels. They contain the
+//! certificate chain, the ticket and the TMD of the title, followed by its
+//! contents, which are encrypted with the title key. Every section is padded
+//! to 0x40 bytes. A channel is made from the ticket and the TMD of the data
+//! partition of the original game, so it's encrypted with the game's title
+//! key. Both are changed to the channel's title ID and contents and are
+//! fakesigned, so the channel can only be installed with an IOS that has the
+//! Trucha bug.
+
+use byteorder::{ByteOrder, BE};
+use failure::Error;
+use iso::wii;
+use sha1::Sha1;
+use std::io::Write;
+
+const HEADER_LEN: usize = 0x20;
+const ALIGNMENT: usize = 0x40;
+const KIND_INSTALLABLE: &[u8] = b"Is\0\0";
+
+const OFFSET_HEADER_SIZE: usize = 0x00;
+const OFFSET_KIND: usize = 0x04;
+const OFFSET_CERT_CHAIN_SIZE: usize = 0x08;
+const OFFSET_TICKET_SIZE: usize = 0x10;
+const OFFSET_TMD_SIZE: usize = 0x14;
+const OFFSET_DATA_SIZE: usize = 0x18;
+
+/// The upper half of the title IDs of channels.
+const TITLE_ID_CHANNEL: u32 = 0x0001_0001;
+const GAME_CODE_LEN: usize = 4;
+
+// Relative to the start of the ticket
+const OFFSET_TICKET_TITLE_ID: usize = 0x1DC;
+const OFFSET_TICKET_COMMON_KEY_INDEX: usize = 0x1F1;
+const OFFSET_TICKET_CONTENT_ACCESS: usize = 0x222;
+const TICKET_LEN: usize = 0x2A4;
+/// Every content has a bit in the access permissions of the ticket. The bytes
+/// after the ones for this many contents are used for fakesigning.
+const MAX_CONTENTS: usize = 0x2A * 8;
+
+// Relative to the start of the TMD
+const OFFSET_TMD_TITLE_ID: usize = 0x18C;
+const OFFSET_TMD_NUM_CONTENTS: usize = 0x1DE;
+const OFFSET_TMD_BOOT_INDEX: usize = 0x1E0;
+const OFFSET_TMD_CONTENTS: usize = 0x1E4;
+
+// Relative to the start of a content record
+const OFFSET_CONTENT_ID: usize = 0x00;
+const OFFSET_CONTENT_INDEX: usize = 0x04;
+const OFFSET_CONTENT_TYPE: usize = 0x06;
+const OFFSET_CONTENT_SIZE: usize = 0x08;
+const OFFSET_CONTENT_HASH: usize = 0x10;
+const CONTENT_RECORD_LEN: usize = 0x24;
+const CONTENT_TYPE_NORMAL: u16 = 1;
+const HASH_SIZE: usize = 20;
+
+/// The banner is the first content of a channel and the DOL it boots follows.
+const BOOT_INDEX_DOL: u16 = 1;
+
+pub struct Wad {
+    /// The certificates the ticket and the TMD are signed with.
+    pub cert_chain: Vec<u8>,
+    /// The ticket, which holds the title key encrypted with the common key.
+    pub ticket: Vec<u8>,
+    /// The TMD up to its content records, which are made from the contents
+    /// when the WAD is written.
+    pub tmd: Vec<u8>,
+    /// The decrypted contents in the order of their records. They are
+    /// numbered by their position when the WAD is written.
+    pub contents: Vec<Vec<u8>>,
+}
+
+impl Wad {
+    /// Makes a channel with the game code from the certificate chain, the
+    /// ticket and the TMD of a data partition. It boots the DOL and shows the
+    /// banner, which is an `opening.bnr` like the one on the disc.
+    pub fn channel(
+        cert_chain: &[u8],
+        ticket: &[u8],
+        tmd: &[u8],
+        game_code: &[u8],
+        banner: Vec<u8>,
+        dol: Vec<u8>,
+    ) -> Result<Self, Error> {
+        ensure!(
+            game_code.len() == GAME_CODE_LEN,
+            "The game code needs to be 4 characters long"
+        );
+        ensure!(ticket.len() >= TICKET_LEN, "The ticket is truncated");
+        ensure!(tmd.len() >= OFFSET_TMD_CONTENTS, "The TMD is truncated");
+
+        let mut title_id = [0; 8];
+        BE::write_u32(&mut title_id, TITLE_ID_CHANNEL);
+        title_id[8 - GAME_CODE_LEN..].copy_from_slice(game_code);
+
+        let title_key = wii::decrypt_title_key(ticket)?;
+        let mut ticket = ticket[..TICKET_LEN].to_owned();
+        ticket[OFFSET_TICKET_TITLE_ID..][..8].copy_from_slice(&title_id);
+        // Korean discs use a common key that only Korean consoles have, while
+        // every console has the first one
+        ticket[OFFSET_TICKET_COMMON_KEY_INDEX] = 0;
+        wii::encrypt_title_key(&mut ticket, &title_key)?;
+
+        let mut tmd = tmd[..OFFSET_TMD_CONTENTS].to_owned();
+        tmd[OFFSET_TMD_TITLE_ID..][..8].copy_from_slice(&title_id);
+        BE::write_u16(&mut tmd[OFFSET_TMD_BOOT_INDEX..], BOOT_INDEX_DOL);
+
+        Ok(Wad {
+            cert_chain: cert_chain.to_owned(),
+            ticket,
+            tmd,
+            contents: vec![banner, dol],
+        })
+    }
+
+    /// Parses an installable WAD and decrypts its contents, which are checked
+    /// against the hashes in the TMD.
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        ensure!(
+            data.len() >= HEADER_LEN
+                && BE::read_u32(&data[OFFSET_HEADER_SIZE..]) as usize == HEADER_LEN
+                && &data[OFFSET_KIND..][..KIND_INSTALLABLE.len()] == KIND_INSTALLABLE,
+            "The file is not an installable WAD"
+        );
+
+        let mut sections = Vec::new();
+        let mut offset = HEADER_LEN;
+        for &size_offset in &[
+            OFFSET_CERT_CHAIN_SIZE,
+            OFFSET_TICKET_SIZE,
+            OFFSET_TMD_SIZE,
+            OFFSET_DATA_SIZE,
+        ] {
+            offset = align(offset, ALIGNMENT);
+            let size = BE::read_u32(&data[size_offset..]) as usize;
+            ensure!(offset + size <= data.len(), "The WAD file is truncated");
+            sections.push(&data[offset..][..size]);
+            offset += size;
+        }
+        let (cert_chain, ticket, tmd, data) = (sections[0], sections[1], sections[2], sections[3]);
+
+        ensure!(ticket.len() >= TICKET_LEN, "The ticket is truncated");
+        ensure!(tmd.len() >= OFFSET_TMD_CONTENTS, "The TMD is truncated");
+        let num_contents = BE::read_u16(&tmd[OFFSET_TMD_NUM_CONTENTS..]) as usize;
+        ensure!(
+            tmd.len() >= OFFSET_TMD_CONTENTS + num_contents * CONTENT_RECORD_LEN,
+            "The TMD is truncated"
+        );
+        let title_key = wii::decrypt_title_key(ticket)?;
+
+        let mut contents = Vec::with_capacity(num_contents);
+        let mut offset = 0;
+        for record in tmd[OFFSET_TMD_CONTENTS..]
+            .chunks(CONTENT_RECORD_LEN)
+            .take(num_contents)
+        {
+            let index = BE::read_u16(&record[OFFSET_CONTENT_INDEX..]);
+            let size = BE::read_u64(&record[OFFSET_CONTENT_SIZE..]) as usize;
+            let encrypted_len = align(size, 16);
+            ensure!(
+                offset + encrypted_len <= data.len(),
+                "The content {} is truncated",
+                index
+            );
+
+            let mut content = data[offset..][..encrypted_len].to_owned();
+            wii::decrypt(&title_key, content_iv(index), &mut content);
+            content.truncate(size);
+            ensure!(
+                sha1(&content)[..] == record[OFFSET_CONTENT_HASH..][..HASH_SIZE],
+                "The content {} doesn't match its hash in the TMD",
+                index
+            );
+            contents.push(content);
+
+            offset = align(offset + encrypted_len, ALIGNMENT);
+        }
+
+        Ok(Wad {
+            cert_chain: cert_chain.to_owned(),
+            ticket: ticket.to_owned(),
+            tmd: tmd[..OFFSET_TMD_CONTENTS].to_owned(),
+            contents,
+        })
+    }
+
+    /// Encrypts the contents and writes them with their records in the TMD.
+    /// The ticket and the TMD are fakesigned, as their signatures can't match
+    /// anymore.
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), Error> {
+        ensure!(self.ticket.len() >= TICKET_LEN, "The ticket is truncated");
+        ensure!(
+            self.tmd.len() >= OFFSET_TMD_CONTENTS,
+            "The TMD is truncated"
+        );
+        ensure!(
+            self.contents.len() <= MAX_CONTENTS,
+            "A WAD can't have more than {} contents",
+            MAX_CONTENTS
+        );
+        ensure!(
+            (BE::read_u16(&self.tmd[OFFSET_TMD_BOOT_INDEX..]) as usize) < self.contents.len(),
+            "The WAD has no content to boot"
+        );
+
+        let title_key = wii::decrypt_title_key(&self.ticket)?;
+        let mut ticket = self.ticket.clone();
+        for byte in &mut ticket[OFFSET_TICKET_CONTENT_ACCESS..][..(self.contents.len() + 7) / 8] {
+            *byte = 0xFF;
+        }
+        wii::fakesign_ticket(&mut ticket)?;
+
+        let mut tmd = self.tmd[..OFFSET_TMD_CONTENTS].to_owned();
+        BE::write_u16(
+            &mut tmd[OFFSET_TMD_NUM_CONTENTS..],
+            self.contents.len() as u16,
+        );
+
+        let mut data = Vec::new();
+        for (index, content) in self.contents.iter().enumerate() {
+            let mut record = [0; CONTENT_RECORD_LEN];
+            BE::write_u32(&mut record[OFFSET_CONTENT_ID..], index as u32);
+            BE::write_u16(&mut record[OFFSET_CONTENT_INDEX..], index as u16);
+            BE::write_u16(&mut record[OFFSET_CONTENT_TYPE..], CONTENT_TYPE_NORMAL);
+            BE::write_u64(&mut record[OFFSET_CONTENT_SIZE..], content.len() as u64);
+            record[OFFSET_CONTENT_HASH..].copy_from_slice(&sha1(content));
+            tmd.extend_from_slice(&record);
+
+            let padded_len = align(data.len(), ALIGNMENT);
+            data.resize(padded_len, 0);
+            let start = data.len();
+            data.extend_from_slice(content);
+            data.resize(start + align(content.len(), 16), 0);
+            wii::encrypt(&title_key, content_iv(index as u16), &mut data[start..]);
+        }
+        wii::fakesign_tmd(&mut tmd)?;
+
+        let mut header = [0; HEADER_LEN];
+        BE::write_u32(&mut header[OFFSET_HEADER_SIZE..], HEADER_LEN as u32);
+        header[OFFSET_KIND..][..KIND_INSTALLABLE.len()].copy_from_slice(KIND_INSTALLABLE);
+        BE::write_u32(
+            &mut header[OFFSET_CERT_CHAIN_SIZE..],
+            self.cert_chain.len() as u32,
+        );
+        BE::write_u32(&mut header[OFFSET_TICKET_SIZE..], ticket.len() as u32);
+        BE::write_u32(&mut header[OFFSET_TMD_SIZE..], tmd.len() as u32);
+        BE::write_u32(&mut header[OFFSET_DATA_SIZE..], data.len() as u32);
+
+        for section in &[&header[..], &self.cert_chain, &ticket, &tmd, &data] {
+            writer.write_all(section)?;
+            writer.write_all(&[0; ALIGNMENT][..align(section.len(), ALIGNMENT) - section.len()])?;
+        }
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+/// The index of a content is the IV of its encryption.
+fn content_iv(index: u16) -> [u8; 16] {
+    let mut iv = [0; 16];
+    BE::write_u16(&mut iv, index);
+    iv
+}
+
+fn align(len: usize, alignment: usize) -> usize {
+    (len + alignment - 1) & !(alignment - 1)
+}
+
+fn sha1(data: &[u8]) -> [u8; HASH_SIZE] {
+    let mut hasher = Sha1::new();
+    hasher.update(data);
+    hasher.digest().bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TITLE_KEY: [u8; 16] = *b"0123456789abcdef";
+
+    /// The ticket and the TMD of a Korean disc with the game code "RABK".
+    fn disc_wad(contents: Vec<Vec<u8>>) -> Wad {
+        let mut ticket = vec![0; TICKET_LEN];
+        ticket[OFFSET_TICKET_TITLE_ID..][..8].copy_from_slice(b"\0\0\0\x01RABK");
+        ticket[OFFSET_TICKET_COMMON_KEY_INDEX] = 1;
+        wii::encrypt_title_key(&mut ticket, &TITLE_KEY).unwrap();
+
+        let mut tmd = vec![0; OFFSET_TMD_CONTENTS];
+        tmd[OFFSET_TMD_TITLE_ID..][..8].copy_from_slice(b"\0\0\0\x01RABK");
+
+        Wad {
+            cert_chain: b"Root-CA00000001-XS00000003".to_vec(),
+            ticket,
+            tmd,
+            contents,
+        }
+    }
+
+    fn write(wad: &Wad) -> Vec<u8> {
+        let mut buf = Vec::new();
+        wad.write(&mut buf).unwrap();
+        buf
+    }
+
+    /// The certificate chain, the ticket, the TMD or the data of the WAD.
+    fn section(wad: &[u8], index: usize) -> &[u8] {
+        let sizes = [
+            OFFSET_CERT_CHAIN_SIZE,
+            OFFSET_TICKET_SIZE,
+            OFFSET_TMD_SIZE,
+            OFFSET_DATA_SIZE,
+        ].iter()
+            .map(|&offset| BE::read_u32(&wad[offset..]) as usize)
+            .collect::<Vec<_>>();
+        let offset = sizes[..index]
+            .iter()
+            .fold(align(HEADER_LEN, ALIGNMENT), |offset, &size| {
+                align(offset + size, ALIGNMENT)
+            });
+        &wad[offset..][..sizes[index]]
+    }
+
+    #[test]
+    fn round_trips_the_contents() {
+        let contents = vec![b"banner".to_vec(), vec![0x42; 0x123], Vec::new()];
+        let wad = Wad::parse(&write(&disc_wad(contents.clone()))).unwrap();
+
+        assert_eq!(wad.contents, contents);
+        assert_eq!(wad.cert_chain, b"Root-CA00000001-XS00000003");
+        assert_eq!(wii::decrypt_title_key(&wad.ticket).unwrap(), TITLE_KEY);
+        assert_eq!(&wad.tmd[OFFSET_TMD_TITLE_ID..][..8], b"\0\0\0\x01RABK");
+    }
+
+    #[test]
+    fn encrypts_the_contents() {
+        let content = b"The contents of the channel".to_vec();
+        let wad = write(&disc_wad(vec![content.clone()]));
+
+        assert!(!wad.windows(content.len()).any(|w| w == &content[..]));
+    }
+
+    #[test]
+    fn fakesigns_the_ticket_and_the_tmd() {
+        let wad = write(&disc_wad(vec![b"banner".to_vec(), b"dol".to_vec()]));
+
+        for &index in &[1, 2] {
+            let signed = section(&wad, index);
+            assert!(signed[4..0x104].iter().all(|&b| b == 0));
+            assert_eq!(sha1(&signed[0x140..])[0], 0);
+        }
+    }
+
+    #[test]
+    fn rejects_contents_that_dont_match_their_hash() {
+        let mut wad = write(&disc_wad(vec![b"banner".to_vec()]));
+        let len = wad.len();
+        wad[len - 0x40] ^= 1;
+
+        assert!(Wad::parse(&wad).is_err());
+    }
+
+    #[test]
+    fn makes_a_channel_of_a_disc() {
+        let disc = disc_wad(Vec::new());
+        let channel = Wad::channel(
+            &disc.cert_chain,
+            &disc.ticket,
+            &disc.tmd,
+            b"RABE",
+            b"banner".to_vec(),
+            b"dol".to_vec(),
+        ).unwrap();
+        let wad = Wad::parse(&write(&channel)).unwrap();
+
+        assert_eq!(wad.contents, vec![b"banner".to_vec(), b"dol".to_vec()]);
+        assert_eq!(
+            &wad.ticket[OFFSET_TICKET_TITLE_ID..][..8],
+            b"\0\x01\0\x01RABE"
+        );
+        assert_eq!(&wad.tmd[OFFSET_TMD_TITLE_ID..][..8], b"\0\x01\0\x01RABE");
+        assert_eq!(
+            BE::read_u16(&wad.tmd[OFFSET_TMD_BOOT_INDEX..]),
+            BOOT_INDEX_DOL
+        );
+        assert_eq!(wad.ticket[OFFSET_TICKET_COMMON_KEY_INDEX], 0);
+        assert_eq!(wii::decrypt_title_key(&wad.ticket).unwrap(), TITLE_KEY);
+    }
+}
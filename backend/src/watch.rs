@@ -0,0 +1,296 @@
+//! Rebuilds the Rom Hack whenever one of its sources changes. The files are
+//! polled for changes of their modification times, which works the same on
+//! every platform and is fast enough for the few hundred files of a project.
+//! Optionally Dolphin is restarted with the new build, as it can't reload a
+//! game that is already running. `run` avoids the restart where possible by
+//! patching the memory of the running game instead.
+
+use assembler;
+use cache;
+use config::{Config, OutputFormat};
+use dol::DolFile;
+use dolphin::Dolphin;
+use failure::{Error, ResultExt};
+use file_source::FileSystem;
+use key_val_print::{KeyValPrint, MessageKind};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use toml;
+use BuildOptions;
+
+const POLL_INTERVAL_MS: u64 = 500;
+
+/// Editors often save a file in multiple steps, so a change is only acted on
+/// once the files stopped changing for this long.
+const SETTLE_TIME_MS: u64 = 200;
+
+type Snapshot = Vec<(PathBuf, Option<SystemTime>)>;
+
+pub fn watch<P: KeyValPrint>(
+    printer: &P,
+    options: &BuildOptions,
+    dolphin: Option<&Path>,
+) -> Result<(), Error> {
+    watch_with(printer, options, dolphin, false)
+}
+
+/// Builds the Rom Hack, starts it in Dolphin and keeps rebuilding it. Changes
+/// that only affect the DOL are written into the memory of the running game
+/// where possible, everything else restarts the game.
+pub fn run<P: KeyValPrint>(
+    printer: &P,
+    debug: bool,
+    defines: &[String],
+    features: &[String],
+    dolphin: &Path,
+) -> Result<(), Error> {
+    let options = BuildOptions {
+        debug,
+        defines,
+        features,
+        ..Default::default()
+    };
+    watch_with(printer, &options, Some(dolphin), true)
+}
+
+fn watch_with<P: KeyValPrint>(
+    printer: &P,
+    options: &BuildOptions,
+    dolphin: Option<&Path>,
+    hot_patch: bool,
+) -> Result<(), Error> {
+    // Watching never only checks the Rom Hack or builds it twice
+    let options = BuildOptions {
+        check: false,
+        verify_reproducible: false,
+        ..*options
+    };
+    let mut running = None::<(Dolphin, DolFile)>;
+    // The snapshot of the build that Dolphin is currently running
+    let mut launched_snapshot = None::<Snapshot>;
+
+    loop {
+        // The snapshot is taken before building, so changes made during the
+        // build cause another build
+        let snapshot = take_snapshot()?;
+
+        match ::build(printer, &options) {
+            Ok(()) => {
+                printer.print(None, "Finished", "Rom Hack");
+                if let Some(dolphin) = dolphin {
+                    let can_hot_patch = hot_patch
+                        && launched_snapshot
+                            .as_ref()
+                            .map_or(false, |old| !needs_restart(old, &snapshot));
+                    let result = update_dolphin(
+                        printer,
+                        dolphin,
+                        options.patch || options.riivolution,
+                        can_hot_patch,
+                        &mut running,
+                    );
+                    match result {
+                        Ok(()) => launched_snapshot = Some(snapshot.clone()),
+                        Err(e) => print_error(printer, &e),
+                    }
+                }
+            }
+            Err(e) => print_error(printer, &e),
+        }
+
+        printer.print(None, "Watching", "for changes");
+        let mut current = take_snapshot()?;
+        while current == snapshot {
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+            current = take_snapshot()?;
+        }
+        loop {
+            thread::sleep(Duration::from_millis(SETTLE_TIME_MS));
+            let settled = take_snapshot()?;
+            if settled == current {
+                break;
+            }
+            current = settled;
+        }
+    }
+}
+
+fn print_error<P: KeyValPrint>(printer: &P, error: &Error) {
+    let causes = error
+        .iter_chain()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(": ");
+    printer.print(Some(MessageKind::Error), "Error", &causes);
+}
+
+fn parse_config() -> Result<Config, Error> {
+    let mut toml_buf = String::new();
+    File::open("RomHack.toml")
+        .context("Couldn't find \"RomHack.toml\".")?
+        .read_to_string(&mut toml_buf)
+        .context("Failed to read \"RomHack.toml\".")?;
+    Ok(toml::from_str(&toml_buf).context("Can't parse RomHack.toml")?)
+}
+
+/// Collects the modification times of all the files the build depends on.
+/// Files that don't exist are part of the snapshot too, so creating them is
+/// noticed.
+fn take_snapshot() -> Result<Snapshot, Error> {
+    let mut paths = vec![PathBuf::from("RomHack.toml")];
+
+    // A broken config still needs to be watched, so it can be fixed
+    if let Ok(config) = parse_config() {
+        let crate_dir = config.src.src.clone().unwrap_or_else(|| PathBuf::from("."));
+        paths.push(crate_dir.join("Cargo.toml"));
+        collect_dir(&crate_dir.join("src"), &mut paths);
+
+        for path in cache::assembly_files(&config) {
+            match assembler::source_files(&mut FileSystem, path) {
+                Ok(files) => paths.extend(files),
+                Err(_) => paths.push(path.to_owned()),
+            }
+        }
+        paths.extend(cache::input_files(&config).into_iter().map(Path::to_owned));
+        paths.push(config.src.iso.clone());
+
+        for region in config.regions.values() {
+            paths.push(region.iso.clone());
+            paths.extend(region.symbols.iter().cloned());
+            paths.extend(region.port_map.iter().cloned());
+            paths.extend(region.files.values().cloned());
+        }
+        for disc in config.discs.values() {
+            paths.push(disc.iso.clone());
+            paths.extend(disc.symbols.iter().cloned());
+            paths.extend(disc.files.values().cloned());
+        }
+        for feature in config.features.values() {
+            if let Some(patch) = &feature.patch {
+                match assembler::source_files(&mut FileSystem, patch) {
+                    Ok(files) => paths.extend(files),
+                    Err(_) => paths.push(patch.clone()),
+                }
+            }
+            paths.extend(feature.files.values().cloned());
+        }
+    }
+
+    paths.sort();
+    paths.dedup();
+
+    Ok(paths
+        .into_iter()
+        .map(|path| {
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok();
+            (path, modified)
+        })
+        .collect())
+}
+
+fn collect_dir(dir: &Path, paths: &mut Vec<PathBuf>) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.filter_map(Result::ok) {
+            let path = entry.path();
+            if path.is_dir() {
+                collect_dir(&path, paths);
+            } else {
+                paths.push(path);
+            }
+        }
+    }
+}
+
+/// Checks whether any of the files changed that end up somewhere else than
+/// in the DOL, like the replaced files or the banner. Those can only be
+/// loaded by restarting the game.
+fn needs_restart(old: &Snapshot, new: &Snapshot) -> bool {
+    let config = match parse_config() {
+        Ok(config) => config,
+        Err(_) => return true,
+    };
+
+    let mut paths = vec![Path::new("RomHack.toml"), &config.src.iso];
+    paths.extend(config.files.values().map(|p| &**p));
+    paths.extend(config.textures.values().map(|p| &**p));
+    paths.extend(config.fonts.values().flat_map(|g| g.values()).map(|p| &**p));
+    paths.extend(config.videos.values().map(|p| &**p));
+    paths.extend(config.sounds.values().map(|p| &**p));
+    paths.extend(config.info.image.iter().map(|p| &**p));
+    for region in config.regions.values() {
+        paths.push(&region.iso);
+        paths.extend(region.files.values().map(|p| &**p));
+    }
+    for disc in config.discs.values() {
+        paths.push(&disc.iso);
+        paths.extend(disc.files.values().map(|p| &**p));
+    }
+    for feature in config.features.values() {
+        paths.extend(feature.files.values().map(|p| &**p));
+    }
+
+    let old = old.iter().cloned().collect::<HashMap<_, _>>();
+    new.len() != old.len()
+        || new
+            .iter()
+            .filter(|&&(ref path, modified)| old.get(path) != Some(&modified))
+            .any(|&(ref path, _)| paths.contains(&&**path))
+}
+
+/// Starts Dolphin with the built ISO or patches the game that is already
+/// running. Patches can't be started directly, so they are skipped with a
+/// warning.
+fn update_dolphin<P: KeyValPrint>(
+    printer: &P,
+    executable: &Path,
+    is_patch: bool,
+    can_hot_patch: bool,
+    running: &mut Option<(Dolphin, DolFile)>,
+) -> Result<(), Error> {
+    let mut config = parse_config()?;
+    if is_patch || config.build.format != OutputFormat::Iso {
+        printer.print(
+            Some(MessageKind::Warning),
+            "Warning",
+            "Only ISOs can be started in Dolphin",
+        );
+        return Ok(());
+    }
+
+    // Only the first region and its first disc are started
+    if let Some(name) = config.regions.keys().next().cloned() {
+        ::select_region(&mut config, &name);
+    }
+    if let Some(name) = config.discs.keys().next().cloned() {
+        ::select_disc(&mut config, &name);
+    }
+
+    let dol = ::read_main_dol(&config.build.iso)?;
+    let dol = DolFile::parse(&dol).context("Couldn't parse the built DOL")?;
+
+    if let Some((ref mut dolphin, ref mut running_dol)) = *running {
+        if can_hot_patch && dolphin.is_running() {
+            if let Some(count) = dolphin.hot_patch(running_dol, &dol)? {
+                printer.print(
+                    None,
+                    "Patched",
+                    &format!("{} words in the running game", count),
+                );
+                *running_dol = dol;
+                return Ok(());
+            }
+            printer.print(None, "Restarting", "as the memory layout changed");
+        }
+    }
+
+    // Dropping the old process closes it
+    *running = None;
+    printer.print(None, "Starting", "Dolphin");
+    *running = Some((Dolphin::launch(executable, &config.build.iso)?, dol));
+
+    Ok(())
+}
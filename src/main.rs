@@ -1,20 +1,62 @@
 #[macro_use]
 extern crate structopt;
+#[macro_use]
 extern crate failure;
+#[macro_use]
+extern crate log;
 extern crate romhack_backend;
+#[macro_use]
+extern crate serde_json;
 extern crate termcolor;
 
 mod opt;
 
 use failure::{Error, ResultExt};
-use opt::Opt;
-use romhack_backend::{apply_patch, build, new, KeyValPrint, MessageKind};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use opt::{Command, LogFormat, Opt};
+use romhack_backend::{
+    apply_patch, build, create_patch_file, diff, extract_dol, extract_messages, new, replace_dol,
+    run, verify, watch, BuildOptions, Fields, KeyValPrint, MessageKind,
+};
+use std::fs;
 use std::io::prelude::*;
+use std::path::PathBuf;
+use std::process;
 use structopt::StructOpt;
 use termcolor::{BufferWriter, Color, ColorChoice, ColorSpec, WriteColor};
 
 fn main() {
-    if let Err(e) = try_main() {
+    let opt = Opt::from_args();
+    let printer = TermPrinter {
+        format: opt.log_format,
+    };
+    let logger = Logger {
+        format: opt.log_format,
+    };
+    log::set_boxed_logger(Box::new(logger)).expect("Couldn't set up the logger");
+    log::set_max_level(match opt.verbose {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    });
+
+    if let Err(e) = try_main(&printer, opt.command) {
+        if printer.format == LogFormat::Json {
+            let causes = e
+                .iter_chain()
+                .skip(1)
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>();
+            printer.log(
+                Level::Error,
+                "Error",
+                &e.to_string(),
+                &Fields::default(),
+                &causes,
+            );
+            process::exit(1);
+        }
+
         eprintln!();
 
         let mut bufwtr = BufferWriter::stderr(ColorChoice::Always);
@@ -43,53 +85,215 @@ fn main() {
             writeln!(&mut buffer, " {}", cause).expect("Error while printing error");
         }
         bufwtr.print(&buffer).expect("Error while printing error");
+        process::exit(1);
     } else {
-        key_val_print(None, "Finished", "Rom Hack");
+        printer.print(None, "Finished", "Rom Hack");
     }
 }
 
-fn try_main() -> Result<(), Error> {
-    let opt = Opt::from_args();
-
-    match opt {
-        Opt::Build { debug, patch } => {
-            build(&TermPrinter, debug, patch).context("Couldn't build the Rom Hack")?
+fn try_main(printer: &TermPrinter, command: Command) -> Result<(), Error> {
+    match command {
+        Command::Build {
+            debug,
+            patch,
+            riivolution,
+            check,
+            verify_reproducible,
+            defines,
+            features,
+        } => {
+            let options = BuildOptions {
+                debug,
+                patch,
+                riivolution,
+                check,
+                verify_reproducible,
+                defines: &defines,
+                features: &features,
+            };
+            build(printer, &options).context("Couldn't build the Rom Hack")?
+        }
+        Command::Watch {
+            debug,
+            patch,
+            riivolution,
+            defines,
+            features,
+            dolphin,
+        } => {
+            let options = BuildOptions {
+                debug,
+                patch,
+                riivolution,
+                defines: &defines,
+                features: &features,
+                ..Default::default()
+            };
+            watch(printer, &options, dolphin.as_ref().map(|p| &**p))
+                .context("Couldn't watch the Rom Hack")?
         }
-        Opt::New { name } => new(&name).context("Couldn't create the Rom Hack project")?,
-        Opt::Apply {
+        Command::Run {
+            debug,
+            defines,
+            features,
+            dolphin,
+        } => run(printer, debug, &defines, &features, &dolphin)
+            .context("Couldn't run the Rom Hack")?,
+        Command::New { name } => new(&name).context("Couldn't create the Rom Hack project")?,
+        Command::Apply {
             patch,
             original_game,
             output,
-        } => apply_patch(&TermPrinter, patch, original_game, output)
+        } => apply_patch(printer, patch, original_game, output)
             .context("Couldn't apply the patch")?,
+        Command::CreatePatch {
+            dol,
+            format,
+            original_game,
+            patched_game,
+            output,
+        } => create_patch_file(printer, format, original_game, patched_game, output, dol)
+            .context("Couldn't create the patch")?,
+        Command::Diff {
+            original_game,
+            patched_game,
+            output,
+        } => {
+            let report =
+                diff(printer, original_game, patched_game).context("Couldn't compare the games")?;
+            write_output(output, &report).context("Couldn't write the differences")?
+        }
+        Command::Verify { game, dat } => {
+            verify(printer, game, dat).context("Couldn't verify the game")?
+        }
+        Command::ExtractDol {
+            original_game,
+            output,
+        } => extract_dol(printer, original_game, output).context("Couldn't extract the DOL")?,
+        Command::ExtractMessages {
+            original_game,
+            iso_path,
+            table,
+            output,
+        } => {
+            let messages = extract_messages(printer, original_game, iso_path, table)
+                .context("Couldn't extract the messages")?;
+            write_output(output, &messages).context("Couldn't write the messages")?
+        }
+        Command::ReplaceDol {
+            original_game,
+            dol,
+            output,
+        } => {
+            replace_dol(printer, original_game, dol, output).context("Couldn't replace the DOL")?
+        }
     }
 
     Ok(())
 }
 
-pub struct TermPrinter;
+/// Writes the text to the output or to stdout if there is none.
+fn write_output(output: Option<PathBuf>, text: &str) -> Result<(), Error> {
+    match output {
+        Some(output) => fs::write(output, text)?,
+        None => print!("{}", text),
+    }
+    Ok(())
+}
+
+pub struct TermPrinter {
+    format: LogFormat,
+}
+
+impl TermPrinter {
+    fn log(&self, level: Level, key: &str, val: &str, fields: &Fields, causes: &[String]) {
+        match self.format {
+            LogFormat::Human => log!(target: key, level, "{}", val),
+            LogFormat::Json => {
+                let name = match level {
+                    Level::Warn => "warning",
+                    Level::Error => "error",
+                    Level::Info => "info",
+                    Level::Debug => "verbose",
+                    Level::Trace => "debug",
+                };
+                let mut line = json!({
+                    "level": name,
+                    "key": key,
+                    "message": val,
+                });
+                if let Some(file) = fields.file {
+                    line["file"] = json!(file);
+                }
+                if let Some(address) = fields.address {
+                    line["address"] = json!(format!("{:08X}", address));
+                }
+                if !causes.is_empty() {
+                    line["causes"] = json!(causes);
+                }
+                log!(target: key, level, "{}", line);
+            }
+        }
+    }
+}
 
 impl KeyValPrint for TermPrinter {
     fn print(&self, kind: Option<MessageKind>, key: &str, val: &str) {
-        let color = match kind {
-            Some(MessageKind::Warning) => Some(Color::Yellow),
-            Some(MessageKind::Error) => Some(Color::Red),
-            None => None,
+        self.print_with(kind, key, val, &Fields::default())
+    }
+
+    fn print_with(&self, kind: Option<MessageKind>, key: &str, val: &str, fields: &Fields) {
+        let level = match kind {
+            Some(MessageKind::Warning) => Level::Warn,
+            Some(MessageKind::Error) => Level::Error,
+            None => Level::Info,
+            Some(MessageKind::Verbose) => Level::Debug,
+            Some(MessageKind::Debug) => Level::Trace,
         };
-        key_val_print(color, key, val)
+        self.log(level, key, val, fields, &[]);
+    }
+}
+
+/// Writes the messages to stderr. JSON messages are already formatted as a
+/// single line each, so tools like editor plugins can tell the warnings and
+/// errors apart from the progress.
+struct Logger {
+    format: LogFormat,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
     }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match self.format {
+            LogFormat::Human => {
+                let color = match record.level() {
+                    Level::Warn => Color::Yellow,
+                    Level::Error => Color::Red,
+                    Level::Info => Color::Green,
+                    Level::Debug | Level::Trace => Color::Cyan,
+                };
+                key_val_print(color, record.target(), &record.args().to_string());
+            }
+            LogFormat::Json => eprintln!("{}", record.args()),
+        }
+    }
+
+    fn flush(&self) {}
 }
 
-fn key_val_print(color: Option<Color>, key: &str, val: &str) {
+fn key_val_print(color: Color, key: &str, val: &str) {
     let bufwtr = BufferWriter::stderr(ColorChoice::Always);
     let mut buffer = bufwtr.buffer();
 
     buffer
-        .set_color(
-            ColorSpec::new()
-                .set_fg(Some(color.unwrap_or(Color::Green)))
-                .set_bold(true),
-        ).ok();
+        .set_color(ColorSpec::new().set_fg(Some(color)).set_bold(true))
+        .ok();
     write!(&mut buffer, "{:>12}", key).ok();
 
     buffer.reset().ok();
@@ -1,7 +1,40 @@
+use failure::Error;
+use romhack_backend::patchfile::Format;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 #[derive(StructOpt, Debug)]
-pub enum Opt {
+pub struct Opt {
+    /// Prints more details, like every branch that is redirected, and even more when repeated
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    pub verbose: u8,
+    /// Format of the messages (human or json). JSON messages are printed one per line
+    #[structopt(long = "log-format", default_value = "human")]
+    pub log_format: LogFormat,
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LogFormat {
+    Human,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match &*s.to_lowercase() {
+            "human" => LogFormat::Human,
+            "json" => LogFormat::Json,
+            _ => bail!("Unknown log format \"{}\", expected human or json", s),
+        })
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
     /// Builds the Rom Hack
     #[structopt(name = "build")]
     Build {
@@ -11,6 +44,62 @@ pub enum Opt {
         /// Compiles the Rom Hack as a patch
         #[structopt(short = "p", long = "patch")]
         patch: bool,
+        /// Builds the Rom Hack as a Riivolution patch instead of an ISO
+        #[structopt(short = "r", long = "riivolution", conflicts_with = "patch")]
+        riivolution: bool,
+        /// Only checks that the Rom Hack builds, without writing the game or any other output
+        #[structopt(long = "check")]
+        check: bool,
+        /// Builds the Rom Hack twice and fails if the builds aren't identical
+        #[structopt(long = "verify-reproducible", conflicts_with = "check")]
+        verify_reproducible: bool,
+        /// Defines a symbol for the conditional directives of the patch files, like REGION_PAL
+        /// or VERSION=2
+        #[structopt(short = "D", long = "define", number_of_values = 1)]
+        defines: Vec<String>,
+        /// Builds the optional features of the Rom Hack, like widescreen,hard-mode
+        #[structopt(long = "features", number_of_values = 1)]
+        features: Vec<String>,
+    },
+    /// Rebuilds the Rom Hack whenever one of its source files changes
+    #[structopt(name = "watch")]
+    Watch {
+        /// Compiles the Rom Hack in Rust's debug mode
+        #[structopt(short = "d", long = "debug")]
+        debug: bool,
+        /// Compiles the Rom Hack as a patch
+        #[structopt(short = "p", long = "patch")]
+        patch: bool,
+        /// Builds the Rom Hack as a Riivolution patch instead of an ISO
+        #[structopt(short = "r", long = "riivolution", conflicts_with = "patch")]
+        riivolution: bool,
+        /// Defines a symbol for the conditional directives of the patch files, like REGION_PAL
+        /// or VERSION=2
+        #[structopt(short = "D", long = "define", number_of_values = 1)]
+        defines: Vec<String>,
+        /// Builds the optional features of the Rom Hack, like widescreen,hard-mode
+        #[structopt(long = "features", number_of_values = 1)]
+        features: Vec<String>,
+        /// Path to the Dolphin executable, to restart it with every new build
+        #[structopt(long = "dolphin", parse(from_os_str))]
+        dolphin: Option<PathBuf>,
+    },
+    /// Builds the Rom Hack, starts it in Dolphin and patches the running game on changes
+    #[structopt(name = "run")]
+    Run {
+        /// Compiles the Rom Hack in Rust's debug mode
+        #[structopt(short = "d", long = "debug")]
+        debug: bool,
+        /// Defines a symbol for the conditional directives of the patch files, like REGION_PAL
+        /// or VERSION=2
+        #[structopt(short = "D", long = "define", number_of_values = 1)]
+        defines: Vec<String>,
+        /// Builds the optional features of the Rom Hack, like widescreen,hard-mode
+        #[structopt(long = "features", number_of_values = 1)]
+        features: Vec<String>,
+        /// Path to the Dolphin executable
+        #[structopt(long = "dolphin", default_value = "dolphin-emu", parse(from_os_str))]
+        dolphin: PathBuf,
     },
     /// Applies a patch file to a game to create a Rom Hack
     #[structopt(name = "apply")]
@@ -25,6 +114,87 @@ pub enum Opt {
         #[structopt(name = "OUT", parse(from_os_str))]
         output: PathBuf,
     },
+    /// Creates an IPS, BPS, UPS or VCDIFF patch from the original and the patched game
+    #[structopt(name = "create-patch")]
+    CreatePatch {
+        /// Only diffs the main DOLs instead of the full games
+        #[structopt(long = "dol")]
+        dol: bool,
+        /// Format of the patch (ips, bps, ups or vcdiff)
+        #[structopt(name = "FORMAT")]
+        format: Format,
+        /// Input path to original game (GCM or ISO format)
+        #[structopt(name = "ORIGINAL", parse(from_os_str))]
+        original_game: PathBuf,
+        /// Input path to the patched game
+        #[structopt(name = "PATCHED", parse(from_os_str))]
+        patched_game: PathBuf,
+        /// Output path for the patch
+        #[structopt(name = "OUT", parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Lists the differences between two games, like a build and the original game
+    #[structopt(name = "diff")]
+    Diff {
+        /// Input path to the original game
+        #[structopt(name = "ORIGINAL", parse(from_os_str))]
+        original_game: PathBuf,
+        /// Input path to the patched game
+        #[structopt(name = "PATCHED", parse(from_os_str))]
+        patched_game: PathBuf,
+        /// Output path for the differences, which are printed otherwise
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Checks whether a game is a known good dump, according to a DAT file like Redump's
+    #[structopt(name = "verify")]
+    Verify {
+        /// Input path to the game
+        #[structopt(name = "GAME", parse(from_os_str))]
+        game: PathBuf,
+        /// Input path to the DAT file with the known good dumps
+        #[structopt(name = "DAT", parse(from_os_str))]
+        dat: PathBuf,
+    },
+    /// Extracts the main DOL from a game
+    #[structopt(name = "extract-dol")]
+    ExtractDol {
+        /// Input path to the game (GCM or ISO format)
+        #[structopt(name = "GAME", parse(from_os_str))]
+        original_game: PathBuf,
+        /// Output path for the DOL
+        #[structopt(name = "OUT", parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Lists the messages of a BMG file of a game, so they can be translated in the config
+    #[structopt(name = "extract-messages")]
+    ExtractMessages {
+        /// Input path to the game (GCM or ISO format)
+        #[structopt(name = "GAME", parse(from_os_str))]
+        original_game: PathBuf,
+        /// Path of the BMG file on the disc
+        #[structopt(name = "PATH")]
+        iso_path: String,
+        /// TBL file with the game's own text encoding
+        #[structopt(short = "t", long = "table", parse(from_os_str))]
+        table: Option<PathBuf>,
+        /// Output path for the messages, which are printed otherwise
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Replaces the main DOL of a game
+    #[structopt(name = "replace-dol")]
+    ReplaceDol {
+        /// Input path to the game (GCM or ISO format)
+        #[structopt(name = "GAME", parse(from_os_str))]
+        original_game: PathBuf,
+        /// Input path to the new DOL
+        #[structopt(name = "DOL", parse(from_os_str))]
+        dol: PathBuf,
+        /// Output path for the new game
+        #[structopt(name = "OUT", parse(from_os_str))]
+        output: PathBuf,
+    },
     /// Creates a new Rom Hack with the given name
     #[structopt(name = "new")]
     New { name: String },
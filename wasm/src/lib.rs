@@ -3,7 +3,8 @@ extern crate romhack_backend;
 
 use failure::Error;
 use romhack_backend::{
-    build_iso, iso::writer::write_iso, open_config_from_patch, KeyValPrint, MessageKind,
+    build_iso, iso::reader::SystemData, iso::writer::write_iso, open_config_from_patch,
+    KeyValPrint, MessageKind,
 };
 use std::alloc::{alloc as allocate, dealloc as deallocate, Layout};
 use std::io::{self, BufWriter, Cursor, SeekFrom, Write};
@@ -29,6 +30,7 @@ impl KeyValPrint for JSPrinter {
                 Some(MessageKind::Error) => 2,
                 Some(MessageKind::Warning) => 1,
                 None => 0,
+                Some(MessageKind::Verbose) | Some(MessageKind::Debug) => return,
             };
             key_val_print(kind, key.as_ptr(), key.len(), val.as_ptr(), val.len());
         }
@@ -128,13 +130,22 @@ fn try_create_romhack(patch: &[u8], iso: &[u8]) -> Result<(), Error> {
             set_name(name.as_ptr(), name.len());
         }
     }
-    let romhack = build_iso(&JSPrinter, zip, iso, compiled_library, &mut config)?;
+    let mut reader = Cursor::new(iso);
+    let system_data = SystemData::read(&mut reader)?;
+    let romhack = build_iso(
+        &JSPrinter,
+        zip,
+        &mut reader,
+        &system_data,
+        compiled_library,
+        &mut config,
+    )?;
     JSPrinter.print(None, "Measuring", "Rom Hack File Size");
-    write_iso(RomHackCounter, &romhack)?;
+    write_iso(&mut reader, RomHackCounter, &romhack)?;
     unsafe {
         restart();
     }
     JSPrinter.print(None, "Writing", "Rom Hack");
     let writer = BufWriter::new(RomHackWriter);
-    write_iso(writer, &romhack)
+    write_iso(&mut reader, writer, &romhack)
 }